@@ -0,0 +1,42 @@
+/*
+ * Counts malloc, calloc and realloc calls per thread for the benches
+ * here, glibc only. Build it and preload it under the bench:
+ *
+ *     cc -O2 -shared -fPIC -o /tmp/malloc_count.so .github/bench/malloc_count.c
+ *     LD_PRELOAD=/tmp/malloc_count.so python .github/bench/parse.py
+ *
+ * The benches find malloc_count() with ctypes and read it on the loop's
+ * thread, so what the client thread allocates isn't counted. Python's
+ * own small objects come out of pymalloc's arenas and mostly aren't
+ * either, what's left is the extension's allocations.
+ */
+#include <stddef.h>
+
+extern void *__libc_malloc(size_t size);
+extern void *__libc_calloc(size_t count, size_t size);
+extern void *__libc_realloc(void *ptr, size_t size);
+
+static __thread unsigned long long count;
+
+void *malloc(size_t size)
+{
+    count++;
+    return __libc_malloc(size);
+}
+
+void *calloc(size_t n, size_t size)
+{
+    count++;
+    return __libc_calloc(n, size);
+}
+
+void *realloc(void *ptr, size_t size)
+{
+    count++;
+    return __libc_realloc(ptr, size);
+}
+
+unsigned long long malloc_count(void)
+{
+    return count;
+}
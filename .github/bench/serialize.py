@@ -0,0 +1,101 @@
+"""
+synth-398: a micro-benchmark of writing the response head. The same as
+parse.py but the other way round, the request is as short as one can
+be and the handler is a plain function answering 200 "hello world", so
+what's left to vary between builds is putting the response together.
+It's run for the bare response and for one with a content type and
+three headers of its own, with the allocations per request on the
+loop's thread as well when malloc_count.c is preloaded. Not run by CI,
+the numbers are only worth comparing on one machine, and writing the
+head is a small part of a request so the allocations are what tell
+builds apart more than the time does.
+
+    PYTHONPATH=old python .github/bench/serialize.py
+    PYTHONPATH=new python .github/bench/serialize.py
+"""
+import asyncio
+import ctypes
+import logging
+import socket
+import sys
+import threading
+import time
+
+import async_rust
+
+
+REQUEST = b"GET / HTTP/1.1\r\nHost: b\r\n\r\n"
+REQUESTS = int(sys.argv[1]) if len(sys.argv) > 1 else 20000
+
+try:
+    malloc_count = ctypes.CDLL(None).malloc_count
+    malloc_count.restype = ctypes.c_ulonglong
+except AttributeError:
+    malloc_count = None
+
+
+def bare(request):
+    return async_rust.HTTPResponse(b"hello world")
+
+
+def with_headers(request):
+    return async_rust.HTTPResponse(b"hello world", headers={
+        "Content-Type": "text/plain; charset=utf-8",
+        "Cache-Control": "no-store",
+        "X-Frame-Options": "DENY",
+        "Vary": "Accept-Encoding",
+    })
+
+
+CASES = [
+    ("hello world", bare),
+    ("with 4 headers", with_headers),
+]
+
+
+def client(port, results):
+    with socket.create_connection(("127.0.0.1", port)) as sock:
+        sock.setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
+        started = time.perf_counter()
+        for _ in range(REQUESTS):
+            sock.sendall(REQUEST)
+            response = b""
+            while not response.endswith(b"hello world"):
+                response += sock.recv(65536)
+        results.append(time.perf_counter() - started)
+
+
+async def bench(name, callback):
+    # take a free port rather than asking for the one they bound, older builds can't say
+    with socket.socket() as sock:
+        sock.bind(("127.0.0.1", 0))
+        port = sock.getsockname()[1]
+    runner = async_rust.AsyncServerRunner("127.0.0.1:%d" % port, callback)
+    task = asyncio.ensure_future(runner)
+    await asyncio.sleep(0.1)
+
+    results = []
+    thread = threading.Thread(target=client, args=(port, results))
+    allocated = malloc_count and malloc_count()
+    thread.start()
+    while thread.is_alive():
+        await asyncio.sleep(0.01)
+    allocated = malloc_count and malloc_count() - allocated
+
+    runner.close()
+    task.cancel()
+    elapsed = results[0]
+    print("%-16s %d requests in %.2fs, %.0f requests/s, %.1fus each%s" % (
+        name + ":", REQUESTS, elapsed, REQUESTS / elapsed, elapsed / REQUESTS * 1e6,
+        ", %.1f mallocs each" % (allocated / REQUESTS) if malloc_count else "",
+    ))
+
+
+async def main():
+    # builds from before access_log was an option log every request, mute them all the same way
+    logging.getLogger("async_rust.access").disabled = True
+    for case in CASES:
+        await bench(*case)
+
+
+asyncio.get_event_loop().run_until_complete(main())
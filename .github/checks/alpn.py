@@ -0,0 +1,53 @@
+"""
+synth-279: ALPN on the TLS listener. A client offering http/1.1 gets it
+and the callback sees what was negotiated, the default config offers
+nothing else.
+"""
+import asyncio
+import shutil
+import ssl
+import tempfile
+
+import async_rust
+
+from support import run, self_signed, serving, skip
+
+
+seen = []
+
+
+async def handler(request):
+    seen.append(request.tls.alpn_protocol)
+    return "ok"
+
+
+async def negotiate(port, protocols):
+    context = ssl.create_default_context()
+    context.check_hostname = False
+    context.verify_mode = ssl.CERT_NONE
+    context.set_alpn_protocols(protocols)
+    reader, writer = await asyncio.open_connection("127.0.0.1", port, ssl=context, server_hostname="localhost")
+    negotiated = writer.get_extra_info("ssl_object").selected_alpn_protocol()
+    writer.write(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+    response = await asyncio.wait_for(reader.read(), 5)
+    writer.close()
+    return negotiated, response
+
+
+async def main(directory):
+    config = async_rust.TLSConfig(*self_signed(directory))
+    assert config.alpn_protocols == ["http/1.1"], config.alpn_protocols
+
+    async with serving(handler, tls=config) as (_, port):
+        negotiated, response = await negotiate(port, ["h2", "http/1.1"])
+        assert negotiated == "http/1.1", negotiated
+        assert response.startswith(b"HTTP/1.1 200"), response
+        assert seen == ["http/1.1"], seen
+
+
+if shutil.which("openssl") is None:
+    skip("needs openssl to make a certificate")
+
+with tempfile.TemporaryDirectory() as directory:
+    run(lambda: main(directory))
+print("alpn ok")
@@ -0,0 +1,96 @@
+"""
+synth-384: the shared awaiting machinery, driven under a real loop.
+
+Awaiting: a callback can hand back any awaitable, a coroutine, a bare
+future, a task or an object of its own with an `__await__`, and an error
+set on a future it's waiting on is thrown into it where it can be caught.
+
+LoopSleeper: a raw connection's reads sleep until the client sends, a
+read cancelled by `wait_for()` leaves the sleep's wake-up harmless, and
+`drain()` and `wait_closed()` finish once the socket has caught up.
+Neither leaves anything for the loop's exception handler.
+"""
+import asyncio
+import time
+
+from support import exchange, run, serving, status
+
+
+class Deferred:
+    """An awaitable of our own, a generator-based `__await__` over a loop future."""
+
+    def __await__(self):
+        future = asyncio.get_event_loop().create_future()
+        asyncio.get_event_loop().call_later(0.01, future.set_result, "deferred")
+        return (yield from future)
+
+
+async def thrown():
+    future = asyncio.get_event_loop().create_future()
+    asyncio.get_event_loop().call_later(0.01, future.set_exception, LookupError("thrown"))
+    try:
+        await future
+    except LookupError as e:
+        return "caught %s" % e
+
+
+def handler(request):
+    loop = asyncio.get_event_loop()
+    if request.path == "/coroutine":
+        return asyncio.sleep(0.01, "coroutine")
+    if request.path == "/future":
+        future = loop.create_future()
+        loop.call_later(0.01, future.set_result, "future")
+        return future
+    if request.path == "/task":
+        return loop.create_task(asyncio.sleep(0.01, "task"))
+    if request.path == "/deferred":
+        return Deferred()
+    if request.path == "/thrown":
+        return thrown()
+    return "plain"
+
+
+async def echo(reader, writer):
+    try:
+        await asyncio.wait_for(reader.read(5), 0.05)
+    except asyncio.TimeoutError:
+        writer.write(b"timed out, ")
+
+    writer.write(await reader.readexactly(5))
+    await writer.drain()
+    writer.close()
+    await writer.wait_closed()
+
+
+async def callbacks():
+    async with serving(handler) as (_, port):
+        for name, body in [("coroutine", "coroutine"), ("future", "future"), ("task", "task"),
+                           ("deferred", "deferred"), ("thrown", "caught thrown"), ("plain", "plain")]:
+            response = await exchange(port, b"GET /%s HTTP/1.1\r\nHost: check\r\nConnection: close\r\n\r\n" % name.encode())
+            assert status(response) == 200 and response.endswith(body.encode()), (name, response)
+
+
+async def sleeps():
+    async with serving(echo, raw=True) as (_, port):
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        await asyncio.sleep(0.2)
+        sent = time.monotonic()
+        writer.write(b"hello")
+        response = await asyncio.wait_for(reader.read(), 5)
+        assert response == b"timed out, hello", response
+        assert time.monotonic() - sent < 0.1, "the read slept %.0fms past the data" % ((time.monotonic() - sent) * 1000)
+        writer.close()
+
+
+async def main():
+    unhandled = []
+    asyncio.get_event_loop().set_exception_handler(lambda loop, context: unhandled.append(context))
+    await callbacks()
+    await sleeps()
+    await asyncio.sleep(0.1)
+    assert unhandled == [], unhandled
+
+
+run(main)
+print("awaiting ok")
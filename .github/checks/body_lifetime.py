@@ -0,0 +1,109 @@
+"""
+synth-397: a request's body is only there while the request is. Read
+twice it's the same bytes object, and two tasks sharing the request get
+that same object too. Once the response has gone, a task still holding
+the request gets RuntimeError("request is no longer active") from body,
+body_view(), memoryview(), form() and multipart(), and so does a request
+kept from earlier on a connection that's moved on to its next keep-alive
+request. Over HTTP/2 it's the same once the stream's answered.
+"""
+import asyncio
+
+from support import (
+    DATA, END_HEADERS, END_STREAM, HEADERS, frame, h2_connect, h2_response, read_response, request_block, run,
+    serving,
+)
+
+
+MESSAGE = "request is no longer active"
+kept = []
+results = {}
+
+
+def reads(request):
+    return {
+        "body": lambda: request.body,
+        "body_view()": lambda: request.body_view(),
+        "memoryview()": lambda: memoryview(request),
+        "form()": lambda: request.form(),
+        "multipart()": lambda: request.multipart(),
+    }
+
+
+def refused(request):
+    """Which reads didn't raise exactly what they should have, by name."""
+    wrong = []
+    for name, read in reads(request).items():
+        try:
+            read()
+            wrong.append((name, "no error"))
+        except RuntimeError as e:
+            if str(e) != MESSAGE:
+                wrong.append((name, str(e)))
+        except Exception as e:
+            wrong.append((name, repr(e)))
+    return wrong
+
+
+async def later(request, key):
+    await asyncio.sleep(0.1)
+    results[key] = refused(request)
+
+
+async def handler(request):
+    if request.path == "/twice":
+        first, second = request.body, request.body
+        return "%s %s" % (first is second, first == b"hello")
+    if request.path == "/shared":
+        async def read():
+            await asyncio.sleep(0)
+            return request.body
+        bodies = await asyncio.gather(read(), read())
+        return "%s %s" % (bodies[0] is bodies[1] is request.body, bodies[0] == b"hello")
+    if request.path == "/after":
+        asyncio.ensure_future(later(request, request.headers.get("x-key")))
+        return "answered"
+    if request.path == "/keep":
+        kept.append(request)
+        return "kept"
+    if request.path == "/next":
+        return repr(refused(kept.pop()))
+    return "?"
+
+
+def post(path, body=b"hello", *headers):
+    head = [b"POST %s HTTP/1.1" % path, b"Host: check", b"Content-Length: %d" % len(body)] + list(headers)
+    return b"\r\n".join(head) + b"\r\n\r\n" + body
+
+
+async def main():
+    async with serving(handler) as (_, port):
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        for path in (b"/twice", b"/shared"):
+            writer.write(post(path))
+            assert (await read_response(reader))[2] == b"True True", path
+
+        writer.write(post(b"/after", b"hello", b"X-Key: h1"))
+        assert (await read_response(reader))[2] == b"answered"
+
+        # the kept request's connection has moved on, to the request asking about it
+        writer.write(post(b"/keep"))
+        assert (await read_response(reader))[2] == b"kept"
+        writer.write(post(b"/next"))
+        assert (await read_response(reader))[2] == b"[]"
+        writer.close()
+
+        reader, writer = await h2_connect(port)
+        block = request_block(b"POST", b"/after", (b"x-key", b"h2"))
+        writer.write(frame(HEADERS, END_HEADERS, 1, block) + frame(DATA, END_STREAM, 1, b"hello"))
+        assert (await h2_response(reader, 1))[2] == b"answered"
+        writer.write(frame(HEADERS, END_HEADERS, 3, request_block(b"POST", b"/twice")) + frame(DATA, END_STREAM, 3, b"hello"))
+        assert (await h2_response(reader, 3))[2] == b"True True"
+        await asyncio.sleep(0.2)
+        writer.close()
+
+    assert results == {"h1": [], "h2": []}, results
+
+
+run(main)
+print("body lifetime ok")
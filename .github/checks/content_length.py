@@ -0,0 +1,57 @@
+"""
+synth-360: strict Content-Length. Anything but plain digits (a sign,
+padding a proxy might not strip, underscores, exponents, hex) and values
+past u64 are a 400. A well-formed length over `max_body_size` is a 413,
+sent before any of the body is read once it's past `max_drain_bytes`
+too. Both reactors are checked where the native one runs.
+"""
+import sys
+
+from support import exchange, run, serving, status
+
+
+MAX_BODY = 1024
+
+MALFORMED = [
+    b"+5", b"-5", b"5_0", b"1e3", b"0x5", b"5.0", b"five", b"", b"5 5", b"\x0b5", b"5\x0b", b"5\x0c",
+    b"18446744073709551616", b"99999999999999999999999",
+]
+WELL_FORMED = [b"5", b"05", b"0005", b" 5", b"5 ", b"\t5\t"]
+
+
+async def handler(request):
+    return "%d" % len(request.body)
+
+
+async def send(port, length, body=b"hello"):
+    return await exchange(port, b"POST / HTTP/1.1\r\nHost: check\r\nConnection: close\r\nContent-Length:" + length + b"\r\n\r\n" + body)
+
+
+async def corpus(**options):
+    async with serving(handler, max_body_size=MAX_BODY, **options) as (_, port):
+        for length in MALFORMED:
+            response = await send(port, b" " + length)
+            assert status(response) == 400, (length, response)
+
+        # the spaces and tabs around a header's value aren't part of it
+        for length in WELL_FORMED:
+            response = await send(port, length)
+            assert status(response) == 200 and response.endswith(b"\r\n\r\n5"), (length, response)
+
+        # a length past max_drain_bytes as well is refused on the head alone, nothing is buffered for it
+        response = await send(port, b" %d" % (1 << 40), body=b"")
+        assert status(response) == 413, response
+        response = await send(port, b" %d" % (MAX_BODY + 1), body=b"x" * (MAX_BODY + 1))
+        assert status(response) == 413, response
+        response = await send(port, b" %d" % MAX_BODY, body=b"x" * MAX_BODY)
+        assert status(response) == 200, response
+
+
+async def main():
+    await corpus()
+    if sys.platform.startswith("linux"):
+        await corpus(reactor="native")
+
+
+run(main)
+print("content length ok")
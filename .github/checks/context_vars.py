@@ -0,0 +1,114 @@
+"""
+synth-406: contextvars. A var set before the runner is awaited is seen
+by every request, and each request runs in its own copy of that context:
+a value middleware's `on_request` sets is what the handler sees, in its
+coroutine after it's awaited something too, and what the access log's
+handler sees, while a concurrent request sees the value it set itself
+and a later request on the same keep-alive connection doesn't see
+either. The same over HTTP/2, where the requests are streams of one
+connection.
+"""
+import asyncio
+import contextvars
+import logging
+
+import async_rust
+
+from support import END_HEADERS, END_STREAM, HEADERS, frame, h2_connect, h2_response, read_response, request_block, run
+
+
+trace = contextvars.ContextVar("trace", default="unset")
+outer = contextvars.ContextVar("outer", default="unset")
+seen = []
+
+
+class Tracing:
+    def on_request(self, request):
+        value = request.headers.get("x-trace")
+        if value:
+            trace.set(value)
+
+    def on_response(self, request, response):
+        seen.append(("on_response", request.path, trace.get()))
+        return response
+
+
+class Recorder(logging.Handler):
+    def emit(self, record):
+        seen.append(("log", record.path, trace.get()))
+
+
+async def handler(request):
+    before = trace.get()
+    await asyncio.sleep(0.1 if request.path == "/slow" else 0.01)
+    seen.append(("handler", request.path, trace.get()))
+    return "%s %s %s" % (before, trace.get(), outer.get())
+
+
+def get(path, value=None):
+    extra = b"X-Trace: %s\r\n" % value.encode() if value else b""
+    return b"GET %s HTTP/1.1\r\nHost: check\r\n%s\r\n" % (path.encode(), extra)
+
+
+def views(path, value):
+    return [("handler", path, value), ("on_response", path, value), ("log", path, value)]
+
+
+async def http1(port):
+    first, second = await asyncio.gather(*[asyncio.open_connection("127.0.0.1", port) for _ in range(2)])
+    first[1].write(get("/slow", "one"))
+    await asyncio.sleep(0.02)
+    second[1].write(get("/fast", "two"))
+    assert (await read_response(second[0]))[2] == b"two two before"
+    assert (await read_response(first[0]))[2] == b"one one before"
+
+    # the next request on the same connection starts from the runner's context again
+    first[1].write(get("/plain"))
+    assert (await read_response(first[0]))[2] == b"unset unset before"
+    for _, writer in (first, second):
+        writer.close()
+
+
+async def http2(port):
+    reader, writer = await h2_connect(port)
+    writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 1, request_block(b"GET", b"/slow", (b"x-trace", b"three"))))
+    writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 3, request_block(b"GET", b"/fast", (b"x-trace", b"four"))))
+    writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 5, request_block(b"GET", b"/plain")))
+    assert (await h2_response(reader, 3))[2] == b"four four before"
+    assert (await h2_response(reader, 5))[2] == b"unset unset before"
+    assert (await h2_response(reader, 1))[2] == b"three three before"
+    writer.close()
+
+
+async def main():
+    logger = logging.getLogger("async_rust.access")
+    logger.setLevel(logging.INFO)
+    logger.propagate = False
+    logger.addHandler(Recorder())
+    outer.set("before")
+
+    runner = async_rust.AsyncServerRunner("127.0.0.1:0", handler)
+    runner.add_middleware(Tracing())
+    task = asyncio.ensure_future(runner)
+    await asyncio.wait_for(runner.wait_ready(), 5)
+    port = runner.local_addr()[1]
+    try:
+        await http1(port)
+        await asyncio.sleep(0.1)
+        assert sorted(seen) == sorted(views("/slow", "one") + views("/fast", "two") + views("/plain", "unset")), seen
+        seen.clear()
+
+        await http2(port)
+        await asyncio.sleep(0.1)
+        assert sorted(seen) == sorted(views("/slow", "three") + views("/fast", "four") + views("/plain", "unset")), seen
+    finally:
+        runner.stop()
+        await asyncio.wait_for(task, 5)
+        runner.close()
+
+    # and nothing the requests set got back out to us
+    assert trace.get() == "unset"
+
+
+run(main)
+print("contextvars ok")
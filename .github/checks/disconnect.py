@@ -0,0 +1,61 @@
+"""
+synth-329: clients going away mid-request. A handler still waiting when
+its client hangs up is cancelled straight away rather than running to
+the end, and a client that hangs up halfway through its head doesn't
+leave the connection behind either, `connections_active` gets back to 0
+in both cases. The proactor loop windows defaults to can't watch a
+socket, there the handler only finds out when it writes and just the
+half-sent head is checked.
+"""
+import asyncio
+import sys
+import time
+
+from support import run, serving
+
+
+cancelled = []
+
+
+async def handler(request):
+    try:
+        await asyncio.sleep(30)
+    except asyncio.CancelledError:
+        cancelled.append(time.monotonic())
+        raise
+    return "too late"
+
+
+async def hang_up(port, data):
+    _, writer = await asyncio.open_connection("127.0.0.1", port)
+    writer.write(data)
+    await writer.drain()
+    await asyncio.sleep(0.1)
+    started = time.monotonic()
+    writer.close()
+    return started
+
+
+async def settled(runner, since, limit=1):
+    while runner.stats()["connections_active"]:
+        assert time.monotonic() - since < limit, "a connection is still active %ss after its client left" % limit
+        await asyncio.sleep(0.01)
+    return time.monotonic() - since
+
+
+async def main():
+    async with serving(handler) as (runner, port):
+        if sys.platform != "win32":
+            since = await hang_up(port, b"GET / HTTP/1.1\r\nHost: check\r\n\r\n")
+            waiting = await settled(runner, since)
+            assert cancelled and cancelled[0] - since < 1, "the handler wasn't cancelled when its client left"
+            print("freed in %.0fms waiting on the handler" % (waiting * 1000))
+
+        since = await hang_up(port, b"GET / HTTP/1.1\r\nHost: ch")
+        partial = await settled(runner, since)
+        print("freed in %.0fms halfway through the head" % (partial * 1000))
+
+    print("disconnect ok")
+
+
+run(main)
@@ -0,0 +1,103 @@
+"""
+synth-373: what's left of a refused request's body. A 413 whose body is
+up to `max_drain_bytes` long has it read and thrown away, the next
+request on the connection is answered as if nothing had happened, even
+when the body it threw away looked like a request of its own. A byte
+over the limit and the 413 says `Connection: close` and the connection
+ends. A client waiting on a `100 Continue` never gets one when it's
+refused, and since it hasn't sent its body it's closed too, one that's
+let through gets its `100` and carries on. A response from `on_headers`
+is a refusal like the rest.
+"""
+import asyncio
+
+import async_rust
+
+from support import closed, read_response, run, serving
+
+
+MAX_BODY = 1024
+DRAIN = 64 * 1024
+seen = []
+
+
+def on_headers(meta):
+    if meta.path == "/private":
+        return async_rust.HTTPResponse(b"no", status=401)
+
+
+async def handler(request):
+    seen.append(request.path)
+    return "%d" % len(request.body)
+
+
+def post(path, length, *headers):
+    head = [b"POST %s HTTP/1.1" % path, b"Host: check", b"Content-Length: %d" % length] + list(headers)
+    return b"\r\n".join(head) + b"\r\n\r\n"
+
+
+async def then_get(reader, writer):
+    """The connection is still good for another request, and that's the one answered."""
+    writer.write(b"GET /after HTTP/1.1\r\nHost: check\r\n\r\n")
+    assert (await read_response(reader))[::2] == (200, b"0")
+
+
+async def refused(port, path, length, status, body=None):
+    reader, writer = await asyncio.open_connection("127.0.0.1", port)
+    writer.write(post(path, length) + (body if body is not None else b"x" * length))
+    code, headers, _ = await read_response(reader)
+    assert code == status, (path, length, code)
+    return reader, writer, headers
+
+
+async def main():
+    async with serving(handler, on_headers=on_headers, max_body_size=MAX_BODY) as (_, port):
+        # within the drain limit, the smallest and the largest
+        for length in (MAX_BODY + 1, DRAIN):
+            reader, writer, headers = await refused(port, b"/", length, 413)
+            assert headers.get("connection") != "close", headers
+            await then_get(reader, writer)
+            writer.close()
+
+        # a body that's a request itself is still only body
+        smuggled = b"GET /smuggled HTTP/1.1\r\nHost: check\r\n\r\n".ljust(MAX_BODY + 1, b"x")
+        reader, writer, _ = await refused(port, b"/", len(smuggled), 413, smuggled)
+        await then_get(reader, writer)
+        writer.close()
+
+        # a byte over and it's closed rather than read
+        reader, writer, headers = await refused(port, b"/", DRAIN + 1, 413)
+        assert headers.get("connection") == "close", headers
+        assert await closed(reader)
+        writer.close()
+
+        # refused while the client waits for its 100, the body never comes so neither does the next request
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        writer.write(post(b"/", 2 * MAX_BODY, b"Expect: 100-continue"))
+        code, headers, _ = await read_response(reader)
+        assert code == 413 and headers.get("connection") == "close", (code, headers)
+        assert await closed(reader)
+        writer.close()
+
+        # let through, the 100 comes first and the connection carries on
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        writer.write(post(b"/", 10, b"Expect: 100-continue"))
+        assert await asyncio.wait_for(reader.readuntil(b"\r\n\r\n"), 5) == b"HTTP/1.1 100 Continue\r\n\r\n"
+        writer.write(b"x" * 10)
+        assert (await read_response(reader))[::2] == (200, b"10")
+        await then_get(reader, writer)
+        writer.close()
+
+        # and on_headers' answer, drained within the limit and closed past it
+        reader, writer, headers = await refused(port, b"/private", 100, 401)
+        await then_get(reader, writer)
+        writer.close()
+        reader, writer, headers = await refused(port, b"/private", DRAIN + 1, 401)
+        assert headers.get("connection") == "close" and await closed(reader), headers
+        writer.close()
+
+    assert seen == ["/after", "/after", "/after", "/", "/after", "/after"], seen
+
+
+run(main)
+print("drain ok")
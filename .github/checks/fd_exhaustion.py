@@ -0,0 +1,141 @@
+"""
+synth-378: running out of file descriptors. With RLIMIT_NOFILE lowered
+to a little over what the process already has open, another process
+opens 300 connections at once and holds them. Accepting pauses and it's
+logged once, not on every try, the accept thread and the native reactor
+don't spin while they wait (on linux, where their threads' CPU can be
+told from the loop's), and a keep-alive connection accepted before the flood is answered all
+the way through it. Once the flood lets go new connections are accepted
+again. For each accept mode, the native reactor's too on linux.
+"""
+import asyncio
+import logging
+import os
+import subprocess
+import sys
+import threading
+import time
+
+from support import read_response, run, serving, skip
+
+if sys.platform == "win32":
+    skip("there's no RLIMIT_NOFILE on windows")
+
+import resource
+
+
+FLOOD = 300
+HOLD = 1.5
+HEADROOM = 40
+
+FLOODER = """
+import socket, sys, time
+held = []
+for _ in range(%d):
+    try:
+        held.append(socket.create_connection(("127.0.0.1", int(sys.argv[1])), timeout=2))
+    except OSError:
+        pass
+print(len(held), flush=True)
+time.sleep(%f)
+""" % (FLOOD, HOLD)
+
+
+class Warnings(logging.Handler):
+    def __init__(self):
+        super().__init__()
+        self.messages = []
+
+    def emit(self, record):
+        if "not accepting for" in record.getMessage():
+            self.messages.append(record.getMessage())
+
+
+def cpu():
+    """
+    CPU seconds so far for the threads that aren't the loop's, where the
+    accept thread and the native reactor accept. The loop's thread polls
+    its idle connections by design, what the flood leaves it is counted
+    there however accepting pauses so it's left out. None off linux.
+    """
+    if not os.path.isdir("/proc/self/task"):
+        return None
+    ticks = 0
+    for tid in os.listdir("/proc/self/task"):
+        if int(tid) == threading.get_native_id():
+            continue
+        try:
+            with open("/proc/self/task/%s/stat" % tid) as f:
+                fields = f.read().rsplit(")", 1)[1].split()
+        except OSError:
+            continue
+        ticks += int(fields[11]) + int(fields[12])
+    return ticks / os.sysconf("SC_CLK_TCK")
+
+
+async def handler(request):
+    return "ok"
+
+
+async def flood(mode, options, warnings):
+    original = resource.getrlimit(resource.RLIMIT_NOFILE)
+    async with serving(handler, accept_cooldown=0.2, **options) as (_, port):
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        writer.write(b"GET / HTTP/1.1\r\nHost: check\r\n\r\n")
+        assert (await read_response(reader))[::2] == (200, b"ok")
+
+        # the cpu's read outside the lowered limit, reading it opens files too
+        started, used = time.monotonic(), cpu()
+        resource.setrlimit(resource.RLIMIT_NOFILE, (len(os.listdir("/proc/self/fd" if os.path.isdir("/proc/self/fd") else "/dev/fd")) + HEADROOM, original[1]))
+        try:
+            flooder = subprocess.Popen(
+                [sys.executable, "-c", FLOODER, str(port)], stdout=subprocess.PIPE,
+                preexec_fn=lambda: resource.setrlimit(resource.RLIMIT_NOFILE, original),
+            )
+
+            # the connection we already had is answered all the way through
+            answered, slowest = 0, 0
+            while flooder.poll() is None:
+                sent = time.monotonic()
+                writer.write(b"GET / HTTP/1.1\r\nHost: check\r\n\r\n")
+                assert (await read_response(reader))[::2] == (200, b"ok"), mode
+                slowest = max(slowest, time.monotonic() - sent)
+                answered += 1
+                await asyncio.sleep(0.05)
+            ended = time.monotonic()
+            opened = int(flooder.stdout.read())
+        finally:
+            resource.setrlimit(resource.RLIMIT_NOFILE, original)
+        spent = used is not None and (cpu() - used) / (ended - started)
+
+        assert opened > HEADROOM, "the flood only opened %d connections" % opened
+        assert answered > 5 and slowest < 0.2, "%s: %d answers, the slowest in %.0fms" % (mode, answered, slowest * 1000)
+        assert spent < 0.2, "%s used %.0f%% of a cpu accepting while it couldn't" % (mode, spent * 100)
+        assert len(warnings.messages) == 1, (mode, warnings.messages)
+        warnings.messages.clear()
+
+        # and new ones get in again
+        await asyncio.sleep(0.3)
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        writer.write(b"GET / HTTP/1.1\r\nHost: check\r\n\r\n")
+        assert (await read_response(reader))[::2] == (200, b"ok"), mode
+        writer.close()
+
+    print("%s: %d connections, %d answers during them (slowest %.0fms), %.0f%% cpu off the loop" % (
+        mode, opened, answered, slowest * 1000, spent * 100,
+    ))
+
+
+async def main():
+    warnings = Warnings()
+    logging.getLogger("async_rust").addHandler(warnings)
+
+    modes = [("poll", {}), ("thread", {"accept_mode": "thread"})]
+    if sys.platform.startswith("linux"):
+        modes.append(("native", {"reactor": "native"}))
+    for mode, options in modes:
+        await flood(mode, options, warnings)
+
+
+run(main)
+print("fd exhaustion ok")
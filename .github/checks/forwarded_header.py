@@ -0,0 +1,61 @@
+"""
+synth-335: only `forwarded_header` names the client. Behind a trusted
+proxy that writes X-Forwarded-For a client's own Forwarded header is
+ignored, both for `request.remote_addr` and for the `rate_limit` bucket
+it's counted in, and with `forwarded_header="forwarded"` it's the other
+way round.
+"""
+import asyncio
+
+import async_rust
+
+from support import exchange, run, serving, status
+
+
+async def handler(request):
+    return request.remote_addr
+
+
+async def remote_addr(port, *headers):
+    head = b"".join(b"%s: %s\r\n" % header for header in headers)
+    response = await exchange(port, b"GET / HTTP/1.1\r\nHost: check\r\nConnection: close\r\n" + head + b"\r\n")
+    assert status(response) == 200, response
+    return response.rpartition(b"\r\n\r\n")[2].decode()
+
+
+async def main():
+    async with serving(handler, trusted_proxies=["127.0.0.1"]) as (runner, port):
+        assert runner.config()["forwarded_header"] == "x-forwarded-for"
+        assert await remote_addr(port, (b"X-Forwarded-For", b"203.0.113.7")) == "203.0.113.7"
+        spoofed = (b"Forwarded", b"for=198.51.100.9")
+        assert await remote_addr(port, spoofed, (b"X-Forwarded-For", b"203.0.113.7")) == "203.0.113.7"
+        assert await remote_addr(port, spoofed) == "127.0.0.1"
+
+        # walked from the right, what the client put in front of our proxy's hop doesn't count
+        chain = (b"X-Forwarded-For", b"192.0.2.1, 203.0.113.7, 127.0.0.1")
+        assert await remote_addr(port, chain) == "203.0.113.7"
+
+    async with serving(handler, trusted_proxies=["127.0.0.1"], forwarded_header="forwarded") as (_, port):
+        both = [(b"Forwarded", b"for=198.51.100.9"), (b"X-Forwarded-For", b"203.0.113.7")]
+        assert await remote_addr(port, *both) == "198.51.100.9"
+        assert await remote_addr(port, both[1]) == "127.0.0.1"
+
+    # a fresh Forwarded on every request doesn't get a client a fresh bucket
+    async with serving(handler, trusted_proxies=["127.0.0.1"], rate_limit=(1, 0.001)) as (_, port):
+        statuses = []
+        for n in range(3):
+            head = b"X-Forwarded-For: 203.0.113.7\r\nForwarded: for=198.51.100.%d\r\n" % n
+            response = await exchange(port, b"GET / HTTP/1.1\r\nHost: check\r\nConnection: close\r\n" + head + b"\r\n")
+            statuses.append(status(response))
+        assert statuses == [200, 429, 429], statuses
+
+    try:
+        async_rust.AsyncServerRunner("127.0.0.1:0", handler, forwarded_header="x-real-ip")
+    except ValueError:
+        pass
+    else:
+        raise AssertionError("an unknown forwarded_header wasn't refused")
+
+
+run(main)
+print("forwarded header ok")
@@ -0,0 +1,111 @@
+"""
+synth-358: a runner let go of lets go of its port. For a runner never
+awaited, one whose task was cancelled, one closed with close() and one
+abandoned asleep with its loop closed under it, `del` and a
+`gc.collect()` are enough for a plain socket without SO_REUSEADDR to
+bind the same port straight after. A closed runner can't be awaited or
+started again, "server is closed".
+"""
+import asyncio
+import gc
+import socket
+
+import async_rust
+
+from support import run
+
+
+async def handler(request):
+    return "ok"
+
+
+def rebinds(port):
+    sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    try:
+        sock.bind(("127.0.0.1", port))
+        sock.listen()
+        return True
+    except OSError:
+        return False
+    finally:
+        sock.close()
+
+
+def bound():
+    runner = async_rust.AsyncServerRunner("127.0.0.1:0", handler, access_log=False)
+    port = runner.local_addr()[1]
+    assert not rebinds(port), "the port could be bound while the runner had it"
+    return runner, port
+
+
+def closed(call):
+    """If `call` raises the RuntimeError a closed runner does."""
+    try:
+        call()
+    except RuntimeError as e:
+        return str(e) == "server is closed"
+    return False
+
+
+async def never_awaited():
+    runner, port = bound()
+    return port
+
+
+async def cancelled():
+    runner, port = bound()
+    task = asyncio.ensure_future(runner)
+    await asyncio.wait_for(runner.wait_ready(), 5)
+    task.cancel()
+    await asyncio.gather(task, return_exceptions=True)
+    return port
+
+
+async def closed_runner():
+    runner, port = bound()
+    task = asyncio.ensure_future(runner)
+    await asyncio.wait_for(runner.wait_ready(), 5)
+    runner.close()
+    await asyncio.wait_for(task, 5)
+
+    # closing drops the listener straight away, before the runner's gone
+    assert rebinds(port), "close() left the port bound"
+    assert closed(runner.start)
+    try:
+        await runner
+        assert False, "a closed runner was awaited"
+    except RuntimeError as e:
+        assert str(e) == "server is closed", e
+    return port
+
+
+def abandoned():
+    loop = asyncio.new_event_loop()
+    asyncio.set_event_loop(loop)
+    runner, port = bound()
+    loop.create_task(serve(runner))
+    loop.run_until_complete(asyncio.wait_for(runner.wait_ready(), 5))
+    # long enough idle for the runner to be in one of its sleeps, asyncio says its task was destroyed pending
+    loop.run_until_complete(asyncio.sleep(0.2))
+    loop.close()
+    return port
+
+
+async def serve(runner):
+    await runner
+
+
+def released(case, port):
+    """The port's free once the runner its case made is gone, it only goes with its case's frame."""
+    gc.collect()
+    assert rebinds(port), "the %s runner still has its port after del and gc.collect()" % case
+
+
+async def main():
+    for case in (never_awaited, cancelled, closed_runner):
+        released(case.__name__, await case())
+
+
+run(main)
+released("abandoned", abandoned())
+print("gc rebind ok")
@@ -0,0 +1,90 @@
+"""
+synth-311: socket IO runs without the GIL. A CPU-bound Python thread
+keeps most of its idle pace while the server pushes a large body over
+TLS to a client in another process, rather than stalling behind every
+read and write.
+"""
+import asyncio
+import os
+import shutil
+import subprocess
+import sys
+import tempfile
+import threading
+import time
+
+import async_rust
+
+from support import run, self_signed, serving, skip
+
+
+BODY = b"x" * (64 * 1024 * 1024)
+CLIENT = """
+import socket, ssl, sys
+context = ssl.SSLContext(ssl.PROTOCOL_TLS_CLIENT)
+context.check_hostname = False
+context.verify_mode = ssl.CERT_NONE
+with context.wrap_socket(socket.create_connection(("127.0.0.1", int(sys.argv[1])))) as sock:
+    sock.sendall(b"GET / HTTP/1.1\\r\\nHost: localhost\\r\\nConnection: close\\r\\n\\r\\n")
+    received = 0
+    while True:
+        chunk = sock.recv(1 << 20)
+        if not chunk:
+            break
+        received += len(chunk)
+print(received)
+"""
+
+
+class Spinner(threading.Thread):
+    """Counts its way round a pure Python loop until it's stopped."""
+
+    def __init__(self):
+        super().__init__(daemon=True)
+        self.count = 0
+        self.running = True
+
+    def run(self):
+        while self.running:
+            self.count += 1
+
+
+async def pace(spinner, awaitable):
+    """The spinner's loops per second while `awaitable` runs, and what it gave back."""
+    started, count = time.perf_counter(), spinner.count
+    result = await awaitable
+    return (spinner.count - count) / (time.perf_counter() - started), result
+
+
+async def handler(request):
+    return async_rust.HTTPResponse(BODY)
+
+
+async def main(directory):
+    config = async_rust.TLSConfig(*self_signed(directory))
+    async with serving(handler, tls=config) as (_, port):
+        spinner = Spinner()
+        spinner.start()
+        idle, _ = await pace(spinner, asyncio.sleep(1))
+
+        client = await asyncio.create_subprocess_exec(
+            sys.executable, "-c", CLIENT, str(port), stdout=subprocess.PIPE,
+        )
+        busy, (stdout, _) = await pace(spinner, client.communicate())
+        spinner.running = False
+        spinner.join()
+
+    assert client.returncode == 0
+    assert int(stdout) > len(BODY), stdout
+    share = busy / idle * 100
+    assert share > 50, "the spinner fell to %.0f%% of its idle pace during the transfer" % share
+    print("gil release ok, the spinner kept %.0f%% of its idle pace" % share)
+
+
+if (os.cpu_count() or 1) < 2:
+    skip("with one CPU the spinner shares it with the client, held GIL or not")
+if shutil.which("openssl") is None:
+    skip("needs openssl to make a certificate")
+
+with tempfile.TemporaryDirectory() as directory:
+    run(lambda: main(directory))
@@ -0,0 +1,185 @@
+"""
+synth-382: the parts of RFC 9113 a client leans on, spoken frame by
+frame since h2spec isn't something CI can fetch offline.
+
+SETTINGS: ours come first, the window and stream limit they promise, and
+the client's are ACKed, as a PING is. Flow control: a response stops at
+the window the client's SETTINGS gave it and carries on when a
+WINDOW_UPDATE opens it, and a body more than our window long goes
+through because we reopen ours, on the stream and the connection, as it
+arrives. RST_STREAM from the client cancels the stream's handler and
+nothing more is sent on it. HPACK: a field the client adds to its
+dynamic table is there to be referenced by index in the next request.
+GOAWAY: `stop()` sends one before it closes, with the last stream we
+took, and `graceful_shutdown()` sends one and still answers the stream
+in flight.
+"""
+import asyncio
+import struct
+
+import async_rust
+
+from support import (
+    ACK, DATA, END_HEADERS, END_STREAM, GOAWAY, HEADERS, PING, RST_STREAM, SETTINGS, WINDOW_UPDATE, frame,
+    h2_connect, h2_response, literal, read_frame, request_block, run, serving,
+)
+
+
+CANCEL = 0x8
+NO_ERROR = 0x0
+MAX_BODY = 4 << 20
+CHUNK = 16384
+cancelled = []
+tokens = []
+
+
+async def handler(request):
+    if request.path == "/slow":
+        try:
+            await asyncio.sleep(5)
+        except asyncio.CancelledError:
+            cancelled.append(request.path)
+            raise
+    if request.path == "/while":
+        await asyncio.sleep(0.3)
+    if request.path == "/token":
+        tokens.append(request.headers.get("x-token"))
+    if request.path == "/big":
+        return b"x" * 40
+    return "%d" % len(request.body)
+
+
+def settings(*pairs):
+    return b"".join(struct.pack(">HI", key, value) for key, value in pairs)
+
+
+async def frames_until(reader, done, timeout=5):
+    """Frames up to and including the first that `done` is true of."""
+    frames = []
+    while True:
+        frames.append(await read_frame(reader, timeout))
+        if done(*frames[-1]):
+            return frames
+
+
+async def settings_exchange(port):
+    reader, writer = await h2_connect(port, settings((0x2, 0), (0x3, 50)))
+    kind, flags, stream, payload = await read_frame(reader)
+    assert (kind, flags, stream) == (SETTINGS, 0, 0), (kind, flags, stream)
+    ours = dict(struct.unpack(">HI", payload[i:i + 6]) for i in range(0, len(payload), 6))
+    assert ours == {0x3: 100, 0x4: 1 << 20}, ours
+
+    frames = await frames_until(reader, lambda kind, flags, *_: kind == SETTINGS)
+    assert frames[-1][:2] == (SETTINGS, ACK) and frames[-1][3] == b"", frames
+    writer.write(frame(SETTINGS, ACK, 0) + frame(PING, 0, 0, b"12345678"))
+    assert (await frames_until(reader, lambda kind, *_: kind == PING))[-1] == (PING, ACK, 0, b"12345678")
+    writer.close()
+
+
+async def window(port):
+    # the client's windows, every stream gets 16 bytes to send into
+    reader, writer = await h2_connect(port, settings((0x4, 16)))
+    writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 1, request_block(b"GET", b"/big")))
+    frames = await frames_until(reader, lambda kind, flags, stream, _: kind == DATA and stream == 1)
+    assert frames[-1][1:] == (0, 1, b"x" * 16), frames[-1]
+    try:
+        extra = await read_frame(reader, 0.3)
+        assert False, "sent past the window %r" % (extra,)
+    except asyncio.TimeoutError:
+        pass
+
+    writer.write(frame(WINDOW_UPDATE, 0, 1, struct.pack(">I", 100)))
+    frames = await frames_until(reader, lambda kind, flags, stream, _: stream == 1 and flags & END_STREAM)
+    assert b"".join(payload for kind, _, stream, payload in frames if kind == DATA) == b"x" * 24, frames
+    writer.close()
+
+    # ours, a body longer than the megabyte we offer only fits if we keep reopening it
+    reader, writer = await h2_connect(port)
+    # past our preface and its connection WINDOW_UPDATE to the ACK, what's after is from the body
+    await frames_until(reader, lambda kind, flags, *_: (kind, flags) == (SETTINGS, ACK))
+    writer.write(frame(HEADERS, END_HEADERS, 1, request_block(b"POST", b"/")))
+    sent = 0
+    for _ in range(2):
+        for _ in range(40):
+            writer.write(frame(DATA, 0, 1, b"x" * CHUNK))
+            sent += CHUNK
+        updated = set()
+        while updated != {0, 1}:
+            kind, _, stream, _ = await read_frame(reader)
+            if kind == WINDOW_UPDATE:
+                updated.add(stream)
+    writer.write(frame(DATA, END_STREAM, 1))
+    assert sent > 1 << 20
+    assert (await h2_response(reader, 1))[::2] == (200, b"%d" % sent)
+    writer.close()
+
+
+async def reset(port):
+    reader, writer = await h2_connect(port)
+    writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 1, request_block(b"GET", b"/slow")))
+    await asyncio.sleep(0.1)
+    writer.write(frame(RST_STREAM, 0, 1, struct.pack(">I", CANCEL)))
+    await asyncio.sleep(0.1)
+    assert cancelled == ["/slow"], cancelled
+
+    writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 3, request_block(b"GET", b"/")))
+    others = []
+    assert (await h2_response(reader, 3, others))[::2] == (200, b"0")
+    assert not [f for f in others if f[2] == 1], others
+    writer.close()
+
+
+async def dynamic_table(port):
+    reader, writer = await h2_connect(port)
+    # a literal with incremental indexing, it's entry 62 once it's decoded
+    indexed = b"\x40" + literal(b"x-token") + literal(b"first")
+    writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 1, request_block(b"GET", b"/token") + indexed))
+    assert (await h2_response(reader, 1))[0] == 200
+    # then only by its index, and again after a second entry has pushed it to 63
+    writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 3, request_block(b"GET", b"/token") + b"\xbe"))
+    assert (await h2_response(reader, 3))[0] == 200
+    second = b"\x40" + literal(b"x-other") + literal(b"second")
+    writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 5, request_block(b"GET", b"/token") + second + b"\xbf"))
+    assert (await h2_response(reader, 5))[0] == 200
+    assert tokens == ["first", "first", "first"], tokens
+    writer.close()
+
+
+async def going_away(stop):
+    runner = async_rust.AsyncServerRunner("127.0.0.1:0", handler, access_log=False)
+    task = asyncio.ensure_future(runner)
+    await asyncio.wait_for(runner.wait_ready(), 5)
+    reader, writer = await h2_connect(runner.local_addr()[1])
+    writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 1, request_block(b"GET", b"/while")))
+    await asyncio.sleep(0.1)
+    stop(runner)
+
+    frames = await frames_until(reader, lambda kind, *_: kind == GOAWAY)
+    assert struct.unpack(">II", frames[-1][3][:8]) == (1, NO_ERROR), frames[-1]
+    rest = []
+    try:
+        while True:
+            rest.append(await read_frame(reader))
+    except asyncio.IncompleteReadError:
+        pass
+    writer.close()
+    await asyncio.wait_for(task, 5)
+    runner.close()
+    return [f for f in rest if f[2] == 1]
+
+
+async def main():
+    async with serving(handler, max_body_size=MAX_BODY) as (_, port):
+        await settings_exchange(port)
+        await window(port)
+        await reset(port)
+        await dynamic_table(port)
+
+    # stop() cancels the stream in flight, graceful_shutdown() answers it first
+    assert await going_away(lambda runner: runner.stop()) == []
+    answered = await going_away(lambda runner: runner.graceful_shutdown())
+    assert [f[0] for f in answered] == [HEADERS, DATA] and answered[-1][3] == b"0", answered
+
+
+run(main)
+print("h2 conformance ok")
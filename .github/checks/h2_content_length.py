@@ -0,0 +1,67 @@
+"""
+synth-382: content-length over HTTP/2. It's held to the same digits-only
+parse as HTTP/1.1, anything else resets the stream with PROTOCOL_ERROR
+without the callback seeing it, and a length over `max_body_size` is a
+413. Spoken over cleartext with prior knowledge, frame by frame.
+"""
+import struct
+
+from support import DATA, END_HEADERS, END_STREAM, GOAWAY, HEADERS, RST_STREAM, frame, h2_connect, literal, read_frame, run, serving
+
+
+PROTOCOL_ERROR = 0x1
+
+MALFORMED = [b"+5", b" 5", b"5 ", b"5_0", b"0x5", b"five", b"", b"18446744073709551616"]
+seen = []
+
+
+async def handler(request):
+    seen.append(request.body)
+    return "ok"
+
+
+def request_headers(*lengths):
+    # POST, /, http, then :authority and each content-length as literals never indexed
+    block = b"\x83\x84\x86\x10" + literal(b":authority") + literal(b"check")
+    for length in lengths:
+        block += b"\x10" + literal(b"content-length") + literal(length)
+    return block
+
+
+async def answer(reader, writer, stream, lengths, body=b"hello"):
+    """What the server does with a POST carrying `lengths`, a status or the RST_STREAM's error code."""
+    writer.write(frame(HEADERS, END_HEADERS, stream, request_headers(*lengths)))
+    if body is not None:
+        writer.write(frame(DATA, END_STREAM, stream, body))
+    while True:
+        kind, _, on, payload = await read_frame(reader)
+        assert kind != GOAWAY, payload
+        if on != stream:
+            continue
+        if kind == RST_STREAM:
+            return "reset", struct.unpack(">I", payload)[0]
+        if kind == HEADERS:
+            block = payload[1:] if payload[:1] == b"\x20" else payload
+            return "status", 200 if block[:1] == b"\x88" else int(block[2:5])
+
+
+async def main():
+    async with serving(handler, max_body_size=1024) as (_, port):
+        reader, writer = await h2_connect(port)
+        streams = iter(range(1, 1000, 2))
+
+        for length in MALFORMED:
+            assert await answer(reader, writer, next(streams), [length]) == ("reset", PROTOCOL_ERROR), length
+        assert await answer(reader, writer, next(streams), [b"5", b"5"]) == ("reset", PROTOCOL_ERROR)
+        assert await answer(reader, writer, next(streams), [b"4"]) == ("reset", PROTOCOL_ERROR)
+        assert seen == [], seen
+
+        assert await answer(reader, writer, next(streams), [b"5"]) == ("status", 200)
+        # answered on the head alone, before the client sends any of the body
+        assert await answer(reader, writer, next(streams), [b"2048"], body=None) == ("status", 413)
+        assert seen == [b"hello"], seen
+        writer.close()
+
+
+run(main)
+print("h2 content length ok")
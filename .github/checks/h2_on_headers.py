@@ -0,0 +1,76 @@
+"""
+synth-382: on_headers over HTTP/2. Each stream's head goes through the
+hook the way an HTTP/1 head does: a response it returns is the answer and
+the callback never sees the request, async or not, and the
+`max_body_size` it leaves is the stream's limit, above the runner's as
+well as below it, for a body that arrived while the hook was deciding
+too. A refused stream doesn't hold up the others on the connection.
+"""
+import asyncio
+
+import async_rust
+
+from support import DATA, END_HEADERS, END_STREAM, HEADERS, frame, h2_connect, h2_response, request_block, run, serving
+
+
+MAX_BODY = 1024
+seen = []
+
+
+async def on_headers(meta):
+    if meta.path == "/slow":
+        await asyncio.sleep(0.2)
+    if meta.headers.get("authorization") != "secret":
+        return async_rust.HTTPResponse(b"who are you?", status=401)
+    if meta.path == "/upload":
+        meta.max_body_size = 4 * MAX_BODY
+    if meta.path == "/tiny":
+        meta.max_body_size = 4
+
+
+async def handler(request):
+    seen.append((request.path, len(request.body)))
+    return "%d" % len(request.body)
+
+
+async def send(reader, writer, stream, path, body=None, authorized=True):
+    fields = [(b"authorization", b"secret")] if authorized else []
+    writer.write(frame(HEADERS, END_HEADERS | (0 if body else END_STREAM), stream, request_block(b"POST" if body else b"GET", path, *fields)))
+    if body:
+        writer.write(frame(DATA, END_STREAM, stream, body))
+    status, _, response = await h2_response(reader, stream)
+    return status, response
+
+
+async def main():
+    async with serving(handler, on_headers=on_headers, max_body_size=MAX_BODY) as (_, port):
+        reader, writer = await h2_connect(port)
+
+        assert await send(reader, writer, 1, b"/", authorized=False) == (401, b"who are you?")
+        assert await send(reader, writer, 3, b"/", body=b"x" * 10, authorized=False) == (401, b"who are you?")
+        assert await send(reader, writer, 5, b"/slow", authorized=False) == (401, b"who are you?")
+        assert seen == [], seen
+
+        assert await send(reader, writer, 7, b"/") == (200, b"0")
+        assert await send(reader, writer, 9, b"/slow", body=b"x" * 10) == (200, b"10")
+        # the body's all in before the hook's done with its head, it's held to what the hook leaves
+        assert await send(reader, writer, 11, b"/upload", body=b"x" * (2 * MAX_BODY)) == (200, b"%d" % (2 * MAX_BODY))
+        assert await send(reader, writer, 13, b"/", body=b"x" * (2 * MAX_BODY)) == (413, b"")
+        assert await send(reader, writer, 15, b"/tiny", body=b"x" * 10) == (413, b"")
+
+        # a stream waiting on the hook, the one after it's refused by it meanwhile and answered first
+        writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 17, request_block(b"GET", b"/slow", (b"authorization", b"secret"))))
+        writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 19, request_block(b"GET", b"/slow")))
+        writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 21, request_block(b"GET", b"/")))
+        early = []
+        assert (await h2_response(reader, 21, early))[0] == 401
+        assert not any(stream in (17, 19) for _, _, stream, _ in early), early
+        assert (await h2_response(reader, 19))[0] == 401
+        assert (await h2_response(reader, 17))[::2] == (200, b"0")
+        writer.close()
+
+    assert seen == [("/", 0), ("/slow", 10), ("/upload", 2 * MAX_BODY), ("/slow", 0)], seen
+
+
+run(main)
+print("h2 on_headers ok")
@@ -0,0 +1,111 @@
+"""
+synth-382: streamed responses over HTTP/2. An async generator's body goes
+out as DATA a chunk at a time as it's produced, the last one ending the
+stream, and an EventSourceResponse's events the same. A generator that
+raises after its head is out has its stream reset with INTERNAL_ERROR
+while the connection carries on, a HEAD gets the head alone, and an
+Upgrade, which HTTP/2 has no way of doing, is a 500. A runner with
+`stream_request_body` doesn't speak HTTP/2 at all, the preface is
+answered as the HTTP/1 request it isn't.
+"""
+import asyncio
+
+import async_rust
+
+from support import (
+    DATA, END_HEADERS, END_STREAM, H2_PREFACE, HEADERS, RST_STREAM, exchange, frame, h2_connect, h2_response,
+    read_frame, request_block, response_fields, run, serving, status,
+)
+
+
+INTERNAL_ERROR = 0x2
+
+
+async def chunks():
+    for chunk in (b"one", b"two", b"three"):
+        yield chunk
+        await asyncio.sleep(0.05)
+
+
+async def broken():
+    yield b"half"
+    await asyncio.sleep(0.01)
+    raise LookupError("the rest went missing")
+
+
+async def events():
+    yield "first"
+    yield {"event": "second", "data": "two\nlines"}
+
+
+async def handler(request):
+    if request.path == "/events":
+        return async_rust.EventSourceResponse(events())
+    if request.path == "/broken":
+        return broken()
+    if request.path == "/upgrade":
+        return async_rust.Upgrade(async_rust.HTTPResponse(status=101), asyncio.Protocol)
+    return chunks()
+
+
+async def frames_of(reader, stream):
+    """Every HEADERS and DATA frame on `stream` up to its END_STREAM or RST_STREAM."""
+    frames = []
+    while True:
+        kind, flags, on, payload = await read_frame(reader)
+        if on != stream:
+            continue
+        frames.append((kind, flags, payload))
+        if kind == RST_STREAM or flags & END_STREAM:
+            return frames
+
+
+async def main():
+    unhandled = []
+    asyncio.get_event_loop().set_exception_handler(lambda loop, context: unhandled.append(context))
+
+    async with serving(handler) as (_, port):
+        reader, writer = await h2_connect(port)
+
+        writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 1, request_block(b"GET", b"/")))
+        frames = await frames_of(reader, 1)
+        assert [kind for kind, _, _ in frames] == [HEADERS, DATA, DATA, DATA, DATA], frames
+        assert [payload for kind, _, payload in frames if kind == DATA] == [b"one", b"two", b"three", b""], frames
+        assert [flags & END_STREAM for _, flags, _ in frames] == [0, 0, 0, 0, END_STREAM], frames
+        fields = dict(response_fields(frames[0][2]))
+        assert fields[b":status"] == b"200" and b"content-length" not in fields, fields
+
+        writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 3, request_block(b"GET", b"/events")))
+        code, fields, body = await h2_response(reader, 3)
+        assert code == 200 and fields["content-type"].startswith("text/event-stream"), fields
+        assert body == b"data: first\n\nevent: second\ndata: two\ndata: lines\n\n", body
+
+        writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 5, request_block(b"GET", b"/broken")))
+        frames = await frames_of(reader, 5)
+        assert [kind for kind, _, _ in frames] == [HEADERS, DATA, RST_STREAM], frames
+        assert frames[-1][2] == INTERNAL_ERROR.to_bytes(4, "big"), frames
+
+        writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 7, request_block(b"HEAD", b"/")))
+        frames = await frames_of(reader, 7)
+        assert len(frames) == 1 and frames[0][:2] == (HEADERS, END_HEADERS | END_STREAM), frames
+
+        writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 9, request_block(b"GET", b"/upgrade")))
+        assert (await h2_response(reader, 9))[0] == 500
+
+        # and the connection's still good after all of that
+        writer.write(frame(HEADERS, END_HEADERS | END_STREAM, 11, request_block(b"GET", b"/")))
+        assert (await h2_response(reader, 11))[::2] == (200, b"onetwothree")
+        writer.close()
+
+    async with serving(handler, stream_request_body=True) as (runner, port):
+        assert runner.config()["http2"] is False
+        response = await exchange(port, H2_PREFACE)
+        assert status(response) is not None, response
+
+    # the generator's error and the Upgrade went to the loop like a callback's, nothing else did
+    reported = [str(context.get("exception")) for context in unhandled]
+    assert len(reported) == 2 and "LookupError" in reported[0] and "Upgrade" in reported[1], reported
+
+
+run(main)
+print("h2 streaming ok")
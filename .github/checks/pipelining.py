@@ -0,0 +1,43 @@
+"""
+synth-375: pipelined requests. Three GETs in a single send() get three
+responses, in the order they were sent, even when the first one's
+handler is the slowest, without the client sending another byte. One
+that closes with the last request gets everything before the close.
+"""
+import asyncio
+
+from support import closed, read_response, run, serving
+
+
+async def handler(request):
+    delay = {"/one": 0.2, "/two": 0.0, "/three": 0.1}.get(request.path, 0)
+    await asyncio.sleep(delay)
+    return request.path
+
+
+def get(path, *headers):
+    return b"\r\n".join([b"GET %s HTTP/1.1" % path, b"Host: check"] + list(headers)) + b"\r\n\r\n"
+
+
+async def main():
+    async with serving(handler) as (_, port):
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        writer.write(get(b"/one") + get(b"/two") + get(b"/three"))
+        bodies = [(await read_response(reader))[::2] for _ in range(3)]
+        assert bodies == [(200, b"/one"), (200, b"/two"), (200, b"/three")], bodies
+
+        # the connection's still in step for the next one
+        writer.write(get(b"/four"))
+        assert (await read_response(reader))[::2] == (200, b"/four")
+        writer.close()
+
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        writer.write(get(b"/one") + get(b"/two") + get(b"/three", b"Connection: close"))
+        bodies = [(await read_response(reader))[2] for _ in range(3)]
+        assert bodies == [b"/one", b"/two", b"/three"], bodies
+        assert await closed(reader)
+        writer.close()
+
+
+run(main)
+print("pipelining ok")
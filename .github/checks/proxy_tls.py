@@ -0,0 +1,147 @@
+"""
+synth-395: the PROXY, TLS and HTTP stages of a connection each get
+exactly their own bytes, however they're cut into TCP segments. For
+proxy + tls, proxy only, tls only and neither, the preamble is sent a
+piece at a time: a PROXY header (v1, and v2 with a TLV) cut in the
+middle and sent with the start of what follows it, a ClientHello and a
+request cut inside themselves. With TLS the client's Finished and two
+pipelined requests go together in one segment, the ClientHello driven
+through a MemoryBIO so where each segment ends is ours to choose. Both
+requests are answered, with the address the PROXY header gave and the
+TLS version.
+"""
+import asyncio
+import shutil
+import socket
+import ssl
+import struct
+import tempfile
+
+import async_rust
+
+from support import run, self_signed, serving, skip
+
+
+GAP = 0.02
+REQUESTS = b"GET /one HTTP/1.1\r\nHost: check\r\n\r\nGET /two HTTP/1.1\r\nHost: check\r\nConnection: close\r\n\r\n"
+
+V1 = b"PROXY TCP4 203.0.113.7 192.0.2.1 56324 443\r\n"
+V2 = (
+    b"\r\n\r\n\x00\r\nQUIT\n" + bytes([0x21, 0x11]) + struct.pack(">H", 12 + 7)
+    + socket.inet_aton("203.0.113.7") + socket.inet_aton("192.0.2.1") + struct.pack(">HH", 56324, 443)
+    + bytes([0x04]) + struct.pack(">H", 4) + b"noop"
+)
+
+
+async def handler(request):
+    version = request.tls.version if request.tls else "plain"
+    return "%s %s:%d %s" % (request.path, request.client[0], request.client[1], version)
+
+
+async def send(writer, segments):
+    """Each of `segments` in a TCP segment of its own, Nagle's off and there's a gap between them."""
+    for segment in segments:
+        writer.write(segment)
+        await writer.drain()
+        await asyncio.sleep(GAP)
+
+
+def split(proxy, stage):
+    """The PROXY header cut in two, its end sent with the next stage's start, then the stage in pieces."""
+    if not proxy:
+        return [stage[:5], stage[5:40], stage[40:]]
+    half = len(proxy) // 2
+    return [proxy[:half], proxy[half:] + stage[:5], stage[5:40], stage[40:]]
+
+
+async def connect(port):
+    reader, writer = await asyncio.open_connection("127.0.0.1", port)
+    writer.get_extra_info("socket").setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
+    return reader, writer
+
+
+async def plain(port, proxy):
+    reader, writer = await connect(port)
+    await send(writer, split(proxy, REQUESTS))
+    response = await asyncio.wait_for(reader.read(), 5)
+    writer.close()
+    return response
+
+
+async def tls(port, proxy):
+    context = ssl.create_default_context()
+    context.check_hostname = False
+    context.verify_mode = ssl.CERT_NONE
+    incoming, outgoing = ssl.MemoryBIO(), ssl.MemoryBIO()
+    session = context.wrap_bio(incoming, outgoing, server_hostname="localhost")
+
+    reader, writer = await connect(port)
+    try:
+        session.do_handshake()
+    except ssl.SSLWantReadError:
+        pass
+    await send(writer, split(proxy, outgoing.read()))
+
+    while True:
+        try:
+            session.do_handshake()
+            break
+        except ssl.SSLWantReadError:
+            data = await asyncio.wait_for(reader.read(65536), 5)
+            assert data, "the server closed during the handshake"
+            incoming.write(data)
+
+    # the client's Finished and both requests in the one segment
+    session.write(REQUESTS)
+    writer.write(outgoing.read())
+
+    response = b""
+    while True:
+        try:
+            data = session.read(65536)
+            if not data:
+                break
+            response += data
+            continue
+        except ssl.SSLWantReadError:
+            pass
+        except (ssl.SSLZeroReturnError, ssl.SSLEOFError):
+            break
+        data = await asyncio.wait_for(reader.read(65536), 5)
+        if not data:
+            break
+        incoming.write(data)
+    writer.close()
+    return response
+
+
+async def main(directory):
+    config = async_rust.TLSConfig(*self_signed(directory))
+    cases = [
+        ("proxy v1 + tls", {"proxy_protocol": True, "tls": config}, tls, V1),
+        ("proxy v2 + tls", {"proxy_protocol": True, "tls": config}, tls, V2),
+        ("proxy v1", {"proxy_protocol": True}, plain, V1),
+        ("proxy v2", {"proxy_protocol": True}, plain, V2),
+        ("tls", {"tls": config}, tls, b""),
+        ("neither", {}, plain, b""),
+    ]
+
+    for name, options, client, proxy in cases:
+        async with serving(handler, **options) as (_, port):
+            response = await client(port, proxy)
+
+        bodies = [part.rsplit(b"\r\n\r\n", 1)[-1] for part in response.split(b"HTTP/1.1 ")[1:]]
+        assert len(bodies) == 2 and response.count(b"HTTP/1.1 200 OK") == 2, (name, response)
+        for path, body in zip((b"/one", b"/two"), bodies):
+            got_path, client_addr, version = body.split(b" ")
+            assert got_path == path, (name, body)
+            assert client_addr.startswith(b"203.0.113.7:56324" if proxy else b"127.0.0.1:"), (name, body)
+            assert version == (b"TLSv1.3" if client is tls else b"plain"), (name, body)
+
+
+if shutil.which("openssl") is None:
+    skip("needs openssl to make a certificate")
+
+with tempfile.TemporaryDirectory() as directory:
+    run(lambda: main(directory))
+print("proxy tls ok")
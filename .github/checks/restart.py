@@ -0,0 +1,66 @@
+"""
+synth-323: restarting a stopped runner. One runner is stopped and started
+again twice on the port it first got, a client is served in each round
+and in between the port refuses connections. On linux the native reactor
+goes through the same rounds.
+"""
+import asyncio
+import sys
+
+import async_rust
+
+from support import exchange, run, status
+
+
+ROUNDS = 3
+
+
+async def handler(request):
+    return "ok"
+
+
+async def refused(port):
+    try:
+        _, writer = await asyncio.open_connection("127.0.0.1", port)
+    except ConnectionRefusedError:
+        return True
+    writer.close()
+    return False
+
+
+async def rounds(**options):
+    runner = async_rust.AsyncServerRunner("127.0.0.1:0", handler, access_log=False, **options)
+    port = None
+    for round_ in range(ROUNDS):
+        if round_:
+            runner.start()
+        task = asyncio.ensure_future(runner)
+        await asyncio.wait_for(runner.wait_ready(), 5)
+        port = port or runner.local_addr()[1]
+        assert runner.local_addr()[1] == port, (round_, runner.local_addr(), port)
+
+        try:
+            runner.start()
+        except RuntimeError:
+            pass
+        else:
+            raise AssertionError("start() on a running runner didn't raise")
+
+        response = await exchange(port, b"GET / HTTP/1.1\r\nHost: check\r\nConnection: close\r\n\r\n")
+        assert status(response) == 200, (round_, response)
+
+        runner.stop()
+        await asyncio.wait_for(task, 5)
+        assert await refused(port), "round %d left the port accepting" % round_
+
+    runner.close()
+
+
+async def main():
+    await rounds()
+    if sys.platform.startswith("linux"):
+        await rounds(reactor="native")
+
+
+run(main)
+print("restart ok")
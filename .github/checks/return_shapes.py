@@ -0,0 +1,99 @@
+"""
+synth-391: what a handler can return instead of an HTTPResponse. bytes
+and str are a 200 of them, `(status, body)` and `(status, headers, body)`
+take their headers as a dict or a list of pairs, str or bytes. Anything
+else is a 500 with the handler's TypeError or ValueError reported to the
+loop: None, a bool status, a body on a 204 or 304, a header value that's
+neither str nor bytes, and one with a line break in it, which
+HTTPResponse itself refuses too.
+"""
+import asyncio
+
+import async_rust
+
+from support import read_response, run, serving
+
+
+OK = {
+    "/bytes": (b"raw", 200, {}, b"raw"),
+    "/str": ("café", 200, {}, "café".encode()),
+    "/pair": ((201, "made"), 201, {}, b"made"),
+    "/dict": ((202, {"X-One": "1", "X-Two": b"2"}, b"body"), 202, {"x-one": "1", "x-two": "2"}, b"body"),
+    "/list": ((200, [("X-One", "1"), ("Set-Cookie", "a=1")], "body"), 200, {"x-one": "1", "set-cookie": "a=1"}, b"body"),
+    "/204": ((204, b""), 204, {}, b""),
+    "/304": ((304, {"ETag": '"v1"'}, ""), 304, {"etag": '"v1"'}, b""),
+}
+
+BAD = {
+    "/none": (None, TypeError, "the handler returned NoneType"),
+    "/int": (200, TypeError, "the handler returned int"),
+    "/long": ((200, {}, b"", b""), TypeError, "the handler returned tuple"),
+    "/bool": ((True, b"yes"), TypeError, "the returned status must be an int, not bool"),
+    "/range": ((99, b"low"), ValueError, "the returned status must be from 100 to 599, not 99"),
+    "/body": ((200, 5), TypeError, "the returned body must be bytes or str, not int"),
+    "/204-body": ((204, b"nope"), ValueError, "a 204 response can't have a body"),
+    "/304-body": ((304, {}, "nope"), ValueError, "a 304 response can't have a body"),
+    "/headers": ((200, "X-One: 1", b""), TypeError, "the returned headers must be a dict or a list of (name, value) pairs, not str"),
+    "/value": ((200, {"X-One": 1}, b""), TypeError, "the returned header 'X-One' must be str or bytes, not int"),
+    "/none-value": ((200, [("X-One", None)], b""), TypeError, "the returned header 'X-One' must be str or bytes, not NoneType"),
+    "/name": ((200, {1: "one"}, b""), TypeError, "a returned header name must be str, not int"),
+    "/crlf": ((200, {"X-One": "1\r\nX-Injected: 2"}, b""), ValueError, "the header 'X-One' can't contain line breaks"),
+    "/lf-bytes": ((200, [("X-One", b"1\nX-Injected: 2")], b""), ValueError, "the header 'X-One' can't contain line breaks"),
+}
+
+
+def handler(request):
+    if request.path in OK:
+        return OK[request.path][0]
+    return BAD[request.path][0]
+
+
+def refused(call, message):
+    try:
+        call()
+    except ValueError as e:
+        assert str(e) == message, e
+    else:
+        assert False, "%s wasn't refused" % message
+
+
+async def main():
+    unhandled = []
+    asyncio.get_event_loop().set_exception_handler(lambda loop, context: unhandled.append(context))
+
+    async with serving(handler) as (_, port):
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        for path, (_, code, headers, body) in OK.items():
+            writer.write(b"GET %s HTTP/1.1\r\nHost: check\r\n\r\n" % path.encode())
+            got, got_headers, got_body = await read_response(reader)
+            assert (got, got_body) == (code, body), (path, got, got_body)
+            assert headers.items() <= got_headers.items(), (path, got_headers)
+        writer.close()
+
+        # a handler's error closes its connection
+        for path in BAD:
+            reader, writer = await asyncio.open_connection("127.0.0.1", port)
+            writer.write(b"GET %s HTTP/1.1\r\nHost: check\r\n\r\n" % path.encode())
+            got, got_headers, _ = await read_response(reader)
+            assert got == 500 and "x-injected" not in got_headers, (path, got, got_headers)
+            writer.close()
+
+    reported = [context["exception"] for context in unhandled]
+    assert len(reported) == len(BAD), reported
+    for (path, (_, kind, message)), exception in zip(BAD.items(), reported):
+        cause = exception.__cause__
+        assert isinstance(exception, async_rust.HandlerError) and isinstance(cause, kind) and str(cause).startswith(message), (path, repr(cause))
+
+    # and made by hand, every way a header gets into a response
+    response = async_rust.HTTPResponse(b"")
+    refused(lambda: response.set_header("X-One", "1\r\nX-Injected: 2"), "the header 'X-One' can't contain line breaks")
+    refused(lambda: response.add_header("X-One", "1\n"), "the header 'X-One' can't contain line breaks")
+    refused(lambda: response.add_header("X-One\r\n", "1"), "the header 'X-One\\r\\n' can't contain line breaks")
+    refused(lambda: async_rust.HTTPResponse(b"", headers={"X-One": "\r\n"}), "the header 'X-One' can't contain line breaks")
+    refused(lambda: async_rust.HTTPResponse.text("", headers={"X-One": "\0"}), "the header 'X-One' can't contain line breaks")
+    response.set_header("X-One", "fine")
+    assert response.headers == {"X-One": "fine"}, response.headers
+
+
+run(main)
+print("return shapes ok")
@@ -0,0 +1,129 @@
+"""
+synth-401: Router matching, over a route set with exact segments,
+`{name}` segments (the router's only wildcard, one whole segment each)
+and routes that end with and without a `/`. Every row of the table is a
+request sent to three routers over the same routes: a plain one, one
+with `redirect_slashes` and one with the `route_not_found` and
+`method_not_allowed` hooks. Each column is what that router answers,
+the status, the body and the headers that have to be there.
+
+What it covers: exact segments winning over `{name}` ones without
+backtracking, a 405 with the path's methods in `Allow`, `HEAD` answered
+by `GET` with the body dropped unless it has a handler of its own, the
+308 to the path with its `/` toggled (query kept) and only when that
+matches, and `OPTIONS *` with every method.
+"""
+import asyncio
+
+import async_rust
+
+from support import read_response, run, serving
+
+
+def route(name):
+    def handler(request):
+        params = ",".join("%s=%s" % item for item in sorted(request.path_params.items()))
+        return "%s %s" % (name, params) if params else name
+    return handler
+
+
+ROUTES = [
+    ("GET", "/", "index"),
+    ("GET", "/users", "users"),
+    ("POST", "/users", "create"),
+    ("GET", "/users/me", "me"),
+    ("GET", "/users/{id}", "user"),
+    ("PUT", "/users/{id}", "replace"),
+    ("GET", "/users/{id}/posts/{post}", "post"),
+    ("GET", "/users/me/settings", "settings"),
+    ("GET", "/docs/", "docs"),
+    ("GET", "/head", "head-get"),
+    ("HEAD", "/head", "head-head"),
+]
+
+
+def not_found(request):
+    return async_rust.HTTPResponse("no %s here" % request.path, status=404)
+
+
+async def not_allowed(request, allowed):
+    return async_rust.HTTPResponse("%s isn't one of %s" % (request.method, "/".join(allowed)), status=405, headers={
+        "Allow": ", ".join(allowed),
+    })
+
+
+ALLOW_USERS = {"allow": "GET, POST, HEAD"}
+ALLOW_USER = {"allow": "GET, PUT, HEAD"}
+
+# (method, target): (plain, redirect_slashes, hooks), each (status, body, headers)
+TABLE = {
+    ("GET", "/"): [(200, b"index", {})] * 3,
+    ("GET", "/users"): [(200, b"users", {})] * 3,
+    ("POST", "/users"): [(200, b"create", {})] * 3,
+    ("GET", "/users/7"): [(200, b"user id=7", {})] * 3,
+    ("PUT", "/users/7"): [(200, b"replace id=7", {})] * 3,
+    ("GET", "/users/me"): [(200, b"me", {})] * 3,
+    ("GET", "/users/7/posts/9"): [(200, b"post id=7,post=9", {})] * 3,
+    ("GET", "/users/me/settings"): [(200, b"settings", {})] * 3,
+    # `me` is taken as the exact segment, the router doesn't go back to try `{id}`
+    ("GET", "/users/me/posts/9"): [(404, b"", {}), (404, b"", {}), (404, b"no /users/me/posts/9 here", {})],
+    # one segment each, a `{name}` doesn't reach across a `/`
+    ("GET", "/users/7/extra"): [(404, b"", {}), (404, b"", {}), (404, b"no /users/7/extra here", {})],
+    ("GET", "/nowhere"): [(404, b"", {}), (404, b"", {}), (404, b"no /nowhere here", {})],
+    ("DELETE", "/users"): [(405, b"", ALLOW_USERS), (405, b"", ALLOW_USERS), (405, b"DELETE isn't one of GET/POST/HEAD", ALLOW_USERS)],
+    ("DELETE", "/users/7"): [(405, b"", ALLOW_USER), (405, b"", ALLOW_USER), (405, b"DELETE isn't one of GET/PUT/HEAD", ALLOW_USER)],
+    # only the redirecting router toggles the slash, and keeps the query
+    ("GET", "/users/"): [(404, b"", {}), (308, b"", {"location": "/users"}), (404, b"no /users/ here", {})],
+    ("GET", "/users/7/?page=2"): [(404, b"", {}), (308, b"", {"location": "/users/7?page=2"}), (404, b"no /users/7/ here", {})],
+    ("GET", "/docs"): [(404, b"", {}), (308, b"", {"location": "/docs/"}), (404, b"no /docs here", {})],
+    ("GET", "/docs/"): [(200, b"docs", {})] * 3,
+    # the 308 is only for a path that matches once it's toggled
+    ("GET", "/nowhere/"): [(404, b"", {}), (404, b"", {}), (404, b"no /nowhere/ here", {})],
+    # HEAD falls back to GET with the body dropped, a HEAD route of its own wins
+    ("HEAD", "/users/7"): [(200, b"", {"content-length": "9"})] * 3,
+    ("HEAD", "/head"): [(200, b"", {"content-length": "9"})] * 3,
+    ("GET", "/head"): [(200, b"head-get", {})] * 3,
+    ("HEAD", "/nowhere"): [(404, b"", {})] * 3,
+    ("OPTIONS", "*"): [(200, b"", {"allow": "GET, HEAD, OPTIONS, POST, PUT"})] * 3,
+}
+
+
+def router(**options):
+    router = async_rust.Router(**options)
+    for method, path, name in ROUTES:
+        router.add_route(method, path, route(name))
+    return router
+
+
+async def answers(router, column):
+    wrong = []
+    async with serving(router) as (_, port):
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        for (method, target), expected in TABLE.items():
+            writer.write(b"%s %s HTTP/1.1\r\nHost: check\r\n\r\n" % (method.encode(), target.encode()))
+            code, headers, body = await read_response(reader, head=method == "HEAD")
+            want_code, want_body, want_headers = expected[column]
+            if (code, body) != (want_code, want_body) or not want_headers.items() <= headers.items():
+                wrong.append((method, target, code, body, headers))
+        writer.close()
+    return wrong
+
+
+async def main():
+    routers = [router(), router(redirect_slashes=True), router(route_not_found=not_found, method_not_allowed=not_allowed)]
+    for column, each in enumerate(routers):
+        wrong = await answers(each, column)
+        assert wrong == [], (column, wrong)
+
+    # and a route set that can't be
+    for method, path in [("GET", "/users"), ("GET", "/users/{other}"), ("GET", "users")]:
+        try:
+            routers[0].add_route(method, path, route("again"))
+        except ValueError:
+            pass
+        else:
+            assert False, "%s %s was added" % (method, path)
+
+
+run(main)
+print("router ok")
@@ -0,0 +1,68 @@
+"""
+synth-296: Ctrl-C. A server awaited in a subprocess gets SIGINT, the
+KeyboardInterrupt comes out of the await without a Rust panic and the
+port is free to bind again within a second.
+"""
+import signal
+import socket
+import subprocess
+import sys
+import time
+
+from support import skip
+
+
+SERVER = """
+import asyncio, sys
+import async_rust
+
+async def handler(request):
+    return "ok"
+
+async def main():
+    runner = async_rust.AsyncServerRunner(sys.argv[1], handler, access_log=False)
+    task = asyncio.ensure_future(runner)
+    await runner.wait_ready()
+    print("ready", flush=True)
+    await task
+
+asyncio.run(main())
+"""
+
+
+def free_port():
+    with socket.socket() as sock:
+        sock.bind(("127.0.0.1", 0))
+        return sock.getsockname()[1]
+
+
+def bindable(port):
+    with socket.socket() as sock:
+        try:
+            sock.bind(("127.0.0.1", port))
+        except OSError:
+            return False
+        return True
+
+
+if sys.platform == "win32":
+    skip("SIGINT can't be sent to just one process on windows")
+
+port = free_port()
+server = subprocess.Popen(
+    [sys.executable, "-c", SERVER, "127.0.0.1:%d" % port],
+    stdout=subprocess.PIPE, stderr=subprocess.PIPE,
+)
+assert server.stdout.readline() == b"ready\n"
+assert not bindable(port)
+
+interrupted = time.monotonic()
+server.send_signal(signal.SIGINT)
+_, stderr = server.communicate(timeout=5)
+while not bindable(port):
+    assert time.monotonic() - interrupted < 1, "the port is still bound a second after SIGINT"
+    time.sleep(0.01)
+
+assert b"KeyboardInterrupt" in stderr, stderr
+assert b"panicked" not in stderr, stderr
+print("sigint ok in %.0fms" % ((time.monotonic() - interrupted) * 1000))
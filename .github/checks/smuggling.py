@@ -0,0 +1,72 @@
+"""
+synth-339: request smuggling. Framing two servers could read differently
+(Transfer-Encoding with Content-Length, Content-Lengths that disagree, a
+coding other than chunked, a chunk size that isn't plain hex) is a 400
+and the connection closes, so a request hidden behind it is never run.
+Well-formed chunked bodies, extensions and all, still get through. Both
+reactors are checked where the native one runs.
+"""
+import sys
+
+from support import exchange, run, serving, status
+
+
+SMUGGLED = b"GET /smuggled HTTP/1.1\r\nHost: check\r\nConnection: close\r\n\r\n"
+
+FRAMING = {
+    "te and cl": b"Transfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n0\r\n\r\n",
+    "cl and te": b"Content-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+    "two cls": b"Content-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+    "two cls in one": b"Content-Length: 5, 6\r\n\r\nhello!",
+    "gzip": b"Transfer-Encoding: gzip\r\n\r\n0\r\n\r\n",
+    "chunked then gzip": b"Transfer-Encoding: chunked, gzip\r\n\r\n0\r\n\r\n",
+    "chunked twice": b"Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+    "cl with a sign": b"Content-Length: +5\r\n\r\nhello",
+    "cl past u64": b"Content-Length: 18446744073709551616\r\n\r\nhello",
+}
+
+# chunk-size lines, each followed by the same five bytes of data and the last chunk
+BAD_SIZES = [
+    b"+5", b"-5", b" 5", b"\t5", b"5 ", b"0x5", b"5\x0b", b"5\x0c;ext", b"5,ext", b"5 x", b"", b";ext",
+    b"5\x00", b"1" + b"0" * 16, b"f" * 17,
+]
+GOOD_SIZES = [b"5", b"05", b"5;ext", b"5;ext=1;other=\"a b\"", b"5 ;ext", b"5\t;ext"]
+
+
+async def handler(request):
+    return "%s %d" % (request.path, len(request.body))
+
+
+def chunked(size):
+    return b"Transfer-Encoding: chunked\r\n\r\n" + size + b"\r\nhello\r\n0\r\n\r\n"
+
+
+async def send(port, framing):
+    response = await exchange(port, b"POST / HTTP/1.1\r\nHost: check\r\n" + framing + SMUGGLED)
+    return response, response.count(b"HTTP/1.1 ")
+
+
+async def corpus(**options):
+    async with serving(handler, **options) as (_, port):
+        for name, framing in FRAMING.items():
+            response, responses = await send(port, framing)
+            assert status(response) == 400 and responses == 1, (name, response)
+
+        for size in BAD_SIZES:
+            response, responses = await send(port, chunked(size))
+            assert status(response) == 400 and responses == 1, (size, response)
+
+        for size in GOOD_SIZES:
+            response, responses = await send(port, chunked(size))
+            assert status(response) == 200 and responses == 2, (size, response)
+            assert b"/ 5" in response and b"/smuggled 0" in response, (size, response)
+
+
+async def main():
+    await corpus()
+    if sys.platform.startswith("linux"):
+        await corpus(reactor="native")
+
+
+run(main)
+print("smuggling ok")
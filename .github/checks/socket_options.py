@@ -0,0 +1,116 @@
+"""
+synth-370: `so_linger` and `tcp_fastopen` read back with getsockopt.
+Each accepted socket, seen through get_extra_info("socket") in a raw
+callback, has `SO_LINGER` as it was set, with `so_linger=0` and a
+non-zero one and for each accept mode. The native reactor can't take a
+raw callback, there the socket's found by its peer in /proc/self/fd
+while an HTTP handler has it open. Without the option it's left off.
+The listeners from `runner.sockets` have `TCP_FASTOPEN` set, again once
+`start()` has rebound them after a stop. Values out of range are a
+ValueError.
+"""
+import asyncio
+import os
+import socket
+import struct
+import sys
+
+import async_rust
+
+from support import run, serving
+
+
+seen = []
+
+
+async def raw(reader, writer):
+    sock = writer.get_extra_info("socket")
+    seen.append(struct.unpack("ii", sock.getsockopt(socket.SOL_SOCKET, socket.SO_LINGER, 8)))
+    sock.close()
+    writer.write(b"ok")
+    await writer.drain()
+    writer.close()
+
+
+def accepted(peer):
+    """SO_LINGER of the socket among our fds whose peer is `peer`."""
+    for fd in os.listdir("/proc/self/fd"):
+        try:
+            sock = socket.socket(fileno=os.dup(int(fd)))
+        except OSError:
+            continue
+        try:
+            if sock.type == socket.SOCK_STREAM and sock.getpeername() == peer:
+                return struct.unpack("ii", sock.getsockopt(socket.SOL_SOCKET, socket.SO_LINGER, 8))
+        except OSError:
+            pass
+        finally:
+            sock.close()
+
+
+async def handler(request):
+    seen.append(accepted(request.client))
+    return "ok"
+
+
+async def linger(options):
+    seen.clear()
+    native = options.get("reactor") == "native"
+    async with serving(handler, **options) if native else serving(raw, raw=True, **options) as (_, port):
+        reader, writer = await asyncio.open_connection("127.0.0.1", port)
+        if native:
+            writer.write(b"GET / HTTP/1.1\r\nHost: check\r\nConnection: close\r\n\r\n")
+        try:
+            assert (await asyncio.wait_for(reader.read(), 5)).endswith(b"ok")
+        except ConnectionResetError:
+            # what so_linger=0 is for, the close is a reset and can beat the read
+            assert options.get("so_linger") == 0, options
+        writer.close()
+    assert len(seen) == 1, seen
+    return seen[0]
+
+
+def fastopen(runner):
+    queues = []
+    for sock in runner.sockets:
+        queues.append(sock.getsockopt(socket.IPPROTO_TCP, socket.TCP_FASTOPEN))
+        sock.close()
+    return queues
+
+
+async def main():
+    modes = [("poll", {}), ("thread", {"accept_mode": "thread"})]
+    if sys.platform.startswith("linux"):
+        modes.append(("native", {"reactor": "native"}))
+
+    for mode, options in modes:
+        assert await linger(options) == (0, 0), mode
+        assert await linger(dict(options, so_linger=0)) == (1, 0), mode
+        assert await linger(dict(options, so_linger=7)) == (1, 7), mode
+
+    if sys.platform.startswith("linux"):
+        runner = async_rust.AsyncServerRunner("127.0.0.1:0", raw, raw=True, tcp_fastopen=16)
+        for _ in range(2):
+            task = asyncio.ensure_future(runner)
+            await asyncio.wait_for(runner.wait_ready(), 5)
+            assert fastopen(runner) == [16], fastopen(runner)
+            runner.stop()
+            await asyncio.wait_for(task, 5)
+            # rebound the second time round
+            runner.start()
+        runner.close()
+
+        async with serving(raw, raw=True) as (runner, _):
+            assert fastopen(runner) == [0], fastopen(runner)
+
+    for option, value in [("so_linger", -1), ("so_linger", 65536), ("tcp_fastopen", 0), ("tcp_fastopen", -1)]:
+        try:
+            async_rust.AsyncServerRunner("127.0.0.1:0", raw, raw=True, **{option: value})
+        except ValueError:
+            pass
+        else:
+            assert False, "%s=%d was taken" % (option, value)
+
+
+run(main)
+print("socket options ok")
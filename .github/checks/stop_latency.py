@@ -0,0 +1,47 @@
+"""
+synth-388: stop() wakes the runner. With a 5 second poll ceiling and
+long enough idle for the backoff to reach it, `stop()` from another task
+has the awaited runner back within a few milliseconds instead of when
+the sleep runs out, and so does `close()` on a restarted runner.
+"""
+import asyncio
+import time
+
+import async_rust
+
+from support import run
+
+
+LIMIT = 0.05
+
+
+async def handler(request):
+    return "ok"
+
+
+async def stopped_in(runner, stop):
+    task = asyncio.ensure_future(runner)
+    await asyncio.wait_for(runner.wait_ready(), 5)
+    # doubling from 1ms, two seconds idle has the runner in a sleep of about a second
+    await asyncio.sleep(2)
+
+    started = time.monotonic()
+    stop()
+    await asyncio.wait_for(task, 5)
+    return time.monotonic() - started
+
+
+async def main():
+    runner = async_rust.AsyncServerRunner("127.0.0.1:0", handler, access_log=False, max_poll_delay=5)
+
+    elapsed = await stopped_in(runner, runner.stop)
+    assert elapsed < LIMIT, "stop() took %.0fms" % (elapsed * 1000)
+
+    runner.start()
+    restarted = await stopped_in(runner, runner.close)
+    assert restarted < LIMIT, "close() after a restart took %.0fms" % (restarted * 1000)
+
+    print("stop latency ok, %.1fms and %.1fms" % (elapsed * 1000, restarted * 1000))
+
+
+run(main)
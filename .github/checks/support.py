@@ -0,0 +1,204 @@
+"""
+What the behaviour checks share. Each check is a script of its own, run
+by CI after .github/smoke.py, which fails with an AssertionError if the
+behaviour it's named for has gone and prints "skipped" when the platform
+can't run it.
+"""
+import asyncio
+import contextlib
+import os
+import struct
+import subprocess
+import sys
+
+import async_rust
+
+
+def skip(reason):
+    print("skipped:", reason)
+    sys.exit(0)
+
+
+@contextlib.asynccontextmanager
+async def serving(handler, addr="127.0.0.1:0", **options):
+    """A runner serving `handler` for the length of the block, as `(runner, port)`."""
+    options.setdefault("access_log", False)
+    runner = async_rust.AsyncServerRunner(addr, handler, **options)
+    task = asyncio.ensure_future(runner)
+    await asyncio.wait_for(runner.wait_ready(), 5)
+    try:
+        yield runner, runner.local_addr()[1]
+    finally:
+        runner.stop()
+        await asyncio.wait_for(task, 5)
+        runner.close()
+
+
+async def exchange(port, data, timeout=5):
+    """Sends `data` as it is and reads until the server closes, b"" if it resets instead."""
+    reader, writer = await asyncio.open_connection("127.0.0.1", port)
+    writer.write(data)
+    try:
+        response = await asyncio.wait_for(reader.read(), timeout)
+    except ConnectionResetError:
+        response = b""
+    writer.close()
+    return response
+
+
+def status(response):
+    """The status of a raw response, None if there wasn't one."""
+    return int(response.split(b" ", 2)[1]) if response.startswith(b"HTTP/") else None
+
+
+async def read_response(reader, timeout=5, head=False):
+    """
+    Reads one HTTP/1 response off a kept-alive connection, as `(status,
+    headers, body)` with the header names lowercased, the body by its
+    Content-Length (there's none after a `head`). None if the server
+    closes first.
+    """
+    try:
+        raw = await asyncio.wait_for(reader.readuntil(b"\r\n\r\n"), timeout)
+    except asyncio.IncompleteReadError as e:
+        assert e.partial == b"", e.partial
+        return None
+    lines = raw[:-4].split(b"\r\n")
+    headers = {}
+    for line in lines[1:]:
+        name, _, value = line.partition(b":")
+        headers[name.strip().lower().decode()] = value.strip().decode()
+    length = 0 if head else int(headers.get("content-length", 0))
+    body = await asyncio.wait_for(reader.readexactly(length), timeout)
+    return status(lines[0]), headers, body
+
+
+async def closed(reader, timeout=5):
+    """If the server closes (or resets) the connection without sending anything more."""
+    try:
+        return await asyncio.wait_for(reader.read(), timeout) == b""
+    except ConnectionResetError:
+        return True
+
+
+def self_signed(directory):
+    """Makes a certificate for localhost with openssl, as `(certfile, keyfile)` in `directory`."""
+    cert, key = os.path.join(directory, "cert.pem"), os.path.join(directory, "key.pem")
+    subprocess.run(
+        ["openssl", "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1",
+         "-subj", "/CN=localhost", "-keyout", key, "-out", cert],
+        check=True, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL,
+    )
+    return cert, key
+
+
+# HTTP/2 spoken frame by frame, over cleartext with prior knowledge
+H2_PREFACE = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"
+DATA, HEADERS, RST_STREAM, SETTINGS, PING, GOAWAY, WINDOW_UPDATE = 0x0, 0x1, 0x3, 0x4, 0x6, 0x7, 0x8
+END_STREAM, ACK, END_HEADERS = 0x1, 0x1, 0x4
+# the statuses our encoder indexes, the rest are literals
+INDEXED_STATUS = {8: 200, 9: 204, 10: 206, 11: 304, 12: 400, 13: 404, 14: 500}
+
+
+def frame(kind, flags, stream, payload=b""):
+    return struct.pack(">I", len(payload))[1:] + bytes([kind, flags]) + struct.pack(">I", stream) + payload
+
+
+def literal(value):
+    """An HPACK string, short and never Huffman coded."""
+    return bytes([len(value)]) + value
+
+
+def request_block(method=b"GET", path=b"/", *fields):
+    """A request's header block, every field a literal never indexed."""
+    fields = [(b":method", method), (b":scheme", b"http"), (b":path", path), (b":authority", b"check")] + list(fields)
+    return b"".join(b"\x10" + literal(name) + literal(value) for name, value in fields)
+
+
+async def h2_connect(port, settings=b""):
+    """A connection that's sent its preface and SETTINGS, as `(reader, writer)`."""
+    reader, writer = await asyncio.open_connection("127.0.0.1", port)
+    writer.write(H2_PREFACE + frame(SETTINGS, 0, 0, settings))
+    return reader, writer
+
+
+async def read_frame(reader, timeout=5):
+    """The next frame as `(kind, flags, stream, payload)`."""
+    head = await asyncio.wait_for(reader.readexactly(9), timeout)
+    length, kind, flags, stream = int.from_bytes(head[:3], "big"), head[3], head[4], int.from_bytes(head[5:], "big")
+    return kind, flags, stream & 0x7FFFFFFF, await asyncio.wait_for(reader.readexactly(length), timeout)
+
+
+def _integer(block, pos, prefix):
+    mask = (1 << prefix) - 1
+    value, pos = block[pos] & mask, pos + 1
+    if value < mask:
+        return value, pos
+    shift = 0
+    while True:
+        byte, pos = block[pos], pos + 1
+        value += (byte & 0x7F) << shift
+        shift += 7
+        if not byte & 0x80:
+            return value, pos
+
+
+def _string(block, pos):
+    assert not block[pos] & 0x80, "a Huffman coded string"
+    length, pos = _integer(block, pos, 7)
+    return block[pos:pos + length], pos + length
+
+
+def response_fields(block):
+    """Decodes a header block the way our encoder writes them, `:status` first, as a list of `(name, value)`."""
+    pos, fields = 0, []
+    while pos < len(block):
+        if block[pos] & 0xE0 == 0x20:
+            _, pos = _integer(block, pos, 5)
+        elif block[pos] & 0x80:
+            index, pos = _integer(block, pos, 7)
+            fields.append((b":status", str(INDEXED_STATUS[index]).encode()))
+        elif block[pos] == 0x08:
+            value, pos = _string(block, pos + 1)
+            fields.append((b":status", value))
+        else:
+            assert block[pos] == 0x00, "not a literal without indexing"
+            name, pos = _string(block, pos + 1)
+            value, pos = _string(block, pos)
+            fields.append((name, value))
+    return fields
+
+
+async def h2_response(reader, stream, frames=None):
+    """
+    Reads the response on `stream` up to its END_STREAM, as `(status,
+    fields, body)`, or `("reset", code, b"")` if it's reset. Frames for
+    other streams are skipped, or collected in `frames` when it's given.
+    """
+    status, fields, body = None, {}, b""
+    while True:
+        kind, flags, on, payload = await read_frame(reader)
+        assert kind != GOAWAY, payload
+        if on != stream:
+            if frames is not None:
+                frames.append((kind, flags, on, payload))
+            continue
+        if kind == RST_STREAM:
+            return "reset", struct.unpack(">I", payload)[0], b""
+        if kind == HEADERS:
+            for name, value in response_fields(payload):
+                fields[name.decode()] = value.decode()
+            status = int(fields[":status"])
+        if kind == DATA:
+            body += payload
+        if kind in (HEADERS, DATA) and flags & END_STREAM:
+            return status, fields, body
+
+
+def run(main, loop=None):
+    loop = loop or asyncio.new_event_loop()
+    asyncio.set_event_loop(loop)
+    try:
+        loop.run_until_complete(main())
+    finally:
+        loop.close()
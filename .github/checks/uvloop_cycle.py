@@ -0,0 +1,60 @@
+"""
+synth-356: uvloop. The same accept, serve and shutdown cycle runs on the
+default loop and then under `uvloop.install()`: keep-alive requests, a
+body, a handler awaiting a sleep and a future another callback resolves,
+an idle spell long enough for the poll to back off and a `stop()` that
+has to cut its sleep short. uvloop isn't there on windows, or anywhere
+it hasn't been installed, and then only the default loop is checked.
+"""
+import asyncio
+import time
+
+import async_rust
+
+from support import run
+
+
+async def handler(request):
+    await asyncio.sleep(0.01)
+    resolved = asyncio.get_event_loop().create_future()
+    asyncio.get_event_loop().call_later(0.01, resolved.set_result, request.body)
+    return async_rust.HTTPResponse(b"%s %s" % (request.path.encode(), await resolved))
+
+
+async def cycle():
+    runner = async_rust.AsyncServerRunner("127.0.0.1:0", handler, access_log=False)
+    task = asyncio.ensure_future(runner)
+    await asyncio.wait_for(runner.wait_ready(), 5)
+    reader, writer = await asyncio.open_connection("127.0.0.1", runner.local_addr()[1])
+
+    for path, body in ((b"/", b""), (b"/a", b"hello"), (b"/b", b"world")):
+        writer.write(b"POST %s HTTP/1.1\r\nHost: check\r\nContent-Length: %d\r\n\r\n%s" % (path, len(body), body))
+        head = await asyncio.wait_for(reader.readuntil(b"\r\n\r\n"), 5)
+        assert head.startswith(b"HTTP/1.1 200 OK"), head
+        length = int(head.lower().split(b"content-length: ")[1].split(b"\r\n")[0])
+        assert await reader.readexactly(length) == b"%s %s" % (path, body)
+
+    writer.close()
+    await asyncio.sleep(0.5)
+
+    stopping = time.monotonic()
+    runner.stop()
+    await asyncio.wait_for(task, 5)
+    assert time.monotonic() - stopping < 0.05, "stop() took %.0fms" % ((time.monotonic() - stopping) * 1000)
+    assert runner.state == "stopped", runner.state
+    runner.close()
+
+
+run(cycle)
+print("default loop ok")
+
+try:
+    import uvloop
+except ImportError:
+    print("skipped: uvloop isn't installed")
+else:
+    uvloop.install()
+    loop = asyncio.new_event_loop()
+    assert type(loop).__module__.startswith("uvloop"), type(loop)
+    run(cycle, loop)
+    print("uvloop ok")
@@ -0,0 +1,62 @@
+"""
+synth-290: the websocket close handshake. A close with a code a peer
+may send is echoed back, one with a reserved or undefined code or a
+1 byte payload is answered with 1002 and a reason that isn't utf-8 with
+1007 (RFC 6455 7.4).
+"""
+import asyncio
+import os
+import struct
+
+from support import run, serving
+
+
+HANDSHAKE = (
+    b"GET / HTTP/1.1\r\nHost: checks\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n"
+    b"Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+)
+
+CASES = [
+    (b"", 1000),
+    (struct.pack("!H", 1000), 1000),
+    (struct.pack("!H", 1012), 1012),
+    (struct.pack("!H", 3000) + b"bye", 3000),
+    (struct.pack("!H", 4999), 4999),
+    (b"\x03", 1002),
+    (struct.pack("!H", 1000) + b"\xff\xfe", 1007),
+] + [(struct.pack("!H", code), 1002) for code in (0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000)]
+
+
+async def echo(request, ws):
+    while await ws.receive() is not None:
+        pass
+
+
+def close_frame(payload):
+    mask = os.urandom(4)
+    masked = bytes(b ^ mask[i % 4] for i, b in enumerate(payload))
+    return bytes([0x88, 0x80 | len(payload)]) + mask + masked
+
+
+async def close_with(port, payload):
+    reader, writer = await asyncio.open_connection("127.0.0.1", port)
+    writer.write(HANDSHAKE)
+    head = await asyncio.wait_for(reader.readuntil(b"\r\n\r\n"), 5)
+    assert head.startswith(b"HTTP/1.1 101"), head
+
+    writer.write(close_frame(payload))
+    frame = await asyncio.wait_for(reader.read(), 5)
+    writer.close()
+    assert frame[0] == 0x88 and frame[1] >= 2, frame
+    return struct.unpack("!H", frame[2:4])[0]
+
+
+async def main():
+    async with serving(None, websocket=echo) as (_, port):
+        for payload, expected in CASES:
+            code = await close_with(port, payload)
+            assert code == expected, (payload, code, expected)
+
+
+run(main)
+print("websocket close ok")
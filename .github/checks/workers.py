@@ -0,0 +1,64 @@
+"""
+synth-286: workers share the port with SO_REUSEPORT. With `workers=3`
+this script is run again twice as the workers, each answering with its
+pid. Fresh connections from the parent are spread over more than one
+process, only ever the parent and the pids `worker_pids()` gives, and
+the workers are gone again once the parent's stopped.
+"""
+import asyncio
+import os
+import time
+
+import async_rust
+
+from support import read_response, run
+
+
+WORKERS = 3
+
+
+async def handler(request):
+    return str(os.getpid())
+
+
+async def answered_by(port):
+    reader, writer = await asyncio.open_connection("127.0.0.1", port)
+    writer.write(b"GET / HTTP/1.1\r\nHost: check\r\nConnection: close\r\n\r\n")
+    code, _, body = await read_response(reader)
+    writer.close()
+    assert code == 200, code
+    return int(body)
+
+
+async def main():
+    runner = async_rust.AsyncServerRunner("127.0.0.1:0", handler, workers=WORKERS, access_log=False)
+    if runner.worker_id:
+        # a worker, serving until the parent terminates it
+        await runner
+        return
+
+    task = asyncio.ensure_future(runner)
+    await asyncio.wait_for(runner.wait_ready(), 5)
+    port = runner.local_addr()[1]
+    workers = set(runner.worker_pids())
+    assert len(workers) == WORKERS - 1, workers
+
+    # the workers take a moment to start, until then it's all the parent
+    seen, deadline = set(), time.monotonic() + 15
+    while len(seen) < WORKERS and time.monotonic() < deadline:
+        seen.add(await answered_by(port))
+    assert len(seen) > 1, "only %s answered" % seen
+    assert seen <= workers | {os.getpid()}, (seen, workers)
+
+    runner.stop()
+    await asyncio.wait_for(task, 5)
+    runner.close()
+
+    deadline = time.monotonic() + 5
+    while runner.worker_pids() and time.monotonic() < deadline:
+        await asyncio.sleep(0.05)
+    assert runner.worker_pids() == [], runner.worker_pids()
+    print("workers ok, %d of %d processes answered" % (len(seen), WORKERS))
+
+
+run(main)
@@ -0,0 +1,54 @@
+"""
+Accepts, serves and shuts down a runner on whatever event loop the
+platform defaults to (the proactor on windows) and then on a selector
+loop, run by CI against the freshly built extension.
+"""
+import asyncio
+import sys
+
+import async_rust
+
+
+async def handler(request):
+    await asyncio.sleep(0)
+    return async_rust.HTTPResponse(b"hello " + request.path.encode())
+
+
+async def get(port, path):
+    reader, writer = await asyncio.open_connection("127.0.0.1", port)
+    writer.write(b"GET " + path + b" HTTP/1.1\r\nHost: smoke\r\nConnection: close\r\n\r\n")
+    await writer.drain()
+    response = await asyncio.wait_for(reader.read(), 5)
+    writer.close()
+    return response
+
+
+async def main():
+    runner = async_rust.AsyncServerRunner("127.0.0.1:0", handler, access_log=False)
+    port = runner.local_addr()[1]
+    task = asyncio.ensure_future(runner)
+    await asyncio.sleep(0.1)
+
+    for path in (b"/", b"/a", b"/b"):
+        response = await get(port, path)
+        assert response.startswith(b"HTTP/1.1 200 OK"), response
+        assert response.endswith(b"hello " + path), response
+
+    runner.stop()
+    await asyncio.wait_for(task, 5)
+    assert runner.state == "stopped", runner.state
+    runner.close()
+
+
+def run(loop):
+    asyncio.set_event_loop(loop)
+    try:
+        loop.run_until_complete(main())
+    finally:
+        loop.close()
+    print(type(loop).__name__, "ok")
+
+
+run(asyncio.new_event_loop())
+if sys.platform == "win32":
+    run(asyncio.SelectorEventLoop())
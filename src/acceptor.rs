@@ -0,0 +1,301 @@
+use pyo3::prelude::*;
+use pyo3::class::gc::{PyTraverseError, PyVisit};
+
+use std::net::{TcpListener, TcpStream};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::JoinHandle;
+
+use crate::listener::{AcceptPause, ClientOptions};
+use crate::log;
+use crate::options::RunnerOptions;
+use crate::sleep::SleepWake;
+use crate::stats::ServerStats;
+
+
+///
+/// AcceptThread is `accept_mode="thread"`, the listeners are set back to
+/// blocking and a Rust thread sits in accept for us, pushing every client
+/// onto a channel. The runner drains the channel from `__next__` and when
+/// it's empty waits on a future the thread resolves (with
+/// `call_soon_threadsafe`) as soon as the next client arrives, so there's
+/// no polling and no sleeping between clients.
+///
+/// The thread only wakes the loop when the runner is waiting, that's when
+/// the channel goes from empty to having something in it, a busy server
+/// costs nothing more than the channel.
+///
+/// The thread waits on the listeners and a self-pipe together so `stop()`
+/// can get it out of the wait. A client that gives up between the wait and
+/// the accept would leave it blocked in accept, so `stop()` also shuts the
+/// listeners down which gets it out of that on linux. The allow / deny
+/// lists are checked before a client ever reaches the loop, and running
+/// out of fds has it wait out `accept_cooldown` on just the self-pipe.
+///
+pub(crate) struct AcceptThread {
+    shared: Arc<Shared>,
+    clients: Receiver<(TcpStream, usize)>,     // Each client and the index of the listener it came off
+    listeners: Vec<TcpListener>,    // Our own handles on the thread's listeners, to shut them down with
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    loop_: PyObject,
+    waiter: Mutex<Option<PyObject>>,    // The future the runner is waiting on, only set while the channel is empty
+    stopping: AtomicBool,
+    wake_read: RawFd,                   // The self-pipe the thread waits on alongside the listeners
+    wake_write: RawFd,
+}
+
+impl Shared {
+    fn new(loop_: PyObject) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error())
+        }
+
+        for fd in fds.iter() {
+            unsafe {
+                libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK);
+            }
+        }
+
+        Ok(Self {
+            loop_,
+            waiter: Mutex::new(None),
+            stopping: AtomicBool::new(false),
+            wake_read: fds[0],
+            wake_write: fds[1],
+        })
+    }
+
+    fn wake(&self) {
+        let _ = unsafe { libc::write(self.wake_write, [1u8].as_ptr() as *const libc::c_void, 1) };
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.wake_read);
+            libc::close(self.wake_write);
+        }
+    }
+}
+
+impl AcceptThread {
+    ///
+    /// Internal Method: AcceptThread::spawn() -> io::Result<Self>
+    ///
+    ///     Starts the thread accepting on `listeners`, clones of the
+    ///     runner's own so it keeps those for `local_addr()`. `stats` are
+    ///     each listener's counters, in the same order.
+    ///
+    pub(crate) fn spawn(
+        listeners: Vec<TcpListener>,
+        loop_: PyObject,
+        options: Arc<RunnerOptions>,
+        stats: Vec<Arc<ServerStats>>,
+        client_options: ClientOptions,
+    ) -> io::Result<Self> {
+        for listener in listeners.iter() {
+            listener.set_nonblocking(false)?;
+        }
+
+        let shared = Arc::new(Shared::new(loop_)?);
+        let (sender, clients) = mpsc::channel();
+        let handles = listeners
+            .iter()
+            .map(TcpListener::try_clone)
+            .collect::<io::Result<Vec<_>>>()?;
+        let worker = Worker {
+            pause: options.accept_pause(),
+            listeners,
+            shared: shared.clone(),
+            sender,
+            options,
+            stats,
+            client_options,
+        };
+
+        let thread = std::thread::Builder::new()
+            .name(String::from("async-rust-accept"))
+            .spawn(move || worker.run())?;
+
+        Ok(Self {
+            shared,
+            clients,
+            listeners: handles,
+            thread: Some(thread),
+        })
+    }
+
+    /// The next client the thread has accepted and the index of its listener, if there is one.
+    pub(crate) fn next_client(&self) -> Option<(TcpStream, usize)> {
+        self.clients.try_recv().ok()
+    }
+
+    ///
+    /// Internal Method: AcceptThread::wait() -> PyResult<Option<PyObject>>
+    ///
+    ///     What the runner yields when `next_client()` came back empty, a
+    ///     future the thread resolves once there's a client. None if one
+    ///     turned up in the meantime, the runner just goes back round.
+    ///
+    pub(crate) fn wait(&self, py: Python) -> PyResult<Option<PyObject>> {
+        let fut = self.shared.loop_.call_method0(py, "create_future")?;
+
+        {
+            // the thread sends before it looks for a waiter, so anything it
+            // sent before we got the lock is in the channel by now
+            let mut waiter = self.shared.waiter.lock().unwrap();
+            match self.clients.try_recv() {
+                Err(TryRecvError::Empty) => *waiter = Some(fut.clone_ref(py)),
+                _ => return Ok(None),
+            }
+        }
+
+        let mut awaiting = fut.call_method0(py, "__await__")?;
+        awaiting = awaiting.call_method0(py, "__next__")?;
+        Ok(Some(awaiting))
+    }
+
+    ///
+    /// Stops the thread and waits for it, clients it accepted that we never
+    /// took are closed. A runner waiting on us is woken so it can see it's
+    /// been stopped.
+    ///
+    pub(crate) fn stop(&mut self, py: Python) {
+        self.shared.stopping.store(true, Ordering::Release);
+        self.shared.wake();
+        for listener in self.listeners.iter() {
+            let _ = unsafe { libc::shutdown(listener.as_raw_fd(), libc::SHUT_RD) };
+        }
+
+        if let Some(thread) = self.thread.take() {
+            py.allow_threads(|| {
+                let _ = thread.join();
+            });
+        }
+
+        let waiter = self.shared.waiter.lock().unwrap().take();
+        if let Some(fut) = waiter {
+            let _ = Py::new(py, SleepWake::new(fut))
+                .and_then(|wake| self.shared.loop_.call_method1(py, "call_soon", (wake,)));
+        }
+    }
+
+    /// The future we're waiting on if there is one, for the runner's `__traverse__`.
+    pub(crate) fn traverse(&self, visit: PyVisit) -> Result<(), PyTraverseError> {
+        if let Ok(waiter) = self.shared.waiter.try_lock() {
+            if let Some(fut) = waiter.as_ref() {
+                visit.call(fut)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the future we're waiting on, it leads back to the task awaiting the runner.
+    pub(crate) fn clear(&self) {
+        if let Ok(mut waiter) = self.shared.waiter.lock() {
+            waiter.take();
+        }
+    }
+}
+
+impl Drop for AcceptThread {
+    fn drop(&mut self) {
+        // like the reactor we can't wait for it without the GIL so it's just told to stop
+        self.shared.stopping.store(true, Ordering::Release);
+        self.shared.wake();
+    }
+}
+
+
+/// The thread's side, owning the listeners and the sending end of the channel.
+struct Worker {
+    listeners: Vec<TcpListener>,
+    shared: Arc<Shared>,
+    sender: Sender<(TcpStream, usize)>,
+    options: Arc<RunnerOptions>,
+    stats: Vec<Arc<ServerStats>>,      // By listener
+    client_options: ClientOptions,
+    pause: AcceptPause,
+}
+
+impl Worker {
+    fn run(mut self) {
+        let mut fds: Vec<libc::pollfd> = std::iter::once(self.shared.wake_read)
+            .chain(self.listeners.iter().map(AsRawFd::as_raw_fd))
+            .map(|fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+            .collect();
+
+        while !self.shared.stopping.load(Ordering::Acquire) {
+            // only the self-pipe is waited on while accepting is paused
+            let (count, timeout) = match self.pause.remaining() {
+                Some(left) => (1, left.as_millis() as libc::c_int + 1),
+                None => (fds.len(), -1),
+            };
+
+            let res = unsafe { libc::poll(fds.as_mut_ptr(), count as libc::nfds_t, timeout) };
+            if res == -1 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue
+                }
+
+                log::socket_error("the accept thread failed to wait for clients", &e);
+                return
+            }
+
+            if self.shared.stopping.load(Ordering::Acquire) {
+                return
+            }
+
+            for index in 0..count - 1 {
+                if fds[index + 1].revents & libc::POLLIN != 0 && !self.accept(index) {
+                    return
+                }
+            }
+        }
+    }
+
+    /// Accepts the client waiting on `listeners[index]`, false once the runner has gone.
+    fn accept(&mut self, index: usize) -> bool {
+        let sock = match self.listeners[index].accept() {
+            Ok((sock, _)) => sock,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return true,
+            Err(e) => {
+                self.pause.failed(&e);
+                return true
+            },
+        };
+
+        let sock = match self.options.acl.admit(sock, self.options.deny_403, &self.stats[index]) {
+            Some(sock) => sock,
+            None => return true,
+        };
+
+        self.client_options.apply(&sock);
+
+        if self.sender.send((sock, index)).is_err() {
+            return false
+        }
+
+        let waiter = self.shared.waiter.lock().unwrap().take();
+        if let Some(fut) = waiter {
+            Python::with_gil(|py| {
+                // a closed loop refuses it, the runner's gone with it
+                let _ = Py::new(py, SleepWake::new(fut))
+                    .and_then(|wake| self.shared.loop_.call_method1(py, "call_soon_threadsafe", (wake,)));
+            });
+        }
+
+        true
+    }
+}
@@ -0,0 +1,102 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::PyValueError;
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::sync::RwLock;
+
+use crate::forwarded::Cidr;
+use crate::stats::ServerStats;
+
+
+/// What a refused connection is sent when `deny_403` is set, before it's closed.
+const FORBIDDEN: &[u8] = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+
+///
+/// Acl is the `allow_ips` and `deny_ips` of a runner, checked against the
+/// peer of every connection as it's accepted. A peer on the deny list is
+/// refused even if it's also allowed, and once there's anything on the
+/// allow list a peer has to be on it. Both lists can be swapped out while
+/// the runner is going with `AsyncServerRunner.update_acl()`.
+///
+/// Connections without an IP peer (a unix socket) are never refused.
+///
+#[derive(Default)]
+pub(crate) struct Acl {
+    lists: RwLock<Lists>,
+}
+
+#[derive(Default)]
+struct Lists {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Acl {
+    /// Replaces whichever of the lists are given.
+    pub(crate) fn update(&self, allow: Option<Vec<Cidr>>, deny: Option<Vec<Cidr>>) {
+        let mut lists = self.lists.write().unwrap();
+        if let Some(allow) = allow {
+            lists.allow = allow;
+        }
+        if let Some(deny) = deny {
+            lists.deny = deny;
+        }
+    }
+
+    /// Copies of `(allow, deny)` as they are now.
+    pub(crate) fn lists(&self) -> (Vec<Cidr>, Vec<Cidr>) {
+        let lists = self.lists.read().unwrap();
+        (lists.allow.clone(), lists.deny.clone())
+    }
+
+    pub(crate) fn permits(&self, ip: IpAddr) -> bool {
+        let lists = self.lists.read().unwrap();
+        if lists.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false
+        }
+
+        lists.allow.is_empty() || lists.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    ///
+    /// Internal Method: Acl::admit() -> Option<TcpStream>
+    ///
+    ///     Gives a newly accepted connection back if it can go on to be
+    ///     served, one that can't is counted, sent a bare `403` when
+    ///     `forbidden` is set and closed by being dropped.
+    ///
+    pub(crate) fn admit(&self, sock: TcpStream, forbidden: bool, stats: &ServerStats) -> Option<TcpStream> {
+        match sock.peer_addr() {
+            Ok(addr) if !self.permits(addr.ip()) => {},
+            _ => return Some(sock),
+        }
+
+        stats.denied();
+        if forbidden {
+            // it's only worth the one try, the socket's closed either way.
+            // Whatever the client already sent is read off first since
+            // closing with it unread resets the connection, which can throw
+            // away the 403 before the client sees it.
+            let _ = (&sock).write(FORBIDDEN);
+            let _ = sock.shutdown(Shutdown::Write);
+            if sock.set_nonblocking(true).is_ok() {
+                let mut scratch = [0; 4096];
+                while matches!((&sock).read(&mut scratch), Ok(n) if n > 0) {}
+            }
+        }
+
+        None
+    }
+}
+
+/// The `allow_ips` / `deny_ips` given from python, `which` is just for the error.
+pub(crate) fn parse_list(which: &str, addrs: &[String]) -> PyResult<Vec<Cidr>> {
+    addrs
+        .iter()
+        .map(|addr| Cidr::parse(addr).ok_or_else(|| {
+            PyValueError::new_err(format!("invalid {} entry '{}', expected an address or CIDR", which, addr))
+        }))
+        .collect()
+}
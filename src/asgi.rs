@@ -0,0 +1,422 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::exceptions::{PyRuntimeError, PyStopAsyncIteration, PyTypeError, PyValueError};
+use pyo3::types::{PyBytes, PyDict, PyList, PyTuple};
+
+use crate::get_loop;
+use crate::http::{self, HTTPRequest, HTTPResponse};
+use crate::inbound::{NextChunk, RequestStream};
+use crate::sleep::Awaiting;
+
+
+/// How much of a spooled body each `http.request` message carries.
+const SPOOL_CHUNK: usize = 64 * 1024;
+
+///
+/// ASGIApp adapts an ASGI 3.0 application into a callback the runner
+/// understands, so `AsyncServerRunner(addr, ASGIApp(app))` serves it.
+///
+/// Only the `http` scope is supported (no lifespan or websockets) and the
+/// whole response is collected before it's written so `more_body` is
+/// honoured but doesn't stream.
+///
+///     Requires:
+///         - app:  the ASGI application `async def app(scope, receive, send)`
+///
+#[pyclass]
+pub struct ASGIApp {
+    app: PyObject,
+}
+
+#[pymethods]
+impl ASGIApp {
+    #[new]
+    fn new(app: PyObject) -> Self {
+        Self { app }
+    }
+
+    ///
+    /// PythonMethod: ASGIApp(request) -> ASGICall
+    ///
+    ///     Builds the scope for the request and starts the application
+    ///     giving back an awaitable which resolves to the HTTPResponse.
+    ///
+    #[call]
+    fn __call__(&self, py: Python, request: PyRef<HTTPRequest>) -> PyResult<ASGICall> {
+        let scope = build_scope(py, &request)?;
+
+        let exchange = Py::new(py, ASGIExchange {
+            loop_: get_loop(py)?.into_py(py),
+            body: match (&request.spooled, &request.stream) {
+                (None, None) => Some(request.body.as_slice().to_vec()),
+                _ => None,
+            },
+            spooled: request.spooled.as_ref().map(|spool| spool.to_python(py)).transpose()?,
+            stream: request.stream.as_ref().map(|stream| stream.clone_ref(py)),
+            status: None,
+            headers: Vec::new(),
+            response: Vec::new(),
+            complete: false,
+            disconnected: false,
+            disconnect: Vec::new(),
+        })?;
+
+        let receive = exchange.getattr(py, "receive")?;
+        let send = exchange.getattr(py, "send")?;
+
+        let coro = self.app.call1(py, (scope, receive, send))?;
+        let awaiting = Awaiting::of(coro.as_ref(py))?
+            .ok_or_else(|| PyTypeError::new_err("the ASGI app should be an async function"))?;
+
+        Ok(ASGICall { awaiting, exchange })
+    }
+}
+
+
+///
+/// Builds the ASGI `http` scope out of a parsed request.
+///
+fn build_scope<'p>(py: Python<'p>, request: &HTTPRequest) -> PyResult<&'p PyDict> {
+    let asgi = PyDict::new(py);
+    asgi.set_item("version", "3.0")?;
+    asgi.set_item("spec_version", "2.1")?;
+
+    let headers = PyList::empty(py);
+    for (name, value) in request.headers.iter_raw() {
+        let pair = PyTuple::new(py, [
+            PyBytes::new(py, name.to_ascii_lowercase().as_bytes()),
+            PyBytes::new(py, value),
+        ]);
+        headers.append(pair)?;
+    }
+
+    let http_version = request.protocol
+        .strip_prefix("HTTP/")
+        .unwrap_or(&request.protocol);
+
+    let scope = PyDict::new(py);
+    scope.set_item("type", "http")?;
+    scope.set_item("asgi", asgi)?;
+    scope.set_item("http_version", http_version)?;
+    scope.set_item("method", &request.method)?;
+    scope.set_item("scheme", &request.scheme)?;
+    scope.set_item("path", &request.path)?;
+    scope.set_item("raw_path", PyBytes::new(py, &request.raw_path))?;
+    scope.set_item("query_string", PyBytes::new(py, request.raw_query.as_bytes()))?;
+    scope.set_item("root_path", "")?;
+    scope.set_item("headers", headers)?;
+    scope.set_item("client", request.client.clone())?;
+    scope.set_item("server", request.server.clone())?;
+
+    Ok(scope)
+}
+
+
+///
+/// ASGIExchange is the state shared between the `receive` and `send`
+/// callables handed to the application for a single request.
+///
+#[pyclass]
+pub struct ASGIExchange {
+    loop_: PyObject,                    // The asyncio event loop
+    body: Option<Vec<u8>>,              // The request body until the app has received it
+    spooled: Option<PyObject>,          // A spooled body's file instead, until the app has received all of it
+    stream: Option<Py<RequestStream>>,  // Or a streamed body, until the app has received all of it
+    status: Option<u16>,                // Set by `http.response.start`
+    headers: Vec<(String, String)>,     // Set by `http.response.start`
+    response: Vec<u8>,                  // All the `http.response.body` chunks so far
+    complete: bool,                     // If the app has sent its last body chunk
+    disconnected: bool,                 // Set if the client went away before the response was done
+    disconnect: Vec<PyObject>,          // Futures from `receive` waiting on `http.disconnect`
+}
+
+#[pymethods]
+impl ASGIExchange {
+
+    ///
+    /// PythonMethod: receive() -> awaitable dict
+    ///
+    ///     The first call gets the whole body as one `http.request`, after
+    ///     that we wait for the response to finish and give `http.disconnect`.
+    ///     A spooled body comes `SPOOL_CHUNK` at a time instead, read from
+    ///     its temp file as the app asks for it, and a streamed one a chunk
+    ///     at a time as it's read off the connection (see StreamedMessage).
+    ///
+    fn receive(&mut self, py: Python) -> PyResult<PyObject> {
+        if let Some(body) = self.body.take() {
+            let message = PyDict::new(py);
+            message.set_item("type", "http.request")?;
+            message.set_item("body", PyBytes::new(py, &body))?;
+            message.set_item("more_body", false)?;
+
+            return Ok(Py::new(py, Ready { value: Some(message.into()) })?.into_py(py))
+        }
+
+        if let Some(file) = self.spooled.as_ref() {
+            let chunk: &PyBytes = file.as_ref(py).call_method1("read", (SPOOL_CHUNK,))?.extract()?;
+            let more_body = !chunk.as_bytes().is_empty();
+
+            let message = PyDict::new(py);
+            message.set_item("type", "http.request")?;
+            message.set_item("body", chunk)?;
+            message.set_item("more_body", more_body)?;
+            if !more_body {
+                self.spooled = None;
+            }
+
+            return Ok(Py::new(py, Ready { value: Some(message.into()) })?.into_py(py))
+        }
+
+        if let Some(stream) = self.stream.as_ref() {
+            let ended = stream.borrow(py).is_ended();
+            match ended {
+                true => self.stream = None,
+                false => return Ok(Py::new(py, StreamedMessage { next: NextChunk::new(stream.clone_ref(py)) })?.into_py(py)),
+            }
+        }
+
+        let fut = self.loop_.call_method0(py, "create_future")?;
+        if self.complete || self.disconnected {
+            fut.call_method1(py, "set_result", (disconnect_message(py)?,))?;
+        } else {
+            self.disconnect.push(fut.clone_ref(py));
+        }
+
+        Ok(fut)
+    }
+
+    ///
+    /// PythonMethod: send(message) -> awaitable None
+    ///
+    ///     Collects `http.response.start` and `http.response.body` messages
+    ///     into the response, anything out of order raises. Once the client
+    ///     has disconnected messages are dropped.
+    ///
+    fn send(&mut self, py: Python, message: &PyDict) -> PyResult<Ready> {
+        let kind: String = match message.get_item("type") {
+            Some(kind) => kind.extract()?,
+            None => return Err(PyValueError::new_err("ASGI message is missing 'type'")),
+        };
+
+        if self.disconnected {
+            return Ok(Ready { value: None })
+        }
+
+        if self.complete {
+            return Err(PyRuntimeError::new_err("the response has already been sent"))
+        }
+
+        match kind.as_str() {
+            "http.response.start" if self.status.is_none() => {
+                self.status = match message.get_item("status") {
+                    Some(status) => Some(status.extract()?),
+                    None => return Err(PyValueError::new_err("'http.response.start' is missing 'status'")),
+                };
+
+                if let Some(headers) = message.get_item("headers") {
+                    for pair in headers.iter()? {
+                        let (name, value): (&PyBytes, &PyBytes) = pair?.extract()?;
+                        self.headers.push((http::latin1(name.as_bytes()), http::latin1(value.as_bytes())));
+                    }
+                }
+            },
+            "http.response.body" if self.status.is_some() => {
+                if let Some(body) = message.get_item("body") {
+                    let body: &PyBytes = body.extract()?;
+                    self.response.extend_from_slice(body.as_bytes());
+                }
+
+                let more_body = match message.get_item("more_body") {
+                    Some(more) => more.is_true()?,
+                    None => false,
+                };
+
+                if !more_body {
+                    self.finish(py)?;
+                }
+            },
+            _ => return Err(PyRuntimeError::new_err(format!("unexpected ASGI message '{}'", kind))),
+        }
+
+        Ok(Ready { value: None })
+    }
+}
+
+impl ASGIExchange {
+
+    ///
+    /// Marks the response as done waking anything waiting in `receive`.
+    ///
+    fn finish(&mut self, py: Python) -> PyResult<()> {
+        self.complete = true;
+        self.wake_disconnect(py)
+    }
+
+    /// Resolves everything waiting in `receive` with `http.disconnect`.
+    fn wake_disconnect(&mut self, py: Python) -> PyResult<()> {
+        for fut in self.disconnect.drain(..) {
+            if !fut.call_method0(py, "done")?.as_ref(py).is_true()? {
+                fut.call_method1(py, "set_result", (disconnect_message(py)?,))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn disconnect_message(py: Python<'_>) -> PyResult<&PyDict> {
+    let message = PyDict::new(py);
+    message.set_item("type", "http.disconnect")?;
+    Ok(message)
+}
+
+
+///
+/// ASGICall drives the application's coroutine for one request, passing
+/// along whatever it yields and resolving to the collected HTTPResponse.
+///
+#[pyclass]
+pub struct ASGICall {
+    awaiting: Awaiting,             // The app's coroutine
+    exchange: Py<ASGIExchange>,     // What the app has sent us so far
+}
+
+#[pymethods]
+impl ASGICall {
+    ///
+    /// PythonMethod: ASGICall.throw(type, value=None, traceback=None)
+    ///
+    ///     Passes an exception (e.g. the CancelledError when the request is
+    ///     cancelled) on into the app's coroutine, like `yield from` would.
+    ///
+    #[args(value = "None", traceback = "None")]
+    fn throw(&self, py: Python, type_: &PyAny, value: Option<&PyAny>, traceback: Option<&PyAny>) -> PyResult<PyObject> {
+        self.awaiting.throw(py, type_, value, traceback)
+    }
+}
+
+impl ASGICall {
+    ///
+    /// The client went away while the app was running, `receive` gives it
+    /// `http.disconnect` from now on.
+    ///
+    pub(crate) fn disconnect(&self, py: Python) -> PyResult<()> {
+        let mut exchange = self.exchange.borrow_mut(py);
+        exchange.disconnected = true;
+        exchange.wake_disconnect(py)
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for ASGICall {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for ASGICall {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(slf: PyRef<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+
+        if let IterNextOutput::Yield(yielded) = slf.awaiting.step(py, None)? {
+            return Ok(IterNextOutput::Yield(Some(yielded)))
+        }
+
+        let mut exchange = slf.exchange.borrow_mut(py);
+        let status = match exchange.status {
+            Some(status) => status,
+            None => return Err(PyRuntimeError::new_err("ASGI app returned without starting a response")),
+        };
+
+        if !exchange.complete {
+            exchange.finish(py)?;
+        }
+
+        let response = HTTPResponse::from_parts(
+            status,
+            std::mem::take(&mut exchange.headers),
+            std::mem::take(&mut exchange.response),
+        );
+
+        Ok(IterNextOutput::Return(Some(Py::new(py, response)?.into_py(py))))
+    }
+}
+
+
+///
+/// StreamedMessage is what `receive` gives for a streamed body, the next
+/// chunk as an `http.request` with `more_body` once it's been read off the
+/// connection and an empty one without after the last of it.
+///
+#[pyclass]
+pub struct StreamedMessage {
+    next: NextChunk,
+}
+
+#[pyproto]
+impl PyAsyncProtocol for StreamedMessage {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for StreamedMessage {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+        let (body, more_body) = match slf.next.poll(py) {
+            Ok(IterNextOutput::Yield(yielded)) => return Ok(IterNextOutput::Yield(yielded)),
+            Ok(IterNextOutput::Return(chunk)) => (chunk, true),
+            Err(e) if e.is_instance::<PyStopAsyncIteration>(py) => (PyBytes::new(py, b"").into(), false),
+            Err(e) => return Err(e),
+        };
+
+        let message = PyDict::new(py);
+        message.set_item("type", "http.request")?;
+        message.set_item("body", body)?;
+        message.set_item("more_body", more_body)?;
+        Ok(IterNextOutput::Return(message.into()))
+    }
+}
+
+
+///
+/// Ready is an awaitable which is already finished, it's what `send` and
+/// the first `receive` give back since neither has to wait on anything
+/// (and what entering a Server gives back).
+///
+#[pyclass]
+pub struct Ready {
+    pub(crate) value: Option<PyObject>,
+}
+
+#[pyproto]
+impl PyAsyncProtocol for Ready {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for Ready {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> IterNextOutput<Option<PyObject>, Option<PyObject>> {
+        IterNextOutput::Return(slf.value.take())
+    }
+}
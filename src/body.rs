@@ -0,0 +1,254 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::exceptions::{PyStopAsyncIteration, PyTypeError};
+
+use std::io::Write;
+
+use crate::compress::{Encoder, Encoding};
+use crate::http;
+use crate::sleep::Awaiting;
+use crate::sse::{EventSource, SourceStep};
+
+
+///
+/// BodyStream is a response body coming from an async iterator (an async
+/// generator from the handler), each item is written as it arrives rather
+/// than the whole body being collected first.
+///
+/// The next item is only asked for once the previous one has been
+/// written, so a slow client holds the generator up instead of us
+/// buffering everything it produces.
+///
+/// The events of an EventSourceResponse are a BodyStream too, they're just
+/// waited on through an EventSource rather than stepped directly.
+///
+pub(crate) struct BodyStream {
+    iterator: PyObject,             // What `__aiter__` gave us
+    pending: Option<Awaiting>,      // The `__anext__()` being awaited
+    chunked: bool,                  // Framed with `Transfer-Encoding: chunked`, otherwise ended by closing
+    encoder: Option<Encoder>,       // Compresses each item as it comes when the response is compressed
+    events: Option<EventSource>,    // Set for Server-Sent Events, see `sse::EventSourceResponse`
+}
+
+///
+/// Where driving a BodyStream got to, `Yield` is something the iterator
+/// is waiting on that has to go to the event loop.
+///
+pub(crate) enum StreamStep {
+    Yield(PyObject),
+    Chunk,
+    Done,
+}
+
+impl BodyStream {
+    pub(crate) fn new(py: Python, iterable: &PyAny, chunked: bool, encoding: Option<Encoding>) -> PyResult<Self> {
+        Ok(Self {
+            iterator: iterable.call_method0("__aiter__")?.into_py(py),
+            pending: None,
+            chunked,
+            encoder: encoding.map(Encoder::new),
+            events: None,
+        })
+    }
+
+    /// The body of an EventSourceResponse, never compressed.
+    pub(crate) fn events(py: Python, iterable: &PyAny, chunked: bool, source: EventSource) -> PyResult<Self> {
+        Ok(Self {
+            events: Some(source),
+            ..Self::new(py, iterable, chunked, None)?
+        })
+    }
+
+    /// If this is Server-Sent Events, which are expected to go on indefinitely.
+    pub(crate) fn is_events(&self) -> bool {
+        self.events.is_some()
+    }
+
+    /// If `obj` should be streamed as a body, anything async iterable.
+    pub(crate) fn is_stream(obj: &PyAny) -> PyResult<bool> {
+        obj.hasattr("__aiter__")
+    }
+
+    ///
+    /// Internal Method: BodyStream::step() -> PyResult<StreamStep>
+    ///
+    ///     Moves the iterator along, a `Chunk` has been framed onto the end
+    ///     of `out`. Once it's finished `Done` comes back with the final
+    ///     empty chunk (if chunked) already added. Empty items are skipped
+    ///     since a zero length chunk would end the body.
+    ///
+    ///     A compressed body is compressed an item at a time, each one is
+    ///     flushed so the client gets it straight away.
+    ///
+    pub(crate) fn step(&mut self, py: Python, out: &mut Vec<u8>) -> PyResult<StreamStep> {
+        if let Some(source) = self.events.as_mut() {
+            return match source.step(py, &self.iterator)? {
+                SourceStep::Yield(yielded) => Ok(StreamStep::Yield(yielded)),
+                SourceStep::Data(data) => {
+                    self.frame(&data, out);
+                    Ok(StreamStep::Chunk)
+                },
+                SourceStep::Done => {
+                    self.end(out);
+                    Ok(StreamStep::Done)
+                },
+            }
+        }
+
+        loop {
+            let pending = match self.pending.as_ref() {
+                Some(pending) => pending,
+                None => {
+                    let next = self.iterator.call_method0(py, "__anext__")?;
+                    let pending = Awaiting::of(next.as_ref(py))?
+                        .ok_or_else(|| PyTypeError::new_err("__anext__ didn't return an awaitable"))?;
+                    self.pending = Some(pending);
+                    self.pending.as_ref().unwrap()
+                },
+            };
+
+            let item = match pending.step(py, None) {
+                Ok(IterNextOutput::Yield(yielded)) => return Ok(StreamStep::Yield(yielded)),
+                Ok(IterNextOutput::Return(item)) => {
+                    self.pending = None;
+                    item
+                },
+                Err(e) if e.is_instance::<PyStopAsyncIteration>(py) => {
+                    self.pending = None;
+                    if let Some(encoder) = self.encoder.as_mut() {
+                        let mut trailer = Vec::new();
+                        encoder.finish(&mut trailer);
+                        self.frame(&trailer, out);
+                    }
+
+                    self.end(out);
+                    return Ok(StreamStep::Done)
+                },
+                Err(e) => {
+                    self.pending = None;
+                    return Err(e)
+                },
+            };
+
+            let mut data = http::body_to_bytes(item.as_ref(py))?;
+            if data.is_empty() {
+                continue
+            }
+
+            if let Some(encoder) = self.encoder.as_mut() {
+                let mut compressed = Vec::new();
+                encoder.write(&data, &mut compressed);
+                data = compressed;
+            }
+
+            self.frame(&data, out);
+            return Ok(StreamStep::Chunk)
+        }
+    }
+
+    /// The final empty chunk, when there is one.
+    fn end(&self, out: &mut Vec<u8>) {
+        if self.chunked {
+            out.extend_from_slice(b"0\r\n\r\n");
+        }
+    }
+
+    fn frame(&self, data: &[u8], out: &mut Vec<u8>) {
+        if self.chunked {
+            let _ = write!(out, "{:x}\r\n", data.len());
+            out.extend_from_slice(data);
+            out.extend_from_slice(b"\r\n");
+        } else {
+            out.extend_from_slice(data);
+        }
+    }
+
+    /// Passes an exception (cancellation) into whatever the iterator is waiting on.
+    pub(crate) fn throw(&mut self, py: Python, type_: &PyAny, value: Option<&PyAny>) {
+        if let Some(source) = self.events.as_mut() {
+            source.cancel(py);
+        }
+
+        if let Some(pending) = self.pending.take() {
+            let _ = pending.throw(py, type_, value, None);
+        }
+    }
+}
+
+
+///
+/// BodyStep awaits a BodyStream on its own until it's produced its next
+/// chunk, for an HTTP/2 stream whose body is stepped from a task of its
+/// own rather than the connection's. Once the task's done the chunk is
+/// taken with `take()` and the same BodyStep is awaited again by a fresh
+/// task for the one after, when the last has been framed.
+///
+#[pyclass]
+pub(crate) struct BodyStep {
+    body: BodyStream,
+    chunk: Vec<u8>,     // What the last step framed
+    done: bool,         // The iterator's finished, `chunk` is the end of the body
+}
+
+impl BodyStep {
+    pub(crate) fn new(body: BodyStream) -> Self {
+        Self {
+            body,
+            chunk: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// The chunk the last step produced and if it's the end of the body.
+    pub(crate) fn take(&mut self) -> (Vec<u8>, bool) {
+        (std::mem::take(&mut self.chunk), self.done)
+    }
+}
+
+#[pymethods]
+impl BodyStep {
+    ///
+    /// PythonMethod: BodyStep.throw(type, value=None, traceback=None)
+    ///
+    ///     Passes an exception (the CancelledError when the stream is
+    ///     reset) on into what the iterator is waiting on and raises it.
+    ///
+    #[args(value = "None", _traceback = "None")]
+    fn throw(&mut self, py: Python, type_: &PyAny, value: Option<&PyAny>, _traceback: Option<&PyAny>) -> PyResult<()> {
+        self.body.throw(py, type_, value);
+        match value {
+            Some(value) if !value.is_none() => Err(PyErr::from_instance(value)),
+            _ => Err(PyErr::from_instance(type_)),
+        }
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for BodyStep {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for BodyStep {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+        let step = &mut *slf;
+        match step.body.step(py, &mut step.chunk)? {
+            StreamStep::Yield(yielded) => Ok(IterNextOutput::Yield(Some(yielded))),
+            StreamStep::Chunk => Ok(IterNextOutput::Return(None)),
+            StreamStep::Done => {
+                step.done = true;
+                Ok(IterNextOutput::Return(None))
+            },
+        }
+    }
+}
@@ -0,0 +1,522 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::types::IntoPyDict;
+use pyo3::wrap_pyfunction;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6, TcpStream};
+use std::time::{Duration, Instant};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::sleep::LoopSleeper;
+use crate::stream::{self, Reader, SharedTransport, Transport, Writer};
+use crate::tls::{ClientTls, TlsSession};
+
+
+///
+/// Adds `connect()` and the awaitable behind it to the module, the
+/// function has to be wrapped from in here where pyo3 generated it.
+///
+pub(crate) fn init(m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(connect, m)?)?;
+    m.add_class::<Connect>()?;
+    Ok(())
+}
+
+///
+/// PythonMethod: async_rust.connect(host, port, timeout=None) -> awaitable (Reader, Writer)
+///
+///     The `asyncio.open_connection` of the crate, awaiting it connects to
+///     `host` without blocking the loop and gives back the same Reader and
+///     Writer a raw server connection gets, `get_extra_info("peername")`
+///     is the address that won.
+///
+///     The host is looked up with `loop.getaddrinfo()` and its IPv4 and
+///     IPv6 addresses raced Happy Eyeballs style (RFC 8305): the families
+///     take turns, each address gets 250ms of a head start over the next and
+///     the first to connect wins, the others are closed. If none of them
+///     take the connection the error of the last one is raised
+///     (ConnectionRefusedError...).
+///
+///     Requires:
+///         - host:     str     (a hostname or an IP address)
+///         - port:     int
+///
+///     Optional:
+///         - timeout:  float   (seconds for the whole connect before asyncio.TimeoutError, none by default)
+///
+#[pyfunction(timeout = "None")]
+pub(crate) fn connect(host: &str, port: u16, timeout: Option<f64>) -> PyResult<Connect> {
+    let timeout = match timeout {
+        Some(timeout) if !timeout.is_finite() || timeout < 0.0 => {
+            return Err(PyValueError::new_err("timeout must be a positive number of seconds"))
+        },
+        timeout => timeout.map(Duration::from_secs_f64),
+    };
+
+    Ok(Connect::new(host, port, timeout, None))
+}
+
+/// How long an attempt has before the next address is tried alongside it (RFC 8305's Connection Attempt Delay).
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The most a pooled connection keeps of what was written to it to send again after a redial.
+const REPLAY_LIMIT: usize = 64 * 1024;
+
+///
+/// Connect is the awaitable `connect()` gives back, like a coroutine
+/// nothing happens until it's awaited. The first step starts looking up
+/// the host (unless it's an IP address already) and every step after that
+/// checks on the lookup, then the connect attempts and then the TLS
+/// handshake if there is one (a ConnectionPool's `tls=True`), sleeping on
+/// the loop in between like the Reader and Writer do.
+///
+#[pyclass]
+pub struct Connect {
+    target: Option<(String, u16)>,                  // Taken the first time we're stepped
+    timeout: Option<Duration>,
+    tls: Option<ClientTls>,                         // How to start TLS once connected, None for plain TCP
+    started: Option<(LoopSleeper, Option<Instant>)>,    // Our sleeper and the deadline, from the first step
+    resolving: Option<PyObject>,                    // The `loop.getaddrinfo()` task while the host is looked up
+    race: Option<Race>,                             // The connect attempts once we have addresses
+    handshaking: Option<SharedTransport>,           // The connection while its TLS handshake is done
+}
+
+///
+/// Race is the connect attempts to every address of the host, a new one
+/// starts each time the last has had `ATTEMPT_DELAY` to itself (or has
+/// already failed) until the first of them connects.
+///
+struct Race {
+    addrs: Vec<SocketAddr>,         // Left to try, last first
+    attempts: Vec<Socket>,          // Still connecting
+    next_at: Instant,               // When the next address starts
+    error: Option<io::Error>,       // Why the last address to fail didn't work
+}
+
+impl Connect {
+    pub(crate) fn new(host: &str, port: u16, timeout: Option<Duration>, tls: Option<ClientTls>) -> Self {
+        Self {
+            target: Some((host.to_string(), port)),
+            timeout,
+            tls,
+            started: None,
+            resolving: None,
+            race: None,
+            handshaking: None,
+        }
+    }
+
+    fn start(&mut self, py: Python, host: String, port: u16) -> PyResult<()> {
+        let loop_ = crate::get_loop(py)?;
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.started = Some((LoopSleeper::for_connection(loop_.into()), deadline));
+
+        // nothing to look up, `[::1]` is how an IPv6 host is usually written
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            self.race = Some(Race::new(vec![SocketAddr::new(ip, port)]));
+            return Ok(())
+        }
+
+        let stream = py.import("socket")?.getattr("SOCK_STREAM")?;
+        let kwargs = [("type", stream)].into_py_dict(py);
+        let lookup = loop_.call_method("getaddrinfo", (host, port), Some(kwargs))?;
+        self.resolving = Some(py.import("asyncio")?.call1("ensure_future", (lookup,))?.into());
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: Connect::resolved() -> PyResult<bool>
+    ///
+    ///     If the lookup has finished, starting the race with its addresses
+    ///     when it has. A lookup that failed raises its error (socket.gaierror).
+    ///
+    fn resolved(&mut self, py: Python) -> PyResult<bool> {
+        let task = match self.resolving.as_ref() {
+            Some(task) => task,
+            None => return Ok(true),
+        };
+
+        if !task.call_method0(py, "done")?.is_true(py)? {
+            return Ok(false)
+        }
+
+        let infos = task.call_method0(py, "result");
+        self.resolving = None;
+
+        let mut addrs = Vec::new();
+        for info in infos?.as_ref(py).iter()? {
+            let (_, _, _, _, sockaddr): (&PyAny, &PyAny, &PyAny, &PyAny, &PyAny) = info?.extract()?;
+            if let Some(addr) = socket_addr(sockaddr) {
+                addrs.push(addr);
+            }
+        }
+
+        self.race = Some(Race::new(interleave(addrs)));
+        Ok(true)
+    }
+
+    ///
+    /// Internal Method: Connect::poll() -> PyResult<Option<SharedTransport>>
+    ///
+    ///     One step of the connect, the transport once it's connected (and
+    ///     its TLS handshake is done) or `None` to sleep before the next.
+    ///     Raises asyncio.TimeoutError once `timeout` has passed.
+    ///
+    pub(crate) fn poll(&mut self, py: Python) -> PyResult<Option<SharedTransport>> {
+        if let Some((host, port)) = self.target.take() {
+            self.start(py, host, port)?;
+        }
+
+        if self.handshaking.is_none() {
+            if self.resolving.is_none() && self.race.is_none() {
+                return Err(PyRuntimeError::new_err("cannot reuse already awaited connect()"))
+            }
+
+            let sock = match self.resolved(py)? {
+                true => match self.race.as_mut().map(Race::poll) {
+                    Some(Ok(sock)) => sock,
+                    Some(Err(e)) => {
+                        self.race = None;
+                        return Err(e.into())
+                    },
+                    None => None,
+                },
+                false => None,
+            };
+
+            if let Some(sock) = sock {
+                self.race = None;
+                let tls = self.tls.as_ref().map(ClientTls::session).transpose()?;
+                let plain = tls.is_none();
+                let transport = Transport::new(sock, tls, Vec::new());
+                if plain {
+                    return Ok(Some(transport))
+                }
+
+                self.handshaking = Some(transport);
+            }
+        }
+
+        if let Some(transport) = self.handshaking.as_ref() {
+            // nothing has been written yet, all a flush does is the handshake
+            let flushed = {
+                let mut guard = stream::lock(transport)?;
+                let transport = &mut *guard;
+                py.allow_threads(|| transport.flush())
+            };
+
+            match flushed {
+                Ok(true) => return Ok(self.handshaking.take()),
+                Ok(false) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => {
+                    self.handshaking = None;
+                    return Err(e.into())
+                },
+            }
+        }
+
+        let (_, deadline) = self.started.as_ref().unwrap();
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.race = None;
+            self.handshaking = None;
+            if let Some(task) = self.resolving.take() {
+                task.call_method0(py, "cancel")?;
+            }
+
+            let timeout = py.import("asyncio")?.getattr("TimeoutError")?;
+            return Err(PyErr::from_type(timeout.downcast()?, "connect timed out"))
+        }
+
+        Ok(None)
+    }
+}
+
+///
+/// A `sockaddr` from getaddrinfo, `(host, port)` for IPv4 and `(host, port,
+/// flowinfo, scope_id)` for IPv6. Anything else (a family we can't connect
+/// to) is skipped.
+///
+fn socket_addr(sockaddr: &PyAny) -> Option<SocketAddr> {
+    if let Ok((host, port, flowinfo, scope_id)) = sockaddr.extract::<(&str, u16, u32, u32)>() {
+        // a scope in the host (`fe80::1%eth0`) is the same as scope_id
+        let host = host.split('%').next().unwrap_or(host);
+        return Some(SocketAddrV6::new(host.parse().ok()?, port, flowinfo, scope_id).into())
+    }
+
+    let (host, port) = sockaddr.extract::<(&str, u16)>().ok()?;
+    Some(SocketAddr::new(host.parse().ok()?, port))
+}
+
+///
+/// Orders the addresses so the families take turns, starting with the
+/// family of the first address the resolver gave (RFC 8305 section 4).
+///
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    ordered
+}
+
+impl Race {
+    fn new(mut addrs: Vec<SocketAddr>) -> Self {
+        addrs.reverse();
+        Self {
+            addrs,
+            attempts: Vec::new(),
+            next_at: Instant::now(),
+            error: None,
+        }
+    }
+
+    ///
+    /// Internal Method: Race::poll() -> io::Result<Option<TcpStream>>
+    ///
+    ///     Moves every attempt along without blocking and starts the next
+    ///     one when it's due, `None` while none of them have connected. An
+    ///     attempt failing makes the next one due straight away.
+    ///
+    fn poll(&mut self) -> io::Result<Option<TcpStream>> {
+        loop {
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match finished(&self.attempts[i]) {
+                    Ok(None) => i += 1,
+                    Ok(Some(Ok(()))) => {
+                        // the losers are closed as they're dropped
+                        let sock = self.attempts.swap_remove(i);
+                        self.attempts.clear();
+                        return Ok(Some(sock.into()))
+                    },
+                    Ok(Some(Err(e))) | Err(e) => {
+                        self.attempts.swap_remove(i);
+                        self.error = Some(e);
+                        self.next_at = Instant::now();
+                    },
+                }
+            }
+
+            if !self.attempts.is_empty() && Instant::now() < self.next_at {
+                return Ok(None)
+            }
+
+            let addr = match self.addrs.pop() {
+                Some(addr) => addr,
+                None if self.attempts.is_empty() => {
+                    let error = self.error.take();
+                    return Err(error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the host has no addresses")))
+                },
+                None => return Ok(None),
+            };
+
+            let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+            sock.set_nonblocking(true)?;
+            match sock.connect(&addr.into()) {
+                Ok(()) => {
+                    self.attempts.clear();
+                    return Ok(Some(sock.into()))
+                },
+                Err(ref e) if in_progress(e) => {
+                    self.attempts.push(sock);
+                    self.next_at = Instant::now() + ATTEMPT_DELAY;
+                },
+                Err(e) => self.error = Some(e),
+            }
+        }
+    }
+}
+
+///
+/// Redial is how a connection a ConnectionPool handed out again gets
+/// itself back if the server closed it while it sat idle, the race being
+/// that nothing tells us until the first write or read fails. Whatever was
+/// written since it was handed out is kept so a new connection to the same
+/// address can be sent it again, once. The server answering with anything
+/// at all (so it was alive after all) or more than `REPLAY_LIMIT` being
+/// written drops the Redial.
+///
+pub(crate) struct Redial {
+    addr: SocketAddr,
+    tls: Option<ClientTls>,
+    sent: Vec<u8>,                  // Written since the connection was handed out
+    race: Option<Race>,             // The new connect, once the old one has been found dead
+}
+
+impl Redial {
+    pub(crate) fn new(addr: SocketAddr, tls: Option<ClientTls>) -> Self {
+        Self {
+            addr,
+            tls,
+            sent: Vec::new(),
+            race: None,
+        }
+    }
+
+    /// Keeps `data` to send again, `false` once there's more than we're willing to replay.
+    pub(crate) fn record(&mut self, data: &[u8]) -> bool {
+        self.sent.extend_from_slice(data);
+        self.race.is_some() || self.sent.len() <= REPLAY_LIMIT
+    }
+
+    pub(crate) fn start(&mut self) {
+        self.race = Some(Race::new(vec![self.addr]));
+    }
+
+    pub(crate) fn is_dialing(&self) -> bool {
+        self.race.is_some()
+    }
+
+    /// The new socket once it has connected, `None` while it's still connecting.
+    pub(crate) fn poll(&mut self) -> io::Result<Option<TcpStream>> {
+        match self.race.as_mut() {
+            Some(race) => race.poll(),
+            None => Ok(None),
+        }
+    }
+
+    /// The TLS session for the new socket and what has to be sent on it again.
+    pub(crate) fn finish(self) -> io::Result<(Option<TlsSession>, Vec<u8>)> {
+        let tls = self.tls.as_ref().map(ClientTls::session).transpose()?;
+        Ok((tls, self.sent))
+    }
+}
+
+/// A non-blocking connect that's underway, windows says so with WSAEWOULDBLOCK which is WouldBlock.
+fn in_progress(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::EINPROGRESS) {
+        return true
+    }
+
+    e.kind() == io::ErrorKind::WouldBlock
+}
+
+///
+/// If a connect in progress has finished, `Some` with how it went. The
+/// socket turns writable once it's done either way and SO_ERROR says if it
+/// worked.
+///
+#[cfg(unix)]
+fn finished(sock: &Socket) -> io::Result<Option<io::Result<()>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut fd = libc::pollfd { fd: sock.as_raw_fd(), events: libc::POLLOUT, revents: 0 };
+
+    // SAFETY: one valid pollfd for a socket we own, a timeout of 0 never blocks
+    match unsafe { libc::poll(&mut fd, 1, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(None),
+        _ => Ok(Some(so_error(sock))),
+    }
+}
+
+///
+/// Windows only reports a connect that failed through select()'s
+/// exceptfds, SO_ERROR isn't even set until select() has looked at the
+/// socket, so that's what we ask rather than poll().
+///
+#[cfg(windows)]
+fn finished(sock: &Socket) -> io::Result<Option<io::Result<()>>> {
+    use std::os::windows::io::AsRawSocket;
+
+    // winsock's fd_set is a count and that many SOCKETs, one is all we need
+    #[repr(C)]
+    struct FdSet {
+        count: u32,
+        sockets: [usize; 1],
+    }
+
+    #[repr(C)]
+    struct TimeVal {
+        sec: i32,
+        usec: i32,
+    }
+
+    #[link(name = "ws2_32")]
+    extern "system" {
+        fn select(nfds: i32, read: *mut FdSet, write: *mut FdSet, except: *mut FdSet, timeout: *const TimeVal) -> i32;
+    }
+
+    let raw = sock.as_raw_socket() as usize;
+    let mut write = FdSet { count: 1, sockets: [raw] };
+    let mut except = FdSet { count: 1, sockets: [raw] };
+    let timeout = TimeVal { sec: 0, usec: 0 };
+
+    // SAFETY: both sets hold the one socket we own and a zero timeout never blocks
+    match unsafe { select(0, std::ptr::null_mut(), &mut write, &mut except, &timeout) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(None),
+        _ if except.count > 0 => Ok(Some(Err(sock.take_error()?.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::ConnectionRefused, "the connection failed")
+        })))),
+        _ => Ok(Some(so_error(sock))),
+    }
+}
+
+/// Without poll() or winsock a finished connect is one with an error or a peer.
+#[cfg(not(any(unix, windows)))]
+fn finished(sock: &Socket) -> io::Result<Option<io::Result<()>>> {
+    match sock.take_error()? {
+        Some(e) => Ok(Some(Err(e))),
+        None if sock.peer_addr().is_ok() => Ok(Some(Ok(()))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(any(unix, windows))]
+fn so_error(sock: &Socket) -> io::Result<()> {
+    match sock.take_error()? {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for Connect {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for Connect {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+        let this = &mut *slf;
+
+        let transport = match this.poll(py)? {
+            Some(transport) => transport,
+            None => {
+                let (sleeper, _) = this.started.as_mut().unwrap();
+                return Ok(IterNextOutput::Yield(sleeper._iter_sleep(py)))
+            },
+        };
+
+        let (sleeper, _) = this.started.as_ref().unwrap();
+        let loop_ = sleeper.loop_.clone_ref(py);
+        let reader = Py::new(py, Reader::new(transport.clone(), loop_.clone_ref(py)))?;
+        let writer = Py::new(py, Writer::new(transport, loop_, stream::DEFAULT_HIGH_WATER)?)?;
+
+        Ok(IterNextOutput::Return(Some((reader, writer).into_py(py))))
+    }
+}
@@ -0,0 +1,279 @@
+use crate::deflate::Deflater;
+use crate::headers::{HeaderName, Headers};
+use crate::http::HTTPResponse;
+use crate::options::RunnerOptions;
+
+
+/// Responses smaller than this aren't worth compressing by default.
+pub(crate) const DEFAULT_MIN_SIZE: usize = 1024;
+
+///
+/// The content types compressed by default, a `/*` entry covers the
+/// whole type. Images, video and archives are already compressed so
+/// they're left out.
+///
+pub(crate) const DEFAULT_TYPES: &[&str] = &[
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/xhtml+xml",
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+
+///
+/// The content codings we can compress a response with.
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Encoding {
+    Gzip,
+    Deflate,    // The zlib format (RFC 1950) which is what `deflate` means over HTTP
+}
+
+impl Encoding {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+}
+
+///
+/// Internal Method: accepted() -> Option<Encoding>
+///
+///     The encoding to use for a request's response if compression is on
+///     and its `Accept-Encoding` allows one, see `negotiate()`.
+///
+pub(crate) fn accepted(options: &RunnerOptions, headers: &Headers) -> Option<Encoding> {
+    if !options.compress {
+        return None
+    }
+
+    negotiate(headers.get_all(HeaderName::AcceptEncoding))
+}
+
+///
+/// Picks an encoding from `Accept-Encoding` values by their q-values, gzip
+/// wins a tie. `*` stands in for any coding not named and `q=0` rules one
+/// out.
+///
+fn negotiate<'a>(values: impl Iterator<Item = &'a str>) -> Option<Encoding> {
+    let (mut gzip, mut deflate, mut any) = (None, None, None);
+
+    for coding in values.flat_map(|value| value.split(',')) {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let q = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok());
+
+        // a q-value we can't read rules the coding out rather than guessing
+        let q = q.unwrap_or(0.0);
+        if name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") {
+            gzip = Some(q);
+        } else if name.eq_ignore_ascii_case("deflate") {
+            deflate = Some(q);
+        } else if name == "*" {
+            any = Some(q);
+        }
+    }
+
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let deflate = deflate.or(any).unwrap_or(0.0);
+    match (gzip, deflate) {
+        (gzip, deflate) if gzip > 0.0 && gzip >= deflate => Some(Encoding::Gzip),
+        (_, deflate) if deflate > 0.0 => Some(Encoding::Deflate),
+        _ => None,
+    }
+}
+
+///
+/// Internal Method: compressible() -> bool
+///
+///     If a response should be compressed when the client accepts it,
+///     `length` is the size of the body or `None` if it's streamed (and so
+///     always big enough).
+///
+///     Nothing is compressed that has no body, already has a
+///     `Content-Encoding` or a `Content-Length` of its own (file responses
+///     go out as they are), asks for `Cache-Control: no-transform` or whose
+///     `Content-Type` isn't in `compress_types`. A response without a
+///     `Content-Type` is compressed.
+///
+pub(crate) fn compressible(options: &RunnerOptions, response: &HTTPResponse, length: Option<usize>) -> bool {
+    if !options.compress {
+        return false
+    }
+
+    let status = response.status;
+    if status < 200 || status == 204 || status == 206 || status == 304 {
+        return false
+    }
+
+    if response.header("content-encoding").is_some() || response.header("content-length").is_some() {
+        return false
+    }
+
+    let no_transform = response.header("cache-control").is_some_and(|value| {
+        value.split(',').any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+    });
+    if no_transform || length.is_some_and(|length| length < options.compress_min_size) {
+        return false
+    }
+
+    match response.header("content-type") {
+        Some(content_type) => type_allowed(&options.compress_types, content_type),
+        None => true,
+    }
+}
+
+fn type_allowed(allowed: &[String], content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    allowed.iter().any(|allowed| match allowed.strip_suffix("/*") {
+        Some(prefix) => media_type.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/')),
+        None => *allowed == media_type,
+    })
+}
+
+
+///
+/// Encoder wraps a Deflater in the gzip (RFC 1952) or zlib (RFC 1950)
+/// format, the header goes out with the first write and the checksum
+/// trailer with `finish()`.
+///
+pub(crate) struct Encoder {
+    encoding: Encoding,
+    deflater: Deflater,
+    checksum: Checksum,
+    length: u32,        // The uncompressed size mod 2^32 for the gzip trailer
+    started: bool,      // If the header has been written
+}
+
+impl Encoder {
+    pub(crate) fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            deflater: Deflater::new(),
+            checksum: Checksum::new(encoding),
+            length: 0,
+            started: false,
+        }
+    }
+
+    /// Compresses all of `data` in one go.
+    pub(crate) fn compress(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+        let mut encoder = Self::new(encoding);
+        let mut out = Vec::with_capacity(data.len() / 2 + 32);
+
+        encoder.header(&mut out);
+        encoder.update(data);
+        Deflater::compress(data, &mut out);
+        encoder.trailer(&mut out);
+
+        out
+    }
+
+    /// Compresses the next piece of a streamed body, everything so far can be decompressed afterwards.
+    pub(crate) fn write(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.header(out);
+        self.update(data);
+        self.deflater.write(data, out);
+    }
+
+    /// Ends the stream.
+    pub(crate) fn finish(&mut self, out: &mut Vec<u8>) {
+        self.header(out);
+        self.deflater.finish(out);
+        self.trailer(out);
+    }
+
+    fn header(&mut self, out: &mut Vec<u8>) {
+        if self.started {
+            return
+        }
+
+        self.started = true;
+        match self.encoding {
+            // no name, no mtime and an unknown OS
+            Encoding::Gzip => out.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]),
+            Encoding::Deflate => out.extend_from_slice(&[0x78, 0x9c]),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.checksum.update(data);
+        self.length = self.length.wrapping_add(data.len() as u32);
+    }
+
+    fn trailer(&self, out: &mut Vec<u8>) {
+        match self.checksum {
+            Checksum::Crc32(crc) => {
+                out.extend_from_slice(&(!crc).to_le_bytes());
+                out.extend_from_slice(&self.length.to_le_bytes());
+            },
+            Checksum::Adler32(a, b) => out.extend_from_slice(&((b << 16) | a).to_be_bytes()),
+        }
+    }
+}
+
+enum Checksum {
+    Crc32(u32),         // Kept inverted until the trailer
+    Adler32(u32, u32),
+}
+
+impl Checksum {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Gzip => Self::Crc32(!0),
+            Encoding::Deflate => Self::Adler32(1, 0),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Crc32(crc) => {
+                for &byte in data {
+                    *crc = CRC_TABLE[((*crc ^ u32::from(byte)) & 0xff) as usize] ^ (*crc >> 8);
+                }
+            },
+            Self::Adler32(a, b) => {
+                // 5552 bytes is the most that can be summed before the u32s overflow
+                for chunk in data.chunks(5552) {
+                    for &byte in chunk {
+                        *a += u32::from(byte);
+                        *b += *a;
+                    }
+                    *a %= 65521;
+                    *b %= 65521;
+                }
+            },
+        }
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = match c & 1 {
+                1 => 0xedb88320 ^ (c >> 1),
+                _ => c >> 1,
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
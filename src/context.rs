@@ -0,0 +1,148 @@
+use pyo3::prelude::*;
+use pyo3::{ffi, AsPyPointer, PyIterProtocol};
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+
+use std::os::raw::c_int;
+
+use crate::sleep::Awaiting;
+
+
+// Part of the C API since 3.7, pyo3 just doesn't bind them yet.
+extern "C" {
+    fn PyContext_Enter(ctx: *mut ffi::PyObject) -> c_int;
+    fn PyContext_Exit(ctx: *mut ffi::PyObject) -> c_int;
+}
+
+
+/// A copy of the `contextvars.Context` we're running in right now.
+pub(crate) fn capture(py: Python) -> PyResult<PyObject> {
+    Ok(py.import("contextvars")?.call0("copy_context")?.into())
+}
+
+/// A copy of `context`, for a request that shouldn't see what the others set or have them see what it sets.
+pub(crate) fn copy(py: Python, context: &PyObject) -> PyResult<PyObject> {
+    context.call_method0(py, "copy")
+}
+
+///
+/// Internal Method: context::spawn() -> PyResult<&PyAny>
+///
+///     `asyncio.ensure_future(awaitable)`, the task it makes running in a
+///     copy of `context` rather than whatever context we're in now.
+///     That's the task copying the context current when it's made, so it
+///     works on every version rather than only with 3.11's
+///     `create_task(context=...)` (which also only takes coroutines).
+///
+pub(crate) fn spawn<'p>(py: Python<'p>, context: Option<&PyObject>, awaitable: impl IntoPy<PyObject>) -> PyResult<&'p PyAny> {
+    let ensure_future = py.import("asyncio")?.getattr("ensure_future")?;
+    match context {
+        Some(context) => Ok(context.call_method1(py, "run", (ensure_future, awaitable))?.into_ref(py)),
+        None => ensure_future.call1((awaitable,)),
+    }
+}
+
+///
+/// Internal Method: context::spawn_in() -> PyResult<&PyAny>
+///
+///     `spawn()` with every step of `awaitable` taken in `context` itself
+///     (see InContext) rather than the task's copy of it, how an HTTP/2
+///     stream's callback and streamed body are run.
+///
+pub(crate) fn spawn_in<'p>(py: Python<'p>, context: Option<&PyObject>, awaitable: &PyAny) -> PyResult<&'p PyAny> {
+    match context.map(|context| InContext::of(awaitable, context.clone_ref(py))).transpose()?.flatten() {
+        Some(awaitable) => spawn(py, context, Py::new(py, awaitable)?),
+        None => spawn(py, None, awaitable),
+    }
+}
+
+///
+/// Makes `context` the current one until the guard is dropped, like
+/// `context.run()` does for a call but around whatever Rust code we like,
+/// e.g. stepping a handler's coroutine. Contexts can be entered one inside
+/// another but not the same one twice, `enter()` raises a RuntimeError then.
+///
+pub(crate) struct Entered(PyObject);
+
+pub(crate) fn enter(py: Python, context: Option<&PyObject>) -> PyResult<Option<Entered>> {
+    let context = match context {
+        Some(context) => context.clone_ref(py),
+        None => return Ok(None),
+    };
+
+    // SAFETY: we have the GIL and `context` is a contextvars.Context, which we hold on to until we exit it
+    if unsafe { PyContext_Enter(context.as_ptr()) } == -1 {
+        return Err(PyErr::fetch(py))
+    }
+
+    Ok(Some(Entered(context)))
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let gil = Python::acquire_gil();
+        // SAFETY: entered in `enter()`, and anything entered since has been exited by its own guard
+        if unsafe { PyContext_Exit(self.0.as_ptr()) } == -1 {
+            PyErr::fetch(gil.python()).print(gil.python());
+        }
+    }
+}
+
+
+///
+/// InContext awaits something else with `context` entered for each step,
+/// so all of it runs in that one context rather than the copy the task
+/// awaiting us made. An HTTP/2 stream's callback is awaited through one so
+/// its access log sees whatever it set, however late, and so is its
+/// streamed body.
+///
+#[pyclass]
+pub(crate) struct InContext {
+    awaiting: Awaiting,
+    context: PyObject,
+}
+
+impl InContext {
+    /// `awaitable` in `context`, None if it isn't awaitable.
+    pub(crate) fn of(awaitable: &PyAny, context: PyObject) -> PyResult<Option<Self>> {
+        Ok(Awaiting::of(awaitable)?.map(|awaiting| Self { awaiting, context }))
+    }
+}
+
+#[pymethods]
+impl InContext {
+    ///
+    /// PythonMethod: InContext.throw(type, value=None, traceback=None)
+    ///
+    ///     Passes an exception (the CancelledError when the stream is
+    ///     reset) on into what we're awaiting, in the context too.
+    ///
+    #[args(value = "None", traceback = "None")]
+    fn throw(&self, py: Python, type_: &PyAny, value: Option<&PyAny>, traceback: Option<&PyAny>) -> PyResult<PyObject> {
+        let _entered = enter(py, Some(&self.context))?;
+        self.awaiting.throw(py, type_, value, traceback)
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for InContext {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for InContext {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(slf: PyRef<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        let py = slf.py();
+        let _entered = enter(py, Some(&slf.context))?;
+        match slf.awaiting.step(py, None)? {
+            IterNextOutput::Yield(yielded) => Ok(IterNextOutput::Yield(Some(yielded))),
+            IterNextOutput::Return(result) => Ok(IterNextOutput::Return(Some(result))),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::{PyTypeError, PyValueError};
+
+use crate::http;
+
+
+///
+/// Parses a `Cookie` header value, `name=value` pairs split by `;`.
+/// Surrounding quotes are removed from values and escapes decoded, empty
+/// segments and segments without a `=` are skipped, as are names we've
+/// already seen.
+///
+pub(crate) fn parse_cookies(header: &str, cookies: &mut Vec<(String, String)>) {
+    for pair in header.split(';') {
+        let (name, value) = match pair.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+
+        if name.is_empty() || cookies.iter().any(|(existing, _)| existing == name) {
+            continue
+        }
+
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(unquoted) => unquoted,
+            None => value,
+        };
+
+        cookies.push((name.to_string(), http::percent_decode(value)));
+    }
+}
+
+
+///
+/// A `Set-Cookie` header being put together by `HTTPResponse.set_cookie()`,
+/// everything is checked here so a bad name or attribute is an error in the
+/// handler rather than a header the browser quietly ignores.
+///
+pub(crate) struct SetCookie<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) value: &'a str,
+    pub(crate) max_age: Option<i64>,
+    pub(crate) expires: Option<&'a PyAny>,  // A datetime, unix timestamp or preformatted str
+    pub(crate) path: Option<&'a str>,
+    pub(crate) domain: Option<&'a str>,
+    pub(crate) secure: bool,
+    pub(crate) httponly: bool,
+    pub(crate) samesite: Option<&'a str>,
+}
+
+impl SetCookie<'_> {
+    ///
+    /// Internal Method: SetCookie::to_header() -> PyResult<String>
+    ///
+    ///     The header value, a `ValueError` for names with characters that
+    ///     aren't allowed and attributes which would break the header.
+    ///
+    pub(crate) fn to_header(&self) -> PyResult<String> {
+        if self.name.is_empty() || !self.name.bytes().all(is_token) {
+            return Err(PyValueError::new_err(format!("invalid cookie name {:?}", self.name)))
+        }
+
+        let mut header = format!("{}={}", self.name, encode_value(self.value));
+
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age));
+        }
+
+        if let Some(expires) = self.expires {
+            header.push_str(&format!("; Expires={}", format_expires(expires)?));
+        }
+
+        if let Some(domain) = self.domain {
+            header.push_str(&format!("; Domain={}", attribute("domain", domain)?));
+        }
+
+        if let Some(path) = self.path {
+            header.push_str(&format!("; Path={}", attribute("path", path)?));
+        }
+
+        if self.secure {
+            header.push_str("; Secure");
+        }
+
+        if self.httponly {
+            header.push_str("; HttpOnly");
+        }
+
+        if let Some(samesite) = self.samesite {
+            let samesite = match samesite.to_ascii_lowercase().as_str() {
+                "strict" => "Strict",
+                "lax" => "Lax",
+                "none" => "None",
+                _ => return Err(PyValueError::new_err("samesite must be one of 'Strict', 'Lax' or 'None'")),
+            };
+
+            header.push_str(&format!("; SameSite={}", samesite));
+        }
+
+        Ok(header)
+    }
+}
+
+/// Characters allowed in a cookie name, a RFC 7230 token.
+pub(crate) fn is_token(b: u8) -> bool {
+    b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b)
+}
+
+///
+/// Percent-encodes anything in a value which isn't allowed as a
+/// `cookie-octet` (and `%` itself) so `request.cookies` decodes it back to
+/// what was set.
+///
+fn encode_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for &b in value.as_bytes() {
+        match b {
+            0x21 | 0x23..=0x24 | 0x26..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    out
+}
+
+fn attribute<'a>(name: &str, value: &'a str) -> PyResult<&'a str> {
+    if value.bytes().any(|b| b == b';' || b.is_ascii_control()) {
+        return Err(PyValueError::new_err(format!("invalid cookie {} {:?}", name, value)))
+    }
+
+    Ok(value)
+}
+
+fn format_expires(expires: &PyAny) -> PyResult<String> {
+    if let Ok(expires) = expires.extract::<&str>() {
+        return attribute("expires", expires).map(String::from)
+    }
+
+    let timestamp: f64 = match expires.hasattr("timestamp")? {
+        true => expires.call_method0("timestamp")?.extract()?,
+        false => expires.extract().map_err(|_| {
+            PyTypeError::new_err("expires must be a datetime, a unix timestamp or a str")
+        })?,
+    };
+
+    Ok(http::http_date(timestamp.max(0.0) as u64))
+}
@@ -0,0 +1,222 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::types::PyDict;
+
+use crate::headers::{HeaderName, Headers};
+use crate::http::HTTPResponse;
+
+
+///
+/// Cors is the `cors` option, the cross-origin requests we answer for
+/// browsers so handlers don't have to. A preflight (an `OPTIONS` with
+/// `Origin` and `Access-Control-Request-Method`) never reaches the callback,
+/// it's answered by `preflight()`, and every other request from an allowed
+/// origin has `Access-Control-Allow-Origin` added to its response.
+///
+///     Keys:
+///         - origins:      list[str] | "*"     (exact origins, `https://*.example.com` for any subdomain, `"*"` for any, required)
+///         - methods:      list[str]   (what a preflight allows, defaults to GET, HEAD, POST, PUT, PATCH and DELETE)
+///         - headers:      list[str]   (the request headers a preflight allows, `"*"` allows whatever was asked for, none by default)
+///         - max_age:      int         (seconds a browser can cache a preflight for, left to the browser by default)
+///         - allow_credentials: bool   (let cookies and auth go with cross-origin requests, defaults to false)
+///
+pub(crate) struct Cors {
+    any_origin: bool,
+    origins: Vec<String>,               // Lowercased, exact or `scheme://*.domain` patterns
+    methods: String,                    // Already joined for `Access-Control-Allow-Methods`
+    headers: Vec<String>,
+    any_header: bool,
+    max_age: Option<u32>,
+    credentials: bool,
+}
+
+impl Cors {
+    pub(crate) fn from_py(value: &PyAny) -> PyResult<Self> {
+        let config: &PyDict = value.downcast().map_err(|_| PyTypeError::new_err("cors must be a dict"))?;
+
+        let mut cors = Self {
+            any_origin: false,
+            origins: Vec::new(),
+            methods: String::from(DEFAULT_METHODS),
+            headers: Vec::new(),
+            any_header: false,
+            max_age: None,
+            credentials: false,
+        };
+
+        let mut has_origins = false;
+        for (key, value) in config.iter() {
+            let key: &str = key.extract()?;
+            if value.is_none() {
+                continue
+            }
+
+            match key {
+                "origins" => {
+                    let origins: Vec<String> = match value.extract::<&str>() {
+                        Ok(origin) => vec![origin.to_string()],
+                        Err(_) => value.extract()?,
+                    };
+
+                    for origin in origins.iter().map(|origin| origin.trim().to_ascii_lowercase()) {
+                        if origin == "*" {
+                            cors.any_origin = true;
+                        } else if !valid_pattern(&origin) {
+                            return Err(PyValueError::new_err(format!(
+                                "invalid cors origin '{}', expected `scheme://host[:port]` or `scheme://*.domain`", origin,
+                            )))
+                        } else {
+                            cors.origins.push(origin);
+                        }
+                    }
+                    has_origins = true;
+                },
+                "methods" => {
+                    let methods: Vec<String> = value.extract()?;
+                    cors.methods = methods.iter().map(|method| method.trim().to_ascii_uppercase()).collect::<Vec<_>>().join(", ");
+                },
+                "headers" => {
+                    let headers: Vec<String> = value.extract()?;
+                    for header in headers.iter().map(|header| header.trim()) {
+                        match header {
+                            "*" => cors.any_header = true,
+                            header => cors.headers.push(header.to_string()),
+                        }
+                    }
+                },
+                "max_age" => cors.max_age = Some(value.extract()?),
+                "allow_credentials" => cors.credentials = value.is_true()?,
+                _ => return Err(PyTypeError::new_err(format!("cors got an unexpected key '{}'", key))),
+            }
+        }
+
+        if !has_origins {
+            return Err(PyValueError::new_err("cors needs the origins it allows"))
+        }
+
+        Ok(cors)
+    }
+
+    ///
+    /// Internal Method: Cors::allow_origin() -> Option<String>
+    ///
+    ///     What `Access-Control-Allow-Origin` should be for a request, None
+    ///     when it isn't cross-origin or its origin isn't allowed. It's only
+    ///     `*` without `allow_credentials`, browsers won't send credentials
+    ///     to a wildcard so otherwise the origin is echoed back.
+    ///
+    pub(crate) fn allow_origin(&self, headers: &Headers) -> Option<String> {
+        let origin = headers.get(HeaderName::Origin)?.trim();
+        if self.any_origin && !self.credentials {
+            return Some(String::from("*"))
+        }
+
+        let lowered = origin.to_ascii_lowercase();
+        match self.any_origin || self.origins.iter().any(|pattern| origin_matches(pattern, &lowered)) {
+            true => Some(origin.to_string()),
+            false => None,
+        }
+    }
+
+    ///
+    /// Internal Method: Cors::preflight() -> Option<HTTPResponse>
+    ///
+    ///     The answer to a preflight, None for anything that isn't one so it
+    ///     goes on to the callback as normal. A preflight from an origin we
+    ///     don't allow is refused with a `403`.
+    ///
+    pub(crate) fn preflight(&self, method: &str, headers: &Headers) -> Option<HTTPResponse> {
+        if method != "OPTIONS" || !headers.contains(HeaderName::AccessControlRequestMethod) {
+            return None
+        }
+
+        let origin = match self.allow_origin(headers) {
+            Some(origin) => origin,
+            None if headers.contains(HeaderName::Origin) => return Some(HTTPResponse::refused(403, "cross-origin request not allowed")),
+            None => return None,
+        };
+
+        let echoed = origin != "*";
+        let mut allowed = vec![
+            (String::from("Access-Control-Allow-Origin"), origin),
+            (String::from("Access-Control-Allow-Methods"), self.methods.clone()),
+        ];
+
+        let requested = headers.get(HeaderName::AccessControlRequestHeaders).map(str::trim).filter(|requested| !requested.is_empty());
+        match requested {
+            Some(requested) if self.any_header => {
+                allowed.push((String::from("Access-Control-Allow-Headers"), requested.to_string()));
+            },
+            _ if !self.headers.is_empty() => {
+                allowed.push((String::from("Access-Control-Allow-Headers"), self.headers.join(", ")));
+            },
+            _ => {},
+        }
+
+        if let Some(max_age) = self.max_age {
+            allowed.push((String::from("Access-Control-Max-Age"), max_age.to_string()));
+        }
+
+        if self.credentials {
+            allowed.push((String::from("Access-Control-Allow-Credentials"), String::from("true")));
+        }
+
+        if echoed {
+            allowed.push((String::from("Vary"), String::from("Origin")));
+        }
+
+        Some(HTTPResponse::from_parts(204, allowed, Vec::new()))
+    }
+
+    ///
+    /// The headers added to an actual request's response alongside the
+    /// `Access-Control-Allow-Origin` from `allow_origin()`. An echoed origin
+    /// means the response differs by `Origin` so caches are told as much.
+    ///
+    pub(crate) fn defaults<'a>(&self, origin: &'a str, defaults: &mut Vec<(&str, &'a str)>) {
+        defaults.push(("Access-Control-Allow-Origin", origin));
+        if self.credentials {
+            defaults.push(("Access-Control-Allow-Credentials", "true"));
+        }
+
+        if origin != "*" {
+            defaults.push(("Vary", "Origin"));
+        }
+    }
+}
+
+/// The methods a preflight allows when `methods` isn't given.
+const DEFAULT_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE";
+
+/// If an origin from the config is `scheme://host[:port]`, with `*.` allowed at the start of the host.
+fn valid_pattern(origin: &str) -> bool {
+    let host = match origin.split_once("://") {
+        Some((scheme, host)) if !scheme.is_empty() && scheme.bytes().all(|b| b.is_ascii_alphanumeric() || b"+-.".contains(&b)) => host,
+        _ => return origin == "null",
+    };
+
+    let host = host.strip_prefix("*.").unwrap_or(host);
+    !host.is_empty() && host.bytes().all(|b| b.is_ascii_alphanumeric() || b"-.:[]".contains(&b))
+}
+
+///
+/// Checks an origin (already lowercased) against one of the `origins`,
+/// `https://*.example.com` matches any subdomain of `example.com` over
+/// https on the default port but not `https://example.com` itself.
+///
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let (scheme, domain) = match pattern.split_once("://*.") {
+        Some(wildcard) => wildcard,
+        None => return pattern == origin,
+    };
+
+    let host = match origin.split_once("://") {
+        Some((origin_scheme, host)) if origin_scheme == scheme => host,
+        _ => return false,
+    };
+
+    match host.strip_suffix(domain).and_then(|sub| sub.strip_suffix('.')) {
+        Some(sub) => !sub.is_empty() && sub.bytes().all(|b| b.is_ascii_alphanumeric() || b"-.".contains(&b)),
+        None => false,
+    }
+}
@@ -0,0 +1,167 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::types::PyBytes;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::io;
+
+use crate::get_loop;
+use crate::errors;
+use crate::log;
+use crate::sleep::LoopSleeper;
+
+
+/// The largest payload a UDP datagram can carry.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+
+///
+/// The AsyncDatagramRunner is the UDP counterpart of the AsyncServerRunner,
+/// it binds a non-blocking UdpSocket and polls `recv_from` when awaited.
+///
+/// Every datagram is handed to the callback as `callback(data, (host, port))`,
+/// if the callback returns bytes they are sent back to the same address.
+///
+#[pyclass]
+pub struct AsyncDatagramRunner {
+    // External inputs
+    callback: PyObject,
+
+    // Internal systems
+    socket: UdpSocket,          // The non-blocking UDP socket
+    state: u8,                  // A int representing the asyncio state, either 0, 1, 2 or Error
+    exit: bool,                 // A bool to signal if the runner should shutdown and return
+    sleeper: LoopSleeper,       // The non-blocking sleep between loop iterations to save CPU
+    buffer: Vec<u8>,            // Reused receive buffer large enough for any datagram
+}
+
+#[pymethods]
+impl AsyncDatagramRunner {
+
+    ///
+    /// PythonMethod: AsyncDatagramRunner::new() -> Self
+    ///
+    ///     new() binds the UdpSocket and aquires the asyncio event loop,
+    ///     default state is set to `0`, exit `false` and clock delay `0.01`.
+    ///
+    ///     Requires:
+    ///         - binding_addr: String
+    ///         - callback:     PyObject
+    ///
+    #[new]
+    fn new(py: Python, binding_addr: String, callback: PyObject) -> PyResult<Self> {
+        let socket = UdpSocket::bind(binding_addr).map_err(|e| errors::bind_error(py, e))?;
+        socket.set_nonblocking(true)?;
+
+        let loop_ = get_loop(py)?.into_py(py);
+
+        Ok(AsyncDatagramRunner {
+            callback,
+            socket,
+            state: 0,
+            exit: false,
+            sleeper: LoopSleeper::new(loop_, 0.01),
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+        })
+    }
+
+    ///
+    /// PythonMethod: AsyncDatagramRunner.local_addr() -> (str, int)
+    ///
+    ///     The `(host, port)` the socket actually bound to.
+    ///
+    fn local_addr(&self) -> PyResult<(String, u16)> {
+        let addr = self.socket.local_addr()?;
+        Ok((addr.ip().to_string(), addr.port()))
+    }
+}
+
+impl AsyncDatagramRunner {
+
+    ///
+    /// Internal Method: AsyncDatagramRunner::handle() -> PyResult<()>
+    ///
+    ///     Invokes the callback with the datagram and the sender's address,
+    ///     sending back whatever bytes it returns. `None` means no reply.
+    ///
+    fn handle(&mut self, py: Python, len: usize, addr: SocketAddr) -> PyResult<()> {
+        let data = PyBytes::new(py, &self.buffer[..len]);
+        let peer = (addr.ip().to_string(), addr.port());
+
+        let reply = self.callback.call1(py, (data, peer)).map_err(|e| errors::handler_error(py, &e))?;
+        if reply.is_none(py) {
+            return Ok(())
+        }
+
+        let reply: &PyBytes = reply.extract(py)?;
+        match self.socket.send_to(reply.as_bytes(), addr) {
+            Ok(_) => Ok(()),
+
+            // the reply is dropped like any other lost datagram.
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for AsyncDatagramRunner {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for AsyncDatagramRunner {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    ///
+    /// Works the same way as the AsyncServerRunner, state `1` polls the socket
+    /// for the next datagram and state `2` sleeps when there wasn't one.
+    ///
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+
+        if slf.state == 0 {
+            slf.state = 1;
+        }
+
+        if slf.state == 1 {
+            let this = &mut *slf;
+            match this.socket.recv_from(&mut this.buffer) {
+                Ok((len, addr)) => {
+                    // a bad callback shouldn't bring the whole endpoint down.
+                    if let Err(e) = this.handle(py, len, addr) {
+                        e.print(py);
+                    }
+
+                    return Ok(IterNextOutput::Yield(None))
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => log::socket_error("failed to receive a datagram", &e),
+            }
+
+            if this.exit {
+                return Ok(IterNextOutput::Return(None))
+            }
+
+            this.state = 2;
+        }
+
+        if slf.state == 2 {
+            let nxt = slf.sleeper._iter_sleep(py);
+            if nxt.is_none() {
+                slf.state = 1;
+            }
+
+            return Ok(IterNextOutput::Yield(nxt))
+        }
+
+        Ok(IterNextOutput::Return(None))
+    }
+}
@@ -0,0 +1,601 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+
+/// How far back a match can reach.
+const WINDOW: usize = 32 * 1024;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Sizes of the match finder's hash table and how many candidates it tries.
+const HASH_BITS: u32 = 15;
+const MAX_CHAIN: usize = 128;
+
+/// How many tokens go into one block before it's written out.
+const BLOCK_TOKENS: usize = 16 * 1024;
+
+/// The most a stored block can hold.
+const MAX_STORED: usize = 65535;
+
+const END_OF_BLOCK: usize = 256;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// The order the code length code lengths are sent in.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+
+///
+/// Deflater is a raw deflate (RFC 1951) compressor that can be fed a bit
+/// at a time, matches reach back into what was written before so a
+/// streamed body compresses about as well as one done in one go.
+///
+/// Each block is written with whichever of fixed Huffman codes, codes
+/// built for that block or no compression at all comes out smallest.
+///
+pub(crate) struct Deflater {
+    window: Vec<u8>,        // The input still in reach of a match, at most 2 * WINDOW
+    head: Vec<u32>,         // The latest window position (+ 1) for each hash, 0 for none
+    prev: Vec<u32>,         // The position before it with the same hash, by position & (WINDOW - 1)
+    tokens: Vec<Token>,     // The block being built
+    block_start: usize,     // Where in `window` the block being built starts
+    bits: BitWriter,
+}
+
+#[derive(Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+impl Deflater {
+    pub(crate) fn new() -> Self {
+        Self {
+            window: Vec::new(),
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; WINDOW],
+            tokens: Vec::new(),
+            block_start: 0,
+            bits: BitWriter::default(),
+        }
+    }
+
+    ///
+    /// Internal Method: Deflater::write()
+    ///
+    ///     Compresses `data` onto `out`, ending on a sync flush so everything
+    ///     written so far can be decompressed by the other end already.
+    ///
+    pub(crate) fn write(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.deflate(data, false, out);
+
+        // an empty stored block, the byte aligned `00 00 ff ff` marker
+        self.bits.put(0, 3, out);
+        self.bits.align(out);
+        out.extend_from_slice(&[0, 0, 0xff, 0xff]);
+    }
+
+    /// Ends the stream with an empty final block after what `write` gave us.
+    pub(crate) fn finish(&mut self, out: &mut Vec<u8>) {
+        self.bits.put(1, 1, out);
+        self.bits.put(1, 2, out);
+        self.bits.put(0, 7, out);   // the fixed code for end of block is seven 0 bits
+        self.bits.align(out);
+    }
+
+    /// Compresses all of `data` as a complete stream.
+    pub(crate) fn compress(data: &[u8], out: &mut Vec<u8>) {
+        let mut deflater = Self::new();
+        deflater.deflate(data, true, out);
+    }
+
+    fn deflate(&mut self, mut data: &[u8], last: bool, out: &mut Vec<u8>) {
+        loop {
+            if self.window.len() >= 2 * WINDOW {
+                self.slide();
+            }
+
+            let take = data.len().min(2 * WINDOW - self.window.len());
+            let start = self.window.len();
+            self.window.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            self.tokenize(start, out);
+
+            let done = data.is_empty();
+            if !self.tokens.is_empty() || (done && last) {
+                self.emit_block(done && last, out);
+            }
+
+            if done {
+                return
+            }
+        }
+    }
+
+    /// Drops the older half of the window, everything in it has been written already.
+    fn slide(&mut self) {
+        self.window.drain(..WINDOW);
+        self.block_start -= WINDOW;
+
+        let shift = |pos: &mut u32| *pos = pos.saturating_sub(WINDOW as u32);
+        self.head.iter_mut().for_each(shift);
+        self.prev.iter_mut().for_each(shift);
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let bytes = [self.window[pos], self.window[pos + 1], self.window[pos + 2], 0];
+        (u32::from_le_bytes(bytes).wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, pos: usize) {
+        let hash = self.hash(pos);
+        self.prev[pos & (WINDOW - 1)] = self.head[hash];
+        self.head[hash] = pos as u32 + 1;
+    }
+
+    /// The longest earlier match for what's at `pos` as `(length, distance)`.
+    fn longest_match(&self, pos: usize) -> (usize, usize) {
+        let limit = (self.window.len() - pos).min(MAX_MATCH);
+        let mut best = (0, 0);
+
+        let mut candidate = self.head[self.hash(pos)] as usize;
+        for _ in 0..MAX_CHAIN {
+            if candidate == 0 || pos - (candidate - 1) > WINDOW {
+                break
+            }
+
+            let at = candidate - 1;
+            let length = self.window[at..at + limit]
+                .iter()
+                .zip(&self.window[pos..pos + limit])
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            if length > best.0 {
+                best = (length, pos - at);
+                if length == limit {
+                    break
+                }
+            }
+
+            // an older slot can have been reused by a newer position, never go forwards
+            let next = self.prev[at & (WINDOW - 1)] as usize;
+            if next >= candidate {
+                break
+            }
+            candidate = next;
+        }
+
+        best
+    }
+
+    /// Turns `window[from..]` into literals and matches, writing blocks as they fill up.
+    fn tokenize(&mut self, from: usize, out: &mut Vec<u8>) {
+        let end = self.window.len();
+        let mut pos = from;
+
+        while pos < end {
+            let mut found = (0, 0);
+            if pos + MIN_MATCH <= end {
+                found = self.longest_match(pos);
+                self.insert(pos);
+            }
+
+            if found.0 >= MIN_MATCH {
+                let (length, distance) = found;
+                for skipped in pos + 1..pos + length {
+                    if skipped + MIN_MATCH <= end {
+                        self.insert(skipped);
+                    }
+                }
+
+                self.tokens.push(Token::Match { length: length as u16, distance: distance as u16 });
+                pos += length;
+            } else {
+                self.tokens.push(Token::Literal(self.window[pos]));
+                pos += 1;
+            }
+
+            if self.tokens.len() >= BLOCK_TOKENS {
+                self.emit_block_to(pos, false, out);
+            }
+        }
+    }
+
+    fn emit_block(&mut self, last: bool, out: &mut Vec<u8>) {
+        self.emit_block_to(self.window.len(), last, out)
+    }
+
+    ///
+    /// Internal Method: Deflater::emit_block_to()
+    ///
+    ///     Writes the tokens so far as one block covering the input up to
+    ///     `end`, picking the cheapest way to encode it.
+    ///
+    fn emit_block_to(&mut self, end: usize, last: bool, out: &mut Vec<u8>) {
+        let raw_len = end - self.block_start;
+        let tokens = std::mem::take(&mut self.tokens);
+
+        let mut lit_freqs = [0u32; 286];
+        let mut dist_freqs = [0u32; 30];
+        let mut extra_bits = 0u64;
+        for token in tokens.iter() {
+            match *token {
+                Token::Literal(byte) => lit_freqs[byte as usize] += 1,
+                Token::Match { length, distance } => {
+                    let (symbol, extra, _) = length_code(length);
+                    let (dist_symbol, dist_extra, _) = distance_code(distance);
+                    lit_freqs[257 + symbol] += 1;
+                    dist_freqs[dist_symbol] += 1;
+                    extra_bits += u64::from(extra) + u64::from(dist_extra);
+                },
+            }
+        }
+        lit_freqs[END_OF_BLOCK] += 1;
+
+        let (fixed_lit, fixed_dist) = fixed_lengths();
+        let fixed_cost = 3 + cost(&lit_freqs, &fixed_lit) + cost(&dist_freqs, &fixed_dist) + extra_bits;
+
+        let dynamic = DynamicHeader::new(&lit_freqs, &dist_freqs);
+        let dynamic_cost = 3 + dynamic.cost + cost(&lit_freqs, &dynamic.lit) + cost(&dist_freqs, &dynamic.dist) + extra_bits;
+
+        let stored_cost = 3 + 7 + (raw_len.div_ceil(MAX_STORED).max(1) * 32 + raw_len * 8) as u64;
+
+        if stored_cost < fixed_cost.min(dynamic_cost) {
+            self.write_stored(end, last, out);
+        } else if dynamic_cost < fixed_cost {
+            self.bits.put(last as u32, 1, out);
+            self.bits.put(2, 2, out);
+            dynamic.write(&mut self.bits, out);
+            write_tokens(&mut self.bits, &tokens, &dynamic.lit, &dynamic.dist, out);
+        } else {
+            self.bits.put(last as u32, 1, out);
+            self.bits.put(1, 2, out);
+            write_tokens(&mut self.bits, &tokens, &fixed_lit, &fixed_dist, out);
+        }
+
+        if last {
+            self.bits.align(out);
+        }
+
+        self.block_start = end;
+        self.tokens = tokens;
+        self.tokens.clear();
+    }
+
+    fn write_stored(&mut self, end: usize, last: bool, out: &mut Vec<u8>) {
+        let raw = &self.window[self.block_start..end];
+        let mut chunks = raw.chunks(MAX_STORED).peekable();
+        if chunks.peek().is_none() {
+            self.bits.put(last as u32, 1, out);
+            self.bits.put(0, 2, out);
+            self.bits.align(out);
+            out.extend_from_slice(&[0, 0, 0xff, 0xff]);
+            return
+        }
+
+        while let Some(chunk) = chunks.next() {
+            let final_chunk = last && chunks.peek().is_none();
+            self.bits.put(final_chunk as u32, 1, out);
+            self.bits.put(0, 2, out);
+            self.bits.align(out);
+
+            let len = chunk.len() as u16;
+            out.extend_from_slice(&len.to_le_bytes());
+            out.extend_from_slice(&(!len).to_le_bytes());
+            out.extend_from_slice(chunk);
+        }
+    }
+}
+
+
+///
+/// Packs bits least significant first the way deflate wants them,
+/// Huffman codes are reversed before going in (see `put_code`).
+///
+#[derive(Default)]
+struct BitWriter {
+    acc: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, bits: u32, out: &mut Vec<u8>) {
+        self.acc |= u64::from(value) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn put_code(&mut self, code: u16, length: u8, out: &mut Vec<u8>) {
+        let reversed = code.reverse_bits() >> (16 - u32::from(length));
+        self.put(u32::from(reversed), u32::from(length), out);
+    }
+
+    fn align(&mut self, out: &mut Vec<u8>) {
+        if self.count > 0 {
+            out.push(self.acc as u8);
+        }
+        self.acc = 0;
+        self.count = 0;
+    }
+}
+
+
+/// The symbol, extra bit count and extra bits value for a match length.
+fn length_code(length: u16) -> (usize, u8, u16) {
+    let symbol = LENGTH_BASE.partition_point(|&base| base <= length) - 1;
+    (symbol, LENGTH_EXTRA[symbol], length - LENGTH_BASE[symbol])
+}
+
+/// The symbol, extra bit count and extra bits value for a match distance.
+fn distance_code(distance: u16) -> (usize, u8, u16) {
+    let symbol = DISTANCE_BASE.partition_point(|&base| base <= distance) - 1;
+    (symbol, DISTANCE_EXTRA[symbol], distance - DISTANCE_BASE[symbol])
+}
+
+/// The code lengths of the fixed Huffman codes (RFC 1951 section 3.2.6).
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut lit = vec![8u8; 288];
+    lit[144..256].fill(9);
+    lit[256..280].fill(7);
+    (lit, vec![5u8; 30])
+}
+
+fn cost(freqs: &[u32], lengths: &[u8]) -> u64 {
+    freqs.iter()
+        .zip(lengths)
+        .map(|(&freq, &length)| u64::from(freq) * u64::from(length))
+        .sum()
+}
+
+fn write_tokens(bits: &mut BitWriter, tokens: &[Token], lit: &[u8], dist: &[u8], out: &mut Vec<u8>) {
+    let lit_codes = canonical_codes(lit);
+    let dist_codes = canonical_codes(dist);
+
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => bits.put_code(lit_codes[byte as usize], lit[byte as usize], out),
+            Token::Match { length, distance } => {
+                let (symbol, extra, value) = length_code(length);
+                bits.put_code(lit_codes[257 + symbol], lit[257 + symbol], out);
+                bits.put(u32::from(value), u32::from(extra), out);
+
+                let (symbol, extra, value) = distance_code(distance);
+                bits.put_code(dist_codes[symbol], dist[symbol], out);
+                bits.put(u32::from(value), u32::from(extra), out);
+            },
+        }
+    }
+
+    bits.put_code(lit_codes[END_OF_BLOCK], lit[END_OF_BLOCK], out);
+}
+
+/// The canonical Huffman codes for a set of code lengths (RFC 1951 section 3.2.2).
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; 16];
+    for &length in lengths {
+        counts[length as usize] += 1;
+    }
+    counts[0] = 0;
+
+    let mut next = [0u16; 16];
+    let mut code = 0u16;
+    for bits in 1..16 {
+        code = (code + counts[bits - 1]) << 1;
+        next[bits] = code;
+    }
+
+    lengths.iter()
+        .map(|&length| {
+            if length == 0 {
+                return 0
+            }
+
+            let code = next[length as usize];
+            next[length as usize] += 1;
+            code
+        })
+        .collect()
+}
+
+///
+/// Huffman code lengths for `freqs` no longer than `limit`, if the tree
+/// comes out too deep the counts are flattened until it fits.
+///
+fn code_lengths(freqs: &[u32], limit: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    loop {
+        let lengths = huffman_lengths(&freqs);
+        if lengths.iter().all(|&length| length <= limit) {
+            return lengths
+        }
+
+        for freq in freqs.iter_mut().filter(|freq| **freq > 0) {
+            *freq = freq.div_ceil(2);
+        }
+    }
+}
+
+fn huffman_lengths(freqs: &[u32]) -> Vec<u8> {
+    let mut lengths = vec![0u8; freqs.len()];
+    let used: Vec<usize> = (0..freqs.len()).filter(|&symbol| freqs[symbol] > 0).collect();
+    if used.len() == 1 {
+        lengths[used[0]] = 1;
+    }
+    if used.len() <= 1 {
+        return lengths
+    }
+
+    // leaves are 0..used.len(), internal nodes get added after them
+    let mut parents = vec![0usize; used.len()];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = used.iter()
+        .enumerate()
+        .map(|(node, &symbol)| Reverse((u64::from(freqs[symbol]), node)))
+        .collect();
+
+    while heap.len() > 1 {
+        let Reverse((a, left)) = heap.pop().unwrap();
+        let Reverse((b, right)) = heap.pop().unwrap();
+
+        let node = parents.len();
+        parents.push(node);
+        parents[left] = node;
+        parents[right] = node;
+        heap.push(Reverse((a + b, node)));
+    }
+
+    let root = parents.len() - 1;
+    for (leaf, &symbol) in used.iter().enumerate() {
+        let mut depth = 0u8;
+        let mut node = leaf;
+        while node != root {
+            node = parents[node];
+            depth += 1;
+        }
+        lengths[symbol] = depth;
+    }
+
+    lengths
+}
+
+
+///
+/// The code lengths for a dynamic Huffman block and the run-length coded
+/// header that sends them, `cost` is the size of that header in bits.
+///
+struct DynamicHeader {
+    lit: Vec<u8>,
+    dist: Vec<u8>,
+    lengths: Vec<u8>,           // The lengths of the code length codes
+    runs: Vec<(u8, u8)>,        // The code length symbols with their extra bits
+    cost: u64,
+}
+
+impl DynamicHeader {
+    fn new(lit_freqs: &[u32], dist_freqs: &[u32]) -> Self {
+        let lit = code_lengths(lit_freqs, 15);
+
+        // there has to be at least one distance code even if nothing uses it
+        let mut dist = code_lengths(dist_freqs, 15);
+        if dist.iter().all(|&length| length == 0) {
+            dist[0] = 1;
+        }
+
+        let lit_count = 257.max(lit.iter().rposition(|&length| length > 0).map_or(0, |last| last + 1));
+        let dist_count = 1.max(dist.iter().rposition(|&length| length > 0).map_or(0, |last| last + 1));
+
+        let mut all = lit[..lit_count].to_vec();
+        all.extend_from_slice(&dist[..dist_count]);
+        let runs = run_lengths(&all);
+
+        let mut freqs = [0u32; 19];
+        for &(symbol, _) in runs.iter() {
+            freqs[symbol as usize] += 1;
+        }
+        let lengths = code_lengths(&freqs, 7);
+
+        let mut cost = 5 + 5 + 4 + 3 * header_lengths(&lengths) as u64;
+        for &(symbol, _) in runs.iter() {
+            cost += u64::from(lengths[symbol as usize]) + u64::from(run_extra_bits(symbol));
+        }
+
+        Self {
+            lit: lit[..lit_count].to_vec(),
+            dist: dist[..dist_count].to_vec(),
+            lengths,
+            runs,
+            cost,
+        }
+    }
+
+    fn write(&self, bits: &mut BitWriter, out: &mut Vec<u8>) {
+        let sent = header_lengths(&self.lengths);
+        bits.put(self.lit.len() as u32 - 257, 5, out);
+        bits.put(self.dist.len() as u32 - 1, 5, out);
+        bits.put(sent as u32 - 4, 4, out);
+        for &symbol in CODE_LENGTH_ORDER[..sent].iter() {
+            bits.put(u32::from(self.lengths[symbol]), 3, out);
+        }
+
+        let codes = canonical_codes(&self.lengths);
+        for &(symbol, extra) in self.runs.iter() {
+            bits.put_code(codes[symbol as usize], self.lengths[symbol as usize], out);
+            bits.put(u32::from(extra), run_extra_bits(symbol), out);
+        }
+    }
+}
+
+/// How many of the code length code lengths have to be sent, trailing zeros are left off.
+fn header_lengths(lengths: &[u8]) -> usize {
+    let last = CODE_LENGTH_ORDER.iter().rposition(|&symbol| lengths[symbol] > 0).unwrap_or(0);
+    (last + 1).max(4)
+}
+
+fn run_extra_bits(symbol: u8) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+///
+/// Run-length codes a list of code lengths, 16 repeats the previous length
+/// 3-6 times, 17 and 18 are runs of 3-10 and 11-138 zeros.
+///
+fn run_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&l| l == length).count();
+        let mut left = run;
+
+        if length == 0 {
+            while left >= 11 {
+                let n = left.min(138);
+                runs.push((18, (n - 11) as u8));
+                left -= n;
+            }
+            if left >= 3 {
+                runs.push((17, (left - 3) as u8));
+                left = 0;
+            }
+        } else {
+            runs.push((length, 0));
+            left -= 1;
+            while left >= 3 {
+                let n = left.min(6);
+                runs.push((16, (n - 3) as u8));
+                left -= n;
+            }
+        }
+
+        runs.extend(std::iter::repeat_n((length, 0), left));
+        i += run;
+    }
+
+    runs
+}
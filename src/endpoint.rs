@@ -0,0 +1,153 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::types::PyDict;
+
+use std::sync::Arc;
+
+use crate::listener::BindAddr;
+use crate::options::RunnerOptions;
+use crate::stats::ServerStats;
+
+
+/// The options about the runner as a whole rather than its connections, a listener can't have its own.
+const RUNNER_WIDE: [&str; 20] = [
+    "reactor", "accept_mode", "min_poll_delay", "max_poll_delay", "housekeeping_interval",
+    "idle_reclaim_after", "on_ready", "on_shutdown", "shutdown_timeout", "profiling", "resolve", "backlog", "accept_cooldown",
+    "tcp_keepalive", "so_linger", "tcp_fastopen", "max_total_write_rate_bytes_per_sec",
+    "memory_budget", "memory_low_water", "memory_overflow",
+];
+
+
+///
+/// ListenerSpec is one of the addresses an AsyncServerRunner was given,
+/// either just the address (`str`) or a dict giving the listener a
+/// callback and options of its own. Options it doesn't give are the
+/// runner's, the ones about the runner as a whole (see `RUNNER_WIDE`)
+/// can't be given.
+///
+///     Requires:
+///         - addr:         str         (what a plain address would be)
+///
+///     Optional:
+///         - name:         str         (what `ConnectionInfo.listener` and `stats(per_listener=True)` call it, its address by default)
+///         - callback:     PyObject    (called instead of the runner's callback, a dict of hosts works like it does there)
+///         - **options:    see RunnerOptions (tls, max_body_size, allow_ips ...)
+///
+///     Example:
+///         AsyncServerRunner([
+///             "0.0.0.0:8080",
+///             {"addr": "unix:/run/app/admin.sock", "callback": admin, "max_body_size": 4096},
+///         ], app)
+///
+pub(crate) struct ListenerSpec {
+    pub(crate) addr: String,
+    pub(crate) name: Option<String>,
+    pub(crate) callback: Option<PyObject>,
+    overrides: Option<Py<PyDict>>,      // The options it gives, None if it gives none
+}
+
+impl ListenerSpec {
+    pub(crate) fn extract(py: Python, spec: &PyAny) -> PyResult<Self> {
+        if let Ok(addr) = spec.extract::<String>() {
+            return Ok(Self { addr, name: None, callback: None, overrides: None })
+        }
+
+        let spec: &PyDict = spec.downcast().map_err(|_| {
+            PyTypeError::new_err("a listener is either an address or a dict with its 'addr'")
+        })?;
+
+        let (mut addr, mut name, mut callback) = (None, None, None);
+        let overrides = PyDict::new(py);
+        for (key, value) in spec.iter() {
+            match key.extract::<&str>()? {
+                "addr" => addr = Some(value.extract()?),
+                "name" => name = Some(value.extract()?),
+                "callback" => callback = Some(value.into()),
+                key if RUNNER_WIDE.contains(&key) => return Err(PyValueError::new_err(format!(
+                    "{} applies to the whole runner, a listener can't have its own", key
+                ))),
+                key => overrides.set_item(key, value)?,
+            }
+        }
+
+        let addr = addr.ok_or_else(|| PyValueError::new_err("a listener dict needs the 'addr' to bind"))?;
+        let overrides = (!overrides.is_empty()).then(|| overrides.into());
+        Ok(Self { addr, name, callback, overrides })
+    }
+
+    /// If the listener is served just like the runner's others.
+    pub(crate) fn is_plain(&self) -> bool {
+        self.name.is_none() && self.callback.is_none() && self.overrides.is_none()
+    }
+
+    ///
+    /// Internal Method: ListenerSpec::options() -> PyResult<Option<RunnerOptions>>
+    ///
+    ///     The listener's own options (see `listener_options()`), None when
+    ///     it has none and shares the runner's.
+    ///
+    pub(crate) fn options(&self, py: Python, kwargs: Option<&PyDict>, runner: &RunnerOptions) -> PyResult<Option<RunnerOptions>> {
+        match self.overrides.as_ref() {
+            Some(overrides) => Ok(Some(listener_options(py, kwargs, overrides.as_ref(py), runner)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The options it gives of its own, None if it gives none.
+    pub(crate) fn overrides(&self, py: Python) -> Option<Py<PyDict>> {
+        self.overrides.as_ref().map(|overrides| overrides.clone_ref(py))
+    }
+}
+
+///
+/// The options of a listener with `overrides`, the runner's `kwargs` with
+/// them on top. The `max_total_write_rate_bytes_per_sec` and
+/// `memory_budget` budgets stay the `runner`'s, they're for every
+/// connection however accepted.
+///
+pub(crate) fn listener_options(py: Python, kwargs: Option<&PyDict>, overrides: &PyDict, runner: &RunnerOptions) -> PyResult<RunnerOptions> {
+    let mut options = RunnerOptions::from_kwargs(Some(merge(py, kwargs, overrides)?))?;
+    options.write_budget = runner.write_budget.clone();
+    options.memory_budget = runner.memory_budget.clone();
+    Ok(options)
+}
+
+/// What a listener's options are made from, a copy of `kwargs` with `overrides` on top.
+pub(crate) fn merge<'p>(py: Python<'p>, kwargs: Option<&'p PyDict>, overrides: &PyDict) -> PyResult<&'p PyDict> {
+    let merged = match kwargs {
+        Some(kwargs) => kwargs.copy()?,
+        None => PyDict::new(py),
+    };
+    for (key, value) in overrides.iter() {
+        merged.set_item(key, value)?;
+    }
+
+    Ok(merged)
+}
+
+
+///
+/// Endpoint is how the clients of one of a runner's listeners are served,
+/// its name, the callback and options they get and the counters they're
+/// counted against. Most listeners share the runner's callback and options.
+///
+pub(crate) struct Endpoint {
+    pub(crate) name: Arc<str>,
+    pub(crate) callback: Option<PyObject>,  // Its own callback, None for the runner's
+    pub(crate) options: Arc<RunnerOptions>,
+    pub(crate) overrides: Option<Py<PyDict>>,   // The options its ListenerSpec gave, None if it shares the runner's
+    pub(crate) stats: Arc<ServerStats>,     // Its own counters, which add up to the runner's
+}
+
+impl Endpoint {
+    /// A listener bound to `addr` served with the runner's callback and `options`.
+    pub(crate) fn new(addr: &BindAddr, options: Arc<RunnerOptions>, total: &Arc<ServerStats>) -> Self {
+        Self {
+            name: addr.to_string().into(),
+            callback: None,
+            options,
+            overrides: None,
+            stats: ServerStats::for_listener(total.clone()),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use pyo3::prelude::*;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyNotImplementedError};
+
+use std::io;
+
+use crate::listener::BindFailed;
+
+
+create_exception!(async_rust, AsyncRustError, PyException);
+create_exception!(async_rust, BindError, AsyncRustError);
+create_exception!(async_rust, ParseError, AsyncRustError);
+create_exception!(async_rust, HandlerError, AsyncRustError);
+create_exception!(async_rust, ConnectionClosed, AsyncRustError);
+create_exception!(async_rust, BadRequest, AsyncRustError);
+
+
+///
+/// Adds the exceptions to the module, everything we raise ourselves is an
+/// `AsyncRustError`:
+///
+///     - BindError:        a listener couldn't be set up, `errno` is the OS error if there was one
+///     - ParseError:       something we were given couldn't be parsed, e.g. a bind address
+///     - HandlerError:     a handler raised, the original is its `__cause__`
+///     - ConnectionClosed: the connection or websocket was used after it closed
+///     - BadRequest:       the request body didn't make sense, a handler that lets it out answers with a `400`
+///
+pub(crate) fn init(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("AsyncRustError", py.get_type::<AsyncRustError>())?;
+    m.add("BindError", py.get_type::<BindError>())?;
+    m.add("ParseError", py.get_type::<ParseError>())?;
+    m.add("HandlerError", py.get_type::<HandlerError>())?;
+    m.add("ConnectionClosed", py.get_type::<ConnectionClosed>())?;
+    m.add("BadRequest", py.get_type::<BadRequest>())?;
+    Ok(())
+}
+
+///
+/// Internal Method: errors::bind_error() -> PyErr
+///
+///     What setting up a listener failing is raised as, an address we
+///     couldn't make sense of is a ParseError, one the platform can't do
+///     NotImplementedError and anything else a BindError with the errno
+///     from the OS.
+///
+pub(crate) fn bind_error(py: Python, e: io::Error) -> PyErr {
+    if e.kind() == io::ErrorKind::InvalidInput {
+        return ParseError::new_err(e.to_string())
+    }
+    if e.kind() == io::ErrorKind::Unsupported {
+        return PyNotImplementedError::new_err(e.to_string())
+    }
+
+    let errno = e.raw_os_error().or_else(|| {
+        e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<BindFailed>())
+            .and_then(|failed| failed.source.raw_os_error())
+    });
+    let err = BindError::new_err(e.to_string());
+    let _ = err.instance(py).setattr("errno", errno);
+    err
+}
+
+///
+/// Internal Method: errors::handler_error() -> PyErr
+///
+///     Wraps what a handler raised in a HandlerError so it's clear it came
+///     from the handler rather than us, chained so the traceback is kept.
+///
+pub(crate) fn handler_error(py: Python, e: &PyErr) -> PyErr {
+    // `into_instance()` would leak the exception (and with its traceback
+    // the connection), the borrowed `pvalue` is kept alive by `e` instead
+    let err = HandlerError::new_err(format!("the handler raised {}", e.pvalue(py)));
+    let _ = err.instance(py).setattr("__cause__", e.pvalue(py));
+    err
+}
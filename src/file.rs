@@ -0,0 +1,469 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use std::fs::{self, File};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::time::UNIX_EPOCH;
+
+use crate::headers::{HeaderName, Headers};
+use crate::http::{self, HTTPResponse};
+
+
+/// How much of the file we read at a time when we can't use sendfile.
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+
+///
+/// FileResponse can be returned from the callback instead of a HTTPResponse
+/// to send a file off disk, the file is streamed straight to the socket
+/// (with `sendfile(2)` on Linux) rather than passing through python.
+///
+/// When `root` is given `path` is resolved inside it, anything which ends
+/// up outside the root after resolving `..` and symlinks is refused.
+///
+///     Requires:
+///         - path:         str
+///
+///     Optional:
+///         - root:         str             (the directory files must be served from)
+///         - status:       int             (defaults to 200)
+///         - headers:      dict
+///         - content_type: str             (guessed from the extension otherwise)
+///
+///     Missing files give a `404` and directories (or paths outside the
+///     root) a `403`.
+///
+///     A `200` carries a weak `ETag` made from the file's size and mtime
+///     and a `Last-Modified`, a `GET` or `HEAD` whose `If-None-Match`
+///     matches (or, without one, whose `If-Modified-Since` isn't older
+///     than the file) gets a `304` with those and `headers` but no body.
+///
+///     A `GET` with a single `Range: bytes=...` (`a-b`, `a-` or `-n`) gets
+///     a `206` with just that slice, or a `416` if it's past the end of the
+///     file. Several ranges at once aren't supported, those get the whole
+///     file as a `200`.
+///
+#[pyclass]
+pub struct FileResponse {
+    path: String,
+    root: Option<String>,
+
+    #[pyo3(get, set)]
+    status: u16,
+
+    headers: Vec<(String, String)>,
+    content_type: Option<String>,
+}
+
+#[pymethods]
+impl FileResponse {
+    #[new]
+    #[args(root = "None", status = "200", headers = "None", content_type = "None")]
+    fn new(
+        path: String,
+        root: Option<String>,
+        status: u16,
+        headers: Option<&PyDict>,
+        content_type: Option<String>,
+    ) -> PyResult<Self> {
+        let mut pairs = Vec::new();
+        if let Some(headers) = headers {
+            for (name, value) in headers.iter() {
+                pairs.push((name.extract()?, value.extract()?));
+            }
+        }
+
+        Ok(Self {
+            path,
+            root,
+            status,
+            headers: pairs,
+            content_type,
+        })
+    }
+
+    #[getter]
+    fn path(&self) -> String {
+        self.path.clone()
+    }
+}
+
+impl FileResponse {
+
+    ///
+    /// Internal Method: FileResponse::open() -> Result<(HTTPResponse, Option<FileBody>), u16>
+    ///
+    ///     Resolves and opens the file giving back the response head and the
+    ///     body to stream after it, or the status to respond with instead.
+    ///     There's no body when `conditional` makes it a `304`.
+    ///
+    pub(crate) fn open(&self, conditional: &Conditional) -> Result<(HTTPResponse, Option<FileBody>), u16> {
+        let path = self.resolve()?;
+
+        let metadata = fs::metadata(&path).map_err(status_for)?;
+        if metadata.is_dir() {
+            return Err(403)
+        }
+
+        let mut headers = self.headers.clone();
+        let modified = metadata.modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+
+        // the handler's own validators win over ours
+        if let Some(modified) = modified.filter(|_| self.status == 200) {
+            if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("etag")) {
+                headers.push((String::from("ETag"), format!("W/\"{:x}-{:x}\"", metadata.len(), modified.as_nanos())));
+            }
+            if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("last-modified")) {
+                headers.push((String::from("Last-Modified"), http::http_date(modified.as_secs())));
+            }
+
+            if conditional.not_modified(&headers) {
+                return Ok((HTTPResponse::from_parts(304, headers, Vec::new()), None))
+            }
+        }
+
+        let size = metadata.len();
+        let (status, start, len) = match self.status {
+            200 => match conditional.range(&headers, size) {
+                ByteRange::Full => (200, 0, size),
+                ByteRange::Slice(start, end) => {
+                    headers.push((String::from("Content-Range"), format!("bytes {}-{}/{}", start, end, size)));
+                    (206, start, end - start + 1)
+                },
+                ByteRange::Unsatisfiable => {
+                    headers.push((String::from("Content-Range"), format!("bytes */{}", size)));
+                    return Ok((HTTPResponse::from_parts(416, headers, Vec::new()), None))
+                },
+            },
+            status => (status, 0, size),
+        };
+
+        let mut file = File::open(&path).map_err(status_for)?;
+        if start > 0 {
+            file.seek(SeekFrom::Start(start)).map_err(status_for)?;
+        }
+
+        let content_type = self.content_type
+            .clone()
+            .unwrap_or_else(|| guess_content_type(&path).to_string());
+
+        if self.status == 200 {
+            headers.push((String::from("Accept-Ranges"), String::from("bytes")));
+        }
+        headers.push((String::from("Content-Type"), content_type));
+        headers.push((String::from("Content-Length"), len.to_string()));
+
+        let head = HTTPResponse::from_parts(status, headers, Vec::new());
+        Ok((head, Some(FileBody { file, start, offset: start, remaining: len })))
+    }
+
+    fn resolve(&self) -> Result<PathBuf, u16> {
+        let root = match self.root.as_ref() {
+            Some(root) => fs::canonicalize(root).map_err(status_for)?,
+            None => return fs::canonicalize(&self.path).map_err(status_for),
+        };
+
+        // resolve `..` ourselves first so we never even ask the filesystem about it
+        let relative = http::normalize_decoded(&self.path).map_err(|_| 403u16)?;
+        let path = fs::canonicalize(root.join(relative.trim_start_matches('/')))
+            .map_err(status_for)?;
+
+        // `..` or a symlink took us somewhere we shouldn't be
+        if !path.starts_with(&root) {
+            return Err(403)
+        }
+
+        Ok(path)
+    }
+}
+
+
+///
+/// The conditional headers of the request a FileResponse answers, kept
+/// from before the request went to the callback. Only `GET` and `HEAD`
+/// have any, a condition on anything else doesn't make it a `304`. The
+/// `Range` (and its `If-Range`) are only kept for a `GET`.
+///
+#[derive(Default)]
+pub(crate) struct Conditional {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    range: Option<String>,
+    if_range: Option<String>,
+}
+
+/// The part of the file a `Range` asks for.
+enum ByteRange {
+    Full,
+    Slice(u64, u64),    // The first and last byte, both included
+    Unsatisfiable,
+}
+
+impl Conditional {
+    pub(crate) fn from_headers(method: &str, headers: &Headers) -> Self {
+        if method != "GET" && method != "HEAD" {
+            return Self::default()
+        }
+
+        let ranged = method == "GET";
+        Self {
+            if_none_match: headers.get(HeaderName::IfNoneMatch).map(str::to_string),
+            if_modified_since: headers.get(HeaderName::IfModifiedSince).map(str::to_string),
+            range: headers.get(HeaderName::Range).filter(|_| ranged).map(str::to_string),
+            if_range: headers.get(HeaderName::IfRange).filter(|_| ranged).map(str::to_string),
+        }
+    }
+
+    ///
+    /// If the response with `headers` can be a `304`, `If-Modified-Since`
+    /// only counts when there's no `If-None-Match`.
+    ///
+    fn not_modified(&self, headers: &[(String, String)]) -> bool {
+        if let Some(list) = self.if_none_match.as_deref() {
+            return header(headers, "etag").is_some_and(|etag| etag_matches(list, etag))
+        }
+
+        let since = self.if_modified_since.as_deref().and_then(http::parse_http_date);
+        let modified = header(headers, "last-modified").and_then(http::parse_http_date);
+        match (since, modified) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    ///
+    /// The slice of a file of `size` bytes to send. A `Range` we can't
+    /// read (or one with several ranges) is ignored and so is one whose
+    /// `If-Range` doesn't name the file as it is now, that needs a strong
+    /// match so with our weak `ETag` it's only ever a `Last-Modified`.
+    ///
+    fn range(&self, headers: &[(String, String)], size: u64) -> ByteRange {
+        let range = match self.range.as_deref() {
+            Some(range) => range,
+            None => return ByteRange::Full,
+        };
+
+        if let Some(if_range) = self.if_range.as_deref().map(str::trim) {
+            let current = match if_range.starts_with('"') || if_range.starts_with("W/") {
+                true => header(headers, "etag").filter(|etag| !etag.starts_with("W/")),
+                false => header(headers, "last-modified"),
+            };
+
+            if current != Some(if_range) {
+                return ByteRange::Full
+            }
+        }
+
+        let spec = match range.trim().split_once('=') {
+            Some((unit, spec)) if unit.trim().eq_ignore_ascii_case("bytes") && !spec.contains(',') => spec.trim(),
+            _ => return ByteRange::Full,
+        };
+
+        let position = |digits: &str| -> Option<u64> {
+            match !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+                true => digits.parse().ok(),
+                false => None,
+            }
+        };
+
+        let (first, last) = match spec.split_once('-') {
+            Some((first, last)) => (first.trim(), last.trim()),
+            None => return ByteRange::Full,
+        };
+
+        match (position(first), position(last)) {
+            // the last n bytes
+            (None, Some(suffix)) if first.is_empty() => match suffix > 0 && size > 0 {
+                true => ByteRange::Slice(size - suffix.min(size), size - 1),
+                false => ByteRange::Unsatisfiable,
+            },
+            (Some(start), None) if last.is_empty() => match start < size {
+                true => ByteRange::Slice(start, size - 1),
+                false => ByteRange::Unsatisfiable,
+            },
+            (Some(start), Some(end)) if start <= end => match start < size {
+                true => ByteRange::Slice(start, end.min(size - 1)),
+                false => ByteRange::Unsatisfiable,
+            },
+            _ => ByteRange::Full,
+        }
+    }
+}
+
+/// The first value of a response header, ignoring case.
+fn header<'a>(headers: &'a [(String, String)], wanted: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+        .map(|(_, value)| value.as_str())
+}
+
+///
+/// Whether `etag` is in an `If-None-Match` list, by the weak comparison
+/// so `W/"x"` and `"x"` are the same tag. The tags are quoted and can
+/// themselves contain commas, so the list is walked rather than split.
+///
+fn etag_matches(list: &str, etag: &str) -> bool {
+    let list = list.trim();
+    if list == "*" {
+        return true
+    }
+
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let wanted = opaque(etag);
+
+    let mut rest = list;
+    loop {
+        rest = rest.trim_start_matches([',', ' ', '\t']);
+        if rest.is_empty() {
+            return false
+        }
+
+        let start = rest.find('"').unwrap_or(rest.len());
+        let end = match rest[start..].get(1..).and_then(|after| after.find('"')) {
+            Some(end) => start + end + 2,
+            None => return false,
+        };
+
+        if opaque(&rest[..end]) == wanted {
+            return true
+        }
+        rest = &rest[end..];
+    }
+}
+
+fn status_for(e: io::Error) -> u16 {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => 403,
+        _ => 404,
+    }
+}
+
+fn guess_content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    match ext.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("mp4") => "video/mp4",
+        Some("mp3") => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+
+///
+/// FileBody is the open file still to be written after the response head,
+/// `remaining` is tracked ourselves so a file growing underneath us can't
+/// send more than the Content-Length we promised.
+///
+pub(crate) struct FileBody {
+    file: File,
+    start: u64,         // Where in the file the body starts, past 0 for a `206`
+    offset: u64,
+    remaining: u64,
+}
+
+impl FileBody {
+
+    ///
+    /// Internal Method: FileBody::sendfile() -> io::Result<bool>
+    ///
+    ///     Hands the file to the kernel to copy straight to the socket, at
+    ///     most `max` bytes of it this time, `Ok(true)` once it's all gone.
+    ///     `WouldBlock` just means come back later, we pick up from the same
+    ///     offset.
+    ///
+    #[cfg(target_os = "linux")]
+    pub(crate) fn sendfile(&mut self, sock: &TcpStream, max: u64) -> io::Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        let stop = self.remaining.saturating_sub(max);
+        while self.remaining > stop {
+            let mut offset = self.offset as libc::off_t;
+            let n = unsafe {
+                libc::sendfile(
+                    sock.as_raw_fd(),
+                    self.file.as_raw_fd(),
+                    &mut offset,
+                    (self.remaining - stop).min(isize::MAX as u64) as usize,
+                )
+            };
+
+            if n == -1 {
+                return Err(io::Error::last_os_error())
+            }
+
+            // the file got shorter than when we opened it
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+
+            self.offset += n as u64;
+            self.remaining -= n as u64;
+        }
+
+        Ok(self.remaining == 0)
+    }
+
+    /// How much of the file has been sent so far.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn sent(&self) -> u64 {
+        self.offset - self.start
+    }
+
+    /// How much of the file is still to be sent.
+    pub(crate) fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// If there's nothing of the file left to send.
+    pub(crate) fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+
+    ///
+    /// Reads the next chunk of the file into `buf` for the plain write path,
+    /// used with TLS and where sendfile isn't available. `Ok(false)` once
+    /// everything has been read.
+    ///
+    pub(crate) fn read_chunk(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        if self.remaining == 0 {
+            return Ok(false)
+        }
+
+        let len = (self.remaining as usize).min(FILE_CHUNK_SIZE);
+        buf.resize(len, 0);
+        self.file.read_exact(buf)?;
+
+        self.offset += len as u64;
+        self.remaining -= len as u64;
+
+        Ok(true)
+    }
+}
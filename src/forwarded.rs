@@ -0,0 +1,224 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::headers::{HeaderName, Headers};
+
+
+///
+/// Cidr is one entry of `trusted_proxies`, `allow_ips` or `deny_ips`, a
+/// network like `10.0.0.0/8` or `2001:db8::/32` or a single address which
+/// is the same as a full length prefix. IPv4-mapped IPv6 addresses (`::ffff:10.0.0.1`) count as IPv4.
+///
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value.trim(), None),
+        };
+
+        let network = canonical(addr.parse().ok()?);
+        let max = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix = match prefix {
+            Some(prefix) if !prefix.is_empty() && prefix.bytes().all(|b| b.is_ascii_digit()) => prefix.parse().ok()?,
+            Some(_) => return None,
+            None => max,
+        };
+
+        if prefix > max {
+            return None
+        }
+
+        Some(Self { network, prefix })
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Written the way it can be given back, a full length prefix as just the address.
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.network, self.prefix) {
+            (IpAddr::V4(_), 32) | (IpAddr::V6(_), 128) => write!(f, "{}", self.network),
+            (network, prefix) => write!(f, "{}/{}", network, prefix),
+        }
+    }
+}
+
+/// IPv4-mapped IPv6 addresses as the IPv4 address they stand for.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+///
+/// The header `trusted_proxies` record the client in, see `forwarded_header`.
+/// Only that one is read, a client can send the other with whatever it
+/// likes in it and our proxies pass it along untouched.
+///
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ForwardedHeader {
+    XForwardedFor,
+    Forwarded,
+}
+
+impl ForwardedHeader {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ForwardedHeader::XForwardedFor => "x-forwarded-for",
+            ForwardedHeader::Forwarded => "forwarded",
+        }
+    }
+}
+
+fn is_trusted(trusted: &[Cidr], ip: IpAddr) -> bool {
+    trusted.iter().any(|cidr| cidr.contains(ip))
+}
+
+
+///
+/// Internal Method: forwarded::remote_addr() -> Option<String>
+///
+///     The address of the client as far as we can trust it, see
+///     `HTTPRequest.remote_addr`. Unless the peer is one of the
+///     `trusted_proxies` it's just the peer, otherwise the hops the proxies
+///     recorded in `header` are walked from the right past every trusted
+///     proxy and the first hop that isn't one is the client.
+///
+///     A hop we can't read (`unknown`, an obfuscated `_name`) can't be
+///     vouched for so the walk stops there, the last trusted proxy before it
+///     is the best we know.
+///
+pub(crate) fn remote_addr(
+    trusted: &[Cidr],
+    header: ForwardedHeader,
+    peer: Option<&(String, u16)>,
+    headers: &Headers,
+) -> Option<String> {
+    let peer_ip: IpAddr = peer?.0.parse().ok()?;
+    if !is_trusted(trusted, peer_ip) {
+        return Some(peer_ip.to_string())
+    }
+
+    let hops = match header {
+        ForwardedHeader::Forwarded => forwarded_for(headers),
+        ForwardedHeader::XForwardedFor => headers
+            .get_all("x-forwarded-for")
+            .flat_map(|value| value.split(','))
+            .map(|hop| parse_node(hop.trim()))
+            .collect(),
+    };
+
+    let mut client = peer_ip;
+    for hop in hops.iter().rev() {
+        match *hop {
+            Some(ip) if is_trusted(trusted, ip) => client = ip,
+            Some(ip) => return Some(ip.to_string()),
+            None => break,
+        }
+    }
+
+    Some(client.to_string())
+}
+
+/// The `for=` of each element of every `Forwarded` header (RFC 7239), in order.
+fn forwarded_for(headers: &Headers) -> Vec<Option<IpAddr>> {
+    let mut hops = Vec::new();
+    for value in headers.get_all(HeaderName::Forwarded) {
+        for element in split_outside_quotes(value, ',') {
+            let node = split_outside_quotes(element, ';')
+                .into_iter()
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .map(|(_, node)| unquote(node.trim()));
+
+            // an element without a `for` says nothing about who the client is
+            if let Some(node) = node {
+                hops.push(parse_node(&node));
+            }
+        }
+    }
+
+    hops
+}
+
+///
+/// A hop's address, with or without a port: `192.0.2.1`, `192.0.2.1:80`,
+/// `2001:db8::1` and `[2001:db8::1]:80` all work.
+///
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(canonical(ip))
+    }
+
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(canonical(addr.ip()))
+    }
+
+    node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .and_then(|ip| ip.parse().ok())
+        .map(canonical)
+}
+
+fn split_outside_quotes(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+
+    parts.push(&value[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    let inner = match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return value.to_string(),
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+
+    unquoted
+}
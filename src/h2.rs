@@ -0,0 +1,1429 @@
+use pyo3::prelude::*;
+use pyo3::class::gc::{PyTraverseError, PyVisit};
+
+use bytes::Bytes;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::body::BodyStep;
+use crate::compress::Encoding;
+use crate::cookie;
+use crate::file::{Conditional, FileBody};
+use crate::headers::{HeaderName, Headers};
+use crate::http::{self, RequestLease};
+use crate::prehandler::RequestMeta;
+use crate::hpack::{Decoder, Encoder, Field};
+use crate::spool::{Spool, SpoolPolicy};
+use crate::stats::RequestTimings;
+
+
+/// What an HTTP/2 client opens with, before its first SETTINGS.
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const CANCEL: u32 = 0x8;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+/// The frame size everyone starts with, we never offer a bigger one.
+const MAX_FRAME_SIZE: usize = 16384;
+
+/// The flow control window everyone starts with.
+const DEFAULT_WINDOW: i64 = 65535;
+
+/// The biggest a flow control window can get.
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// The window we give the client for each stream and for the whole connection.
+const RECV_WINDOW: i64 = 1 << 20;
+
+/// How many streams a client can have open at once.
+const MAX_CONCURRENT_STREAMS: usize = 100;
+
+/// The most a header block can be once its CONTINUATIONs are put together.
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+
+/// The most a request's decoded headers can add up to before it's a `431`.
+const MAX_HEADER_LIST: usize = 64 * 1024;
+
+/// How much we frame ahead of the socket before waiting for it to take some.
+const OUT_HIGH_WATER: usize = 64 * 1024;
+
+/// How many closed streams we remember, to tell a late frame from a bad one.
+const CLOSED_REMEMBERED: usize = 128;
+
+/// The headers that only mean something to an HTTP/1 connection, a request with any of them is malformed.
+const CONNECTION_SPECIFIC: [&[u8]; 5] = [b"connection", b"keep-alive", b"proxy-connection", b"transfer-encoding", b"upgrade"];
+
+
+///
+/// What `H2Connection::receive()` found in what the client sent, a
+/// request that's all arrived for the callback, the head of one for
+/// `on_headers` (see `H2Connection::release()`), one we answer with a
+/// status without it, or a stream the client gave up on whose handler
+/// should be cancelled.
+///
+pub(crate) enum Event {
+    Request(Request),
+    Head(u32),
+    Refuse(u32, u16),
+    Cancelled(Exchange),
+}
+
+/// A request that's all arrived, its body and any trailers included.
+pub(crate) struct Request {
+    pub(crate) stream: u32,
+    pub(crate) method: String,
+    pub(crate) target: Vec<u8>,         // The `:path`, or the `:authority` of a CONNECT
+    pub(crate) headers: Headers,        // Including a `host` from `:authority`
+    pub(crate) trailers: Headers,
+    pub(crate) body: Vec<u8>,
+    pub(crate) spooled: Option<Spool>,  // The body once it's past `spool_threshold`, `body` is left empty
+    pub(crate) received: Instant,       // When its HEADERS came in
+}
+
+impl Request {
+    /// Adds `data` to the body, which is moved to a spool once it's past `spool_threshold`.
+    fn push_body(&mut self, data: &[u8], policy: Option<&SpoolPolicy>) -> io::Result<()> {
+        if let Some(policy) = policy.filter(|policy| self.spooled.is_none() && policy.spools(self.body.len() + data.len())) {
+            let mut spool = Spool::create(&policy.dir)?;
+            spool.write(&self.body)?;
+            self.body = Vec::new();
+            self.spooled = Some(spool);
+        }
+
+        match self.spooled.as_mut() {
+            Some(spool) => spool.write(data),
+            None => {
+                self.body.extend_from_slice(data);
+                Ok(())
+            },
+        }
+    }
+}
+
+///
+/// Exchange is what the connection keeps about a request it handed to the
+/// callback, the same things an HTTP/1 connection keeps about its one
+/// request at a time. It goes back to the connection once the response
+/// is out, for the access log.
+///
+pub(crate) struct Exchange {
+    pub(crate) task: Option<PyObject>,          // The task running the callback (or `on_headers`, or stepping `body`), None in between
+    pub(crate) deadline: Option<Instant>,       // When `handler_timeout` runs out
+    pub(crate) request_line: (String, String, String),
+    pub(crate) request_id: String,
+    pub(crate) encoding: Option<Encoding>,
+    pub(crate) conditional: Conditional,
+    pub(crate) cors_origin: Option<String>,
+    pub(crate) head_only: bool,
+    pub(crate) timings: Arc<RequestTimings>,  // When each stage of the request happened, shared with its HTTPRequest
+    pub(crate) lease: Option<RequestLease>,     // Its HTTPRequest's hold on the body, let go of with the exchange
+    pub(crate) context: Option<PyObject>,       // The `contextvars.Context` its callback runs in, its access log too
+    pub(crate) meta: Option<Py<RequestMeta>>,   // What `on_headers` was given while `task` is the hook's
+    pub(crate) body: Option<Py<BodyStep>>,      // A streamed body, stepped for its next chunk whenever the last is framed
+    pub(crate) status: u16,
+    pub(crate) sent: u64,                       // Everything framed for the response, head included
+    pub(crate) body_sent: u64,
+}
+
+impl Exchange {
+    /// Cancels the callback if it's still going, its stream's gone.
+    pub(crate) fn cancel(&mut self, py: Python) {
+        if let Some(task) = self.task.take() {
+            let _ = task.call_method0(py, "cancel");
+        }
+    }
+}
+
+/// A frame that ends the whole connection, the GOAWAY says why.
+struct ConnectionError(u32, &'static str);
+
+/// What the header block being put back together is for.
+#[derive(Clone, Copy)]
+enum Block {
+    Request,
+    Trailers,
+    Reset(u32),     // Decoded for the table's sake and then the stream is reset
+    Ignored,        // Decoded for the table's sake, a stream opened after our GOAWAY
+}
+
+/// A HEADERS that didn't have END_HEADERS, the CONTINUATIONs carrying the rest have to follow.
+struct Continuation {
+    stream: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+    purpose: Block,
+}
+
+struct Stream {
+    receiving: Option<Receiving>,   // Until the client's END_STREAM
+    sending: Option<Sending>,       // Once there's a response
+    waiting: bool,                  // On `on_headers`, until it's released
+    held: Option<Receiving>,        // A request that was all in before `on_headers` released it
+    recv_window: i64,
+    send_window: i64,
+    exchange: Option<Exchange>,
+}
+
+struct Receiving {
+    request: Option<Request>,       // None when the request's refused, the rest of it is thrown away
+    length: Option<usize>,          // The request's `content-length`, which the DATA has to add up to
+    received: usize,
+    max_body_size: usize,
+}
+
+struct Sending {
+    head: Option<Vec<u8>>,          // The encoded HEADERS block until it's framed
+    body: Bytes,
+    sent: usize,                    // How much of `body` has been framed
+    file: Option<FileBody>,         // The rest of a FileResponse, read into `body` a chunk at a time
+    streaming: bool,                // More of `body` is to be pushed, until its last chunk
+}
+
+impl Sending {
+    fn is_done(&self) -> bool {
+        !self.streaming && self.sent == self.body.len() && self.file.as_ref().is_none_or(FileBody::is_done)
+    }
+
+    /// If a streamed body has framed everything it was given.
+    fn is_starved(&self) -> bool {
+        self.streaming && self.sent == self.body.len()
+    }
+}
+
+
+///
+/// H2Connection is the HTTP/2 (RFC 9113) side of a connection once it's
+/// switched over (see `OnceFuture::start_h2()`), it takes what the client
+/// sends and gives back the frames to answer with without ever touching
+/// the socket itself.
+///
+/// Each stream is a request, its HEADERS and DATA are put together and only
+/// handed out once the client's ended it, the same as an HTTP/1 request
+/// whose body is read before the callback sees it. With `on_headers` the
+/// head is handed out first and the stream waits on the hook, what body
+/// arrives meanwhile is held to the limit the hook leaves once it's done
+/// and the client gets no more window for it until then. Flow control is
+/// ours to honour on the way out, a response is only framed as far as the
+/// client's windows allow (a streamed body a chunk at a time as it's
+/// produced), and on the way in we hand the client back its window as
+/// whatever it sent is taken.
+///
+/// A client that breaks the protocol gets a GOAWAY saying why and the
+/// connection ends, one that only breaks a stream has that stream reset.
+///
+pub(crate) struct H2Connection {
+    out: Vec<u8>,                       // Frames waiting for the socket
+    written: usize,                     // How much of `out` the socket has taken
+    decoder: Decoder,
+    encoder: Encoder,
+    streams: BTreeMap<u32, Stream>,
+    closed: VecDeque<(u32, bool)>,      // The streams closed most recently and if it was us that reset them
+    continuation: Option<Continuation>,
+    cancelled: Vec<Exchange>,           // Streams we reset whose callbacks are still to be cancelled
+    last_stream: u32,                   // The highest stream the client has opened
+    preface: bool,                      // Set once the client's preface has arrived
+    settings: bool,                     // Set once its first SETTINGS has
+    recv_window: i64,
+    send_window: i64,
+    initial_window: i64,                // What the client's SETTINGS gave each stream to send into
+    max_frame_size: usize,              // The biggest frame the client takes
+    max_body_size: usize,
+    spool: Option<SpoolPolicy>,         // Where bodies past `spool_threshold` go
+    hold: bool,                         // Streams wait on `on_headers` before they're handed on
+    going_away: bool,                   // We've sent GOAWAY, no more streams are taken
+    peer_gone: bool,                    // The client has sent GOAWAY
+    failed: bool,                       // A connection error, nothing more is read
+}
+
+impl H2Connection {
+    ///
+    /// Our preface, the SETTINGS we want and the extra window for the
+    /// whole connection, are queued straight away so they go out first.
+    ///
+    pub(crate) fn new(max_body_size: usize, spool: Option<SpoolPolicy>, hold: bool) -> Self {
+        let mut conn = Self {
+            out: Vec::new(),
+            written: 0,
+            decoder: Decoder::new(),
+            encoder: Encoder::default(),
+            streams: BTreeMap::new(),
+            closed: VecDeque::new(),
+            continuation: None,
+            cancelled: Vec::new(),
+            last_stream: 0,
+            preface: false,
+            settings: false,
+            recv_window: RECV_WINDOW,
+            send_window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: MAX_FRAME_SIZE,
+            max_body_size,
+            spool,
+            hold,
+            going_away: false,
+            peer_gone: false,
+            failed: false,
+        };
+
+        let mut settings = Vec::new();
+        for (id, value) in [(0x3u16, MAX_CONCURRENT_STREAMS as u32), (0x4, RECV_WINDOW as u32)] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        conn.frame(SETTINGS, 0, 0, &settings);
+        conn.window_update(0, (RECV_WINDOW - DEFAULT_WINDOW) as u32);
+
+        conn
+    }
+
+    /// The frames still to be written.
+    pub(crate) fn pending(&self) -> &[u8] {
+        &self.out[self.written..]
+    }
+
+    /// Frees the frame buffer of an idle connection if it's over `retain`, see `crate::reclaim_buffer()`.
+    pub(crate) fn reclaim(&mut self, retain: usize) -> usize {
+        match self.pending().is_empty() {
+            true => crate::reclaim_buffer(&mut self.out, retain),
+            false => 0,
+        }
+    }
+
+    /// The socket took `n` more of `pending()`.
+    pub(crate) fn advance(&mut self, n: usize) {
+        self.written += n;
+        if self.written == self.out.len() {
+            self.out.clear();
+            self.written = 0;
+        }
+    }
+
+    /// If the connection is over, every stream is done and everything's been written.
+    pub(crate) fn is_done(&self) -> bool {
+        (self.failed || self.going_away || self.peer_gone) && self.streams.is_empty() && self.pending().is_empty()
+    }
+
+    /// If nothing is happening on the connection, for `keep_alive_timeout`.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.streams.is_empty() && self.continuation.is_none()
+    }
+
+    /// If we've stopped taking new streams, by GOAWAY or a connection error.
+    pub(crate) fn is_closing(&self) -> bool {
+        self.going_away || self.failed
+    }
+
+    /// If nothing the client sends matters any more.
+    pub(crate) fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    ///
+    /// Internal Method: H2Connection::go_away()
+    ///
+    ///     Tells the client we won't take any more streams (the runner is
+    ///     draining or it's been idle too long), the ones it's already
+    ///     opened are still answered and the connection ends after them.
+    ///
+    pub(crate) fn go_away(&mut self) {
+        if !self.is_closing() {
+            self.going_away = true;
+            self.goaway(NO_ERROR, "");
+        }
+    }
+
+    ///
+    /// Internal Method: H2Connection::take_all() -> Vec<Exchange>
+    ///
+    ///     Ends every stream, the connection's going away without them
+    ///     (the client disconnected or we were cancelled). What comes back
+    ///     is for the caller to cancel.
+    ///
+    pub(crate) fn take_all(&mut self) -> Vec<Exchange> {
+        let streams = std::mem::take(&mut self.streams);
+        streams.into_values().filter_map(|stream| stream.exchange).collect()
+    }
+
+    ///
+    /// Internal Method: H2Connection::receive() -> Vec<Event>
+    ///
+    ///     Takes every whole frame at the start of `buffer` off it, whatever
+    ///     follows the last one stays for the next read. Once the client's
+    ///     broken the connection everything it sends is thrown away.
+    ///
+    pub(crate) fn receive(&mut self, buffer: &mut Vec<u8>) -> Vec<Event> {
+        let mut events = Vec::new();
+        if self.failed {
+            buffer.clear();
+            return events
+        }
+
+        let mut pos = 0;
+        if !self.preface {
+            if buffer.len() < PREFACE.len() {
+                if !PREFACE.starts_with(buffer) {
+                    self.fail(ConnectionError(PROTOCOL_ERROR, "invalid connection preface"));
+                    buffer.clear();
+                }
+                return events
+            }
+
+            if !buffer.starts_with(PREFACE) {
+                self.fail(ConnectionError(PROTOCOL_ERROR, "invalid connection preface"));
+                buffer.clear();
+                return events
+            }
+
+            self.preface = true;
+            pos = PREFACE.len();
+        }
+
+        while buffer.len() - pos >= 9 {
+            let header = &buffer[pos..pos + 9];
+            let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let (kind, flags) = (header[3], header[4]);
+            let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+
+            if len > MAX_FRAME_SIZE {
+                self.fail(ConnectionError(FRAME_SIZE_ERROR, "frame larger than SETTINGS_MAX_FRAME_SIZE"));
+                break
+            }
+
+            if buffer.len() - pos < 9 + len {
+                break
+            }
+
+            let payload = &buffer[pos + 9..pos + 9 + len];
+            pos += 9 + len;
+
+            if let Err(error) = self.handle(kind, flags, id, payload, &mut events) {
+                self.fail(error);
+                break
+            }
+        }
+
+        match self.failed {
+            true => buffer.clear(),
+            false => drop(buffer.drain(..pos)),
+        }
+
+        // the client gets back whatever window it's used up once it's half gone
+        if self.recv_window < RECV_WINDOW / 2 {
+            self.window_update(0, (RECV_WINDOW - self.recv_window) as u32);
+            self.recv_window = RECV_WINDOW;
+        }
+
+        let mut updates = Vec::new();
+        for (id, stream) in self.streams.iter_mut() {
+            if stream.receiving.is_some() && !stream.waiting && stream.recv_window < RECV_WINDOW / 2 {
+                updates.push((*id, (RECV_WINDOW - stream.recv_window) as u32));
+                stream.recv_window = RECV_WINDOW;
+            }
+        }
+
+        for (id, increment) in updates {
+            self.window_update(id, increment);
+        }
+
+        events.extend(self.cancelled.drain(..).map(Event::Cancelled));
+        events
+    }
+
+    /// The callbacks' tasks and streamed bodies, for the connection's `__traverse__`.
+    pub(crate) fn traverse(&self, visit: PyVisit) -> Result<(), PyTraverseError> {
+        for exchange in self.streams.values().filter_map(|stream| stream.exchange.as_ref()) {
+            if let Some(task) = exchange.task.as_ref() {
+                visit.call(task)?;
+            }
+            if let Some(body) = exchange.body.as_ref() {
+                visit.call(body)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hands a stream's request over to the callback, it's tracked with the stream until the response is out.
+    pub(crate) fn attach(&mut self, id: u32, exchange: Exchange) {
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.exchange = Some(exchange);
+        }
+    }
+
+    /// Every stream with a task still running (its callback, its hook or its body), to see if it's done.
+    pub(crate) fn running(&mut self) -> impl Iterator<Item = (u32, &mut Exchange)> {
+        self.streams
+            .iter_mut()
+            .filter_map(|(id, stream)| stream.exchange.as_mut().map(|exchange| (*id, exchange)))
+            .filter(|(_, exchange)| exchange.task.is_some())
+    }
+
+    /// The streams whose body has framed everything it's produced so far and isn't producing more.
+    pub(crate) fn starved(&self) -> Vec<u32> {
+        self.streams
+            .iter()
+            .filter(|(_, stream)| stream.sending.as_ref().is_some_and(Sending::is_starved))
+            .filter(|(_, stream)| stream.exchange.as_ref().is_some_and(|exchange| exchange.body.is_some() && exchange.task.is_none()))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// The request of a stream waiting on `on_headers`, to check and build its RequestMeta from.
+    pub(crate) fn waiting(&mut self, id: u32) -> Option<&mut Request> {
+        let stream = self.streams.get_mut(&id).filter(|stream| stream.waiting)?;
+        stream.receiving.as_mut().or(stream.held.as_mut())?.request.as_mut()
+    }
+
+    ///
+    /// Internal Method: H2Connection::release() -> Option<Event>
+    ///
+    ///     `on_headers` is done with a stream and left it `max_body_size`,
+    ///     the body is held to that from here on, what's arrived already
+    ///     included. A request that's all in comes back for the callback,
+    ///     one that's gone past the limit as a `413`.
+    ///
+    pub(crate) fn release(&mut self, id: u32, max_body_size: usize) -> Option<Event> {
+        let stream = self.streams.get_mut(&id).filter(|stream| stream.waiting)?;
+        stream.waiting = false;
+
+        let receiving = match stream.receiving.as_mut() {
+            Some(receiving) => receiving,
+            None => {
+                let held = stream.held.take()?;
+                return match held.is_over(max_body_size) {
+                    true => Some(Event::Refuse(id, 413)),
+                    false => held.request.map(Event::Request),
+                }
+            },
+        };
+
+        receiving.max_body_size = max_body_size;
+        match receiving.is_over(max_body_size) && receiving.request.take().is_some() {
+            true => Some(Event::Refuse(id, 413)),
+            false => None,
+        }
+    }
+
+    /// What's tracked for a stream, to answer it with.
+    pub(crate) fn exchange(&mut self, id: u32) -> Option<&mut Exchange> {
+        self.streams.get_mut(&id).and_then(|stream| stream.exchange.as_mut())
+    }
+
+    ///
+    /// Internal Method: H2Connection::respond()
+    ///
+    ///     Queues a stream's response, `fields` are its headers as
+    ///     `HTTPResponse::h2_head()` gives them. The body (and the file
+    ///     after it) is framed by `fill()` as the windows allow. A stream
+    ///     that's already gone (reset by the client) is left alone, and
+    ///     any of the request still to come is thrown away.
+    ///
+    pub(crate) fn respond(&mut self, id: u32, status: u16, fields: &[(String, String)], body: Bytes, file: Option<FileBody>) {
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) if stream.sending.is_none() => stream,
+            _ => return,
+        };
+
+        if let Some(receiving) = stream.receiving.as_mut() {
+            receiving.request = None;
+        }
+        stream.waiting = false;
+        stream.held = None;
+
+        let mut head = Vec::new();
+        self.encoder.encode(status, fields, &mut head);
+        if let Some(exchange) = stream.exchange.as_mut() {
+            exchange.status = status;
+        }
+
+        stream.sending = Some(Sending {
+            head: Some(head),
+            body,
+            sent: 0,
+            file,
+            streaming: false,
+        });
+    }
+
+    /// Queues the head of a response whose body is streamed, each chunk is given to `push()` as it's produced.
+    pub(crate) fn stream(&mut self, id: u32, status: u16, fields: &[(String, String)]) {
+        self.respond(id, status, fields, Bytes::new(), None);
+        if let Some(sending) = self.sending(id) {
+            sending.streaming = true;
+        }
+    }
+
+    /// The next chunk of a streamed body, once it's framed all the last one. `end` if it's the last.
+    pub(crate) fn push(&mut self, id: u32, chunk: Vec<u8>, end: bool) {
+        if let Some(sending) = self.sending(id).filter(|sending| sending.is_starved()) {
+            sending.body = chunk.into();
+            sending.sent = 0;
+            sending.streaming = !end;
+        }
+    }
+
+    fn sending(&mut self, id: u32) -> Option<&mut Sending> {
+        self.streams.get_mut(&id).and_then(|stream| stream.sending.as_mut())
+    }
+
+    ///
+    /// Internal Method: H2Connection::fill() -> io::Result<Vec<Exchange>>
+    ///
+    ///     Frames as much of the queued responses as the windows allow,
+    ///     stopping once there's `OUT_HIGH_WATER` waiting for the socket.
+    ///     File bodies are read a chunk at a time as they're needed. What
+    ///     comes back are the exchanges whose responses are all framed.
+    ///
+    pub(crate) fn fill(&mut self, py: Python) -> io::Result<Vec<Exchange>> {
+        let mut finished = Vec::new();
+        let ready: Vec<u32> = self.streams
+            .iter()
+            .filter(|(_, stream)| stream.sending.is_some())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ready {
+            if self.pending().len() >= OUT_HIGH_WATER {
+                break
+            }
+
+            if !self.frame_response(py, id)? {
+                continue
+            }
+
+            let stream = self.streams.remove(&id).unwrap();
+            // we've answered without reading the rest of the request, it can stop sending it
+            if stream.receiving.is_some() {
+                self.reset(id, NO_ERROR);
+            }
+
+            self.remember(id, false);
+            finished.extend(stream.exchange);
+        }
+
+        Ok(finished)
+    }
+
+    /// Frames what it can of a stream's response, true once it's all framed.
+    fn frame_response(&mut self, py: Python, id: u32) -> io::Result<bool> {
+        let stream = self.streams.get_mut(&id).unwrap();
+        let sending = stream.sending.as_mut().unwrap();
+        let before = self.out.len();
+
+        if let Some(head) = sending.head.take() {
+            let end = sending.is_done();
+            write_headers(&mut self.out, id, &head, end, self.max_frame_size);
+            if let Some(exchange) = stream.exchange.as_mut() {
+                exchange.sent += (self.out.len() - before) as u64;
+            }
+
+            if end {
+                return Ok(true)
+            }
+        }
+
+        loop {
+            if sending.sent == sending.body.len() {
+                if let Some(file) = sending.file.as_mut() {
+                    let mut chunk = Vec::new();
+                    if py.allow_threads(|| file.read_chunk(&mut chunk))? {
+                        sending.body = chunk.into();
+                        sending.sent = 0;
+                    }
+                }
+            }
+
+            let left = sending.body.len() - sending.sent;
+            if left == 0 && sending.streaming {
+                return Ok(false)
+            }
+
+            let window = self.send_window.min(stream.send_window).max(0) as usize;
+            let n = left.min(window).min(self.max_frame_size);
+            if n == 0 && left > 0 {
+                return Ok(false)
+            }
+
+            let last = n == left && !sending.streaming && sending.file.as_ref().is_none_or(FileBody::is_done);
+            let flags = if last { END_STREAM } else { 0 };
+            let start = self.out.len();
+            frame_to(&mut self.out, DATA, flags, id, &sending.body[sending.sent..sending.sent + n]);
+
+            sending.sent += n;
+            self.send_window -= n as i64;
+            stream.send_window -= n as i64;
+            if let Some(exchange) = stream.exchange.as_mut() {
+                exchange.sent += (self.out.len() - start) as u64;
+                exchange.body_sent += n as u64;
+            }
+
+            if last {
+                return Ok(true)
+            }
+
+            if self.out.len() - self.written >= OUT_HIGH_WATER {
+                return Ok(false)
+            }
+        }
+    }
+
+    fn handle(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8], events: &mut Vec<Event>) -> Result<(), ConnectionError> {
+        if !self.settings && kind != SETTINGS {
+            return Err(ConnectionError(PROTOCOL_ERROR, "expected SETTINGS after the preface"))
+        }
+
+        if let Some(continuation) = self.continuation.as_ref() {
+            if kind != CONTINUATION || id != continuation.stream {
+                return Err(ConnectionError(PROTOCOL_ERROR, "expected CONTINUATION"))
+            }
+        }
+
+        match kind {
+            DATA => self.on_data(flags, id, payload, events),
+            HEADERS => self.on_headers(flags, id, payload, events),
+            PRIORITY => self.on_priority(id, payload),
+            RST_STREAM => self.on_reset(id, payload, events),
+            SETTINGS => self.on_settings(flags, id, payload),
+            PUSH_PROMISE => Err(ConnectionError(PROTOCOL_ERROR, "clients can't push")),
+            PING => self.on_ping(flags, id, payload),
+            GOAWAY => self.on_goaway(id, payload),
+            WINDOW_UPDATE => self.on_window_update(id, payload),
+            CONTINUATION => self.on_continuation(flags, id, payload, events),
+            // unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, flags: u8, id: u32, payload: &[u8], events: &mut Vec<Event>) -> Result<(), ConnectionError> {
+        if id == 0 {
+            return Err(ConnectionError(PROTOCOL_ERROR, "DATA on stream 0"))
+        }
+
+        // padding counts against the window too
+        let len = payload.len() as i64;
+        if len > self.recv_window {
+            return Err(ConnectionError(FLOW_CONTROL_ERROR, "DATA past the connection window"))
+        }
+        self.recv_window -= len;
+
+        let data = unpad(flags, payload)?;
+        if self.is_idle_stream(id) {
+            return Err(ConnectionError(PROTOCOL_ERROR, "DATA on an idle stream"))
+        }
+
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return self.closed_stream(id),
+        };
+
+        let receiving = match stream.receiving.as_mut() {
+            Some(receiving) => receiving,
+            None => {
+                self.reset(id, STREAM_CLOSED);
+                return Ok(())
+            },
+        };
+
+        if len > stream.recv_window {
+            self.reset(id, FLOW_CONTROL_ERROR);
+            return Ok(())
+        }
+        stream.recv_window -= len;
+
+        receiving.received += data.len();
+        if receiving.length.is_some_and(|length| receiving.received > length) {
+            self.reset(id, PROTOCOL_ERROR);
+            return Ok(())
+        }
+
+        if let Some(request) = receiving.request.as_mut() {
+            if !stream.waiting && receiving.received > receiving.max_body_size {
+                receiving.request = None;
+                events.push(Event::Refuse(id, 413));
+            } else if let Err(e) = request.push_body(data, self.spool.as_ref()) {
+                crate::log::error(&format!("spooling a request body: {}", e));
+                receiving.request = None;
+                events.push(Event::Refuse(id, 500));
+            }
+        }
+
+        if flags & END_STREAM != 0 {
+            self.end_request(id, events);
+        }
+
+        Ok(())
+    }
+
+    fn on_headers(&mut self, flags: u8, id: u32, payload: &[u8], events: &mut Vec<Event>) -> Result<(), ConnectionError> {
+        if id == 0 || id.is_multiple_of(2) {
+            return Err(ConnectionError(PROTOCOL_ERROR, "HEADERS on a stream the client can't open"))
+        }
+
+        let mut block = unpad(flags, payload)?;
+        let mut depends_on_itself = false;
+        if flags & PRIORITY_FLAG != 0 {
+            if block.len() < 5 {
+                return Err(ConnectionError(FRAME_SIZE_ERROR, "HEADERS too short for its priority"))
+            }
+
+            depends_on_itself = u32::from_be_bytes([block[0], block[1], block[2], block[3]]) & 0x7fff_ffff == id;
+            block = &block[5..];
+        }
+
+        let purpose = match self.streams.get(&id) {
+            Some(stream) if stream.receiving.is_none() => Block::Reset(STREAM_CLOSED),
+            Some(_) if flags & END_STREAM == 0 => Block::Reset(PROTOCOL_ERROR),
+            Some(_) => Block::Trailers,
+            None if id <= self.last_stream => {
+                return match self.closed.iter().any(|(closed, _)| *closed == id) {
+                    true => Err(ConnectionError(STREAM_CLOSED, "HEADERS on a closed stream")),
+                    false => Err(ConnectionError(PROTOCOL_ERROR, "HEADERS on a stream lower than one already opened")),
+                }
+            },
+            None => {
+                self.last_stream = id;
+                match () {
+                    _ if self.going_away || self.peer_gone => Block::Ignored,
+                    _ if depends_on_itself => Block::Reset(PROTOCOL_ERROR),
+                    _ if self.streams.len() >= MAX_CONCURRENT_STREAMS => Block::Reset(REFUSED_STREAM),
+                    _ => Block::Request,
+                }
+            },
+        };
+
+        let purpose = match (purpose, depends_on_itself) {
+            (Block::Trailers, true) => Block::Reset(PROTOCOL_ERROR),
+            (purpose, _) => purpose,
+        };
+
+        let continuation = Continuation {
+            stream: id,
+            end_stream: flags & END_STREAM != 0,
+            block: block.to_vec(),
+            purpose,
+        };
+
+        match flags & END_HEADERS != 0 {
+            true => self.end_block(continuation, events),
+            false => {
+                self.continuation = Some(continuation);
+                Ok(())
+            },
+        }
+    }
+
+    fn on_continuation(&mut self, flags: u8, id: u32, payload: &[u8], events: &mut Vec<Event>) -> Result<(), ConnectionError> {
+        let mut continuation = match self.continuation.take() {
+            Some(continuation) => continuation,
+            None => return Err(ConnectionError(PROTOCOL_ERROR, "CONTINUATION without HEADERS")),
+        };
+
+        if id == 0 {
+            return Err(ConnectionError(PROTOCOL_ERROR, "CONTINUATION on stream 0"))
+        }
+
+        if continuation.block.len() + payload.len() > MAX_HEADER_BLOCK {
+            return Err(ConnectionError(ENHANCE_YOUR_CALM, "header block too large"))
+        }
+        continuation.block.extend_from_slice(payload);
+
+        match flags & END_HEADERS != 0 {
+            true => self.end_block(continuation, events),
+            false => {
+                self.continuation = Some(continuation);
+                Ok(())
+            },
+        }
+    }
+
+    /// A whole header block has arrived, it's always decoded whatever it's for.
+    fn end_block(&mut self, block: Continuation, events: &mut Vec<Event>) -> Result<(), ConnectionError> {
+        let id = block.stream;
+        let fields = self.decoder
+            .decode(&block.block, MAX_HEADER_LIST)
+            .map_err(|_| ConnectionError(COMPRESSION_ERROR, "header block failed to decode"))?;
+
+        match block.purpose {
+            Block::Ignored => Ok(()),
+            Block::Reset(code) => {
+                self.reset(id, code);
+                Ok(())
+            },
+            Block::Trailers => {
+                match fields.map(trailers) {
+                    Some(Ok(trailers)) => {
+                        let receiving = self.streams.get_mut(&id).and_then(|stream| stream.receiving.as_mut());
+                        if let Some(request) = receiving.and_then(|receiving| receiving.request.as_mut()) {
+                            request.trailers = trailers;
+                        }
+                        self.end_request(id, events);
+                    },
+                    Some(Err(())) => self.reset(id, PROTOCOL_ERROR),
+                    None => {
+                        self.refuse(id, 431, events);
+                        self.end_request(id, events);
+                    },
+                }
+
+                Ok(())
+            },
+            Block::Request => {
+                let mut stream = Stream {
+                    receiving: None,
+                    sending: None,
+                    waiting: false,
+                    held: None,
+                    recv_window: RECV_WINDOW,
+                    send_window: self.initial_window,
+                    exchange: None,
+                };
+
+                let fields = match fields {
+                    Some(fields) => fields,
+                    None => {
+                        // too big to hand on, answered for the callback
+                        if !block.end_stream {
+                            stream.receiving = Some(Receiving::refused());
+                        }
+                        self.streams.insert(id, stream);
+                        events.push(Event::Refuse(id, 431));
+                        return Ok(())
+                    },
+                };
+
+                let request = match request(id, fields) {
+                    Ok(request) => request,
+                    Err(()) => {
+                        self.reset(id, PROTOCOL_ERROR);
+                        return Ok(())
+                    },
+                };
+
+                // held to the same digits-only parse as HTTP/1.1, a length we can't trust can't be checked against the DATA
+                let length = match http::content_length(&request.headers) {
+                    Some(Err(())) => {
+                        self.reset(id, PROTOCOL_ERROR);
+                        return Ok(())
+                    },
+                    Some(Ok(length)) => Some(length),
+                    None => None,
+                };
+
+                if block.end_stream && length.is_some_and(|length| length != 0) {
+                    self.reset(id, PROTOCOL_ERROR);
+                    return Ok(())
+                }
+
+                // the hook says what the limit is, until then the stream only has its window
+                if self.hold {
+                    let receiving = Receiving::new(request, length, self.max_body_size);
+                    match block.end_stream {
+                        true => stream.held = Some(receiving),
+                        false => stream.receiving = Some(receiving),
+                    }
+                    stream.waiting = true;
+                    self.streams.insert(id, stream);
+                    events.push(Event::Head(id));
+                    return Ok(())
+                }
+
+                if block.end_stream {
+                    self.streams.insert(id, stream);
+                    events.push(Event::Request(request));
+                    return Ok(())
+                }
+
+                let mut receiving = Receiving::new(request, length, self.max_body_size);
+                let too_large = receiving.is_over(self.max_body_size);
+                if too_large {
+                    receiving.request = None;
+                }
+                stream.receiving = Some(receiving);
+                self.streams.insert(id, stream);
+
+                if too_large {
+                    events.push(Event::Refuse(id, 413));
+                }
+
+                Ok(())
+            },
+        }
+    }
+
+    /// The client's ended its side of the stream, its request can go to the callback.
+    fn end_request(&mut self, id: u32, events: &mut Vec<Event>) {
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let receiving = match stream.receiving.take() {
+            Some(receiving) => receiving,
+            None => return,
+        };
+
+        if receiving.length.is_some_and(|length| length != receiving.received) {
+            self.reset(id, PROTOCOL_ERROR);
+            return
+        }
+
+        if stream.waiting {
+            stream.held = Some(receiving);
+            return
+        }
+
+        if let Some(request) = receiving.request {
+            events.push(Event::Request(request));
+        }
+    }
+
+    /// Answers a stream with `status` instead of handing it on, anything more it sends is thrown away.
+    fn refuse(&mut self, id: u32, status: u16, events: &mut Vec<Event>) {
+        if let Some(receiving) = self.streams.get_mut(&id).and_then(|stream| stream.receiving.as_mut()) {
+            if receiving.request.take().is_some() {
+                events.push(Event::Refuse(id, status));
+            }
+        }
+    }
+
+    fn on_priority(&mut self, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        if id == 0 {
+            return Err(ConnectionError(PROTOCOL_ERROR, "PRIORITY on stream 0"))
+        }
+
+        if payload.len() != 5 {
+            self.reset(id, FRAME_SIZE_ERROR);
+            return Ok(())
+        }
+
+        // we don't prioritise, but a stream can't depend on itself
+        if u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7fff_ffff == id {
+            self.reset(id, PROTOCOL_ERROR);
+        }
+
+        Ok(())
+    }
+
+    fn on_reset(&mut self, id: u32, payload: &[u8], events: &mut Vec<Event>) -> Result<(), ConnectionError> {
+        if id == 0 {
+            return Err(ConnectionError(PROTOCOL_ERROR, "RST_STREAM on stream 0"))
+        }
+
+        if payload.len() != 4 {
+            return Err(ConnectionError(FRAME_SIZE_ERROR, "RST_STREAM must be 4 bytes"))
+        }
+
+        if self.is_idle_stream(id) {
+            return Err(ConnectionError(PROTOCOL_ERROR, "RST_STREAM on an idle stream"))
+        }
+
+        if let Some(stream) = self.streams.remove(&id) {
+            self.remember(id, false);
+            events.extend(stream.exchange.map(Event::Cancelled));
+        }
+
+        Ok(())
+    }
+
+    fn on_settings(&mut self, flags: u8, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        if id != 0 {
+            return Err(ConnectionError(PROTOCOL_ERROR, "SETTINGS on a stream"))
+        }
+
+        if flags & ACK != 0 {
+            return match payload.is_empty() {
+                true => Ok(()),
+                false => Err(ConnectionError(FRAME_SIZE_ERROR, "SETTINGS ack with a payload")),
+            }
+        }
+
+        if !payload.len().is_multiple_of(6) {
+            return Err(ConnectionError(FRAME_SIZE_ERROR, "SETTINGS must be a multiple of 6 bytes"))
+        }
+
+        for setting in payload.chunks(6) {
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match u16::from_be_bytes([setting[0], setting[1]]) {
+                // ENABLE_PUSH, we never push whatever it says
+                0x2 if value > 1 => return Err(ConnectionError(PROTOCOL_ERROR, "SETTINGS_ENABLE_PUSH must be 0 or 1")),
+                // INITIAL_WINDOW_SIZE, which changes every open stream's window by the difference
+                0x4 => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(ConnectionError(FLOW_CONTROL_ERROR, "SETTINGS_INITIAL_WINDOW_SIZE too large"))
+                    }
+
+                    let delta = value - self.initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(ConnectionError(FLOW_CONTROL_ERROR, "a stream's window went past the maximum"))
+                        }
+                    }
+                    self.initial_window = value;
+                },
+                // MAX_FRAME_SIZE
+                0x5 => {
+                    if !(MAX_FRAME_SIZE as u32..=0xff_ffff).contains(&value) {
+                        return Err(ConnectionError(PROTOCOL_ERROR, "SETTINGS_MAX_FRAME_SIZE out of range"))
+                    }
+                    self.max_frame_size = value as usize;
+                },
+                // the table size is ours to use or not (we don't), the rest don't matter to a server
+                _ => {},
+            }
+        }
+
+        self.settings = true;
+        self.frame(SETTINGS, ACK, 0, &[]);
+        Ok(())
+    }
+
+    fn on_ping(&mut self, flags: u8, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        if id != 0 {
+            return Err(ConnectionError(PROTOCOL_ERROR, "PING on a stream"))
+        }
+
+        if payload.len() != 8 {
+            return Err(ConnectionError(FRAME_SIZE_ERROR, "PING must be 8 bytes"))
+        }
+
+        if flags & ACK == 0 {
+            self.frame(PING, ACK, 0, payload);
+        }
+
+        Ok(())
+    }
+
+    fn on_goaway(&mut self, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        if id != 0 {
+            return Err(ConnectionError(PROTOCOL_ERROR, "GOAWAY on a stream"))
+        }
+
+        if payload.len() < 8 {
+            return Err(ConnectionError(FRAME_SIZE_ERROR, "GOAWAY too short"))
+        }
+
+        // the streams it's opened are still answered
+        self.peer_gone = true;
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        if payload.len() != 4 {
+            return Err(ConnectionError(FRAME_SIZE_ERROR, "WINDOW_UPDATE must be 4 bytes"))
+        }
+
+        let increment = (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7fff_ffff) as i64;
+        if id == 0 {
+            if increment == 0 {
+                return Err(ConnectionError(PROTOCOL_ERROR, "WINDOW_UPDATE of 0"))
+            }
+
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW {
+                return Err(ConnectionError(FLOW_CONTROL_ERROR, "connection window went past the maximum"))
+            }
+
+            return Ok(())
+        }
+
+        if self.is_idle_stream(id) {
+            return Err(ConnectionError(PROTOCOL_ERROR, "WINDOW_UPDATE on an idle stream"))
+        }
+
+        // it could've crossed with the end of the stream
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+
+        if increment == 0 {
+            self.reset(id, PROTOCOL_ERROR);
+            return Ok(())
+        }
+
+        stream.send_window += increment;
+        if stream.send_window > MAX_WINDOW {
+            self.reset(id, FLOW_CONTROL_ERROR);
+        }
+
+        Ok(())
+    }
+
+    /// A frame on a stream that isn't open any more, which is fine after we reset it ourselves.
+    fn closed_stream(&mut self, id: u32) -> Result<(), ConnectionError> {
+        match self.closed.iter().find(|(closed, _)| *closed == id) {
+            Some((_, true)) => Ok(()),
+            Some((_, false)) => {
+                self.frame(RST_STREAM, 0, id, &STREAM_CLOSED.to_be_bytes());
+                Ok(())
+            },
+            None => Err(ConnectionError(STREAM_CLOSED, "frame on a closed stream")),
+        }
+    }
+
+    /// A stream the client hasn't opened yet, every even one is since we never push.
+    fn is_idle_stream(&self, id: u32) -> bool {
+        id.is_multiple_of(2) || id > self.last_stream
+    }
+
+    /// Resets a stream, `CANCEL` for one we've given up on ourselves.
+    pub(crate) fn cancel(&mut self, id: u32) -> Option<Exchange> {
+        let exchange = self.streams.get_mut(&id).and_then(|stream| stream.exchange.take());
+        self.reset(id, CANCEL);
+        exchange
+    }
+
+    /// Resets a stream whose response can't be finished, its streamed body raised after the head was out.
+    pub(crate) fn abort(&mut self, id: u32) -> Option<Exchange> {
+        let exchange = self.streams.get_mut(&id).and_then(|stream| stream.exchange.take());
+        self.reset(id, INTERNAL_ERROR);
+        exchange
+    }
+
+    fn reset(&mut self, id: u32, code: u32) {
+        self.frame(RST_STREAM, 0, id, &code.to_be_bytes());
+        if let Some(exchange) = self.streams.remove(&id).and_then(|stream| stream.exchange) {
+            self.cancelled.push(exchange);
+        }
+        self.remember(id, true);
+    }
+
+    fn remember(&mut self, id: u32, by_us: bool) {
+        if self.closed.len() == CLOSED_REMEMBERED {
+            self.closed.pop_front();
+        }
+        self.closed.push_back((id, by_us));
+    }
+
+    fn fail(&mut self, error: ConnectionError) {
+        let ConnectionError(code, reason) = error;
+        self.goaway(code, reason);
+        self.failed = true;
+        self.continuation = None;
+    }
+
+    fn goaway(&mut self, code: u32, reason: &str) {
+        let mut payload = Vec::with_capacity(8 + reason.len());
+        payload.extend_from_slice(&self.last_stream.to_be_bytes());
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(reason.as_bytes());
+        self.frame(GOAWAY, 0, 0, &payload);
+    }
+
+    fn window_update(&mut self, id: u32, increment: u32) {
+        self.frame(WINDOW_UPDATE, 0, id, &increment.to_be_bytes());
+    }
+
+    fn frame(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+        frame_to(&mut self.out, kind, flags, id, payload);
+    }
+}
+
+impl Receiving {
+    fn new(request: Request, length: Option<usize>, max_body_size: usize) -> Self {
+        Self {
+            request: Some(request),
+            length,
+            received: 0,
+            max_body_size,
+        }
+    }
+
+    fn refused() -> Self {
+        Self {
+            request: None,
+            length: None,
+            received: 0,
+            max_body_size: 0,
+        }
+    }
+
+    /// If the body's past `max_body_size`, by what's arrived or what its `content-length` says.
+    fn is_over(&self, max_body_size: usize) -> bool {
+        self.received > max_body_size || self.length.is_some_and(|length| length > max_body_size)
+    }
+}
+
+
+fn frame_to(out: &mut Vec<u8>, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// A header block as a HEADERS and however many CONTINUATIONs it takes to fit the client's frame size.
+fn write_headers(out: &mut Vec<u8>, id: u32, block: &[u8], end_stream: bool, max_frame_size: usize) {
+    let mut chunks = block.chunks(max_frame_size).peekable();
+    let first = chunks.next().unwrap_or(&[]);
+
+    let mut flags = if end_stream { END_STREAM } else { 0 };
+    if chunks.peek().is_none() {
+        flags |= END_HEADERS;
+    }
+    frame_to(out, HEADERS, flags, id, first);
+
+    while let Some(chunk) = chunks.next() {
+        let flags = if chunks.peek().is_none() { END_HEADERS } else { 0 };
+        frame_to(out, CONTINUATION, flags, id, chunk);
+    }
+}
+
+/// The payload without its padding, padding that's as long as the frame is a connection error.
+fn unpad(flags: u8, payload: &[u8]) -> Result<&[u8], ConnectionError> {
+    if flags & PADDED == 0 {
+        return Ok(payload)
+    }
+
+    let padding = *payload.first().ok_or(ConnectionError(FRAME_SIZE_ERROR, "padded frame without a pad length"))? as usize;
+    if padding >= payload.len() {
+        return Err(ConnectionError(PROTOCOL_ERROR, "padding longer than the frame"))
+    }
+
+    Ok(&payload[1..payload.len() - padding])
+}
+
+///
+/// Internal Method: h2::request() -> Result<Request, ()>
+///
+///     Checks a request's fields the way RFC 9113 8.3 has them and turns
+///     them into the head an HTTP/1 request would have had, `:authority`
+///     as `host` (unless there's one already) and the `cookie` fields
+///     joined back into one. `Err` is a malformed request, the stream's
+///     reset.
+///
+fn request(id: u32, fields: Vec<Field>) -> Result<Request, ()> {
+    let mut method = None;
+    let mut scheme = None;
+    let mut authority = None;
+    let mut path = None;
+    let mut headers = Headers::new();
+    let mut cookies = Vec::new();
+    let mut regular = false;
+
+    for (name, value) in fields {
+        if let Some(pseudo) = name.strip_prefix(b":") {
+            let slot = match pseudo {
+                _ if regular => return Err(()),
+                b"method" => &mut method,
+                b"scheme" => &mut scheme,
+                b"authority" => &mut authority,
+                b"path" => &mut path,
+                _ => return Err(()),
+            };
+
+            if slot.replace(value).is_some() {
+                return Err(())
+            }
+            continue
+        }
+
+        regular = true;
+        check_field(&name, &value)?;
+        if name == b"cookie" {
+            cookies.push(value);
+            continue
+        }
+
+        // `check_field` made sure it's a token
+        headers.append(std::str::from_utf8(&name).map_err(|_| ())?, &value);
+    }
+
+    let method = method.ok_or(())?;
+    if !http::is_method(&method) {
+        return Err(())
+    }
+    let method = http::method_name(&method);
+
+    let target = match method.as_str() {
+        "CONNECT" if scheme.is_none() && path.is_none() => authority.clone().ok_or(())?,
+        "CONNECT" => return Err(()),
+        _ => {
+            let path = path.filter(|path| !path.is_empty()).ok_or(())?;
+            if scheme.filter(|scheme| !scheme.is_empty()).is_none() {
+                return Err(())
+            }
+            if !(path.starts_with(b"/") || (path == b"*" && method == "OPTIONS")) {
+                return Err(())
+            }
+            path
+        },
+    };
+
+    if let Some(authority) = authority.filter(|_| !headers.contains(HeaderName::Host)) {
+        headers.append("host", &authority);
+    }
+
+    if !cookies.is_empty() {
+        headers.append("cookie", &cookies.join(&b"; "[..]));
+    }
+
+    Ok(Request {
+        stream: id,
+        method,
+        target,
+        headers,
+        trailers: Headers::new(),
+        body: Vec::new(),
+        spooled: None,
+        received: Instant::now(),
+    })
+}
+
+/// Trailers are regular fields only.
+fn trailers(fields: Vec<Field>) -> Result<Headers, ()> {
+    let mut headers = Headers::new();
+    for (name, value) in fields {
+        check_field(&name, &value)?;
+        headers.append(std::str::from_utf8(&name).map_err(|_| ())?, &value);
+    }
+
+    Ok(headers)
+}
+
+/// A field name has to be a lowercase token and not an HTTP/1 connection header, a value can't break a line.
+fn check_field(name: &[u8], value: &[u8]) -> Result<(), ()> {
+    if name.is_empty() || !name.iter().all(|b| cookie::is_token(*b) && !b.is_ascii_uppercase()) {
+        return Err(())
+    }
+
+    if CONNECTION_SPECIFIC.contains(&name) || (name == b"te" && value != b"trailers") {
+        return Err(())
+    }
+
+    if value.iter().any(|b| matches!(b, b'\0' | b'\r' | b'\n')) {
+        return Err(())
+    }
+
+    Ok(())
+}
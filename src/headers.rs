@@ -0,0 +1,398 @@
+use pyo3::prelude::*;
+use pyo3::{PyIterProtocol, PyMappingProtocol, PyObjectProtocol, PySequenceProtocol};
+use pyo3::exceptions::PyKeyError;
+use pyo3::types::{PyBytes, PyList};
+
+use crate::http::latin1;
+
+
+///
+/// Headers are the request headers as the client sent them, lookups ignore
+/// the case of the name (`headers["content-length"]` finds `Content-Length`)
+/// while iterating gives back the names exactly as they arrived.
+///
+/// A header can be sent more than once (`Set-Cookie`, `Via`...), every
+/// occurrence is kept, `get()` and `headers[name]` give the first one and
+/// `get_all()` the lot in the order they were received.
+///
+/// Values are whatever bytes the client sent decoded as latin-1, which
+/// can't fail and maps each byte to one character so nothing is lost,
+/// `get_raw()` gives the bytes back exactly. Names are always ascii tokens.
+///
+/// Anything we make a framing decision on internally (Content-Length,
+/// Connection, Transfer-Encoding...) is looked up through this as well so
+/// `connection: CLOSE` means the same as `Connection: close`.
+///
+/// A request has a handful of headers so they're kept in the order they
+/// arrived and looked up by going through them, which beats hashing at
+/// these sizes. Most of them cost the one allocation for the value, the
+/// usual names (see HeaderName) sent the usual way or all lowercase
+/// aren't copied at all and finding one of them is comparing the variant
+/// rather than the text.
+///
+#[pyclass]
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    entries: Vec<(Name, Value)>,
+}
+
+///
+/// HeaderName is one of the names clients usually send or that we look up
+/// ourselves to make framing decisions, any case of it parses to the same
+/// variant. Names that aren't one of these are kept as they are.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HeaderName {
+    Host,
+    UserAgent,
+    Accept,
+    AcceptEncoding,
+    AcceptLanguage,
+    Connection,
+    ContentLength,
+    ContentType,
+    ContentDisposition,
+    Cookie,
+    Referer,
+    Origin,
+    CacheControl,
+    Pragma,
+    Authorization,
+    IfNoneMatch,
+    IfModifiedSince,
+    IfRange,
+    Range,
+    Upgrade,
+    UpgradeInsecureRequests,
+    TransferEncoding,
+    Trailer,
+    Te,
+    Expect,
+    SecWebSocketKey,
+    SecWebSocketVersion,
+    XForwardedFor,
+    XForwardedProto,
+    XRequestedWith,
+    XRequestId,
+    Forwarded,
+    Via,
+    Dnt,
+    AccessControlRequestMethod,
+    AccessControlRequestHeaders,
+}
+
+/// Each HeaderName written the way it's usually written and all lowercase (how HTTP/2 sends it), in the order of the variants.
+const SPELLINGS: [(&str, &str); 36] = [
+    ("Host", "host"),
+    ("User-Agent", "user-agent"),
+    ("Accept", "accept"),
+    ("Accept-Encoding", "accept-encoding"),
+    ("Accept-Language", "accept-language"),
+    ("Connection", "connection"),
+    ("Content-Length", "content-length"),
+    ("Content-Type", "content-type"),
+    ("Content-Disposition", "content-disposition"),
+    ("Cookie", "cookie"),
+    ("Referer", "referer"),
+    ("Origin", "origin"),
+    ("Cache-Control", "cache-control"),
+    ("Pragma", "pragma"),
+    ("Authorization", "authorization"),
+    ("If-None-Match", "if-none-match"),
+    ("If-Modified-Since", "if-modified-since"),
+    ("If-Range", "if-range"),
+    ("Range", "range"),
+    ("Upgrade", "upgrade"),
+    ("Upgrade-Insecure-Requests", "upgrade-insecure-requests"),
+    ("Transfer-Encoding", "transfer-encoding"),
+    ("Trailer", "trailer"),
+    ("TE", "te"),
+    ("Expect", "expect"),
+    ("Sec-WebSocket-Key", "sec-websocket-key"),
+    ("Sec-WebSocket-Version", "sec-websocket-version"),
+    ("X-Forwarded-For", "x-forwarded-for"),
+    ("X-Forwarded-Proto", "x-forwarded-proto"),
+    ("X-Requested-With", "x-requested-with"),
+    ("X-Request-Id", "x-request-id"),
+    ("Forwarded", "forwarded"),
+    ("Via", "via"),
+    ("DNT", "dnt"),
+    ("Access-Control-Request-Method", "access-control-request-method"),
+    ("Access-Control-Request-Headers", "access-control-request-headers"),
+];
+
+const NAMES: [HeaderName; 36] = [
+    HeaderName::Host, HeaderName::UserAgent, HeaderName::Accept, HeaderName::AcceptEncoding,
+    HeaderName::AcceptLanguage, HeaderName::Connection, HeaderName::ContentLength, HeaderName::ContentType,
+    HeaderName::ContentDisposition, HeaderName::Cookie, HeaderName::Referer, HeaderName::Origin,
+    HeaderName::CacheControl, HeaderName::Pragma, HeaderName::Authorization, HeaderName::IfNoneMatch,
+    HeaderName::IfModifiedSince, HeaderName::IfRange, HeaderName::Range, HeaderName::Upgrade,
+    HeaderName::UpgradeInsecureRequests, HeaderName::TransferEncoding, HeaderName::Trailer, HeaderName::Te,
+    HeaderName::Expect, HeaderName::SecWebSocketKey, HeaderName::SecWebSocketVersion, HeaderName::XForwardedFor,
+    HeaderName::XForwardedProto, HeaderName::XRequestedWith, HeaderName::XRequestId, HeaderName::Forwarded,
+    HeaderName::Via, HeaderName::Dnt, HeaderName::AccessControlRequestMethod, HeaderName::AccessControlRequestHeaders,
+];
+
+impl HeaderName {
+    /// The variant for `name` in whatever case it's in.
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        SPELLINGS
+            .iter()
+            .position(|(usual, _)| usual.len() == name.len() && usual.eq_ignore_ascii_case(name))
+            .map(|index| NAMES[index])
+    }
+
+    /// The name written the way it's usually written, `Content-Length`.
+    pub(crate) fn as_str(self) -> &'static str {
+        SPELLINGS[self as usize].0
+    }
+
+    fn lowercase(self) -> &'static str {
+        SPELLINGS[self as usize].1
+    }
+}
+
+///
+/// Name is how a header's name is kept, as its HeaderName when it has one
+/// along with how it was spelt so it can be given back exactly, only an
+/// unusual spelling (`CONTENT-LENGTH`) of one of those needs a copy.
+///
+#[derive(Debug, Clone)]
+pub(crate) enum Name {
+    Known(HeaderName, Spelling),
+    Other(String),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Spelling {
+    Usual,          // As `HeaderName::as_str()` has it
+    Lowercase,
+    Sent(Box<str>),
+}
+
+impl Name {
+    fn new(name: &str) -> Self {
+        let known = match HeaderName::parse(name) {
+            Some(known) => known,
+            None => return Self::Other(name.to_string()),
+        };
+
+        let spelling = if name == known.as_str() {
+            Spelling::Usual
+        } else if name == known.lowercase() {
+            Spelling::Lowercase
+        } else {
+            Spelling::Sent(name.into())
+        };
+
+        Self::Known(known, spelling)
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Known(known, Spelling::Usual) => known.as_str(),
+            Self::Known(known, Spelling::Lowercase) => known.lowercase(),
+            Self::Known(_, Spelling::Sent(name)) => name,
+            Self::Other(name) => name,
+        }
+    }
+}
+
+///
+/// Lookup is what headers can be found by, a HeaderName which only has to
+/// be compared with the names we know or any name as a `str` compared
+/// ignoring case.
+///
+pub(crate) trait Lookup: Copy {
+    fn is(self, name: &Name) -> bool;
+}
+
+impl Lookup for HeaderName {
+    fn is(self, name: &Name) -> bool {
+        matches!(name, Name::Known(known, _) if *known == self)
+    }
+}
+
+impl Lookup for &str {
+    fn is(self, name: &Name) -> bool {
+        name.as_str().eq_ignore_ascii_case(self)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Value {
+    raw: Vec<u8>,               // The bytes that were sent
+    latin1: Option<Box<str>>,   // Only made when `raw` isn't ascii, otherwise it's already the str
+}
+
+impl Value {
+    fn new(raw: &[u8]) -> Self {
+        let latin1 = match raw.is_ascii() {
+            true => None,
+            false => Some(latin1(raw).into_boxed_str()),
+        };
+
+        Self { raw: raw.to_vec(), latin1 }
+    }
+
+    fn as_str(&self) -> &str {
+        match self.latin1.as_deref() {
+            Some(value) => value,
+            // ascii, so always utf-8
+            None => std::str::from_utf8(&self.raw).unwrap_or_default(),
+        }
+    }
+}
+
+impl Headers {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a header from the bytes sent, any earlier ones with the same name are kept.
+    pub(crate) fn append(&mut self, name: &str, value: &[u8]) {
+        self.entries.push((Name::new(name), Value::new(value)));
+    }
+
+    /// Replaces every value for `name` with the one given.
+    pub(crate) fn set(&mut self, name: &str, value: &[u8]) {
+        self.entries.retain(|(existing, _)| !name.is(existing));
+        self.append(name, value);
+    }
+
+    /// The first value sent for `name`.
+    pub(crate) fn get(&self, name: impl Lookup) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(existing, _)| name.is(existing))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value sent for `name` in the order they were received.
+    pub(crate) fn get_all<'a>(&'a self, name: impl Lookup + 'a) -> impl Iterator<Item = &'a str> {
+        self.entries
+            .iter()
+            .filter(move |(existing, _)| name.is(existing))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The bytes of the first value sent for `name`.
+    pub(crate) fn get_raw(&self, name: impl Lookup) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(existing, _)| name.is(existing))
+            .map(|(_, value)| value.raw.as_slice())
+    }
+
+    /// The `(name, value)` pairs with the value as the bytes that were sent.
+    pub(crate) fn iter_raw(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.raw.as_slice()))
+    }
+
+    pub(crate) fn contains(&self, name: impl Lookup) -> bool {
+        self.entries.iter().any(|(existing, _)| name.is(existing))
+    }
+
+    /// The `(name, value)` pairs in the order they were received.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[pymethods]
+impl Headers {
+    ///
+    /// PythonMethod: Headers.get(name, default=None) -> str
+    ///
+    #[args(default = "None")]
+    #[name = "get"]
+    fn py_get(&self, py: Python, name: &str, default: Option<PyObject>) -> PyObject {
+        match self.get(name) {
+            Some(value) => value.into_py(py),
+            None => default.unwrap_or_else(|| py.None()),
+        }
+    }
+
+    ///
+    /// PythonMethod: Headers.get_raw(name, default=None) -> bytes
+    ///
+    ///     The first value sent for `name` as the exact bytes that arrived,
+    ///     for when the latin-1 str isn't good enough (a signature over it,
+    ///     utf-8 the client sent anyway...).
+    ///
+    #[args(default = "None")]
+    #[name = "get_raw"]
+    fn py_get_raw(&self, py: Python, name: &str, default: Option<PyObject>) -> PyObject {
+        match self.get_raw(name) {
+            Some(value) => PyBytes::new(py, value).into(),
+            None => default.unwrap_or_else(|| py.None()),
+        }
+    }
+
+    ///
+    /// PythonMethod: Headers.get_all(name) -> list[str]
+    ///
+    ///     Every value sent for `name`, empty if there weren't any.
+    ///
+    #[name = "get_all"]
+    fn py_get_all<'a>(&'a self, name: &'a str) -> Vec<&'a str> {
+        self.get_all(name).collect()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.iter().map(|(name, _)| name).collect()
+    }
+
+    fn values(&self) -> Vec<&str> {
+        self.iter().map(|(_, value)| value).collect()
+    }
+
+    fn items(&self) -> Vec<(&str, &str)> {
+        self.iter().collect()
+    }
+}
+
+#[pyproto]
+impl PyMappingProtocol for Headers {
+    fn __getitem__(&self, name: &str) -> PyResult<String> {
+        match self.get(name) {
+            Some(value) => Ok(value.to_string()),
+            None => Err(PyKeyError::new_err(name.to_string())),
+        }
+    }
+
+    fn __len__(&self) -> usize {
+        self.len()
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for Headers {
+    fn __contains__(&self, name: &str) -> bool {
+        self.contains(name)
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for Headers {
+    fn __iter__(slf: PyRef<Self>) -> PyResult<PyObject> {
+        let py = slf.py();
+        let names = PyList::new(py, slf.keys());
+        Ok(names.call_method0("__iter__")?.into())
+    }
+}
+
+#[pyproto]
+impl PyObjectProtocol for Headers {
+    fn __repr__(&self) -> String {
+        format!("Headers({:?})", self.items())
+    }
+}
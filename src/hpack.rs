@@ -0,0 +1,450 @@
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+
+/// What every dynamic table entry costs on top of its name and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// The dynamic table size HTTP/2 starts with, we never offer more so it's the most a client can ask for.
+const TABLE_SIZE: usize = 4096;
+
+/// Marks a Huffman tree branch that's a symbol rather than another node.
+const LEAF: u16 = 0x8000;
+
+/// A name and value as they came off the wire, HTTP/2 says nothing about their encoding.
+pub(crate) type Field = (Vec<u8>, Vec<u8>);
+
+///
+/// A header block that doesn't decode, the dynamic tables are out of step
+/// from then on so it's the end of the connection (a `COMPRESSION_ERROR`).
+///
+#[derive(Debug)]
+pub(crate) struct CompressionError;
+
+/// The static table from RFC 7541 appendix A, index 1 is the first entry.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// The code and its length in bits for every byte and then EOS, RFC 7541 appendix B.
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6),
+    (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7),
+    (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7),
+    (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13),
+    (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5),
+    (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5),
+    (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15),
+    (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23),
+    (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19),
+    (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26),
+    (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25),
+    (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26),
+    (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+
+///
+/// Decoder is our half of HPACK (RFC 7541) for what the client sends,
+/// turning header blocks back into fields. It has to see every block on
+/// the connection in order, even those of streams we're refusing, since
+/// any of them can change the dynamic table the next one refers to.
+///
+pub(crate) struct Decoder {
+    table: VecDeque<Field>,     // The dynamic table, newest first
+    size: usize,                // What its entries cost, see ENTRY_OVERHEAD
+    max_size: usize,            // The most they can cost, the client can lower it from TABLE_SIZE
+}
+
+impl Decoder {
+    pub(crate) fn new() -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+        }
+    }
+
+    ///
+    /// Internal Method: Decoder::decode() -> Result<Option<Vec<Field>>, CompressionError>
+    ///
+    ///     Decodes a whole header block (a HEADERS and its CONTINUATIONs put
+    ///     back together) into its fields in order. Once the fields add up
+    ///     to more than `limit` (counted as the table counts them) they stop
+    ///     being kept and `None` comes back, the rest of the block is still
+    ///     decoded so the table stays in step.
+    ///
+    pub(crate) fn decode(&mut self, block: &[u8], limit: usize) -> Result<Option<Vec<Field>>, CompressionError> {
+        let mut fields = Vec::new();
+        let mut total = 0usize;
+        let mut pos = 0;
+
+        while pos < block.len() {
+            let first = block[pos];
+            let field = if first & 0x80 != 0 {
+                let index = integer(block, &mut pos, 7)?;
+                self.get(index)?
+            } else if first & 0x40 != 0 {
+                let field = self.literal(block, &mut pos, 6)?;
+                self.insert(field.clone());
+                field
+            } else if first & 0x20 != 0 {
+                // a size update has to come before any field
+                if total > 0 {
+                    return Err(CompressionError)
+                }
+
+                let size = integer(block, &mut pos, 5)?;
+                if size > TABLE_SIZE {
+                    return Err(CompressionError)
+                }
+
+                self.max_size = size;
+                self.evict(0);
+                continue
+            } else {
+                // without indexing and never indexed are the same to us
+                self.literal(block, &mut pos, 4)?
+            };
+
+            total = total.saturating_add(field.0.len() + field.1.len() + ENTRY_OVERHEAD);
+            if total <= limit {
+                fields.push(field);
+            }
+        }
+
+        match total <= limit {
+            true => Ok(Some(fields)),
+            false => Ok(None),
+        }
+    }
+
+    /// A literal field, its name indexed (with `prefix` bits) or a literal too.
+    fn literal(&self, block: &[u8], pos: &mut usize, prefix: u8) -> Result<Field, CompressionError> {
+        let name = match integer(block, pos, prefix)? {
+            0 => string(block, pos)?,
+            index => self.get(index)?.0,
+        };
+
+        Ok((name, string(block, pos)?))
+    }
+
+    /// The static table and then the dynamic one, 0 and anything past the end are errors.
+    fn get(&self, index: usize) -> Result<Field, CompressionError> {
+        if let Some((name, value)) = index.checked_sub(1).and_then(|index| STATIC_TABLE.get(index)) {
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        }
+
+        index
+            .checked_sub(STATIC_TABLE.len() + 1)
+            .and_then(|index| self.table.get(index))
+            .cloned()
+            .ok_or(CompressionError)
+    }
+
+    /// Adds an entry, one bigger than the whole table just empties it.
+    fn insert(&mut self, field: Field) {
+        let cost = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        if cost > self.max_size {
+            self.table.clear();
+            self.size = 0;
+            return
+        }
+
+        self.evict(cost);
+        self.size += cost;
+        self.table.push_front(field);
+    }
+
+    /// Drops the oldest entries until there's room for `cost` more.
+    fn evict(&mut self, cost: usize) {
+        while self.size + cost > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+
+///
+/// Encoder is our half of HPACK for responses. We never add to the dynamic
+/// table, so every field is a literal (bar a `:status` the static table has)
+/// and a block only ever depends on itself, the first one tells the client
+/// our table is empty so it doesn't keep one for us.
+///
+#[derive(Default)]
+pub(crate) struct Encoder {
+    started: bool,
+}
+
+impl Encoder {
+    ///
+    /// Internal Method: Encoder::encode()
+    ///
+    ///     Encodes a response's `:status` and then its fields onto `out`,
+    ///     the names have to be lowercase already (see
+    ///     `HTTPResponse::h2_head()`).
+    ///
+    pub(crate) fn encode(&mut self, status: u16, fields: &[(String, String)], out: &mut Vec<u8>) {
+        if !std::mem::replace(&mut self.started, true) {
+            integer_to(0, 5, 0x20, out);
+        }
+
+        let indexed = STATIC_TABLE[7..14].iter().position(|(_, value)| value.parse() == Ok(status));
+        match indexed {
+            Some(index) => integer_to(index + 8, 7, 0x80, out),
+            None => {
+                integer_to(8, 4, 0x00, out);
+                string_to(status.to_string().as_bytes(), out);
+            },
+        }
+
+        for (name, value) in fields {
+            out.push(0x00);
+            string_to(name.as_bytes(), out);
+            string_to(value.as_bytes(), out);
+        }
+    }
+}
+
+
+/// An integer with a `prefix` bit prefix (RFC 7541 5.1), capped well below anything that could overflow.
+fn integer(block: &[u8], pos: &mut usize, prefix: u8) -> Result<usize, CompressionError> {
+    let mask = (1usize << prefix) - 1;
+    let first = *block.get(*pos).ok_or(CompressionError)? as usize & mask;
+    *pos += 1;
+    if first < mask {
+        return Ok(first)
+    }
+
+    let mut value = mask;
+    for shift in (0..28).step_by(7) {
+        let byte = *block.get(*pos).ok_or(CompressionError)?;
+        *pos += 1;
+
+        value += ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value)
+        }
+    }
+
+    Err(CompressionError)
+}
+
+fn integer_to(value: usize, prefix: u8, flags: u8, out: &mut Vec<u8>) {
+    let mask = (1usize << prefix) - 1;
+    if value < mask {
+        out.push(flags | value as u8);
+        return
+    }
+
+    out.push(flags | mask as u8);
+    let mut rest = value - mask;
+    while rest >= 0x80 {
+        out.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+/// A string literal (RFC 7541 5.2), Huffman coded or not.
+fn string(block: &[u8], pos: &mut usize) -> Result<Vec<u8>, CompressionError> {
+    let huffman = *block.get(*pos).ok_or(CompressionError)? & 0x80 != 0;
+    let len = integer(block, pos, 7)?;
+    let end = pos.checked_add(len).filter(|end| *end <= block.len()).ok_or(CompressionError)?;
+
+    let raw = &block[*pos..end];
+    *pos = end;
+    match huffman {
+        true => huffman_decode(raw),
+        false => Ok(raw.to_vec()),
+    }
+}
+
+/// We don't Huffman code what we send, it isn't worth it for a response's few headers.
+fn string_to(value: &[u8], out: &mut Vec<u8>) {
+    integer_to(value.len(), 7, 0x00, out);
+    out.extend_from_slice(value);
+}
+
+///
+/// The Huffman code as a tree, a node's branches are the next node for a
+/// 0 and a 1 or the symbol (with LEAF set) the code ends on. Built the
+/// first time a client sends anything Huffman coded.
+///
+fn huffman_tree() -> &'static [[u16; 2]] {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut nodes = vec![[0u16; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for bit in (0..len).rev() {
+                let branch = ((code >> bit) & 1) as usize;
+                if bit == 0 {
+                    nodes[node][branch] = LEAF | symbol as u16;
+                    break
+                }
+
+                if nodes[node][branch] == 0 {
+                    nodes.push([0; 2]);
+                    nodes[node][branch] = (nodes.len() - 1) as u16;
+                }
+                node = nodes[node][branch] as usize;
+            }
+        }
+
+        nodes
+    })
+}
+
+///
+/// Decodes a Huffman coded string, what's left over at the end has to be
+/// under a byte of the start of EOS (all 1s), and EOS itself is never
+/// allowed in a string.
+///
+fn huffman_decode(raw: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    let tree = huffman_tree();
+    let mut out = Vec::with_capacity(raw.len() * 8 / 5);
+    let (mut node, mut depth, mut ones) = (0usize, 0u8, true);
+
+    for byte in raw {
+        for bit in (0..8).rev() {
+            let branch = (byte >> bit) & 1;
+            let next = tree[node][branch as usize];
+            depth += 1;
+            ones &= branch == 1;
+
+            if next & LEAF == 0 {
+                node = next as usize;
+                continue
+            }
+
+            match next & !LEAF {
+                256 => return Err(CompressionError),
+                symbol => out.push(symbol as u8),
+            }
+            node = 0;
+            depth = 0;
+            ones = true;
+        }
+    }
+
+    match depth < 8 && ones {
+        true => Ok(out),
+        false => Err(CompressionError),
+    }
+}
@@ -0,0 +1,2420 @@
+use pyo3::prelude::*;
+use pyo3::{ffi, AsPyPointer, PyBufferProtocol};
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::types::{PyBool, PyBytes, PyDict, PyString, PyTuple, PyType};
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use bstr::ByteSlice;
+use bytes::Bytes;
+
+use crate::compress::{Encoder, Encoding};
+use crate::cookie::{self, SetCookie};
+use crate::headers::{HeaderName, Headers};
+use crate::inbound::{Inbound, RequestStream};
+use crate::multipart::{self, MultipartCall};
+use crate::options::RunnerOptions;
+use crate::outgoing::Outgoing;
+use crate::spool::Spool;
+use crate::stats::RequestTimings;
+use crate::tls::TLSInfo;
+
+
+/// The most fields `request.form()` will decode, a body with more is refused.
+const MAX_FORM_FIELDS: usize = 1000;
+
+///
+/// HTTPRequest is what the callback receives for every request, it is
+/// built once the full request head has arrived and just carries the
+/// parsed pieces over to python.
+///
+#[pyclass]
+#[derive(Debug)]
+pub struct HTTPRequest {
+    pub(crate) method: String,
+
+    /// The percent-decoded path with `.` and `..` segments resolved, just
+    /// `"*"` for an `OPTIONS *` handed over by `pass_options_star`.
+    #[pyo3(get)]
+    pub(crate) path: String,
+
+    pub(crate) raw_path: Vec<u8>,       // The path exactly as it was sent, see the `raw_path` getter
+
+    pub(crate) path_bytes: Vec<u8>,     // `path` before it was made utf-8, WSGI wants these as latin-1
+
+    /// The query string as it was sent, without the leading `?`. Bytes
+    /// that aren't utf-8 are `%XX` encoded.
+    #[pyo3(get)]
+    pub(crate) raw_query: String,
+
+    pub(crate) query: Vec<(String, Vec<String>)>,
+
+    #[pyo3(get)]
+    pub(crate) protocol: String,
+
+    pub(crate) headers: Headers,
+
+    /// The trailer fields sent after a chunked body, empty for any other
+    /// body. The body is read in full before the callback is invoked so
+    /// these are complete by the time it sees them, a body streamed with
+    /// `stream_request_body` never has them.
+    #[pyo3(get)]
+    pub(crate) trailers: Headers,
+
+    /// The `(host, port)` of the peer or `None` if it couldn't be determined.
+    #[pyo3(get)]
+    pub(crate) client: Option<(String, u16)>,
+
+    /// The host of the client as far as `trusted_proxies` vouch for it, the
+    /// address a trusted proxy said it forwarded for (in `forwarded_header`)
+    /// or the peer's own host otherwise.
+    #[pyo3(get)]
+    pub(crate) remote_addr: Option<String>,
+
+    /// A dict for the handler's own state about the connection (a session,
+    /// a counter...), the same dict for every request made on it and let go
+    /// of once it closes.
+    #[pyo3(get)]
+    pub(crate) connection: Option<Py<PyDict>>,
+
+    /// What was negotiated with the client (see TLSInfo), `None` for
+    /// plain TCP connections. Every request on a connection shares it.
+    #[pyo3(get)]
+    pub(crate) tls: Option<Py<TLSInfo>>,
+
+    /// The `(host, port)` of our end of the connection.
+    #[pyo3(get)]
+    pub(crate) server: Option<(String, u16)>,
+
+    /// Either `"http"` or `"https"` depending on if the listener terminates TLS.
+    #[pyo3(get)]
+    pub(crate) scheme: String,
+
+    pub(crate) target: TargetForm,      // The form the target was sent in, see the `target_*` getters
+
+    /// A unique id for the request, 32 hex digits, or the client's own
+    /// `X-Request-Id` with `trust_request_id`. It's in the access log as
+    /// `request_id` and sent back as `X-Request-Id` unless the response
+    /// already has one.
+    #[pyo3(get)]
+    pub(crate) id: String,
+
+    /// The `loop.time()` when `handler_timeout` runs out for this request,
+    /// it's exactly when the handler is cancelled so `deadline - loop.time()`
+    /// is how long it has left. `None` without a `handler_timeout`.
+    #[pyo3(get)]
+    pub(crate) deadline: Option<f64>,
+
+    pub(crate) received: Instant,       // When the head was in, see `elapsed()`
+    pub(crate) timings: Arc<RequestTimings>,    // When each stage of the request happened, see `timings`
+
+    pub(crate) body: RequestBody,
+    pub(crate) spooled: Option<Spool>,  // A body past `spool_threshold`, `body` is empty with one
+    body_py: Option<Py<PyBytes>>,       // What `body` gives, made the first time it's looked at
+    body_file: Option<PyObject>,        // What `body_file()` gives, made the first time it's called
+    pub(crate) stream: Option<Py<RequestStream>>,   // What `stream()` gives, set from the start when the body is streamed
+    body_read: Cell<bool>,              // Set once the body's been read other than with `stream()`
+    active: Arc<AtomicBool>,            // Cleared once the connection is done with the request, see RequestLease
+
+    pub(crate) path_params: Vec<(String, String)>,  // The `{name}` segments a Router matched
+
+    cookies: Option<Vec<(String, String)>>,     // Parsed the first time `cookies` is looked at
+    form: Option<Vec<(String, Vec<String>)>>,   // Parsed the first time `form()` is called
+    headers_py: Option<Py<Headers>>,            // The python copy of `headers`, made the first time they're looked at
+}
+
+///
+/// RequestLease is a connection's hold on the request it's handling, the
+/// request's body can be read for as long as it's held. The connection
+/// lets go of it once it moves on to its next request (or a stream's
+/// exchange is over, or it closes), from then on reading the body raises a
+/// RuntimeError rather than giving a handler that held on to the request
+/// something that looks like it belongs to a request that's still going.
+///
+#[derive(Debug)]
+pub(crate) struct RequestLease(Arc<AtomicBool>);
+
+impl Drop for RequestLease {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+///
+/// RequestBody is the body an HTTPRequest owns, either a copy or for a
+/// large upload the connection's whole read buffer (head and all) handed
+/// over so the body is never copied, the connection reads the next request
+/// into a new buffer.
+///
+#[derive(Debug)]
+pub(crate) struct RequestBody {
+    buffer: Vec<u8>,
+    start: usize,   // Where the body starts in `buffer`
+}
+
+impl RequestBody {
+    /// The body that is everything from `start` on in `buffer`.
+    pub(crate) fn within(buffer: Vec<u8>, start: usize) -> Self {
+        Self { buffer, start }
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.buffer[self.start..]
+    }
+}
+
+impl From<Vec<u8>> for RequestBody {
+    fn from(buffer: Vec<u8>) -> Self {
+        Self { buffer, start: 0 }
+    }
+}
+
+impl HTTPRequest {
+    pub(crate) fn new(
+        method: String,
+        mut target: Vec<u8>,
+        protocol: String,
+        headers: Headers,
+        body: RequestBody,
+    ) -> Self {
+        let raw_query = match target.find_byte(b'?') {
+            Some(start) => {
+                let query = request_target(&target[start + 1..]);
+                target.truncate(start);
+                query
+            },
+            None => String::new(),
+        };
+
+        let path_bytes = percent_decode_bytes(&request_target(&target));
+
+        Self {
+            method,
+            query: parse_query(&raw_query),
+            path: String::from_utf8_lossy(&path_bytes).into_owned(),
+            path_bytes,
+            raw_path: target,
+            raw_query,
+            protocol,
+            headers,
+            trailers: Headers::new(),
+            client: None,
+            remote_addr: None,
+            connection: None,
+            tls: None,
+            server: None,
+            scheme: String::from("http"),
+            target: TargetForm::Origin,
+            id: String::new(),
+            deadline: None,
+            received: Instant::now(),
+            timings: Arc::new(RequestTimings::new(Instant::now())),
+            body,
+            spooled: None,
+            body_py: None,
+            body_file: None,
+            stream: None,
+            body_read: Cell::new(false),
+            active: Arc::new(AtomicBool::new(true)),
+            path_params: Vec::new(),
+            cookies: None,
+            form: None,
+            headers_py: None,
+        }
+    }
+
+    /// The lease the connection holds for as long as the request is its current one.
+    pub(crate) fn lease(&self) -> RequestLease {
+        RequestLease(self.active.clone())
+    }
+
+    /// A RuntimeError once the connection has let go of the request.
+    fn check_active(&self) -> PyResult<()> {
+        match self.active.load(Ordering::Acquire) {
+            true => Ok(()),
+            false => Err(PyRuntimeError::new_err("request is no longer active")),
+        }
+    }
+
+    ///
+    /// The body for python, a ValueError when it was spooled so it's never
+    /// read into memory by accident, or when it's streamed (or has been
+    /// read with `stream()`) since it's not ours to give.
+    ///
+    fn active_body(&self) -> PyResult<&[u8]> {
+        self.check_active()?;
+        if self.spooled.is_some() {
+            return Err(PyValueError::new_err("the body was spooled to disk, read it with body_file()"))
+        }
+        if self.stream.is_some() {
+            return Err(PyValueError::new_err("the body is streamed, read it with stream()"))
+        }
+
+        self.body_read.set(true);
+        Ok(self.body.as_slice())
+    }
+
+    /// Hands the body over to be read with `stream()` as the connection reads it, see Inbound.
+    pub(crate) fn stream_from(&mut self, py: Python, inbound: &mut Inbound) -> PyResult<()> {
+        self.stream = Some(inbound.stream(py, self.active.clone())?);
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: HTTPRequest::normalize() -> Result<(), ()>
+    ///
+    ///     Resolves `.` and `..` in the path (and merges `//` if asked to),
+    ///     `Err` if a `..` would climb above the root which is a `400`.
+    ///
+    pub(crate) fn normalize(&mut self, merge_slashes: bool) -> Result<(), ()> {
+        let normalized = normalize_path(&request_target(&self.raw_path), merge_slashes)?;
+        self.path_bytes = percent_decode_bytes(&normalized);
+        self.path = String::from_utf8_lossy(&self.path_bytes).into_owned();
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl HTTPRequest {
+    ///
+    /// The request method, `"GET"`, `"POST"`... exactly as it was sent,
+    /// methods are case-sensitive. The standard ones are interned.
+    ///
+    #[getter]
+    fn method(&self, py: Python) -> PyObject {
+        interned_method(py, &self.method).unwrap_or_else(|| self.method.to_object(py))
+    }
+
+    ///
+    /// The path exactly as it arrived (without the query string) as bytes,
+    /// nothing is decoded or normalized. This is the only way to tell an
+    /// encoded `%2F` apart from a real `/`, or to check a signature over
+    /// the path.
+    ///
+    #[getter]
+    fn raw_path(&self, py: Python) -> PyObject {
+        PyBytes::new(py, &self.raw_path).into()
+    }
+
+    ///
+    /// The form the request target was sent in, `"origin"` for a path,
+    /// `"asterisk"` for `OPTIONS *` and with `proxy_mode` `"absolute"` for
+    /// `GET http://example.com/` or `"authority"` for `CONNECT host:port`.
+    ///
+    #[getter]
+    fn target_form(&self) -> &'static str {
+        self.target.name()
+    }
+
+    /// The scheme of an absolute-form target, `None` for any other form.
+    #[getter]
+    fn target_scheme(&self) -> Option<&'static str> {
+        match self.target {
+            TargetForm::Absolute { scheme, .. } => Some(scheme),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The host an absolute-form or authority-form target is for, lowercased
+    /// and without an IPv6 address's brackets. `None` for a path.
+    ///
+    #[getter]
+    fn target_host(&self) -> Option<&str> {
+        match &self.target {
+            TargetForm::Absolute { host, .. } | TargetForm::Authority { host, .. } => Some(host),
+            _ => None,
+        }
+    }
+
+    /// The port that host is asked for on, the scheme's own when an absolute-form target leaves it out.
+    #[getter]
+    fn target_port(&self) -> Option<u16> {
+        match self.target {
+            TargetForm::Absolute { port, .. } | TargetForm::Authority { port, .. } => Some(port),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The request body, this is read in full before the callback is invoked
+    /// so it's always complete (and empty without a `Content-Length`).
+    /// It's copied into bytes the first time it's looked at and every
+    /// access after gives that same object, `body_view()` doesn't copy.
+    ///
+    /// Once the connection has moved on to its next request (or closed)
+    /// this raises a RuntimeError, as do `body_view()`, `form()`,
+    /// `multipart()` and `body_file()`. A body bigger than `spool_threshold`
+    /// was spooled to disk, all but `body_file()` raise a ValueError for
+    /// one. They all raise a ValueError for a body that's read with
+    /// `stream()` too, see there.
+    ///
+    #[getter]
+    fn body(&mut self, py: Python) -> PyResult<Py<PyBytes>> {
+        self.active_body()?;
+
+        let body = &self.body;
+        let bytes = self.body_py.get_or_insert_with(|| PyBytes::new(py, body.as_slice()).into());
+        Ok(bytes.clone_ref(py))
+    }
+
+    /// If the body was spooled to disk, it can only be read with `body_file()`.
+    #[getter]
+    fn spooled(&self) -> bool {
+        self.spooled.is_some()
+    }
+
+    ///
+    /// PythonMethod: HTTPRequest.body_file() -> file
+    ///
+    ///     The body as a binary file to read, straight over the temp file
+    ///     for one that was spooled and an `io.BytesIO` of it otherwise, so
+    ///     a handler that takes large uploads can read every body the same
+    ///     way. Every call gives the same file, it carries on from wherever
+    ///     the last read left it.
+    ///
+    ///     A spooled body's file goes once the request and this file
+    ///     have both been let go of, there's never anything to clean up.
+    ///
+    fn body_file(&mut self, py: Python) -> PyResult<PyObject> {
+        self.check_active()?;
+        if let Some(file) = self.body_file.as_ref() {
+            return Ok(file.clone_ref(py))
+        }
+
+        let file = match self.spooled.as_ref() {
+            Some(spool) => spool.to_python(py)?,
+            None => py.import("io")?.call_method1("BytesIO", (PyBytes::new(py, self.active_body()?),))?.into(),
+        };
+
+        self.body_file = Some(file.clone_ref(py));
+        Ok(file)
+    }
+
+    ///
+    /// PythonMethod: HTTPRequest.stream() -> RequestStream
+    ///
+    ///     The body as an async iterator of bytes, `async for chunk in
+    ///     request.stream()`. With `stream_request_body=True` the body is
+    ///     still in the socket when the callback is invoked and each chunk
+    ///     is read as it's asked for, so however big it is it never has to
+    ///     fit in memory and a handler that's slow with it slows the client
+    ///     down too. Otherwise it's the body already read, in one chunk.
+    ///
+    ///     A streamed body is an HTTP/1 one, HTTP/2 requests are always
+    ///     read in full first. Every call gives the same iterator, and it
+    ///     can't be mixed with `body` (or anything else that reads it): once
+    ///     one has been used the other raises a ValueError.
+    ///
+    fn stream(&mut self, py: Python) -> PyResult<Py<RequestStream>> {
+        self.check_active()?;
+        if self.spooled.is_some() {
+            return Err(PyValueError::new_err("the body was spooled to disk, read it with body_file()"))
+        }
+        if self.body_read.get() {
+            return Err(PyValueError::new_err("the body has already been read, it can't be streamed as well"))
+        }
+
+        if let Some(stream) = self.stream.as_ref() {
+            return Ok(stream.clone_ref(py))
+        }
+
+        let stream = Py::new(py, RequestStream::buffered(self.body.as_slice(), self.active.clone()))?;
+        self.stream = Some(stream.clone_ref(py));
+        Ok(stream)
+    }
+
+    ///
+    /// PythonMethod: HTTPRequest.body_view() -> memoryview
+    ///
+    ///     The body without copying it, a read only memoryview straight
+    ///     over the buffer it was read into. The view keeps the request
+    ///     (and with it the buffer) alive, nothing else ever writes to it so
+    ///     it's valid for as long as it's held, even after the connection
+    ///     has moved on to its next request. Making one after that raises a
+    ///     RuntimeError.
+    ///
+    fn body_view(slf: PyRef<Self>, py: Python) -> PyResult<PyObject> {
+        slf.active_body()?;
+
+
+        // the view borrows the request itself (see the buffer protocol below)
+        // which it can't while we still have it borrowed
+        let request: Py<Self> = slf.into();
+
+        // SAFETY: the pointer is a live object and the result is a new reference or NULL
+        unsafe { PyObject::from_owned_ptr_or_err(py, ffi::PyMemoryView_FromObject(request.as_ptr())) }
+    }
+
+    ///
+    /// The decoded query string as a dict of lists, `?a=1&a=2&flag` gives
+    /// `{"a": ["1", "2"], "flag": [""]}`.
+    ///
+    #[getter]
+    fn query(&self, py: Python) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        for (key, values) in self.query.iter() {
+            dict.set_item(key, values.clone())?;
+        }
+
+        Ok(dict.into())
+    }
+
+    ///
+    /// The `{name}` segments of the route a `Router` matched as a dict of
+    /// str, empty when the request didn't go through one.
+    ///
+    #[getter]
+    fn path_params(&self, py: Python) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        for (name, value) in self.path_params.iter() {
+            dict.set_item(name, value)?;
+        }
+
+        Ok(dict.into())
+    }
+
+    ///
+    /// The request headers (see `Headers`), the same object every time.
+    /// Handlers that never look at them don't pay for them being made.
+    ///
+    #[getter]
+    fn headers(&mut self, py: Python) -> PyResult<Py<Headers>> {
+        if self.headers_py.is_none() {
+            self.headers_py = Some(Py::new(py, self.headers.clone())?);
+        }
+
+        Ok(self.headers_py.as_ref().unwrap().clone_ref(py))
+    }
+
+    ///
+    /// The cookies from the `Cookie` header as a dict, parsed the first time
+    /// this is used. If a name is sent twice the first one wins.
+    ///
+    #[getter]
+    fn cookies(&mut self, py: Python) -> PyResult<PyObject> {
+        if self.cookies.is_none() {
+            let mut cookies = Vec::new();
+            for header in self.headers.get_all(HeaderName::Cookie) {
+                cookie::parse_cookies(header, &mut cookies);
+            }
+
+            self.cookies = Some(cookies);
+        }
+
+        let dict = PyDict::new(py);
+        for (name, value) in self.cookies.iter().flatten() {
+            dict.set_item(name, value)?;
+        }
+
+        Ok(dict.into())
+    }
+
+    ///
+    /// PythonMethod: HTTPRequest.elapsed() -> float
+    ///
+    ///     Seconds since the request's head arrived, so the time it took
+    ///     the body to come in counts too.
+    ///
+    fn elapsed(&self) -> f64 {
+        self.received.elapsed().as_secs_f64()
+    }
+
+    ///
+    /// When each stage of the request happened as a dict of seconds since
+    /// the connection was accepted: `accept` (always `0.0`), `headers`,
+    /// `body`, `handler_start`, `handler_end` and `flushed`. A stage that
+    /// hasn't happened (yet, or at all) is `None`, so while the handler's
+    /// running `handler_end` and `flushed` are. The access log's `duration`
+    /// and `handler_duration` come from the same numbers.
+    ///
+    #[getter]
+    fn timings<'p>(&self, py: Python<'p>) -> PyResult<&'p PyDict> {
+        self.timings.to_dict(py)
+    }
+
+    ///
+    /// PythonMethod: HTTPRequest.form() -> dict
+    ///
+    ///     The body of an `application/x-www-form-urlencoded` post decoded
+    ///     into a dict of str, a key sent more than once gets a list of its
+    ///     values in the order they were sent. Any other Content-Type, or a
+    ///     body with more than 1000 fields, raises a ValueError.
+    ///
+    ///     The body is decoded the first time this is called, after that
+    ///     it's just a new dict each time.
+    ///
+    fn form(&mut self, py: Python) -> PyResult<PyObject> {
+        self.active_body()?;
+
+        if self.form.is_none() {
+            let urlencoded = self.headers
+                .get(HeaderName::ContentType)
+                .map(|value| value.split(';').next().unwrap_or("").trim())
+                .is_some_and(|mime| mime.eq_ignore_ascii_case("application/x-www-form-urlencoded"));
+
+            if !urlencoded {
+                return Err(PyValueError::new_err("the body isn't application/x-www-form-urlencoded"))
+            }
+
+            let body = String::from_utf8_lossy(self.body.as_slice());
+            let form = parse_form(&body, MAX_FORM_FIELDS)
+                .ok_or_else(|| PyValueError::new_err(format!("the form has more than {} fields", MAX_FORM_FIELDS)))?;
+            self.form = Some(form);
+        }
+
+        let dict = PyDict::new(py);
+        for (key, values) in self.form.iter().flatten() {
+            match values.as_slice() {
+                [value] => dict.set_item(key, value)?,
+                values => dict.set_item(key, values.to_vec())?,
+            }
+        }
+
+        Ok(dict.into())
+    }
+
+    ///
+    /// PythonMethod: HTTPRequest.multipart(spool_size=1MB, spool_dir=None) -> asyncio.Future
+    ///
+    ///     Decodes a `multipart/form-data` body into a list of
+    ///     MultipartPart, awaiting it gives the parts in the order they
+    ///     were sent. Any part bigger than `spool_size` bytes is written to
+    ///     a temp file in `spool_dir` (the system's temp directory by
+    ///     default), the decoding is done on the loop's executor so that
+    ///     doesn't hold up the loop.
+    ///
+    ///     Any other Content-Type raises a ValueError, a body that isn't
+    ///     valid multipart (a bad boundary, no closing boundary, a part
+    ///     without a proper `Content-Disposition`...) raises a BadRequest
+    ///     which answers with a `400` if the handler doesn't catch it.
+    ///
+    #[args(spool_size = "1024 * 1024", spool_dir = "None")]
+    fn multipart(&self, py: Python, spool_size: usize, spool_dir: Option<String>) -> PyResult<PyObject> {
+        let call = MultipartCall::new(
+            self.headers.get(HeaderName::ContentType),
+            self.active_body()?.to_vec(),
+            spool_size,
+            spool_dir.map(Into::into),
+        )?;
+
+        multipart::parts(py, call)
+    }
+}
+
+
+///
+/// Exports the body, `memoryview(request)` is the same as `body_view()`.
+///
+#[pyproto]
+impl PyBufferProtocol for HTTPRequest {
+    fn bf_getbuffer(slf: PyRefMut<Self>, view: *mut ffi::Py_buffer, flags: c_int) -> PyResult<()> {
+        let body = slf.active_body()?;
+
+        // SAFETY: python gives us a view to fill in, FillInfo takes its own
+        // reference to the request so the body outlives the view. It raises
+        // a BufferError if asked for a writable buffer.
+        let res = unsafe {
+            ffi::PyBuffer_FillInfo(
+                view,
+                slf.as_ptr(),
+                body.as_ptr() as *mut c_void,
+                body.len() as ffi::Py_ssize_t,
+                1,
+                flags,
+            )
+        };
+
+        match res {
+            0 => Ok(()),
+            _ => Err(PyErr::fetch(slf.py())),
+        }
+    }
+
+    fn bf_releasebuffer(_slf: PyRefMut<Self>, _view: *mut ffi::Py_buffer) -> PyResult<()> {
+        Ok(())
+    }
+}
+
+///
+/// HTTPResponse is what the callback hands back to us, for quick scripts
+/// it can return `bytes`, a `str`, `(status, body)` or `(status, headers,
+/// body)` instead (see `HTTPResponse::from_return()`).
+///
+///     Optional:
+///         - body:     bytes or str    (str is encoded as utf-8)
+///         - status:   int             (defaults to 200)
+///         - headers:  dict            (written in the order given)
+///
+///     One without a `Content-Type` is sent with `default_content_type`,
+///     `text()`, `json()` and `bytes()` make one with its type already set.
+///
+///     The head always comes out in the same order, the headers we add
+///     (Date, Server, X-Request-Id...) that `headers` doesn't set itself,
+///     then Content-Length, then `headers`.
+///
+#[pyclass]
+#[derive(Debug)]
+pub struct HTTPResponse {
+    #[pyo3(get, set)]
+    pub(crate) status: u16,
+
+    headers: Vec<(String, String)>,
+    body: Bytes,        // Shared with the connection writing it out, so handing it over is free
+    reason: Option<&'static str>,   // A reason phrase of our own rather than the status's standard one
+}
+
+#[pymethods]
+impl HTTPResponse {
+    #[new]
+    #[args(body = "None", status = "200", headers = "None")]
+    fn new(body: Option<&PyAny>, status: u16, headers: Option<&PyDict>) -> PyResult<Self> {
+        let body = match body {
+            Some(body) => body_to_bytes(body)?,
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            status,
+            headers: header_pairs(headers)?,
+            body: body.into(),
+            reason: None,
+        })
+    }
+
+    ///
+    /// PythonMethod: HTTPResponse.text(text, status=200, headers=None) -> HTTPResponse
+    ///
+    ///     A `text/plain; charset=utf-8` response of the str encoded as utf-8.
+    ///
+    #[classmethod]
+    #[args(text, status = "200", headers = "None")]
+    fn text(_cls: &PyType, text: &str, status: u16, headers: Option<&PyDict>) -> PyResult<Self> {
+        Self::typed(text.as_bytes().to_vec(), "text/plain; charset=utf-8", status, headers)
+    }
+
+    ///
+    /// PythonMethod: HTTPResponse.json(obj, status=200, headers=None) -> HTTPResponse
+    ///
+    ///     An `application/json` response of `obj` serialized with `json.dumps()`,
+    ///     it's serialized right away so anything that can't be raises here in
+    ///     the handler rather than once the response is being written.
+    ///
+    #[classmethod]
+    #[args(obj, status = "200", headers = "None")]
+    fn json(_cls: &PyType, py: Python, obj: &PyAny, status: u16, headers: Option<&PyDict>) -> PyResult<Self> {
+        let serialized: String = py.import("json")?.call1("dumps", (obj,))?.extract()?;
+        Self::typed(serialized.into_bytes(), "application/json", status, headers)
+    }
+
+    ///
+    /// PythonMethod: HTTPResponse.bytes(body, content_type="application/octet-stream", status=200, headers=None) -> HTTPResponse
+    ///
+    ///     A response of the bytes with the `Content-Type` given.
+    ///
+    #[classmethod]
+    #[args(body, content_type = "\"application/octet-stream\"", status = "200", headers = "None")]
+    fn bytes(_cls: &PyType, body: &PyBytes, content_type: &str, status: u16, headers: Option<&PyDict>) -> PyResult<Self> {
+        Self::typed(body.as_bytes().to_vec(), content_type, status, headers)
+    }
+
+    #[getter]
+    fn body(&self, py: Python) -> PyObject {
+        PyBytes::new(py, &self.body).into()
+    }
+
+    #[setter]
+    fn set_body(&mut self, body: &PyAny) -> PyResult<()> {
+        self.body = body_to_bytes(body)?.into();
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: HTTPResponse.set_cookie(name, value, *, ...)
+    ///
+    ///     Adds a `Set-Cookie` header, each call adds another one. Values
+    ///     are percent-encoded where needed and a name with characters a
+    ///     cookie name can't have is a `ValueError`.
+    ///
+    ///     Requires:
+    ///         - name:     str
+    ///         - value:    str
+    ///
+    ///     Optional:
+    ///         - max_age:  int
+    ///         - expires:  datetime, int / float (unix timestamp) or str
+    ///         - path:     str             (defaults to "/")
+    ///         - domain:   str
+    ///         - secure:   bool
+    ///         - httponly: bool
+    ///         - samesite: str             ("Strict", "Lax" or "None")
+    ///
+    #[args(
+        name,
+        value,
+        "*",
+        max_age = "None",
+        expires = "None",
+        path = "\"/\"",
+        domain = "None",
+        secure = "false",
+        httponly = "false",
+        samesite = "None",
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn set_cookie(
+        &mut self,
+        name: &str,
+        value: &str,
+        max_age: Option<i64>,
+        expires: Option<&PyAny>,
+        path: Option<&str>,
+        domain: Option<&str>,
+        secure: bool,
+        httponly: bool,
+        samesite: Option<&str>,
+    ) -> PyResult<()> {
+        let cookie = SetCookie {
+            name,
+            value,
+            max_age,
+            expires,
+            path,
+            domain,
+            secure,
+            httponly,
+            samesite,
+        };
+
+        self.headers.push((String::from("Set-Cookie"), cookie.to_header()?));
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: HTTPResponse.set_header(name, value)
+    ///
+    ///     Sets a header, replacing any already set with the same name
+    ///     (ignoring case). `headers` is a copy so this is how a response is
+    ///     changed after it's made, e.g. by middleware. A name or value with
+    ///     a line break in it is a ValueError.
+    ///
+    fn set_header(&mut self, name: String, value: String) -> PyResult<()> {
+        let header = checked_header(name, value)?;
+        self.remove_header(&header.0);
+        self.headers.push(header);
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: HTTPResponse.add_header(name, value)
+    ///
+    ///     Adds another header, leaving any with the same name as they are.
+    ///     A name or value with a line break in it is a ValueError.
+    ///
+    fn add_header(&mut self, name: String, value: String) -> PyResult<()> {
+        self.headers.push(checked_header(name, value)?);
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: HTTPResponse.remove_header(name) -> bool
+    ///
+    ///     Removes every header with the name (ignoring case), if there
+    ///     were any.
+    ///
+    fn remove_header(&mut self, name: &str) -> bool {
+        let before = self.headers.len();
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.len() != before
+    }
+
+    #[getter]
+    fn headers(&self, py: Python) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        for (name, value) in self.headers.iter() {
+            dict.set_item(name, value)?;
+        }
+
+        Ok(dict.into())
+    }
+}
+
+impl Default for HTTPResponse {
+    fn default() -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body: Bytes::new(),
+            reason: None,
+        }
+    }
+}
+
+impl HTTPResponse {
+    /// A response with `content_type` unless `headers` has a `Content-Type` of its own.
+    fn typed(body: Vec<u8>, content_type: &str, status: u16, headers: Option<&PyDict>) -> PyResult<Self> {
+        let mut headers = header_pairs(headers)?;
+        if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-type")) {
+            headers.insert(0, (String::from("Content-Type"), content_type.to_string()));
+        }
+
+        Ok(Self::from_parts(status, headers, body))
+    }
+
+    ///
+    /// Internal Method: HTTPResponse::from_return() -> PyResult<HTTPResponse>
+    ///
+    ///     What the callback returned when it isn't one of our response
+    ///     types, `bytes` or a `str` (encoded as utf-8) is a `200` of it,
+    ///     `(status, body)` and `(status, headers, body)` take the headers
+    ///     as a dict or a list of `(name, value)` pairs. Anything else,
+    ///     `None` included, is a TypeError and so is a header value that
+    ///     isn't str or bytes (which have to be utf-8), a body for a status
+    ///     that can't have one is a ValueError.
+    ///
+    pub(crate) fn from_return(value: &PyAny) -> PyResult<Self> {
+        if value.downcast::<PyBytes>().is_ok() || value.downcast::<PyString>().is_ok() {
+            return Ok(Self::from_parts(200, Vec::new(), body_to_bytes(value)?))
+        }
+
+        let tuple = match value.downcast::<PyTuple>() {
+            Ok(tuple) if tuple.len() == 2 || tuple.len() == 3 => tuple,
+            _ => return Err(PyTypeError::new_err(format!(
+                "the handler returned {}, expected an HTTPResponse, bytes, str, (status, body) or (status, headers, body)",
+                describe(value),
+            ))),
+        };
+
+        let status = tuple.get_item(0);
+        let status = match status.downcast::<PyBool>() {
+            Ok(_) => return Err(PyTypeError::new_err("the returned status must be an int, not bool")),
+            Err(_) => status.extract::<u16>().map_err(|_| PyTypeError::new_err(format!(
+                "the returned status must be an int from 100 to 599, not {}", describe(status),
+            )))?,
+        };
+        if !(100..=599).contains(&status) {
+            return Err(PyValueError::new_err(format!("the returned status must be from 100 to 599, not {}", status)))
+        }
+
+        let headers = match tuple.len() {
+            3 => returned_headers(tuple.get_item(1))?,
+            _ => Vec::new(),
+        };
+
+        let body = tuple.get_item(tuple.len() - 1);
+        let body = body_to_bytes(body).map_err(|_| PyTypeError::new_err(format!(
+            "the returned body must be bytes or str, not {}", describe(body),
+        )))?;
+        if !body.is_empty() && (status < 200 || status == 204 || status == 304) {
+            return Err(PyValueError::new_err(format!("a {} response can't have a body", status)))
+        }
+
+        Ok(Self::from_parts(status, headers, body))
+    }
+
+    pub(crate) fn from_parts(status: u16, headers: Vec<(String, String)>, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers,
+            body: body.into(),
+            reason: None,
+        }
+    }
+
+    /// The first value the handler set for a header, ignoring case.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(set, _)| set.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    ///
+    /// Internal Method: HTTPResponse::check_length() -> PyResult<Option<Self>>
+    ///
+    ///     Checks a `Content-Length` the handler set against the body, a
+    ///     wrong one would break the framing of everything after it on the
+    ///     connection. With `strict` a mismatch is a ValueError, otherwise a
+    ///     copy without it (so the right one is added) comes back. `Ok(None)`
+    ///     when there's nothing to correct.
+    ///
+    pub(crate) fn check_length(&self, strict: bool) -> PyResult<Option<Self>> {
+        if self.status < 200 || self.status == 204 || self.status == 304 {
+            return Ok(None)
+        }
+
+        let body_len = self.body.len() as u64;
+        let declared = self.headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map(|(_, value)| value)
+            .find(|value| parse_length(value.trim_matches(|c| c == ' ' || c == '\t')) != Some(body_len));
+
+        let declared = match declared {
+            Some(declared) => declared,
+            None => return Ok(None),
+        };
+
+        if strict {
+            return Err(PyValueError::new_err(format!(
+                "the response's Content-Length is {:?} but its body is {} bytes",
+                declared, body_len,
+            )))
+        }
+
+        Ok(Some(Self {
+            status: self.status,
+            headers: self.headers
+                .iter()
+                .filter(|(name, _)| !name.eq_ignore_ascii_case("content-length"))
+                .cloned()
+                .collect(),
+            body: self.body.clone(),
+            reason: self.reason,
+        }))
+    }
+
+    /// A request we refused saying why, see `HeadError`.
+    pub(crate) fn refused(status: u16, reason: &str) -> Self {
+        let headers = vec![(String::from("Content-Type"), String::from("text/plain; charset=utf-8"))];
+        Self::from_parts(status, headers, reason.as_bytes().to_vec())
+    }
+
+    pub(crate) fn with_status(status: u16) -> Self {
+        Self {
+            status,
+            ..Self::default()
+        }
+    }
+
+    /// The `200 Connection Established` an accepted `CONNECT` is answered with.
+    pub(crate) fn established() -> Self {
+        Self {
+            reason: Some("Connection Established"),
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn body_len(&self) -> usize {
+        self.body.len()
+    }
+
+    ///
+    /// A copy of the response with `Content-Encoding` set and
+    /// `Accept-Encoding` added to `Vary`, the body is compressed with
+    /// `with_body` or left empty for one that's streamed after the head.
+    ///
+    pub(crate) fn encoded(&self, encoding: Encoding, with_body: bool) -> Self {
+        let mut headers = self.headers.clone();
+        headers.push((String::from("Content-Encoding"), String::from(encoding.name())));
+
+        match headers.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case("vary")) {
+            Some((_, vary)) if vary.split(',').any(|value| {
+                let value = value.trim();
+                value == "*" || value.eq_ignore_ascii_case("accept-encoding")
+            }) => {},
+            Some((_, vary)) => vary.push_str(", Accept-Encoding"),
+            None => headers.push((String::from("Vary"), String::from("Accept-Encoding"))),
+        }
+
+        let body = match with_body {
+            true => Encoder::compress(encoding, &self.body).into(),
+            false => Bytes::new(),
+        };
+
+        Self {
+            status: self.status,
+            headers,
+            body,
+            reason: self.reason,
+        }
+    }
+
+    ///
+    /// Internal Method: HTTPResponse::serialize()
+    ///
+    ///     Queues the bytes that go on the wire onto `out`, a `Content-Length`
+    ///     is added unless the handler set its own (or the status can't have
+    ///     a body). The body isn't copied, `out` shares it.
+    ///
+    ///     `defaults` are headers (Date, Server, Connection) added only if
+    ///     the handler didn't set its own.
+    ///
+    pub(crate) fn serialize(&self, defaults: &[(&str, &str)], out: &mut Outgoing) {
+        self.serialize_head(defaults, out.buffer());
+        out.push_body(self.body.clone());
+    }
+
+    ///
+    /// Just the body, which is all an HTTP/0.9 response is.
+    ///
+    pub(crate) fn serialize_body(&self, out: &mut Outgoing) {
+        out.push_body(self.body.clone());
+    }
+
+    ///
+    /// Just the status line and headers, the `Content-Length` is still the
+    /// one the body would have had. Used to answer `HEAD` requests.
+    ///
+    pub(crate) fn serialize_head(&self, defaults: &[(&str, &str)], out: &mut Vec<u8>) {
+        out.reserve(128);
+        self.write_head(defaults, Some(self.body.len()), out);
+    }
+
+    ///
+    /// The head for a body streamed after it (see `body::BodyStream`), with
+    /// `chunked` it's framed by `Transfer-Encoding: chunked` otherwise
+    /// there's no length at all and the body ends when the connection does.
+    ///
+    pub(crate) fn serialize_stream_head(&self, defaults: &[(&str, &str)], chunked: bool, out: &mut Vec<u8>) {
+        match chunked {
+            true => {
+                let mut defaults = defaults.to_vec();
+                defaults.push(("Transfer-Encoding", "chunked"));
+                self.write_head(&defaults, None, out);
+            },
+            false => self.write_head(defaults, None, out),
+        }
+    }
+
+    ///
+    /// The headers for an HTTP/2 response (see `h2::H2Connection::respond()`),
+    /// the same ones in the same order `write_head()` would write but
+    /// lowercased and without the HTTP/1 connection headers HTTP/2 doesn't
+    /// have.
+    ///
+    pub(crate) fn h2_head(&self, defaults: &[(&str, &str)], length: Option<usize>) -> Vec<(String, String)> {
+        let mut fields = Vec::with_capacity(self.headers.len() + defaults.len() + 1);
+        for (name, value) in defaults {
+            if !self.has_header(name) {
+                fields.push((name.to_ascii_lowercase(), value.to_string()));
+            }
+        }
+
+        let has_length = self.status < 200 || self.status == 204 || self.status == 304 || self.has_header("content-length");
+        if let (false, Some(length)) = (has_length, length) {
+            fields.push((String::from("content-length"), length.to_string()));
+        }
+
+        for (name, value) in self.headers.iter() {
+            let name = name.to_ascii_lowercase();
+            if ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"].contains(&name.as_str()) {
+                continue
+            }
+
+            fields.push((name, value.clone()));
+        }
+
+        fields
+    }
+
+    /// The body, shared rather than copied.
+    pub(crate) fn body_bytes(&self) -> Bytes {
+        self.body.clone()
+    }
+
+    ///
+    /// Internal Method: HTTPResponse::write_head()
+    ///
+    ///     Writes the status line and headers, always in the same order:
+    ///     the `defaults` the response hasn't set itself in the order
+    ///     they're given, then `Content-Length`, then the response's own
+    ///     headers in the order they were set. Everything is copied in
+    ///     as it is, the status line for any status with a reason phrase
+    ///     is made once (see `status_line()`) so nothing is formatted bar
+    ///     the length.
+    ///
+    fn write_head(&self, defaults: &[(&str, &str)], length: Option<usize>, out: &mut Vec<u8>) {
+        match (self.reason, status_line(self.status)) {
+            (Some(reason), _) => { let _ = write!(out, "HTTP/1.1 {} {}\r\n", self.status, reason); },
+            (None, Some(line)) => out.extend_from_slice(line),
+            (None, None) => { let _ = write!(out, "HTTP/1.1 {} \r\n", self.status); },
+        }
+
+        for (name, value) in defaults {
+            if !self.has_header(name) {
+                push_header(out, name, value);
+            }
+        }
+
+        let has_length = self.status < 200 || self.status == 204 || self.status == 304 || self.has_header("content-length");
+        if let (false, Some(length)) = (has_length, length) {
+            out.extend_from_slice(b"Content-Length: ");
+            push_decimal(out, length);
+            out.extend_from_slice(b"\r\n");
+        }
+
+        for (name, value) in self.headers.iter() {
+            push_header(out, name, value);
+        }
+
+        out.extend_from_slice(b"\r\n");
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|(set, _)| set.eq_ignore_ascii_case(name))
+    }
+}
+
+/// The headers a response was made with, in the order they're in the dict.
+fn header_pairs(headers: Option<&PyDict>) -> PyResult<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    if let Some(headers) = headers {
+        for (name, value) in headers.iter() {
+            pairs.push(checked_header(name.extract()?, value.extract()?)?);
+        }
+    }
+
+    Ok(pairs)
+}
+
+/// The headers of a returned `(status, headers, body)`, see `HTTPResponse::from_return()`.
+fn returned_headers(headers: &PyAny) -> PyResult<Vec<(String, String)>> {
+    let pairs: Vec<(&PyAny, &PyAny)> = match headers.downcast::<PyDict>() {
+        Ok(dict) => dict.iter().collect(),
+        Err(_) => headers.extract().map_err(|_| PyTypeError::new_err(format!(
+            "the returned headers must be a dict or a list of (name, value) pairs, not {}", describe(headers),
+        )))?,
+    };
+
+    pairs
+        .into_iter()
+        .map(|(name, value)| {
+            let name: String = name.extract().map_err(|_| PyTypeError::new_err(format!(
+                "a returned header name must be str, not {}", describe(name),
+            )))?;
+
+            let value = match value.downcast::<PyBytes>() {
+                Ok(bytes) => String::from_utf8(bytes.as_bytes().to_vec()).map_err(|_| PyValueError::new_err(format!(
+                    "the returned header '{}' isn't valid utf-8", name,
+                )))?,
+                Err(_) => value.extract().map_err(|_| PyTypeError::new_err(format!(
+                    "the returned header '{}' must be str or bytes, not {}", name, describe(value),
+                )))?,
+            };
+
+            checked_header(name, value)
+        })
+        .collect()
+}
+
+///
+/// A header from python as it is, or a ValueError if a CR, LF or NUL in it
+/// would end the line early and let the rest of it write a header (or a
+/// response) of its own.
+///
+fn checked_header(name: String, value: String) -> PyResult<(String, String)> {
+    if name.contains(['\r', '\n', '\0']) || value.contains(['\r', '\n', '\0']) {
+        return Err(PyValueError::new_err(format!("the header '{}' can't contain line breaks", name.escape_debug())))
+    }
+
+    Ok((name, value))
+}
+
+/// If `value` is bytes, a str or a tuple, what `HTTPResponse::from_return()` makes a response of.
+pub(crate) fn is_shorthand(value: &PyAny) -> bool {
+    value.downcast::<PyBytes>().is_ok() || value.downcast::<PyString>().is_ok() || value.downcast::<PyTuple>().is_ok()
+}
+
+/// The python type of `value` for an error message, e.g. `NoneType`.
+fn describe(value: &PyAny) -> String {
+    value.get_type().name().into_owned()
+}
+
+pub(crate) fn body_to_bytes(body: &PyAny) -> PyResult<Vec<u8>> {
+    if let Ok(bytes) = body.downcast::<PyBytes>() {
+        return Ok(bytes.as_bytes().to_vec())
+    }
+
+    if let Ok(string) = body.downcast::<PyString>() {
+        return Ok(string.to_str()?.as_bytes().to_vec())
+    }
+
+    Err(PyTypeError::new_err("response body must be bytes or str"))
+}
+
+/// `name: value\r\n`, copied straight in.
+fn push_header(out: &mut Vec<u8>, name: &str, value: &str) {
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(b": ");
+    out.extend_from_slice(value.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Writes `n` in decimal without going through `fmt`.
+fn push_decimal(out: &mut Vec<u8>, mut n: usize) {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break
+        }
+    }
+
+    out.extend_from_slice(&digits[start..]);
+}
+
+///
+/// The `HTTP/1.1 <status> <reason>\r\n` line for a status with a reason
+/// phrase, they're all made the first time one is asked for. None for any
+/// other status, those are rare enough to format.
+///
+fn status_line(status: u16) -> Option<&'static [u8]> {
+    static LINES: OnceLock<Vec<Option<Vec<u8>>>> = OnceLock::new();
+
+    let lines = LINES.get_or_init(|| {
+        (100..600)
+            .map(|status| match reason_phrase(status) {
+                "" => None,
+                reason => Some(format!("HTTP/1.1 {} {}\r\n", status, reason).into_bytes()),
+            })
+            .collect()
+    });
+
+    let index = usize::from(status).checked_sub(100)?;
+    lines.get(index)?.as_deref()
+}
+
+///
+/// The standard reason phrase for a status code, unknown codes just get
+/// an empty phrase which is still valid on the wire.
+///
+pub(crate) fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        421 => "Misdirected Request",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+
+///
+/// The request line and headers of a request, `version` is `protocol`
+/// split into its major and minor numbers so decisions about framing can
+/// compare versions rather than strings.
+///
+pub(crate) struct RequestHead {
+    pub(crate) method: String,
+    pub(crate) target: Vec<u8>,         // Exactly as it was sent, see `request_target()` for a str
+    pub(crate) form: TargetForm,        // Which form `target` takes, anything but origin-form only gets this far with `proxy_mode`
+    pub(crate) protocol: String,
+    pub(crate) version: (u8, u8),
+    pub(crate) headers: Headers,
+}
+
+/// The most headers a request can send before we refuse it.
+const MAX_HEADER_COUNT: usize = 32;
+
+///
+/// The four forms a request target can take (RFC 7230 section 5.3), the
+/// absolute and authority forms are for proxies and carry where the
+/// client wants to go. `GET http://example.com/ HTTP/1.1` is absolute-form
+/// and `CONNECT example.com:443 HTTP/1.1` authority-form.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum TargetForm {
+    Origin,
+    Absolute { scheme: &'static str, host: String, port: u16 },
+    Authority { host: String, port: u16 },
+    Asterisk,
+}
+
+impl TargetForm {
+    ///
+    /// Internal Method: TargetForm::classify() -> Result<TargetForm, HeadError>
+    ///
+    ///     Works out the form of a request line's target, which also has
+    ///     to be the right one for its method. Only `CONNECT` uses
+    ///     authority-form and it can't use anything else, and without
+    ///     `proxy_mode` neither proxy form is accepted at all so a router
+    ///     is never handed something that isn't a path.
+    ///
+    fn classify(method: &[u8], target: &[u8], proxy_mode: bool) -> Result<Self, HeadError> {
+        let form = match target {
+            b"*" => Self::Asterisk,
+            _ if target.starts_with(b"/") => Self::Origin,
+            _ if target.contains_str("://") => Self::absolute(target)?,
+            _ if method != b"CONNECT" => return Err(HeadError::invalid("authority-form target is only for CONNECT")),
+            _ => match split_authority(target) {
+                Some((host, Some(port))) => Self::Authority { host, port },
+                _ => return Err(HeadError::invalid("CONNECT needs a host:port target")),
+            },
+        };
+
+        match (&form, method == b"CONNECT") {
+            (Self::Authority { .. }, _) if !proxy_mode => Err(HeadError::invalid("CONNECT is only accepted with proxy_mode")),
+            (Self::Authority { .. }, _) => Ok(form),
+            (_, true) => Err(HeadError::invalid("CONNECT needs a host:port target")),
+            (Self::Absolute { .. }, _) if !proxy_mode => Err(HeadError::invalid("absolute-form target is only accepted with proxy_mode")),
+            _ => Ok(form),
+        }
+    }
+
+    /// An `http://` or `https://` target, the port defaults to the scheme's.
+    fn absolute(target: &[u8]) -> Result<Self, HeadError> {
+        let (scheme, rest, default_port) = if let Some(rest) = target.strip_prefix(b"http://") {
+            ("http", rest, 80)
+        } else if let Some(rest) = target.strip_prefix(b"https://") {
+            ("https", rest, 443)
+        } else {
+            return Err(HeadError::invalid("absolute-form target has to be http or https"))
+        };
+
+        let authority = &rest[..rest.find_byteset(b"/?").unwrap_or(rest.len())];
+        let (host, port) = split_authority(authority)
+            .ok_or(HeadError::invalid("invalid authority in request target"))?;
+
+        Ok(Self::Absolute { scheme, host, port: port.unwrap_or(default_port) })
+    }
+
+    /// What `request.target_form` says, `"origin"`, `"absolute"`, `"authority"` or `"asterisk"`.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Origin => "origin",
+            Self::Absolute { .. } => "absolute",
+            Self::Authority { .. } => "authority",
+            Self::Asterisk => "asterisk",
+        }
+    }
+}
+
+/// Splits `host[:port]` into the host as `host_name()` gives it and the port if there is one.
+fn split_authority(authority: &[u8]) -> Option<(String, Option<u16>)> {
+    let authority = std::str::from_utf8(authority).ok().filter(|authority| valid_host(authority))?;
+    let port = match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => Some(authority[i + 1..].parse().ok()?),
+        _ => None,
+    };
+
+    let host = host_name(authority);
+    match host.is_empty() {
+        true => None,
+        false => Some((host, port)),
+    }
+}
+
+///
+/// Why a request head was refused, the status to answer with and a reason
+/// short enough to go back to the client as the body (see
+/// `HTTPResponse::refused()`).
+///
+#[derive(Debug)]
+pub(crate) struct HeadError {
+    pub(crate) status: u16,
+    pub(crate) reason: &'static str,
+}
+
+impl HeadError {
+    /// A `400`, the head is malformed.
+    pub(crate) fn invalid(reason: &'static str) -> Self {
+        Self { status: 400, reason }
+    }
+}
+
+///
+/// Internal Method: http::parse_head() -> PyResult<Option<(RequestHead, usize)>>
+///
+///     Parses the request line and headers straight out of the connection's
+///     buffer, `None` means the head hasn't all arrived yet and `Some` gives
+///     the head along with where it ends. Each line is checked as soon as
+///     it's complete so garbage is refused without waiting for the rest of
+///     it, nothing is copied out of the buffer until the whole head is valid.
+///
+///     A request line longer than `max_request_line` is a `414` and a head
+///     longer than `MAX_HEAD_SIZE` (or with too many headers) a `431`, both
+///     as soon as that much has arrived without waiting for the end of it.
+///     The method has to be a token no longer than `max_method_length`,
+///     that's checked as it arrives too.
+///
+///     Only HTTP/1.0 and 1.1 are spoken, any other version is a `505`. A
+///     request line of just `GET /path` is HTTP/0.9 which has no headers, it
+///     ends the head with `http09` and is a `505` without it.
+///
+///     Folded header lines (obs-fold), whitespace before a header's colon,
+///     control characters in header values and lines ending in a bare LF
+///     are all refused, see RFC 7230 section 3. With `lenient` (the
+///     `lenient=True` option) the last three are let through for clients
+///     that don't know better: the whitespace is dropped, any control
+///     character but NUL and CR is kept and LF alone ends a line. Nothing
+///     that decides where the request ends is relaxed.
+///
+pub(crate) fn parse_head(buffer: &[u8], options: &RunnerOptions) -> Result<Option<(RequestHead, usize)>, HeadError> {
+    let mut lines = HeadLines { buffer, pos: 0, lenient: options.lenient };
+    let line_too_long = HeadError { status: 414, reason: "request line too long" };
+    let head_too_large = HeadError { status: 431, reason: "request head too large" };
+
+    // a client may send a stray CRLF after the body of the previous request
+    let request_line = loop {
+        match lines.next_line()? {
+            Some(b"") => continue,
+            Some(line) if line.len() > options.max_request_line => return Err(line_too_long),
+            Some(line) => break line,
+            None => {
+                check_partial_method(lines.rest(), options.max_method_length)?;
+                match lines.remaining() > options.max_request_line {
+                    true => return Err(line_too_long),
+                    false => return Ok(None),
+                }
+            },
+        }
+    };
+    let (method, target, protocol) = parse_request_line(request_line, options.max_method_length)?;
+    let form = TargetForm::classify(method, target, options.proxy_mode)?;
+
+    // two parts is an HTTP/0.9 request, which is all there is to it
+    let protocol = match protocol {
+        Some(protocol) => protocol,
+        None if !options.http09 => return Err(HeadError { status: 505, reason: "HTTP/0.9 isn't supported" }),
+        None if method != b"GET" => return Err(HeadError::invalid("HTTP/0.9 only has GET")),
+        None => {
+            let head = RequestHead {
+                method: String::from("GET"),
+                target: target.to_vec(),
+                form,
+                protocol: String::from("HTTP/0.9"),
+                version: (0, 9),
+                headers: Headers::new(),
+            };
+
+            return Ok(Some((head, lines.pos)))
+        },
+    };
+
+    let (protocol, version) = std::str::from_utf8(protocol)
+        .ok()
+        .and_then(|protocol| Some((protocol, parse_version(protocol)?)))
+        .ok_or(HeadError::invalid("malformed HTTP version"))?;
+
+    if version != (1, 0) && version != (1, 1) {
+        return Err(HeadError { status: 505, reason: "unsupported HTTP version" })
+    }
+
+    let mut raw: [(&[u8], &[u8]); MAX_HEADER_COUNT] = [(&[], &[]); MAX_HEADER_COUNT];
+    let mut count = 0;
+    loop {
+        let line = match lines.next_line()? {
+            Some(_) if lines.pos > crate::MAX_HEAD_SIZE => return Err(head_too_large),
+            Some(line) => line,
+            None if buffer.len() > crate::MAX_HEAD_SIZE => return Err(head_too_large),
+            None => return Ok(None),
+        };
+
+        if line.is_empty() {
+            break
+        }
+
+        if count == MAX_HEADER_COUNT {
+            return Err(HeadError { status: 431, reason: "too many headers" })
+        }
+
+        raw[count] = parse_header_line(line, options.lenient)?;
+        count += 1;
+    }
+
+    // the method and names are tokens and so already ascii, values can be anything
+    let mut headers = Headers::new();
+    for (name, value) in &raw[..count] {
+        headers.append(&name.to_str_lossy(), value);
+    }
+
+    let head = RequestHead {
+        method: method_name(method),
+        target: target.to_vec(),
+        form,
+        protocol: protocol.to_string(),
+        version,
+        headers,
+    };
+
+    Ok(Some((head, lines.pos)))
+}
+
+/// Walks the CRLF terminated lines of a request head.
+struct HeadLines<'a> {
+    buffer: &'a [u8],
+    pos: usize,         // Where the next line starts
+    lenient: bool,      // A bare LF ends a line too
+}
+
+impl<'a> HeadLines<'a> {
+    /// How much there is after the lines we've had so far.
+    fn remaining(&self) -> usize {
+        self.buffer.len() - self.pos
+    }
+
+    /// What there is of the line that hasn't all arrived yet.
+    fn rest(&self) -> &'a [u8] {
+        &self.buffer[self.pos..]
+    }
+
+    /// The next line without its CRLF, `None` until all of it has arrived.
+    fn next_line(&mut self) -> Result<Option<&'a [u8]>, HeadError> {
+        let rest = &self.buffer[self.pos..];
+        let end = match rest.find_byte(b'\n') {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+
+        let line = match rest[..end].strip_suffix(b"\r") {
+            Some(line) => line,
+            None if self.lenient => &rest[..end],
+            None => return Err(HeadError::invalid("bare LF in request head")),
+        };
+
+        self.pos += end + 1;
+        Ok(Some(line))
+    }
+}
+
+/// The method, target and protocol of a request line, HTTP/0.9 has no protocol.
+type RequestLine<'a> = (&'a [u8], &'a [u8], Option<&'a [u8]>);
+
+/// Splits `GET /path HTTP/1.1` (or `GET /path`), the parts are separated by exactly one space.
+fn parse_request_line(line: &[u8], max_method_length: usize) -> Result<RequestLine<'_>, HeadError> {
+    let mut parts = line.split(|&b| b == b' ');
+
+    let (method, target, protocol) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), protocol, None) => (method, target, protocol),
+        _ => return Err(HeadError::invalid("malformed request line")),
+    };
+
+    check_method(method, max_method_length)?;
+
+    // raw high bytes in the target are let through, they're decoded like `%XX` later
+    if target.is_empty() || !target.iter().all(|&b| b > b' ' && b != 0x7f) {
+        return Err(HeadError::invalid("invalid request target"))
+    }
+
+    // asterisk-form (`OPTIONS * HTTP/1.1`) is the server as a whole
+    if target.starts_with(b"*") && (target != b"*" || method != b"OPTIONS") {
+        return Err(HeadError::invalid("asterisk-form target is only for OPTIONS"))
+    }
+
+    Ok((method, target, protocol))
+}
+
+/// The methods RFC 7231 and RFC 5789 define, see `method_name()`.
+const STANDARD_METHODS: [&str; 9] = ["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+/// If `method` is a token, which is all a method can be.
+pub(crate) fn is_method(method: &[u8]) -> bool {
+    !method.is_empty() && method.iter().all(|&b| cookie::is_token(b))
+}
+
+/// A method that isn't a token or is longer than `max_method_length` is a `400`.
+fn check_method(method: &[u8], max_method_length: usize) -> Result<(), HeadError> {
+    if !is_method(method) {
+        return Err(HeadError::invalid("invalid request method"))
+    }
+
+    if method.len() > max_method_length {
+        return Err(HeadError::invalid("request method too long"))
+    }
+
+    Ok(())
+}
+
+///
+/// Checks the method of a request line that's still arriving, so a
+/// binary blob or a huge "method" is refused without waiting for the
+/// rest of the line (or `max_request_line` of it). The part after the
+/// method is left for `parse_request_line()` once the line is complete,
+/// as is a line only just starting with the CR of a stray CRLF.
+///
+fn check_partial_method(rest: &[u8], max_method_length: usize) -> Result<(), HeadError> {
+    let method = match rest.find_byte(b' ') {
+        Some(end) => &rest[..end],
+        None => rest.strip_suffix(b"\r").unwrap_or(rest),
+    };
+
+    match method.is_empty() {
+        true => Ok(()),
+        false => check_method(method, max_method_length),
+    }
+}
+
+///
+/// The method as a String, a standard one is copied out of the static
+/// name rather than decoded. The python side hands out the same interned
+/// str for each standard method (see `HTTPRequest.method`) so comparing it
+/// against a literal is an identity check.
+///
+pub(crate) fn method_name(method: &[u8]) -> String {
+    match STANDARD_METHODS.iter().find(|name| name.as_bytes() == method) {
+        Some(name) => String::from(*name),
+        None => method.to_str_lossy().into_owned(),
+    }
+}
+
+/// The interned python str for a standard method, None for any other.
+fn interned_method(py: Python, method: &str) -> Option<PyObject> {
+    static INTERNED: OnceLock<Vec<PyObject>> = OnceLock::new();
+
+    let position = STANDARD_METHODS.iter().position(|name| *name == method)?;
+    let interned = INTERNED.get_or_init(|| {
+        STANDARD_METHODS
+            .iter()
+            .map(|name| {
+                let name = std::ffi::CString::new(*name).unwrap();
+                // SAFETY: the name is a valid C string and the result is a new reference or NULL
+                unsafe { PyObject::from_owned_ptr(py, ffi::PyUnicode_InternFromString(name.as_ptr())) }
+            })
+            .collect()
+    });
+
+    Some(interned[position].clone_ref(py))
+}
+
+/// If `allowed_methods` lets `method` through to the callback, anything else is a `501`.
+pub(crate) fn method_allowed(options: &RunnerOptions, method: &str) -> bool {
+    options.allowed_methods
+        .as_ref()
+        .is_none_or(|allowed| allowed.iter().any(|allowed| allowed == method))
+}
+
+///
+/// The target as a str, one that isn't utf-8 has its high bytes `%XX`
+/// encoded which they'd be decoded the same as anyway, so `path` still
+/// comes out of exactly the bytes that were sent.
+///
+pub(crate) fn request_target(target: &[u8]) -> String {
+    if let Ok(target) = std::str::from_utf8(target) {
+        return target.to_string()
+    }
+
+    let mut encoded = String::with_capacity(target.len() * 3);
+    for &b in target {
+        match b.is_ascii() {
+            true => encoded.push(b as char),
+            false => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    encoded
+}
+
+/// Splits `Name: value` trimming the whitespace around the value, see `parse_head()` for `lenient`.
+fn parse_header_line(line: &[u8], lenient: bool) -> Result<(&[u8], &[u8]), HeadError> {
+    if line.starts_with(b" ") || line.starts_with(b"\t") {
+        return Err(HeadError::invalid("obsolete header line folding"))
+    }
+
+    let colon = line
+        .find_byte(b':')
+        .ok_or(HeadError::invalid("malformed header line"))?;
+
+    let mut name = &line[..colon];
+    if lenient {
+        name = name.trim_end_with(|c| c == ' ' || c == '\t');
+    }
+
+    if name.is_empty() || !name.iter().all(|&b| cookie::is_token(b)) {
+        return match name.last() {
+            Some(b' ') | Some(b'\t') => Err(HeadError::invalid("whitespace before header colon")),
+            _ => Err(HeadError::invalid("invalid header name")),
+        }
+    }
+
+    let value = line[colon + 1..].trim_with(|c| c == ' ' || c == '\t');
+    let allowed = |b: u8| match lenient {
+        true => b != 0 && b != b'\r',
+        false => b == b'\t' || (b >= b' ' && b != 0x7f),
+    };
+    if !value.iter().all(|&b| allowed(b)) {
+        return Err(HeadError::invalid("control character in header value"))
+    }
+
+    Ok((name, value))
+}
+
+/// Splits `HTTP/1.1` into `(1, 1)`.
+fn parse_version(protocol: &str) -> Option<(u8, u8)> {
+    let (major, minor) = protocol.strip_prefix("HTTP/")?.split_once('.')?;
+
+    let digit = |part: &str| match part.as_bytes() {
+        [d] if d.is_ascii_digit() => Some(d - b'0'),
+        _ => None,
+    };
+
+    Some((digit(major)?, digit(minor)?))
+}
+
+///
+/// If the connection should stay open after this request, `Connection:
+/// close` always closes it, HTTP/1.1 stays open by default and HTTP/1.0 only
+/// does when the client asked for `keep-alive`.
+///
+pub(crate) fn keep_alive(version: (u8, u8), headers: &Headers) -> bool {
+    let has_token = |token: &str| {
+        headers.get_all(HeaderName::Connection).any(|value| {
+            value.split(',').any(|v| v.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    if has_token("close") {
+        return false
+    }
+
+    version >= (1, 1) || has_token("keep-alive")
+}
+
+///
+/// The `Content-Length` of a request, used to know how much body to wait
+/// for. `None` if there's no header, `Some(Err(()))` if the value isn't a
+/// valid length (see `parse_length()`) or the header was sent more than
+/// once, we'd have to guess which one the client meant and something in
+/// front of us might have guessed differently.
+///
+pub(crate) fn content_length(headers: &Headers) -> Option<Result<usize, ()>> {
+    let mut values = headers.get_all(HeaderName::ContentLength);
+    let value = values.next()?;
+
+    if values.next().is_some() {
+        return Some(Err(()))
+    }
+
+    Some(parse_length(value).and_then(|len| <usize as std::convert::TryFrom<u64>>::try_from(len).ok()).ok_or(()))
+}
+
+///
+/// A `Content-Length` value, which is digits and nothing else. `str::parse`
+/// takes a leading `+` and trimming would take whitespace other than the
+/// spaces and tabs the header parser already stripped (`lenient` lets
+/// control characters through), anything a server in front of us might
+/// read as a different number is refused instead. A value too big for a
+/// u64 is refused rather than wrapped.
+///
+fn parse_length(value: &str) -> Option<u64> {
+    if value.is_empty() {
+        return None
+    }
+
+    value.bytes().try_fold(0u64, |len, b| match b {
+        b'0'..=b'9' => len.checked_mul(10)?.checked_add(u64::from(b - b'0')),
+        _ => None,
+    })
+}
+
+///
+/// The size of a chunk-size line, hex digits and then nothing but its
+/// extensions, which are ignored. Like `parse_length()` anything a server
+/// in front of us might read differently is refused: a sign, whitespace
+/// before the size or after it other than spaces and tabs ahead of a `;`,
+/// and a size too big for a usize rather than wrapping it.
+///
+fn parse_chunk_size(line: &[u8]) -> Option<usize> {
+    let digits = line.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+    let (size, extensions) = line.split_at(digits);
+    if size.is_empty() {
+        return None
+    }
+
+    match extensions.trim_start_with(|c| c == ' ' || c == '\t') {
+        [] if extensions.is_empty() => {},
+        [b';', ..] => {},
+        _ => return None,
+    }
+
+    size.iter().try_fold(0usize, |size, b| {
+        let digit = (*b as char).to_digit(16)?;
+        size.checked_mul(16)?.checked_add(digit as usize)
+    })
+}
+
+///
+/// How the request body is delimited, `Err` is the status to refuse the
+/// request with: `413` for a `Content-Length` over the limit. The head has
+/// been through `valid_framing()` so a `Transfer-Encoding` is `chunked`.
+///
+/// Only a chunked body can have trailers, announcing them (`Trailer`) for
+/// any other body is a `400`.
+///
+pub(crate) fn body_framing(headers: &Headers, max_body_size: usize) -> Result<BodyFraming, u16> {
+    if headers.contains(HeaderName::TransferEncoding) {
+        return Ok(BodyFraming::Chunked(ChunkedDecoder::new(max_body_size)))
+    }
+
+    if headers.contains(HeaderName::Trailer) {
+        return Err(400)
+    }
+
+    match content_length(headers) {
+        Some(Ok(len)) if len > max_body_size => Err(413),
+        Some(Ok(len)) => Ok(BodyFraming::Length(len)),
+        Some(Err(())) => Err(400),
+        None => Ok(BodyFraming::Length(0)),
+    }
+}
+
+pub(crate) enum BodyFraming {
+    Length(usize),
+    Chunked(ChunkedDecoder),
+}
+
+
+///
+/// ChunkedDecoder decodes a `Transfer-Encoding: chunked` request body as it
+/// arrives, it's fed whatever is in the buffer and says how much of it was
+/// used so nothing has to be decoded twice. Trailer fields are parsed
+/// like the head's headers and held to the same limits.
+///
+pub(crate) struct ChunkedDecoder {
+    state: ChunkState,
+    body: Vec<u8>,
+    decoded: usize,     // All the body decoded so far, `body` is just what hasn't been spooled of it
+    limit: usize,       // The most decoded body we'll accept
+    trailers: Headers,
+    trailer_size: usize, // How much of the trailer section we've read, capped like the head
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ChunkState {
+    Size,               // Waiting for a `<hex size>[;ext]\r\n` line
+    Data(usize),        // This much of the current chunk still to come
+    DataEnd,            // The `\r\n` after a chunk's data
+    Trailers,           // Trailer lines until an empty one
+    Done,
+}
+
+/// Why decoding a chunked body stopped early.
+pub(crate) enum ChunkError {
+    Invalid,
+    TooLarge,
+}
+
+/// The longest size or trailer line we'll wait for.
+const MAX_CHUNK_LINE: usize = 4096;
+
+impl ChunkedDecoder {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            state: ChunkState::Size,
+            body: Vec::new(),
+            decoded: 0,
+            limit,
+            trailers: Headers::new(),
+            trailer_size: 0,
+        }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    /// The decoded body and its trailers.
+    pub(crate) fn into_parts(self) -> (Vec<u8>, Headers) {
+        (self.body, self.trailers)
+    }
+
+    pub(crate) fn decoded(&self) -> usize {
+        self.decoded
+    }
+
+    /// The body decoded since it was last cleared.
+    pub(crate) fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Empties the body once it's been spooled, keeping its buffer for the rest.
+    pub(crate) fn clear_body(&mut self) {
+        self.body.clear();
+    }
+
+    fn add_trailer(&mut self, line: &[u8]) -> Result<(), ChunkError> {
+        self.trailer_size += line.len() + 2;
+        if self.trailers.len() == MAX_HEADER_COUNT || self.trailer_size > crate::MAX_HEAD_SIZE {
+            return Err(ChunkError::Invalid)
+        }
+
+        let (name, value) = parse_header_line(line, false).map_err(|_| ChunkError::Invalid)?;
+        self.trailers.append(&name.to_str_lossy(), value);
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: ChunkedDecoder::feed() -> Result<usize, ChunkError>
+    ///
+    ///     Decodes as much of `buf` as it can, returning how many bytes were
+    ///     used. Whatever is left over is an incomplete line the next call
+    ///     should be given again with more data behind it.
+    ///
+    pub(crate) fn feed(&mut self, buf: &[u8]) -> Result<usize, ChunkError> {
+        let mut used = 0;
+
+        loop {
+            let rest = &buf[used..];
+
+            match self.state {
+                ChunkState::Done => return Ok(used),
+                ChunkState::Data(remaining) => {
+                    if rest.is_empty() {
+                        return Ok(used)
+                    }
+
+                    let n = remaining.min(rest.len());
+                    self.body.extend_from_slice(&rest[..n]);
+                    self.decoded += n;
+                    used += n;
+
+                    self.state = match remaining - n {
+                        0 => ChunkState::DataEnd,
+                        left => ChunkState::Data(left),
+                    };
+                },
+                ChunkState::DataEnd => {
+                    if rest.len() < 2 {
+                        return Ok(used)
+                    }
+
+                    if &rest[..2] != b"\r\n" {
+                        return Err(ChunkError::Invalid)
+                    }
+
+                    used += 2;
+                    self.state = ChunkState::Size;
+                },
+                ChunkState::Size | ChunkState::Trailers => {
+                    let line_end = match rest.find(b"\r\n") {
+                        Some(i) => i,
+                        None if rest.len() > MAX_CHUNK_LINE => return Err(ChunkError::Invalid),
+                        None => return Ok(used),
+                    };
+
+                    let line = &rest[..line_end];
+                    used += line_end + 2;
+
+                    if self.state == ChunkState::Trailers {
+                        if line.is_empty() {
+                            self.state = ChunkState::Done;
+                        } else {
+                            self.add_trailer(line)?;
+                        }
+                        continue
+                    }
+
+                    let size = parse_chunk_size(line).ok_or(ChunkError::Invalid)?;
+
+                    if self.decoded.saturating_add(size) > self.limit {
+                        return Err(ChunkError::TooLarge)
+                    }
+
+                    self.state = match size {
+                        0 => ChunkState::Trailers,
+                        size => ChunkState::Data(size),
+                    };
+                },
+            }
+        }
+    }
+}
+
+///
+/// If the headers which decide where the body ends can be trusted. These
+/// are the ways of getting us and something in front of us to disagree on
+/// where a request ends (request smuggling) so rather than picking one
+/// reading the request is refused and the connection closed:
+///
+///     - both `Transfer-Encoding` and `Content-Length`
+///     - a repeated `Transfer-Encoding` or `Content-Length`
+///     - a `Content-Length` that isn't just digits or is too big to be a length
+///     - a `Transfer-Encoding` that isn't exactly `chunked`
+///
+pub(crate) fn valid_framing(headers: &Headers) -> bool {
+    let mut codings = headers.get_all(HeaderName::TransferEncoding);
+    match (codings.next(), codings.next()) {
+        (None, _) => !matches!(content_length(headers), Some(Err(()))),
+        (Some(coding), None) => coding.trim().eq_ignore_ascii_case("chunked") && !headers.contains(HeaderName::ContentLength),
+        (Some(_), Some(_)) => false,
+    }
+}
+
+///
+/// Decodes `%XX` escapes in a request path, invalid escapes are left as
+/// they are and anything which isn't utf-8 afterwards is replaced.
+///
+pub(crate) fn percent_decode(value: &str) -> String {
+    String::from_utf8_lossy(&percent_decode_bytes(value)).into_owned()
+}
+
+///
+/// Internal Method: http::check_host() -> Result<(), u16>
+///
+///     Makes sure we know which host the request is for, HTTP/1.1 has to
+///     send exactly one `Host` (1.0 may leave it out) and with an
+///     `allowed_hosts` list it has to be one of those. An absolute-form
+///     target (`GET http://example.com/ HTTP/1.1`, only let through with
+///     `proxy_mode`) wins over the Host header, the header is replaced
+///     with its authority and the target reduced to the path.
+///
+///     `Err` is the status to answer with.
+///
+pub(crate) fn check_host(
+    target: &mut Vec<u8>,
+    version: (u8, u8),
+    headers: &mut Headers,
+    allowed_hosts: Option<&[String]>,
+    invalid_host_status: u16,
+) -> Result<(), u16> {
+    if let Some((authority, path)) = split_absolute_form(target) {
+        headers.set("Host", authority.as_bytes());
+        *target = path;
+    }
+
+    let host = {
+        let mut hosts = headers.get_all(HeaderName::Host);
+        match (hosts.next(), hosts.next()) {
+            (Some(host), None) => Some(host.trim()),
+            (None, _) if version < (1, 1) => None,
+            _ => return Err(400),
+        }
+    };
+
+    let host = match host {
+        Some(host) if valid_host(host) => host,
+        Some(_) => return Err(400),
+        None => return Ok(()),
+    };
+
+    match allowed_hosts {
+        Some(allowed) if !host_allowed(host, allowed) => Err(invalid_host_status),
+        _ => Ok(()),
+    }
+}
+
+/// Splits `http://authority/path?query` into the authority and the origin-form target.
+fn split_absolute_form(target: &[u8]) -> Option<(String, Vec<u8>)> {
+    let rest = target
+        .strip_prefix(b"http://")
+        .or_else(|| target.strip_prefix(b"https://"))?;
+
+    let (authority, path) = rest.split_at(rest.find_byteset(b"/?").unwrap_or(rest.len()));
+    match path.starts_with(b"/") {
+        true => Some((latin1(authority), path.to_vec())),
+        false => Some((latin1(authority), [b"/", path].concat())),
+    }
+}
+
+/// If a Host value is a plausible `host[:port]`, anything with userinfo, a path or spaces isn't.
+fn valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._:[]".contains(&b))
+}
+
+/// A Host without its port (or an IPv6 address's brackets), lowercased.
+pub(crate) fn host_name(host: &str) -> String {
+    // strip the port, being careful of `[::1]:8080`
+    let name = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+
+    name.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase()
+}
+
+///
+/// Checks a Host against the `allowed_hosts` patterns ignoring case and the
+/// port, `*` allows anything and `*.example.com` any subdomain of it (but
+/// not `example.com` itself).
+///
+fn host_allowed(host: &str, allowed: &[String]) -> bool {
+    let name = host_name(host);
+    allowed.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) if suffix.starts_with('.') => name.len() > suffix.len() && name.ends_with(suffix),
+            _ => name == pattern,
+        }
+    })
+}
+
+///
+/// Resolves the `.` and `..` segments of a raw request path, which segments
+/// are dots is decided after decoding them (`%2e%2e` is still `..`) but the
+/// segments themselves are left encoded so a `%2F` never becomes a
+/// separator. Anything that isn't an absolute path (`*`, absolute-form
+/// targets) is left as it is.
+///
+pub(crate) fn normalize_path(raw: &str, merge_slashes: bool) -> Result<String, ()> {
+    if !raw.starts_with('/') {
+        return Ok(raw.to_string())
+    }
+
+    collapse_segments(raw, merge_slashes, percent_decode_bytes)
+}
+
+///
+/// The same as `normalize_path` for a path that has already been decoded,
+/// used for the paths handed to a FileResponse.
+///
+pub(crate) fn normalize_decoded(path: &str) -> Result<String, ()> {
+    collapse_segments(path, true, |segment| segment.as_bytes().to_vec())
+}
+
+fn collapse_segments(path: &str, merge_slashes: bool, decode: fn(&str) -> Vec<u8>) -> Result<String, ()> {
+    let mut out: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+
+    for segment in path.trim_start_matches('/').split('/') {
+        trailing_slash = true;
+        match decode(segment).as_slice() {
+            b"." => {},
+            b".." => {
+                out.pop().ok_or(())?;
+            },
+            b"" if merge_slashes || out.is_empty() => {},
+            _ => {
+                out.push(segment);
+                trailing_slash = false;
+            },
+        }
+    }
+
+    let mut normalized = format!("/{}", out.join("/"));
+    if trailing_slash && !out.is_empty() {
+        normalized.push('/');
+    }
+
+    Ok(normalized)
+}
+
+///
+/// Splits and decodes a `application/x-www-form-urlencoded` query string,
+/// repeated keys are collected in the order they appear and a key without
+/// a `=` just gets an empty value.
+///
+pub(crate) fn parse_query(query: &str) -> Vec<(String, Vec<String>)> {
+    let mut pairs: Vec<(String, Vec<String>)> = Vec::new();
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = percent_decode(&key.replace('+', " "));
+        let value = percent_decode(&value.replace('+', " "));
+
+        match pairs.iter_mut().find(|(existing, _)| *existing == key) {
+            Some((_, values)) => values.push(value),
+            None => pairs.push((key, vec![value])),
+        }
+    }
+
+    pairs
+}
+
+///
+/// Internal Method: http::parse_form() -> Option<Vec<(String, Vec<String>)>>
+///
+///     `parse_query` for a form body, which can be a lot bigger than a
+///     query string. The keys are looked up in a map rather than walked
+///     and None is a body with more than `limit` fields, that's counted
+///     before anything is decoded.
+///
+pub(crate) fn parse_form(body: &str, limit: usize) -> Option<Vec<(String, Vec<String>)>> {
+    let fields = body.split('&').filter(|pair| !pair.is_empty());
+    if fields.clone().count() > limit {
+        return None
+    }
+
+    let mut pairs: Vec<(String, Vec<String>)> = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for pair in fields {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = percent_decode(&key.replace('+', " "));
+        let value = percent_decode(&value.replace('+', " "));
+
+        match seen.get(&key) {
+            Some(&index) => pairs[index].1.push(value),
+            None => {
+                seen.insert(key.clone(), pairs.len());
+                pairs.push((key, vec![value]));
+            },
+        }
+    }
+
+    Some(pairs)
+}
+
+///
+/// The raw bytes behind `percent_decode` for when the caller wants to pick
+/// the encoding itself, WSGI for example wants them as latin-1.
+///
+pub(crate) fn percent_decode_bytes(value: &str) -> Vec<u8> {
+    fn hex(b: u8) -> Option<u8> {
+        (b as char).to_digit(16).map(|d| d as u8)
+    }
+
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                out.push(hi << 4 | lo);
+                i += 3;
+                continue
+            }
+        }
+
+        out.push(bytes[i]);
+        i += 1;
+    }
+
+    out
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+///
+/// Formats a unix timestamp as an IMF-fixdate, the one date format HTTP
+/// wants everywhere, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+///
+pub(crate) fn http_date(timestamp: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+    let days = timestamp / 86400;
+    let secs = timestamp % 86400;
+
+    // Howard Hinnant's days_from_civil in reverse
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    )
+}
+
+///
+/// The unix timestamp of an IMF-fixdate (see `http_date()`), None for
+/// anything else. The two obsolete formats HTTP/1.0 used aren't taken, a
+/// condition on a date we can't read is one that doesn't hold.
+///
+pub(crate) fn parse_http_date(value: &str) -> Option<u64> {
+    let mut parts = value.trim().split(' ');
+    let (weekday, day, month, year, time, zone) = (
+        parts.next()?, parts.next()?, parts.next()?, parts.next()?, parts.next()?, parts.next()?,
+    );
+
+    if parts.next().is_some() || !weekday.ends_with(',') || zone != "GMT" || day.len() != 2 || year.len() != 4 {
+        return None
+    }
+
+    let number = |digits: &str| -> Option<i64> {
+        match digits.bytes().all(|b| b.is_ascii_digit()) {
+            true => digits.parse().ok(),
+            false => None,
+        }
+    };
+
+    let day = number(day).filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let year = number(year)?;
+
+    let mut clock = time.split(':');
+    let (hour, minute, second) = (number(clock.next()?)?, number(clock.next()?)?, number(clock.next()?)?);
+    if clock.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None
+    }
+
+    // Howard Hinnant's days_from_civil, the other way round to `http_date()`
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let timestamp = days * 86400 + hour * 3600 + minute * 60 + second;
+    match timestamp >= 0 {
+        true => Some(timestamp as u64),
+        false => None,
+    }
+}
+
+///
+/// DateCache keeps the formatted `Date` header for the current second so
+/// it's formatted once a second rather than for every response, every
+/// response in that second shares the one string.
+///
+#[derive(Default)]
+pub(crate) struct DateCache {
+    cached: Mutex<(u64, Arc<str>)>, // The unix second and its formatted date
+}
+
+impl DateCache {
+    pub(crate) fn now(&self) -> Arc<str> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        if cached.0 != secs || cached.1.is_empty() {
+            *cached = (secs, http_date(secs).into());
+        }
+
+        cached.1.clone()
+    }
+}
+
+/// Decodes bytes as latin-1, every byte maps straight to the same code point.
+pub(crate) fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
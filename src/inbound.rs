@@ -0,0 +1,432 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::class::gc::{PyGCProtocol, PyTraverseError, PyVisit};
+use pyo3::exceptions::{PyRuntimeError, PyStopAsyncIteration};
+use pyo3::types::PyBytes;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::errors::{BadRequest, ConnectionClosed};
+use crate::http::{BodyFraming, ChunkError};
+use crate::sleep::LoopSleeper;
+use crate::OnceFuture;
+
+
+///
+/// Inbound is the connection's side of a body it's streaming to the
+/// handler with `stream_request_body=True`, how it's framed and how much
+/// of it is left. The body is read from the front of the connection's
+/// buffer once the head has gone from it, what follows the body (a
+/// pipelined request) is left there for the next request.
+///
+pub(crate) struct Inbound {
+    framing: BodyFraming,                   // A `Length` is what's left of the body to come
+    done: bool,                             // Set once the last of the body has been read
+    failed: Option<StreamError>,            // Why it couldn't be, the body never gets to its end then
+    stream: Option<Py<RequestStream>>,      // What the handler reads the body from, once the request's been made
+}
+
+///
+/// What taking from the buffer got, `More` means the rest of the body is
+/// still to be read off the socket.
+///
+pub(crate) enum Taken {
+    Chunk(Vec<u8>),
+    Done,
+    More,
+}
+
+impl Inbound {
+    pub(crate) fn new(framing: BodyFraming) -> Self {
+        Self { framing, done: false, failed: None, stream: None }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// If the client went away before it sent all of the body.
+    pub(crate) fn client_gone(&self) -> bool {
+        matches!(self.failed, Some(StreamError::Closed))
+    }
+
+    /// Where the body ends from the front of the buffer for `read_size()`, not known for a chunked one.
+    pub(crate) fn remaining(&self) -> Option<usize> {
+        match &self.framing {
+            BodyFraming::Length(left) => Some(*left),
+            BodyFraming::Chunked(_) => None,
+        }
+    }
+
+    /// Makes the RequestStream the handler is given for the body.
+    pub(crate) fn stream(&mut self, py: Python, active: Arc<AtomicBool>) -> PyResult<Py<RequestStream>> {
+        let stream = Py::new(py, RequestStream::live(active))?;
+        self.stream = Some(stream.clone_ref(py));
+        Ok(stream)
+    }
+
+    ///
+    /// Lets the stream read for itself through `connection` when it's
+    /// awaited away from the connection's task, see NextChunk.
+    ///
+    pub(crate) fn link(&self, py: Python, connection: &Py<OnceFuture>) {
+        if let Some(stream) = self.stream.as_ref() {
+            let mut stream = stream.borrow_mut(py);
+            if stream.connection.is_none() {
+                stream.connection = Some(connection.clone_ref(py));
+            }
+        }
+    }
+
+    /// Lets go of the connection once it's done with the request, whatever's left of the body can't be read now.
+    pub(crate) fn detach(&self, py: Python) {
+        if let Some(stream) = self.stream.as_ref() {
+            stream.borrow_mut(py).connection = None;
+        }
+    }
+
+    /// If something is waiting on the next piece of the body, the socket is only read for it when it is.
+    pub(crate) fn wanted(&self, py: Python) -> bool {
+        !self.done && self.stream.as_ref().is_some_and(|stream| stream.borrow(py).wanted)
+    }
+
+    ///
+    /// If the handler's own task is waiting on the next piece of the body
+    /// (it yielded to us rather than the loop, see NextChunk), it's only
+    /// stepped again once the body has given it something.
+    ///
+    pub(crate) fn parked(&self, py: Python) -> bool {
+        self.stream.as_ref().is_some_and(|stream| stream.borrow(py).parked)
+    }
+
+    /// Clears `parked` as the handler's stepped again.
+    pub(crate) fn unpark(&self, py: Python) {
+        if let Some(stream) = self.stream.as_ref() {
+            stream.borrow_mut(py).parked = false;
+        }
+    }
+
+    pub(crate) fn traverse(&self, visit: PyVisit) -> Result<(), PyTraverseError> {
+        match self.stream.as_ref() {
+            Some(stream) => visit.call(stream),
+            None => Ok(()),
+        }
+    }
+
+    ///
+    /// Internal Method: Inbound::take() -> Result<Taken, ChunkError>
+    ///
+    ///     Takes the next piece of the body off the front of `buffer`,
+    ///     `Done` once there's no more of it. A chunked body's framing
+    ///     goes with it, an incomplete size line is left for the next
+    ///     read to finish.
+    ///
+    pub(crate) fn take(&mut self, buffer: &mut Vec<u8>) -> Result<Taken, ChunkError> {
+        if self.done {
+            return Ok(Taken::Done)
+        }
+
+        let taken = match &mut self.framing {
+            BodyFraming::Length(0) => Taken::Done,
+            BodyFraming::Length(_) if buffer.is_empty() => Taken::More,
+            BodyFraming::Length(left) => {
+                let n = (*left).min(buffer.len());
+                *left -= n;
+                Taken::Chunk(buffer.drain(..n).collect())
+            },
+            BodyFraming::Chunked(decoder) => {
+                let used = decoder.feed(buffer)?;
+                buffer.drain(..used);
+
+                match (decoder.body().is_empty(), decoder.is_done()) {
+                    (false, _) => {
+                        let chunk = decoder.body().to_vec();
+                        decoder.clear_body();
+                        Taken::Chunk(chunk)
+                    },
+                    (true, true) => Taken::Done,
+                    (true, false) => Taken::More,
+                }
+            },
+        };
+
+        self.done = match (&taken, &self.framing) {
+            (Taken::Done, _) => true,
+            (_, BodyFraming::Length(left)) => *left == 0,
+            (_, BodyFraming::Chunked(decoder)) => decoder.is_done(),
+        };
+
+        Ok(taken)
+    }
+
+    ///
+    /// Throws away whatever of the body is already buffered once the
+    /// handler's done without reading all of it, true if that was the end
+    /// of it and the connection can carry on to its next request.
+    ///
+    pub(crate) fn skip_buffered(&mut self, buffer: &mut Vec<u8>) -> bool {
+        loop {
+            match self.take(buffer) {
+                Ok(Taken::Chunk(_)) => continue,
+                Ok(Taken::Done) => return true,
+                Ok(Taken::More) | Err(_) => return false,
+            }
+        }
+    }
+
+    /// Hands what was taken (a chunk or the end) to whatever is waiting on the stream.
+    pub(crate) fn deliver(&self, py: Python, taken: Taken) {
+        let mut stream = match self.stream.as_ref() {
+            Some(stream) => stream.borrow_mut(py),
+            None => return,
+        };
+
+        if let Taken::Chunk(chunk) = taken {
+            stream.pending = Some(chunk);
+        }
+        stream.done = self.done;
+        stream.wanted = false;
+    }
+
+    ///
+    /// Ends the stream with `error`, what it raises from then on. The body
+    /// never gets to its end so the connection is closed after the response.
+    ///
+    pub(crate) fn fail(&mut self, py: Python, error: StreamError) {
+        self.failed = Some(error);
+        if let Some(stream) = self.stream.as_ref() {
+            let mut stream = stream.borrow_mut(py);
+            stream.failed = Some(error);
+            stream.wanted = false;
+        }
+    }
+}
+
+
+///
+/// Why a streamed body ended before all of it was read.
+///
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum StreamError {
+    Malformed,      // The chunked framing was broken
+    TooLarge,       // A chunked body went past `max_body_size`
+    Closed,         // The client went away part way through
+}
+
+impl From<ChunkError> for StreamError {
+    fn from(e: ChunkError) -> Self {
+        match e {
+            ChunkError::Invalid => Self::Malformed,
+            ChunkError::TooLarge => Self::TooLarge,
+        }
+    }
+}
+
+impl StreamError {
+    fn to_err(self) -> PyErr {
+        match self {
+            Self::Malformed => BadRequest::new_err("malformed chunked body"),
+            Self::TooLarge => BadRequest::new_err("the body is bigger than max_body_size"),
+            Self::Closed => ConnectionClosed::new_err("the client went away before sending the whole body"),
+        }
+    }
+}
+
+
+///
+/// RequestStream is what `request.stream()` gives, an async iterator of
+/// the body as `bytes`. With `stream_request_body=True` it's fed straight
+/// from the connection as the handler asks for each piece, nothing is read
+/// off the socket until it does so a handler that's slow to consume holds
+/// the client up rather than the body building up in memory. Otherwise
+/// the body was already read in full and it's the one chunk.
+///
+/// Once it's exhausted the connection is right where the next request
+/// starts, a handler that finishes without exhausting it has whatever is
+/// left of the body thrown away if it's already buffered, if it isn't the
+/// connection is closed after the response.
+///
+#[pyclass]
+pub(crate) struct RequestStream {
+    pending: Option<Vec<u8>>,               // Read off the connection but not handed out yet
+    done: bool,                             // The last of the body has been read
+    ended: bool,                            // The handler's been told there's no more
+    failed: Option<StreamError>,            // Why the body couldn't be read to the end
+    wanted: bool,                           // Something's waiting on the next piece
+    parked: bool,                           // What's waiting is the handler's own task, see NextChunk
+    connection: Option<Py<OnceFuture>>,     // The connection we're reading from, once its task has yielded
+    active: Arc<AtomicBool>,                // The request's lease, see RequestLease
+}
+
+impl RequestStream {
+    fn live(active: Arc<AtomicBool>) -> Self {
+        Self {
+            pending: None,
+            done: false,
+            ended: false,
+            failed: None,
+            wanted: false,
+            parked: false,
+            connection: None,
+            active,
+        }
+    }
+
+    /// A body that's already been read in full, handed out as one chunk.
+    pub(crate) fn buffered(body: &[u8], active: Arc<AtomicBool>) -> Self {
+        Self {
+            pending: Some(body.to_vec()).filter(|body| !body.is_empty()),
+            done: true,
+            ..Self::live(active)
+        }
+    }
+
+    /// If the handler's been given all of the body, and told it has.
+    pub(crate) fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    ///
+    /// Internal Method: RequestStream::ready() -> Option<PyResult<PyObject>>
+    ///
+    ///     The next piece of the body if there's one to hand out, or the
+    ///     StopAsyncIteration (or what the body failed with) once there
+    ///     isn't going to be. `None` means it has to be read first.
+    ///
+    fn ready(&mut self, py: Python) -> Option<PyResult<PyObject>> {
+        if !self.active.load(Ordering::Acquire) {
+            return Some(Err(PyRuntimeError::new_err("request is no longer active")))
+        }
+
+        if let Some(chunk) = self.pending.take() {
+            return Some(Ok(PyBytes::new(py, &chunk).into()))
+        }
+
+        if let Some(error) = self.failed {
+            return Some(Err(error.to_err()))
+        }
+
+        if self.done {
+            self.ended = true;
+            return Some(Err(PyStopAsyncIteration::new_err(())))
+        }
+
+        None
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for RequestStream {
+    fn __aiter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __anext__(slf: PyRef<Self>) -> Option<NextChunk> {
+        Some(NextChunk::new(slf.into()))
+    }
+}
+
+#[pyproto]
+impl PyGCProtocol for RequestStream {
+    fn __traverse__(&self, visit: PyVisit) -> Result<(), PyTraverseError> {
+        if let Some(connection) = self.connection.as_ref() {
+            visit.call(connection)?;
+        }
+
+        Ok(())
+    }
+
+    fn __clear__(&mut self) {
+        self.connection = None;
+    }
+}
+
+
+///
+/// NextChunk is the awaitable behind `RequestStream.__anext__`. Awaited
+/// from the handler's own task it yields straight back to the connection
+/// (which is what's stepping it) and the connection reads the socket until
+/// there's something for it before stepping it again. From any other task
+/// the connection isn't busy so it reads for itself, sleeping on the loop
+/// while the socket would block the same way OnceFuture does.
+///
+#[pyclass]
+pub(crate) struct NextChunk {
+    stream: Py<RequestStream>,
+    sleeper: Option<LoopSleeper>,   // Made the first time we have to wait away from the connection's task
+}
+
+impl NextChunk {
+    pub(crate) fn new(stream: Py<RequestStream>) -> Self {
+        Self { stream, sleeper: None }
+    }
+
+    ///
+    /// Internal Method: NextChunk::poll() -> PyResult<IterNextOutput<PyObject, PyObject>>
+    ///
+    ///     A step of the await, `Return` is the next chunk and the end of
+    ///     the body is a StopAsyncIteration.
+    ///
+    pub(crate) fn poll(&mut self, py: Python) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        let mut stream = self.stream.borrow_mut(py);
+        if let Some(ready) = stream.ready(py) {
+            return ready.map(IterNextOutput::Return)
+        }
+
+        stream.wanted = true;
+        let connection = match stream.connection.as_ref() {
+            Some(connection) => connection.clone_ref(py),
+
+            // the connection's task hasn't yielded since the request was
+            // made, we can only be running from it
+            None => {
+                stream.parked = true;
+                return Ok(IterNextOutput::Yield(py.None()))
+            },
+        };
+        drop(stream);
+
+        match connection.try_borrow_mut(py) {
+            Ok(mut connection) => {
+                let _ = connection.pull_body(py);
+            },
+            Err(_) => {
+                self.stream.borrow_mut(py).parked = true;
+                return Ok(IterNextOutput::Yield(py.None()))
+            },
+        }
+
+        if let Some(ready) = self.stream.borrow_mut(py).ready(py) {
+            return ready.map(IterNextOutput::Return)
+        }
+
+        let sleeper = match self.sleeper.as_mut() {
+            Some(sleeper) => sleeper,
+            None => self.sleeper.get_or_insert(LoopSleeper::for_connection(crate::get_loop(py)?.into())),
+        };
+        Ok(IterNextOutput::Yield(sleeper._iter_sleep(py).unwrap_or_else(|| py.None())))
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for NextChunk {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for NextChunk {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+        slf.poll(py)
+    }
+}
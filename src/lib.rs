@@ -2,12 +2,102 @@ use pyo3::prelude::*;
 use pyo3::PyIterProtocol;
 use pyo3::class::pyasync::PyAsyncProtocol;
 use pyo3::class::iter::IterNextOutput;
+use pyo3::class::gc::{PyGCProtocol, PyTraverseError, PyVisit};
 
-use std::net::{TcpListener, TcpStream};
+use bytes::Bytes;
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::io;
 use std::io::prelude::*;
-use std::collections::HashMap;
-use bstr::ByteSlice;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+mod acceptor;
+mod acl;
+mod asgi;
+mod body;
+mod client;
+mod compress;
+mod context;
+mod cookie;
+mod cors;
+mod datagram;
+mod deflate;
+mod endpoint;
+mod errors;
+mod file;
+mod forwarded;
+mod h2;
+mod headers;
+mod hpack;
+mod http;
+mod inbound;
+mod listener;
+mod log;
+mod memory;
+mod middleware;
+mod multipart;
+mod options;
+mod outgoing;
+mod pool;
+mod prehandler;
+mod proxy;
+mod ratelimit;
+mod request_id;
+#[cfg(target_os = "linux")]
+mod reactor;
+mod router;
+mod server;
+mod sleep;
+mod spool;
+mod sse;
+mod stats;
+mod stream;
+mod throttle;
+mod tls;
+mod tunnel;
+mod upgrade;
+mod vhost;
+mod websocket;
+mod worker;
+mod wsgi;
+
+use asgi::{ASGIApp, ASGICall};
+use body::{BodyStep, BodyStream, StreamStep};
+use client::Connect;
+use compress::Encoding;
+use datagram::AsyncDatagramRunner;
+use endpoint::{Endpoint, ListenerSpec};
+use file::{Conditional, FileBody, FileResponse};
+use h2::{Exchange, H2Connection};
+use headers::{HeaderName, Headers};
+use http::{BodyFraming, ChunkError, ChunkedDecoder, DateCache, HTTPRequest, HTTPResponse, HeadError, RequestBody, RequestHead, RequestLease, TargetForm};
+use inbound::{Inbound, StreamError, Taken};
+use listener::{AcceptPause, BindAddr, BindFailed, ClientOptions, Listener};
+use memory::MemoryCharge;
+use middleware::{Middleware, MiddlewareCall};
+use multipart::MultipartPart;
+use options::{AcceptMode, ReactorKind, RunnerOptions, SniMismatch};
+use outgoing::Outgoing;
+use pool::ConnectionPool;
+use prehandler::{Linger, RequestMeta};
+use ratelimit::Verdict;
+use router::Router;
+use sleep::{Awaiting, LoopSleeper};
+use spool::Spool;
+use sse::EventSourceResponse;
+use stats::{ActiveGuard, ConnectionActivity, ConnectionInfo, Profile, RequestTimings, Section, ServerStats, Stage};
+use stream::{Reader, SharedTransport, Transport, Writer};
+use throttle::WriteThrottle;
+use tls::{TLSConfig, TLSInfo, TlsSession};
+use tunnel::Tunnel;
+use upgrade::Upgrade;
+use vhost::VirtualHosts;
+use websocket::WebSocketConnection;
+use worker::{WorkerHandoff, WorkerPool};
+use wsgi::WSGIApp;
+use pyo3::types::{PyBytes, PyDict, PyTuple, PyType};
+use pyo3::exceptions::{PyAttributeError, PyException, PyNotImplementedError, PyRuntimeError, PyStopIteration, PyTypeError, PyValueError};
 
 
 ///
@@ -17,438 +107,5599 @@ use bstr::ByteSlice;
 /// in python returning a result should asyncio not exist 
 /// (just a rust thing)
 ///
-fn get_loop(py: Python) -> PyResult<&PyAny> {
+fn get_loop(py: Python<'_>) -> PyResult<&PyAny> {
     let asyncio = py.import("asyncio")?;
-    Ok(asyncio.call0("get_event_loop")?)
+    asyncio.call0("get_event_loop")
+}
+
+///
+/// If the error is asyncio's CancelledError, these must always be re-raised
+/// so cancelling a task actually cancels it.
+///
+fn is_cancelled(py: Python, e: &PyErr) -> bool {
+    match py.import("asyncio").and_then(|asyncio| asyncio.getattr("CancelledError")) {
+        Ok(cancelled) => e.matches(py, cancelled),
+        Err(_) => false,
+    }
+}
+
+///
+/// The formatted traceback of an error, the same as python would print it.
+///
+fn format_traceback(py: Python, e: &PyErr) -> PyResult<String> {
+    let lines = py.import("traceback")?.call1(
+        "format_exception",
+        (e.ptype(py), e.pvalue(py), e.ptraceback(py)),
+    )?;
+
+    let lines: Vec<String> = lines.extract()?;
+    Ok(lines.concat())
+}
+
+/// The `async_rust.access` logger every request is logged to.
+fn access_logger(py: Python) -> PyResult<PyObject> {
+    Ok(py.import("logging")?.call1("getLogger", ("async_rust.access",))?.into())
 }
 
 ///
-/// AsynServer represents the actual Rust TCP listener 
-/// it initially binds to the address on creation with new(),
+/// AsynServer represents the actual Rust listeners
+/// it initially binds to the addresses on creation with bind_all(),
 /// accept_client() can be called to get the next tcp stream,
 /// because this is for asyncio we want this to be non-blocking so
-/// we set none-blocking on the socket. accept_client will return 
-/// either None or a TcpStream.
-/// 
+/// we set none-blocking on the sockets. accept_client will return
+/// either None or a TcpStream and the index of the listener it came off,
+/// taking turns between the listeners.
+///
 /// ```
-/// let server = AsyncServer::new("127.0.0.1:8080");
-/// 
+/// let server = AsyncServer::bind_all(&["localhost:8080", "unix:/tmp/app.sock"], true)?;
+///
 /// let next_client = server.accept_client();
 /// println!("{:?}", next_client);
 /// ```
-///  
+///
 struct AsyncServer {
-    listener: TcpListener,
+    listeners: Vec<Listener>,   // Empty once the server has been closed
+    addrs: Vec<BindAddr>,       // Where the listeners were bound, for reopening them after a close
+    next: usize,                // The listener accept_client tries first, so none of them get starved
+    backlog: Option<i32>,       // What we passed to listen(), None for a socket handed to us already listening
+    client_options: ClientOptions,  // Set on every client accepted from a TCP listener
+    fastopen: Option<u32>,          // The TCP Fast Open queue, set on every TCP listener (again after a reopen)
+    pause: AcceptPause,             // Stops accept_client trying for a while once we're out of fds
 }
 
 impl AsyncServer {
-    fn new(addr: String) -> Self {
-        let listener = TcpListener::bind(addr).unwrap();
-        listener.set_nonblocking(true).expect("Cannot set non-blocking");
-
-        Self { listener }
-    }
-
-    fn accept_client(&mut self) -> Option<TcpStream> {
-        return match self.listener.incoming().next() {
-            Some(s) => {
-                match s {
-                    Ok(res) => Some(res),
-                    Err(ref er) if er.kind() == io::ErrorKind::WouldBlock => None,
-                    Err(er) => {
-                        eprintln!("{}", er);
-                        None
-                    },
-                }
-            }
-            _ => {
-                None
-            }
-        };
-    }
-}
+    ///
+    /// Binds a listener for every address, if any of them fails the ones
+    /// already bound are dropped again and the error says which address
+    /// it was. Hostnames are resolved unless `resolve` is off, which can
+    /// block so this should be called without the GIL.
+    ///
+    fn bind_all(addrs: &[impl AsRef<str>], resolve: bool, backlog: i32) -> io::Result<Self> {
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let addr = addr.as_ref();
+            let listener = Listener::bind(addr, resolve, backlog)
+                .map_err(|e| io::Error::new(e.kind(), BindFailed { addr: addr.to_string(), source: e }))?;
+            listeners.push(listener);
+        }
 
+        Self::from_listeners(listeners, Some(backlog))
+    }
 
-///
-/// The AsyncServerRunner struct houses the TCP listener and sparks the async tasks,
-/// it has a integral clock delay set to n to save cpu todo: find the right match.
-///
-#[pyclass]
-struct AsyncServerRunner {
-    // External inputs
-    callback: PyObject,
+    ///
+    /// Wraps an already bound and listening socket, the fd is checked to
+    /// actually be a listening TCP socket before we take ownership of it so
+    /// a bad fd is left untouched for the caller to deal with.
+    ///
+    /// Once wrapped the listener owns the fd and closes it when dropped.
+    /// `FD_CLOEXEC` is set on it again so one inherited through an exec
+    /// (see `export_listener()`) doesn't leak on into our own children.
+    ///
+    #[cfg(unix)]
+    fn from_fd(fd: i32) -> io::Result<Self> {
+        use std::os::unix::io::FromRawFd;
 
-    // Internal systems
-    server: AsyncServer,        // The non-blocking TCP listener Struct
-    server_state: u8,           // A int representing the asyncio state, either 0, 1, 2 or Error
-    server_exit: bool,          // A bool to signal if the server should shutdown and return
-    loop_: PyObject,            // The asyncio event loop
-    fut: Option<Py<PyAny>>,     // The temporary future to house the sleep future to save CPU
-    internal_clock_delay: f32,  // the delay between loop iterations.
+        fn sockopt(fd: i32, opt: libc::c_int) -> io::Result<libc::c_int> {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let res = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    opt,
+                    &mut value as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            };
 
-}
+            if res == -1 {
+                return Err(io::Error::last_os_error())
+            }
 
+            Ok(value)
+        }
 
-#[pymethods]
-impl AsyncServerRunner {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(io::Error::last_os_error())
+        }
 
-    ///
-    /// PythonMethod: AsyncServerRunner::new() -> Self
-    /// 
-    ///     new() creates the AsyncServer instance and aquires the asyncio
-    ///     event loop, default state is set to `0`, server exit `false`,
-    ///     clock delay `0.05`.
-    /// 
-    ///     Requires:
-    ///         - binding_addr: String
-    ///         - callback:     PyObject
-    ///
-    #[new]
-    fn new(binding_addr: String, callback: PyObject) -> Self {
-        println!("Connecting to {}", &binding_addr);
+        if sockopt(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "fd is not a stream socket"))
+        }
 
-        let server = AsyncServer::new(binding_addr);
-        let loop_ = {
-            let gil = Python::acquire_gil();
-            let py = gil.python();
-            get_loop(py).unwrap().into_py(py)
-        };
+        if sockopt(fd, libc::SO_ACCEPTCONN)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "socket is not listening"))
+        }
 
-        AsyncServerRunner {
-            server,
-            server_state: 0,
-            server_exit: false,
-            loop_,
-            fut: None,
-            internal_clock_delay: 0.01,
-            callback,
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
         }
-    }
-}
 
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
 
-///  
-/// This implementation houses the intenal functions for creating a non-blocking
-/// delay on the event loop to save cpu.
-///   
-impl AsyncServerRunner {
+        Self::from_listeners(vec![Listener::Tcp(listener)], None)
+    }
 
-    /// 
-    /// Internal Method: AsyncServerRunner._sleep() -> PyResult<()>
-    ///     
-    ///     _sleep recreated what asyncio.sleep() does, internally
-    ///     it calls loop.create_future() on the running event loop, aquires the 
-    ///     asyncio.futures module, and then calles loop.call_later() using
-    ///     `AsyncServerRunner.internal_clock_delay` as the delay to then invoke
-    ///     future's private method `_set_result_unless_cancelled`. After the future
-    ///     has been set we just set the future to the iterator to yeild from.
-    ///     
-    ///     Note:
-    ///         I used `_set_result_unless_cancelled` because I was getting
-    ///         a error or it just not waiting at all with set_result or using
-    ///         a normal callback, this system is just a plain copy of asyncio.sleep.
-    ///         
-    ///     Requires:
-    ///         - py: Python
-    /// 
-    fn _sleep(&mut self, py: Python) -> PyResult<()> {
-        self.fut = Option::from(self.loop_.call_method0(py, "create_future")?);
+    ///
+    /// Binds with `SO_REUSEPORT` set so several processes can each have
+    /// their own listener on the same port, the kernel then balances new
+    /// connections between them.
+    ///
+    #[cfg(unix)]
+    fn bind_reuse_port(addr: SocketAddr, backlog: i32) -> io::Result<Self> {
+        use socket2::{Domain, Socket, Type};
 
-        let futures = py.import("asyncio")?.get("futures")?;
-        let _ = self.loop_.call_method1(
-            py,
-            "call_later",
-            (
-                self.internal_clock_delay,
-                futures.getattr("_set_result_unless_cancelled")?,
-                self.fut.as_ref(),
-                "",
-            )
-        );
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(backlog)?;
 
-        self.fut = Option::from(
-            self.fut
-                .as_ref()
-                .unwrap()
-                .call_method0(py, "__iter__")?
-        );
+        let listener: TcpListener = socket.into();
+        listener.set_nonblocking(true)?;
 
-        Ok(())
+        Self::from_listeners(vec![Listener::Tcp(listener)], Some(backlog))
     }
 
-    /// 
-    /// Internal Method: AsyncServerRunner._iter_sleep() -> Option<PyObject>
-    ///    
-    ///     _iter_sleep is what actually yields the next iteration the future,
-    ///     you could interprete this has `yield from` in python just with more
-    ///     steps involved.
-    /// 
-    fn _iter_sleep(&mut self) -> Option<PyObject> {
-        let gil = Python::acquire_gil();
-        let py = gil.python();
+    #[cfg(not(unix))]
+    fn bind_reuse_port(_addr: SocketAddr, _backlog: i32) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Other, "SO_REUSEPORT is only supported on unix"))
+    }
 
-        // if the future isnt set we'll create a new one
-        if self.fut.is_none() {
-            let _ = self._sleep(py);
-        }
+    #[cfg(not(unix))]
+    fn from_fd(_fd: i32) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Other, "constructing from a fd is only supported on unix"))
+    }
 
-        let nxt = self.fut
-            .as_ref()
-            .unwrap()
-            .call_method0(py, "__next__");
+    fn from_listeners(listeners: Vec<Listener>, backlog: Option<i32>) -> io::Result<Self> {
+        let addrs = listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<io::Result<Vec<_>>>()?;
 
-        return match nxt {
-            Ok(f) => Some(f),
-            Err(_) => {
-                self.server_state = 1;
-                self.fut = None;
+        Ok(Self {
+            listeners,
+            addrs,
+            next: 0,
+            backlog,
+            client_options: ClientOptions::default(),
+            fastopen: None,
+            pause: RunnerOptions::default().accept_pause(),
+        })
+    }
 
-                None
-            },
+    /// Turns on TCP Fast Open for the TCP listeners, see `listener::set_fastopen()`.
+    fn set_fastopen(&mut self, queue: Option<u32>) {
+        self.fastopen = queue;
+        if let Some(queue) = queue {
+            for listener in self.listeners.iter().filter_map(Listener::as_tcp) {
+                listener::set_fastopen(listener, queue);
+            }
         }
     }
-}
 
-/// 
-/// This implementation adds the required __await__ dunder for
-/// python to use a coroutine, it just simply returns itself
-/// 
-#[pyproto]
-impl PyAsyncProtocol for AsyncServerRunner {
-    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
-        slf
-    }
-}
+    ///
+    /// Binds fresh listeners to the addresses we had before `close()`, so
+    /// a server bound to port `0` comes back on the same port. Nothing
+    /// happens if the listeners are still open.
+    ///
+    fn reopen(&mut self) -> io::Result<()> {
+        if !self.listeners.is_empty() {
+            return Ok(())
+        }
 
-///
-/// This applies the iterator magic methods for producing a coroutine,
-/// becauase asyncio is built entirely off of generators to give concurrency
-/// we much build our objects top behaving like generators aswell, hence the use
-/// of the IterProtocol.
-/// 
-#[pyproto]
-impl PyIterProtocol for AsyncServerRunner {
+        if self.addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "the server never had an address to reopen"))
+        }
 
-    /// 
-    /// Iter is just a required dunder so we return ourselves as the 
-    /// iterator.
-    /// 
-    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
-        slf
+        let backlog = self.backlog.unwrap_or(listener::DEFAULT_BACKLOG);
+        self.listeners = self.addrs
+            .iter()
+            .map(|addr| Listener::rebind(addr, backlog))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.set_fastopen(self.fastopen);
+        Ok(())
     }
 
-    /// 
-    /// __next__ is what does all the actual work, this gets called every time by the event
-    /// loop, Pyo3 gives us some useful helpers to replicate what yield and Return do.
-    /// 
-    /// If we yield it tells the event loop we still have something todo and we'll get called again
-    /// this is useful if we plan on making a loop at anypoint because we cannot just do a standard 
-    /// loop otherwise we would block.
-    /// 
-    /// when `server_state` is 0 we can use this to setup anything before yielding from another coro
-    /// or in this case polling the server listener.
-    /// 
-    /// when `server_state` is 1 we poll our listener for a client or None, this is what is actually
-    /// yielding everything other than if we set to state 2 where we sleep for x time.
-    /// 
-    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
-        // setup futures
-        if slf.server_state == 0 {
-            slf.server_state = 1;
+    /// The first TCP address we're listening on, the one workers share.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        if self.listeners.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "the server has been closed"))
         }
 
-        // yield futures
-        if slf.server_state == 1 {
-            let client = slf.server.accept_client();
+        match self.listeners.iter().find_map(Listener::as_tcp) {
+            Some(listener) => listener.local_addr(),
+            None => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "the server has no TCP listener")),
+        }
+    }
 
-            // if we have a client connecting we will get it as Some()
-            if client.is_some() {
+    ///
+    /// Drops the listeners so the ports are released straight away rather
+    /// than whenever python gets round to collecting the runner.
+    ///
+    fn close(&mut self) {
+        self.listeners.clear();
+    }
 
-                // todo create task then parse stuff.
-                let cli = client.unwrap();
-                cli.set_nonblocking(true);
-                let gil = Python::acquire_gil();
-                let py = gil.python();
-                let asyncio = py.import("asyncio")?;
-                let caller = OnceFuture::new(Stream::new(cli));
-                let _task = asyncio.call1( "ensure_future", (caller,))?;
+    fn accept_client(&mut self) -> Option<(TcpStream, usize)> {
+        if self.pause.remaining().is_some() {
+            return None
+        }
 
-                return Ok(IterNextOutput::Yield(None))
-            }
-            
-            // Should we stop the server?
-            if slf.server_exit {
-                return Ok(IterNextOutput::Return(None))
-            }
+        for _ in 0..self.listeners.len() {
+            let index = self.next % self.listeners.len();
+            self.next = index + 1;
 
-            // Lets change our sleep so we sleep for a bit
-            slf.server_state = 2;
-        }
+            let listener = &self.listeners[index];
+            match listener.accept() {
+                Ok(res) => {
+                    if listener.as_tcp().is_some() {
+                        self.client_options.apply(&res);
+                    }
 
-        // Sleep x time (save cpu)
-        if slf.server_state == 2 {
-            return Ok(IterNextOutput::Yield(slf._iter_sleep()))
+                    return Some((res, index))
+                },
+                Err(ref er) if er.kind() == io::ErrorKind::WouldBlock => {},
+                Err(er) => {
+                    if self.pause.failed(&er) {
+                        return None
+                    }
+                },
+            }
         }
 
-        // Invalid state
-        return Ok(IterNextOutput::Return(None))
+        None
     }
 }
 
 
 ///
-/// This struct is hell, litterally. It creates a 'false' Clone
-/// implementation to allow Pyo3 to use it. This should NOT be allowed
-/// to be accessible as a public interface because this ignores some required
-/// functions like extract to convert pyobjects into it.
-///
-/// This is a one way Object only.
+/// Where an AsyncServerRunner's accept loop is up to, `Draining` means
+/// we've been closed and return the next time we're polled, `Stopped` that
+/// we already have.
 ///
-struct Stream {
-    internal_stream: Option<TcpStream>
+#[derive(Clone, Copy, PartialEq)]
+enum ServerState {
+    Init,
+    Accepting,
+    Sleeping,
+    Draining,
+    Stopped,
 }
 
-impl Stream {
-    fn new(stream: TcpStream) -> Self {
-        Self {
-            internal_stream: Some(stream)
+impl ServerState {
+    fn name(self) -> &'static str {
+        match self {
+            ServerState::Init => "init",
+            ServerState::Accepting => "accepting",
+            ServerState::Sleeping => "sleeping",
+            ServerState::Draining => "draining",
+            ServerState::Stopped => "stopped",
         }
     }
 }
 
-impl Clone for Stream {
-    fn clone(&self) -> Self {
-        // We'll just try clone this and hope it works
-        // note: I have no idea what issues this can cause
-        //       later on so...
-        Self {
-            internal_stream: Some(
-                self.internal_stream
-                    .as_ref()
-                    .unwrap()
-                    .try_clone()
-                    .unwrap(),
-            )
-        }
-    }
-}
 
-impl pyo3::conversion::FromPyObject<'_> for Stream {
-    fn extract(_ob: &PyAny) -> PyResult<Self> {
-        // Cant recreate a listener like this.
-        Ok(Self {
-            internal_stream: None
-        })
-    }
+///
+/// The AsyncServerRunner struct houses the TCP listener and sparks the async tasks,
+/// while no clients arrive it sleeps between polls, backing off from
+/// `min_poll_delay` to `max_poll_delay` so an idle server costs next to nothing.
+///
+#[pyclass(gc)]
+struct AsyncServerRunner {
+    // External inputs
+    callback: PyObject,
+    options: Arc<RunnerOptions>,    // The keyword options shared by all the constructors
+    kwargs: Option<Py<PyDict>>,     // What `options` were parsed from, for `config()` and `update_config()`
+    middleware: Option<Py<Middleware>>, // Wraps the callback once `add_middleware()` is used, it's the callback then
+
+    // Internal systems
+    server: AsyncServer,        // The non-blocking TCP listener Struct
+    endpoints: Vec<Endpoint>,   // How the clients of each of `server.listeners` are served, in the same order
+    server_state: ServerState,  // Where the accept loop is up to, see ServerState
+    awaited: bool,              // Set once something awaits us, a runner can only be awaited once
+    closed: bool,               // Set by `close()`, the runner can't be started or awaited again
+    transfer: Option<Option<Instant>>,  // Set by `transfer_and_drain()` with its deadline, we return once the connections are gone
+    on_shutdown: Option<(PyObject, Option<Instant>)>,   // An async `on_shutdown` `graceful_shutdown()` is waiting on before closing, with its deadline
+    signal_loop: Option<PyObject>,  // The loop `install_signal_handlers()` put our SIGTERM handler on
+    ready_waiters: Vec<PyObject>,   // The futures from `wait_ready()` waiting for us to start accepting
+    context: Option<PyObject>,  // A copy of the `contextvars.Context` we were awaited in, every connection's task runs in a copy of it
+    loop_: PyObject,            // The asyncio event loop
+    sleeper: LoopSleeper,       // The non-blocking sleep between loop iterations to save CPU
+    workers: Option<WorkerPool>,    // The spawned worker processes when we're the parent
+    worker_id: usize,           // 0 for the parent / single process, 1.. for workers
+    access_logger: Option<PyObject>,    // `async_rust.access` unless no listener logs requests
+    stats: Arc<ServerStats>,    // The counters behind `stats()`
+    profile: Option<Arc<Profile>>,  // The section timings behind `profile_snapshot()` with `profiling=True`
+    tasks: PyObject,            // The connection tasks still running, mapped to their ConnectionInfo
+    housekeeping: Instant,      // When the next pass over `tasks` for idle connections is due, see `housekeep()`
+    exception_handler: Arc<Mutex<Option<PyObject>>>,    // From `set_exception_handler()`, shared with every task's TaskDone
+    date: Arc<DateCache>,       // The `Date` header shared by every connection
+    #[cfg(target_os = "linux")]
+    native: Option<reactor::NativeReactor>, // The reactor thread with `reactor="native"`
+    #[cfg(unix)]
+    acceptor: Option<acceptor::AcceptThread>,   // The accept thread with `accept_mode="thread"`
+
 }
 
 
-/// Wraps a Python future and TCP stream
-#[pyclass]
-struct OnceFuture {
-    // External parameters
-    stream: Stream,
+#[pymethods]
+impl AsyncServerRunner {
 
+    ///
+    /// PythonMethod: AsyncServerRunner::new() -> Self
+    /// 
+    ///     new() binds the listeners and aquires the asyncio event loop.
+    ///     The runner starts out in `ServerState::Init`, moves between
+    ///     `Accepting` and `Sleeping` once it's awaited, and is `Draining`
+    ///     then `Stopped` after `close()` or `stop()` (see `state`). How it
+    ///     serves is down to the keyword options parsed into RunnerOptions,
+    ///     the accept poll backing off from `min_poll_delay` to
+    ///     `max_poll_delay` among them.
+    /// 
+    ///     Requires:
+    ///         - binding_addr: str | list[str]
+    ///         - callback:     PyObject
+    ///
+    ///     Optional:
+    ///         - workers:      int        (processes sharing the port via SO_REUSEPORT)
+    ///         - **options:    see RunnerOptions (tls, websocket, access_log ...)
+    ///
+    ///     With `workers > 1` the script is re-run `workers - 1` times, each
+    ///     worker builds its own runner which binds to the address the parent
+    ///     actually got (so port `0` works) and the parent terminates them
+    ///     when it shuts down. This means the runner must be constructed from a
+    ///     script which can safely be re-run, not `python -c` or a REPL.
+    ///
+    ///     The callback can be a dict of host names to handlers instead, the
+    ///     request goes to the handler for its Host (see VirtualHosts).
+    ///
+    ///     A list of addresses listens on all of them with the same callback,
+    ///     `unix:/path/to.sock` binds a unix socket and on linux
+    ///     `unix-abstract:name` (or `unix:@name`) an abstract one, elsewhere
+    ///     that's a NotImplementedError. If one of them can't be bound the
+    ///     rest are closed again and BindError is raised (ParseError for an
+    ///     address that isn't one). Workers
+    ///     only support a single TCP address.
+    ///
+    ///     An address in the list can be a dict instead, giving that
+    ///     listener a name, callback and options of its own (see
+    ///     ListenerSpec), e.g. a unix socket for an admin API with its own
+    ///     handler and tighter limits. Those aren't supported with workers,
+    ///     `reactor="native"` or `accept_mode="thread"` yet.
+    ///
+    #[new]
+    #[args(workers = "1", options = "**")]
+    fn new(
+        py: Python,
+        binding_addr: &PyAny,
+        callback: PyObject,
+        workers: usize,
+        options: Option<&PyDict>,
+    ) -> PyResult<Self> {
+        let kwargs = options;
+        let options = RunnerOptions::from_kwargs(kwargs)?;
+        let specs = match binding_addr.extract::<String>() {
+            Ok(_) => vec![ListenerSpec::extract(py, binding_addr)?],
+            Err(_) => binding_addr.iter()?.map(|spec| ListenerSpec::extract(py, spec?)).collect::<PyResult<Vec<_>>>()?,
+        };
+        let addrs: Vec<String> = specs.iter().map(|spec| spec.addr.clone()).collect();
 
+        if workers > 1 && specs.iter().any(|spec| !spec.is_plain()) {
+            return Err(PyValueError::new_err("listener dicts can't be used with workers yet"))
+        }
 
-    // Internals
-    state: u8,
+        // we were spawned by a parent, share its port rather than binding our own
+        if let Some(handoff) = WorkerHandoff::from_env() {
+            let server = AsyncServer::bind_reuse_port(handoff.addr, options.backlog).map_err(|e| errors::bind_error(py, e))?;
+            let mut runner = Self::with_server(py, server, callback, options, kwargs)?;
+            runner.worker_id = handoff.index;
+            return Ok(runner)
+        }
 
-}
+        log::info(&format!("Connecting to {}", addrs.join(", ")));
 
-#[pymethods]
-impl OnceFuture {
-    #[new]
-    fn new(stream: Stream) -> Self {
-        OnceFuture {
-            stream,
-            state: 0,
+        let (resolve, backlog) = (options.resolve, options.backlog);
+        if workers <= 1 {
+            let server = py.allow_threads(|| AsyncServer::bind_all(&addrs, resolve, backlog)).map_err(|e| errors::bind_error(py, e))?;
+            let mut runner = Self::with_server(py, server, callback, options, kwargs)?;
+            runner.serve_listeners(py, specs, kwargs)?;
+            return Ok(runner)
         }
-    }
-}
 
-#[pyproto]
-impl PyAsyncProtocol for OnceFuture {
-    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
-        slf
+        let binding_addr = match addrs.as_slice() {
+            [addr] => addr,
+            _ => return Err(PyValueError::new_err("workers can only share a single bind address")),
+        };
+
+        let addr = py.allow_threads(|| listener::resolve(binding_addr, resolve)).map_err(|e| errors::bind_error(py, e))?[0];
+
+        let server = AsyncServer::bind_reuse_port(addr, backlog).map_err(|e| errors::bind_error(py, e))?;
+        let pool = WorkerPool::spawn(py, workers - 1, server.local_addr()?)?;
+
+        let mut runner = Self::with_server(py, server, callback, options, kwargs)?;
+        runner.workers = Some(pool);
+        Ok(runner)
     }
-}
 
-#[pyproto]
-impl PyIterProtocol for OnceFuture {
-    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
-        slf
+    ///
+    /// PythonMethod: AsyncServerRunner.from_fd() -> Self
+    ///
+    ///     Builds the runner around a socket that is already bound and
+    ///     listening instead of binding one ourselves, e.g. one handed over
+    ///     by a supervisor process.
+    ///
+    ///     The runner takes ownership of the fd and closes it when it is
+    ///     dropped, so `os.dup()` it first if it needs to outlive the runner.
+    ///     Invalid fds raise `BindError` and are left open. This is the
+    ///     new process's half of a reload, see `export_listener()`.
+    ///
+    ///     Requires:
+    ///         - fd:           int
+    ///         - callback:     PyObject
+    ///
+    ///     Optional:
+    ///         - **options:    see RunnerOptions
+    ///
+    #[classmethod]
+    #[args(options = "**")]
+    fn from_fd(
+        _cls: &PyType,
+        py: Python,
+        fd: i32,
+        callback: PyObject,
+        options: Option<&PyDict>,
+    ) -> PyResult<Self> {
+        let kwargs = options;
+        let options = RunnerOptions::from_kwargs(kwargs)?;
+        let server = AsyncServer::from_fd(fd).map_err(|e| errors::bind_error(py, e))?;
+        Self::with_server(py, server, callback, options, kwargs)
     }
-    fn __next__(
-        slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> { // PyResult<IterNextOutput<Option<PyObject>, Option<(String, String, String, HashMap<String, String>)>>> {
 
-        //let parsed = parse_partial(
-        //    slf.stream.internal_stream.as_ref().unwrap())?;
-        let _ = slf.stream.internal_stream.as_ref().unwrap().write_all(b"HTTP/1.1 200 OK\r\n\r\n");
+    ///
+    /// PythonMethod: AsyncServerRunner.from_systemd() -> Self
+    ///
+    ///     Uses the socket passed in via systemd socket activation, this
+    ///     checks `LISTEN_PID` is us and takes the first fd from
+    ///     `LISTEN_FDS` (fd 3). The variables are removed afterwards so child
+    ///     processes don't try to take the same socket.
+    ///
+    ///     Requires:
+    ///         - callback:     PyObject
+    ///
+    ///     Optional:
+    ///         - **options:    see RunnerOptions
+    ///
+    #[classmethod]
+    #[args(options = "**")]
+    fn from_systemd(
+        _cls: &PyType,
+        py: Python,
+        callback: PyObject,
+        options: Option<&PyDict>,
+    ) -> PyResult<Self> {
+        let kwargs = options;
+        let options = RunnerOptions::from_kwargs(kwargs)?;
+        const SD_LISTEN_FDS_START: i32 = 3;
+
+        let pid = std::env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+        let fds = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok());
 
-        Ok(IterNextOutput::Return(None))
+        match (pid, fds) {
+            (Some(pid), Some(fds)) if pid == std::process::id() && fds >= 1 => {},
+            _ => return Err(errors::BindError::new_err(
+                "no sockets were passed in by systemd (LISTEN_PID / LISTEN_FDS not set for this process)"
+            )),
+        }
+
+        // going through os.environ keeps python's copy of the environment in sync
+        let environ = py.import("os")?.getattr("environ")?;
+        for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            environ.call_method1("pop", (*name, py.None()))?;
+        }
+
+        let server = AsyncServer::from_fd(SD_LISTEN_FDS_START).map_err(|e| errors::bind_error(py, e))?;
+        Self::with_server(py, server, callback, options, kwargs)
     }
-}
 
-#[pyclass]
-#[derive(Debug)]
-struct HTTPRequest {
-    method: &'static str,
-    path: &'static str,
-    protocol: &'static str,
-    headers: HashMap<String, String>,
-}
-
-///
-/// Parses a tcp stream reading the headers; repeat until complete
-/// todo: add a better parser
-fn parse_partial(stream: &TcpStream) -> PyResult<(String, String, String, HashMap<String, String>)> {
-    let mut reader = io::BufReader::new(stream);
-
-    const MAX_HEADER_COUNT: usize = 32;
-
-    let mut headers: HashMap<String, String> = HashMap::default();
-    let mut method = String::new();
-    let mut path= String::new();
-    let mut protocol= String::new();
-
-    for i in 0..MAX_HEADER_COUNT {
-        let mut buff = Vec::with_capacity(1024);
-        let n = reader.read_until(b'\n', &mut buff)?;
-        let _ = buff.split_off(if n >= 2 {n-2} else {0});
-        if &buff == b"" {
-            break
-        }
-
-        if i != 0 {
-            let mut iter = buff.splitn_str(2, b": ");
-            headers.insert(
-                String::from_utf8(
-                    Vec::from(iter.next().unwrap())
-                )?,
-                String::from_utf8(
-                    Vec::from(iter.next().unwrap().trim_start())
-                )?
-            );
-        } else {
-            let mut items =  buff.split_str( b" ").into_iter();
+    ///
+    /// PythonMethod: AsyncServerRunner.local_addr() -> (str, int)
+    ///
+    ///     The `(host, port)` the listener actually bound to, this is mostly
+    ///     useful when binding to port `0` and letting the OS pick one. With
+    ///     several addresses it's the first TCP one, see `local_addrs()`.
+    ///
+    fn local_addr(&self) -> PyResult<(String, u16)> {
+        let addr = self.server.local_addr()?;
+        Ok((addr.ip().to_string(), addr.port()))
+    }
 
-            method = String::from_utf8_lossy(items.next().unwrap()).parse()?;
-            path = String::from_utf8_lossy(items.next().unwrap()).parse()?;
-            protocol = String::from_utf8_lossy(items.next().unwrap()).parse()?;
+    ///
+    /// PythonMethod: AsyncServerRunner.local_addrs() -> list[(str, int) | str]
+    ///
+    ///     Every address we're listening on in the order they were given,
+    ///     `(host, port)` for TCP, the path for a unix socket and `@name`
+    ///     for an abstract one. Empty once the runner has been closed.
+    ///
+    fn local_addrs(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        let mut addrs = Vec::with_capacity(self.server.listeners.len());
+        for listener in &self.server.listeners {
+            let addr = match listener.local_addr()? {
+                BindAddr::Tcp(addr) => (addr.ip().to_string(), addr.port()).into_py(py),
+                #[cfg(unix)]
+                BindAddr::Unix(path) => path.to_string_lossy().into_py(py),
+                #[cfg(target_os = "linux")]
+                BindAddr::Abstract(name) => format!("@{}", name).into_py(py),
+            };
+            addrs.push(addr);
         }
+
+        Ok(addrs)
     }
 
-    Ok((method, path, protocol, headers))
-}
+    ///
+    /// PythonMethod: AsyncServerRunner.backlog -> int | None
+    ///
+    ///     The listen backlog we actually got, the `backlog=` asked for
+    ///     capped the way the kernel caps it (`net.core.somaxconn` on
+    ///     linux). None when the socket was handed to us already listening.
+    ///
+    #[getter]
+    fn backlog(&self) -> Option<i32> {
+        self.server.backlog.map(listener::effective_backlog)
+    }
 
+    ///
+    /// PythonMethod: AsyncServerRunner.worker_id -> int
+    ///
+    ///     `0` in the parent (or when not using workers), otherwise which
+    ///     worker this process is starting from `1`.
+    ///
+    #[getter]
+    fn worker_id(&self) -> usize {
+        self.worker_id
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.worker_pids() -> list[int]
+    ///
+    ///     The pids of the worker processes that are still running, always
+    ///     empty inside a worker.
+    ///
+    fn worker_pids(&self, py: Python) -> PyResult<Vec<u32>> {
+        match self.workers.as_ref() {
+            Some(pool) => pool.pids(py),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.stats() -> dict
+    ///
+    ///     A snapshot of the server's counters, `connections_accepted`,
+    ///     `connections_active`, `connections_denied`, `requests`,
+    ///     `rate_limited`, `bytes_written`, `body_bytes_written` (the
+    ///     part of `bytes_written` that was response bodies, only counted
+    ///     by the asyncio reactor), `parse_errors`, `bytes_reclaimed`
+    ///     (buffer capacity idle connections freed, see `idle_reclaim_after`)
+    ///     and `connections_shed` / `requests_shed` (refused for being over
+    ///     `memory_budget`). With a `memory_budget` the totals also have
+    ///     `memory_in_use`, roughly what the connections are holding, and
+    ///     `memory_shedding`. With workers each process only counts its own
+    ///     connections.
+    ///
+    ///     With `per_listener=True` it's a dict of the same counters for
+    ///     each listener by its name (see `ConnectionInfo.listener`), which
+    ///     add up to the totals. The native reactor only keeps the totals.
+    ///
+    ///     Optional:
+    ///         - per_listener:     bool    (the counters of each listener rather than the totals, defaults to false)
+    ///
+    #[args(per_listener = "false")]
+    fn stats(&self, py: Python, per_listener: bool) -> PyResult<PyObject> {
+        if !per_listener {
+            let stats = self.stats.snapshot(py)?;
+            if let Some(budget) = self.options.memory_budget.as_ref() {
+                stats.set_item("memory_in_use", budget.used())?;
+                stats.set_item("memory_shedding", budget.shedding())?;
+            }
+            return Ok(stats.into())
+        }
+
+        let listeners = PyDict::new(py);
+        for endpoint in self.endpoints.iter() {
+            listeners.set_item(&*endpoint.name, endpoint.stats.snapshot(py)?)?;
+        }
+
+        Ok(listeners.into())
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.profile_snapshot() -> Optional[dict]
+    ///
+    ///     With `profiling=True` where the time has gone, a dict for each
+    ///     of `accept`, `read`, `parse`, `handler` and `write` with the
+    ///     total `ns` spent in it and the `count` of times it was timed,
+    ///     None when the runner isn't profiling. Only the asyncio reactor
+    ///     is timed and an `accept_mode="thread"` runner's accepts aren't,
+    ///     `handler` is the time spent running python code the callback
+    ///     gave us rather than how long it took to answer (see
+    ///     `request.timings` for that). `reset_stats()` zeroes it too.
+    ///
+    fn profile_snapshot(&self, py: Python) -> PyResult<Option<PyObject>> {
+        match self.profile.as_ref() {
+            Some(profile) => Ok(Some(profile.snapshot(py)?.into())),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.connections() -> list[ConnectionInfo]
+    ///
+    ///     The connections currently being handled, each with its peer
+    ///     address, the listener that accepted it, how many requests it has
+    ///     served, how long it's been idle and the task driving it.
+    ///
+    fn connections(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        let tasks: &PyDict = self.tasks.as_ref(py).downcast()?;
+        Ok(tasks.values().iter().map(|info| info.into()).collect())
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.set_exception_handler(handler)
+    ///
+    ///     Called as `handler(context)` when a connection task ends with an
+    ///     exception nothing handled, which is a bug of ours (or of a raw
+    ///     callback) rather than a handler raising, that's already a `500`.
+    ///     The context is a dict of `message`, `exception`, `task`, `peer`
+    ///     and `request`, the request line being handled if there was one.
+    ///
+    ///     Without a handler (or with None) it goes to the loop's exception
+    ///     handler, either way as soon as the task is done rather than when
+    ///     asyncio notices nobody retrieved it.
+    ///
+    fn set_exception_handler(&self, py: Python, handler: PyObject) -> PyResult<()> {
+        if !handler.is_none(py) && !handler.as_ref(py).is_callable() {
+            return Err(PyValueError::new_err("the exception handler must be callable or None"))
+        }
+
+        let handler = Some(handler).filter(|handler| !handler.is_none(py));
+        *self.exception_handler.lock().unwrap() = handler;
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.abort(peer_addr) -> int
+    ///
+    ///     Drops the connection(s) from `peer_addr` (a `(host, port)` tuple
+    ///     like `ConnectionInfo.peer`), the task is cancelled and the socket
+    ///     closed. Returns how many connections matched.
+    ///
+    fn abort(&self, py: Python, peer_addr: (String, u16)) -> PyResult<usize> {
+        self.abort_matching(py, |info| info.is_peer(&peer_addr))
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.abort_all() -> int
+    ///
+    ///     Drops every connection but leaves the server accepting new ones.
+    ///
+    fn abort_all(&self, py: Python) -> PyResult<usize> {
+        self.abort_matching(py, |_| true)
+    }
+
+    ///
+    /// Where the accept loop is up to, one of `"init"`, `"accepting"`,
+    /// `"sleeping"`, `"draining"` or `"stopped"`. Only meant for debugging.
+    ///
+    #[getter]
+    fn state(&self) -> &'static str {
+        self.server_state.name()
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.is_serving() -> bool
+    ///
+    ///     If we're accepting connections right now, like
+    ///     `asyncio.Server.is_serving()`. False before the runner is awaited
+    ///     and once it's been stopped or closed.
+    ///
+    fn is_serving(&self) -> bool {
+        matches!(self.server_state, ServerState::Accepting | ServerState::Sleeping)
+            && !self.server.listeners.is_empty()
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.sockets -> list[socket.socket]
+    ///
+    ///     The listening sockets like `asyncio.Server.sockets`, each a
+    ///     duplicate (see `Writer.get_extra_info`) so closing one doesn't
+    ///     touch ours. Empty once the runner is closed. A duplicate still
+    ///     open keeps the port listening after `close()` so close them when
+    ///     done.
+    ///
+    #[getter]
+    fn sockets(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        self.server.listeners
+            .iter()
+            .map(|listener| stream::dup_socket(py, listener.raw_fd()))
+            .collect()
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.reset_stats()
+    ///
+    ///     Zeroes the running totals and every listener's counters,
+    ///     `connections_active` is left as is.
+    ///
+    fn reset_stats(&self) {
+        self.reset_counters();
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.update_acl(allow_ips=None, deny_ips=None)
+    ///
+    ///     Replaces the `allow_ips` and / or `deny_ips` the runner was made
+    ///     with, a list left as None stays as it is and an empty one clears
+    ///     it. Connections already accepted aren't checked again. With
+    ///     workers this only changes the lists of the process it's called in.
+    ///     Listeners with options of their own get the new lists too.
+    ///
+    ///     Optional:
+    ///         - allow_ips:    list[str]   (addresses or CIDRs)
+    ///         - deny_ips:     list[str]   (addresses or CIDRs)
+    ///
+    #[args(allow_ips = "None", deny_ips = "None")]
+    fn update_acl(&mut self, py: Python, allow_ips: Option<Vec<String>>, deny_ips: Option<Vec<String>>) -> PyResult<()> {
+        let allow = allow_ips.map(|addrs| acl::parse_list("allow_ips", &addrs)).transpose()?;
+        let deny = deny_ips.map(|addrs| acl::parse_list("deny_ips", &addrs)).transpose()?;
+        self.update_lists(allow, deny);
+
+        // a client already waiting on the listener is checked against the new lists, not the old
+        self.sleeper.reset();
+        self.sleeper.wake(py);
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.config(per_listener=False) -> dict
+    ///
+    ///     Every option (see RunnerOptions) as the runner is using it now,
+    ///     with the defaults of the ones it wasn't given and whatever
+    ///     `update_config()` and `update_acl()` have changed since.
+    ///
+    ///     With `per_listener=True` it's a dict of the same for each
+    ///     listener by its name, a listener dict's own options on top of
+    ///     the runner's.
+    ///
+    ///     Optional:
+    ///         - per_listener:     bool    (the options of each listener rather than the runner's, defaults to false)
+    ///
+    #[args(per_listener = "false")]
+    fn config(&self, py: Python, per_listener: bool) -> PyResult<PyObject> {
+        let kwargs = self.kwargs.as_ref().map(|kwargs| kwargs.as_ref(py));
+        if !per_listener {
+            return Ok(self.options.to_dict(py, kwargs)?.into())
+        }
+
+        let listeners = PyDict::new(py);
+        for endpoint in self.endpoints.iter() {
+            let given = match endpoint.overrides.as_ref() {
+                Some(overrides) => Some(endpoint::merge(py, kwargs, overrides.as_ref(py))?),
+                None => kwargs,
+            };
+            listeners.set_item(&*endpoint.name, endpoint.options.to_dict(py, given)?)?;
+        }
+
+        Ok(listeners.into())
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.update_config(**changes)
+    ///
+    ///     Changes options while the runner is going, connections accepted
+    ///     from then on are served with them while those already accepted
+    ///     carry on as they were. Only the ones each connection reads for
+    ///     itself can be changed (timeouts, limits, the ACLs and logging,
+    ///     see `options::RUNTIME`), the rest such as the bind address or
+    ///     TLS raise ValueError. An option changed to None goes back to its
+    ///     default.
+    ///
+    ///     Every change is checked before any is made, so if one is
+    ///     invalid it raises and the runner is left as it was. Listeners
+    ///     with options of their own get the changes too, apart from the
+    ///     ones they give themselves. The lists `update_acl()` set and the
+    ///     `rate_limit` buckets are kept unless they're changed.
+    ///
+    ///     With workers this only changes the process it's called in, and
+    ///     it isn't supported with `reactor="native"` or
+    ///     `accept_mode="thread"` yet, their threads hold on to the options.
+    ///
+    ///     Optional:
+    ///         - **changes:    see RunnerOptions
+    ///
+    ///     Example:
+    ///         runner.update_config(max_body_size=1024 * 1024, deny_ips=["10.0.0.0/8"])
+    ///
+    #[args(changes = "**")]
+    fn update_config(&mut self, py: Python, changes: Option<&PyDict>) -> PyResult<()> {
+        let changes = match changes {
+            Some(changes) => changes,
+            None => return Ok(()),
+        };
+
+        if self.options.reactor == ReactorKind::Native || self.options.accept_mode == AcceptMode::Thread {
+            return Err(PyValueError::new_err("update_config() isn't supported with reactor='native' or accept_mode='thread' yet"))
+        }
+
+        let known = RunnerOptions::default().to_dict(py, None)?;
+        for key in changes.keys() {
+            let key: &str = key.extract()?;
+            if !known.contains(key)? {
+                return Err(PyTypeError::new_err(format!("update_config() got an unexpected keyword argument '{}'", key)))
+            }
+            if !options::RUNTIME.contains(&key) {
+                return Err(PyValueError::new_err(format!("{} can't be changed once the runner is made", key)))
+            }
+        }
+
+        let kwargs = match self.kwargs.as_ref() {
+            Some(kwargs) => kwargs.as_ref(py).copy()?,
+            None => PyDict::new(py),
+        };
+        for (key, value) in changes.iter() {
+            kwargs.set_item(key, value)?;
+        }
+
+        let mut options = RunnerOptions::from_kwargs(Some(kwargs))?;
+        options.carry_over(&self.options, changes);
+        let options = Arc::new(options);
+
+        // every listener's are made before any are swapped in, one that's invalid changes nothing
+        let mut swapped = Vec::with_capacity(self.endpoints.len());
+        for endpoint in self.endpoints.iter() {
+            swapped.push(match endpoint.overrides.as_ref() {
+                Some(overrides) => {
+                    let mut own = endpoint::listener_options(py, Some(kwargs), overrides.as_ref(py), &options)?;
+                    own.carry_over(&endpoint.options, changes);
+                    Arc::new(own)
+                },
+                None => options.clone(),
+            });
+        }
+
+        // the old lists were carried over, they're changed in place like update_acl() does
+        let list = |key: &str| match changes.get_item(key) {
+            Some(addrs) if addrs.is_none() => Ok(Some(Vec::new())),
+            Some(addrs) => acl::parse_list(key, &addrs.extract::<Vec<String>>()?).map(Some),
+            None => Ok(None),
+        };
+        let (allow, deny) = (list("allow_ips")?, list("deny_ips")?);
+
+        for (endpoint, options) in self.endpoints.iter_mut().zip(swapped) {
+            endpoint.options = options;
+        }
+        self.options = options;
+        self.kwargs = Some(kwargs.into());
+        self.update_lists(allow, deny);
+
+        if self.access_logger.is_none() && self.endpoints.iter().any(|endpoint| endpoint.options.access_log) {
+            self.access_logger = Some(access_logger(py)?);
+        }
+
+        // a client already waiting on the listener gets the new options, not the old
+        self.sleeper.reset();
+        self.sleeper.wake(py);
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.throw(type, value=None, traceback=None)
+    ///
+    ///     Called by whatever is awaiting us when it gets cancelled (asyncio
+    ///     does this to everything left running on Ctrl-C), we shut down
+    ///     and let the exception carry on up.
+    ///
+    #[args(value = "None", _traceback = "None")]
+    fn throw(&mut self, py: Python, type_: &PyAny, value: Option<&PyAny>, _traceback: Option<&PyAny>) -> PyResult<()> {
+        self.shutdown(py);
+        self.server_state = ServerState::Stopped;
+
+        match value {
+            Some(value) if !value.is_none() => Err(PyErr::from_instance(value)),
+            _ => Err(PyErr::from_instance(type_)),
+        }
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.close()
+    ///
+    ///     Stops the server for good, the listener is closed straight away
+    ///     and connections still being handled are cancelled. A
+    ///     `serve_forever()` in progress returns, after that awaiting the
+    ///     runner or calling `start()` raises RuntimeError("server is closed").
+    ///
+    fn close(&mut self, py: Python) {
+        self.shutdown(py);
+        self.closed = true;
+        if !self.awaited {
+            self.server_state = ServerState::Stopped;
+        }
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.serve_forever() -> awaitable
+    ///
+    ///     Awaiting the runner spelled the way `asyncio.Server` does it, it
+    ///     accepts until `stop()` is called and then returns. If the task
+    ///     awaiting it is cancelled the server is shut down first and the
+    ///     CancelledError carries on up, so `asyncio.wait_for()` works.
+    ///
+    fn serve_forever(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.wait_ready() -> awaitable
+    ///
+    ///     Resolves once the runner is accepting connections, straight away
+    ///     if it already is, so a test can schedule `serve_forever()` as a
+    ///     task and `await runner.wait_ready()` before connecting. It
+    ///     raises RuntimeError if the runner stops (or was already stopped)
+    ///     before it got that far.
+    ///
+    fn wait_ready(&mut self, py: Python) -> PyResult<PyObject> {
+        if self.closed {
+            return Err(PyRuntimeError::new_err("server is closed"))
+        }
+
+        let fut = self.loop_.call_method0(py, "create_future")?;
+        match self.server_state {
+            ServerState::Init => self.ready_waiters.push(fut.clone_ref(py)),
+            ServerState::Accepting | ServerState::Sleeping => {
+                fut.call_method1(py, "set_result", (py.None(),))?;
+            },
+            ServerState::Draining | ServerState::Stopped => {
+                return Err(PyRuntimeError::new_err("AsyncServerRunner has already stopped"))
+            },
+        }
+
+        Ok(fut)
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.stop()
+    ///
+    ///     Makes `serve_forever()` return, the same shutdown as `close()`.
+    ///
+    fn stop(&mut self, py: Python) {
+        self.shutdown(py);
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.export_listener() -> (int, str)
+    ///
+    ///     Hands the first TCP listener to another process for a reload
+    ///     without dropping connections, a `dup()` of its fd with
+    ///     `FD_CLOEXEC` cleared so it survives an exec, and the address as
+    ///     `host:port`. The new process takes it with `from_fd()`.
+    ///
+    ///     The fd is the caller's to close, we keep our own. Anything this
+    ///     process execs from now on inherits it too, so pass it on (e.g.
+    ///     `subprocess.Popen(..., pass_fds=[fd])`) and close it straight
+    ///     after.
+    ///
+    #[cfg(unix)]
+    fn export_listener(&self) -> PyResult<(i32, String)> {
+        let listener = self.server.listeners
+            .iter()
+            .find_map(Listener::as_tcp)
+            .ok_or_else(|| PyRuntimeError::new_err("the runner has no open TCP listener to export"))?;
+        let addr = listener.local_addr()?;
+
+        // dup() leaves FD_CLOEXEC off the copy, ours keeps it
+        let fd = unsafe { libc::dup(stream::raw_fd(listener)) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error().into())
+        }
+
+        Ok((fd, addr.to_string()))
+    }
+
+    #[cfg(not(unix))]
+    fn export_listener(&self) -> PyResult<(i32, String)> {
+        Err(PyNotImplementedError::new_err("export_listener() is only supported on unix"))
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.transfer_and_drain(timeout=None)
+    ///
+    ///     The old process's half of a reload, once the listener has gone
+    ///     to the new one with `export_listener()`. We stop accepting
+    ///     straight away (the new process keeps the port) and every
+    ///     connection closes once the request it's on has been answered,
+    ///     an idle keep-alive one right away. After the last one has gone,
+    ///     or `timeout` seconds with the rest cancelled, `serve_forever()`
+    ///     returns and the runner is closed.
+    ///
+    ///     Raw connections aren't HTTP so can't be drained, they run until
+    ///     they finish or the timeout. Not supported with the native reactor
+    ///     or workers.
+    ///
+    ///     Optional:
+    ///         - timeout:      float
+    ///
+    #[args(timeout = "None")]
+    fn transfer_and_drain(&mut self, py: Python, timeout: Option<f64>) -> PyResult<()> {
+        if self.options.reactor == ReactorKind::Native || self.workers.is_some() {
+            return Err(PyValueError::new_err("transfer_and_drain() isn't supported with the native reactor or workers"))
+        }
+
+        let deadline = match timeout {
+            Some(timeout) if timeout > 0.0 && timeout.is_finite() => Some(Instant::now() + Duration::from_secs_f64(timeout)),
+            Some(_) => return Err(PyValueError::new_err("timeout must be a positive number of seconds")),
+            None => None,
+        };
+
+        if !self.awaited {
+            self.close(py);
+            return Ok(())
+        }
+
+        self.drain(py, deadline)
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.graceful_shutdown(timeout=None)
+    ///
+    ///     What a SIGTERM should do, see `install_signal_handlers()`. The
+    ///     `on_shutdown` hook is called first so the app can deregister
+    ///     from service discovery while we're still accepting, if it's
+    ///     async the listener closes once it's done (or at the deadline).
+    ///     Then it's `transfer_and_drain()` without the transfer, kept-alive
+    ///     connections get `Connection: close` on their next response and
+    ///     `serve_forever()` returns once they've all gone, or after
+    ///     `timeout` seconds (`shutdown_timeout` by default) with the rest
+    ///     cancelled.
+    ///
+    ///     Calling it again while it's going does nothing. With the native
+    ///     reactor or workers there's no draining, the hook is called and
+    ///     the runner stops like `stop()`.
+    ///
+    ///     Optional:
+    ///         - timeout:      float
+    ///
+    #[args(timeout = "None")]
+    fn graceful_shutdown(&mut self, py: Python, timeout: Option<f64>) -> PyResult<()> {
+        let timeout = timeout.or_else(|| self.options.shutdown_timeout.map(f64::from));
+        let deadline = match timeout {
+            Some(timeout) if timeout > 0.0 && timeout.is_finite() => Some(Instant::now() + Duration::from_secs_f64(timeout)),
+            Some(_) => return Err(PyValueError::new_err("timeout must be a positive number of seconds")),
+            None => None,
+        };
+
+        if self.closed || self.transfer.is_some() || self.on_shutdown.is_some() {
+            return Ok(())
+        }
+
+        if !self.awaited || self.server_state == ServerState::Stopped {
+            self.close(py);
+            return Ok(())
+        }
+
+        let pending = self.call_on_shutdown(py);
+        if self.options.reactor == ReactorKind::Native || self.workers.is_some() {
+            self.shutdown(py);
+            return Ok(())
+        }
+
+        match pending {
+            Some(task) => {
+                self.on_shutdown = Some((task, deadline));
+                Ok(())
+            },
+            None => self.drain(py, deadline),
+        }
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.install_signal_handlers(loop=None) -> bool
+    ///
+    ///     Has SIGTERM call `graceful_shutdown()`, with `loop.add_signal_handler()`
+    ///     on the runner's loop by default. The handler is removed again
+    ///     once the runner stops, a runner started again needs it installing
+    ///     again. It has to be called from the main thread.
+    ///
+    ///     Returns False where the loop has no signal handlers (Windows),
+    ///     there the runner still stops on Ctrl-C's KeyboardInterrupt.
+    ///
+    ///     Optional:
+    ///         - loop:         asyncio.AbstractEventLoop
+    ///
+    #[args(loop_ = "None")]
+    fn install_signal_handlers(slf: PyRef<Self>, py: Python, loop_: Option<PyObject>) -> PyResult<bool> {
+        let loop_ = loop_.unwrap_or_else(|| slf.loop_.clone_ref(py));
+        let runner = slf.into_py(py);
+        let handler = runner.getattr(py, "graceful_shutdown")?;
+        let sigterm = py.import("signal")?.getattr("SIGTERM")?;
+
+        match loop_.call_method1(py, "add_signal_handler", (sigterm, handler)) {
+            Ok(_) => {},
+            Err(e) if e.is_instance::<PyNotImplementedError>(py) || e.is_instance::<PyAttributeError>(py) => {
+                return Ok(false)
+            },
+            Err(e) => return Err(e),
+        }
+
+        let runner: &PyCell<Self> = runner.as_ref(py).downcast()?;
+        runner.borrow_mut().signal_loop = Some(loop_);
+        Ok(true)
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.start()
+    ///
+    ///     Gets a stopped runner ready to be awaited again, the listener is
+    ///     rebound to the same address (and port) if it was closed and the
+    ///     stats start again from zero. Worker processes aren't respawned.
+    ///     Raises RuntimeError if the runner is still running.
+    ///
+    fn start(&mut self, py: Python) -> PyResult<()> {
+        if self.closed {
+            return Err(PyRuntimeError::new_err("server is closed"))
+        }
+
+        match self.server_state {
+            ServerState::Stopped => {},
+            ServerState::Init if !self.awaited => {},
+            _ => return Err(PyRuntimeError::new_err("AsyncServerRunner is still running")),
+        }
+
+        self.server.reopen()?;
+        self.server_state = ServerState::Init;
+        self.awaited = false;
+        self.sleeper.reset();
+        self.reset_counters();
+        self.tasks = PyDict::new(py).into();
+
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: AsyncServerRunner.add_middleware(middleware)
+    ///
+    ///     Runs `middleware.on_request(request)` before the callback and
+    ///     `middleware.on_response(request, response)` after it for every
+    ///     request, either can be left out and either can be async. They run
+    ///     in the order they were added for requests and the reverse for
+    ///     responses, an `on_request` that returns a response answers the
+    ///     request instead of the callback. See `MiddlewareCall`.
+    ///
+    ///     Each request runs in a copy of the `contextvars.Context` the
+    ///     runner was awaited in, so a contextvar `on_request` sets (a trace
+    ///     id...) is seen by the callback, `on_response` and the request's
+    ///     access log but not by any other request, even one after it on
+    ///     the same connection. The native reactor doesn't do this yet.
+    ///
+    ///     Middleware has to be added before the runner is started, and
+    ///     can't be used with `raw=True`. It only wraps the runner's own
+    ///     callback, not the ones listeners were given of their own.
+    ///
+    ///     Requires:
+    ///         - middleware:   an object with `on_request` and / or `on_response`
+    ///
+    fn add_middleware(&mut self, py: Python, middleware: &PyAny) -> PyResult<()> {
+        if self.options.raw {
+            return Err(PyValueError::new_err("middleware doesn't apply to raw connections"))
+        }
+
+        if self.awaited {
+            return Err(PyRuntimeError::new_err("middleware has to be added before the runner is started"))
+        }
+
+        let chain = match self.middleware.as_ref() {
+            Some(chain) => chain.clone_ref(py),
+            None => {
+                let chain = Py::new(py, Middleware::new(self.callback.clone_ref(py)))?;
+                self.callback = chain.clone_ref(py).into_py(py);
+                self.middleware = Some(chain.clone_ref(py));
+                chain
+            },
+        };
+
+        let mut chain = chain.borrow_mut(py);
+        chain.add(middleware)
+    }
+}
+
+
+impl AsyncServerRunner {
+
+    ///
+    /// Internal Method: AsyncServerRunner::with_server() -> Self
+    ///
+    ///     The shared half of the constructors once we have a listener,
+    ///     aquires the event loop and sets the default state.
+    ///
+    fn with_server(
+        py: Python,
+        mut server: AsyncServer,
+        callback: PyObject,
+        options: RunnerOptions,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<Self> {
+        let callback = Self::host_callback(py, callback, &options)?;
+
+        if options.reactor == ReactorKind::Native && server.listeners.iter().any(|l| l.as_tcp().is_none()) {
+            return Err(PyValueError::new_err("the native reactor only supports TCP listeners"))
+        }
+        if options.accept_mode == AcceptMode::Thread && server.listeners.iter().any(|l| l.as_tcp().is_none()) {
+            return Err(PyValueError::new_err("accept_mode='thread' only supports TCP listeners"))
+        }
+
+        let loop_ = get_loop(py)?.into_py(py);
+        server.client_options = options.client_options();
+        server.pause = options.accept_pause();
+        server.set_fastopen(options.tcp_fastopen);
+
+        let access_logger = match options.access_log {
+            true => Some(access_logger(py)?),
+            false => None,
+        };
+        let profile = options.profiling.then(Arc::default);
+        let options = Arc::new(options);
+        let stats = Arc::default();
+        let endpoints = server.addrs
+            .iter()
+            .map(|addr| Endpoint::new(addr, options.clone(), &stats))
+            .collect();
+
+        Ok(AsyncServerRunner {
+            server,
+            endpoints,
+            server_state: ServerState::Init,
+            awaited: false,
+            closed: false,
+            transfer: None,
+            on_shutdown: None,
+            signal_loop: None,
+            ready_waiters: Vec::new(),
+            context: None,
+            sleeper: LoopSleeper::with_backoff(loop_.clone(), options.min_poll_delay, options.max_poll_delay),
+            loop_,
+            callback,
+            middleware: None,
+            workers: None,
+            worker_id: 0,
+            access_logger,
+            stats,
+            profile,
+            tasks: PyDict::new(py).into(),
+            housekeeping: Instant::now() + Duration::from_secs_f32(options.housekeeping_interval),
+            exception_handler: Arc::default(),
+            date: Arc::default(),
+            #[cfg(target_os = "linux")]
+            native: None,
+            options,
+            kwargs: kwargs.map(PyDict::copy).transpose()?.map(Into::into),
+            #[cfg(unix)]
+            acceptor: None,
+        })
+    }
+
+    /// Swaps in whichever of the `allow_ips` and `deny_ips` lists are given, for every listener.
+    fn update_lists(&self, allow: Option<Vec<forwarded::Cidr>>, deny: Option<Vec<forwarded::Cidr>>) {
+        self.options.acl.update(allow.clone(), deny.clone());
+        for endpoint in self.endpoints.iter().filter(|endpoint| !Arc::ptr_eq(&endpoint.options, &self.options)) {
+            endpoint.options.acl.update(allow.clone(), deny.clone());
+        }
+    }
+
+    /// The callback as we call it, a dict of hosts becomes VirtualHosts.
+    fn host_callback(py: Python, callback: PyObject, options: &RunnerOptions) -> PyResult<PyObject> {
+        let hosts = match callback.as_ref(py).downcast::<PyDict>() {
+            Ok(hosts) => hosts,
+            Err(_) => return Ok(callback),
+        };
+
+        if options.raw {
+            return Err(PyValueError::new_err("raw connections have no Host to pick a handler by"))
+        }
+        Ok(Py::new(py, VirtualHosts::new(hosts)?)?.into_py(py))
+    }
+
+    ///
+    /// Internal Method: AsyncServerRunner::serve_listeners() -> PyResult<()>
+    ///
+    ///     Gives the listeners their names, callbacks and options from the
+    ///     dicts they were given as (see ListenerSpec), the rest keep the
+    ///     runner's. `specs` are in the same order as the listeners.
+    ///
+    fn serve_listeners(&mut self, py: Python, specs: Vec<ListenerSpec>, kwargs: Option<&PyDict>) -> PyResult<()> {
+        if specs.iter().all(ListenerSpec::is_plain) {
+            return Ok(())
+        }
+
+        if self.options.reactor == ReactorKind::Native || self.options.accept_mode == AcceptMode::Thread {
+            return Err(PyValueError::new_err("listener dicts aren't supported with reactor='native' or accept_mode='thread' yet"))
+        }
+
+        for (endpoint, spec) in self.endpoints.iter_mut().zip(specs) {
+            if let Some(options) = spec.options(py, kwargs, &self.options)? {
+                endpoint.options = Arc::new(options);
+                endpoint.overrides = spec.overrides(py);
+            }
+            if let Some(callback) = spec.callback {
+                endpoint.callback = Some(Self::host_callback(py, callback, &endpoint.options)?);
+            }
+            if let Some(name) = spec.name {
+                endpoint.name = name.into();
+            }
+        }
+
+        let mut names = std::collections::HashSet::new();
+        if let Some(endpoint) = self.endpoints.iter().find(|endpoint| !names.insert(endpoint.name.clone())) {
+            return Err(PyValueError::new_err(format!("two listeners are called '{}'", endpoint.name)))
+        }
+
+        if self.access_logger.is_none() && self.endpoints.iter().any(|endpoint| endpoint.options.access_log) {
+            self.access_logger = Some(access_logger(py)?);
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: AsyncServerRunner::shutdown()
+    ///
+    ///     Closes the listener, cancels every connection task still running
+    ///     and stops the workers, used when we get interrupted or cancelled.
+    ///
+    fn abort_matching(&self, py: Python, matches: impl Fn(&ConnectionInfo) -> bool) -> PyResult<usize> {
+        let tasks: &PyDict = self.tasks.as_ref(py).downcast()?;
+
+        let mut aborted = 0;
+        for info in tasks.values() {
+            let info: PyRef<ConnectionInfo> = info.extract()?;
+            if matches(&info) {
+                info.cancel(py)?;
+                aborted += 1;
+            }
+        }
+
+        Ok(aborted)
+    }
+
+    ///
+    /// Internal Method: AsyncServerRunner::spawn_connection() -> PyResult<()>
+    ///
+    ///     Starts a task for a client newly accepted off `listeners[index]`,
+    ///     a OnceFuture for HTTP or with `raw=True` the callback itself given
+    ///     the client's Reader and Writer. Either way it gets that listener's
+    ///     callback and options.
+    ///
+    fn spawn_connection(&mut self, py: Python, cli: TcpStream, index: usize) -> PyResult<()> {
+        // peer_addr can fail if the client has already reset the connection
+        let client = cli.peer_addr()
+            .ok()
+            .map(|addr| (addr.ip().to_string(), addr.port()));
+        let server = cli.local_addr()
+            .ok()
+            .map(|addr| (addr.ip().to_string(), addr.port()));
+
+        let endpoint = &self.endpoints[index];
+        let (options, listener) = (endpoint.options.clone(), endpoint.name.clone());
+        let callback = endpoint.callback.as_ref().unwrap_or(&self.callback).clone_ref(py);
+
+        let tls = match options.tls.as_ref() {
+            Some(config) => Some(TlsSession::new(config)?),
+            None => None,
+        };
+
+        if options.raw {
+            let transport = Transport::new(cli, tls, Vec::new());
+            let reader = Py::new(py, Reader::new(transport.clone(), self.loop_.clone_ref(py)))?;
+            let writer = Writer::new(transport, self.loop_.clone_ref(py), options.write_high_water)?;
+            let writer = Py::new(py, writer)?;
+
+            // a bad callback shouldn't bring the whole server down.
+            let task = callback
+                .call1(py, (reader, writer))
+                .and_then(|coro| context::spawn(py, self.context.as_ref(), coro));
+            let task = match task {
+                Ok(task) => task,
+                Err(e) => {
+                    e.print(py);
+                    return Ok(())
+                },
+            };
+
+            return self.track_task(py, task, Arc::new(ConnectionActivity::new(client)), listener)
+        }
+
+        let mut caller = OnceFuture::new(
+            cli,
+            callback,
+            self.loop_.clone_ref(py),
+        );
+        let activity = Arc::new(ConnectionActivity::new(client.clone()));
+        caller.activity = activity.clone();
+        caller.client = client;
+        caller.server = server;
+        caller.access_logger = self.access_logger.as_ref().filter(|_| options.access_log).map(|log| log.clone_ref(py));
+        caller.connection = Some(endpoint.stats.connection());
+        caller.memory = options.memory_budget.clone().map(MemoryCharge::new);
+        caller.profile = self.profile.clone();
+        caller.throttle = WriteThrottle::new(options.max_write_rate, options.write_budget.as_deref());
+        caller.date = self.date.clone();
+        caller.tls = tls;
+        caller.proxy_header = options.proxy_protocol;
+        caller.options = options;
+        caller.context = Some(PyDict::new(py).into());
+        caller.base_context = self.context.as_ref().map(|context| context.clone_ref(py));
+
+        let task = context::spawn(py, self.context.as_ref(), Py::new(py, caller)?)?;
+
+        self.track_task(py, task, activity, listener)
+    }
+
+    ///
+    /// Internal Method: AsyncServerRunner::start_reactor() -> PyResult<()>
+    ///
+    ///     With `reactor="native"` the listener is handed to a reactor
+    ///     thread the first time we're polled, from then on we're only
+    ///     driven to notice being cancelled or interrupted.
+    ///
+    #[cfg(target_os = "linux")]
+    fn start_reactor(&mut self, py: Python) -> PyResult<()> {
+        if self.options.reactor != ReactorKind::Native {
+            return Ok(())
+        }
+
+        // with_server already refused anything that isn't TCP
+        let listeners = self.server.listeners
+            .iter()
+            .filter_map(Listener::as_tcp)
+            .map(TcpListener::try_clone)
+            .collect::<io::Result<Vec<_>>>()?;
+
+        if listeners.is_empty() {
+            return Ok(())
+        }
+
+        let ctx = reactor::Context {
+            callback: self.callback.clone_ref(py),
+            loop_: self.loop_.clone_ref(py),
+            options: self.options.clone(),
+            date: self.date.clone(),
+            stats: self.stats.clone(),
+        };
+
+        self.native = Some(reactor::NativeReactor::spawn(listeners, ctx)?);
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: AsyncServerRunner::start_acceptor() -> PyResult<()>
+    ///
+    ///     With `accept_mode="thread"` clones of the listeners go to an
+    ///     accept thread the first time we're polled, from then on clients
+    ///     come off its channel (see `AsyncServerRunner::next_client()`).
+    ///
+    #[cfg(unix)]
+    fn start_acceptor(&mut self, py: Python) -> PyResult<()> {
+        if self.options.accept_mode != AcceptMode::Thread {
+            return Ok(())
+        }
+
+        // with_server already refused anything that isn't TCP
+        let listeners = self.server.listeners
+            .iter()
+            .filter_map(Listener::as_tcp)
+            .map(TcpListener::try_clone)
+            .collect::<io::Result<Vec<_>>>()?;
+
+        if listeners.is_empty() {
+            return Ok(())
+        }
+
+        self.acceptor = Some(acceptor::AcceptThread::spawn(
+            listeners,
+            self.loop_.clone_ref(py),
+            self.options.clone(),
+            self.endpoints.iter().map(|endpoint| endpoint.stats.clone()).collect(),
+            self.server.client_options,
+        )?);
+        Ok(())
+    }
+
+    ///
+    /// The next client to hand to a task and the index of the listener
+    /// that accepted it, off the accept thread's channel if there is one.
+    ///
+    fn next_client(&mut self) -> Option<(TcpStream, usize)> {
+        #[cfg(unix)]
+        if let Some(acceptor) = self.acceptor.as_ref() {
+            return acceptor.next_client()
+        }
+
+        let server = &mut self.server;
+        stats::timed(self.profile.as_deref(), Section::Accept, || server.accept_client())
+    }
+
+    /// Zeroes the stats, every listener's too, and the profile.
+    fn reset_counters(&self) {
+        self.stats.reset();
+        for endpoint in self.endpoints.iter() {
+            endpoint.stats.reset();
+        }
+
+        if let Some(profile) = self.profile.as_ref() {
+            profile.reset();
+        }
+    }
+
+    ///
+    /// Internal Method: AsyncServerRunner::housekeep() -> PyResult<()>
+    ///
+    ///     At most once every `housekeeping_interval`, as we come round
+    ///     between accepts and sleeps, every connection that's been idle
+    ///     for `idle_reclaim_after` is asked to free its buffers (see
+    ///     `OnceFuture::reclaim_idle()`). A connection that once took a big
+    ///     request keeps buffers sized for it, an idle keep-alive one may
+    ///     never need them again. With `accept_mode="thread"` we only come
+    ///     round as clients arrive so that's as often as it runs.
+    ///
+    fn housekeep(&mut self, py: Python) -> PyResult<()> {
+        let now = Instant::now();
+        if now < self.housekeeping {
+            return Ok(())
+        }
+        self.housekeeping = now + Duration::from_secs_f32(self.options.housekeeping_interval);
+
+        let after = Duration::from_secs_f32(self.options.idle_reclaim_after);
+        let tasks: &PyDict = self.tasks.as_ref(py).downcast()?;
+        for info in tasks.values() {
+            let info: PyRef<ConnectionInfo> = info.extract()?;
+            info.reclaim(after);
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Keeps the task in `tasks` until it's done so `connections()` can see
+    /// it, and reports it if it ends with an exception (see TaskDone).
+    ///
+    fn track_task(&self, py: Python, task: &PyAny, activity: Arc<ConnectionActivity>, listener: Arc<str>) -> PyResult<()> {
+        // the entry goes as soon as the task is done so we never keep a connection around
+        let info = Py::new(py, ConnectionInfo::new(activity, task.into(), listener))?;
+        let tasks = self.tasks.as_ref(py);
+        tasks.set_item(task, info)?;
+
+        let done = TaskDone {
+            tasks: self.tasks.clone_ref(py),
+            loop_: self.loop_.clone_ref(py),
+            handler: self.exception_handler.clone(),
+        };
+        task.call_method1("add_done_callback", (Py::new(py, done)?,))?;
+
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: AsyncServerRunner::ready()
+    ///
+    ///     We've just started accepting, the first time we're polled once
+    ///     awaited, so `on_ready` is called with our addresses and the
+    ///     `wait_ready()` futures are woken. An `on_ready` that raises is
+    ///     printed like a raw callback's error and an async one runs as a
+    ///     task of its own, the server carries on accepting either way.
+    ///
+    fn ready(&mut self, py: Python) {
+        for fut in self.ready_waiters.drain(..) {
+            let _ = fut.call_method1(py, "set_result", (py.None(),));
+        }
+
+        let hook = match self.options.on_ready.as_ref() {
+            Some(hook) => hook.clone_ref(py),
+            None => return,
+        };
+
+        let result = self.local_addrs(py)
+            .and_then(|addrs| hook.call1(py, (addrs,)))
+            .and_then(|result| {
+                if py.import("inspect")?.call1("isawaitable", (&result,))?.is_true()? {
+                    py.import("asyncio")?.call1("ensure_future", (result,))?;
+                }
+                Ok(())
+            });
+
+        if let Err(e) = result {
+            e.print(py);
+        }
+    }
+
+    ///
+    /// Internal Method: AsyncServerRunner::call_on_shutdown() -> Option<PyObject>
+    ///
+    ///     Calls the `on_shutdown` hook for `graceful_shutdown()`, returning
+    ///     the task running it if it's async. One that raises is printed
+    ///     like `on_ready`'s errors and the shutdown carries on.
+    ///
+    fn call_on_shutdown(&self, py: Python) -> Option<PyObject> {
+        let hook = self.options.on_shutdown.as_ref()?;
+
+        let result = hook.call0(py).and_then(|result| {
+            if py.import("inspect")?.call1("isawaitable", (&result,))?.is_true()? {
+                return Ok(Some(py.import("asyncio")?.call1("ensure_future", (result,))?.into()))
+            }
+            Ok(None)
+        });
+
+        result.unwrap_or_else(|e| {
+            e.print(py);
+            None
+        })
+    }
+
+    ///
+    /// Internal Method: AsyncServerRunner::drain() -> PyResult<()>
+    ///
+    ///     Stops accepting for good and asks every connection to close
+    ///     once it's between requests, `__next__` returns once they've
+    ///     gone or `deadline` has passed.
+    ///
+    fn drain(&mut self, py: Python, deadline: Option<Instant>) -> PyResult<()> {
+        #[cfg(unix)]
+        if let Some(mut acceptor) = self.acceptor.take() {
+            acceptor.stop(py);
+        }
+
+        self.server.close();
+        self.closed = true;
+        self.transfer = Some(deadline);
+        self.sleeper.wake(py);
+
+        let tasks: &PyDict = self.tasks.as_ref(py).downcast()?;
+        for info in tasks.values() {
+            let info: PyRef<ConnectionInfo> = info.extract()?;
+            info.drain();
+        }
+
+        Ok(())
+    }
+
+    fn shutdown(&mut self, py: Python) {
+        if let Some(loop_) = self.signal_loop.take() {
+            if let Ok(sigterm) = py.import("signal").and_then(|signal| signal.getattr("SIGTERM")) {
+                let _ = loop_.call_method1(py, "remove_signal_handler", (sigterm,));
+            }
+        }
+
+        if let Some((task, _)) = self.on_shutdown.take() {
+            let _ = task.call_method0(py, "cancel");
+        }
+
+        // a cancelled waiter refuses the exception, it doesn't need it anyway
+        for fut in self.ready_waiters.drain(..) {
+            let e = PyRuntimeError::new_err("the server stopped before it was ready");
+            let _ = fut.call_method1(py, "set_exception", (e.into_instance(py),));
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(mut native) = self.native.take() {
+            native.stop(py);
+        }
+
+        #[cfg(unix)]
+        if let Some(mut acceptor) = self.acceptor.take() {
+            acceptor.stop(py);
+        }
+
+        self.server.close();
+        if self.server_state != ServerState::Stopped {
+            self.server_state = ServerState::Draining;
+        }
+
+        // `serve_forever()` returns as soon as its task is back with us, not once the sleep runs out
+        self.sleeper.wake(py);
+
+        if let Ok(tasks) = self.tasks.call_method0(py, "copy") {
+            if let Ok(tasks) = tasks.as_ref(py).iter() {
+                for task in tasks.flatten() {
+                    let _ = task.call_method0("cancel");
+                }
+            }
+        }
+
+        if let Some(mut pool) = self.workers.take() {
+            pool.shutdown(py);
+        }
+    }
+}
+
+/// 
+/// This implementation adds the required __await__ dunder for
+/// python to use a coroutine, it just simply returns itself
+/// 
+#[pyproto]
+impl PyAsyncProtocol for AsyncServerRunner {
+    fn __await__(mut slf: PyRefMut<Self>) -> PyResult<PyRefMut<Self>> {
+        if slf.closed && slf.server_state == ServerState::Stopped {
+            return Err(PyRuntimeError::new_err("server is closed"))
+        }
+        if slf.awaited && slf.server_state != ServerState::Stopped {
+            return Err(PyRuntimeError::new_err("AsyncServerRunner is already being awaited"))
+        }
+
+        slf.awaited = true;
+        slf.context = Some(context::capture(slf.py())?);
+        Ok(slf)
+    }
+}
+
+///
+/// This applies the iterator magic methods for producing a coroutine,
+/// becauase asyncio is built entirely off of generators to give concurrency
+/// we much build our objects top behaving like generators aswell, hence the use
+/// of the IterProtocol.
+/// 
+#[pyproto]
+impl PyIterProtocol for AsyncServerRunner {
+
+    /// 
+    /// Iter is just a required dunder so we return ourselves as the 
+    /// iterator.
+    /// 
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    /// 
+    /// __next__ is what does all the actual work, this gets called every time by the event
+    /// loop, Pyo3 gives us some useful helpers to replicate what yield and Return do.
+    /// 
+    /// If we yield it tells the event loop we still have something todo and we'll get called again
+    /// this is useful if we plan on making a loop at anypoint because we cannot just do a standard 
+    /// loop otherwise we would block.
+    /// 
+    /// `server_state` walks through ServerState, `Accepting` polls the listener
+    /// and hands any client to a new task, `Sleeping` yields from the sleeper
+    /// when there wasn't one and `Draining` finishes once we've been told to
+    /// stop. Being driven again after that is an error.
+    ///
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+
+        // let Ctrl-C out while we're spinning rather than it landing somewhere inside pyo3
+        if let Err(e) = py.check_signals() {
+            slf.shutdown(py);
+            slf.server_state = ServerState::Stopped;
+            return Err(e)
+        }
+
+        match slf.server_state {
+            ServerState::Init => {
+                #[cfg(target_os = "linux")]
+                slf.start_reactor(py)?;
+                #[cfg(unix)]
+                slf.start_acceptor(py)?;
+                slf.server_state = ServerState::Accepting;
+                slf.ready(py);
+            },
+            ServerState::Draining => {
+                slf.server_state = ServerState::Stopped;
+                return Ok(IterNextOutput::Return(None))
+            },
+            ServerState::Stopped if slf.closed => {
+                return Err(PyRuntimeError::new_err("server is closed"))
+            },
+            ServerState::Stopped => {
+                return Err(PyRuntimeError::new_err("AsyncServerRunner has already stopped"))
+            },
+            ServerState::Accepting | ServerState::Sleeping => {},
+        }
+
+        // `graceful_shutdown()` closes the listener once an async `on_shutdown` has finished
+        if let Some((task, deadline)) = slf.on_shutdown.as_ref() {
+            let expired = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if expired || task.call_method0(py, "done")?.is_true(py)? {
+                let (task, deadline) = slf.on_shutdown.take().unwrap();
+                if expired {
+                    task.call_method0(py, "cancel")?;
+                }
+                slf.drain(py, deadline)?;
+            }
+        }
+
+        // there's nothing left to accept from, we're just waiting for the connections to finish
+        if let Some(deadline) = slf.transfer {
+            let drained = slf.tasks.as_ref(py).len()? == 0;
+            if drained || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                slf.shutdown(py);
+                slf.server_state = ServerState::Stopped;
+                return Ok(IterNextOutput::Return(None))
+            }
+
+            return Ok(IterNextOutput::Yield(slf.sleeper._iter_sleep(py)))
+        }
+
+        slf.housekeep(py)?;
+
+        if slf.server_state == ServerState::Accepting {
+            // the reactor thread does the accepting in native mode
+            let client = match slf.options.reactor {
+                ReactorKind::Asyncio => slf.next_client(),
+                ReactorKind::Native => None,
+            };
+
+            // if we have a client connecting we will get it as Some()
+            if let Some((cli, index)) = client {
+                slf.sleeper.reset();
+
+                let endpoint = &slf.endpoints[index];
+                let cli = match endpoint.options.acl.admit(cli, endpoint.options.deny_403, &endpoint.stats) {
+                    Some(cli) => cli,
+                    None => return Ok(IterNextOutput::Yield(None)),
+                };
+                let cli = match endpoint.options.memory_budget.as_ref() {
+                    Some(budget) => match budget.admit(cli, &endpoint.stats) {
+                        Some(cli) => cli,
+                        None => return Ok(IterNextOutput::Yield(None)),
+                    },
+                    None => cli,
+                };
+
+                if cli.set_nonblocking(true).is_err() {
+                    return Ok(IterNextOutput::Yield(None))
+                }
+                slf.spawn_connection(py, cli, index)?;
+                return Ok(IterNextOutput::Yield(None))
+            }
+
+            // the accept thread wakes us for the next one, there's no need to sleep, unless
+            // we're also watching for `on_shutdown` to finish
+            #[cfg(unix)]
+            if let Some(acceptor) = slf.acceptor.as_ref().filter(|_| slf.on_shutdown.is_none()) {
+                return Ok(IterNextOutput::Yield(acceptor.wait(py)?))
+            }
+
+            // Lets change our sleep so we sleep for a bit
+            slf.server_state = ServerState::Sleeping;
+        }
+
+        // Sleep x time (save cpu)
+        let nxt = slf.sleeper._iter_sleep(py);
+        if nxt.is_none() {
+            slf.server_state = ServerState::Accepting;
+        }
+
+        Ok(IterNextOutput::Yield(nxt))
+    }
+}
+
+///
+/// The runner is in a cycle with the task awaiting it whenever it's asleep
+/// (runner -> sleep future -> task -> runner), if that's abandoned (the loop
+/// is closed with it still pending) the GC has to be able to see through
+/// us to collect it, otherwise the listener stays bound until exit.
+/// Clearing closes the listener and drops the sleep, which breaks it.
+///
+#[pyproto]
+impl PyGCProtocol for AsyncServerRunner {
+    fn __traverse__(&self, visit: PyVisit) -> Result<(), PyTraverseError> {
+        visit.call(&self.callback)?;
+        if let Some(middleware) = self.middleware.as_ref() {
+            visit.call(middleware)?;
+        }
+        for callback in self.endpoints.iter().filter_map(|endpoint| endpoint.callback.as_ref()) {
+            visit.call(callback)?;
+        }
+        visit.call(&self.loop_)?;
+        if let Some(context) = self.context.as_ref() {
+            visit.call(context)?;
+        }
+        if let Some(loop_) = self.signal_loop.as_ref() {
+            visit.call(loop_)?;
+        }
+        if let Some((task, _)) = self.on_shutdown.as_ref() {
+            visit.call(task)?;
+        }
+        self.sleeper.traverse(visit)?;
+        #[cfg(unix)]
+        if let Some(acceptor) = self.acceptor.as_ref() {
+            acceptor.traverse(visit)?;
+        }
+        if let Some(logger) = self.access_logger.as_ref() {
+            visit.call(logger)?;
+        }
+        visit.call(&self.tasks)?;
+        for fut in self.ready_waiters.iter() {
+            visit.call(fut)?;
+        }
+        if let Ok(handler) = self.exception_handler.try_lock() {
+            if let Some(handler) = handler.as_ref() {
+                visit.call(handler)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn __clear__(&mut self) {
+        self.server.close();
+        if let Ok(mut handler) = self.exception_handler.lock() {
+            handler.take();
+        }
+        self.closed = true;
+        self.ready_waiters.clear();
+        self.signal_loop = None;
+        self.on_shutdown = None;
+        self.context = None;
+        self.sleeper.clear();
+        #[cfg(unix)]
+        if let Some(acceptor) = self.acceptor.as_ref() {
+            acceptor.clear();
+        }
+    }
+}
+
+
+/// How long a connection first sleeps for when its socket has nothing for us.
+const CONNECTION_POLL_DELAY: f32 = 0.001;
+
+/// The most that backs off to, how late a quiet keep-alive connection can be to notice its next request.
+const CONNECTION_MAX_POLL_DELAY: f32 = 0.02;
+
+/// The most we'll buffer while waiting for the end of the request head.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// The most buffer capacity a keep-alive connection holds on to between requests.
+const MAX_RETAINED_BUFFER: usize = 64 * 1024;
+
+/// The default for `read_buffer_size` and `read_high_water`.
+const DEFAULT_READ_SIZE: usize = 64 * 1024;
+
+/// What we send as the `Server` header.
+const SERVER_HEADER: &str = concat!("async-rust/", env!("CARGO_PKG_VERSION"));
+
+
+///
+/// How much of a response `serialize_response` writes after its head.
+///
+#[derive(Clone, Copy, PartialEq)]
+enum SerializedBody {
+    Full,
+    HeadOnly,                       // Answering a `HEAD` request
+    Streamed { chunked: bool },     // The body follows from a BodyStream
+    Tunnel,                         // An Upgrade's response, only the head since what follows is the protocol's
+}
+
+impl SerializedBody {
+    fn unless_head(head_only: bool) -> Self {
+        match head_only {
+            true => Self::HeadOnly,
+            false => Self::Full,
+        }
+    }
+}
+
+///
+/// How a response `OnceFuture::frame_h2()` queues goes on after its head.
+///
+enum H2Body {
+    Whole(Option<FileBody>),        // Its own body, then the rest of a FileResponse's file
+    Streamed { compress: bool },    // From a BodyStream, pushed a chunk at a time as it's produced
+}
+
+///
+/// Serializes a response into `out` along with our default headers (Date,
+/// Server and Connection), the body is left off for a `HEAD` request
+/// whatever the handler gave us. Returns if the connection can stay open
+/// afterwards, the handler can close it by sending `Connection: close`.
+///
+/// `requests` is how many requests the connection has had counting this
+/// one, the one that uses up `keep_alive_max_requests` closes it. With
+/// `keep_alive_header` a response that leaves it open says for how long and
+/// for how many more requests in `Keep-Alive`.
+///
+/// A streamed body without chunked framing can only end by closing the
+/// connection, so it never stays open. Neither does an HTTP/0.9 one which
+/// is only the body, there's nothing to put our defaults in.
+///
+/// `encoding` is what the client accepts (see `compress::accepted`), the
+/// response is compressed with it if it's worth it and the encoding used
+/// is given back, a streamed body still has to be compressed by the caller.
+///
+/// A tunnel (see `upgrade::Upgrade` and `tunnel::Tunnel`) is never kept alive but it isn't
+/// closed either, so it gets neither `Connection` nor any framing headers,
+/// a `2xx` to `CONNECT` mustn't have a `Content-Length`.
+///
+/// `request_id` goes out as `X-Request-Id` unless the handler sent its own,
+/// the same goes for `cors_origin` as `Access-Control-Allow-Origin` (see
+/// `Cors::allow_origin()`).
+///
+#[allow(clippy::too_many_arguments)]
+fn serialize_response(
+    response: &HTTPResponse,
+    options: &RunnerOptions,
+    date: &DateCache,
+    version: (u8, u8),
+    keep_alive: bool,
+    requests: u64,
+    body: SerializedBody,
+    encoding: Option<Encoding>,
+    request_id: Option<&str>,
+    cors_origin: Option<&str>,
+    out: &mut Outgoing,
+) -> (bool, Option<Encoding>) {
+    if version < (1, 0) {
+        if body == SerializedBody::Full {
+            response.serialize_body(out);
+        }
+        return (false, None)
+    }
+
+    let closing = response.header("connection").is_some_and(|value| {
+        value.split(',').any(|v| v.trim().eq_ignore_ascii_case("close"))
+    });
+    let left = options.keep_alive_max_requests.map(|max| max.saturating_sub(requests));
+    let keep_alive = keep_alive
+        && !closing
+        && body != SerializedBody::Streamed { chunked: false }
+        && body != SerializedBody::Tunnel
+        && left != Some(0);
+
+    let date = date.now();
+    let mut defaults = common_defaults(response, options, &date, request_id, cors_origin);
+
+    // 1.1 clients assume keep-alive, 1.0 ones have to be told
+    match keep_alive {
+        _ if body == SerializedBody::Tunnel => {},
+        false => defaults.push(("Connection", "close")),
+        true if version < (1, 1) => defaults.push(("Connection", "keep-alive")),
+        true => {},
+    }
+
+    let budget;
+    if keep_alive && options.keep_alive_header {
+        let timeout = options.keep_alive_timeout.map(|timeout| format!("timeout={}", timeout.floor()));
+        let max = left.map(|left| format!("max={}", left));
+        budget = timeout.into_iter().chain(max).collect::<Vec<_>>().join(", ");
+        if !budget.is_empty() {
+            defaults.push(("Keep-Alive", budget.as_str()));
+        }
+    }
+
+    let length = match body {
+        SerializedBody::Streamed { .. } | SerializedBody::Tunnel => None,
+        _ => Some(response.body_len()),
+    };
+
+    // caches have to know the body depends on Accept-Encoding even when it isn't compressed
+    let compressible = body != SerializedBody::Tunnel && compress::compressible(options, response, length);
+    let encoding = encoding.filter(|_| compressible);
+    if compressible && encoding.is_none() {
+        defaults.push(("Vary", "Accept-Encoding"));
+    }
+
+    let encoded;
+    let response = match encoding {
+        Some(encoding) => {
+            encoded = response.encoded(encoding, length.is_some());
+            &encoded
+        },
+        None => response,
+    };
+
+    match body {
+        SerializedBody::Full => response.serialize(&defaults, out),
+        SerializedBody::HeadOnly => response.serialize_head(&defaults, out.buffer()),
+        SerializedBody::Streamed { chunked } => response.serialize_stream_head(&defaults, chunked, out.buffer()),
+        SerializedBody::Tunnel => response.serialize_stream_head(&defaults, false, out.buffer()),
+    }
+
+    (keep_alive, encoding)
+}
+
+///
+/// The defaults every response gets whatever the protocol, Date, Server,
+/// `default_content_type` for a body and the `X-Request-Id` and cors
+/// headers `serialize_response` describes.
+///
+fn common_defaults<'a>(
+    response: &HTTPResponse,
+    options: &'a RunnerOptions,
+    date: &'a str,
+    request_id: Option<&'a str>,
+    cors_origin: Option<&'a str>,
+) -> Vec<(&'a str, &'a str)> {
+    // room for everything that can be pushed on, here and by the callers
+    let mut defaults = Vec::with_capacity(12);
+    defaults.push(("Date", date));
+    if options.server_header {
+        defaults.push(("Server", SERVER_HEADER));
+    }
+
+    if let Some(id) = request_id.filter(|_| response.header("x-request-id").is_none()) {
+        defaults.push(("X-Request-Id", id));
+    }
+
+    // only a body needs a type, an empty `200` or a `204` is left alone
+    if let Some(content_type) = options.default_content_type.as_deref().filter(|_| response.body_len() > 0) {
+        defaults.push(("Content-Type", content_type));
+    }
+
+    if let (Some(cors), Some(origin)) = (options.cors.as_ref(), cors_origin) {
+        cors.defaults(origin, &mut defaults);
+    }
+
+    defaults
+}
+
+///
+/// How much the next read can take, at most `read_buffer_size` and never
+/// more than `read_high_water` past `request_end` (where the request being
+/// parsed ends, or `None` when that isn't known until it arrives). 0 means
+/// we've read as far ahead as we're allowed to until the buffer is used.
+///
+fn read_size(options: &RunnerOptions, buffered: usize, request_end: Option<usize>) -> usize {
+    let limit = match request_end {
+        Some(end) => end.saturating_add(options.read_high_water),
+        None => usize::MAX,
+    };
+
+    options.read_buffer_size.min(limit.saturating_sub(buffered))
+}
+
+///
+/// Frees an idle connection's buffer if its capacity is over `retain`,
+/// anything still in it is kept. Gives back how many bytes that freed,
+/// the next use allocates afresh as the first one did.
+///
+pub(crate) fn reclaim_buffer(buffer: &mut Vec<u8>, retain: usize) -> usize {
+    let capacity = buffer.capacity();
+    if capacity <= retain {
+        return 0
+    }
+
+    buffer.shrink_to(buffer.len());
+    capacity - buffer.capacity()
+}
+
+///
+/// Reads with `read` onto the end of `buffer`, giving it room for `max`
+/// more bytes and trimming back off whatever it didn't fill.
+///
+fn read_into(buffer: &mut Vec<u8>, max: usize, read: impl FnOnce(&mut [u8]) -> io::Result<usize>) -> io::Result<usize> {
+    let len = buffer.len();
+    buffer.resize(len + max, 0);
+
+    let result = read(&mut buffer[len..]);
+    buffer.truncate(len + *result.as_ref().unwrap_or(&0));
+    result
+}
+
+///
+/// The `500` for a handler that raised, the traceback is only included
+/// when the runner has `debug=True`. A BadRequest it let out is the
+/// client's fault, that's a `400` saying what was wrong with the request.
+///
+fn error_response(py: Python, e: &PyErr, debug: bool) -> HTTPResponse {
+    if e.is_instance::<errors::BadRequest>(py) {
+        return HTTPResponse::from_parts(
+            400,
+            vec![(String::from("Content-Type"), String::from("text/plain; charset=utf-8"))],
+            e.pvalue(py).str().map_or_else(|_| Vec::new(), |reason| reason.to_string_lossy().into_owned().into_bytes()),
+        )
+    }
+
+    let body = match debug {
+        true => format_traceback(py, e).unwrap_or_default().into_bytes(),
+        false => Vec::new(),
+    };
+
+    HTTPResponse::from_parts(
+        500,
+        vec![(String::from("Content-Type"), String::from("text/plain; charset=utf-8"))],
+        body,
+    )
+}
+
+///
+/// Reports a handler's exception through the loop's exception handler so
+/// asyncio's debug tooling sees it, printing it if even that fails.
+///
+fn report_handler_error(py: Python, loop_: &PyObject, e: &PyErr, client: Option<(String, u16)>) {
+    // a bad request isn't the handler going wrong
+    if e.is_instance::<errors::BadRequest>(py) {
+        return
+    }
+
+    let reported = (|| -> PyResult<()> {
+        let context = PyDict::new(py);
+        context.set_item("message", "Unhandled exception in request handler")?;
+        context.set_item("exception", errors::handler_error(py, e).pvalue(py))?;
+        context.set_item("client", client)?;
+
+        loop_.call_method1(py, "call_exception_handler", (context,))?;
+        Ok(())
+    })();
+
+    if reported.is_err() {
+        errors::handler_error(py, e).print(py);
+    }
+}
+
+///
+/// An access log record, the fields are also passed as `extra` for
+/// structured handlers. `duration` is from the head arriving to the response
+/// being flushed and `handler_duration` the callback's part of it, both out
+/// of the request's timings (which are there in full as `timings`). A
+/// CONNECT that was tunnelled also has what went through it each way,
+/// `tunnel_sent` by the client and `tunnel_received` back from the target.
+/// Anything going wrong in logging is swallowed, it's never worth losing
+/// the connection over.
+///
+#[allow(clippy::too_many_arguments)]
+fn access_log(
+    py: Python,
+    logger: &PyObject,
+    client: Option<&(String, u16)>,
+    request_line: Option<&(String, String, String)>,
+    status: u16,
+    bytes: u64,
+    body_bytes: u64,
+    timings: &RequestTimings,
+    request_id: Option<&str>,
+    tunnel: Option<(u64, u64)>,
+) {
+    let _ = (|| -> PyResult<()> {
+        if !logger.call_method1(py, "isEnabledFor", (20,))?.as_ref(py).is_true()? {
+            return Ok(())
+        }
+
+        let client = match client {
+            Some((host, port)) => format!("{}:{}", host, port),
+            None => String::from("-"),
+        };
+        let (method, path, protocol) = request_line
+            .cloned()
+            .unwrap_or_else(|| ("-".into(), "-".into(), "-".into()));
+
+        let duration = timings.total();
+        let handler_duration = timings.handler();
+
+        let extra = PyDict::new(py);
+        extra.set_item("client", &client)?;
+        extra.set_item("method", &method)?;
+        extra.set_item("path", &path)?;
+        extra.set_item("status", status)?;
+        extra.set_item("bytes", bytes)?;
+        extra.set_item("body_bytes", body_bytes)?;
+        extra.set_item("duration", duration)?;
+        extra.set_item("handler_duration", handler_duration)?;
+        extra.set_item("timings", timings.to_dict(py)?)?;
+        extra.set_item("request_id", request_id)?;
+        if let Some((sent, received)) = tunnel {
+            extra.set_item("tunnel_sent", sent)?;
+            extra.set_item("tunnel_received", received)?;
+        }
+
+        let kwargs = PyDict::new(py);
+        kwargs.set_item("extra", extra)?;
+
+        let mut format = String::from("%s - \"%s %s %s\" %d %d %.2fms");
+        let mut args = vec![
+            client.to_object(py), method.to_object(py), path.to_object(py), protocol.to_object(py),
+            status.to_object(py), bytes.to_object(py), (duration * 1000.0).to_object(py),
+        ];
+
+        // the handler's part is only there when it ran to the end, a `400` or a `503` never gets one
+        if let Some(handler) = handler_duration {
+            format.push_str(" (handler %.2fms)");
+            args.push((handler * 1000.0).to_object(py));
+        }
+        if let Some((sent, received)) = tunnel {
+            format.push_str(" (tunnel %d sent %d received)");
+            args.extend([sent.to_object(py), received.to_object(py)]);
+        }
+        args.insert(0, format.to_object(py));
+
+        logger.call_method(py, "info", PyTuple::new(py, args), Some(kwargs))?;
+
+        Ok(())
+    })();
+}
+
+///
+/// OnceFuture drives a single connection, it reads the request head,
+/// hands the parsed request to the callback (awaiting it if it returns
+/// a coroutine) and then writes whatever response it gave us back.
+///
+///     state:
+///         0 - reading the PROXY protocol header (if it's on) then finishing
+///             the TLS handshake (skipped for plain TCP)
+///         1 - reading the request head and body
+///         2 - awaiting the callback
+///         3 - writing the response
+///         4 - flushing TLS and closing
+///         5 - running the websocket handler after a successful upgrade, or the
+///             protocol an Upgrade handed the connection to
+///         6 - awaiting the `on_headers` hook, between reading the head and the body
+///         7 - speaking HTTP/2, see `start_h2()`
+///         8 - connecting to where a CONNECT `connect_handler` accepted asks to go
+///         9 - relaying between the client and there once the `200` is out, see `tunnel::Tunnel`
+///
+/// Pipelined requests need nothing special, whatever comes in behind the
+/// request being handled waits in `buffer` and is parsed from there before
+/// we read again, so responses always go out in the order they were asked
+/// for. Anything after a `Connection: close` request is thrown away unread.
+///
+/// A connection that turns out to be HTTP/2 stays in state 7 until it's
+/// done, its streams' callbacks run side by side as tasks of their own.
+///
+#[pyclass(gc)]
+struct OnceFuture {
+    // External parameters
+    stream: Option<TcpStream>,          // The client's socket, handed over to the WebSocketConnection on upgrade
+    callback: PyObject,                 // The user's request handler
+    options: Arc<RunnerOptions>,        // The runner's options, including the websocket handler
+    date: Arc<DateCache>,               // The runner's cached `Date` header
+    tls: Option<TlsSession>,            // The TLS session if the listener terminates TLS
+    client: Option<(String, u16)>,      // The peer's (host, port) if we could get it
+    server: Option<(String, u16)>,      // Our end's (host, port)
+
+    // Internals
+    state: u8,                          // see above
+    proxy_header: bool,                 // Still waiting for the PROXY protocol header
+    sleeper: LoopSleeper,               // The non-blocking sleep used when the socket would block
+    buffer: Vec<u8>,                    // Bytes read off the socket but not yet parsed
+    head_end: Option<usize>,            // Where the request head ends once we've seen all of it
+    body_len: usize,                    // How much body follows the head, or how much of a chunked one in the buffer has been decoded
+    spool: Option<Spool>,               // Where a body past `spool_threshold` is going as it arrives
+    chunked: Option<ChunkedDecoder>,    // Decodes the body as it arrives for `Transfer-Encoding: chunked`
+    inbound: Option<Inbound>,           // A body `stream_request_body` leaves for the handler to read as it wants it
+    head: Option<Result<RequestHead, u16>>, // The checked head, or the status to refuse the request with
+    refusal: Option<&'static str>,      // Why a head that couldn't be parsed was refused, the response says
+    retry_after: Option<u64>,           // Set when `rate_limit` refuses the request, the `429` says when to come back
+    meta: Option<Py<RequestMeta>>,      // What `on_headers` was given, its `max_body_size` is the limit for the body
+    early: Option<Py<HTTPResponse>>,    // The response `on_headers` answered with, sent instead of calling the callback
+    linger: Option<Linger>,             // Set when a response leaves a body unread, it's drained a little before we close
+    drained: bool,                      // Set when a refused request's body is read anyway so the connection can carry on
+    interim: Vec<u8>,                   // A `100 Continue` still to be written before reading the body
+    version: (u8, u8),                  // The HTTP version of the request being handled
+    keep_alive: bool,                   // If we go back to reading another request after this one
+    requests: u64,                      // Requests read on the connection so far, for `keep_alive_max_requests`
+    encoding: Option<Encoding>,         // How the client will take a compressed response, see `compress::accepted`
+    awaiting: Option<Awaiting>,         // The callback's awaitable if it returned one
+    response: Outgoing,                 // The serialized response waiting to be written
+    file: Option<FileBody>,             // The file still to be sent after `response` for a FileResponse
+    conditional: Conditional,           // The request's `If-None-Match` / `If-Modified-Since` for a FileResponse
+    stream_body: Option<BodyStream>,    // The async generator still producing the body, if the handler returned one
+    alpn_protocol: Option<String>,      // The protocol negotiated via ALPN, None without TLS
+    server_name: Option<String>,        // The SNI name the client asked for, None without TLS
+    peer_certificate: Option<Vec<u8>>,  // The DER client certificate when using mTLS
+    tls_info: Option<Py<TLSInfo>>,      // The `request.tls` every request on this connection shares, None without TLS
+    upgrade: Option<Py<HTTPRequest>>,   // The request being upgraded once the 101 is queued
+    websocket: Option<Py<WebSocketConnection>>, // The connection handed to the websocket handler
+    hijack: Option<PyObject>,           // An Upgrade's `protocol_factory`, given the connection once the response is out
+    protocol: Option<PyObject>,         // The task running what `protocol_factory` returned
+    connect_target: Option<(String, u16)>,  // Where the CONNECT being handled asks to go, while `connect_handler` decides
+    dialing: Option<Connect>,           // Connecting there once `connect_handler` has accepted it
+    dialed: Option<SharedTransport>,    // The connection there while the `200` goes out
+    tunnel: Option<Tunnel>,             // The two connections an accepted CONNECT is relayed between
+    access_logger: Option<PyObject>,    // Where the access log goes, None when it's turned off
+    request_line: Option<(String, String, String)>, // The method, path and protocol for the access log
+    request_id: Option<String>,         // The `request.id` of the request being handled, once its head has arrived
+    cors_origin: Option<String>,        // The `Access-Control-Allow-Origin` for the request being handled, with `cors`
+    status: u16,                        // The status of the response being written
+    bytes_sent: u64,                    // How much has gone out on the socket for the response
+    body_from: Option<u64>,             // What `bytes_sent` will be once the response's head is out, the rest is body
+    throttle: Option<WriteThrottle>,    // Keeps response bodies to `max_write_rate_bytes_per_sec`, when there's a write rate
+    throttled: Option<f64>,             // How long the throttle says to wait before writing again, once it's refused a write
+    accepted: Instant,                  // When the connection was accepted, what every request's timings are from
+    timings: Arc<RequestTimings>,       // When each stage of the request being handled happened, see `request.timings`
+    lease: Option<RequestLease>,        // Lets the request being handled read its body, let go of once it's answered
+    connection: Option<ActiveGuard>,    // Keeps us counted as an active connection until we're done
+    memory: Option<MemoryCharge>,       // What our buffers are counted as against `memory_budget`, when there is one
+    profile: Option<Arc<Profile>>,      // The runner's section timings with `profiling=True`
+    activity: Arc<ConnectionActivity>,  // What `ConnectionInfo` reports about us
+    context: Option<Py<PyDict>>,        // The `request.connection` dict every request on this connection shares
+    base_context: Option<PyObject>,     // The runner's `contextvars.Context`, each request runs in a copy of its own
+    request_context: Option<PyObject>,  // The request being handled's copy, see `invoke()`
+    watching: Option<stream::RawSocket>,    // The fd we've given `add_reader` while awaiting the callback
+    unwatchable: bool,                  // The loop has no `add_reader` (the proactor on windows), we don't watch at all
+    deadline: Option<PyObject>,         // The `call_at` handle that times the callback out with `handler_timeout`
+    handler_deadline: Option<f64>,      // The `loop.time()` that is, set as the callback is invoked (see `request.deadline`)
+    head_received: Option<Instant>,     // When the head of the request being read arrived
+    timed_out: bool,                    // Set by the deadline just before it cancels our task
+    thrown: Option<PyErr>,              // What a future the callback awaited failed with, thrown into it on the next step
+    sniffed: bool,                      // Set once we know the connection isn't HTTP/2
+    h2: Option<H2Connection>,           // The HTTP/2 connection once we've switched to it
+
+}
+
+#[pymethods]
+impl OnceFuture {
+    ///
+    /// PythonMethod: OnceFuture.throw(type, value=None, traceback=None)
+    ///
+    ///     Our task was cancelled (shutdown, `abort()`...), the handler gets
+    ///     the exception too and the connection is closed right away rather
+    ///     than whenever the task happens to be collected.
+    ///
+    ///     When it's `handler_timeout` that cancelled us the handler is still
+    ///     cancelled but we carry on to answer with a `503`, see `time_out()`.
+    ///
+    ///     The task also throws in whatever a future we yielded for the
+    ///     callback failed with, that isn't the end of us, it goes on to the
+    ///     callback to catch (or fail with) as if it had awaited it itself.
+    ///
+    #[args(value = "None", _traceback = "None")]
+    fn throw(mut slf: PyRefMut<Self>, py: Python, type_: &PyAny, value: Option<&PyAny>, _traceback: Option<&PyAny>) -> PyResult<Option<PyObject>> {
+        let error = match value {
+            Some(value) if !value.is_none() => PyErr::from_instance(value),
+            _ => PyErr::from_instance(type_),
+        };
+
+        let awaited = (slf.state == 2 || slf.state == 6) && slf.awaiting.is_some() && !slf.timed_out;
+        if awaited && error.is_instance::<PyException>(py) && !is_cancelled(py, &error) {
+            slf.thrown = Some(error);
+            return match OnceFuture::resume(slf.into(), py) {
+                Ok(IterNextOutput::Yield(yielded)) => Ok(yielded),
+                Ok(IterNextOutput::Return(result)) => Err(PyStopIteration::new_err((result,))),
+                Err(e) => Err(e),
+            }
+        }
+
+        slf.abort(py, type_, value, error)
+    }
+
+    ///
+    /// The protocol negotiated with the client via ALPN e.g. `"http/1.1"`,
+    /// this is `None` for plain TCP connections or when the client didn't
+    /// offer any protocols.
+    ///
+    #[getter]
+    fn alpn_protocol(&self) -> Option<String> {
+        self.alpn_protocol.clone()
+    }
+
+    ///
+    /// The server name the client sent via SNI which picked the certificate,
+    /// handlers can compare this against the Host header. `None` for plain
+    /// TCP connections or clients that don't send SNI.
+    ///
+    #[getter]
+    fn server_name(&self) -> Option<String> {
+        self.server_name.clone()
+    }
+
+    ///
+    /// The DER encoded certificate the client authenticated with, only
+    /// present when the TLSConfig has a `ca_file` and the client sent one.
+    ///
+    #[getter]
+    fn peer_certificate(&self, py: Python) -> Option<PyObject> {
+        self.peer_certificate
+            .as_ref()
+            .map(|der| PyBytes::new(py, der).into())
+    }
+
+    ///
+    /// The subject common name of the client certificate, if there is one.
+    ///
+    #[getter]
+    fn peer_common_name(&self) -> Option<String> {
+        self.peer_certificate
+            .as_ref()
+            .and_then(|der| tls::subject_common_name(der))
+    }
+}
+
+impl OnceFuture {
+    ///
+    /// Connections are only ever built here by the runner, there is no
+    /// constructor on the python side so nothing can end up driving a
+    /// OnceFuture without a socket. The socket is closed when it's dropped.
+    ///
+    fn new(stream: TcpStream, callback: PyObject, loop_: PyObject) -> Self {
+        let accepted = Instant::now();
+        OnceFuture {
+            stream: Some(stream),
+            callback,
+            options: Arc::default(),
+            date: Arc::default(),
+            tls: None,
+            client: None,
+            server: None,
+            state: 0,
+            proxy_header: false,
+            sleeper: LoopSleeper::for_connection(loop_),
+            buffer: Vec::new(),
+            head_end: None,
+            body_len: 0,
+            spool: None,
+            chunked: None,
+            inbound: None,
+            head: None,
+            refusal: None,
+            retry_after: None,
+            meta: None,
+            early: None,
+            linger: None,
+            drained: false,
+            interim: Vec::new(),
+            version: (1, 1),
+            keep_alive: false,
+            requests: 0,
+            awaiting: None,
+            response: Outgoing::default(),
+            file: None,
+            conditional: Conditional::default(),
+            stream_body: None,
+            encoding: None,
+            alpn_protocol: None,
+            server_name: None,
+            peer_certificate: None,
+            tls_info: None,
+            upgrade: None,
+            websocket: None,
+            hijack: None,
+            protocol: None,
+            connect_target: None,
+            dialing: None,
+            dialed: None,
+            tunnel: None,
+            access_logger: None,
+            request_line: None,
+            request_id: None,
+            cors_origin: None,
+            status: 0,
+            bytes_sent: 0,
+            body_from: None,
+            throttle: None,
+            throttled: None,
+            accepted,
+            timings: Arc::new(RequestTimings::new(accepted)),
+            lease: None,
+            connection: None,
+            memory: None,
+            profile: None,
+            activity: Arc::default(),
+            context: None,
+            base_context: None,
+            request_context: None,
+            watching: None,
+            unwatchable: false,
+            deadline: None,
+            handler_deadline: None,
+            head_received: None,
+            timed_out: false,
+            thrown: None,
+            sniffed: false,
+            h2: None,
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::read_some() -> io::Result<usize>
+    ///
+    ///     Reads up to `max` bytes of whatever is available off the socket
+    ///     (decrypting it first if we're using TLS) onto the end of the
+    ///     buffer, `Ok(0)` means the client has gone away.
+    ///
+    ///     The GIL is released for the read so other python threads aren't
+    ///     held up by the syscall or the decryption, all it touches is the
+    ///     socket, the TLS session and the buffer.
+    ///
+    fn read_some(&mut self, py: Python, max: usize) -> io::Result<usize> {
+        let sock = self.stream.as_ref().unwrap();
+        let tls = self.tls.as_mut();
+        let buffer = &mut self.buffer;
+
+        stats::timed(self.profile.as_deref(), Section::Read, || py.allow_threads(move || read_into(buffer, max, |buf| match tls {
+            Some(tls) => tls.read(sock, buf),
+            None => (&*sock).read(buf),
+        })))
+    }
+
+    /// Writes as much of `buf` as the socket takes, without the GIL.
+    fn write_some(&mut self, py: Python, buf: &[u8]) -> io::Result<usize> {
+        let sock = self.stream.as_ref().unwrap();
+        let tls = self.tls.as_mut();
+
+        let n = stats::timed(self.profile.as_deref(), Section::Write, || py.allow_threads(move || match tls {
+            Some(tls) => tls.write(sock, buf),
+            None => (&*sock).write(buf),
+        }))?;
+
+        self.record_sent(n as u64);
+        Ok(n)
+    }
+
+    /// Writes some more of `response`, the head and body in one go on plain TCP.
+    fn write_response(&mut self, py: Python) -> io::Result<usize> {
+        let limit = self.throttle_write(self.response.remaining_len() as u64)? as usize;
+        let sock = self.stream.as_ref().unwrap();
+        let tls = self.tls.as_mut();
+        let response = &self.response;
+
+        let n = stats::timed(self.profile.as_deref(), Section::Write, || py.allow_threads(move || match tls {
+            Some(tls) => {
+                let next = response.next();
+                tls.write(sock, &next[..next.len().min(limit)])
+            },
+            None => response.write_to(sock, limit),
+        }))?;
+
+        self.response.advance(n);
+        self.record_sent(n as u64);
+        Ok(n)
+    }
+
+    ///
+    /// Internal Method: OnceFuture::read_request() -> io::Result<Option<usize>>
+    ///
+    ///     Reads into the buffer until we've seen the blank line ending the
+    ///     request head and then however much body its `Content-Length` says
+    ///     follows, returning where the head ends or `None` if the socket ran
+    ///     dry before then.
+    ///
+    ///     A head we're going to refuse doesn't wait for the body, and a
+    ///     client which sent `Expect: 100-continue` only gets its `100` once
+    ///     we know we're going to accept it.
+    ///
+    ///     A complete request already in the buffer is always handed back
+    ///     before we read again. EOF part way through a request, or while
+    ///     idle between keep-alive requests, is `UnexpectedEof` and the
+    ///     connection is closed without a response. A client half-closing
+    ///     (`SHUT_WR`) while the callback is running looks the same as one
+    ///     that disconnected, it gets cancelled (see DisconnectWatch).
+    ///
+    fn read_request(&mut self, py: Python) -> io::Result<Option<usize>> {
+        loop {
+            if self.head_end.is_none() {
+                let parsed = stats::timed(self.profile.as_deref(), Section::Parse, || http::parse_head(&self.buffer, &self.options));
+                let parsed = match parsed {
+                    Ok(Some((head, end))) => Some((Ok(head), end)),
+                    Ok(None) => None,
+
+                    // the rest of a head we can't parse is never read
+                    Err(e) => Some((Err(e), self.buffer.len())),
+                };
+
+                if let Some((parsed, end)) = parsed {
+                    let head = self.check_head(parsed).and_then(|head| self.check_rate(head));
+                    self.head_end = Some(end);
+
+                    match head {
+                        Ok(head) if self.options.on_headers.is_some() && self.retry_after.is_none() => {
+                            // the body isn't touched until the hook is done with the head
+                            if !self.start_on_headers(py, head) {
+                                self.state = 6;
+                                return Ok(None)
+                            }
+                        },
+                        head => self.frame_body(head, self.options.max_body_size),
+                    }
+                }
+            }
+
+            while !self.interim.is_empty() {
+                let interim = std::mem::take(&mut self.interim);
+                match self.write_some(py, &interim) {
+                    Ok(n) => self.interim = interim[n..].to_vec(),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        self.interim = interim;
+                        return Ok(None)
+                    },
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if let Some(end) = self.head_end {
+                if self.streams_body() {
+                    return Ok(Some(end))
+                }
+
+                if let Some(decoder) = self.chunked.as_mut() {
+                    let rest = &self.buffer[end + self.body_len..];
+                    let fed = stats::timed(self.profile.as_deref(), Section::Parse, || decoder.feed(rest));
+                    match fed {
+                        Ok(used) => self.body_len += used,
+                        Err(e) => {
+                            let status = match e {
+                                ChunkError::TooLarge => 413,
+                                ChunkError::Invalid => {
+                                    self.refusal = Some("malformed chunked body");
+                                    400
+                                },
+                            };
+
+                            // whatever is left of the body is never read
+                            self.head = Some(Err(status));
+                            self.chunked = None;
+                            self.spool = None;
+                            self.linger = Some(Linger::default());
+                            return Ok(Some(end))
+                        },
+                    }
+
+                    let done = decoder.is_done();
+                    if let Err(e) = self.spool_decoded(end) {
+                        self.spool_failed(&e);
+                        return Ok(Some(end))
+                    }
+
+                    if done {
+                        return Ok(Some(end))
+                    }
+                } else if let Some(spool) = self.spool.as_mut() {
+                    // a spooled body never builds up in the buffer, what's
+                    // read of it is written out straight away
+                    let take = (self.buffer.len() - end).min(self.body_len - spool.len());
+                    if let Err(e) = spool.write(&self.buffer[end..end + take]) {
+                        self.spool_failed(&e);
+                        return Ok(Some(end))
+                    }
+
+                    self.buffer.drain(end..end + take);
+                    if spool.len() == self.body_len {
+                        return Ok(Some(end))
+                    }
+                } else if self.buffer.len() >= end + self.body_len {
+                    return Ok(Some(end))
+                }
+            }
+
+            // the request itself is always read, it's only whatever we'd
+            // read past the end of it that `read_high_water` holds back
+            let request_end = match (self.head_end, self.chunked.is_some()) {
+                (None, _) => Some(MAX_HEAD_SIZE),
+                (Some(_), true) => None,
+                (Some(end), false) => Some(end + self.body_len - self.spool.as_ref().map_or(0, Spool::len)),
+            };
+
+            let max = read_size(&self.options, self.buffer.len(), request_end);
+            match self.read_some(py, max) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => self.activity.touch(),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::check_head() -> Result<RequestHead, u16>
+    ///
+    ///     Everything we can refuse a request for from the head alone, `Err`
+    ///     is the status to answer with.
+    ///
+    fn check_head(&mut self, parsed: Result<RequestHead, HeadError>) -> Result<RequestHead, u16> {
+        self.head_received = Some(Instant::now());
+        self.timings.mark(Stage::Headers);
+        self.request_id = Some(request_id::assign(&self.options, parsed.as_ref().ok().map(|head| &head.headers)));
+
+        let mut head = match parsed {
+            Ok(head) if http::valid_framing(&head.headers) => head,
+            parsed => {
+                if let Some(connection) = self.connection.as_ref() {
+                    connection.stats().parse_error();
+                }
+
+                let error = parsed.err().unwrap_or(HeadError::invalid("conflicting or invalid body framing"));
+                self.refusal = Some(error.reason);
+                return Err(error.status)
+            },
+        };
+
+        let target = match self.options.log_raw_path {
+            true => http::latin1(&head.target),
+            false => http::request_target(&head.target),
+        };
+        self.activity.set_request(Some(format!("{} {} {}", head.method, target, head.protocol)));
+        self.request_line = Some((head.method.clone(), target, head.protocol.clone()));
+
+        let allowed_hosts = self.options.allowed_hosts.as_deref();
+        http::check_host(
+            &mut head.target,
+            head.version,
+            &mut head.headers,
+            allowed_hosts,
+            self.options.invalid_host_status,
+        )?;
+
+        if !http::method_allowed(&self.options, &head.method) {
+            return Err(501)
+        }
+        self.check_sni(&head.headers, self.request_id.as_deref())?;
+
+        self.version = head.version;
+        self.keep_alive = http::keep_alive(head.version, &head.headers);
+
+        Ok(head)
+    }
+
+    ///
+    /// Internal Method: OnceFuture::check_sni() -> Result<(), u16>
+    ///
+    ///     With `sni_mismatch` a request over TLS has to be for the host the
+    ///     client named with SNI. One for another host (HTTP/2 clients reuse
+    ///     a connection for any name the certificate covers) is logged, or
+    ///     refused with a `421` so the client opens a connection of its own
+    ///     for it. A client that gave no SNI (it connected by address) or a
+    ///     request without a Host isn't checked.
+    ///
+    fn check_sni(&self, headers: &Headers, request_id: Option<&str>) -> Result<(), u16> {
+        let (mode, sni) = match (self.options.sni_mismatch, self.server_name.as_deref()) {
+            (Some(mode), Some(sni)) => (mode, sni),
+            _ => return Ok(()),
+        };
+
+        let host = match headers.get(HeaderName::Host) {
+            Some(host) => http::host_name(host.trim()),
+            None => return Ok(()),
+        };
+
+        if host.eq_ignore_ascii_case(sni) {
+            return Ok(())
+        }
+
+        match mode {
+            SniMismatch::Reject => Err(421),
+            SniMismatch::Log => {
+                log::warning(&format!(
+                    "request {} is for Host '{}' on a TLS connection opened for '{}'",
+                    request_id.unwrap_or("-"), host, sni,
+                ));
+                Ok(())
+            },
+        }
+    }
+
+    ///
+    /// Takes a token from the client's `rate_limit` bucket. A client that's
+    /// out of them still has its body read so the connection can carry on
+    /// after the `429`, unless it's gone past `rate_limit_burst` in which case
+    /// it's refused here and the connection closed.
+    ///
+    fn check_rate(&mut self, head: RequestHead) -> Result<RequestHead, u16> {
+        let (retry_after, close) = match ratelimit::check(&self.options, self.client.as_ref(), &head.headers) {
+            Verdict::Allowed => return Ok(head),
+            Verdict::Limited(retry_after) => (retry_after, false),
+            Verdict::Exceeded(retry_after) => (retry_after, true),
+        };
+
+        if let Some(connection) = self.connection.as_ref() {
+            connection.stats().rate_limited();
+        }
+
+        self.retry_after = Some(retry_after);
+        match close {
+            true => Err(429),
+            false => Ok(head),
+        }
+    }
+
+    ///
+    /// The last of the checks on a head, the ones that decide how its body
+    /// is read. A head refused by them still has a small body read (see
+    /// `drain_len()`) so the connection can carry on after the refusal,
+    /// anything else is closed after it.
+    ///
+    fn frame_body(&mut self, head: Result<RequestHead, u16>, max_body_size: usize) {
+        let drain = head.as_ref().ok().and_then(|head| self.drain_len(head));
+        let head = head
+            .and_then(|head| self.check_body(head, max_body_size))
+            .and_then(|head| self.check_expect(head));
+
+        if head.is_err() {
+            self.chunked = None;
+            self.spool = None;
+            self.body_len = drain.unwrap_or(0);
+            self.drained = drain.is_some();
+            if drain.is_none() {
+                self.linger = Some(Linger::default());
+            }
+        }
+
+        self.head = Some(head);
+    }
+
+    ///
+    /// How much body we'd read and throw away to keep the connection after
+    /// answering without it, None if it has to be closed instead. Only a
+    /// `Content-Length` up to `max_drain_bytes` is, and not one whose
+    /// client is waiting on a `100 Continue` before it sends it.
+    ///
+    fn drain_len(&self, head: &RequestHead) -> Option<usize> {
+        let expects_continue = head.version >= (1, 1) && head.headers.contains(HeaderName::Expect);
+        match http::body_framing(&head.headers, self.options.max_drain_bytes) {
+            Ok(BodyFraming::Length(len)) if len == 0 || !expects_continue => Some(len),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Works out how the body is delimited, a `Content-Length` over
+    /// `max_body_size` is refused with a `413` before any of it is read and a
+    /// chunked body is held to the same limit as it's decoded. One over
+    /// `spool_threshold` is spooled from the start, a chunked one only once
+    /// it gets past it (see `spool_decoded()`). While we're over
+    /// `memory_budget` a request with any body at all is a `503`.
+    ///
+    fn check_body(&mut self, head: RequestHead, max_body_size: usize) -> Result<RequestHead, u16> {
+        let framing = http::body_framing(&head.headers, max_body_size)?;
+        if !matches!(framing, BodyFraming::Length(0)) && self.shed_body() {
+            self.refusal = Some("the server is out of memory for request bodies");
+            return Err(503)
+        }
+
+        match framing {
+            BodyFraming::Length(len) => {
+                self.body_len = len;
+                if let Some(policy) = self.options.spool.as_ref().filter(|policy| policy.spools(len)) {
+                    match Spool::create(&policy.dir) {
+                        Ok(spool) => self.spool = Some(spool),
+                        Err(e) => {
+                            log::error(&format!("spooling a request body: {}", e));
+                            self.refusal = Some("the body couldn't be spooled");
+                            return Err(500)
+                        },
+                    }
+                }
+            },
+            BodyFraming::Chunked(decoder) => self.chunked = Some(decoder),
+        }
+
+        Ok(head)
+    }
+
+    ///
+    /// Internal Method: OnceFuture::spool_decoded() -> io::Result<()>
+    ///
+    ///     Writes what the chunked decoder has decoded out to the spool once
+    ///     the body is past `spool_threshold`, the framing it was decoded
+    ///     from goes from the buffer with it so neither builds up.
+    ///
+    fn spool_decoded(&mut self, end: usize) -> io::Result<()> {
+        let decoder = match self.chunked.as_mut() {
+            Some(decoder) => decoder,
+            None => return Ok(()),
+        };
+
+        if self.spool.is_none() {
+            match self.options.spool.as_ref() {
+                Some(policy) if policy.spools(decoder.decoded()) => self.spool = Some(Spool::create(&policy.dir)?),
+                _ => return Ok(()),
+            }
+        }
+
+        if let Some(spool) = self.spool.as_mut() {
+            spool.write(decoder.body())?;
+            decoder.clear_body();
+            self.buffer.drain(end..end + self.body_len);
+            self.body_len = 0;
+        }
+
+        Ok(())
+    }
+
+    /// A body that couldn't be spooled (the disk's full...) is a `500`, the rest of it is never read.
+    fn spool_failed(&mut self, e: &io::Error) {
+        log::error(&format!("spooling a request body: {}", e));
+        self.refusal = Some("the body couldn't be spooled");
+        self.head = Some(Err(500));
+        self.body_len = 0;
+        self.chunked = None;
+        self.spool = None;
+        self.linger = Some(Linger::default());
+    }
+
+    ///
+    /// With `stream_request_body` the callback is invoked as soon as the
+    /// head has been accepted, its body is left for it to read with
+    /// `request.stream()` (see Inbound). A request being refused (or
+    /// answered by `on_headers`) still has its body read or drained as
+    /// normal, and one without a body has nothing to stream.
+    ///
+    fn streams_body(&mut self) -> bool {
+        let accepted = matches!(self.head, Some(Ok(_))) && self.early.is_none() && self.retry_after.is_none();
+        if !self.options.stream_request_body || !accepted {
+            return false
+        }
+
+        let framing = match self.chunked.take() {
+            Some(decoder) => BodyFraming::Chunked(decoder),
+            None if self.body_len > 0 => BodyFraming::Length(std::mem::take(&mut self.body_len)),
+            None => return false,
+        };
+
+        self.inbound = Some(Inbound::new(framing));
+        true
+    }
+
+    ///
+    /// Queues the `100 Continue` for a request that asked for one and has a
+    /// body to send, any expectation other than `100-continue` is a `417`.
+    /// HTTP/1.0 clients don't know about `100` so theirs is ignored.
+    ///
+    fn check_expect(&mut self, head: RequestHead) -> Result<RequestHead, u16> {
+        match head.headers.get(HeaderName::Expect) {
+            Some(expect) if head.version >= (1, 1) => {
+                if !expect.trim().eq_ignore_ascii_case("100-continue") {
+                    return Err(417)
+                }
+
+                if self.body_len > 0 || self.chunked.is_some() {
+                    self.interim = b"HTTP/1.1 100 Continue\r\n\r\n".to_vec();
+                }
+            },
+            _ => {},
+        }
+
+        Ok(head)
+    }
+
+    ///
+    /// Internal Method: OnceFuture::start_on_headers() -> bool
+    ///
+    ///     Hands the head to the `on_headers` hook, true if it's already
+    ///     done with it. An awaitable it returns is kept in `awaiting` and
+    ///     stepped from state 6 instead.
+    ///
+    fn start_on_headers(&mut self, py: Python, head: RequestHead) -> bool {
+        let hook = self.options.on_headers.as_ref().unwrap().clone_ref(py);
+        let id = self.request_id.clone().unwrap_or_default();
+        let meta = RequestMeta::new(&head, &self.options, self.client.clone(), id);
+        self.head = Some(Ok(head));
+
+        let result = Py::new(py, meta).and_then(|meta| {
+            self.meta = Some(meta.clone_ref(py));
+            hook.call1(py, (meta,))
+        });
+
+        let result = match result {
+            Ok(result) if result.as_ref(py).hasattr("__await__").unwrap_or(false) => {
+                Awaiting::of(result.as_ref(py))
+            },
+            other => {
+                self.finish_on_headers(py, other);
+                return true
+            },
+        };
+
+        match result {
+            Ok(awaiting) => {
+                self.awaiting = awaiting;
+                false
+            },
+            Err(e) => {
+                self.finish_on_headers(py, Err(e));
+                true
+            },
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::finish_on_headers()
+    ///
+    ///     What the hook came back with, `None` carries on to the body with
+    ///     whatever `max_body_size` it left on the meta. A response is sent
+    ///     in place of calling the callback, the body still has to be got
+    ///     past for the connection to carry on so a small one is read and
+    ///     thrown away and anything else closes the connection without
+    ///     reading it. The hook raising is a `500` like the callback raising.
+    ///
+    fn finish_on_headers(&mut self, py: Python, result: PyResult<PyObject>) {
+        let head = match self.head.take() {
+            Some(Ok(head)) => head,
+            other => {
+                self.head = other;
+                return
+            },
+        };
+
+        let max_body_size = match self.meta.take() {
+            Some(meta) => meta.borrow(py).max_body_size,
+            None => self.options.max_body_size,
+        };
+
+        let response = match result {
+            Ok(result) if result.is_none(py) => return self.frame_body(Ok(head), max_body_size),
+            Ok(result) => result.extract::<Py<HTTPResponse>>(py),
+            Err(e) => Err(e),
+        };
+
+        let response = response.or_else(|e| {
+            self.report(py, &e);
+            Py::new(py, error_response(py, &e, self.options.debug))
+        });
+
+        match self.drain_len(&head) {
+            Some(len) => self.body_len = len,
+            None => {
+                self.keep_alive = false;
+                self.body_len = 0;
+                self.linger = Some(Linger::default());
+            },
+        }
+
+        self.early = response.ok();
+        self.head = Some(Ok(head));
+    }
+
+    ///
+    /// Internal Method: OnceFuture::start_request() -> PyResult<()>
+    ///
+    ///     Parses the head and body into a HTTPRequest and invokes the callback,
+    ///     if the callback gives us something awaitable we keep hold of its
+    ///     iterator to drive later otherwise it's treated as the response.
+    ///
+    fn start_request(&mut self, py: Python, head_end: usize) -> PyResult<()> {
+        self.requests += 1;
+
+        // a refused request's body is only read when it's drained
+        let head = self.head.take().unwrap_or(Err(400));
+        if head.is_ok() || self.drained {
+            self.timings.mark(Stage::Body);
+        }
+        let spooled = self.spool.take();
+        let (body, trailers) = match self.chunked.take() {
+            Some(decoder) => {
+                self.buffer.drain(..head_end + self.body_len);
+                let (body, trailers) = decoder.into_parts();
+                (body.into(), trailers)
+            },
+            None if spooled.is_some() => {
+                self.buffer.drain(..head_end);
+                (Vec::new().into(), Headers::new())
+            },
+            None => (self.take_body(head_end), Headers::new()),
+        };
+        self.head_end = None;
+
+        // anything we refuse at this point could have left a body we didn't read
+        let head = match head {
+            Ok(head) => head,
+            Err(status) => {
+                self.keep_alive &= self.drained;
+                let response = match (status, self.refusal.take()) {
+                    (429, _) => ratelimit::too_many_requests(self.retry_after.unwrap_or(1)),
+                    (status, Some(reason)) => HTTPResponse::refused(status, reason),
+                    (status, _) => HTTPResponse::with_status(status),
+                };
+                self.set_response(response);
+                return Ok(())
+            },
+        };
+
+        if let Some(retry_after) = self.retry_after {
+            self.set_response(ratelimit::too_many_requests(retry_after));
+            return Ok(())
+        }
+
+        let mut request = HTTPRequest::new(head.method, head.target, head.protocol, head.headers, body);
+        request.target = head.form;
+        request.trailers = trailers;
+        request.spooled = spooled;
+        if let Some(inbound) = self.inbound.as_mut() {
+            request.stream_from(py, inbound)?;
+        }
+        self.encoding = compress::accepted(&self.options, &request.headers);
+        self.conditional = Conditional::from_headers(&request.method, &request.headers);
+        self.cors_origin = self.options.cors.as_ref().and_then(|cors| cors.allow_origin(&request.headers));
+
+        // a `..` trying to get above the root
+        if request.normalize(self.options.merge_slashes).is_err() {
+            if let Some(connection) = self.connection.as_ref() {
+                connection.stats().parse_error();
+            }
+
+            self.set_response(HTTPResponse::with_status(400));
+            return Ok(())
+        }
+
+        if let Some(connection) = self.connection.as_ref() {
+            connection.stats().request();
+        }
+        self.activity.request();
+
+        if let Some(response) = self.early.take() {
+            return self.queue_checked(&response.borrow(py))
+        }
+
+        if request.raw_path == b"*" && !self.options.pass_options_star {
+            self.set_response(router::options_star(py, &self.callback, &self.options));
+            return Ok(())
+        }
+
+        if let Some(response) = self.options.cors.as_ref().and_then(|cors| cors.preflight(&request.method, &request.headers)) {
+            self.set_response(response);
+            return Ok(())
+        }
+
+        request.client = self.client.clone();
+        request.remote_addr = forwarded::remote_addr(&self.options.trusted_proxies, self.options.forwarded_header, request.client.as_ref(), &request.headers);
+        request.server = self.server.clone();
+        request.id = self.request_id.clone().unwrap_or_default();
+        request.connection = self.context.as_ref().map(|context| context.clone_ref(py));
+        request.tls = self.tls_info.as_ref().map(|info| info.clone_ref(py));
+        if self.tls.is_some() {
+            request.scheme = String::from("https");
+        }
+        self.lease = Some(request.lease());
+
+        if self.options.websocket.is_some() && websocket::is_upgrade(&request.headers) {
+            match websocket::handshake(&request.method, &request.headers) {
+                Ok(response) => {
+                    self.upgrade = Some(Py::new(py, request)?);
+                    self.set_response(response);
+                },
+                Err(response) => self.set_response(response),
+            }
+
+            return Ok(())
+        }
+
+        if let Some(timeout) = self.options.handler_timeout {
+            let now: f64 = self.sleeper.loop_.call_method0(py, "time")?.extract(py)?;
+            self.handler_deadline = Some(now + f64::from(timeout));
+        }
+        request.deadline = self.handler_deadline;
+        request.received = self.head_received.unwrap_or_else(Instant::now);
+        request.timings = self.timings.clone();
+
+        // `proxy_mode` is the only way a CONNECT gets this far, always for a host:port
+        self.connect_target = match (&request.target, self.options.connect_handler.is_some()) {
+            (TargetForm::Authority { host, port }, true) => Some((host.clone(), *port)),
+            _ => None,
+        };
+
+        let request = Py::new(py, request)?;
+
+        // whatever middleware (or the callback) sets is seen by the rest
+        // of this request, its access log included, and nothing else
+        self.request_context = self.base_context.as_ref().map(|context| context::copy(py, context)).transpose()?;
+        let entered = context::enter(py, self.request_context.as_ref())?;
+
+        self.timings.mark(Stage::HandlerStart);
+        let result = stats::timed(self.profile.as_deref(), Section::Handler, || match (self.connect_target.as_ref(), self.options.connect_handler.as_ref()) {
+            (Some((host, port)), Some(handler)) => handler.call1(py, (host, *port, request)),
+            _ => self.callback.call1(py, (request,)),
+        });
+        drop(entered);
+        let result = result?;
+        self.awaiting = Awaiting::of(result.as_ref(py))?;
+        match self.awaiting.is_some() {
+            true => self.state = 2,
+            false => self.finish_request(py, result)?,
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: OnceFuture::take_body() -> RequestBody
+    ///
+    ///     Takes the body after the head out of the read buffer, leaving
+    ///     anything pipelined after it. A body too big for us to keep the
+    ///     buffer around for anyway takes the whole buffer with it instead of
+    ///     being copied (see `HTTPRequest.body_view()`), since the request
+    ///     owns it from then on it's never reused for the next request.
+    ///
+    fn take_body(&mut self, head_end: usize) -> RequestBody {
+        let end = head_end + self.body_len;
+        if self.body_len < MAX_RETAINED_BUFFER {
+            let body = self.buffer[head_end..end].to_vec();
+            self.buffer.drain(..end);
+            return body.into()
+        }
+
+        let rest = self.buffer.split_off(end);
+        RequestBody::within(std::mem::replace(&mut self.buffer, rest), head_end)
+    }
+
+    ///
+    /// Takes whatever the callback produced and queues it to be written,
+    /// anything other than our response types goes through
+    /// `HTTPResponse::from_return()`.
+    ///
+    /// An async generator (anything with `__aiter__`) is a `200 OK` whose
+    /// body is streamed as it's produced, chunked for HTTP/1.1 and ended by
+    /// closing the connection for HTTP/1.0. A `HEAD` request only gets the
+    /// head, the generator is never started.
+    ///
+    /// A CONNECT `connect_handler` accepted isn't answered yet, its target
+    /// is connected to first (see `dial()`).
+    ///
+    fn finish_request(&mut self, py: Python, result: PyObject) -> PyResult<()> {
+        self.timings.handler_done();
+
+        // `connect_handler` accepts the CONNECT with None and refuses it
+        // with a status, anything else answers it like any other request
+        if let Some((host, port)) = self.connect_target.take() {
+            if result.is_none(py) {
+                self.dialing = Some(Connect::new(&host, port, Some(tunnel::DIAL_TIMEOUT), None));
+                self.state = 8;
+                return Ok(())
+            }
+
+            if let Ok(status) = result.extract::<u16>(py) {
+                if (200..300).contains(&status) {
+                    return Err(PyValueError::new_err("connect_handler accepts a CONNECT by returning None, a status it returns has to refuse it"))
+                }
+
+                self.set_response(HTTPResponse::with_status(status));
+                return Ok(())
+            }
+        }
+        if let Ok(file) = result.extract::<PyRef<FileResponse>>(py) {
+            match file.open(&self.conditional) {
+                Ok((head, body)) => {
+                    self.set_response(head);
+                    if !self.is_head() {
+                        self.file = body;
+                    }
+                },
+                Err(status) => self.set_response(HTTPResponse::with_status(status)),
+            }
+
+            return Ok(())
+        }
+
+        if let Ok(events) = result.extract::<PyRef<EventSourceResponse>>(py) {
+            // compressing would hold events back until there was enough to be worth it
+            self.encoding = None;
+            let chunked = self.version >= (1, 1);
+            self.queue_head(&events.head(), SerializedBody::Streamed { chunked });
+            if !self.is_head() {
+                self.stream_body = Some(events.stream(py, &self.sleeper.loop_, chunked)?);
+            }
+
+            return Ok(())
+        }
+
+        if let Ok(upgrade) = result.extract::<PyRef<Upgrade>>(py) {
+            // the connection is the protocol's from here on, never ours to read another request from
+            self.queue_head(&upgrade.response.borrow(py), SerializedBody::Tunnel);
+            self.hijack = Some(upgrade.factory.clone_ref(py));
+            return Ok(())
+        }
+
+        if BodyStream::is_stream(result.as_ref(py))? {
+            let chunked = self.version >= (1, 1);
+            let encoding = self.queue_head(&HTTPResponse::default(), SerializedBody::Streamed { chunked });
+            if !self.is_head() {
+                self.stream_body = Some(BodyStream::new(py, result.as_ref(py), chunked, encoding)?);
+            }
+
+            return Ok(())
+        }
+
+        match result.extract::<PyRef<HTTPResponse>>(py) {
+            Ok(response) => self.queue_checked(&response),
+            Err(_) => self.queue_checked(&HTTPResponse::from_return(result.as_ref(py))?),
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::start_websocket() -> PyResult<()>
+    ///
+    ///     Once the `101` has been written the socket (and anything already
+    ///     buffered after the handshake) is handed to a WebSocketConnection
+    ///     and the websocket handler is invoked with it.
+    ///
+    fn start_websocket(&mut self, py: Python) -> PyResult<()> {
+        // as far as HTTP goes the request is done once the 101 is out
+        self.log_access(py);
+
+        let request = self.upgrade.take().unwrap();
+        let sock = self.stream.take().unwrap();
+
+        let ws = Py::new(py, WebSocketConnection::new(
+            self.sleeper.loop_.clone_ref(py),
+            sock,
+            self.tls.take(),
+            std::mem::take(&mut self.buffer),
+        ))?;
+        self.websocket = Some(ws.clone_ref(py));
+        self.state = 5;
+
+        let callback = self.options.websocket.as_ref().unwrap();
+        let result = callback.call1(py, (request, ws))?;
+        self.awaiting = Awaiting::of(result.as_ref(py))?;
+
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: OnceFuture::start_protocol() -> PyResult<()>
+    ///
+    ///     Once an Upgrade's response has been written the socket (and
+    ///     anything already buffered after the request) goes to a Reader /
+    ///     Writer pair for its `protocol_factory`. What it returns runs as a
+    ///     task of its own which we wait on, so the connection is still
+    ///     counted (and can be aborted) until the protocol is done with it.
+    ///
+    fn start_protocol(&mut self, py: Python) -> PyResult<()> {
+        // as far as HTTP goes the request is done once the response is out
+        self.log_access(py);
+        self.state = 5;
+
+        let factory = self.hijack.take().unwrap();
+        let sock = self.stream.take().unwrap();
+        let loop_ = &self.sleeper.loop_;
+
+        let transport = Transport::new(sock, self.tls.take(), std::mem::take(&mut self.buffer));
+        let reader = Py::new(py, Reader::new(transport.clone(), loop_.clone_ref(py)))?;
+        let writer = Py::new(py, Writer::new(transport, loop_.clone_ref(py), self.options.write_high_water)?)?;
+
+        let result = factory.call1(py, (reader, writer))?;
+        if result.as_ref(py).hasattr("__await__")? {
+            let task = py.import("asyncio")?.call1("ensure_future", (result,))?;
+            self.awaiting = Awaiting::of(task)?;
+            self.protocol = Some(task.into());
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: OnceFuture::dial() -> Option<Option<PyObject>>
+    ///
+    ///     State 8, moves the connect to an accepted CONNECT's target along,
+    ///     `Some` is what to yield until it's done. Getting through is
+    ///     answered with `200 Connection Established` and the tunnel starts
+    ///     once that's out, not getting through with a `502` (or a `504`
+    ///     after `tunnel::DIAL_TIMEOUT`).
+    ///
+    fn dial(&mut self, py: Python) -> Option<Option<PyObject>> {
+        let dialing = self.dialing.as_mut()?;
+        match dialing.poll(py) {
+            Ok(None) => return Some(self.sleeper._iter_sleep(py)),
+            Ok(Some(target)) => {
+                self.dialed = Some(target);
+                self.queue_head(&HTTPResponse::established(), SerializedBody::Tunnel);
+            },
+            Err(e) => self.set_response(tunnel::dial_failed(py, &e)),
+        }
+
+        self.dialing = None;
+        None
+    }
+
+    /// Once the `200` is out the client's socket (and anything it sent after the CONNECT) is relayed to the target.
+    fn start_tunnel(&mut self) {
+        let client = Transport::new(self.stream.take().unwrap(), self.tls.take(), std::mem::take(&mut self.buffer));
+        self.tunnel = Some(Tunnel::new(client, self.dialed.take().unwrap()));
+        self.state = 9;
+    }
+
+    ///
+    /// Internal Method: OnceFuture::relay() -> PyResult<Option<Option<PyObject>>>
+    ///
+    ///     State 9, a round of the tunnel, `Some` is what to yield until
+    ///     the next and `None` once it's over. A round that moved anything
+    ///     comes straight back round, what came from the target counts as
+    ///     written to the client in the runner's stats.
+    ///
+    fn relay(&mut self, py: Python) -> PyResult<Option<Option<PyObject>>> {
+        let tunnel = match self.tunnel.as_mut() {
+            Some(tunnel) => tunnel,
+            None => return Ok(None),
+        };
+
+        let (_, before) = tunnel.bytes();
+        let moved = tunnel.pump()?;
+        let (_, after) = tunnel.bytes();
+        if let Some(connection) = self.connection.as_ref() {
+            connection.stats().written(after - before);
+        }
+
+        match moved {
+            Some(true) => {
+                self.activity.touch();
+                self.sleeper.reset();
+                Ok(Some(None))
+            },
+            Some(false) => Ok(Some(self.sleeper._iter_sleep(py))),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::sniff_h2() -> io::Result<bool>
+    ///
+    ///     If the connection is HTTP/2, which it is when ALPN picked `h2`
+    ///     or when it opens with the HTTP/2 preface (a client with prior
+    ///     knowledge). Only the start of the first request is looked at,
+    ///     it's read as far as it matches the preface and anything that
+    ///     doesn't is left in the buffer for the HTTP/1 parser.
+    ///
+    fn sniff_h2(&mut self, py: Python) -> io::Result<bool> {
+        if self.alpn_protocol.as_deref() == Some("h2") {
+            return Ok(true)
+        }
+
+        loop {
+            let seen = self.buffer.len().min(h2::PREFACE.len());
+            if self.buffer[..seen] != h2::PREFACE[..seen] {
+                return Ok(false)
+            }
+
+            if seen == h2::PREFACE.len() {
+                return Ok(true)
+            }
+
+            if self.read_some(py, self.options.read_buffer_size)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+        }
+    }
+
+    ///
+    /// Switches the connection to HTTP/2 (RFC 9113) before its first
+    /// request, our SETTINGS go out on the first round of `poll_h2()`.
+    /// Everything about the connection is the same as for HTTP/1 bar how
+    /// requests arrive and responses are framed, the callback can't tell
+    /// besides `request.protocol` being `HTTP/2.0`.
+    ///
+    fn start_h2(&mut self) {
+        let hold = self.options.on_headers.is_some();
+        self.h2 = Some(H2Connection::new(self.options.max_body_size, self.options.spool.clone(), hold));
+        self.version = (2, 0);
+        self.state = 7;
+    }
+
+    ///
+    /// Internal Method: OnceFuture::poll_h2() -> Option<Option<PyObject>>
+    ///
+    ///     State 7, a round of the HTTP/2 connection: what the client sent is
+    ///     read and taken, requests that have all arrived go to the callback
+    ///     (see `dispatch_h2()`), callbacks that have finished are answered
+    ///     and whatever's been framed is written. `Some` is what to yield
+    ///     until the next round, `None` once the connection's over.
+    ///
+    ///     Unlike HTTP/1 the callbacks run as tasks of their own, so one
+    ///     waiting on something doesn't hold up the other streams. A client
+    ///     that goes away takes all of them with it.
+    ///
+    fn poll_h2(&mut self, py: Python) -> Option<Option<PyObject>> {
+        let mut h2 = self.h2.take()?;
+        let polled = self.drive_h2(py, &mut h2);
+        self.h2 = Some(h2);
+        polled
+    }
+
+    fn drive_h2(&mut self, py: Python, h2: &mut H2Connection) -> Option<Option<PyObject>> {
+        let mut busy = false;
+        if !h2.has_failed() {
+            match self.read_some(py, self.options.read_buffer_size) {
+                Ok(0) => return self.h2_gone(py, h2),
+                Ok(_) => busy = true,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(_) => return self.h2_gone(py, h2),
+            }
+        }
+
+        let buffer = &mut self.buffer;
+        for event in stats::timed(self.profile.as_deref(), Section::Parse, || h2.receive(buffer)) {
+            busy = true;
+            match event {
+                h2::Event::Request(request) => self.dispatch_h2(py, h2, request),
+                h2::Event::Head(id) => self.headers_h2(py, h2, id),
+                h2::Event::Refuse(id, status) => {
+                    // past the limit while `on_headers` was still deciding, it's too late for it
+                    if let Some(exchange) = h2.exchange(id) {
+                        exchange.cancel(py);
+                        exchange.meta = None;
+                    }
+                    self.respond_h2(h2, id, &HTTPResponse::with_status(status), None);
+                },
+                h2::Event::Cancelled(mut exchange) => exchange.cancel(py),
+            }
+        }
+
+        let now = Instant::now();
+        let mut finished = Vec::new();
+        for (id, exchange) in h2.running() {
+            let task = match exchange.task.as_ref() {
+                Some(task) => task,
+                None => continue,
+            };
+
+            match task.call_method0(py, "done").and_then(|done| done.is_true(py)) {
+                Ok(true) => {
+                    finished.push((id, Some(task.call_method0(py, "result"))));
+                    exchange.task = None;
+                },
+                Ok(false) if exchange.deadline.is_some_and(|deadline| now >= deadline) => {
+                    exchange.cancel(py);
+                    finished.push((id, None));
+                },
+                Ok(false) => {},
+                Err(e) => finished.push((id, Some(Err(e)))),
+            }
+        }
+
+        for (id, result) in finished {
+            busy = true;
+            let (hooked, streamed) = match h2.exchange(id) {
+                Some(exchange) => (exchange.meta.is_some(), exchange.body.is_some()),
+                None => continue,
+            };
+
+            match result {
+                Some(result) if hooked => self.finish_h2_hook(py, h2, id, result),
+                Some(result) if streamed => self.pumped_h2(py, h2, id, result),
+                Some(Ok(result)) => {
+                    if let Err(e) = self.answer_h2(py, h2, id, result) {
+                        self.h2_failed(py, h2, id, e);
+                    }
+                },
+                Some(Err(e)) => self.h2_failed(py, h2, id, e),
+                // `handler_timeout` ran out
+                None if streamed => self.h2_broke(py, h2, id, None),
+                None => self.respond_h2(h2, id, &HTTPResponse::with_status(503), None),
+            }
+        }
+
+        match h2.fill(py) {
+            Ok(done) => {
+                for exchange in done {
+                    self.log_h2(py, &exchange);
+                }
+            },
+            Err(_) => return self.h2_gone(py, h2),
+        }
+
+        for id in h2.starved() {
+            busy = true;
+            if let Err(e) = self.pump_h2(py, h2, id) {
+                self.h2_broke(py, h2, id, Some(e));
+            }
+        }
+
+        while !h2.pending().is_empty() {
+            match self.write_some(py, h2.pending()) {
+                Ok(n) => {
+                    h2.advance(n);
+                    busy = true;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return self.h2_gone(py, h2),
+            }
+        }
+
+        let idle_expired = self.options.keep_alive_timeout
+            .is_some_and(|timeout| h2.is_idle() && self.activity.idle().as_secs_f32() >= timeout);
+        let used_up = self.options.keep_alive_max_requests.is_some_and(|max| self.requests >= max);
+        if self.activity.draining() || idle_expired || used_up {
+            h2.go_away();
+        }
+
+        if h2.is_done() {
+            return None
+        }
+
+        if !busy && h2.is_idle() {
+            self.reclaim_idle(Some(h2));
+        }
+
+        match busy {
+            true => Some(None),
+            false => Some(self.sleeper._iter_sleep(py)),
+        }
+    }
+
+    /// The client's gone (or broke the socket), every callback still running is cancelled.
+    fn h2_gone(&mut self, py: Python, h2: &mut H2Connection) -> Option<Option<PyObject>> {
+        for mut exchange in h2.take_all() {
+            exchange.cancel(py);
+        }
+
+        None
+    }
+
+    ///
+    /// Internal Method: OnceFuture::dispatch_h2()
+    ///
+    ///     Hands an HTTP/2 request to the callback, after the same checks
+    ///     (and answering for it) that `start_request()` does for HTTP/1.
+    ///     An awaitable it returns runs as a task of its own, watched by
+    ///     `poll_h2()` and cancelled along with the stream. With
+    ///     `on_headers` the stream was checked (and given its exchange)
+    ///     when its head came in, see `headers_h2()`.
+    ///
+    fn dispatch_h2(&mut self, py: Python, h2: &mut H2Connection, request: h2::Request) {
+        let id = request.stream;
+        let checked = h2.exchange(id).is_some();
+        if !checked {
+            self.requests += 1;
+            let exchange = self.exchange_h2(&request);
+            h2.attach(id, exchange);
+        }
+
+        if let Some(exchange) = h2.exchange(id) {
+            exchange.timings.mark(Stage::Body);
+        }
+
+        if let Err(e) = self.call_h2(py, h2, request, checked) {
+            self.h2_failed(py, h2, id, e);
+        }
+    }
+
+    /// What's tracked for a stream from its head on, until its response is out.
+    fn exchange_h2(&self, request: &h2::Request) -> Exchange {
+        let timings = Arc::new(RequestTimings::new(self.accepted));
+        timings.mark_at(Stage::Headers, request.received);
+
+        let target = match self.options.log_raw_path {
+            true => http::latin1(&request.target),
+            false => http::request_target(&request.target),
+        };
+
+        Exchange {
+            task: None,
+            deadline: None,
+            request_line: (request.method.clone(), target, String::from("HTTP/2.0")),
+            request_id: request_id::assign(&self.options, Some(&request.headers)),
+            encoding: compress::accepted(&self.options, &request.headers),
+            conditional: Conditional::from_headers(&request.method, &request.headers),
+            cors_origin: self.options.cors.as_ref().and_then(|cors| cors.allow_origin(&request.headers)),
+            head_only: request.method == "HEAD",
+            timings,
+            lease: None,
+            context: None,
+            meta: None,
+            body: None,
+            status: 0,
+            sent: 0,
+            body_sent: 0,
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::headers_h2()
+    ///
+    ///     A stream's head is in and `on_headers` is set, the stream gets
+    ///     the checks `call_h2()` would make and then goes to the hook the
+    ///     same as an HTTP/1 head would (see `start_on_headers()`). An
+    ///     awaitable the hook returns runs as the stream's task, either way
+    ///     `finish_h2_hook()` carries on with what it came back with.
+    ///
+    fn headers_h2(&mut self, py: Python, h2: &mut H2Connection, id: u32) {
+        let exchange = match h2.waiting(id) {
+            Some(request) => self.exchange_h2(request),
+            None => return,
+        };
+        let request_id = exchange.request_id.clone();
+        self.requests += 1;
+        h2.attach(id, exchange);
+
+        let request = match h2.waiting(id) {
+            Some(request) => request,
+            None => return,
+        };
+
+        if let Err(response) = self.check_h2(Some(&request_id), &request.method, &mut request.target, &mut request.headers) {
+            self.respond_h2(h2, id, &response, None);
+            return
+        }
+
+        let head = RequestHead {
+            method: request.method.clone(),
+            target: request.target.clone(),
+            form: TargetForm::Origin,
+            protocol: String::from("HTTP/2.0"),
+            version: (2, 0),
+            headers: request.headers.clone(),
+        };
+
+        let hook = self.options.on_headers.as_ref().unwrap().clone_ref(py);
+        let meta = RequestMeta::new(&head, &self.options, self.client.clone(), request_id);
+        let result = Py::new(py, meta).and_then(|meta| {
+            if let Some(exchange) = h2.exchange(id) {
+                exchange.meta = Some(meta.clone_ref(py));
+            }
+            hook.call1(py, (meta,))
+        });
+
+        let result = match result {
+            Ok(result) if result.as_ref(py).hasattr("__await__").unwrap_or(false) => {
+                context::spawn(py, None, result).map(|task| task.into())
+            },
+            other => return self.finish_h2_hook(py, h2, id, other),
+        };
+
+        match result {
+            Ok(task) => {
+                if let Some(exchange) = h2.exchange(id) {
+                    exchange.task = Some(task);
+                }
+            },
+            Err(e) => self.finish_h2_hook(py, h2, id, Err(e)),
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::finish_h2_hook()
+    ///
+    ///     What `on_headers` came back with for a stream, as in
+    ///     `finish_on_headers()`. `None` releases the stream to the
+    ///     `max_body_size` it left on the meta, a response answers it in
+    ///     place of the callback and the rest of the body is thrown away
+    ///     (the stream's reset once it's out), the hook raising is a `500`.
+    ///
+    fn finish_h2_hook(&mut self, py: Python, h2: &mut H2Connection, id: u32, result: PyResult<PyObject>) {
+        let meta = match h2.exchange(id).and_then(|exchange| exchange.meta.take()) {
+            Some(meta) => meta,
+            None => return,
+        };
+
+        let response = match result {
+            Ok(result) if result.is_none(py) => {
+                let max_body_size = meta.borrow(py).max_body_size;
+                match h2.release(id, max_body_size) {
+                    Some(h2::Event::Request(request)) => self.dispatch_h2(py, h2, request),
+                    Some(h2::Event::Refuse(id, status)) => self.respond_h2(h2, id, &HTTPResponse::with_status(status), None),
+                    _ => {},
+                }
+                return
+            },
+            Ok(result) => result.extract::<Py<HTTPResponse>>(py),
+            Err(e) if is_cancelled(py, &e) => {
+                let _ = h2.cancel(id);
+                return
+            },
+            Err(e) => Err(e),
+        };
+
+        let response = response.or_else(|e| {
+            self.report(py, &e);
+            Py::new(py, error_response(py, &e, self.options.debug))
+        });
+
+        if let Ok(response) = response {
+            self.respond_h2(h2, id, &response.borrow(py), None);
+        }
+    }
+
+    ///
+    /// The checks `check_head()` and `start_request()` make of an HTTP/1
+    /// head, for a stream, by the time its head is in. The response to
+    /// refuse it with is the error.
+    ///
+    fn check_h2(&self, request_id: Option<&str>, method: &str, target: &mut Vec<u8>, headers: &mut Headers) -> Result<(), HTTPResponse> {
+        if method.len() > self.options.max_method_length {
+            return Err(HTTPResponse::refused(400, "request method too long"))
+        }
+
+        // a tunnel (an Upgrade or an accepted CONNECT) takes the connection, there's no such thing over HTTP/2 yet
+        if method == "CONNECT" {
+            return match self.options.proxy_mode {
+                true => Err(HTTPResponse::refused(501, "CONNECT isn't supported over HTTP/2 yet")),
+                false => Err(HTTPResponse::refused(400, "CONNECT is only accepted with proxy_mode")),
+            }
+        }
+
+        let allowed_hosts = self.options.allowed_hosts.as_deref();
+        http::check_host(target, (2, 0), headers, allowed_hosts, self.options.invalid_host_status)
+            .map_err(HTTPResponse::with_status)?;
+
+        if !http::method_allowed(&self.options, method) {
+            return Err(HTTPResponse::with_status(501))
+        }
+
+        self.check_sni(headers, request_id).map_err(HTTPResponse::with_status)?;
+
+        // there's no closing one stream, so going past the burst is just another `429`
+        match ratelimit::check(&self.options, self.client.as_ref(), headers) {
+            Verdict::Allowed => Ok(()),
+            Verdict::Limited(retry_after) | Verdict::Exceeded(retry_after) => {
+                if let Some(connection) = self.connection.as_ref() {
+                    connection.stats().rate_limited();
+                }
+
+                Err(ratelimit::too_many_requests(retry_after))
+            },
+        }
+    }
+
+    fn call_h2(&mut self, py: Python, h2: &mut H2Connection, request: h2::Request, checked: bool) -> PyResult<()> {
+        let h2::Request { stream: id, method, mut target, mut headers, trailers, body, spooled, received } = request;
+
+        if !checked {
+            let request_id = h2.exchange(id).map(|exchange| exchange.request_id.clone());
+            if let Err(response) = self.check_h2(request_id.as_deref(), &method, &mut target, &mut headers) {
+                self.respond_h2(h2, id, &response, None);
+                return Ok(())
+            }
+        }
+
+        if (!body.is_empty() || spooled.is_some()) && self.shed_body() {
+            self.respond_h2(h2, id, &HTTPResponse::refused(503, "the server is out of memory for request bodies"), None);
+            return Ok(())
+        }
+
+        let mut request = HTTPRequest::new(method, target, String::from("HTTP/2.0"), headers, body.into());
+        request.trailers = trailers;
+        request.spooled = spooled;
+        if request.raw_path == b"*" {
+            request.target = TargetForm::Asterisk;
+        }
+
+        if request.normalize(self.options.merge_slashes).is_err() {
+            if let Some(connection) = self.connection.as_ref() {
+                connection.stats().parse_error();
+            }
+
+            self.respond_h2(h2, id, &HTTPResponse::with_status(400), None);
+            return Ok(())
+        }
+
+        if let Some(connection) = self.connection.as_ref() {
+            connection.stats().request();
+        }
+        self.activity.request();
+
+        if request.raw_path == b"*" && !self.options.pass_options_star {
+            self.respond_h2(h2, id, &router::options_star(py, &self.callback, &self.options), None);
+            return Ok(())
+        }
+
+        if let Some(response) = self.options.cors.as_ref().and_then(|cors| cors.preflight(&request.method, &request.headers)) {
+            self.respond_h2(h2, id, &response, None);
+            return Ok(())
+        }
+
+        request.client = self.client.clone();
+        request.remote_addr = forwarded::remote_addr(&self.options.trusted_proxies, self.options.forwarded_header, request.client.as_ref(), &request.headers);
+        request.server = self.server.clone();
+        request.connection = self.context.as_ref().map(|context| context.clone_ref(py));
+        request.tls = self.tls_info.as_ref().map(|info| info.clone_ref(py));
+        request.received = received;
+        if self.tls.is_some() {
+            request.scheme = String::from("https");
+        }
+
+        let mut deadline = None;
+        if let Some(timeout) = self.options.handler_timeout {
+            let now: f64 = self.sleeper.loop_.call_method0(py, "time")?.extract(py)?;
+            request.deadline = Some(now + f64::from(timeout));
+            deadline = Some(Instant::now() + Duration::from_secs_f32(timeout));
+        }
+
+        let timings = match h2.exchange(id) {
+            Some(exchange) => {
+                request.id = exchange.request_id.clone();
+                exchange.deadline = deadline;
+                exchange.lease = Some(request.lease());
+                exchange.timings.clone()
+            },
+            None => return Ok(()),
+        };
+        request.timings = timings.clone();
+
+        // the stream's task (or the callback, when it isn't async) runs in a context of its own like a request over HTTP/1
+        let stream_context = self.base_context.as_ref().map(|context| context::copy(py, context)).transpose()?;
+        if let Some(exchange) = h2.exchange(id) {
+            exchange.context = stream_context.as_ref().map(|context| context.clone_ref(py));
+        }
+
+        timings.mark(Stage::HandlerStart);
+        let request = Py::new(py, request)?;
+        let entered = context::enter(py, stream_context.as_ref())?;
+        let result = stats::timed(self.profile.as_deref(), Section::Handler, || self.callback.call1(py, (request,)));
+        drop(entered);
+        let result = result?;
+        if !result.as_ref(py).hasattr("__await__")? {
+            return self.answer_h2(py, h2, id, result)
+        }
+
+        let task = context::spawn_in(py, stream_context.as_ref(), result.as_ref(py))?;
+        if let Some(exchange) = h2.exchange(id) {
+            exchange.task = Some(task.into());
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: OnceFuture::answer_h2() -> PyResult<()>
+    ///
+    ///     Answers a stream with what its callback produced, the same as
+    ///     `finish_request()` bar Upgrade, which has no HTTP/2 equivalent
+    ///     (a TypeError, so the client gets a `500`). A streamed body (an
+    ///     EventSourceResponse's events too) goes out as DATA a chunk at a
+    ///     time, see `pump_h2()`.
+    ///
+    fn answer_h2(&mut self, py: Python, h2: &mut H2Connection, id: u32, result: PyObject) -> PyResult<()> {
+        let head_only = match h2.exchange(id) {
+            Some(exchange) => {
+                exchange.timings.handler_done();
+                exchange.head_only
+            },
+            None => return Ok(()),
+        };
+
+        if let Ok(file) = result.extract::<PyRef<FileResponse>>(py) {
+            let opened = match h2.exchange(id) {
+                Some(exchange) => file.open(&exchange.conditional),
+                None => return Ok(()),
+            };
+
+            match opened {
+                Ok((head, body)) => self.respond_h2(h2, id, &head, body.filter(|_| !head_only)),
+                Err(status) => self.respond_h2(h2, id, &HTTPResponse::with_status(status), None),
+            }
+
+            return Ok(())
+        }
+
+        if let Ok(events) = result.extract::<PyRef<EventSourceResponse>>(py) {
+            // compressing would hold events back until there was enough to be worth it
+            self.frame_h2(h2, id, &events.head(), H2Body::Streamed { compress: false });
+            if !head_only {
+                let body = events.stream(py, &self.sleeper.loop_, false);
+                self.stream_h2(py, h2, id, body);
+            }
+
+            return Ok(())
+        }
+
+        if result.extract::<PyRef<Upgrade>>(py).is_ok() {
+            return Err(PyTypeError::new_err("Upgrade isn't supported over HTTP/2, a stream can't be handed to another protocol"))
+        }
+
+        if BodyStream::is_stream(result.as_ref(py))? {
+            let encoding = self.frame_h2(h2, id, &HTTPResponse::default(), H2Body::Streamed { compress: true });
+            if !head_only {
+                let body = BodyStream::new(py, result.as_ref(py), false, encoding);
+                self.stream_h2(py, h2, id, body);
+            }
+
+            return Ok(())
+        }
+
+        let extracted = result.extract::<PyRef<HTTPResponse>>(py);
+        let returned;
+        let response = match extracted.as_ref() {
+            Ok(response) => &**response,
+            Err(_) => {
+                returned = HTTPResponse::from_return(result.as_ref(py))?;
+                &returned
+            },
+        };
+
+        let corrected = match head_only {
+            true => None,
+            false => response.check_length(self.options.strict_content_length)?,
+        };
+
+        self.respond_h2(h2, id, corrected.as_ref().unwrap_or(response), None);
+        Ok(())
+    }
+
+    /// Queues an HTTP/2 response and all of its body, see `frame_h2()`.
+    fn respond_h2(&self, h2: &mut H2Connection, id: u32, response: &HTTPResponse, file: Option<FileBody>) {
+        self.frame_h2(h2, id, response, H2Body::Whole(file));
+    }
+
+    ///
+    /// Internal Method: OnceFuture::frame_h2() -> Option<Encoding>
+    ///
+    ///     Queues an HTTP/2 response with our defaults, compressed the same
+    ///     way `serialize_response` would. Of a streamed body only the head
+    ///     is queued (unless it's for a HEAD, which is done with that), the
+    ///     encoding that comes back is what its chunks are compressed with.
+    ///     A stream without an exchange is one we refused before it got
+    ///     that far.
+    ///
+    fn frame_h2(&self, h2: &mut H2Connection, id: u32, response: &HTTPResponse, body: H2Body) -> Option<Encoding> {
+        let (head_only, encoding, request_id, cors_origin) = match h2.exchange(id) {
+            Some(exchange) => (exchange.head_only, exchange.encoding, Some(exchange.request_id.clone()), exchange.cors_origin.clone()),
+            None => (false, None, None, None),
+        };
+
+        let date = self.date.now();
+        let mut defaults = common_defaults(response, &self.options, &date, request_id.as_deref(), cors_origin.as_deref());
+
+        let (length, may_compress) = match &body {
+            H2Body::Whole(file) => (Some(response.body_len()), file.is_none()),
+            H2Body::Streamed { compress } => (None, *compress),
+        };
+
+        let compressible = may_compress && compress::compressible(&self.options, response, length);
+        let encoding = encoding.filter(|_| compressible);
+        if compressible && encoding.is_none() {
+            defaults.push(("Vary", "Accept-Encoding"));
+        }
+
+        let encoded;
+        let response = match encoding {
+            Some(encoding) => {
+                encoded = response.encoded(encoding, length.is_some());
+                &encoded
+            },
+            None => response,
+        };
+
+        let fields = response.h2_head(&defaults, length);
+        match body {
+            H2Body::Streamed { .. } if !head_only => h2.stream(id, response.status, &fields),
+            H2Body::Streamed { .. } => h2.respond(id, response.status, &fields, Bytes::new(), None),
+            H2Body::Whole(_) if head_only => h2.respond(id, response.status, &fields, Bytes::new(), None),
+            H2Body::Whole(file) => h2.respond(id, response.status, &fields, response.body_bytes(), file),
+        }
+
+        encoding
+    }
+
+    /// A streamed body has its head out, it's stepped for its first chunk once that's framed.
+    fn stream_h2(&mut self, py: Python, h2: &mut H2Connection, id: u32, body: PyResult<BodyStream>) {
+        let body = body.and_then(|body| {
+            let events = body.is_events();
+            Ok((Py::new(py, BodyStep::new(body))?, events))
+        });
+
+        match body {
+            Ok((step, events)) => {
+                if let Some(exchange) = h2.exchange(id) {
+                    // events go on for as long as the client listens, they aren't held to `handler_timeout`
+                    if events {
+                        exchange.deadline = None;
+                    }
+                    exchange.body = Some(step);
+                }
+            },
+            Err(e) => self.h2_broke(py, h2, id, Some(e)),
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::pump_h2() -> PyResult<()>
+    ///
+    ///     Steps a stream's body for its next chunk, once it's framed all
+    ///     of the last. It's a task of its own in the stream's context, like
+    ///     the callback was, so a slow client only holds up its own body.
+    ///
+    fn pump_h2(&self, py: Python, h2: &mut H2Connection, id: u32) -> PyResult<()> {
+        let exchange = match h2.exchange(id) {
+            Some(exchange) => exchange,
+            None => return Ok(()),
+        };
+
+        let step = match exchange.body.as_ref() {
+            Some(step) => step.clone_ref(py),
+            None => return Ok(()),
+        };
+
+        let task = context::spawn_in(py, exchange.context.as_ref(), step.as_ref(py))?;
+        exchange.task = Some(task.into());
+        Ok(())
+    }
+
+    /// A stream's body has produced its next chunk (or finished, or raised), it's framed as the windows allow.
+    fn pumped_h2(&mut self, py: Python, h2: &mut H2Connection, id: u32, result: PyResult<PyObject>) {
+        if let Err(e) = result {
+            return self.h2_broke(py, h2, id, Some(e))
+        }
+
+        let exchange = match h2.exchange(id) {
+            Some(exchange) => exchange,
+            None => return,
+        };
+
+        let (chunk, done) = match exchange.body.as_ref() {
+            Some(step) => step.borrow_mut(py).take(),
+            None => return,
+        };
+
+        if done {
+            exchange.body = None;
+        }
+        h2.push(id, chunk, done);
+    }
+
+    ///
+    /// A stream's response can't be finished with its head already out,
+    /// its body raised or `handler_timeout` ran out. Like HTTP/1 closing
+    /// the connection all that's left is ending it early, the stream's
+    /// reset.
+    ///
+    fn h2_broke(&mut self, py: Python, h2: &mut H2Connection, id: u32, error: Option<PyErr>) {
+        if let Some(e) = error.as_ref() {
+            if is_cancelled(py, e) {
+                let _ = h2.cancel(id);
+                return
+            }
+
+            self.report(py, e);
+        }
+
+        if let Some(exchange) = h2.abort(id) {
+            self.log_h2(py, &exchange);
+        }
+    }
+
+    /// A stream's callback raised, the same as `handler_failed()` but only that stream is answered.
+    fn h2_failed(&mut self, py: Python, h2: &mut H2Connection, id: u32, e: PyErr) {
+        if is_cancelled(py, &e) {
+            let _ = h2.cancel(id);
+            return
+        }
+
+        if let Some(exchange) = h2.exchange(id) {
+            exchange.timings.handler_done();
+        }
+
+        self.report(py, &e);
+        self.respond_h2(h2, id, &error_response(py, &e, self.options.debug), None);
+    }
+
+    /// A stream's response is all out, it's logged like an HTTP/1 one.
+    fn log_h2(&self, py: Python, exchange: &Exchange) {
+        exchange.timings.mark(Stage::Flushed);
+        if let Some(connection) = self.connection.as_ref() {
+            connection.stats().body_written(exchange.body_sent);
+        }
+
+        if let Some(logger) = self.access_logger.as_ref() {
+            let _entered = match context::enter(py, exchange.context.as_ref()) {
+                Ok(entered) => entered,
+                Err(e) => return e.print(py),
+            };
+
+            access_log(
+                py,
+                logger,
+                self.client.as_ref(),
+                Some(&exchange.request_line),
+                exchange.status,
+                exchange.sent,
+                exchange.body_sent,
+                &exchange.timings,
+                Some(&exchange.request_id),
+                None,
+            );
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::log_access()
+    ///
+    ///     Emits the access log record once the response has been written,
+    ///     see `access_log()`.
+    ///
+    fn log_access(&mut self, py: Python) {
+        self.timings.mark(Stage::Flushed);
+        let context = self.request_context.take();
+        let logger = match self.access_logger.as_ref() {
+            Some(logger) => logger,
+            None => return,
+        };
+
+        let _entered = match context::enter(py, context.as_ref()) {
+            Ok(entered) => entered,
+            Err(e) => return e.print(py),
+        };
+
+        access_log(
+            py,
+            logger,
+            self.client.as_ref(),
+            self.request_line.as_ref(),
+            self.status,
+            self.bytes_sent,
+            self.body_sent(),
+            &self.timings,
+            self.request_id.as_deref(),
+            self.tunnel.as_ref().map(Tunnel::bytes),
+        );
+    }
+
+    ///
+    /// Steps whatever the callback handed us to await, `Yield` passes on
+    /// what it yielded and `Return` is the value it finished with. An
+    /// error `throw()` left for it is thrown in rather than stepping it.
+    ///
+    fn step(&mut self, py: Python) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        let awaiting = match self.awaiting.as_ref() {
+            Some(awaiting) => awaiting,
+            None => return Ok(IterNextOutput::Return(py.None())),
+        };
+
+        let thrown = self.thrown.take();
+        let _entered = context::enter(py, self.request_context.as_ref())?;
+        let stepped = stats::timed(self.profile.as_deref(), Section::Handler, || awaiting.step(py, thrown))?;
+        if let IterNextOutput::Return(_) = stepped {
+            self.awaiting = None;
+        }
+
+        Ok(stepped)
+    }
+
+    ///
+    /// Internal Method: OnceFuture::write_file() -> io::Result<bool>
+    ///
+    ///     Moves the file body along once the head has been written, plain
+    ///     TCP on Linux goes through sendfile and everything else reads the
+    ///     next chunk into `response` for the normal write path. `Ok(false)`
+    ///     means there is nothing left of the file.
+    ///
+    fn write_file(&mut self, py: Python) -> io::Result<bool> {
+        #[cfg(target_os = "linux")]
+        {
+            if self.tls.is_none() && self.file.is_some() {
+                let limit = self.throttle_write(self.file.as_ref().map_or(0, FileBody::remaining))?;
+                let body = self.file.as_mut().unwrap();
+                let sock = self.stream.as_ref().unwrap();
+                let before = body.sent();
+                let done = stats::timed(self.profile.as_deref(), Section::Write, || py.allow_threads(|| body.sendfile(sock, limit)));
+                let sent = body.sent() - before;
+                self.record_sent(sent);
+                if !done? {
+                    return Ok(true)
+                }
+
+                self.file = None;
+                return Ok(false)
+            }
+        }
+
+        let body = match self.file.as_mut() {
+            Some(body) => body,
+            None => return Ok(false),
+        };
+
+        let response = self.response.buffer();
+        if !py.allow_threads(|| body.read_chunk(response))? {
+            self.response.clear();
+            self.file = None;
+            return Ok(false)
+        }
+
+        Ok(true)
+    }
+
+    ///
+    /// Internal Method: OnceFuture::handler_failed() -> PyResult<()>
+    ///
+    ///     The callback raised, we report it through the loop's exception
+    ///     handler (so asyncio's debug tooling sees it) and answer with a 500,
+    ///     the traceback is only included when the runner has `debug=True`.
+    ///     Cancellation isn't a failure so that gets re-raised as is.
+    ///
+    fn handler_failed(&mut self, py: Python, e: PyErr) -> PyResult<()> {
+        self.awaiting = None;
+        self.keep_alive = false;
+
+        if is_cancelled(py, &e) {
+            return Err(e)
+        }
+
+        self.timings.handler_done();
+
+        // the client going away part way through its body isn't the handler going wrong
+        let gone = self.inbound.as_ref().is_some_and(Inbound::client_gone);
+        if !(gone && e.is_instance::<errors::ConnectionClosed>(py)) {
+            self.report(py, &e);
+        }
+        self.set_response(error_response(py, &e, self.options.debug));
+
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: OnceFuture::arm()
+    ///
+    ///     Starts the `handler_timeout` clock once the callback has the
+    ///     request, it's a `call_at` (see HandlerTimeout) rather than
+    ///     anything we check each time we're polled since a stuck handler
+    ///     never gets us polled at all. It's for the same `loop.time()` the
+    ///     request was given as its `deadline`.
+    ///
+    fn arm(&mut self, py: Python, handle: &Py<OnceFuture>) -> PyResult<()> {
+        let when = match (self.handler_deadline, self.deadline.is_some()) {
+            (Some(when), false) => when,
+            _ => return Ok(()),
+        };
+
+        let task = py.import("asyncio")?.call0("current_task")?;
+        let timer = HandlerTimeout {
+            future: handle.clone_ref(py),
+            task: task.into(),
+        };
+
+        self.deadline = Some(self.sleeper.loop_.call_method1(py, "call_at", (when, Py::new(py, timer)?))?);
+        Ok(())
+    }
+
+    /// The callback's done (or gone) in time, the deadline's called off.
+    fn disarm(&mut self, py: Python) {
+        if let Some(deadline) = self.deadline.take() {
+            let _ = deadline.call_method0(py, "cancel");
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::abort() -> PyResult<Option<PyObject>>
+    ///
+    ///     The rest of `throw()` for anything that ends us, closing the
+    ///     connection and letting `error` carry on up.
+    ///
+    fn abort(&mut self, py: Python, type_: &PyAny, value: Option<&PyAny>, error: PyErr) -> PyResult<Option<PyObject>> {
+        self.unwatch(py);
+        self.disarm(py);
+
+        if std::mem::take(&mut self.timed_out) {
+            return self.time_out(py, type_, value)
+        }
+
+        // the handler's `except` / `finally` still runs in its request's context
+        let entered = context::enter(py, self.request_context.take().as_ref());
+        if let Some(awaiting) = self.awaiting.take() {
+            let _ = awaiting.throw(py, type_, value, None);
+        }
+
+        if let Some(mut body) = self.stream_body.take() {
+            body.throw(py, type_, value);
+        }
+        drop(entered);
+
+        if let Some(ws) = self.websocket.take() {
+            ws.borrow_mut(py).close_now();
+        }
+
+        if let Some(protocol) = self.protocol.take() {
+            let _ = protocol.call_method0(py, "cancel");
+        }
+
+        if let Some(inbound) = self.inbound.take() {
+            inbound.detach(py);
+        }
+
+        if let Some(mut h2) = self.h2.take() {
+            for mut exchange in h2.take_all() {
+                exchange.cancel(py);
+            }
+
+            // so the client knows we're going rather than guessing from the close, if the socket takes it
+            h2.go_away();
+            if self.stream.is_some() {
+                let _ = self.write_some(py, h2.pending());
+            }
+        }
+
+        // a tunnel's sockets (the client's included) close with it
+        self.dialing = None;
+        self.dialed = None;
+        self.tunnel = None;
+        self.stream = None;
+        self.tls = None;
+        self.connection = None;
+        self.context = None;
+        self.state = 4;
+
+        Err(error)
+    }
+
+    ///
+    /// Internal Method: OnceFuture::time_out() -> PyResult<Option<PyObject>>
+    ///
+    ///     The rest of `throw()` for a handler that ran out of time, the
+    ///     handler is cancelled and the request answered with a `503`
+    ///     unless some of the response is already out, then all we can do
+    ///     is close the connection. Either way it isn't kept alive since we
+    ///     can't know what the abandoned handler left behind.
+    ///
+    fn time_out(&mut self, py: Python, type_: &PyAny, value: Option<&PyAny>) -> PyResult<Option<PyObject>> {
+        if let Some(awaiting) = self.awaiting.take() {
+            let _ = awaiting.throw(py, type_, value, None);
+        }
+
+        if let Some(mut body) = self.stream_body.take() {
+            body.throw(py, type_, value);
+        }
+
+        self.keep_alive = false;
+        if self.bytes_sent > 0 {
+            self.stream = None;
+            self.tls = None;
+            self.connection = None;
+            self.context = None;
+            self.state = 4;
+            return Err(PyStopIteration::new_err((py.None(),)))
+        }
+
+        self.file = None;
+        self.set_response(HTTPResponse::with_status(503));
+        self.state = 3;
+
+        match self.poll(py) {
+            Ok(IterNextOutput::Yield(yielded)) => Ok(yielded),
+            Ok(IterNextOutput::Return(result)) => {
+                self.connection = None;
+                self.context = None;
+                Err(PyStopIteration::new_err((result,)))
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn report(&self, py: Python, e: &PyErr) {
+        report_handler_error(py, &self.sleeper.loop_, e, self.client.clone());
+    }
+
+    ///
+    /// Internal Method: OnceFuture::reclaim_idle()
+    ///
+    ///     Once the runner's housekeeping has found us idle (see
+    ///     `AsyncServerRunner::housekeep()`) the read and write buffers, and
+    ///     HTTP/2's frame buffer, are freed if they're bigger than
+    ///     `idle_buffer_retain`. What's counted as `bytes_reclaimed` is the
+    ///     capacity given back. Nothing python is kept between requests to
+    ///     drop, the request (and its headers and cookies) goes with the
+    ///     handler once it's answered.
+    ///
+    fn reclaim_idle(&mut self, h2: Option<&mut H2Connection>) {
+        if !self.activity.take_reclaim() {
+            return
+        }
+
+        let retain = self.options.idle_buffer_retain;
+        let mut reclaimed = reclaim_buffer(&mut self.buffer, retain)
+            + reclaim_buffer(&mut self.interim, retain)
+            + self.response.reclaim(retain);
+        if let Some(h2) = h2 {
+            reclaimed += h2.reclaim(retain);
+        }
+
+        if let Some(connection) = self.connection.as_ref() {
+            connection.stats().reclaimed(reclaimed as u64);
+        }
+    }
+
+    ///
+    /// Brings what we're counted as holding against `memory_budget` up to
+    /// date after a step, the buffers' capacities (HTTP/2's aren't
+    /// counted) and how much of a body has been spooled. It's only passed
+    /// on to the budget when that's changed.
+    ///
+    fn account(&mut self) {
+        if let Some(memory) = self.memory.as_mut() {
+            memory.set(
+                self.buffer.capacity()
+                    + self.interim.capacity()
+                    + self.response.capacity()
+                    + self.spool.as_ref().map_or(0, Spool::len)
+            );
+        }
+    }
+
+    /// If a request body would be refused for `memory_budget`, counting it if so.
+    fn shed_body(&self) -> bool {
+        let budget = match self.memory.as_ref() {
+            Some(memory) if memory.budget().shedding() => memory.budget(),
+            _ => return false,
+        };
+
+        if let Some(connection) = self.connection.as_ref() {
+            budget.shed_request(connection.stats());
+        }
+        true
+    }
+
+    ///
+    /// If we've waited `keep_alive_timeout` for the next request without it
+    /// starting to arrive, or the runner is draining and we're between
+    /// requests.
+    ///
+    fn keep_alive_expired(&self) -> bool {
+        if self.activity.draining() && self.requests > 0 && self.buffer.is_empty() {
+            return true
+        }
+
+        match self.options.keep_alive_timeout {
+            Some(timeout) => self.requests > 0 && self.buffer.is_empty() && self.activity.idle().as_secs_f32() >= timeout,
+            None => false,
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::throttle_write() -> io::Result<u64>
+    ///
+    ///     How much of the `want` bytes waiting to go out can be written
+    ///     now with a write rate set, all of them without one. What's left
+    ///     of the head always goes on its own and in full so it's never
+    ///     held up on the rate, a body the throttle won't let through yet
+    ///     is `WouldBlock` with `throttled` set to how long to sleep for.
+    ///
+    fn throttle_write(&mut self, want: u64) -> io::Result<u64> {
+        let (throttle, from) = match (self.throttle.as_mut(), self.body_from) {
+            (Some(throttle), Some(from)) => (throttle, from),
+            _ => return Ok(want),
+        };
+
+        let head_left = from.saturating_sub(self.bytes_sent);
+        if head_left > 0 {
+            return Ok(head_left.min(want))
+        }
+
+        throttle.allowance(self.options.write_budget.as_deref(), want).map_err(|wait| {
+            self.throttled = Some(wait);
+            io::ErrorKind::WouldBlock.into()
+        })
+    }
+
+    /// What to yield when a write would block, the throttle's wait if it's what refused it.
+    fn write_wait(&mut self, py: Python) -> Option<PyObject> {
+        match self.throttled.take() {
+            Some(wait) => self.sleeper._iter_sleep_for(py, wait as f32),
+            None => self.sleeper._iter_sleep(py),
+        }
+    }
+
+    fn record_sent(&mut self, n: u64) {
+        let before = self.bytes_sent;
+        self.bytes_sent += n;
+        self.activity.touch();
+
+        // a `100 Continue` or the head going out isn't body
+        let body = self.body_from.map_or(0, |from| self.bytes_sent.saturating_sub(from.max(before)));
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.spend(self.options.write_budget.as_deref(), body);
+        }
+        if let Some(connection) = self.connection.as_ref() {
+            connection.stats().written(n);
+            connection.stats().body_written(body);
+        }
+    }
+
+    /// How much of the response's body has been written, as framed on the wire.
+    fn body_sent(&self) -> u64 {
+        self.body_from.map_or(0, |from| self.bytes_sent.saturating_sub(from))
+    }
+
+    fn set_response(&mut self, response: HTTPResponse) {
+        self.queue_response(&response)
+    }
+
+    ///
+    /// Serializes the response ready to be written, the body is left off
+    /// for a `HEAD` request whatever the handler gave us.
+    ///
+    fn queue_response(&mut self, response: &HTTPResponse) {
+        self.queue_head(response, SerializedBody::unless_head(self.is_head()));
+    }
+
+    fn queue_head(&mut self, response: &HTTPResponse, body: SerializedBody) -> Option<Encoding> {
+        // a streamed body the handler didn't read to the end is in the way
+        // of the next request, unless the rest of it is already buffered. A
+        // streamed response could still be reading it so that's left be.
+        if let Some(inbound) = self.inbound.as_mut() {
+            let streaming = matches!(body, SerializedBody::Streamed { .. });
+            if !inbound.is_done() && (streaming || !inbound.skip_buffered(&mut self.buffer)) {
+                self.keep_alive = false;
+                self.linger = Some(Linger::default());
+            }
+        }
+
+        self.response.clear();
+        let (keep_alive, encoding) = serialize_response(
+            response,
+            &self.options,
+            &self.date,
+            self.version,
+            self.keep_alive && !self.activity.draining(),
+            self.requests,
+            body,
+            self.encoding,
+            self.request_id.as_deref(),
+            self.cors_origin.as_deref(),
+            &mut self.response,
+        );
+        self.keep_alive = keep_alive;
+        self.status = response.status;
+        self.body_from = Some(self.bytes_sent + self.response.head_len() as u64);
+        self.state = 3;
+
+        encoding
+    }
+
+    ///
+    /// Queues a response the handler (or `on_headers`) made, once any
+    /// `Content-Length` it set has been checked against the body, see
+    /// `HTTPResponse::check_length()`.
+    ///
+    fn queue_checked(&mut self, response: &HTTPResponse) -> PyResult<()> {
+        if !self.is_head() {
+            if let Some(corrected) = response.check_length(self.options.strict_content_length)? {
+                self.queue_response(&corrected);
+                return Ok(())
+            }
+        }
+
+        self.queue_response(response);
+        Ok(())
+    }
+
+    ///
+    /// Clears everything about the request we just answered so the
+    /// connection can read the next one, anything already buffered after it
+    /// (a pipelined request) is kept.
+    ///
+    /// The read and write buffers are kept for the next request rather than
+    /// reallocated, unless one huge request grew them past
+    /// `MAX_RETAINED_BUFFER` in which case they're shrunk back down.
+    ///
+    fn reset_request(&mut self, py: Python) {
+        self.head_end = None;
+        self.body_len = 0;
+        self.spool = None;
+        self.chunked = None;
+        if let Some(inbound) = self.inbound.take() {
+            inbound.detach(py);
+        }
+        self.head = None;
+        self.refusal = None;
+        self.retry_after = None;
+        self.meta = None;
+        self.early = None;
+        self.drained = false;
+        self.interim.clear();
+        self.awaiting = None;
+        self.response.clear();
+        self.file = None;
+        self.conditional = Conditional::default();
+        self.stream_body = None;
+        self.connect_target = None;
+        self.encoding = None;
+        self.request_line = None;
+        self.request_id = None;
+        self.cors_origin = None;
+        self.handler_deadline = None;
+        self.head_received = None;
+        self.timings = Arc::new(RequestTimings::new(self.accepted));
+        self.lease = None;
+        self.activity.set_request(None);
+
+        if self.buffer.capacity() > MAX_RETAINED_BUFFER {
+            self.buffer.shrink_to(MAX_RETAINED_BUFFER);
+        }
+        self.response.shrink_to(MAX_RETAINED_BUFFER);
+
+        self.status = 0;
+        self.bytes_sent = 0;
+        self.body_from = None;
+        self.keep_alive = false;
+        self.state = 1;
+    }
+
+    ///
+    /// Internal Method: OnceFuture::watch() -> PyResult<()>
+    ///
+    ///     While the callback runs nothing else reads the socket, so the
+    ///     loop watches it for us (see DisconnectWatch) and our task gets
+    ///     cancelled if the client goes away before the response is ready.
+    ///
+    ///     The proactor loop windows uses by default can't watch a socket,
+    ///     there a client going away is only noticed once we write to it.
+    ///
+    fn watch(&mut self, py: Python, handle: &Py<OnceFuture>) -> PyResult<()> {
+        let fd = match (self.watching, self.stream.as_ref()) {
+            (None, Some(stream)) if !self.unwatchable => stream::raw_fd(stream),
+            _ => return Ok(()),
+        };
+
+        let task = py.import("asyncio")?.call0("current_task")?;
+        let watch = DisconnectWatch {
+            future: handle.clone_ref(py),
+            task: task.into(),
+        };
+
+        match self.sleeper.loop_.call_method1(py, "add_reader", (fd, Py::new(py, watch)?)) {
+            Ok(_) => self.watching = Some(fd),
+            Err(e) if e.is_instance::<PyNotImplementedError>(py) => self.unwatchable = true,
+            Err(e) => return Err(e),
+        }
+
+        Ok(())
+    }
+
+    /// Stops the loop watching our socket, always before the socket is closed.
+    fn unwatch(&mut self, py: Python) {
+        if let Some(fd) = self.watching.take() {
+            let _ = self.sleeper.loop_.call_method1(py, "remove_reader", (fd,));
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::check_disconnect() -> Watch
+    ///
+    ///     The socket became readable while the callback was running,
+    ///     EOF or an error means the client has gone. Anything it sent is
+    ///     kept for the next request, but once `read_high_water` is waiting
+    ///     we stop watching rather than buffer any more.
+    ///
+    fn check_disconnect(&mut self, py: Python) -> Watch {
+        if self.stream.is_none() {
+            return Watch::Stop
+        }
+
+        // the request being handled is already out of the buffer
+        let max = read_size(&self.options, self.buffer.len(), Some(0));
+        if max == 0 {
+            return Watch::Stop
+        }
+
+        match self.read_some(py, max) {
+            Ok(0) => Watch::Gone,
+            Ok(_) => match self.buffer.len() >= self.options.read_high_water {
+                true => Watch::Stop,
+                false => Watch::Keep,
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Watch::Keep,
+            Err(_) => Watch::Gone,
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::pull_body() -> io::Result<()>
+    ///
+    ///     Reads the next piece of a streamed body for whatever is waiting
+    ///     on it (see `inbound::NextChunk`), from the buffer if some is
+    ///     already there and the socket if not. It's only ever read for
+    ///     something that's waiting so a handler that's slow to consume
+    ///     holds the client up, and never past the end of the body by more
+    ///     than `read_high_water`. `WouldBlock` means there's nothing yet,
+    ///     the client going away (or the framing being broken) ends the
+    ///     stream with an error instead.
+    ///
+    fn pull_body(&mut self, py: Python) -> io::Result<()> {
+        let mut inbound = match self.inbound.take() {
+            Some(inbound) if inbound.wanted(py) => inbound,
+            inbound => {
+                self.inbound = inbound;
+                return Ok(())
+            },
+        };
+
+        let pulled = loop {
+            match inbound.take(&mut self.buffer) {
+                Ok(Taken::More) => {},
+                Ok(taken) => {
+                    inbound.deliver(py, taken);
+                    break Ok(())
+                },
+                Err(e) => {
+                    inbound.fail(py, e.into());
+                    break Ok(())
+                },
+            }
+
+            let max = read_size(&self.options, self.buffer.len(), inbound.remaining());
+            match self.read_some(py, max) {
+                Ok(0) => {},
+                Ok(_) => {
+                    self.activity.touch();
+                    continue
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Err(e),
+                Err(_) => {},
+            }
+
+            inbound.fail(py, StreamError::Closed);
+            break Ok(())
+        };
+
+        self.inbound = Some(inbound);
+        pulled
+    }
+
+    /// Pulls the body for a handler parked on it, `WouldBlock` until there's something to step it with.
+    fn feed_parked(&mut self, py: Python) -> io::Result<()> {
+        match self.parked(py) {
+            true => self.pull_body(py),
+            false => Ok(()),
+        }
+    }
+
+    fn parked(&self, py: Python) -> bool {
+        self.inbound.as_ref().is_some_and(|inbound| inbound.parked(py))
+    }
+
+    fn unpark(&self, py: Python) {
+        if let Some(inbound) = self.inbound.as_ref() {
+            inbound.unpark(py);
+        }
+    }
+
+    ///
+    /// Internal Method: OnceFuture::read_proxy_header() -> io::Result<bool>
+    ///
+    ///     With `proxy_protocol=True` every connection has to start with a
+    ///     PROXY header (see `proxy::parse`), it's peeked at until it has
+    ///     all arrived and only then read off the socket so whatever follows
+    ///     is left for TLS or the HTTP parser. The client it names is who
+    ///     `request.client`, the access log and `connections()` report.
+    ///
+    ///     `Ok(false)` is waiting for more of it, a connection without one
+    ///     (or with a malformed one) is an InvalidData error.
+    ///
+    fn read_proxy_header(&mut self) -> io::Result<bool> {
+        let mut sock = self.stream.as_ref().unwrap();
+        let mut want = proxy::PEEK_SIZE;
+
+        loop {
+            let mut buf = vec![0; want];
+            let n = sock.peek(&mut buf)?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+
+            let (header, length) = match proxy::parse(&buf[..n]) {
+                Ok(proxy::Parsed::Done(header, length)) => (header, length),
+                // a v2 header with more TLVs than we looked at
+                Ok(proxy::Parsed::Incomplete(needed)) if n == want && needed > want => {
+                    want = needed;
+                    continue
+                },
+                Ok(proxy::Parsed::Incomplete(_)) => return Ok(false),
+                Err(()) => return Err(io::ErrorKind::InvalidData.into()),
+            };
+
+            // we've seen all of it already so this can't block
+            sock.read_exact(&mut buf[..length])?;
+
+            let client = header.source.map(|addr| (addr.ip().to_string(), addr.port()));
+            self.activity.proxied(header.family, client.clone());
+            if client.is_some() {
+                self.client = client;
+            }
+
+            return Ok(true)
+        }
+    }
+
+    fn is_head(&self) -> bool {
+        self.request_line
+            .as_ref()
+            .is_some_and(|(method, _, _)| method == "HEAD")
+    }
+
+    ///
+    /// Internal Method: OnceFuture::poll()
+    ///
+    ///     The state machine behind `__next__`, see the states on the struct.
+    ///
+    fn poll(&mut self, py: Python) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // finish the tls handshake before anything else, behind a proxy
+        // its PROXY header comes even before that. Each stage only takes
+        // its own bytes so the next one finds the rest as it was sent: the
+        // PROXY header is peeked at and only it is read off the socket
+        // (see `read_proxy_header()`), leaving the ClientHello for rustls,
+        // and rustls keeps whatever request came in behind the handshake
+        // for our first read. Without TLS it's still in the socket.
+        if self.state == 0 {
+            if self.proxy_header {
+                match self.read_proxy_header() {
+                    Ok(true) => self.proxy_header = false,
+                    Ok(false) => return Ok(IterNextOutput::Yield(self.sleeper._iter_sleep(py))),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(IterNextOutput::Yield(self.sleeper._iter_sleep(py)))
+                    },
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::InvalidData {
+                            if let Some(connection) = self.connection.as_ref() {
+                                connection.stats().parse_error();
+                            }
+                        }
+
+                        return Ok(IterNextOutput::Return(None))
+                    },
+                }
+            }
+
+            let sock = self.stream.as_ref().unwrap();
+            if let Some(tls) = self.tls.as_mut() {
+                match py.allow_threads(|| tls.handshake(sock)) {
+                    Ok(true) => {
+                        self.alpn_protocol = tls.alpn_protocol();
+                        self.server_name = tls.server_name();
+                        self.peer_certificate = tls.peer_certificate();
+                        self.tls_info = Some(Py::new(py, tls.info())?);
+                    },
+                    Ok(false) => return Ok(IterNextOutput::Yield(self.sleeper._iter_sleep(py))),
+                    Err(_) => return Ok(IterNextOutput::Return(None)),
+                }
+            }
+
+            self.state = 1;
+        }
+
+        // on_headers gave us something to await, the body's read once it's done
+        if self.state == 6 {
+            match self.step(py) {
+                Ok(IterNextOutput::Yield(yielded)) => return Ok(IterNextOutput::Yield(Some(yielded))),
+                Ok(IterNextOutput::Return(result)) => self.finish_on_headers(py, Ok(result)),
+                Err(e) if is_cancelled(py, &e) => return Err(e),
+                Err(e) => self.finish_on_headers(py, Err(e)),
+            }
+
+            self.awaiting = None;
+            self.state = 1;
+        }
+
+        // a connection that turns out to be HTTP/2 is that for good
+        if self.state == 1 && !self.sniffed && self.options.http2 {
+            match self.sniff_h2(py) {
+                Ok(true) => self.start_h2(),
+                Ok(false) => self.sniffed = true,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(IterNextOutput::Yield(self.sleeper._iter_sleep(py)))
+                },
+                Err(_) => return Ok(IterNextOutput::Return(None)),
+            }
+        }
+
+        // wait for the full request then hand it to the callback
+        if self.state == 1 {
+            match self.read_request(py) {
+                Ok(Some(head_end)) => {
+                    if let Err(e) = self.start_request(py, head_end) {
+                        self.handler_failed(py, e)?;
+                    }
+                },
+                Ok(None) if self.state == 6 => return Ok(IterNextOutput::Yield(None)),
+                Ok(None) if self.keep_alive_expired() => return Ok(IterNextOutput::Return(None)),
+                Ok(None) => {
+                    self.reclaim_idle(None);
+                    return Ok(IterNextOutput::Yield(self.sleeper._iter_sleep(py)))
+                },
+                Err(e) => {
+                    // data we couldn't make sense of (a broken TLS record) counts as a parse error
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        if let Some(connection) = self.connection.as_ref() {
+                            connection.stats().parse_error();
+                        }
+                    }
+
+                    return Ok(IterNextOutput::Return(None))
+                },
+            }
+        }
+
+        if self.state == 7 {
+            match self.poll_h2(py) {
+                Some(yielded) => return Ok(IterNextOutput::Yield(yielded)),
+                None => {
+                    self.keep_alive = false;
+                    self.state = 4;
+                },
+            }
+        }
+
+        // the callback gave us a coroutine, we step it and pass along whatever
+        // it yields to the event loop, effectively `yield from`.
+        if self.state == 2 {
+            loop {
+                // a handler parked on its body is only stepped once there's
+                // some of it, or an error from a future to throw in
+                if self.thrown.is_none() && self.feed_parked(py).is_err() {
+                    return Ok(IterNextOutput::Yield(self.sleeper._iter_sleep(py)))
+                }
+
+                self.unpark(py);
+                match self.step(py) {
+                    Ok(IterNextOutput::Yield(_)) if self.parked(py) => continue,
+                    Ok(IterNextOutput::Yield(yielded)) => return Ok(IterNextOutput::Yield(Some(yielded))),
+                    Ok(IterNextOutput::Return(result)) => {
+                        if let Err(e) = self.finish_request(py, result) {
+                            self.handler_failed(py, e)?;
+                        }
+                    },
+                    Err(e) => self.handler_failed(py, e)?,
+                }
+
+                break
+            }
+        }
+
+        // answer an accepted CONNECT once we know if we can get to its target
+        if self.state == 8 {
+            if let Some(yielded) = self.dial(py) {
+                return Ok(IterNextOutput::Yield(yielded))
+            }
+        }
+
+        // write out the response
+        if self.state == 3 {
+            loop {
+                while !self.response.is_empty() {
+                    match self.write_response(py) {
+                        Ok(_) => {},
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            return Ok(IterNextOutput::Yield(self.write_wait(py)))
+                        },
+                        Err(_) => return Ok(IterNextOutput::Return(None)),
+                    }
+                }
+
+                // then any file body that follows the head
+                match self.write_file(py) {
+                    Ok(true) => continue,
+                    Ok(false) => {},
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(IterNextOutput::Yield(self.write_wait(py)))
+                    },
+                    Err(_) => return Ok(IterNextOutput::Return(None)),
+                }
+
+                // or the next piece of a streamed body, the generator is only
+                // moved along once everything before it has been written (and
+                // the request body it's parked on has some more for it)
+                if self.stream_body.is_some() {
+                    if self.feed_parked(py).is_err() {
+                        return Ok(IterNextOutput::Yield(self.sleeper._iter_sleep(py)))
+                    }
+                    self.unpark(py);
+                }
+
+                let body = match self.stream_body.as_mut() {
+                    Some(body) => body,
+                    None => break,
+                };
+
+                self.response.clear();
+                let entered = context::enter(py, self.request_context.as_ref())?;
+                let stepped = body.step(py, self.response.buffer());
+                drop(entered);
+                match stepped {
+                    Ok(StreamStep::Chunk) => {},
+                    Ok(StreamStep::Yield(yielded)) => return Ok(IterNextOutput::Yield(Some(yielded))),
+                    Ok(StreamStep::Done) => self.stream_body = None,
+                    Err(e) => {
+                        // the head is already out, all we can do is end the
+                        // body early by dropping the connection
+                        self.stream_body = None;
+                        self.keep_alive = false;
+                        if is_cancelled(py, &e) {
+                            return Err(e)
+                        }
+
+                        self.report(py, &e);
+                        return Ok(IterNextOutput::Return(None))
+                    },
+                }
+            }
+
+            if self.upgrade.is_some() {
+                // the 101 is already out so all we can do is drop the connection
+                if let Err(e) = self.start_websocket(py) {
+                    self.state = 5;
+                    self.awaiting = None;
+                    if is_cancelled(py, &e) {
+                        return Err(e)
+                    }
+
+                    self.report(py, &e);
+                }
+            } else if self.hijack.is_some() {
+                if let Err(e) = self.start_protocol(py) {
+                    self.awaiting = None;
+                    if is_cancelled(py, &e) {
+                        return Err(e)
+                    }
+
+                    self.report(py, &e);
+                }
+            } else if self.dialed.is_some() {
+                self.start_tunnel();
+            } else {
+                self.log_access(py);
+                self.state = 4;
+            }
+        }
+
+        // the websocket handler (or an Upgrade's protocol) owns the connection now, once it's done so are we
+        if self.state == 5 {
+            let res = self.step(py);
+            if let Ok(IterNextOutput::Yield(yielded)) = res {
+                return Ok(IterNextOutput::Yield(Some(yielded)))
+            }
+
+            self.protocol = None;
+
+            if let Some(ws) = self.websocket.take() {
+                ws.borrow_mut(py).close_now();
+            }
+
+            if let Err(e) = res {
+                if is_cancelled(py, &e) {
+                    return Err(e)
+                }
+
+                self.report(py, &e);
+            }
+        }
+
+        // a tunnel is over once either end closes, the CONNECT is logged with what went through it
+        if self.state == 9 {
+            match self.relay(py) {
+                Ok(Some(yielded)) => return Ok(IterNextOutput::Yield(yielded)),
+                Ok(None) => self.log_access(py),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // wait for rustls to get everything out to the socket, then either
+        // go round for the next request or close
+        if self.state == 4 {
+            let sock = self.stream.as_ref().unwrap();
+            if let Some(tls) = self.tls.as_mut() {
+                match py.allow_threads(|| tls.flush(sock)) {
+                    Ok(false) => return Ok(IterNextOutput::Yield(self.sleeper._iter_sleep(py))),
+                    Ok(true) if self.keep_alive => {},
+                    Ok(true) => tls.close(sock),
+                    Err(_) => return Ok(IterNextOutput::Return(None)),
+                }
+            }
+
+            if self.keep_alive {
+                self.reset_request(py);
+                return Ok(IterNextOutput::Yield(None))
+            }
+
+            if let Some(linger) = self.linger.as_mut() {
+                if !linger.drain(sock) {
+                    return Ok(IterNextOutput::Yield(self.sleeper._iter_sleep(py)))
+                }
+            }
+        }
+
+        Ok(IterNextOutput::Return(None))
+    }
+}
+
+///
+/// The same cycle as the runner's through the sleep, and through whatever
+/// the callback is awaiting which leads back to the task driving us, so an
+/// abandoned connection can be collected and its socket closed.
+///
+#[pyproto]
+impl PyGCProtocol for OnceFuture {
+    fn __traverse__(&self, visit: PyVisit) -> Result<(), PyTraverseError> {
+        visit.call(&self.callback)?;
+        self.sleeper.traverse(visit)?;
+        if let Some(awaiting) = self.awaiting.as_ref() {
+            visit.call(awaiting.as_object())?;
+        }
+        if let Some(upgrade) = self.upgrade.as_ref() {
+            visit.call(upgrade)?;
+        }
+        if let Some(websocket) = self.websocket.as_ref() {
+            visit.call(websocket)?;
+        }
+        if let Some(hijack) = self.hijack.as_ref() {
+            visit.call(hijack)?;
+        }
+        if let Some(protocol) = self.protocol.as_ref() {
+            visit.call(protocol)?;
+        }
+        if let Some(h2) = self.h2.as_ref() {
+            h2.traverse(visit)?;
+        }
+        if let Some(inbound) = self.inbound.as_ref() {
+            inbound.traverse(visit)?;
+        }
+        if let Some(logger) = self.access_logger.as_ref() {
+            visit.call(logger)?;
+        }
+        if let Some(context) = self.context.as_ref() {
+            visit.call(context)?;
+        }
+
+        Ok(())
+    }
+
+    fn __clear__(&mut self) {
+        self.sleeper.clear();
+        self.awaiting = None;
+        self.upgrade = None;
+        self.websocket = None;
+        self.hijack = None;
+        self.protocol = None;
+        self.h2 = None;
+        self.inbound = None;
+        self.context = None;
+        self.stream = None;
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for OnceFuture {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for OnceFuture {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+    fn __next__(slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+        OnceFuture::resume(slf.into(), py)
+    }
+}
+
+impl OnceFuture {
+    ///
+    /// Internal Method: OnceFuture::resume() -> PyResult<IterNextOutput<...>>
+    ///
+    ///     A step of the task, from `__next__` or a `throw()` that's
+    ///     passed on to the callback. The disconnect watch and the
+    ///     `handler_timeout` clock are kept going for as long as the callback
+    ///     has the connection.
+    ///
+    fn resume(handle: Py<Self>, py: Python) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        let mut slf = handle.borrow_mut(py);
+        let res = slf.poll(py);
+        slf.sleeper.settle();
+        slf.account();
+
+        // only watched while the callback (or its generator) has the connection
+        let yielded = matches!(res, Ok(IterNextOutput::Yield(_)));
+        if yielded && (slf.state == 2 || slf.stream_body.is_some()) {
+            if let Some(inbound) = slf.inbound.as_ref() {
+                inbound.link(py, &handle);
+            }
+
+            slf.watch(py, &handle)?;
+            if !slf.stream_body.as_ref().is_some_and(BodyStream::is_events) {
+                slf.arm(py, &handle)?;
+            }
+        } else {
+            slf.unwatch(py);
+            slf.disarm(py);
+        }
+
+        // however we finish the connection is no longer active, and its
+        // context goes now rather than whenever the task is collected
+        if !yielded {
+            slf.connection = None;
+            slf.memory = None;
+            slf.context = None;
+            if let Some(inbound) = slf.inbound.take() {
+                inbound.detach(py);
+            }
+        }
+
+        res
+    }
+}
+
+///
+/// What to do with a DisconnectWatch after checking the socket.
+///
+enum Watch {
+    Keep,   // Nothing's wrong, keep watching
+    Stop,   // Stop watching but leave the request be
+    Gone,   // The client disconnected, cancel the request
+}
+
+///
+/// DisconnectWatch is the `add_reader` callback on a connection's socket
+/// while its callback runs, if the client disconnects the task is
+/// cancelled so the handler gets a CancelledError and the connection is
+/// freed then and there. An ASGI app is sent `http.disconnect` first.
+///
+#[pyclass]
+struct DisconnectWatch {
+    future: Py<OnceFuture>,
+    task: PyObject,         // The task driving the OnceFuture
+}
+
+#[pymethods]
+impl DisconnectWatch {
+    #[call]
+    fn __call__(&self, py: Python) -> PyResult<()> {
+        let mut future = match self.future.try_borrow_mut(py) {
+            Ok(future) => future,
+            Err(_) => return Ok(()),
+        };
+
+        match future.check_disconnect(py) {
+            Watch::Keep => return Ok(()),
+            Watch::Stop => {
+                future.unwatch(py);
+                return Ok(())
+            },
+            Watch::Gone => future.unwatch(py),
+        }
+
+        if let Some(awaiting) = future.awaiting.as_ref().map(Awaiting::as_object) {
+            // an ASGI app behind middleware is still told
+            let awaiting = match awaiting.extract::<PyRef<MiddlewareCall>>(py) {
+                Ok(call) => call.awaiting().map(|awaiting| awaiting.as_object().clone_ref(py)),
+                Err(_) => Some(awaiting.clone_ref(py)),
+            };
+
+            if let Some(awaiting) = awaiting {
+                if let Ok(call) = awaiting.extract::<PyRef<ASGICall>>(py) {
+                    call.disconnect(py)?;
+                }
+            }
+        }
+
+        drop(future);
+        self.task.call_method0(py, "cancel")?;
+        Ok(())
+    }
+}
+
+///
+/// HandlerTimeout is the `call_later` callback ending a request whose
+/// handler has had `handler_timeout` to produce its response. The task is
+/// cancelled to get it off whatever it's stuck on, the OnceFuture sees
+/// `timed_out` when the CancelledError is thrown into it and answers with
+/// a `503` instead of closing the connection.
+///
+#[pyclass]
+struct HandlerTimeout {
+    future: Py<OnceFuture>,
+    task: PyObject,         // The task driving the OnceFuture
+}
+
+#[pymethods]
+impl HandlerTimeout {
+    #[call]
+    fn __call__(&self, py: Python) -> PyResult<()> {
+        let mut future = match self.future.try_borrow_mut(py) {
+            Ok(future) => future,
+            Err(_) => return Ok(()),
+        };
+
+        if future.deadline.take().is_none() {
+            return Ok(())
+        }
+
+        future.timed_out = true;
+        log::warning(&format!(
+            "request {} timed out after {}s, the handler was cancelled",
+            future.request_id.as_deref().unwrap_or("-"),
+            future.options.handler_timeout.unwrap_or_default(),
+        ));
+
+        drop(future);
+        self.task.call_method0(py, "cancel")?;
+        Ok(())
+    }
+}
+
+///
+/// TaskDone is the done callback on every connection task, it takes the
+/// task out of the runner's `tasks` and reports the exception it ended
+/// with if it has one. Asking for the exception marks it as retrieved so
+/// asyncio doesn't report it again when the task is collected.
+///
+#[pyclass]
+struct TaskDone {
+    tasks: PyObject,
+    loop_: PyObject,
+    handler: Arc<Mutex<Option<PyObject>>>,  // The runner's `set_exception_handler()`
+}
+
+#[pymethods]
+impl TaskDone {
+    #[call]
+    fn __call__(&self, py: Python, task: &PyAny) -> PyResult<()> {
+        let info = self.tasks.call_method1(py, "pop", (task, py.None()))?;
+        if task.call_method0("cancelled")?.is_true()? {
+            return Ok(())
+        }
+
+        let exception = task.call_method0("exception")?;
+        if exception.is_none() {
+            return Ok(())
+        }
+
+        let context = PyDict::new(py);
+        context.set_item("message", "Unhandled exception in connection task")?;
+        context.set_item("exception", exception)?;
+        context.set_item("task", task)?;
+        match info.extract::<PyRef<ConnectionInfo>>(py) {
+            Ok(info) => {
+                context.set_item("peer", info.peer())?;
+                context.set_item("request", info.current_request())?;
+            },
+            Err(_) => {
+                context.set_item("peer", py.None())?;
+                context.set_item("request", py.None())?;
+            },
+        }
+
+        let handler = self.handler.lock().unwrap().as_ref().map(|handler| handler.clone_ref(py));
+        let handled = match handler {
+            Some(handler) => handler.call1(py, (context,)),
+            None => return self.loop_.call_method1(py, "call_exception_handler", (context,)).map(drop),
+        };
+
+        // a handler that raises itself is reported the way it would be without one
+        if let Err(e) = handled {
+            let context = PyDict::new(py);
+            context.set_item("message", "Unhandled exception in the runner's exception handler")?;
+            context.set_item("exception", e.pvalue(py))?;
+            context.set_item("task", task)?;
+            self.loop_.call_method1(py, "call_exception_handler", (context,))?;
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Wraps all our existing pyobjects together in the module
+///
+#[pymodule]
+fn async_rust(py: Python, m: &PyModule) -> PyResult<()> {
+    log::init(py)?;
+    errors::init(py, m)?;
 
-///
-/// Wraps all our existing pyobjects together in the module
-///
-#[pymodule]
-fn async_rust(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<AsyncServerRunner>()?;
     m.add_class::<OnceFuture>()?;
+    m.add_class::<TLSConfig>()?;
+    m.add_class::<TLSInfo>()?;
+    m.add_class::<AsyncDatagramRunner>()?;
+    m.add_class::<HTTPRequest>()?;
+    m.add_class::<RequestMeta>()?;
+    m.add_class::<MultipartPart>()?;
+    m.add_class::<Headers>()?;
+    m.add_class::<ConnectionInfo>()?;
+    m.add_class::<HTTPResponse>()?;
+    m.add_class::<FileResponse>()?;
+    m.add_class::<EventSourceResponse>()?;
+    m.add_class::<Upgrade>()?;
+    m.add_class::<ASGIApp>()?;
+    m.add_class::<WSGIApp>()?;
+    m.add_class::<Router>()?;
+    m.add_class::<VirtualHosts>()?;
+    m.add_class::<WebSocketConnection>()?;
+    m.add_class::<Reader>()?;
+    m.add_class::<Writer>()?;
+    m.add_class::<ConnectionPool>()?;
+    server::init(m)?;
+    client::init(m)?;
     Ok(())
 }
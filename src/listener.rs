@@ -0,0 +1,491 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+
+use socket2::{Domain, SockAddr, SockRef, Socket, TcpKeepalive, Type};
+
+use crate::log;
+use crate::stream;
+
+
+/// Bind addresses starting with this are unix socket paths.
+const UNIX_PREFIX: &str = "unix:";
+
+/// Bind addresses starting with this (or `unix:@`) are names in linux's abstract unix socket namespace.
+const ABSTRACT_PREFIX: &str = "unix-abstract:";
+
+/// How many pending connections we ask the kernel to queue if not told otherwise.
+pub(crate) const DEFAULT_BACKLOG: i32 = 1024;
+
+/// How long accepting stops for after running out of fds if not told otherwise, in seconds.
+pub(crate) const DEFAULT_ACCEPT_COOLDOWN: f32 = 0.5;
+
+/// Running out of fds is logged at most this often however often it happens.
+const EXHAUSTED_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+
+///
+/// Internal Method: resolve() -> io::Result<Vec<SocketAddr>>
+///
+///     The addresses a `host:port` bind address stands for, in the order
+///     getaddrinfo gave them. This can block so call it without the GIL.
+///     With `resolve` off only IP literals (`[::1]:8080` for IPv6) are
+///     accepted.
+///
+pub(crate) fn resolve(addr: &str, resolve: bool) -> io::Result<Vec<SocketAddr>> {
+    if !resolve {
+        let addr = addr.parse::<SocketAddr>().map_err(|_| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not an IP address and port (resolve is off)", addr),
+        ))?;
+        return Ok(vec![addr])
+    }
+
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve to any address", addr)))
+    }
+
+    Ok(addrs)
+}
+
+
+///
+/// Which of the addresses given to a runner couldn't be bound, the error
+/// keeps the OS's own inside so its errno can still be reported.
+///
+#[derive(Debug)]
+pub(crate) struct BindFailed {
+    pub(crate) addr: String,
+    pub(crate) source: io::Error,
+}
+
+impl fmt::Display for BindFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to bind {}: {}", self.addr, self.source)
+    }
+}
+
+impl std::error::Error for BindFailed {}
+
+
+/// Binds `socket` and starts listening on it, non-blocking like everything we accept from.
+fn listen(socket: Socket, addr: &SockAddr, backlog: i32) -> io::Result<Socket> {
+    socket.bind(addr)?;
+    socket.listen(backlog)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+///
+/// The backlog the kernel will actually give a listener asking for
+/// `backlog`, linux silently caps it at `net.core.somaxconn`.
+///
+pub(crate) fn effective_backlog(backlog: i32) -> i32 {
+    #[cfg(target_os = "linux")]
+    {
+        let somaxconn = std::fs::read_to_string("/proc/sys/net/core/somaxconn")
+            .ok()
+            .and_then(|value| value.trim().parse::<i32>().ok());
+
+        if let Some(somaxconn) = somaxconn {
+            return backlog.min(somaxconn)
+        }
+    }
+
+    backlog
+}
+
+
+///
+/// KeepAlive is the `tcp_keepalive=(idle_secs, interval_secs, probes)`
+/// option, set on every accepted TCP connection so one whose peer (or a
+/// NAT in between) has silently gone away eventually errors out.
+///
+#[derive(Clone, Copy)]
+pub(crate) struct KeepAlive {
+    pub(crate) idle: u32,       // Seconds idle before the first probe
+    pub(crate) interval: u32,   // Seconds between probes
+    pub(crate) probes: u32,     // Unanswered probes before the connection is dropped, not settable on windows
+}
+
+impl KeepAlive {
+    pub(crate) fn apply(&self, sock: &TcpStream) -> io::Result<()> {
+        let keepalive = TcpKeepalive::new()
+            .with_time(Duration::from_secs(self.idle.into()))
+            .with_interval(Duration::from_secs(self.interval.into()));
+
+        #[cfg(not(windows))]
+        let keepalive = keepalive.with_retries(self.probes);
+
+        SockRef::from(sock).set_tcp_keepalive(&keepalive)
+    }
+}
+
+
+///
+/// ClientOptions are the socket options set on every accepted TCP client,
+/// `tcp_keepalive` and `so_linger`. One that can't be set is logged and
+/// the client served without it.
+///
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ClientOptions {
+    pub(crate) keepalive: Option<KeepAlive>,
+    pub(crate) linger: Option<Duration>,    // Zero resets the connection on close rather than leaving it in TIME_WAIT
+}
+
+impl ClientOptions {
+    pub(crate) fn apply(&self, sock: &TcpStream) {
+        if let Some(keepalive) = self.keepalive.as_ref() {
+            if let Err(e) = keepalive.apply(sock) {
+                log::socket_error("failed to set keepalive", &e);
+            }
+        }
+
+        if let Some(linger) = self.linger {
+            if let Err(e) = SockRef::from(sock).set_linger(Some(linger)) {
+                log::socket_error("failed to set SO_LINGER", &e);
+            }
+        }
+    }
+}
+
+
+///
+/// AcceptPause is how an accept loop backs off once the process is out of
+/// file descriptors (or memory). accept() then fails straight away with the
+/// client left queued, so the listener stays readable and retrying it is a
+/// hot loop that accepts nothing. Instead accepting stops for
+/// `accept_cooldown` while the connections we already have carry on (and
+/// free fds as they finish), then it's tried again.
+///
+/// It's logged the first time and then at most every 10 seconds, with how
+/// many more times it happened in between.
+///
+pub(crate) struct AcceptPause {
+    cooldown: Duration,
+    until: Option<Instant>,     // When accepting can start again
+    logged: Option<Instant>,    // When we last said so
+    unlogged: u64,              // How many pauses since then that weren't logged
+}
+
+impl AcceptPause {
+    pub(crate) fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            until: None,
+            logged: None,
+            unlogged: 0,
+        }
+    }
+
+    /// How much longer accepting is paused for, None when it isn't.
+    pub(crate) fn remaining(&mut self) -> Option<Duration> {
+        let left = self.until?.checked_duration_since(Instant::now()).filter(|left| !left.is_zero());
+        if left.is_none() {
+            self.until = None;
+        }
+
+        left
+    }
+
+    ///
+    /// Internal Method: AcceptPause::failed() -> bool
+    ///
+    ///     Deals with an error from accept, true if it means we're out of
+    ///     fds and accepting has been paused. Anything else is just logged.
+    ///
+    pub(crate) fn failed(&mut self, e: &io::Error) -> bool {
+        if !log::out_of_resources(e) {
+            log::socket_error("failed to accept a connection", e);
+            return false
+        }
+
+        let now = Instant::now();
+        self.until = Some(now + self.cooldown);
+
+        if self.logged.is_some_and(|logged| now.duration_since(logged) < EXHAUSTED_LOG_INTERVAL) {
+            self.unlogged += 1;
+            return true
+        }
+
+        let since = match std::mem::take(&mut self.unlogged) {
+            0 => String::new(),
+            n => format!(" ({} more times since the last warning)", n),
+        };
+        log::warning(&format!(
+            "failed to accept a connection: {}, not accepting for {:?}{}",
+            e, self.cooldown, since,
+        ));
+        self.logged = Some(now);
+
+        true
+    }
+}
+
+
+///
+/// Internal Method: set_fastopen()
+///
+///     Turns on TCP Fast Open for a listener, `queue` is how many fast
+///     opens still waiting on their handshake the kernel will hold. Where
+///     the platform doesn't have it (or the kernel has it switched off)
+///     it's a warning and the listener carries on without.
+///
+pub(crate) fn set_fastopen(listener: &TcpListener, queue: u32) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let queue = queue.min(libc::c_int::MAX as u32) as libc::c_int;
+
+        // SAFETY: a valid c_int and its size for a socket we own
+        let res = unsafe {
+            libc::setsockopt(
+                listener.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN,
+                &queue as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        if res == -1 {
+            log::warning(&format!("failed to set TCP_FASTOPEN, carrying on without it: {}", io::Error::last_os_error()));
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (listener, queue);
+        log::warning("tcp_fastopen is only supported on linux, carrying on without it");
+    }
+}
+
+
+///
+/// The name of an abstract unix socket address, `unix-abstract:name` or
+/// `unix:@name`. None if `addr` isn't one.
+///
+fn abstract_name(addr: &str) -> Option<&str> {
+    addr.strip_prefix(ABSTRACT_PREFIX)
+        .or_else(|| addr.strip_prefix(UNIX_PREFIX).and_then(|path| path.strip_prefix('@')))
+}
+
+
+///
+/// BindAddr is where a Listener is bound, either a TCP address or with
+/// `unix:/path/to.sock` a unix socket. On linux `unix-abstract:name` is a
+/// unix socket in the abstract namespace, there's no file to clean up.
+///
+#[derive(Clone)]
+pub(crate) enum BindAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(target_os = "linux")]
+    Abstract(String),
+}
+
+/// The address as it would be given to bind it again, `unix:` and all.
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            BindAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            #[cfg(target_os = "linux")]
+            BindAddr::Abstract(name) => write!(f, "{}{}", ABSTRACT_PREFIX, name),
+        }
+    }
+}
+
+///
+/// Listener is one of the non-blocking sockets an AsyncServer accepts
+/// from. Whatever it was bound as, accepted clients come back as a
+/// TcpStream since everything after the accept only needs the fd to read
+/// and write (a unix client just has no `peer_addr`).
+///
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),    // The path is removed again when the listener is dropped
+    #[cfg(target_os = "linux")]
+    Abstract(UnixListener, String), // The name without the leading NUL, it goes away with the socket
+}
+
+impl Listener {
+    ///
+    /// Internal Method: Listener::bind() -> io::Result<Self>
+    ///
+    ///     Binds a non-blocking listener to `addr`, `unix:` addresses are
+    ///     unix sockets and anything else is resolved (see `resolve()`) with
+    ///     each address tried in turn until one binds. If none do the error
+    ///     lists every address tried and why it failed.
+    ///
+    ///     A stale socket file left behind by a server that went away is
+    ///     replaced, one that is still being served is an AddrInUse error.
+    ///     Abstract addresses (see `abstract_name()`) are an Unsupported
+    ///     error anywhere but linux.
+    ///
+    ///     `backlog` is handed to `listen()`, the kernel may cap it (see
+    ///     `effective_backlog()`).
+    ///
+    pub(crate) fn bind(addr: &str, resolve: bool, backlog: i32) -> io::Result<Self> {
+        if let Some(name) = abstract_name(addr) {
+            #[cfg(target_os = "linux")]
+            return Self::bind_abstract(name.to_string(), backlog);
+
+            #[cfg(not(target_os = "linux"))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("abstract unix sockets are only available on linux, can't bind {:?}", name),
+            ));
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+            return Self::bind_unix(PathBuf::from(path), backlog)
+        }
+
+        let candidates = self::resolve(addr, resolve)?;
+        let mut failures = Vec::with_capacity(candidates.len());
+        for candidate in candidates {
+            match Self::bind_tcp(candidate, backlog) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => failures.push((candidate, e)),
+            }
+        }
+
+        // a single failure is given back as is so e.g. AddrInUse still comes out as such
+        if failures.len() == 1 {
+            return Err(failures.remove(0).1)
+        }
+
+        let tried: Vec<String> = failures
+            .iter()
+            .map(|(candidate, e)| format!("{} ({})", candidate, e))
+            .collect();
+        Err(io::Error::new(io::ErrorKind::AddrNotAvailable, format!("tried {}", tried.join(", "))))
+    }
+
+    ///
+    /// Binds a TCP listener, `SO_REUSEADDR` is set on unix like std would
+    /// so a restarted server doesn't trip over connections in TIME_WAIT.
+    ///
+    pub(crate) fn bind_tcp(addr: SocketAddr, backlog: i32) -> io::Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+
+        Ok(Self::Tcp(listen(socket, &addr.into(), backlog)?.into()))
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: PathBuf, backlog: i32) -> io::Result<Self> {
+        let addr = SockAddr::unix(&path)?;
+        let socket = match listen(Socket::new(Domain::UNIX, Type::STREAM, None)?, &addr, backlog) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                if UnixStream::connect(&path).is_ok() {
+                    return Err(e)
+                }
+
+                std::fs::remove_file(&path)?;
+                listen(Socket::new(Domain::UNIX, Type::STREAM, None)?, &addr, backlog)?
+            },
+            other => other?,
+        };
+
+        Ok(Self::Unix(socket.into(), path))
+    }
+
+    /// Binds a unix socket in the abstract namespace, the name is taken or it isn't so there's nothing stale to replace.
+    #[cfg(target_os = "linux")]
+    fn bind_abstract(name: String, backlog: i32) -> io::Result<Self> {
+        let mut path = vec![0];
+        path.extend_from_slice(name.as_bytes());
+
+        let addr = SockAddr::unix(std::ffi::OsStr::from_bytes(&path))?;
+        let socket = listen(Socket::new(Domain::UNIX, Type::STREAM, None)?, &addr, backlog)?;
+        Ok(Self::Abstract(socket.into(), name))
+    }
+
+    /// Binds again to an address we had before, see `AsyncServer::reopen`.
+    pub(crate) fn rebind(addr: &BindAddr, backlog: i32) -> io::Result<Self> {
+        match addr {
+            BindAddr::Tcp(addr) => Self::bind_tcp(*addr, backlog),
+            #[cfg(unix)]
+            BindAddr::Unix(path) => Self::bind_unix(path.clone(), backlog),
+            #[cfg(target_os = "linux")]
+            BindAddr::Abstract(name) => Self::bind_abstract(name.clone(), backlog),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<BindAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map(BindAddr::Tcp),
+            #[cfg(unix)]
+            Self::Unix(_, path) => Ok(BindAddr::Unix(path.clone())),
+            #[cfg(target_os = "linux")]
+            Self::Abstract(_, name) => Ok(BindAddr::Abstract(name.clone())),
+        }
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<TcpStream> {
+        match self {
+            Self::Tcp(listener) => listener.accept().map(|(sock, _)| sock),
+            #[cfg(unix)]
+            Self::Unix(listener, _) => accept_unix(listener),
+            #[cfg(target_os = "linux")]
+            Self::Abstract(listener, _) => accept_unix(listener),
+        }
+    }
+
+    pub(crate) fn as_tcp(&self) -> Option<&TcpListener> {
+        match self {
+            Self::Tcp(listener) => Some(listener),
+            #[cfg(unix)]
+            Self::Unix(..) => None,
+            #[cfg(target_os = "linux")]
+            Self::Abstract(..) => None,
+        }
+    }
+
+    pub(crate) fn raw_fd(&self) -> stream::RawSocket {
+        match self {
+            Self::Tcp(listener) => stream::raw_fd(listener),
+            #[cfg(unix)]
+            Self::Unix(listener, _) => stream::raw_fd(listener),
+            #[cfg(target_os = "linux")]
+            Self::Abstract(listener, _) => stream::raw_fd(listener),
+        }
+    }
+}
+
+/// A client from a unix listener as a TcpStream, see `Listener`.
+#[cfg(unix)]
+fn accept_unix(listener: &UnixListener) -> io::Result<TcpStream> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    let (sock, _) = listener.accept()?;
+
+    // SAFETY: the fd comes straight from into_raw_fd so nothing else owns it
+    Ok(unsafe { TcpStream::from_raw_fd(sock.into_raw_fd()) })
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use pyo3::prelude::*;
+
+use std::io;
+use std::sync::OnceLock;
+
+
+/// The `async_rust` logger, fetched once when the module is imported.
+static LOGGER: OnceLock<PyObject> = OnceLock::new();
+
+
+///
+/// Grabs `logging.getLogger("async_rust")`, called from the module init so
+/// everything after that can log without going through the import again.
+///
+pub(crate) fn init(py: Python) -> PyResult<()> {
+    let logger = py.import("logging")?.call1("getLogger", ("async_rust",))?;
+    let _ = LOGGER.set(logger.into());
+    Ok(())
+}
+
+///
+/// Sends `message` to the `async_rust` logger at the given level name, if
+/// the logger isn't set up or logging itself fails the message is dropped
+/// rather than raising inside the server.
+///
+fn log(level: &str, message: &str) {
+    let logger = match LOGGER.get() {
+        Some(logger) => logger,
+        None => return,
+    };
+
+    let gil = Python::acquire_gil();
+    let _ = logger.call_method1(gil.python(), level, (message,));
+}
+
+pub(crate) fn debug(message: &str) {
+    log("debug", message)
+}
+
+pub(crate) fn info(message: &str) {
+    log("info", message)
+}
+
+pub(crate) fn warning(message: &str) {
+    log("warning", message)
+}
+
+pub(crate) fn error(message: &str) {
+    log("error", message)
+}
+
+///
+/// Logs an error from accepting / receiving on a socket at a level that
+/// matches how worried anyone should be about it.
+///
+///     - the peer going away before we got to it is routine, `debug`
+///     - running out of fds or memory is usually transient, `warning`
+///     - anything else is unexpected, `error`
+///
+pub(crate) fn socket_error(context: &str, e: &io::Error) {
+    let message = format!("{}: {}", context, e);
+
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted => debug(&message),
+        _ if out_of_resources(e) => warning(&message),
+        _ => error(&message),
+    }
+}
+
+/// If an error is the process (or system) running out of fds or memory.
+#[cfg(unix)]
+pub(crate) fn out_of_resources(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM))
+}
+
+/// Sockets on windows report the winsock codes rather than errno.
+#[cfg(windows)]
+pub(crate) fn out_of_resources(e: &io::Error) -> bool {
+    const WSAEMFILE: i32 = 10024;
+    const WSAENOBUFS: i32 = 10055;
+    const ERROR_NOT_ENOUGH_MEMORY: i32 = 8;
+
+    matches!(e.raw_os_error(), Some(WSAEMFILE) | Some(WSAENOBUFS) | Some(ERROR_NOT_ENOUGH_MEMORY))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn out_of_resources(_e: &io::Error) -> bool {
+    false
+}
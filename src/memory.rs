@@ -0,0 +1,194 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::PyResult;
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::log;
+use crate::stats::ServerStats;
+
+
+/// What a connection refused by `memory_overflow="503"` is sent, before it's closed.
+const UNAVAILABLE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// How far under `memory_budget` we have to get back to by default before we stop shedding.
+const DEFAULT_LOW_WATER: f64 = 0.9;
+
+
+/// What's done with a connection accepted while we're over `memory_budget`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Overflow {
+    Unavailable,    // A bare `503`, then it's closed
+    Close,          // Closed without a word
+}
+
+impl Overflow {
+    pub(crate) fn parse(value: &str) -> PyResult<Self> {
+        match value {
+            "503" => Ok(Self::Unavailable),
+            "close" => Ok(Self::Close),
+            _ => Err(PyValueError::new_err("memory_overflow must be '503' or 'close'")),
+        }
+    }
+
+    /// What `parse()` takes it as.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Unavailable => "503",
+            Self::Close => "close",
+        }
+    }
+}
+
+
+///
+/// MemoryBudget is `memory_budget`, roughly how much memory the runner's
+/// connections are holding in buffers and spooled bodies against how much
+/// they may. Every connection keeps a MemoryCharge of what it has and only
+/// touches the shared count when one of its buffers grows or shrinks, not
+/// for every byte through them.
+///
+/// Going over `limit` starts shedding load, see `shedding()`, and we carry
+/// on until usage is back under `low_water` so a runner sat right at its
+/// budget doesn't flap between the two. Both changes are logged, the
+/// second with what was shed in between.
+///
+pub(crate) struct MemoryBudget {
+    limit: usize,
+    low_water: usize,
+    overflow: Overflow,
+    used: AtomicUsize,              // What every MemoryCharge adds up to
+    shedding: AtomicBool,
+    shed_connections: AtomicU64,    // Refused since we started shedding, for the log once we stop
+    shed_requests: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: usize, low_water: Option<usize>, overflow: Overflow) -> PyResult<Self> {
+        let low_water = low_water.unwrap_or((limit as f64 * DEFAULT_LOW_WATER) as usize);
+        if limit == 0 || low_water > limit {
+            return Err(PyValueError::new_err("memory_budget must be positive and memory_low_water no more than it"))
+        }
+
+        Ok(Self {
+            limit,
+            low_water,
+            overflow,
+            used: AtomicUsize::new(0),
+            shedding: AtomicBool::new(false),
+            shed_connections: AtomicU64::new(0),
+            shed_requests: AtomicU64::new(0),
+        })
+    }
+
+    /// `(limit, low_water, overflow)`, the low water mark worked out if it wasn't given.
+    pub(crate) fn settings(&self) -> (usize, usize, Overflow) {
+        (self.limit, self.low_water, self.overflow)
+    }
+
+    /// Roughly how many bytes the connections are holding.
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// If new connections and request bodies are being refused.
+    pub(crate) fn shedding(&self) -> bool {
+        self.shedding.load(Ordering::Relaxed)
+    }
+
+    ///
+    /// Internal Method: MemoryBudget::admit() -> Option<TcpStream>
+    ///
+    ///     Gives a newly accepted connection back unless we're shedding,
+    ///     in which case it's counted and closed by being dropped, after a
+    ///     bare `503` with `memory_overflow="503"`.
+    ///
+    pub(crate) fn admit(&self, sock: TcpStream, stats: &ServerStats) -> Option<TcpStream> {
+        if !self.shedding() {
+            return Some(sock)
+        }
+
+        stats.shed_connection();
+        self.shed_connections.fetch_add(1, Ordering::Relaxed);
+        if self.overflow == Overflow::Unavailable {
+            // the same one try and read off as `Acl::admit()`'s 403
+            let _ = (&sock).write(UNAVAILABLE);
+            let _ = sock.shutdown(Shutdown::Write);
+            if sock.set_nonblocking(true).is_ok() {
+                let mut scratch = [0; 4096];
+                while matches!((&sock).read(&mut scratch), Ok(n) if n > 0) {}
+            }
+        }
+
+        None
+    }
+
+    /// Counts a request body refused with a `503` while we're shedding.
+    pub(crate) fn shed_request(&self, stats: &ServerStats) {
+        stats.shed_request();
+        self.shed_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///
+    /// Moves `used` from `old` to `new` bytes for a MemoryCharge,
+    /// starting or stopping shedding if that takes us over the limit or
+    /// back under the low water mark. Only the one of the racing changes
+    /// that actually flips `shedding` logs it.
+    ///
+    fn change(&self, old: usize, new: usize) {
+        let used = match new > old {
+            true => self.used.fetch_add(new - old, Ordering::Relaxed) + (new - old),
+            false => self.used.fetch_sub(old - new, Ordering::Relaxed) - (old - new),
+        };
+
+        if used > self.limit && !self.shedding() && !self.shedding.swap(true, Ordering::Relaxed) {
+            log::warning(&format!(
+                "connections are holding {} bytes, over the memory_budget of {}, refusing new connections and request bodies",
+                used, self.limit,
+            ));
+        } else if used < self.low_water && self.shedding() && self.shedding.swap(false, Ordering::Relaxed) {
+            log::warning(&format!(
+                "connections are back down to {} bytes, accepting again after shedding {} connections and {} request bodies",
+                used,
+                self.shed_connections.swap(0, Ordering::Relaxed),
+                self.shed_requests.swap(0, Ordering::Relaxed),
+            ));
+        }
+    }
+}
+
+
+///
+/// What one connection has counted against the MemoryBudget, given back
+/// when it's dropped.
+///
+pub(crate) struct MemoryCharge {
+    budget: Arc<MemoryBudget>,
+    held: usize,
+}
+
+impl MemoryCharge {
+    pub(crate) fn new(budget: Arc<MemoryBudget>) -> Self {
+        Self { budget, held: 0 }
+    }
+
+    pub(crate) fn budget(&self) -> &MemoryBudget {
+        &self.budget
+    }
+
+    /// Records that the connection now holds `held` bytes, the budget only hears about it if that's changed.
+    pub(crate) fn set(&mut self, held: usize) {
+        if held != self.held {
+            self.budget.change(self.held, held);
+            self.held = held;
+        }
+    }
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
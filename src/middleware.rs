@@ -0,0 +1,295 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::exceptions::PyTypeError;
+
+use std::sync::Arc;
+
+use crate::http::{self, HTTPResponse};
+use crate::sleep::Awaiting;
+
+
+///
+/// Hooks are the methods one middleware object has, either can be left
+/// out.
+///
+#[derive(Clone)]
+struct Hooks {
+    on_request: Option<PyObject>,
+    on_response: Option<PyObject>,
+}
+
+///
+/// Middleware wraps the runner's callback once `add_middleware()` has been
+/// called, for each request it runs every `on_request` in the order they
+/// were added, the callback and then every `on_response` in the reverse
+/// order (see `MiddlewareCall`).
+///
+#[pyclass]
+pub(crate) struct Middleware {
+    handler: PyObject,          // The callback the runner was made with
+    hooks: Arc<[Hooks]>,        // Copied on write so requests already going keep the hooks they started with
+}
+
+impl Middleware {
+    pub(crate) fn new(handler: PyObject) -> Self {
+        Self { handler, hooks: Arc::new([]) }
+    }
+
+    /// The callback the middleware wraps.
+    pub(crate) fn handler(&self) -> &PyObject {
+        &self.handler
+    }
+
+    ///
+    /// Internal Method: Middleware::add() -> PyResult<()>
+    ///
+    ///     Adds a middleware from an object with `on_request(request)`
+    ///     and / or `on_response(request, response)`, a TypeError if it has
+    ///     neither.
+    ///
+    pub(crate) fn add(&mut self, middleware: &PyAny) -> PyResult<()> {
+        let hook = |name: &str| -> PyResult<Option<PyObject>> {
+            match middleware.hasattr(name)? {
+                true => Ok(Some(middleware.getattr(name)?.into())),
+                false => Ok(None),
+            }
+        };
+
+        let hooks = Hooks {
+            on_request: hook("on_request")?,
+            on_response: hook("on_response")?,
+        };
+
+        if hooks.on_request.is_none() && hooks.on_response.is_none() {
+            return Err(PyTypeError::new_err("middleware needs an on_request or on_response method"))
+        }
+
+        let mut all = self.hooks.to_vec();
+        all.push(hooks);
+        self.hooks = all.into();
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl Middleware {
+    ///
+    /// PythonMethod: Middleware(request) -> response | MiddlewareCall
+    ///
+    ///     Runs the request through the middleware and the callback, when
+    ///     nothing along the way is async the response comes straight back
+    ///     otherwise an awaitable which resolves to it.
+    ///
+    #[call]
+    fn __call__(&self, py: Python, request: PyObject) -> PyResult<PyObject> {
+        let mut call = MiddlewareCall {
+            handler: self.handler.clone_ref(py),
+            hooks: self.hooks.clone(),
+            request,
+            stage: Stage::Request(0),
+            response: py.None(),
+            awaiting: None,
+        };
+
+        match call.run(py)? {
+            Some(response) => Ok(response),
+            None => Ok(Py::new(py, call)?.into_py(py)),
+        }
+    }
+}
+
+
+///
+/// Where a MiddlewareCall is up to.
+///
+#[derive(Clone, Copy)]
+enum Stage {
+    Request(usize),     // Calling the nth `on_request`
+    Handler,            // Calling the runner's callback
+    Response(usize),    // Calling the `on_response` before the nth, counting down to 0
+}
+
+///
+/// What calling the current stage gave.
+///
+enum Step {
+    Called(PyObject),
+    Skipped,    // The middleware doesn't have the hook for this stage
+    Done,
+}
+
+///
+/// MiddlewareCall takes one request through the middleware. An
+/// `on_request` that returns anything but None has answered the request
+/// itself, the callback and the middleware after it are skipped and the
+/// response goes back through the `on_response` of those before it. An
+/// `on_response` can change the response in place or return a new one.
+///
+/// Any hook (or the callback) can be sync or async, whatever an async one
+/// is waiting on is passed along like `yield from` would. If one raises
+/// it's the same as the callback raising.
+///
+#[pyclass]
+pub(crate) struct MiddlewareCall {
+    handler: PyObject,
+    hooks: Arc<[Hooks]>,
+    request: PyObject,
+    stage: Stage,
+    response: PyObject,             // The response so far, None until something gives one
+    awaiting: Option<Awaiting>,     // The hook (or callback) we're waiting on
+}
+
+#[pymethods]
+impl MiddlewareCall {
+    ///
+    /// PythonMethod: MiddlewareCall.throw(type, value=None, traceback=None)
+    ///
+    ///     Passes an exception (e.g. the CancelledError when the request is
+    ///     cancelled) on into whatever we're waiting on.
+    ///
+    #[args(value = "None", traceback = "None")]
+    fn throw(&self, py: Python, type_: &PyAny, value: Option<&PyAny>, traceback: Option<&PyAny>) -> PyResult<PyObject> {
+        match self.awaiting.as_ref() {
+            Some(awaiting) => awaiting.throw(py, type_, value, traceback),
+            None => match value {
+                Some(value) if !value.is_none() => Err(PyErr::from_instance(value)),
+                _ => Err(PyErr::from_instance(type_)),
+            },
+        }
+    }
+}
+
+impl MiddlewareCall {
+    /// What we're waiting on right now, so an ASGICall under us can still be told about a disconnect.
+    pub(crate) fn awaiting(&self) -> Option<&Awaiting> {
+        self.awaiting.as_ref()
+    }
+
+    ///
+    /// Internal Method: MiddlewareCall::run() -> PyResult<Option<PyObject>>
+    ///
+    ///     Calls through the stages until one gives back an awaitable
+    ///     (`None`, it's in `awaiting`) or we get to the end (the response).
+    ///
+    fn run(&mut self, py: Python) -> PyResult<Option<PyObject>> {
+        loop {
+            let result = match self.call(py)? {
+                Step::Called(result) => result,
+                Step::Skipped => continue,
+                Step::Done => return Ok(Some(self.response.clone_ref(py))),
+            };
+
+            self.awaiting = Awaiting::of(result.as_ref(py))?;
+            if self.awaiting.is_some() {
+                return Ok(None)
+            }
+
+            self.advance(py, result)?;
+        }
+    }
+
+    fn call(&mut self, py: Python) -> PyResult<Step> {
+        let step = match self.stage {
+            Stage::Request(i) => match self.hooks.get(i) {
+                Some(Hooks { on_request: Some(hook), .. }) => Step::Called(hook.call1(py, (self.request.clone_ref(py),))?),
+                Some(_) => {
+                    self.stage = Stage::Request(i + 1);
+                    Step::Skipped
+                },
+                None => {
+                    self.stage = Stage::Handler;
+                    Step::Skipped
+                },
+            },
+            Stage::Handler => Step::Called(self.handler.call1(py, (self.request.clone_ref(py),))?),
+            Stage::Response(0) => Step::Done,
+            Stage::Response(i) => match &self.hooks[i - 1].on_response {
+                Some(hook) => {
+                    let args = (self.request.clone_ref(py), self.response.clone_ref(py));
+                    Step::Called(hook.call1(py, args)?)
+                },
+                None => {
+                    self.stage = Stage::Response(i - 1);
+                    Step::Skipped
+                },
+            },
+        };
+
+        Ok(step)
+    }
+
+    ///
+    /// Moves on to the next stage with what the current one gave, a
+    /// response returned as bytes, a str or a tuple is made into an
+    /// HTTPResponse so an `on_response` always gets one.
+    ///
+    fn advance(&mut self, py: Python, result: PyObject) -> PyResult<()> {
+        self.stage = match self.stage {
+            Stage::Request(i) if result.is_none(py) => Stage::Request(i + 1),
+            Stage::Request(i) => {
+                self.response = response_of(py, result)?;
+                Stage::Response(i)
+            },
+            Stage::Handler => {
+                self.response = response_of(py, result)?;
+                Stage::Response(self.hooks.len())
+            },
+            Stage::Response(i) => {
+                if !result.is_none(py) {
+                    self.response = response_of(py, result)?;
+                }
+                Stage::Response(i - 1)
+            },
+        };
+
+        Ok(())
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for MiddlewareCall {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for MiddlewareCall {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+
+        loop {
+            let awaiting = match slf.awaiting.as_ref() {
+                Some(awaiting) => awaiting,
+                None => return Ok(IterNextOutput::Return(Some(slf.response.clone_ref(py)))),
+            };
+
+            let result = match awaiting.step(py, None)? {
+                IterNextOutput::Yield(yielded) => return Ok(IterNextOutput::Yield(Some(yielded))),
+                IterNextOutput::Return(result) => result,
+            };
+
+            slf.awaiting = None;
+            slf.advance(py, result)?;
+            if let Some(response) = slf.run(py)? {
+                return Ok(IterNextOutput::Return(Some(response)))
+            }
+        }
+    }
+}
+
+
+/// `result` as an HTTPResponse if it's one of the shorthands `HTTPResponse::from_return()` takes.
+fn response_of(py: Python, result: PyObject) -> PyResult<PyObject> {
+    match http::is_shorthand(result.as_ref(py)) {
+        true => Ok(Py::new(py, HTTPResponse::from_return(result.as_ref(py))?)?.into_py(py)),
+        false => Ok(result),
+    }
+}
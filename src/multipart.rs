@@ -0,0 +1,448 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::PyValueError;
+use pyo3::types::{PyBytes, PyList};
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use bstr::ByteSlice;
+
+use crate::cookie;
+use crate::errors::BadRequest;
+use crate::headers::{HeaderName, Headers};
+use crate::http;
+use crate::spool;
+
+
+/// The most parts `request.multipart()` will decode, a body with more is a `400`.
+const MAX_PARTS: usize = 1000;
+
+/// The most a part's headers can take up.
+const MAX_PART_HEAD: usize = 16 * 1024;
+
+/// The longest boundary RFC 2046 allows.
+const MAX_BOUNDARY: usize = 70;
+
+
+///
+/// MultipartPart is one part of a `multipart/form-data` body as given back
+/// by `request.multipart()`. A part no bigger than the `spool_size` it was
+/// decoded with is in `data`, a bigger one was written to a temp file
+/// which is in `path` instead (and `data` is None).
+///
+/// The temp file is removed once the part is let go of, a handler that
+/// wants to keep the upload moves it somewhere else first.
+///
+#[pyclass]
+pub(crate) struct MultipartPart {
+    /// The part's own headers, `Content-Disposition` and all.
+    #[pyo3(get)]
+    headers: Headers,
+
+    /// The `name` of the form field.
+    #[pyo3(get)]
+    name: String,
+
+    /// The `filename` for a file upload exactly as the client sent it,
+    /// it's nothing to build a path out of.
+    #[pyo3(get)]
+    filename: Option<String>,
+
+    /// The part's `Content-Type` if it gave one.
+    #[pyo3(get)]
+    content_type: Option<String>,
+
+    /// How many bytes the part is, whether it's in `data` or `path`.
+    #[pyo3(get)]
+    size: usize,
+
+    #[pyo3(get)]
+    data: Option<PyObject>,
+
+    spooled: Option<Spooled>,
+}
+
+#[pymethods]
+impl MultipartPart {
+    /// The temp file a part bigger than `spool_size` was written to, None otherwise.
+    #[getter]
+    fn path(&self) -> Option<String> {
+        self.spooled.as_ref().map(|spooled| spooled.0.to_string_lossy().into_owned())
+    }
+}
+
+
+///
+/// MultipartCall is the parsing `request.multipart()` hands to the loop's
+/// executor, spooling large parts to disk shouldn't hold up the loop. Like
+/// `WSGIApp` it has its own copy of the body so nothing on the thread
+/// borrows the request.
+///
+#[pyclass]
+pub(crate) struct MultipartCall {
+    body: Vec<u8>,
+    boundary: String,
+    spool_size: usize,
+    spool_dir: PathBuf,
+}
+
+impl MultipartCall {
+    ///
+    /// Internal Method: MultipartCall::new() -> PyResult<Self>
+    ///
+    ///     The call for a body sent with `content_type`, anything but
+    ///     `multipart/form-data` is a ValueError and one without a boundary
+    ///     we can use a BadRequest.
+    ///
+    pub(crate) fn new(content_type: Option<&str>, body: Vec<u8>, spool_size: usize, spool_dir: Option<PathBuf>) -> PyResult<Self> {
+        let content_type = content_type.unwrap_or("");
+        let mut params = content_type.split(';');
+
+        let mime = params.next().unwrap_or("").trim();
+        if !mime.eq_ignore_ascii_case("multipart/form-data") {
+            return Err(PyValueError::new_err("the body isn't multipart/form-data"))
+        }
+
+        let params = parse_params(params.collect::<Vec<_>>().join(";").as_str())
+            .ok_or_else(|| BadRequest::new_err("the multipart Content-Type can't be parsed"))?;
+        let boundary = params
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+            .map(|(_, value)| value)
+            .filter(|boundary| valid_boundary(boundary))
+            .ok_or_else(|| BadRequest::new_err("the multipart body has no valid boundary"))?;
+
+        Ok(Self {
+            body,
+            boundary,
+            spool_size,
+            spool_dir: spool_dir.unwrap_or_else(std::env::temp_dir),
+        })
+    }
+}
+
+#[pymethods]
+impl MultipartCall {
+    ///
+    /// PythonMethod: MultipartCall() -> list[MultipartPart]
+    ///
+    ///     Run on the executor, the parsing and any spooling is done
+    ///     without the GIL.
+    ///
+    #[call]
+    fn __call__(&self, py: Python) -> PyResult<PyObject> {
+        let parts = py
+            .allow_threads(|| parse(&self.body, &self.boundary, self.spool_size, &self.spool_dir))
+            .map_err(|e| match e {
+                MultipartError::Malformed(reason) => BadRequest::new_err(reason),
+                MultipartError::Io(e) => e.into(),
+            })?;
+
+        let list = PyList::empty(py);
+        for part in parts {
+            let data = match part.spooled {
+                Some(_) => None,
+                None => Some(PyBytes::new(py, &self.body[part.content.0..part.content.1]).into()),
+            };
+
+            list.append(Py::new(py, MultipartPart {
+                content_type: part.headers.get(HeaderName::ContentType).map(str::to_string),
+                headers: part.headers,
+                name: part.name,
+                filename: part.filename,
+                size: part.content.1 - part.content.0,
+                data,
+                spooled: part.spooled,
+            })?)?;
+        }
+
+        Ok(list.into())
+    }
+}
+
+
+/// A temp file a part was spooled to, removed when it's dropped.
+struct Spooled(PathBuf);
+
+impl Spooled {
+    fn create(dir: &Path, content: &[u8]) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let path = spool::temp_path(dir, "async-rust-upload")?;
+        let mut file = options.open(&path)?;
+
+        // from here on dropping it removes the file, even if the write fails
+        let spooled = Self(path);
+        file.write_all(content)?;
+        Ok(spooled)
+    }
+}
+
+impl Drop for Spooled {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+
+enum MultipartError {
+    Malformed(&'static str),
+    Io(io::Error),
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// A part as `parse` found it, `content` is its range of the body.
+struct Part {
+    headers: Headers,
+    name: String,
+    filename: Option<String>,
+    content: (usize, usize),
+    spooled: Option<Spooled>,
+}
+
+///
+/// Internal Method: multipart::parse() -> Result<Vec<Part>, MultipartError>
+///
+///     Goes through the body once from boundary to boundary, each part's
+///     content is left where it is in the body unless it's bigger than
+///     `spool_size`, then it's written out to a temp file in `spool_dir`
+///     as soon as its end is found. The preamble before the first
+///     boundary and the epilogue after the last are ignored, a body that
+///     never gets to its closing boundary is malformed.
+///
+fn parse(body: &[u8], boundary: &str, spool_size: usize, spool_dir: &Path) -> Result<Vec<Part>, MultipartError> {
+    let delimiter = format!("\r\n--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+
+    // the first boundary doesn't need a line break before it
+    let mut pos = match body.starts_with(&delimiter[2..]) {
+        true => delimiter.len() - 2,
+        false => match body.find(delimiter) {
+            Some(start) => start + delimiter.len(),
+            None => return Err(MultipartError::Malformed("the multipart body has no boundary")),
+        },
+    };
+
+    let mut parts = Vec::new();
+    loop {
+        let rest = &body[pos..];
+        if rest.starts_with(b"--") {
+            return Ok(parts)
+        }
+
+        // a boundary can be followed by whitespace before its line break
+        let padding = rest.iter().take_while(|b| **b == b' ' || **b == b'\t').count();
+        if !rest[padding..].starts_with(b"\r\n") {
+            return Err(MultipartError::Malformed(match rest[padding..].is_empty() {
+                true => "the multipart body is missing its closing boundary",
+                false => "a multipart boundary isn't followed by a line break",
+            }))
+        }
+
+        if parts.len() == MAX_PARTS {
+            return Err(MultipartError::Malformed("the multipart body has too many parts"))
+        }
+
+        pos += padding + 2;
+        let (headers, content_start) = parse_part_head(body, pos)?;
+        let (name, filename) = disposition(&headers)?;
+
+        let content_end = match body[content_start..].find(delimiter) {
+            Some(len) => content_start + len,
+            None => return Err(MultipartError::Malformed("the multipart body is missing its closing boundary")),
+        };
+
+        let content = &body[content_start..content_end];
+        let spooled = match content.len() > spool_size {
+            true => Some(Spooled::create(spool_dir, content)?),
+            false => None,
+        };
+
+        parts.push(Part { headers, name, filename, content: (content_start, content_end), spooled });
+        pos = content_end + delimiter.len();
+    }
+}
+
+/// The headers of the part starting at `pos` and where its content starts.
+fn parse_part_head(body: &[u8], pos: usize) -> Result<(Headers, usize), MultipartError> {
+    let mut headers = Headers::new();
+
+    // no headers at all is just the blank line
+    if body[pos..].starts_with(b"\r\n") {
+        return Ok((headers, pos + 2))
+    }
+
+    let window = &body[pos..body.len().min(pos + MAX_PART_HEAD)];
+    let len = match window.find(b"\r\n\r\n") {
+        Some(len) => len,
+        None if window.len() == MAX_PART_HEAD => return Err(MultipartError::Malformed("a multipart part's headers are too large")),
+        None => return Err(MultipartError::Malformed("a multipart part's headers never end")),
+    };
+
+    for line in window[..len].split_str("\r\n") {
+        let colon = match line.find_byte(b':') {
+            Some(colon) => colon,
+            None => return Err(MultipartError::Malformed("a multipart part has an invalid header")),
+        };
+
+        let name = line[..colon].to_str().ok().filter(|name| is_token(name));
+        match name {
+            Some(name) => headers.append(name, line[colon + 1..].trim()),
+            None => return Err(MultipartError::Malformed("a multipart part has an invalid header")),
+        }
+    }
+
+    Ok((headers, pos + len + 4))
+}
+
+///
+/// The field name and filename from a part's `Content-Disposition`, which
+/// has to be there once and be `form-data` with a `name`. A `filename*`
+/// (RFC 5987) wins over a plain `filename`, a parameter given twice is as
+/// malformed as an unterminated quote.
+///
+fn disposition(headers: &Headers) -> Result<(String, Option<String>), MultipartError> {
+    let mut values = headers.get_all(HeaderName::ContentDisposition);
+    let value = match (values.next(), values.next()) {
+        (Some(value), None) => value,
+        (None, _) => return Err(MultipartError::Malformed("a multipart part has no Content-Disposition")),
+        (Some(_), Some(_)) => return Err(MultipartError::Malformed("a multipart part has more than one Content-Disposition")),
+    };
+
+    let (kind, params) = value.split_once(';').unwrap_or((value, ""));
+    if !kind.trim().eq_ignore_ascii_case("form-data") {
+        return Err(MultipartError::Malformed("a multipart part isn't form-data"))
+    }
+
+    let params = parse_params(params)
+        .ok_or(MultipartError::Malformed("a multipart part's Content-Disposition can't be parsed"))?;
+
+    let mut name = None;
+    let mut filename = None;
+    let mut extended = None;
+    for (key, value) in params {
+        let slot = match key.to_ascii_lowercase().as_str() {
+            "name" => &mut name,
+            "filename" => &mut filename,
+            "filename*" => &mut extended,
+            _ => continue,
+        };
+
+        if slot.replace(value).is_some() {
+            return Err(MultipartError::Malformed("a multipart part repeats a Content-Disposition parameter"))
+        }
+    }
+
+    let name = name.ok_or(MultipartError::Malformed("a multipart part has no name"))?;
+    let filename = match extended {
+        Some(value) => Some(decode_extended(&value)
+            .ok_or(MultipartError::Malformed("a multipart part has an invalid filename*"))?),
+        None => filename,
+    };
+
+    Ok((name, filename))
+}
+
+///
+/// Splits `; key=value; key="quoted value"` parameters, a quoted value can
+/// have `;` in it and `\` escapes. None is something that isn't a
+/// parameter or a quote that's never closed.
+///
+fn parse_params(params: &str) -> Option<Vec<(String, String)>> {
+    let mut parsed = Vec::new();
+    let mut chars = params.trim_start_matches([';', ' ', '\t']).chars().peekable();
+
+    loop {
+        while chars.next_if(|c| *c == ' ' || *c == '\t' || *c == ';').is_some() {}
+        if chars.peek().is_none() {
+            return Some(parsed)
+        }
+
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ';')).collect();
+        let key = key.trim();
+        if chars.next() != Some('=') || !is_token(key) {
+            return None
+        }
+
+        while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+        let value = match chars.peek() {
+            Some('"') => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => value.push(chars.next()?),
+                        c => value.push(c),
+                    }
+                }
+
+                // nothing but whitespace between the closing quote and the next `;`
+                while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+                if chars.peek().is_some_and(|c| *c != ';') {
+                    return None
+                }
+
+                value
+            },
+            _ => {
+                let value: String = std::iter::from_fn(|| chars.next_if(|c| *c != ';')).collect();
+                value.trim().to_string()
+            },
+        };
+
+        parsed.push((key.to_string(), value));
+    }
+}
+
+/// Decodes an RFC 5987 `charset'language'value`, only utf-8 (and its ascii subset) is taken.
+fn decode_extended(value: &str) -> Option<String> {
+    let mut pieces = value.splitn(3, '\'');
+    let charset = pieces.next()?;
+    let _language = pieces.next()?;
+    let encoded = pieces.next()?;
+
+    if !charset.eq_ignore_ascii_case("utf-8") && !charset.eq_ignore_ascii_case("us-ascii") {
+        return None
+    }
+
+    String::from_utf8(http::percent_decode_bytes(encoded)).ok()
+}
+
+/// If `value` is a non-empty RFC 7230 token.
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(cookie::is_token)
+}
+
+/// If `boundary` is 1 to 70 of the characters RFC 2046 allows, not ending in a space.
+fn valid_boundary(boundary: &str) -> bool {
+    let allowed = |b: u8| b.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(&b);
+
+    !boundary.is_empty()
+        && boundary.len() <= MAX_BOUNDARY
+        && !boundary.ends_with(' ')
+        && boundary.bytes().all(allowed)
+}
+
+///
+/// Internal Method: multipart::parts() -> PyResult<PyObject>
+///
+///     The future `request.multipart()` gives back, the parsing scheduled
+///     on the loop's default executor.
+///
+pub(crate) fn parts(py: Python, call: MultipartCall) -> PyResult<PyObject> {
+    let fut = crate::get_loop(py)?.call_method1("run_in_executor", (py.None(), Py::new(py, call)?))?;
+    Ok(fut.into())
+}
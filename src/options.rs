@@ -0,0 +1,739 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::types::PyDict;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::acl::{self, Acl};
+use crate::compress;
+use crate::cors::Cors;
+use crate::forwarded::{Cidr, ForwardedHeader};
+use crate::http;
+use crate::listener::{AcceptPause, ClientOptions, KeepAlive};
+use crate::memory::{MemoryBudget, Overflow};
+use crate::ratelimit::RateLimiter;
+use crate::spool::SpoolPolicy;
+use crate::throttle::WriteBudget;
+use crate::tls::{self, TLSConfig};
+
+
+///
+/// RunnerOptions are the keyword arguments shared by every way of building
+/// an AsyncServerRunner (`new`, `from_fd`, `from_systemd`), they're parsed
+/// once here so each constructor only has to deal with its own arguments.
+///
+///     Optional:
+///         - tls:          TLSConfig   (terminate TLS on every accepted connection)
+///         - sni_mismatch: str         ("log" or "reject" with a `421` a request whose Host isn't the name the client gave with SNI, not checked by default)
+///         - websocket:    PyObject    (called as `websocket(request, ws)` for upgrade requests)
+///         - connect_handler: PyObject (called as `connect_handler(host, port, request)` instead of the callback for `CONNECT` requests with `proxy_mode`, returning None tunnels the connection to `host:port` and anything else, a status or an Upgrade of its own, is the answer, it can be async)
+///         - on_headers:   PyObject    (called as `on_headers(meta)` once a head is in and before the body is read, a response it returns is sent instead of calling the callback)
+///         - on_ready:     PyObject    (called as `on_ready(addrs)` once the runner is accepting, `addrs` as `local_addrs()` gives them, it can be async)
+///         - on_shutdown:  PyObject    (called as `on_shutdown()` by `graceful_shutdown()` before the listener closes, it can be async)
+///         - shutdown_timeout: float   (seconds `graceful_shutdown()` waits for the connections to finish before cancelling them, no limit by default)
+///         - access_log:   bool        (log every request to `async_rust.access`, defaults to true)
+///         - log_raw_path: bool        (log the target byte for byte as latin-1 rather than with bytes that aren't utf-8 `%XX` encoded)
+///         - debug:        bool        (send tracebacks in 500 responses, defaults to false)
+///         - merge_slashes: bool       (treat `//` in request paths as `/`, defaults to false)
+///         - allowed_hosts: list[str]  (the Host names we answer to, `*.example.com` matches subdomains)
+///         - invalid_host_status: int  (the status for a Host not in `allowed_hosts`, defaults to 400)
+///         - server_header: bool       (send `Server: async-rust/<version>`, defaults to true)
+///         - default_content_type: str (the `Content-Type` of a response with a body but none of its own, `""` to send none, defaults to `text/plain; charset=utf-8`)
+///         - max_body_size: int        (the largest request body we'll read, defaults to 10MB)
+///         - spool_threshold: int      (bodies bigger than this are written to an anonymous temp file as they arrive rather than kept in memory, see `request.body_file()`, off by default)
+///         - spool_dir:    str         (the directory spooled bodies go in, the system's temp directory by default)
+///         - stream_request_body: bool (invoke the callback once the head is in and leave the body for `request.stream()` to read as it's consumed, defaults to false)
+///         - strict_content_length: bool   (a handler's `Content-Length` that isn't its body's length is an error, false corrects it instead, defaults to true)
+///         - max_drain_bytes: int      (the most of a refused request's body we'll read and throw away to keep the connection, bigger ones close it, defaults to 64KB)
+///         - read_buffer_size: int     (the most one read off a socket takes, defaults to 64KB)
+///         - read_high_water: int      (how much we'll buffer past the request being parsed before we stop reading, defaults to 64KB)
+///         - min_poll_delay: float     (seconds between polls right after a client arrives, defaults to 0.001)
+///         - max_poll_delay: float     (the most the idle poll delay backs off to, defaults to 0.1)
+///         - housekeeping_interval: float  (seconds between the runner's passes over its connections looking for idle ones to free the buffers of, defaults to 5)
+///         - idle_reclaim_after: float (seconds a connection has to have been idle for before its buffers are freed, defaults to 10)
+///         - idle_buffer_retain: int   (the biggest buffer an idle connection keeps, anything over it is freed, defaults to 4KB)
+///         - raw:          bool        (skip HTTP and call `callback(reader, writer)` for each connection)
+///         - write_high_water: int     (how much a raw Writer buffers before `write()` warns, defaults to 64KB)
+///         - max_write_rate_bytes_per_sec: int     (the most response body each connection is sent a second, unlimited by default)
+///         - max_total_write_rate_bytes_per_sec: int   (the same across every connection of the runner, unlimited by default, neither applies to HTTP/2 yet)
+///         - memory_budget: int        (roughly the most bytes the connections' buffers and spooled bodies may hold, over it new connections and request bodies are refused, unlimited by default)
+///         - memory_low_water: int     (what usage has to get back under before they aren't, defaults to 90% of `memory_budget`)
+///         - memory_overflow: str      ("503" sends a connection refused for memory a bare `503`, "close" just closes it, defaults to "503")
+///         - reactor:      str         ("asyncio" by default, "native" does the socket work on a Rust thread)
+///         - accept_mode:  str         ("poll" by default, "thread" accepts on a Rust thread that wakes the loop for each new client)
+///         - resolve:      bool        (look up hostnames in bind addresses, false only takes IP literals, defaults to true)
+///         - backlog:      int         (how many pending connections the kernel queues for us, defaults to 1024)
+///         - accept_cooldown: float    (seconds we stop accepting for after running out of file descriptors, defaults to 0.5)
+///         - keep_alive_timeout: float (seconds a kept-alive connection waits for its next request before we close it, off by default)
+///         - handler_timeout: float    (seconds the callback has to produce its response before it's cancelled and a `503` sent, off by default)
+///         - keep_alive_max_requests: int  (the most requests one connection gets, the last is answered with `Connection: close`, unlimited by default)
+///         - keep_alive_header: bool   (send `Keep-Alive: timeout=N, max=M` with the two above while the connection stays open, defaults to false)
+///         - tcp_keepalive: (int, int, int)    (`(idle_secs, interval_secs, probes)` for keepalive on every connection, off by default)
+///         - so_linger:    int         (seconds a close waits for unsent data on every connection, `0` resets instead of leaving TIME_WAIT, off by default)
+///         - tcp_fastopen: int         (the TCP Fast Open queue length for the listeners, linux only and off by default)
+///         - compress:     bool        (gzip or deflate responses for clients that accept it, defaults to false)
+///         - compress_min_size: int    (the smallest body worth compressing, defaults to 1KB)
+///         - compress_types: list[str] (the content types to compress, `text/*` covers every text type)
+///         - proxy_protocol: bool      (every connection starts with a PROXY protocol v1 or v2 header, defaults to false)
+///         - lenient:      bool        (accept bare LF line endings, whitespace before a header's colon and control characters in header values, defaults to false)
+///         - max_request_line: int     (the longest request line before a `414`, at most 64KB, defaults to 8KB)
+///         - max_method_length: int    (the longest request method before a `400`, defaults to 16)
+///         - allowed_methods: list[str]    (the methods handed to the callback, any other is a `501`, case-sensitive, every method when unset)
+///         - pass_options_star: bool   (hand `OPTIONS *` to the callback with `request.path` as `"*"`, otherwise it's answered for it)
+///         - proxy_mode:   bool        (accept absolute-form targets and `CONNECT host:port`, see `request.target_host`, otherwise they're a `400`, defaults to false)
+///         - options_allow: list[str]  (the methods `OPTIONS *` answers with when the callback isn't a Router)
+///         - http09:       bool        (answer HTTP/0.9 `GET /path` requests with just the body, otherwise they're a `505`, defaults to false)
+///         - trusted_proxies: list[str]    (the proxies, by address or CIDR, whose `forwarded_header` sets `request.remote_addr`)
+///         - forwarded_header: str     (the header the trusted proxies write, "x-forwarded-for" by default or "forwarded", the other is ignored)
+///         - allow_ips:    list[str]   (only accept connections from these addresses or CIDRs, any peer when empty)
+///         - deny_ips:     list[str]   (refuse connections from these addresses or CIDRs, even if they're allowed)
+///         - deny_403:     bool        (send a refused connection a bare `403` before closing it, defaults to false)
+///         - rate_limit:   (int, float)    (`(capacity, per_second)` of a token bucket per client, over it is a `429`, off by default)
+///         - rate_limit_burst: int     (how many `429`s in a row a client gets before its connection is closed instead, defaults to 10)
+///         - trust_request_id: bool    (use the client's `X-Request-Id` as `request.id` when it sends one, defaults to false)
+///         - cors:         dict        (answer CORS preflights and allow cross-origin requests, see `cors::Cors` for its keys, off by default)
+///         - http2:        bool        (speak HTTP/2 to clients that negotiate `h2` over ALPN or open with its preface, defaults to true, see below)
+///         - profiling:    bool        (time accepts, reads, parsing, the handler and writes for `profile_snapshot()`, defaults to false)
+///
+///     Over HTTP/2 a request is handled the same as over HTTP/1, streamed
+///     bodies and `on_headers` included, bar an Upgrade which has no HTTP/2
+///     equivalent (the client gets a `500`). `stream_request_body` turns
+///     `http2` off since a stream's body can't be handed over as it arrives
+///     yet, and the native reactor ignores `http2`, it only speaks HTTP/1.
+///     A `tls` config offering `h2` over ALPN has it taken off when `http2`
+///     is off so a client can't pick it.
+///
+pub(crate) struct RunnerOptions {
+    pub(crate) tls: Option<Arc<rustls::ServerConfig>>,
+    pub(crate) sni_mismatch: Option<SniMismatch>,
+    pub(crate) websocket: Option<PyObject>,
+    pub(crate) connect_handler: Option<PyObject>,
+    pub(crate) on_headers: Option<PyObject>,
+    pub(crate) on_ready: Option<PyObject>,
+    pub(crate) on_shutdown: Option<PyObject>,
+    pub(crate) shutdown_timeout: Option<f32>,
+    pub(crate) access_log: bool,
+    pub(crate) log_raw_path: bool,
+    pub(crate) debug: bool,
+    pub(crate) merge_slashes: bool,
+    pub(crate) allowed_hosts: Option<Vec<String>>,
+    pub(crate) invalid_host_status: u16,
+    pub(crate) server_header: bool,
+    pub(crate) default_content_type: Option<String>,
+    pub(crate) max_body_size: usize,
+    pub(crate) spool: Option<SpoolPolicy>,
+    pub(crate) stream_request_body: bool,
+    pub(crate) max_drain_bytes: usize,
+    pub(crate) strict_content_length: bool,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) read_high_water: usize,
+    pub(crate) min_poll_delay: f32,
+    pub(crate) max_poll_delay: f32,
+    pub(crate) housekeeping_interval: f32,
+    pub(crate) idle_reclaim_after: f32,
+    pub(crate) idle_buffer_retain: usize,
+    pub(crate) raw: bool,
+    pub(crate) write_high_water: usize,
+    pub(crate) max_write_rate: Option<u64>,
+    pub(crate) write_budget: Option<Arc<WriteBudget>>,
+    pub(crate) memory_budget: Option<Arc<MemoryBudget>>,
+    pub(crate) reactor: ReactorKind,
+    pub(crate) accept_mode: AcceptMode,
+    pub(crate) resolve: bool,
+    pub(crate) backlog: i32,
+    pub(crate) accept_cooldown: f32,
+    pub(crate) keep_alive_timeout: Option<f32>,
+    pub(crate) handler_timeout: Option<f32>,
+    pub(crate) keep_alive_max_requests: Option<u64>,
+    pub(crate) keep_alive_header: bool,
+    pub(crate) tcp_keepalive: Option<KeepAlive>,
+    pub(crate) so_linger: Option<Duration>,
+    pub(crate) tcp_fastopen: Option<u32>,
+    pub(crate) compress: bool,
+    pub(crate) compress_min_size: usize,
+    pub(crate) compress_types: Vec<String>,
+    pub(crate) proxy_protocol: bool,
+    pub(crate) trusted_proxies: Vec<Cidr>,
+    pub(crate) forwarded_header: ForwardedHeader,
+    pub(crate) acl: Arc<Acl>,
+    pub(crate) deny_403: bool,
+    pub(crate) rate_limit: Option<Arc<RateLimiter>>,
+    pub(crate) trust_request_id: bool,
+    pub(crate) lenient: bool,
+    pub(crate) max_request_line: usize,
+    pub(crate) max_method_length: usize,
+    pub(crate) allowed_methods: Option<Vec<String>>,
+    pub(crate) http09: bool,
+    pub(crate) pass_options_star: bool,
+    pub(crate) proxy_mode: bool,
+    pub(crate) options_allow: String,
+    pub(crate) cors: Option<Cors>,
+    pub(crate) http2: bool,
+    pub(crate) profiling: bool,
+}
+
+///
+/// What drives the sockets, `Asyncio` polls each connection from its own
+/// task on the event loop while `Native` hands them all to a Rust thread
+/// (see `reactor::NativeReactor`) which only calls into python to run the
+/// callback.
+///
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ReactorKind {
+    Asyncio,
+    Native,
+}
+
+///
+/// How the asyncio reactor gets its clients, `Poll` tries the listeners from
+/// the runner's `__next__` and sleeps between tries while `Thread` has a
+/// Rust thread block in accept for them (see `acceptor::AcceptThread`).
+///
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum AcceptMode {
+    Poll,
+    Thread,
+}
+
+///
+/// What happens to a request over TLS whose Host names a different server
+/// than the client asked for with SNI, either it's let through with a
+/// warning logged or refused with a `421` so the client retries on a
+/// connection of its own for that host.
+///
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum SniMismatch {
+    Log,
+    Reject,
+}
+
+impl Default for RunnerOptions {
+    fn default() -> Self {
+        Self {
+            tls: None,
+            sni_mismatch: None,
+            websocket: None,
+            connect_handler: None,
+            on_headers: None,
+            on_ready: None,
+            on_shutdown: None,
+            shutdown_timeout: None,
+            access_log: true,
+            log_raw_path: false,
+            debug: false,
+            merge_slashes: false,
+            allowed_hosts: None,
+            invalid_host_status: 400,
+            server_header: true,
+            default_content_type: Some(String::from("text/plain; charset=utf-8")),
+            max_body_size: 10 * 1024 * 1024,
+            spool: None,
+            stream_request_body: false,
+            max_drain_bytes: crate::prehandler::DEFAULT_DRAIN_LIMIT,
+            strict_content_length: true,
+            read_buffer_size: crate::DEFAULT_READ_SIZE,
+            read_high_water: crate::DEFAULT_READ_SIZE,
+            min_poll_delay: 0.001,
+            max_poll_delay: 0.1,
+            housekeeping_interval: 5.0,
+            idle_reclaim_after: 10.0,
+            idle_buffer_retain: 4 * 1024,
+            raw: false,
+            write_high_water: crate::stream::DEFAULT_HIGH_WATER,
+            max_write_rate: None,
+            write_budget: None,
+            memory_budget: None,
+            reactor: ReactorKind::Asyncio,
+            accept_mode: AcceptMode::Poll,
+            resolve: true,
+            backlog: crate::listener::DEFAULT_BACKLOG,
+            accept_cooldown: crate::listener::DEFAULT_ACCEPT_COOLDOWN,
+            keep_alive_timeout: None,
+            handler_timeout: None,
+            keep_alive_max_requests: None,
+            keep_alive_header: false,
+            tcp_keepalive: None,
+            so_linger: None,
+            tcp_fastopen: None,
+            compress: false,
+            compress_min_size: compress::DEFAULT_MIN_SIZE,
+            compress_types: compress::DEFAULT_TYPES.iter().map(|media_type| media_type.to_string()).collect(),
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            forwarded_header: ForwardedHeader::XForwardedFor,
+            acl: Arc::default(),
+            deny_403: false,
+            rate_limit: None,
+            trust_request_id: false,
+            lenient: false,
+            max_request_line: 8 * 1024,
+            max_method_length: 16,
+            allowed_methods: None,
+            http09: false,
+            pass_options_star: false,
+            proxy_mode: false,
+            options_allow: String::from("GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS"),
+            cors: None,
+            http2: true,
+            profiling: false,
+        }
+    }
+}
+
+impl RunnerOptions {
+    pub(crate) fn from_kwargs(kwargs: Option<&PyDict>) -> PyResult<Self> {
+        let mut options = Self::default();
+
+        let kwargs = match kwargs {
+            Some(kwargs) => kwargs,
+            None => return Ok(options),
+        };
+
+        let (mut rate_limit, mut rate_limit_burst) = (None, DEFAULT_RATE_LIMIT_BURST);
+        let mut total_write_rate = None;
+        let (mut memory_budget, mut memory_low_water, mut memory_overflow) = (None, None, Overflow::Unavailable);
+        let mut spool_threshold = None;
+        let mut spool_dir: Option<String> = None;
+        for (key, value) in kwargs.iter() {
+            let key: &str = key.extract()?;
+            if value.is_none() {
+                continue
+            }
+
+            match key {
+                "tls" => {
+                    let tls: PyRef<TLSConfig> = value.extract()?;
+                    options.tls = Some(tls.config.clone());
+                },
+                "websocket" => options.websocket = Some(value.into()),
+                "connect_handler" => options.connect_handler = Some(value.into()),
+                "on_headers" => options.on_headers = Some(value.into()),
+                "on_ready" => options.on_ready = Some(value.into()),
+                "on_shutdown" => options.on_shutdown = Some(value.into()),
+                "shutdown_timeout" => options.shutdown_timeout = Some(value.extract()?),
+                "access_log" => options.access_log = value.is_true()?,
+                "log_raw_path" => options.log_raw_path = value.is_true()?,
+                "debug" => options.debug = value.is_true()?,
+                "merge_slashes" => options.merge_slashes = value.is_true()?,
+                "allowed_hosts" => options.allowed_hosts = Some(value.extract()?),
+                "invalid_host_status" => options.invalid_host_status = value.extract()?,
+                "server_header" => options.server_header = value.is_true()?,
+                "default_content_type" => {
+                    let content_type: String = value.extract()?;
+                    if content_type.contains(|c: char| c.is_control()) {
+                        return Err(PyValueError::new_err("default_content_type can't contain control characters"))
+                    }
+
+                    options.default_content_type = Some(content_type).filter(|content_type| !content_type.is_empty());
+                },
+                "max_body_size" => options.max_body_size = value.extract()?,
+                "spool_threshold" => spool_threshold = Some(value.extract::<usize>()?),
+                "spool_dir" => spool_dir = Some(value.extract::<String>()?),
+                "stream_request_body" => options.stream_request_body = value.is_true()?,
+                "max_drain_bytes" => options.max_drain_bytes = value.extract()?,
+                "strict_content_length" => options.strict_content_length = value.is_true()?,
+                "read_buffer_size" => options.read_buffer_size = value.extract()?,
+                "read_high_water" => options.read_high_water = value.extract()?,
+                "min_poll_delay" => options.min_poll_delay = value.extract()?,
+                "max_poll_delay" => options.max_poll_delay = value.extract()?,
+                "housekeeping_interval" => options.housekeeping_interval = value.extract()?,
+                "idle_reclaim_after" => options.idle_reclaim_after = value.extract()?,
+                "idle_buffer_retain" => options.idle_buffer_retain = value.extract()?,
+                "raw" => options.raw = value.is_true()?,
+                "write_high_water" => options.write_high_water = value.extract()?,
+                "max_write_rate_bytes_per_sec" => options.max_write_rate = Some(value.extract()?),
+                "max_total_write_rate_bytes_per_sec" => total_write_rate = Some(value.extract::<u64>()?),
+                "memory_budget" => memory_budget = Some(value.extract::<usize>()?),
+                "memory_low_water" => memory_low_water = Some(value.extract::<usize>()?),
+                "memory_overflow" => memory_overflow = Overflow::parse(value.extract()?)?,
+                "reactor" => options.reactor = match value.extract::<&str>()? {
+                    "asyncio" => ReactorKind::Asyncio,
+                    "native" => ReactorKind::Native,
+                    other => return Err(PyValueError::new_err(
+                        format!("unknown reactor '{}', expected 'asyncio' or 'native'", other)
+                    )),
+                },
+                "sni_mismatch" => options.sni_mismatch = match value.extract::<&str>()? {
+                    "log" => Some(SniMismatch::Log),
+                    "reject" => Some(SniMismatch::Reject),
+                    other => return Err(PyValueError::new_err(
+                        format!("unknown sni_mismatch '{}', expected 'log' or 'reject'", other)
+                    )),
+                },
+                "accept_mode" => options.accept_mode = match value.extract::<&str>()? {
+                    "poll" => AcceptMode::Poll,
+                    "thread" => AcceptMode::Thread,
+                    other => return Err(PyValueError::new_err(
+                        format!("unknown accept_mode '{}', expected 'poll' or 'thread'", other)
+                    )),
+                },
+                "resolve" => options.resolve = value.is_true()?,
+                "backlog" => options.backlog = value.extract()?,
+                "accept_cooldown" => options.accept_cooldown = value.extract()?,
+                "keep_alive_timeout" => options.keep_alive_timeout = Some(value.extract()?),
+                "handler_timeout" => options.handler_timeout = Some(value.extract()?),
+                "keep_alive_max_requests" => options.keep_alive_max_requests = Some(value.extract()?),
+                "keep_alive_header" => options.keep_alive_header = value.is_true()?,
+                "tcp_keepalive" => options.tcp_keepalive = Some(keepalive(value)?),
+                "so_linger" => {
+                    let secs: u16 = value.extract().map_err(|_| PyValueError::new_err("so_linger must be between 0 and 65535 seconds"))?;
+                    options.so_linger = Some(Duration::from_secs(secs.into()));
+                },
+                "tcp_fastopen" => {
+                    let queue: u32 = value.extract().map_err(|_| PyValueError::new_err("tcp_fastopen must be a positive queue length"))?;
+                    if queue == 0 {
+                        return Err(PyValueError::new_err("tcp_fastopen must be a positive queue length"))
+                    }
+                    options.tcp_fastopen = Some(queue);
+                },
+                "compress" => options.compress = value.is_true()?,
+                "compress_min_size" => options.compress_min_size = value.extract()?,
+                "compress_types" => {
+                    let types: Vec<String> = value.extract()?;
+                    options.compress_types = types.iter().map(|media_type| media_type.trim().to_ascii_lowercase()).collect();
+                },
+                "proxy_protocol" => options.proxy_protocol = value.is_true()?,
+                "lenient" => options.lenient = value.is_true()?,
+                "max_request_line" => options.max_request_line = value.extract()?,
+                "max_method_length" => options.max_method_length = value.extract()?,
+                "allowed_methods" => {
+                    let methods: Vec<String> = value.extract()?;
+                    let methods: Vec<String> = methods.iter().map(|method| method.trim().to_string()).collect();
+                    if let Some(method) = methods.iter().find(|method| !http::is_method(method.as_bytes())) {
+                        return Err(PyValueError::new_err(format!("'{}' isn't a valid request method", method)))
+                    }
+
+                    options.allowed_methods = Some(methods);
+                },
+                "http09" => options.http09 = value.is_true()?,
+                "pass_options_star" => options.pass_options_star = value.is_true()?,
+                "proxy_mode" => options.proxy_mode = value.is_true()?,
+                "options_allow" => {
+                    let methods: Vec<String> = value.extract()?;
+                    options.options_allow = methods.iter().map(|method| method.trim().to_ascii_uppercase()).collect::<Vec<_>>().join(", ");
+                },
+                "trusted_proxies" => {
+                    let proxies: Vec<String> = value.extract()?;
+                    options.trusted_proxies = proxies
+                        .iter()
+                        .map(|proxy| Cidr::parse(proxy).ok_or_else(|| {
+                            PyValueError::new_err(format!("invalid trusted proxy '{}', expected an address or CIDR", proxy))
+                        }))
+                        .collect::<PyResult<_>>()?;
+                },
+                "forwarded_header" => options.forwarded_header = match value.extract::<&str>()? {
+                    "x-forwarded-for" => ForwardedHeader::XForwardedFor,
+                    "forwarded" => ForwardedHeader::Forwarded,
+                    other => return Err(PyValueError::new_err(
+                        format!("unknown forwarded_header '{}', expected 'x-forwarded-for' or 'forwarded'", other)
+                    )),
+                },
+                "allow_ips" => options.acl.update(Some(acl::parse_list("allow_ips", &value.extract::<Vec<String>>()?)?), None),
+                "deny_ips" => options.acl.update(None, Some(acl::parse_list("deny_ips", &value.extract::<Vec<String>>()?)?)),
+                "deny_403" => options.deny_403 = value.is_true()?,
+                "rate_limit" => rate_limit = Some(value.extract::<(u32, f64)>()?),
+                "rate_limit_burst" => rate_limit_burst = value.extract()?,
+                "trust_request_id" => options.trust_request_id = value.is_true()?,
+                "cors" => options.cors = Some(Cors::from_py(value)?),
+                "http2" => options.http2 = value.is_true()?,
+                "profiling" => options.profiling = value.is_true()?,
+                _ => return Err(PyTypeError::new_err(
+                    format!("AsyncServerRunner got an unexpected keyword argument '{}'", key)
+                )),
+            }
+        }
+
+        if !(options.min_poll_delay > 0.0 && options.max_poll_delay >= options.min_poll_delay) {
+            return Err(PyValueError::new_err("poll delays must be positive with min_poll_delay <= max_poll_delay"))
+        }
+
+        if ![options.housekeeping_interval, options.idle_reclaim_after].iter().all(|secs| *secs > 0.0 && secs.is_finite()) {
+            return Err(PyValueError::new_err("housekeeping_interval and idle_reclaim_after must be positive numbers of seconds"))
+        }
+
+        // the whole head is capped at MAX_HEAD_SIZE anyway
+        if options.max_request_line == 0 || options.max_request_line > crate::MAX_HEAD_SIZE {
+            return Err(PyValueError::new_err("max_request_line must be between 1 and 65536"))
+        }
+
+        if options.max_method_length == 0 {
+            return Err(PyValueError::new_err("max_method_length must be positive"))
+        }
+
+        if options.read_buffer_size == 0 || options.read_high_water == 0 {
+            return Err(PyValueError::new_err("read_buffer_size and read_high_water must be positive"))
+        }
+
+        if options.keep_alive_timeout.is_some_and(|timeout| !(timeout > 0.0 && timeout.is_finite())) {
+            return Err(PyValueError::new_err("keep_alive_timeout must be a positive number of seconds"))
+        }
+
+        if options.handler_timeout.is_some_and(|timeout| !(timeout > 0.0 && timeout.is_finite())) {
+            return Err(PyValueError::new_err("handler_timeout must be a positive number of seconds"))
+        }
+
+        if options.shutdown_timeout.is_some_and(|timeout| !(timeout > 0.0 && timeout.is_finite())) {
+            return Err(PyValueError::new_err("shutdown_timeout must be a positive number of seconds"))
+        }
+
+        if options.keep_alive_max_requests == Some(0) {
+            return Err(PyValueError::new_err("keep_alive_max_requests must be at least 1"))
+        }
+
+        if let Some((capacity, rate)) = rate_limit {
+            if capacity == 0 || !(rate > 0.0 && rate.is_finite()) {
+                return Err(PyValueError::new_err("rate_limit must be (capacity, per_second) with both positive"))
+            }
+
+            options.rate_limit = Some(Arc::new(RateLimiter::new(capacity, rate, rate_limit_burst)));
+        }
+
+        if options.backlog <= 0 {
+            return Err(PyValueError::new_err("backlog must be positive"))
+        }
+
+        if !(options.accept_cooldown > 0.0 && options.accept_cooldown.is_finite()) {
+            return Err(PyValueError::new_err("accept_cooldown must be a positive number of seconds"))
+        }
+
+        if options.max_write_rate == Some(0) || total_write_rate == Some(0) {
+            return Err(PyValueError::new_err("write rates must be a positive number of bytes a second"))
+        }
+        options.write_budget = total_write_rate.map(|rate| Arc::new(WriteBudget::new(rate)));
+
+        if memory_low_water.is_some() && memory_budget.is_none() {
+            return Err(PyValueError::new_err("memory_low_water needs a memory_budget"))
+        }
+        options.memory_budget = match memory_budget {
+            Some(limit) => Some(Arc::new(MemoryBudget::new(limit, memory_low_water, memory_overflow)?)),
+            None => None,
+        };
+
+        options.spool = spool_threshold.map(|threshold| SpoolPolicy {
+            threshold,
+            dir: spool_dir.map_or_else(std::env::temp_dir, Into::into),
+        });
+
+        if options.stream_request_body && options.spool.is_some() {
+            return Err(PyValueError::new_err("spool_threshold can't be used with stream_request_body, a streamed body is never buffered"))
+        }
+
+        // a body streamed to the handler is only read off an HTTP/1 connection, there's no HTTP/2 path for it yet
+        if options.stream_request_body {
+            options.http2 = false;
+        }
+
+        if !options.http2 {
+            options.tls = options.tls.take().map(tls::without_h2);
+        }
+
+        if options.connect_handler.is_some() && !options.proxy_mode {
+            return Err(PyValueError::new_err("connect_handler needs proxy_mode=True, CONNECT is refused without it"))
+        }
+
+        if options.reactor == ReactorKind::Native {
+            if !cfg!(target_os = "linux") {
+                return Err(PyValueError::new_err("the native reactor is only supported on linux"))
+            }
+
+            if options.tls.is_some() || options.websocket.is_some() || options.raw || options.proxy_protocol
+                || options.on_headers.is_some() || options.handler_timeout.is_some()
+                || options.max_write_rate.is_some() || options.write_budget.is_some() || options.connect_handler.is_some()
+                || options.spool.is_some() || options.stream_request_body || options.memory_budget.is_some() {
+                return Err(PyValueError::new_err(
+                    "the native reactor doesn't support tls, websocket, raw, proxy_protocol, on_headers, handler_timeout, write rates, connect_handler, spool_threshold, stream_request_body or memory_budget yet"
+                ))
+            }
+        }
+
+        if options.accept_mode == AcceptMode::Thread {
+            if !cfg!(unix) {
+                return Err(PyValueError::new_err("accept_mode='thread' is only supported on unix"))
+            }
+
+            if options.reactor == ReactorKind::Native {
+                return Err(PyValueError::new_err("accept_mode='thread' is for the asyncio reactor, the native one does its own accepting"))
+            }
+        }
+
+        if options.raw && options.proxy_protocol {
+            return Err(PyValueError::new_err("proxy_protocol isn't supported for raw connections yet"))
+        }
+
+        Ok(options)
+    }
+
+    /// The socket options for every accepted client, however it was accepted.
+    pub(crate) fn client_options(&self) -> ClientOptions {
+        ClientOptions {
+            keepalive: self.tcp_keepalive,
+            linger: self.so_linger,
+        }
+    }
+
+    ///
+    /// Internal Method: RunnerOptions::to_dict() -> PyResult<&PyDict>
+    ///
+    ///     Every option as it's in effect, the defaults of the ones that
+    ///     weren't given included, keyed and given the way they're passed
+    ///     in. `tls` and `cors` are only kept parsed so they're whatever
+    ///     `given` (what the options were made from) has for them.
+    ///
+    pub(crate) fn to_dict<'p>(&self, py: Python<'p>, given: Option<&PyDict>) -> PyResult<&'p PyDict> {
+        let given = |key: &str| given.and_then(|given| given.get_item(key)).map(|value| value.to_object(py));
+        let cidrs = |cidrs: &[Cidr]| cidrs.iter().map(Cidr::to_string).collect::<Vec<_>>();
+        let name = |value: bool, yes: &'static str, no: &'static str| if value { yes } else { no };
+
+        let (allow, deny) = self.acl.lists();
+        let (rate_limit, rate_limit_burst) = match self.rate_limit.as_deref().map(RateLimiter::limits) {
+            Some((capacity, rate, burst)) => (Some((capacity, rate)), burst),
+            None => (None, DEFAULT_RATE_LIMIT_BURST),
+        };
+        let (memory_budget, memory_low_water, memory_overflow) = match self.memory_budget.as_deref().map(MemoryBudget::settings) {
+            Some((limit, low_water, overflow)) => (Some(limit), Some(low_water), overflow),
+            None => (None, None, Overflow::Unavailable),
+        };
+
+        let dict = PyDict::new(py);
+        dict.set_item("tls", given("tls"))?;
+        dict.set_item("sni_mismatch", self.sni_mismatch.map(|mode| name(mode == SniMismatch::Log, "log", "reject")))?;
+        dict.set_item("websocket", &self.websocket)?;
+        dict.set_item("connect_handler", &self.connect_handler)?;
+        dict.set_item("on_headers", &self.on_headers)?;
+        dict.set_item("on_ready", &self.on_ready)?;
+        dict.set_item("on_shutdown", &self.on_shutdown)?;
+        dict.set_item("shutdown_timeout", self.shutdown_timeout.map(secs))?;
+        dict.set_item("access_log", self.access_log)?;
+        dict.set_item("log_raw_path", self.log_raw_path)?;
+        dict.set_item("debug", self.debug)?;
+        dict.set_item("merge_slashes", self.merge_slashes)?;
+        dict.set_item("allowed_hosts", &self.allowed_hosts)?;
+        dict.set_item("invalid_host_status", self.invalid_host_status)?;
+        dict.set_item("server_header", self.server_header)?;
+        dict.set_item("default_content_type", self.default_content_type.as_deref().unwrap_or(""))?;
+        dict.set_item("max_body_size", self.max_body_size)?;
+        dict.set_item("spool_threshold", self.spool.as_ref().map(|policy| policy.threshold))?;
+        dict.set_item("spool_dir", self.spool.as_ref().map(|policy| policy.dir.to_string_lossy()))?;
+        dict.set_item("stream_request_body", self.stream_request_body)?;
+        dict.set_item("strict_content_length", self.strict_content_length)?;
+        dict.set_item("max_drain_bytes", self.max_drain_bytes)?;
+        dict.set_item("read_buffer_size", self.read_buffer_size)?;
+        dict.set_item("read_high_water", self.read_high_water)?;
+        dict.set_item("min_poll_delay", secs(self.min_poll_delay))?;
+        dict.set_item("max_poll_delay", secs(self.max_poll_delay))?;
+        dict.set_item("housekeeping_interval", secs(self.housekeeping_interval))?;
+        dict.set_item("idle_reclaim_after", secs(self.idle_reclaim_after))?;
+        dict.set_item("idle_buffer_retain", self.idle_buffer_retain)?;
+        dict.set_item("raw", self.raw)?;
+        dict.set_item("write_high_water", self.write_high_water)?;
+        dict.set_item("max_write_rate_bytes_per_sec", self.max_write_rate)?;
+        dict.set_item("max_total_write_rate_bytes_per_sec", self.write_budget.as_deref().map(WriteBudget::rate))?;
+        dict.set_item("memory_budget", memory_budget)?;
+        dict.set_item("memory_low_water", memory_low_water)?;
+        dict.set_item("memory_overflow", memory_overflow.name())?;
+        dict.set_item("reactor", name(self.reactor == ReactorKind::Asyncio, "asyncio", "native"))?;
+        dict.set_item("accept_mode", name(self.accept_mode == AcceptMode::Poll, "poll", "thread"))?;
+        dict.set_item("resolve", self.resolve)?;
+        dict.set_item("backlog", self.backlog)?;
+        dict.set_item("accept_cooldown", secs(self.accept_cooldown))?;
+        dict.set_item("keep_alive_timeout", self.keep_alive_timeout.map(secs))?;
+        dict.set_item("handler_timeout", self.handler_timeout.map(secs))?;
+        dict.set_item("keep_alive_max_requests", self.keep_alive_max_requests)?;
+        dict.set_item("keep_alive_header", self.keep_alive_header)?;
+        dict.set_item("tcp_keepalive", self.tcp_keepalive.map(|keepalive| (keepalive.idle, keepalive.interval, keepalive.probes)))?;
+        dict.set_item("so_linger", self.so_linger.map(|linger| linger.as_secs()))?;
+        dict.set_item("tcp_fastopen", self.tcp_fastopen)?;
+        dict.set_item("compress", self.compress)?;
+        dict.set_item("compress_min_size", self.compress_min_size)?;
+        dict.set_item("compress_types", &self.compress_types)?;
+        dict.set_item("proxy_protocol", self.proxy_protocol)?;
+        dict.set_item("lenient", self.lenient)?;
+        dict.set_item("max_request_line", self.max_request_line)?;
+        dict.set_item("max_method_length", self.max_method_length)?;
+        dict.set_item("allowed_methods", &self.allowed_methods)?;
+        dict.set_item("pass_options_star", self.pass_options_star)?;
+        dict.set_item("proxy_mode", self.proxy_mode)?;
+        dict.set_item("options_allow", self.options_allow.split(", ").filter(|method| !method.is_empty()).collect::<Vec<_>>())?;
+        dict.set_item("http09", self.http09)?;
+        dict.set_item("trusted_proxies", cidrs(&self.trusted_proxies))?;
+        dict.set_item("forwarded_header", self.forwarded_header.name())?;
+        dict.set_item("allow_ips", cidrs(&allow))?;
+        dict.set_item("deny_ips", cidrs(&deny))?;
+        dict.set_item("deny_403", self.deny_403)?;
+        dict.set_item("rate_limit", rate_limit)?;
+        dict.set_item("rate_limit_burst", rate_limit_burst)?;
+        dict.set_item("trust_request_id", self.trust_request_id)?;
+        dict.set_item("cors", given("cors"))?;
+        dict.set_item("http2", self.http2)?;
+        dict.set_item("profiling", self.profiling)?;
+        Ok(dict)
+    }
+
+    ///
+    /// Internal Method: RunnerOptions::carry_over()
+    ///
+    ///     For options parsed afresh by `update_config()`, takes on what
+    ///     the `old` ones share with the connections already going rather
+    ///     than starting it over: the write and memory budgets and the ACL
+    ///     (which `update_acl()` may have changed since) always, and the
+    ///     rate limiter's buckets unless `changes` gives it new limits.
+    ///
+    pub(crate) fn carry_over(&mut self, old: &RunnerOptions, changes: &PyDict) {
+        self.write_budget = old.write_budget.clone();
+        self.memory_budget = old.memory_budget.clone();
+        self.acl = old.acl.clone();
+        if changes.get_item("rate_limit").is_none() && changes.get_item("rate_limit_burst").is_none() {
+            self.rate_limit = old.rate_limit.clone();
+        }
+    }
+
+    /// How an accept loop backs off once we're out of fds, see `accept_cooldown`.
+    pub(crate) fn accept_pause(&self) -> AcceptPause {
+        // f32 seconds aren't exact, whole milliseconds log as what was asked for
+        AcceptPause::new(Duration::from_millis(((self.accept_cooldown * 1000.0).round() as u64).max(1)))
+    }
+}
+
+///
+/// The options `AsyncServerRunner.update_config()` can change, the ones a
+/// connection only reads once it's accepted or as it handles its requests.
+/// The rest are bound into the listeners, the accept loop or what every
+/// connection shares (the addresses and their sockets, TLS, the reactor,
+/// the callbacks, the budgets ...) and stay as the runner was made with.
+///
+pub(crate) const RUNTIME: [&str; 39] = [
+    "shutdown_timeout", "access_log", "log_raw_path", "debug", "sni_mismatch", "merge_slashes", "allowed_hosts",
+    "invalid_host_status", "server_header", "default_content_type", "max_body_size", "strict_content_length",
+    "max_drain_bytes", "read_buffer_size", "read_high_water", "idle_buffer_retain", "write_high_water",
+    "max_write_rate_bytes_per_sec", "keep_alive_timeout", "handler_timeout", "keep_alive_max_requests",
+    "keep_alive_header", "compress", "compress_min_size", "compress_types", "lenient", "max_request_line",
+    "max_method_length", "allowed_methods", "options_allow", "trusted_proxies", "allow_ips", "deny_ips",
+    "forwarded_header", "deny_403", "rate_limit", "rate_limit_burst", "trust_request_id", "cors",
+];
+
+/// The `429`s a client gets in a row before `rate_limit` starts closing its connections.
+const DEFAULT_RATE_LIMIT_BURST: u32 = 10;
+
+/// The most linux allows for each of `(idle_secs, interval_secs, probes)`.
+const MAX_KEEPALIVE: (u32, u32, u32) = (32767, 32767, 127);
+
+fn keepalive(value: &PyAny) -> PyResult<KeepAlive> {
+    let invalid = || PyValueError::new_err(format!(
+        "tcp_keepalive must be (idle_secs, interval_secs, probes) with each at least 1 and at most {:?}",
+        MAX_KEEPALIVE,
+    ));
+
+    let (idle, interval, probes): (u32, u32, u32) = value.extract().map_err(|_| invalid())?;
+    let (max_idle, max_interval, max_probes) = MAX_KEEPALIVE;
+    if !(1..=max_idle).contains(&idle) || !(1..=max_interval).contains(&interval) || !(1..=max_probes).contains(&probes) {
+        return Err(invalid())
+    }
+
+    Ok(KeepAlive { idle, interval, probes })
+}
+
+/// Seconds kept as f32 as the f64 they were given as, `0.1` rather than `0.10000000149011612`.
+fn secs(value: f32) -> f64 {
+    value.to_string().parse().unwrap_or_else(|_| value.into())
+}
@@ -0,0 +1,143 @@
+use bytes::Bytes;
+
+use std::io::{self, IoSlice, Write};
+
+
+///
+/// Outgoing is a serialized response waiting to be written, the head we
+/// built and the body as the handler gave it are kept apart so a large
+/// body goes out without first being copied in behind its head. Plain
+/// sockets are written both at once with `write_vectored`.
+///
+/// Anything added once there's a body queued goes after it, so the body is
+/// copied onto the end of the head to keep things in order. That's only a
+/// pipelined response (or a refusal) queued before the last one has gone.
+///
+#[derive(Default)]
+pub(crate) struct Outgoing {
+    head: Vec<u8>,
+    body: Bytes,
+    written: usize,     // How much of the head and then the body has made it to the socket
+}
+
+impl Outgoing {
+    /// If everything queued has been written.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.written == self.head.len() + self.body.len()
+    }
+
+    ///
+    /// The buffer to serialize more into, after anything still queued. Once
+    /// everything has been written it starts again from empty.
+    ///
+    pub(crate) fn buffer(&mut self) -> &mut Vec<u8> {
+        if self.is_empty() {
+            self.clear();
+        }
+
+        if !self.body.is_empty() {
+            self.head.extend_from_slice(&self.body);
+            self.body = Bytes::new();
+        }
+
+        &mut self.head
+    }
+
+    /// How much of what's queued goes ahead of the body, on a fresh one that's just the head.
+    pub(crate) fn head_len(&self) -> usize {
+        self.head.len()
+    }
+
+    /// Queues a body after everything else.
+    pub(crate) fn push_body(&mut self, body: Bytes) {
+        if self.is_empty() {
+            self.clear();
+        }
+
+        match self.body.is_empty() {
+            true => self.body = body,
+            false => self.buffer().extend_from_slice(&body),
+        }
+    }
+
+    /// Queues everything in another one after this, nothing of it can have been written yet.
+    pub(crate) fn append(&mut self, other: Outgoing) {
+        if self.is_empty() {
+            *self = other;
+            return
+        }
+
+        self.buffer().extend_from_slice(&other.head);
+        self.push_body(other.body);
+    }
+
+    /// Drops whatever is queued, written or not.
+    pub(crate) fn clear(&mut self) {
+        self.head.clear();
+        self.body = Bytes::new();
+        self.written = 0;
+    }
+
+    /// Frees the buffer of an idle connection if it's over `retain`, see `crate::reclaim_buffer()`.
+    pub(crate) fn reclaim(&mut self, retain: usize) -> usize {
+        if !self.is_empty() {
+            return 0
+        }
+
+        self.clear();
+        crate::reclaim_buffer(&mut self.head, retain)
+    }
+
+    pub(crate) fn shrink_to(&mut self, capacity: usize) {
+        if self.head.capacity() > capacity {
+            self.head.shrink_to(capacity);
+        }
+    }
+
+    /// The memory it's holding on to, the head's buffer and the body.
+    pub(crate) fn capacity(&self) -> usize {
+        self.head.capacity() + self.body.len()
+    }
+
+    /// How much of what's queued is still to be written.
+    pub(crate) fn remaining_len(&self) -> usize {
+        self.head.len() + self.body.len() - self.written
+    }
+
+    /// The first piece still to be written, for writers (TLS) that take one buffer at a time.
+    pub(crate) fn next(&self) -> &[u8] {
+        match self.remaining() {
+            (head, _) if !head.is_empty() => head,
+            (_, body) => body,
+        }
+    }
+
+    ///
+    /// Writes as much as `w` takes in one go up to `limit` bytes, the rest
+    /// of the head and the body together when there's some of both left.
+    /// Whatever was written has to be passed to `advance()`.
+    ///
+    pub(crate) fn write_to(&self, mut w: impl Write, limit: usize) -> io::Result<usize> {
+        let (head, body) = self.remaining();
+        let head = &head[..head.len().min(limit)];
+        let body = &body[..body.len().min(limit - head.len())];
+
+        match (head, body) {
+            (head, []) => w.write(head),
+            ([], body) => w.write(body),
+            (head, body) => w.write_vectored(&[IoSlice::new(head), IoSlice::new(body)]),
+        }
+    }
+
+    /// Marks `n` more bytes as written, they can run from the head on into the body.
+    pub(crate) fn advance(&mut self, n: usize) {
+        self.written += n;
+    }
+
+    fn remaining(&self) -> (&[u8], &[u8]) {
+        match self.written.checked_sub(self.head.len()) {
+            None => (&self.head[self.written..], &self.body),
+            Some(n) => (&[], &self.body[n..]),
+        }
+    }
+}
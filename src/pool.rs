@@ -0,0 +1,443 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+
+use rustls::ClientConfig;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::client::Connect;
+use crate::sleep::LoopSleeper;
+use crate::stream::{self, Reader, SharedTransport, Writer};
+use crate::tls::{self, ClientTls};
+
+
+/// Which connections can stand in for each other, the (host, port, tls) they were made with.
+type Key = (String, u16, bool);
+
+///
+/// ConnectionPool keeps the connections `acquire()` makes open once they
+/// are handed back with `release()`, so a handler talking to the same
+/// upstream for every request only pays for the connect (and the TLS
+/// handshake) the first time.
+///
+///     reader, writer = await pool.acquire(host, port, tls=False)
+///     ...
+///     pool.release(reader, writer)         back to the pool if it's still good, closed if not
+///     pool.close()                         closes the idle connections, any acquire() after raises
+///
+/// A connection is only kept if it's open with nothing left to send and
+/// nothing unread, a response read halfway would be what the next user
+/// reads first. Idle connections are closed by a sweep on the loop once
+/// they've been idle for `idle_timeout` (or the server closes them).
+///
+/// The server can still close an idle connection just as it's handed out,
+/// so one that comes from the pool keeps what's written to it until the
+/// server first answers and if the connection turns out to be dead it is
+/// redialled once with that sent again, the Reader and Writer carry on as
+/// if nothing happened.
+///
+///     Optional:
+///         - max_per_host:     int     (connections to a (host, port, tls) at once, idle or not, defaults to 10)
+///         - idle_timeout:     float   (seconds a connection is kept idle, defaults to 60)
+///         - connect_timeout:  float   (seconds for a connect and its TLS handshake, none by default)
+///         - ca_file:          str     (PEM roots to verify `tls=True` servers against, the system's CA bundle by default)
+///
+///     Always release() what you acquire, even a connection you've closed,
+///     a connection that's never released only gives its slot back once
+///     it's closed.
+///
+#[pyclass]
+pub struct ConnectionPool {
+    hosts: HashMap<Key, Host>,
+    max_per_host: usize,
+    idle_timeout: Duration,
+    connect_timeout: Option<Duration>,
+    ca_file: Option<String>,
+    tls: Option<Arc<ClientConfig>>,     // Built the first time a `tls=True` connection is asked for
+    sweeping: bool,                     // If a PoolSweep is scheduled
+    closed: bool,
+}
+
+///
+/// Host is the connections to one (host, port, tls), the ones handed out
+/// are only kept to tell they're ours when they come back and to count
+/// them against `max_per_host`.
+///
+#[derive(Default)]
+struct Host {
+    idle: Vec<(SharedTransport, Instant)>,          // Most recently released last
+    in_use: Vec<SharedTransport>,
+    dialing: usize,                                 // Connects underway for an acquire()
+}
+
+impl Host {
+    /// How many connections count against `max_per_host`, forgetting the ones handed out that have since closed.
+    fn open(&mut self) -> usize {
+        self.in_use.retain(|transport| stream::lock(transport).is_ok_and(|t| !t.is_closed()));
+        self.idle.len() + self.in_use.len() + self.dialing
+    }
+}
+
+#[pymethods]
+impl ConnectionPool {
+    #[new]
+    #[args(max_per_host = "10", idle_timeout = "60.0", connect_timeout = "None", ca_file = "None")]
+    fn new(max_per_host: usize, idle_timeout: f64, connect_timeout: Option<f64>, ca_file: Option<String>) -> PyResult<Self> {
+        if max_per_host == 0 {
+            return Err(PyValueError::new_err("max_per_host must be at least 1"))
+        }
+
+        let seconds = |value: f64, name: &str| match value.is_finite() && value > 0.0 {
+            true => Ok(Duration::from_secs_f64(value)),
+            false => Err(PyValueError::new_err(format!("{} must be a positive number of seconds", name))),
+        };
+
+        let idle_timeout = seconds(idle_timeout, "idle_timeout")?;
+        let connect_timeout = connect_timeout.map(|value| seconds(value, "connect_timeout")).transpose()?;
+
+        // a bad ca_file should fail here rather than on the first acquire()
+        let tls = match ca_file.as_deref() {
+            Some(ca_file) => Some(tls::client_config(Some(ca_file))?),
+            None => None,
+        };
+
+        Ok(Self {
+            hosts: HashMap::new(),
+            max_per_host,
+            idle_timeout,
+            connect_timeout,
+            ca_file,
+            tls,
+            sweeping: false,
+            closed: false,
+        })
+    }
+
+    ///
+    /// PythonMethod: ConnectionPool.acquire(host, port, tls=False) -> awaitable (Reader, Writer)
+    ///
+    ///     An idle connection to `host` if there is one that's still good,
+    ///     otherwise a new one made the way `connect()` makes them (with
+    ///     TLS on top for `tls=True`, verifying the server is `host`). Once
+    ///     there are `max_per_host` connections it waits for one to be
+    ///     released.
+    ///
+    #[args(tls = "false")]
+    fn acquire(mut slf: PyRefMut<Self>, host: &str, port: u16, tls: bool) -> PyResult<Acquire> {
+        if slf.closed {
+            return Err(PyRuntimeError::new_err("the pool is closed"))
+        }
+
+        let client_tls = match tls {
+            true => {
+                if slf.tls.is_none() {
+                    slf.tls = Some(tls::client_config(slf.ca_file.as_deref())?);
+                }
+
+                Some(ClientTls::new(slf.tls.clone().unwrap(), host)?)
+            },
+            false => None,
+        };
+
+        Ok(Acquire {
+            pool: slf.into(),
+            key: (host.to_ascii_lowercase(), port, tls),
+            tls: client_tls,
+            sleeper: None,
+            dialing: None,
+        })
+    }
+
+    ///
+    /// PythonMethod: ConnectionPool.release(reader, writer) -> bool
+    ///
+    ///     Hands a connection from `acquire()` back, `True` if it was kept
+    ///     for the next acquire and `False` if it was closed instead (it
+    ///     had been closed already, had unread or unsent data or the pool
+    ///     is closed).
+    ///
+    fn release(mut slf: PyRefMut<Self>, py: Python, reader: PyRef<Reader>, writer: PyRef<Writer>) -> PyResult<bool> {
+        let transport = writer.transport().clone();
+        if !Arc::ptr_eq(&transport, reader.transport()) {
+            return Err(PyValueError::new_err("reader and writer aren't the same connection"))
+        }
+
+        let key = slf.hosts
+            .iter_mut()
+            .find_map(|(key, host)| {
+                let i = host.in_use.iter().position(|t| Arc::ptr_eq(t, &transport))?;
+                host.in_use.swap_remove(i);
+                Some(key.clone())
+            })
+            .ok_or_else(|| PyValueError::new_err("the connection isn't one this pool handed out"))?;
+
+        let kept = {
+            let mut guard = stream::lock(&transport)?;
+            let transport = &mut *guard;
+            let reusable = !slf.closed && py.allow_threads(|| transport.is_reusable());
+
+            // a writer being closed is left to finish sending what it has
+            if !reusable && !transport.is_closing() {
+                transport.close();
+            }
+
+            reusable
+        };
+
+        if kept {
+            slf.hosts.entry(key).or_default().idle.push((transport, Instant::now()));
+            if !slf.sweeping {
+                let delay = slf.idle_timeout;
+                slf.sweeping = true;
+                schedule_sweep(py, slf.into(), delay)?;
+            }
+        }
+
+        Ok(kept)
+    }
+
+    ///
+    /// PythonMethod: ConnectionPool.close()
+    ///
+    ///     Closes every idle connection, the ones handed out are closed as
+    ///     they're released and acquire() raises from now on.
+    ///
+    fn close(&mut self) -> PyResult<()> {
+        self.closed = true;
+        for host in self.hosts.values_mut() {
+            for (transport, _) in host.idle.drain(..) {
+                stream::lock(&transport)?.close();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ConnectionPool {
+    ///
+    /// Internal Method: ConnectionPool::take_idle() -> PyResult<Option<SharedTransport>>
+    ///
+    ///     The most recently released connection for `key` that's still
+    ///     good, the ones that have gone bad while idle are closed on the
+    ///     way.
+    ///
+    fn take_idle(&mut self, py: Python, key: &Key) -> PyResult<Option<SharedTransport>> {
+        let host = match self.hosts.get_mut(key) {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+
+        while let Some((transport, _)) = host.idle.pop() {
+            let mut guard = stream::lock(&transport)?;
+            let inner = &mut *guard;
+            if py.allow_threads(|| inner.is_reusable()) {
+                drop(guard);
+                return Ok(Some(transport))
+            }
+
+            guard.close();
+        }
+
+        Ok(None)
+    }
+
+    ///
+    /// Internal Method: ConnectionPool::sweep() -> PyResult<Option<Duration>>
+    ///
+    ///     Closes the connections that have been idle for `idle_timeout` or
+    ///     have gone bad, how long until the next one is due if any are
+    ///     left idle.
+    ///
+    fn sweep(&mut self, py: Python) -> PyResult<Option<Duration>> {
+        let now = Instant::now();
+        let mut next: Option<Duration> = None;
+
+        for host in self.hosts.values_mut() {
+            let mut i = 0;
+            while i < host.idle.len() {
+                let (transport, since) = &host.idle[i];
+                let expires = *since + self.idle_timeout;
+
+                let mut guard = stream::lock(transport)?;
+                let inner = &mut *guard;
+                if now < expires && py.allow_threads(|| inner.is_reusable()) {
+                    let due = expires - now;
+                    next = Some(next.map_or(due, |next| next.min(due)));
+                    i += 1;
+                    continue
+                }
+
+                guard.close();
+                drop(guard);
+                host.idle.remove(i);
+            }
+        }
+
+        self.hosts.retain(|_, host| host.open() > 0);
+        Ok(next)
+    }
+}
+
+fn schedule_sweep(py: Python, pool: Py<ConnectionPool>, delay: Duration) -> PyResult<()> {
+    let sweep = Py::new(py, PoolSweep { pool })?;
+    crate::get_loop(py)?.call_method1("call_later", (delay.as_secs_f64(), sweep))?;
+    Ok(())
+}
+
+///
+/// PoolSweep is the `call_later` callback closing idle connections once
+/// they've been idle for `idle_timeout`, it schedules itself again for the
+/// next one due while there are any left idle.
+///
+#[pyclass]
+struct PoolSweep {
+    pool: Py<ConnectionPool>,
+}
+
+#[pymethods]
+impl PoolSweep {
+    #[call]
+    fn __call__(&self, py: Python) -> PyResult<()> {
+        let mut pool = self.pool.try_borrow_mut(py)?;
+        let next = match pool.closed {
+            true => None,
+            false => pool.sweep(py)?,
+        };
+
+        pool.sweeping = next.is_some();
+        drop(pool);
+
+        match next {
+            Some(delay) => schedule_sweep(py, self.pool.clone_ref(py), delay),
+            None => Ok(()),
+        }
+    }
+}
+
+
+///
+/// Acquire is the awaitable `acquire()` gives back, each step hands out an
+/// idle connection if there is one, starts a connect if there's room for
+/// another or sleeps on the loop until something is released. A connect
+/// that's underway holds its place against `max_per_host` until it's done
+/// or the awaitable is dropped (the task awaiting it was cancelled).
+///
+#[pyclass]
+pub struct Acquire {
+    pool: Py<ConnectionPool>,
+    key: Key,
+    tls: Option<ClientTls>,
+    sleeper: Option<LoopSleeper>,       // From the first step, while we wait for room or the connect
+    dialing: Option<Connect>,           // The new connection once there was room for it
+}
+
+impl Acquire {
+    ///
+    /// Internal Method: Acquire::poll() -> PyResult<Option<SharedTransport>>
+    ///
+    ///     One step of the acquire, the connection once we have one or
+    ///     `None` to sleep first.
+    ///
+    fn poll(&mut self, py: Python) -> PyResult<Option<SharedTransport>> {
+        let mut pool = self.pool.try_borrow_mut(py)?;
+
+        if let Some(connect) = self.dialing.as_mut() {
+            let connected = connect.poll(py);
+            if let Ok(None) = connected {
+                return Ok(None)
+            }
+
+            self.dialing = None;
+            let host = pool.hosts.entry(self.key.clone()).or_default();
+            host.dialing -= 1;
+
+            let transport = connected?.unwrap();
+            host.in_use.push(transport.clone());
+            return Ok(Some(transport))
+        }
+
+        if pool.closed {
+            return Err(PyRuntimeError::new_err("the pool is closed"))
+        }
+
+        if let Some(transport) = pool.take_idle(py, &self.key)? {
+            stream::lock(&transport)?.arm_redial(self.tls.clone());
+            pool.hosts.entry(self.key.clone()).or_default().in_use.push(transport.clone());
+            return Ok(Some(transport))
+        }
+
+        let (max_per_host, timeout) = (pool.max_per_host, pool.connect_timeout);
+        let host = pool.hosts.entry(self.key.clone()).or_default();
+        if host.open() >= max_per_host {
+            return Ok(None)
+        }
+
+        host.dialing += 1;
+        let (name, port, _) = &self.key;
+        self.dialing = Some(Connect::new(name, *port, timeout, self.tls.clone()));
+        drop(pool);
+
+        self.poll(py)
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if self.dialing.is_none() {
+            return
+        }
+
+        // the connect never finished, its place is given back
+        Python::with_gil(|py| {
+            if let Ok(mut pool) = self.pool.try_borrow_mut(py) {
+                if let Some(host) = pool.hosts.get_mut(&self.key) {
+                    host.dialing -= 1;
+                }
+            }
+        });
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for Acquire {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for Acquire {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+        let this = &mut *slf;
+
+        if this.sleeper.is_none() {
+            let loop_ = crate::get_loop(py)?;
+            this.sleeper = Some(LoopSleeper::for_connection(loop_.into()));
+        }
+
+        let transport = this.poll(py)?;
+        let sleeper = this.sleeper.as_mut().unwrap();
+        let transport = match transport {
+            Some(transport) => transport,
+            None => return Ok(IterNextOutput::Yield(sleeper._iter_sleep(py))),
+        };
+
+        let loop_ = sleeper.loop_.clone_ref(py);
+        let reader = Py::new(py, Reader::new(transport.clone(), loop_.clone_ref(py)))?;
+        let writer = Py::new(py, Writer::new(transport, loop_, stream::DEFAULT_HIGH_WATER)?)?;
+
+        Ok(IterNextOutput::Return(Some((reader, writer).into_py(py))))
+    }
+}
@@ -0,0 +1,127 @@
+use pyo3::prelude::*;
+
+use std::io::{self, Read};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::forwarded;
+use crate::headers::Headers;
+use crate::http::{self, HTTPRequest, RequestHead};
+use crate::options::RunnerOptions;
+
+
+/// The default `max_drain_bytes`, the most of an unwanted body we'll read and throw away to keep a connection going.
+pub(crate) const DEFAULT_DRAIN_LIMIT: usize = 64 * 1024;
+
+/// The most of a refused body we'll read past before closing anyway, and for how long.
+const LINGER_LIMIT: usize = 1024 * 1024;
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+
+///
+/// RequestMeta is what the `on_headers` hook is given, everything about a
+/// request that's known from its head before any of the body has been
+/// read. Setting `max_body_size` changes the limit for just this request,
+/// e.g. letting an authenticated client upload more than everyone else.
+///
+#[pyclass]
+pub(crate) struct RequestMeta {
+    #[pyo3(get)]
+    method: String,
+
+    /// The percent-decoded path, the same as `request.path` will be.
+    #[pyo3(get)]
+    path: String,
+
+    #[pyo3(get)]
+    raw_query: String,
+
+    #[pyo3(get)]
+    protocol: String,
+
+    #[pyo3(get)]
+    headers: Headers,
+
+    #[pyo3(get)]
+    client: Option<(String, u16)>,
+
+    #[pyo3(get)]
+    remote_addr: Option<String>,
+
+    /// The `request.id` the request will have.
+    #[pyo3(get)]
+    id: String,
+
+    /// The `Content-Length` the client sent, `None` without one (or for a chunked body).
+    #[pyo3(get)]
+    content_length: Option<usize>,
+
+    /// The largest body this request can send, the runner's `max_body_size` to start with.
+    #[pyo3(get, set)]
+    pub(crate) max_body_size: usize,
+}
+
+impl RequestMeta {
+    pub(crate) fn new(head: &RequestHead, options: &RunnerOptions, client: Option<(String, u16)>, id: String) -> Self {
+        let mut request = HTTPRequest::new(
+            head.method.clone(),
+            head.target.clone(),
+            head.protocol.clone(),
+            head.headers.clone(),
+            Vec::new().into(),
+        );
+
+        // a path that tries to get above the root is still refused once the
+        // body's in, the hook just sees it as it was sent until then
+        let _ = request.normalize(options.merge_slashes);
+
+        Self {
+            remote_addr: forwarded::remote_addr(&options.trusted_proxies, options.forwarded_header, client.as_ref(), &head.headers),
+            content_length: http::content_length(&head.headers).and_then(Result::ok),
+            method: request.method,
+            path: request.path,
+            raw_query: request.raw_query,
+            protocol: request.protocol,
+            headers: request.headers,
+            client,
+            id,
+            max_body_size: options.max_body_size,
+        }
+    }
+}
+
+
+///
+/// Linger is the close after a response to a request whose body we never
+/// read. Closing with it still coming in resets the connection, which can
+/// throw the response away before the client has read it, so we shut our
+/// side down and read and throw away up to `LINGER_LIMIT` more first.
+///
+#[derive(Default)]
+pub(crate) struct Linger {
+    read: usize,
+    deadline: Option<Instant>,  // Set once our side has been shut down
+}
+
+impl Linger {
+    /// Moves the drain along, true once the socket can be closed.
+    pub(crate) fn drain(&mut self, sock: &TcpStream) -> bool {
+        let deadline = *self.deadline.get_or_insert_with(|| {
+            let _ = sock.shutdown(Shutdown::Write);
+            Instant::now() + LINGER_TIMEOUT
+        });
+
+        let mut scratch = [0; 8192];
+        while self.read < LINGER_LIMIT {
+            match (&*sock).read(&mut scratch) {
+                Ok(0) => return true,
+                Ok(n) => self.read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Instant::now() >= deadline,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => return true,
+            }
+        }
+
+        true
+    }
+}
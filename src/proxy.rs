@@ -0,0 +1,158 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bstr::ByteSlice;
+
+
+/// Every v2 header starts with this.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Every v1 header starts with this.
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// The longest a v1 header can be, CRLF included.
+const V1_MAX: usize = 107;
+
+/// How much we look at to begin with, enough for any v1 header and a v2 one without TLVs.
+pub(crate) const PEEK_SIZE: usize = 232;
+
+
+///
+/// ProxyHeader is what a load balancer told us about a connection with
+/// the PROXY protocol, `source` is the real client or `None` when the
+/// header doesn't say (a health check, `UNKNOWN`, a unix socket...) in
+/// which case the connection's own peer stands.
+///
+pub(crate) struct ProxyHeader {
+    pub(crate) family: &'static str,    // "TCP4", "TCP6", "UDP4", "UDP6", "UNIX", "UNKNOWN" or "LOCAL"
+    pub(crate) source: Option<SocketAddr>,
+}
+
+///
+/// How far `parse()` got, `Incomplete` gives how many bytes it needs to
+/// see at least before it can say more.
+///
+pub(crate) enum Parsed {
+    Incomplete(usize),
+    Done(ProxyHeader, usize),   // The header and how long it was
+}
+
+///
+/// Internal Method: proxy::parse() -> Result<Parsed, ()>
+///
+///     Parses a v1 (text) or v2 (binary) PROXY protocol header from the
+///     start of what a connection has sent, `Err` if it isn't one (or is a
+///     malformed one) which means the connection should be dropped.
+///
+pub(crate) fn parse(buf: &[u8]) -> Result<Parsed, ()> {
+    let starts = |prefix: &[u8]| buf[..buf.len().min(prefix.len())] == prefix[..buf.len().min(prefix.len())];
+
+    if starts(V2_SIGNATURE) {
+        return parse_v2(buf)
+    }
+
+    if starts(V1_PREFIX) {
+        return parse_v1(buf)
+    }
+
+    Err(())
+}
+
+/// `PROXY TCP4 <src> <dst> <src port> <dst port>\r\n` or `PROXY UNKNOWN ...\r\n`.
+fn parse_v1(buf: &[u8]) -> Result<Parsed, ()> {
+    let end = match buf.find(b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX => end,
+        Some(_) => return Err(()),
+        None if buf.len() >= V1_MAX => return Err(()),
+        None => return Ok(Parsed::Incomplete(buf.len() + 1)),
+    };
+
+    let line = buf[V1_PREFIX.len()..end].to_str().map_err(|_| ())?;
+    let mut parts = line.split(' ');
+    let family = parts.next().ok_or(())?;
+    if family == "UNKNOWN" {
+        let header = ProxyHeader { family: "UNKNOWN", source: None };
+        return Ok(Parsed::Done(header, end + 2))
+    }
+
+    let (source, _destination, source_port, _destination_port) = match (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(source), Some(destination), Some(source_port), Some(destination_port), None) => {
+            (source, destination, port(source_port)?, port(destination_port)?)
+        },
+        _ => return Err(()),
+    };
+
+    let (family, ip) = match family {
+        "TCP4" => ("TCP4", IpAddr::V4(source.parse().map_err(|_| ())?)),
+        "TCP6" => ("TCP6", IpAddr::V6(source.parse().map_err(|_| ())?)),
+        _ => return Err(()),
+    };
+
+    let header = ProxyHeader { family, source: Some(SocketAddr::new(ip, source_port)) };
+    Ok(Parsed::Done(header, end + 2))
+}
+
+/// A decimal port, just digits (`"+80"` would get past `str::parse`).
+fn port(value: &str) -> Result<u16, ()> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(())
+    }
+
+    value.parse().map_err(|_| ())
+}
+
+///
+/// The 16 byte fixed part (signature, version and command, family and
+/// transport, length) followed by the addresses and any TLVs which we
+/// skip over.
+///
+fn parse_v2(buf: &[u8]) -> Result<Parsed, ()> {
+    if buf.len() < 16 {
+        return Ok(Parsed::Incomplete(16))
+    }
+
+    let length = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < length {
+        return Ok(Parsed::Incomplete(length))
+    }
+
+    let (version, command) = (buf[12] >> 4, buf[12] & 0x0f);
+    if version != 2 {
+        return Err(())
+    }
+
+    let addresses = &buf[16..length];
+    let header = match (command, buf[13] >> 4, buf[13] & 0x0f) {
+        // LOCAL is the proxy talking to us itself (e.g. a health check)
+        (0, _, _) => ProxyHeader { family: "LOCAL", source: None },
+        (1, 0, _) => ProxyHeader { family: "UNKNOWN", source: None },
+        (1, 1, transport) => {
+            if addresses.len() < 12 {
+                return Err(())
+            }
+
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let source_port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            ProxyHeader {
+                family: if transport == 2 { "UDP4" } else { "TCP4" },
+                source: Some(SocketAddr::new(IpAddr::V4(ip), source_port)),
+            }
+        },
+        (1, 2, transport) => {
+            if addresses.len() < 36 {
+                return Err(())
+            }
+
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let source_port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            ProxyHeader {
+                family: if transport == 2 { "UDP6" } else { "TCP6" },
+                source: Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), source_port)),
+            }
+        },
+        (1, 3, _) => ProxyHeader { family: "UNIX", source: None },
+        _ => return Err(()),
+    };
+
+    Ok(Parsed::Done(header, length))
+}
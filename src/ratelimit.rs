@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::forwarded;
+use crate::headers::Headers;
+use crate::http::HTTPResponse;
+use crate::options::RunnerOptions;
+
+
+/// How often buckets that have refilled are dropped from the map.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+
+///
+/// RateLimiter is `rate_limit`, a token bucket per client address. Every
+/// request takes a token and a client that's run out is answered with a
+/// `429` until its bucket refills at `rate` tokens a second, up to
+/// `capacity`. A client that keeps going past `burst` refused requests in a
+/// row has its connections closed on the next one instead of answered.
+///
+/// Clients are told apart by `request.remote_addr` so clients behind one of
+/// the `trusted_proxies` get a bucket each. A bucket that has filled back up
+/// is no different to a new one, those are swept out every so often.
+///
+pub(crate) struct RateLimiter {
+    capacity: f64,
+    rate: f64,
+    burst: u32,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    swept: Instant,             // When the full buckets were last dropped
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,           // When `tokens` was last topped up
+    refused: u32,               // Requests refused in a row
+}
+
+/// What a client's request gets.
+pub(crate) enum Verdict {
+    Allowed,
+    Limited(u64),       // A `429`, with the seconds until a token comes back
+    Exceeded(u64),      // The same but the connection gets closed too
+}
+
+impl RateLimiter {
+    pub(crate) fn new(capacity: u32, rate: f64, burst: u32) -> Self {
+        Self {
+            capacity: f64::from(capacity),
+            rate,
+            burst,
+            buckets: Mutex::new(Buckets { clients: HashMap::new(), swept: Instant::now() }),
+        }
+    }
+
+    /// `(capacity, rate, burst)` as it was made with.
+    pub(crate) fn limits(&self) -> (u32, f64, u32) {
+        (self.capacity as u32, self.rate, self.burst)
+    }
+
+    /// Takes a token from `ip`'s bucket if there's one to take.
+    pub(crate) fn take(&self, ip: IpAddr) -> Verdict {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.swept) >= SWEEP_INTERVAL {
+            buckets.swept = now;
+            buckets.clients.retain(|_, bucket| self.refill(bucket, now) < self.capacity);
+        }
+
+        let bucket = buckets.clients.entry(ip).or_insert(Bucket { tokens: self.capacity, updated: now, refused: 0 });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.refused = 0;
+            return Verdict::Allowed
+        }
+
+        let retry_after = ((1.0 - bucket.tokens) / self.rate).ceil().max(1.0) as u64;
+        bucket.refused = bucket.refused.saturating_add(1);
+        match bucket.refused > self.burst {
+            true => Verdict::Exceeded(retry_after),
+            false => Verdict::Limited(retry_after),
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.capacity)
+    }
+}
+
+///
+/// Internal Method: ratelimit::check() -> Verdict
+///
+///     Whether a request whose head has just arrived can go ahead, always
+///     `Allowed` when `rate_limit` is off or we can't tell who the client is.
+///
+pub(crate) fn check(options: &RunnerOptions, client: Option<&(String, u16)>, headers: &Headers) -> Verdict {
+    let limiter = match options.rate_limit.as_ref() {
+        Some(limiter) => limiter,
+        None => return Verdict::Allowed,
+    };
+
+    let ip = forwarded::remote_addr(&options.trusted_proxies, options.forwarded_header, client, headers).and_then(|addr| addr.parse().ok());
+    match ip {
+        Some(ip) => limiter.take(ip),
+        None => Verdict::Allowed,
+    }
+}
+
+/// The `429` for a refused request.
+pub(crate) fn too_many_requests(retry_after: u64) -> HTTPResponse {
+    let headers = vec![(String::from("Retry-After"), retry_after.to_string())];
+    HTTPResponse::from_parts(429, headers, Vec::new())
+}
@@ -0,0 +1,1067 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::PyTypeError;
+use pyo3::types::PyDict;
+
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use crate::body::BodyStream;
+use crate::compress::{self, Encoding};
+use crate::file::{Conditional, FileResponse};
+use crate::forwarded;
+use crate::headers::{HeaderName, Headers};
+use crate::http::{self, BodyFraming, ChunkError, ChunkedDecoder, DateCache, HTTPRequest, HTTPResponse, HeadError, RequestHead, RequestLease};
+use crate::listener::AcceptPause;
+use crate::log;
+use crate::options::RunnerOptions;
+use crate::outgoing::Outgoing;
+use crate::ratelimit::{self, Verdict};
+use crate::request_id;
+use crate::sse::EventSourceResponse;
+use crate::upgrade::Upgrade;
+use crate::stats::{ActiveGuard, ServerStats};
+
+
+/// The epoll tokens for the wakeup eventfd and the listeners (counting down), connections count up from 0.
+const WAKE: u64 = u64::MAX;
+const FIRST_LISTENER: u64 = u64::MAX - 1;
+
+/// How many epoll events we take per wait.
+const MAX_EVENTS: usize = 256;
+
+/// How often (in ms) idle connections are checked against `keep_alive_timeout`.
+const IDLE_CHECK_INTERVAL: i32 = 100;
+
+
+///
+/// Everything the reactor thread needs from the runner to handle requests
+/// the same way a OnceFuture would.
+///
+pub(crate) struct Context {
+    pub(crate) callback: PyObject,
+    pub(crate) loop_: PyObject,
+    pub(crate) options: Arc<RunnerOptions>,
+    pub(crate) date: Arc<DateCache>,
+    pub(crate) stats: Arc<ServerStats>,
+}
+
+///
+/// NativeReactor is the `reactor="native"` mode, a dedicated thread runs an
+/// epoll loop over the listener and every connection and does all the
+/// accepting, reading, parsing and writing without the GIL. Python is only
+/// involved once a request is complete, the callback is scheduled on the
+/// event loop with `call_soon_threadsafe` and its response comes back to
+/// the thread already serialized.
+///
+/// Connections handled this way aren't asyncio tasks so they don't show up
+/// in `connections()`, and there's no access log for them yet. Responses
+/// go back as a single buffer so a handler can't stream its body here.
+///
+pub(crate) struct NativeReactor {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NativeReactor {
+    ///
+    /// Internal Method: NativeReactor::spawn() -> io::Result<Self>
+    ///
+    ///     Starts the reactor thread accepting on `listeners`, the runner
+    ///     keeps its own handles on the sockets for `local_addr()`.
+    ///
+    pub(crate) fn spawn(listeners: Vec<TcpListener>, ctx: Context) -> io::Result<Self> {
+        let shared = Arc::new(Shared::new()?);
+        let epoll = Epoll::new()?;
+        epoll.ctl(libc::EPOLL_CTL_ADD, shared.wake, libc::EPOLLIN as u32, WAKE)?;
+
+        for (index, listener) in listeners.iter().enumerate() {
+            listener.set_nonblocking(true)?;
+            epoll.ctl(libc::EPOLL_CTL_ADD, listener.as_raw_fd(), libc::EPOLLIN as u32, FIRST_LISTENER - index as u64)?;
+        }
+
+        let worker = Worker {
+            epoll,
+            listeners,
+            pause: ctx.options.accept_pause(),
+            paused: false,
+            shared: shared.clone(),
+            ctx: Arc::new(ctx),
+            connections: HashMap::new(),
+            next_id: 0,
+            ready: Vec::new(),
+        };
+
+        let thread = std::thread::Builder::new()
+            .name(String::from("async-rust-reactor"))
+            .spawn(move || worker.run())?;
+
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    ///
+    /// Stops the thread and waits for it, every connection it still has is
+    /// closed. The GIL is released while we wait since the thread might
+    /// need it to finish handing off a request.
+    ///
+    pub(crate) fn stop(&mut self, py: Python) {
+        self.shared.stopping.store(true, Ordering::Release);
+        self.shared.wake();
+
+        if let Some(thread) = self.thread.take() {
+            py.allow_threads(|| {
+                let _ = thread.join();
+            });
+        }
+    }
+}
+
+impl Drop for NativeReactor {
+    fn drop(&mut self) {
+        // we can't wait for it without the GIL in hand so it just gets told to stop
+        self.shared.stopping.store(true, Ordering::Release);
+        self.shared.wake();
+    }
+}
+
+
+///
+/// The half of the reactor python can see, finished responses are pushed
+/// onto `completed` and the eventfd written to wake the thread for them.
+///
+struct Shared {
+    wake: RawFd,                        // The eventfd the reactor thread waits on alongside the sockets
+    stopping: AtomicBool,
+    completed: Mutex<Vec<Completion>>,  // Responses waiting for the reactor to write them
+}
+
+/// A serialized response for connection `id`, an empty one closes it.
+struct Completion {
+    id: u64,
+    response: Outgoing,
+    keep_alive: bool,
+}
+
+impl Shared {
+    fn new() -> io::Result<Self> {
+        let wake = cvt(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+
+        Ok(Self {
+            wake,
+            stopping: AtomicBool::new(false),
+            completed: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn complete(&self, completion: Completion) {
+        let mut completed = self.completed.lock().unwrap_or_else(|e| e.into_inner());
+        completed.push(completion);
+
+        // anything already waiting means the reactor has been woken for it
+        if completed.len() == 1 {
+            self.wake();
+        }
+    }
+
+    fn wake(&self) {
+        let one: u64 = 1;
+        unsafe {
+            libc::write(self.wake, &one as *const u64 as *const libc::c_void, 8);
+        }
+    }
+
+    /// Resets the eventfd and takes whatever responses have come in.
+    fn take_completed(&self) -> Vec<Completion> {
+        let mut count: u64 = 0;
+        unsafe {
+            libc::read(self.wake, &mut count as *mut u64 as *mut libc::c_void, 8);
+        }
+
+        std::mem::take(&mut *self.completed.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.wake);
+        }
+    }
+}
+
+
+/// A thin wrapper over an epoll instance, closed when dropped.
+struct Epoll(RawFd);
+
+impl Epoll {
+    fn new() -> io::Result<Self> {
+        cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) }).map(Self)
+    }
+
+    fn ctl(&self, op: libc::c_int, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: token };
+        cvt(unsafe { libc::epoll_ctl(self.0, op, fd, &mut event) })?;
+        Ok(())
+    }
+
+    /// Blocks until something is ready or `timeout` ms pass (-1 waits forever), being interrupted counts as nothing.
+    fn wait(&self, events: &mut [libc::epoll_event], timeout: libc::c_int) -> io::Result<usize> {
+        let n = unsafe { libc::epoll_wait(self.0, events.as_mut_ptr(), events.len() as libc::c_int, timeout) };
+
+        match n {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => Ok(0),
+            -1 => Err(io::Error::last_os_error()),
+            n => Ok(n as usize),
+        }
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+fn cvt(res: libc::c_int) -> io::Result<libc::c_int> {
+    match res {
+        -1 => Err(io::Error::last_os_error()),
+        res => Ok(res),
+    }
+}
+
+
+///
+/// Connection is one client on the reactor thread, only one request at a
+/// time is with python, anything pipelined behind it waits in `buffer`.
+///
+struct Connection {
+    sock: TcpStream,
+    client: Option<(String, u16)>,      // The peer's (host, port) if we could get it
+    server: Option<(String, u16)>,      // Our end's (host, port)
+    buffer: Vec<u8>,                    // Bytes read off the socket but not yet parsed
+    request: Option<PartialRequest>,    // The request whose head we've seen while its body arrives
+    out: Outgoing,                      // Responses waiting to be written
+    version: (u8, u8),                  // The HTTP version of the request being handled
+    request_id: Option<String>,         // The `request.id` of the request being handled, once its head has arrived
+    keep_alive: bool,                   // If we go back to reading another request after this one
+    requests: u64,                      // Requests read on the connection so far, for `keep_alive_max_requests`
+    idle_since: Option<Instant>,        // When we started waiting on the next request, for `keep_alive_timeout`
+    in_flight: bool,                    // Set while python has the request
+    eof: bool,                          // The client has stopped sending
+    closing: bool,                      // Close once `out` has been written
+    interest: u32,                      // The epoll events we're registered for
+    context: Option<Py<PyDict>>,        // The `request.connection` dict, made when the first request goes to python
+    _active: ActiveGuard,               // Keeps us counted as an active connection
+}
+
+/// A request head that has been checked, waiting on its body.
+struct PartialRequest {
+    head: Result<RequestHead, u16>,     // The checked head, or the status to refuse the request with
+    reason: Option<&'static str>,       // Why a head that couldn't be parsed was refused, the response says
+    retry_after: Option<u64>,           // Set when `rate_limit` refuses the request
+    end: usize,                         // Where the head ends in the buffer
+    body_len: usize,                    // How much body follows the head, or has been decoded if chunked
+    chunked: Option<ChunkedDecoder>,
+    received: Instant,                  // When the head arrived, for `request.elapsed()`
+}
+
+/// Where parsing the buffer got to.
+enum Parsed {
+    Partial,
+    Refused(u16, Option<&'static str>),     // The status and maybe why
+    Limited(u64, bool),                     // A `429` with its `Retry-After`, and if the connection closes after
+    Ready(Box<HTTPRequest>, bool),
+}
+
+impl Connection {
+    /// The events we want given what the connection is waiting on.
+    fn wanted(&self, options: &RunnerOptions) -> u32 {
+        if !self.out.is_empty() {
+            return libc::EPOLLOUT as u32
+        }
+
+        match self.in_flight || self.eof || self.read_size(options) == 0 {
+            true => 0,
+            false => (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+        }
+    }
+
+    /// How much the next read can take, see `crate::read_size`.
+    fn read_size(&self, options: &RunnerOptions) -> usize {
+        let request_end = match &self.request {
+            Some(PartialRequest { chunked: Some(_), .. }) => None,
+            Some(request) => Some(request.end + request.body_len),
+            None if self.in_flight => Some(0),
+            None => Some(crate::MAX_HEAD_SIZE),
+        };
+
+        crate::read_size(options, self.buffer.len(), request_end)
+    }
+
+    ///
+    /// Reads what the socket has, `Err` if the connection is broken. We
+    /// stop once `read_high_water` is buffered past the request being
+    /// parsed and only go back for more once it's been handled.
+    ///
+    fn read(&mut self, options: &RunnerOptions) -> io::Result<()> {
+        loop {
+            let max = self.read_size(options);
+            if max == 0 {
+                return Ok(())
+            }
+
+            let sock = &self.sock;
+            match crate::read_into(&mut self.buffer, max, |buf| (&*sock).read(buf)) {
+                Ok(0) => {
+                    self.eof = true;
+                    return Ok(())
+                },
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Writes as much of `out` as the socket takes.
+    fn flush(&mut self, stats: &ServerStats) -> io::Result<()> {
+        while !self.out.is_empty() {
+            match self.out.write_to(&self.sock, usize::MAX) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.out.advance(n);
+                    stats.written(n as u64);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.out.clear();
+        self.out.shrink_to(crate::MAX_RETAINED_BUFFER);
+
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: Connection::parse() -> Parsed
+    ///
+    ///     The same checks OnceFuture::read_request makes, the head is
+    ///     checked as soon as it's all arrived and then we wait for however
+    ///     much body it says follows.
+    ///
+    fn parse(&mut self, options: &RunnerOptions, stats: &ServerStats) -> Parsed {
+        if self.request.is_none() {
+            let (parsed, end) = match http::parse_head(&self.buffer, options) {
+                Ok(Some((head, end))) => (Ok(head), end),
+                Ok(None) => return Parsed::Partial,
+                Err(e) => (Err(e), self.buffer.len()),
+            };
+
+            let mut request = PartialRequest {
+                head: Err(400),
+                reason: None,
+                retry_after: None,
+                end,
+                body_len: 0,
+                chunked: None,
+                received: Instant::now(),
+            };
+            request.head = self.check(parsed, options, stats, &mut request);
+            self.request = Some(request);
+        }
+
+        let request = self.request.as_mut().unwrap();
+        match (&request.head, request.retry_after) {
+            (Err(429), Some(retry_after)) => return Parsed::Limited(retry_after, true),
+            (Err(status), _) => return Parsed::Refused(*status, request.reason),
+            _ => {},
+        }
+
+        let end = request.end;
+        match request.chunked.as_mut() {
+            Some(decoder) => {
+                match decoder.feed(&self.buffer[end + request.body_len..]) {
+                    Ok(used) => request.body_len += used,
+                    Err(ChunkError::TooLarge) => return Parsed::Refused(413, None),
+                    Err(ChunkError::Invalid) => return Parsed::Refused(400, Some("malformed chunked body")),
+                }
+
+                if !decoder.is_done() {
+                    return Parsed::Partial
+                }
+            },
+            None if self.buffer.len() < end + request.body_len => return Parsed::Partial,
+            None => {},
+        }
+
+        let request = self.request.take().unwrap();
+        if let Some(retry_after) = request.retry_after {
+            self.buffer.drain(..end + request.body_len);
+            return Parsed::Limited(retry_after, false)
+        }
+
+        let received = request.received;
+        let head = request.head.unwrap_or_else(|_| unreachable!());
+        let (body, trailers) = match request.chunked {
+            Some(decoder) => decoder.into_parts(),
+            None => (self.buffer[end..end + request.body_len].to_vec(), Headers::new()),
+        };
+        self.buffer.drain(..end + request.body_len);
+        if self.buffer.capacity() > crate::MAX_RETAINED_BUFFER {
+            self.buffer.shrink_to(crate::MAX_RETAINED_BUFFER);
+        }
+
+        let head_only = head.method == "HEAD";
+        let mut request = HTTPRequest::new(head.method, head.target, head.protocol, head.headers, body.into());
+        request.target = head.form;
+        request.trailers = trailers;
+        request.received = received;
+
+        // a `..` trying to get above the root
+        if request.normalize(options.merge_slashes).is_err() {
+            stats.parse_error();
+            return Parsed::Refused(400, None)
+        }
+
+        stats.request();
+        request.client = self.client.clone();
+        request.remote_addr = forwarded::remote_addr(&options.trusted_proxies, options.forwarded_header, request.client.as_ref(), &request.headers);
+        request.server = self.server.clone();
+        request.id = self.request_id.clone().unwrap_or_default();
+
+        Parsed::Ready(Box::new(request), head_only)
+    }
+
+    /// check_head, check_rate, check_body and check_expect from OnceFuture in one.
+    fn check(
+        &mut self,
+        parsed: Result<RequestHead, HeadError>,
+        options: &RunnerOptions,
+        stats: &ServerStats,
+        request: &mut PartialRequest,
+    ) -> Result<RequestHead, u16> {
+        self.request_id = Some(request_id::assign(options, parsed.as_ref().ok().map(|head| &head.headers)));
+
+        let mut head = match parsed {
+            Ok(head) if http::valid_framing(&head.headers) => head,
+            parsed => {
+                stats.parse_error();
+                let error = parsed.err().unwrap_or(HeadError::invalid("conflicting or invalid body framing"));
+                request.reason = Some(error.reason);
+                return Err(error.status)
+            },
+        };
+
+        http::check_host(
+            &mut head.target,
+            head.version,
+            &mut head.headers,
+            options.allowed_hosts.as_deref(),
+            options.invalid_host_status,
+        )?;
+
+        self.version = head.version;
+        self.keep_alive = http::keep_alive(head.version, &head.headers);
+
+        match ratelimit::check(options, self.client.as_ref(), &head.headers) {
+            Verdict::Allowed => {},
+            Verdict::Limited(retry_after) => {
+                stats.rate_limited();
+                request.retry_after = Some(retry_after);
+            },
+            Verdict::Exceeded(retry_after) => {
+                stats.rate_limited();
+                request.retry_after = Some(retry_after);
+                return Err(429)
+            },
+        }
+
+        match http::body_framing(&head.headers, options.max_body_size)? {
+            BodyFraming::Length(len) => request.body_len = len,
+            BodyFraming::Chunked(decoder) => request.chunked = Some(decoder),
+        }
+
+        match head.headers.get(HeaderName::Expect) {
+            Some(expect) if head.version >= (1, 1) => {
+                if !expect.trim().eq_ignore_ascii_case("100-continue") {
+                    return Err(417)
+                }
+
+                if request.body_len > 0 || request.chunked.is_some() {
+                    self.out.buffer().extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+                }
+            },
+            _ => {},
+        }
+
+        Ok(head)
+    }
+}
+
+
+///
+/// Worker is the state owned by the reactor thread.
+///
+struct Worker {
+    epoll: Epoll,
+    listeners: Vec<TcpListener>,
+    pause: AcceptPause,
+    paused: bool,                       // The listeners are out of the epoll set until the pause is over
+    shared: Arc<Shared>,
+    ctx: Arc<Context>,
+    connections: HashMap<u64, Connection>,
+    next_id: u64,                       // Ids aren't reused so a late response can't reach the wrong client
+    ready: Vec<(Pending, HTTPRequest)>, // Requests parsed this round, handed to python together
+}
+
+impl Worker {
+    fn run(mut self) {
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let idle_check = match self.ctx.options.keep_alive_timeout {
+            Some(_) => IDLE_CHECK_INTERVAL,
+            None => -1,
+        };
+
+        while !self.shared.stopping.load(Ordering::Acquire) {
+            let timeout = match self.pause.remaining() {
+                Some(left) => {
+                    let left = left.as_millis() as libc::c_int + 1;
+                    match idle_check {
+                        -1 => left,
+                        idle_check => left.min(idle_check),
+                    }
+                },
+                None => {
+                    if self.paused {
+                        self.listen(true);
+                    }
+                    idle_check
+                },
+            };
+
+            let n = match self.epoll.wait(&mut events, timeout) {
+                Ok(n) => n,
+                Err(e) => {
+                    log::socket_error("the native reactor failed", &e);
+                    break
+                },
+            };
+
+            for event in &events[..n] {
+                let (token, flags) = (event.u64, event.events);
+                match token {
+                    WAKE => self.complete(),
+                    token if FIRST_LISTENER - token < self.listeners.len() as u64 => {
+                        self.accept((FIRST_LISTENER - token) as usize)
+                    },
+                    id => self.ready(id, flags),
+                }
+            }
+
+            self.dispatch();
+            self.close_idle();
+        }
+    }
+
+    /// Drops the connections that have waited `keep_alive_timeout` for their next request.
+    fn close_idle(&mut self) {
+        let timeout = match self.ctx.options.keep_alive_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        self.connections.retain(|_, conn| {
+            !conn.idle_since.is_some_and(|since| since.elapsed().as_secs_f32() >= timeout)
+        });
+    }
+
+    ///
+    /// Internal Method: Worker::dispatch()
+    ///
+    ///     Schedules every request parsed this round on the event loop in
+    ///     one `call_soon_threadsafe`, so a busy round costs one trip through
+    ///     the GIL rather than one per request. If the loop won't take them
+    ///     (it's closed) their connections are dropped.
+    ///
+    fn dispatch(&mut self) {
+        if self.ready.is_empty() {
+            return
+        }
+
+        let ready = std::mem::take(&mut self.ready);
+        let ids: Vec<u64> = ready.iter().map(|(pending, _)| pending.id).collect();
+
+        let scheduled = Python::with_gil(|py| {
+            let scheduled = (|| -> PyResult<()> {
+                let mut batch = Vec::with_capacity(ready.len());
+                for (pending, mut request) in ready {
+                    let context = match self.connections.get_mut(&pending.id) {
+                        Some(conn) => conn.context.get_or_insert_with(|| PyDict::new(py).into()).clone_ref(py),
+                        None => PyDict::new(py).into(),
+                    };
+                    request.connection = Some(context);
+                    batch.push((Arc::new(pending), Py::new(py, request)?));
+                }
+
+                let dispatch = Py::new(py, NativeDispatch { batch })?;
+                self.ctx.loop_.call_method1(py, "call_soon_threadsafe", (dispatch,))?;
+                Ok(())
+            })();
+
+            scheduled.map_err(|e| e.print(py)).is_ok()
+        });
+
+        if !scheduled {
+            for id in ids {
+                self.connections.remove(&id);
+            }
+        }
+    }
+
+    /// Puts the listeners back in the epoll set or takes them out while accepting is paused.
+    fn listen(&mut self, on: bool) {
+        let events = match on {
+            true => libc::EPOLLIN as u32,
+            false => 0,
+        };
+
+        for (index, listener) in self.listeners.iter().enumerate() {
+            if let Err(e) = self.epoll.ctl(libc::EPOLL_CTL_MOD, listener.as_raw_fd(), events, FIRST_LISTENER - index as u64) {
+                log::socket_error("failed to update a listener", &e);
+            }
+        }
+
+        self.paused = !on;
+    }
+
+    fn accept(&mut self, index: usize) {
+        loop {
+            let sock = match self.listeners[index].accept() {
+                Ok((sock, _)) => sock,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    if self.pause.failed(&e) {
+                        self.listen(false);
+                    }
+                    return
+                },
+            };
+
+            let sock = match self.ctx.options.acl.admit(sock, self.ctx.options.deny_403, &self.ctx.stats) {
+                Some(sock) => sock,
+                None => continue,
+            };
+
+            if sock.set_nonblocking(true).is_err() {
+                continue
+            }
+
+            self.ctx.options.client_options().apply(&sock);
+
+            let interest = (libc::EPOLLIN | libc::EPOLLRDHUP) as u32;
+            let id = self.next_id;
+            self.next_id += 1;
+
+            if let Err(e) = self.epoll.ctl(libc::EPOLL_CTL_ADD, sock.as_raw_fd(), interest, id) {
+                log::socket_error("failed to register a connection", &e);
+                continue
+            }
+
+            let conn = Connection {
+                client: sock.peer_addr().ok().map(|addr| (addr.ip().to_string(), addr.port())),
+                server: sock.local_addr().ok().map(|addr| (addr.ip().to_string(), addr.port())),
+                sock,
+                buffer: Vec::new(),
+                request: None,
+                out: Outgoing::default(),
+                version: (1, 1),
+                request_id: None,
+                keep_alive: false,
+                requests: 0,
+                idle_since: None,
+                in_flight: false,
+                eof: false,
+                closing: false,
+                interest,
+                context: None,
+                _active: self.ctx.stats.connection(),
+            };
+            self.connections.insert(id, conn);
+        }
+    }
+
+    /// A connection's socket is ready, dropping it closes the socket.
+    fn ready(&mut self, id: u64, flags: u32) {
+        let mut conn = match self.connections.remove(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        if flags & (libc::EPOLLERR | libc::EPOLLHUP) as u32 != 0 {
+            return
+        }
+
+        if flags & (libc::EPOLLIN | libc::EPOLLRDHUP) as u32 != 0 && conn.read(&self.ctx.options).is_err() {
+            return
+        }
+
+        self.advance(id, &mut conn);
+        if self.settle(id, &mut conn) {
+            self.connections.insert(id, conn);
+        }
+    }
+
+    /// Responses python has finished with, written out to their connections.
+    fn complete(&mut self) {
+        for completion in self.shared.take_completed() {
+            let mut conn = match self.connections.remove(&completion.id) {
+                Some(conn) => conn,
+                None => continue,
+            };
+
+            // the handler was cancelled, there's nothing we can tell the client
+            if completion.response.is_empty() {
+                continue
+            }
+
+            conn.in_flight = false;
+            conn.out.append(completion.response);
+            if !completion.keep_alive {
+                conn.closing = true;
+            }
+
+            self.advance(completion.id, &mut conn);
+            if self.settle(completion.id, &mut conn) {
+                self.connections.insert(completion.id, conn);
+            }
+        }
+    }
+
+    ///
+    /// Handles whatever requests are buffered until one goes to python,
+    /// refusals are answered straight from here and close the connection.
+    /// Requests `rate_limit` turns away are answered here too, without
+    /// closing unless they're past `rate_limit_burst`.
+    ///
+    fn advance(&mut self, id: u64, conn: &mut Connection) {
+        if conn.in_flight || conn.closing {
+            return
+        }
+
+        loop {
+            match conn.parse(&self.ctx.options, &self.ctx.stats) {
+                Parsed::Partial => {},
+                Parsed::Refused(status, reason) => {
+                    let response = match (status, reason) {
+                        (status, Some(reason)) => HTTPResponse::refused(status, reason),
+                        (status, _) => HTTPResponse::with_status(status),
+                    };
+
+                    crate::serialize_response(
+                        &response,
+                        &self.ctx.options,
+                        &self.ctx.date,
+                        conn.version,
+                        false,
+                        conn.requests + 1,
+                        crate::SerializedBody::Full,
+                        None,
+                        conn.request_id.as_deref(),
+                        None,
+                        &mut conn.out,
+                    );
+                    conn.closing = true;
+                },
+                Parsed::Limited(retry_after, close) => {
+                    conn.requests += 1;
+                    let (keep_alive, _) = crate::serialize_response(
+                        &ratelimit::too_many_requests(retry_after),
+                        &self.ctx.options,
+                        &self.ctx.date,
+                        conn.version,
+                        conn.keep_alive && !close,
+                        conn.requests,
+                        crate::SerializedBody::Full,
+                        None,
+                        conn.request_id.as_deref(),
+                        None,
+                        &mut conn.out,
+                    );
+
+                    // there's no python involved so go straight on to the next one
+                    if keep_alive {
+                        continue
+                    }
+                    conn.closing = true;
+                },
+                Parsed::Ready(request, head_only) => {
+                    conn.in_flight = true;
+                    conn.requests += 1;
+                    let encoding = compress::accepted(&self.ctx.options, &request.headers);
+                    let conditional = Conditional::from_headers(&request.method, &request.headers);
+                    let cors_origin = self.ctx.options.cors.as_ref().and_then(|cors| cors.allow_origin(&request.headers));
+                    let pending = Pending {
+                        ctx: self.ctx.clone(),
+                        shared: self.shared.clone(),
+                        id,
+                        version: conn.version,
+                        request_id: request.id.clone(),
+                        keep_alive: conn.keep_alive,
+                        requests: conn.requests,
+                        head_only,
+                        encoding,
+                        conditional,
+                        cors_origin,
+                        client: conn.client.clone(),
+                        _lease: request.lease(),
+                    };
+
+                    self.ready.push((pending, *request));
+                },
+            }
+
+            break
+        }
+
+        // nothing more is coming so a partial request never will be finished
+        if conn.eof && !conn.in_flight {
+            conn.closing = true;
+        }
+    }
+
+    /// Writes what we can and re-registers for what's next, false to drop the connection.
+    fn settle(&self, id: u64, conn: &mut Connection) -> bool {
+        if conn.flush(&self.ctx.stats).is_err() {
+            return false
+        }
+
+        if conn.closing && conn.out.is_empty() {
+            return false
+        }
+
+        // waiting on a request that hasn't started to arrive yet
+        let idle = conn.requests > 0 && !conn.in_flight && conn.out.is_empty() && conn.buffer.is_empty();
+        conn.idle_since = match idle {
+            true => conn.idle_since.or_else(|| Some(Instant::now())),
+            false => None,
+        };
+
+        let wanted = conn.wanted(&self.ctx.options);
+        if wanted != conn.interest {
+            if self.epoll.ctl(libc::EPOLL_CTL_MOD, conn.sock.as_raw_fd(), wanted, id).is_err() {
+                return false
+            }
+            conn.interest = wanted;
+        }
+
+        true
+    }
+}
+
+
+///
+/// Pending is a request that has gone over to python, it carries what's
+/// needed to serialize the response and get it back to the reactor.
+///
+struct Pending {
+    ctx: Arc<Context>,
+    shared: Arc<Shared>,
+    id: u64,                            // The connection the request came in on
+    version: (u8, u8),
+    request_id: String,
+    keep_alive: bool,
+    requests: u64,
+    head_only: bool,
+    encoding: Option<Encoding>,         // From the request's Accept-Encoding
+    conditional: Conditional,           // The request's conditional headers, for a FileResponse
+    cors_origin: Option<String>,        // The `Access-Control-Allow-Origin` for the response, with `cors`
+    client: Option<(String, u16)>,
+    _lease: RequestLease,               // Lets the request read its body until the response is handed back
+}
+
+impl Pending {
+    /// Runs the callback, responding now or once the coroutine it gave us finishes.
+    fn run(self: &Arc<Self>, py: Python, request: &Py<HTTPRequest>) {
+        if request.borrow(py).raw_path == b"*" && !self.ctx.options.pass_options_star {
+            let response = crate::router::options_star(py, &self.ctx.callback, &self.ctx.options);
+            return self.respond(py, Py::new(py, response).map(|response| response.into_py(py)))
+        }
+
+        let preflight = self.ctx.options.cors.as_ref().and_then(|cors| {
+            let request = request.borrow(py);
+            cors.preflight(&request.method, &request.headers)
+        });
+        if let Some(response) = preflight {
+            return self.respond(py, Py::new(py, response).map(|response| response.into_py(py)))
+        }
+
+        let result = match self.ctx.callback.call1(py, (request,)) {
+            Ok(result) => result,
+            Err(e) => return self.respond(py, Err(e)),
+        };
+
+        match result.as_ref(py).hasattr("__await__") {
+            Ok(true) => {},
+            Ok(false) => return self.respond(py, Ok(result)),
+            Err(e) => return self.respond(py, Err(e)),
+        }
+
+        let done = (|| -> PyResult<()> {
+            let task = py.import("asyncio")?.call1("ensure_future", (result,))?;
+            let done = Py::new(py, NativeDone { pending: self.clone() })?;
+            task.call_method1("add_done_callback", (done,))?;
+            Ok(())
+        })();
+
+        if let Err(e) = done {
+            self.respond(py, Err(e));
+        }
+    }
+
+    ///
+    /// Internal Method: Pending::respond()
+    ///
+    ///     Serializes whatever the callback finished with and hands it back
+    ///     to the reactor, a handler that raised is reported and answered
+    ///     with a `500` the same as it would be by a OnceFuture.
+    ///
+    fn respond(&self, py: Python, result: PyResult<PyObject>) {
+        let mut out = Outgoing::default();
+        let keep_alive = match self.serialize(py, result, &mut out) {
+            Ok(keep_alive) => keep_alive,
+            Err(e) if crate::is_cancelled(py, &e) => {
+                out.clear();
+                false
+            },
+            Err(e) => {
+                crate::report_handler_error(py, &self.ctx.loop_, &e, self.client.clone());
+
+                out.clear();
+                let response = crate::error_response(py, &e, self.ctx.options.debug);
+                self.write(&response, false, &mut out)
+            },
+        };
+
+        self.shared.complete(Completion {
+            id: self.id,
+            response: out,
+            keep_alive,
+        });
+    }
+
+    fn serialize(&self, py: Python, result: PyResult<PyObject>, out: &mut Outgoing) -> PyResult<bool> {
+        let result = result?;
+
+        if let Ok(file) = result.extract::<PyRef<FileResponse>>(py) {
+            let (head, body) = match file.open(&self.conditional) {
+                Ok(opened) => opened,
+                Err(status) => return Ok(self.write(&HTTPResponse::with_status(status), self.keep_alive, out)),
+            };
+
+            let keep_alive = self.write(&head, self.keep_alive, out);
+            if let (false, Some(mut body)) = (self.head_only, body) {
+                py.allow_threads(|| -> io::Result<()> {
+                    let mut chunk = Vec::new();
+                    while body.read_chunk(&mut chunk)? {
+                        out.buffer().extend_from_slice(&chunk);
+                    }
+
+                    Ok(())
+                })?;
+            }
+
+            return Ok(keep_alive)
+        }
+
+        if result.extract::<PyRef<EventSourceResponse>>(py).is_ok() || BodyStream::is_stream(result.as_ref(py))? {
+            return Err(PyTypeError::new_err("streamed response bodies (and EventSourceResponse) aren't supported with reactor=\"native\""))
+        }
+
+        if result.extract::<PyRef<Upgrade>>(py).is_ok() {
+            return Err(PyTypeError::new_err("Upgrade isn't supported with reactor=\"native\""))
+        }
+
+        let extracted = result.extract::<PyRef<HTTPResponse>>(py);
+        let returned;
+        let response = match extracted.as_ref() {
+            Ok(response) => &**response,
+            Err(_) => {
+                returned = HTTPResponse::from_return(result.as_ref(py))?;
+                &returned
+            },
+        };
+
+        if !self.head_only {
+            if let Some(corrected) = response.check_length(self.ctx.options.strict_content_length)? {
+                return Ok(self.write(&corrected, self.keep_alive, out))
+            }
+        }
+
+        Ok(self.write(response, self.keep_alive, out))
+    }
+
+    fn write(&self, response: &HTTPResponse, keep_alive: bool, out: &mut Outgoing) -> bool {
+        crate::serialize_response(
+            response,
+            &self.ctx.options,
+            &self.ctx.date,
+            self.version,
+            keep_alive,
+            self.requests,
+            crate::SerializedBody::unless_head(self.head_only),
+            self.encoding,
+            Some(&self.request_id),
+            self.cors_origin.as_deref(),
+            out,
+        ).0
+    }
+}
+
+///
+/// NativeDispatch is what the reactor schedules on the event loop, a batch
+/// of requests whose callbacks get run in the order they were parsed.
+///
+#[pyclass]
+pub struct NativeDispatch {
+    batch: Vec<(Arc<Pending>, Py<HTTPRequest>)>,
+}
+
+#[pymethods]
+impl NativeDispatch {
+    #[call]
+    fn __call__(&self, py: Python) {
+        for (pending, request) in &self.batch {
+            pending.run(py, request);
+        }
+    }
+}
+
+/// The done callback of a coroutine handler's task.
+#[pyclass]
+pub struct NativeDone {
+    pending: Arc<Pending>,
+}
+
+#[pymethods]
+impl NativeDone {
+    #[call]
+    fn __call__(&self, py: Python, task: &PyAny) {
+        let result = task.call_method0("result").map(|result| result.into_py(py));
+        self.pending.respond(py, result);
+    }
+}
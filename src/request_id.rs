@@ -0,0 +1,57 @@
+use ring::rand::{SecureRandom, SystemRandom};
+
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::headers::{HeaderName, Headers};
+use crate::options::RunnerOptions;
+
+
+/// The longest `X-Request-Id` from a client we'll pass on with `trust_request_id`.
+const MAX_TRUSTED_LEN: usize = 200;
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+
+///
+/// Internal Method: request_id::assign() -> String
+///
+///     The id for a request whose head has just arrived, see `request.id`.
+///     With `trust_request_id` it's the client's own `X-Request-Id` if it
+///     sent a sensible one, anything else gets a new one. `headers` is None
+///     for a head we couldn't parse, it still gets an id for the log.
+///
+pub(crate) fn assign(options: &RunnerOptions, headers: Option<&Headers>) -> String {
+    let trusted = headers
+        .filter(|_| options.trust_request_id)
+        .and_then(|headers| headers.get(HeaderName::XRequestId))
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_TRUSTED_LEN && id.bytes().all(|b| b.is_ascii_graphic()));
+
+    match trusted {
+        Some(id) => id.to_string(),
+        None => generate(),
+    }
+}
+
+///
+/// A new id, 32 hex digits made of the time in milliseconds, a random
+/// number picked once per process (so workers don't hand out the same
+/// ones) and a counter. They sort roughly by when they were made.
+///
+fn generate() -> String {
+    static PROCESS: OnceLock<u32> = OnceLock::new();
+
+    let process = *PROCESS.get_or_init(|| {
+        let mut bytes = [0; 4];
+        let _ = SystemRandom::new().fill(&mut bytes);
+        u32::from_ne_bytes(bytes) ^ std::process::id()
+    });
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!("{:012x}{:08x}{:012x}", millis & 0xffff_ffff_ffff, process, count & 0xffff_ffff_ffff)
+}
@@ -0,0 +1,326 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::PyValueError;
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::http::{self, HTTPRequest, HTTPResponse};
+use crate::middleware::Middleware;
+use crate::options::RunnerOptions;
+
+
+///
+/// Router dispatches requests to a handler by method and path so it can
+/// be passed as the runner's callback in place of a single handler.
+///
+/// Paths are made of exact segments and `{name}` segments which match
+/// any one segment, what they matched ends up in `request.path_params`.
+/// Routes are compiled into a tree of segments so finding one takes a
+/// step per segment of the path however many routes there are. An exact
+/// segment wins over a `{name}` one at the same point, the router doesn't
+/// go back and try the other if the rest of the path then doesn't match.
+///
+/// A path no route matches is a `404`, one that only matches for other
+/// methods a `405` with those in `Allow`, unless `route_not_found` or
+/// `method_not_allowed` are given to answer them instead. `HEAD` is
+/// answered by the `GET` handler unless it has one of its own, the body it
+/// gives is dropped like any other response to a `HEAD`. `OPTIONS *` is
+/// answered with every method any route has.
+///
+/// With `redirect_slashes=True` a path no route matches which would with
+/// its trailing `/` added or taken off is answered with a `308` there, the
+/// query string kept, so `/users/` finds `/users` and the other way round.
+///
+/// Routes match the decoded `request.path` unless the router is made with
+/// `raw_path=True`, then it's `request.raw_path` as it was sent (each byte
+/// as its latin-1 character) so `/a%2Fb` is one segment and the params are
+/// left encoded.
+///
+///     Optional:
+///         - raw_path:             bool        (match the path exactly as it was sent, defaults to false)
+///         - redirect_slashes:     bool        (redirect to the path with its trailing `/` added or taken off when only that matches, defaults to false)
+///         - route_not_found:      callable    (called with the request in place of the `404`, like a handler it gives the response)
+///         - method_not_allowed:   callable    (called with the request and the list of methods the path allows in place of the `405`, it should send them as `Allow`)
+///
+///     Example:
+///         router = Router()
+///         router.add_route("GET", "/users/{id}", get_user)
+///         AsyncServerRunner("0.0.0.0:8080", router)
+///
+#[pyclass]
+pub struct Router {
+    root: Node,
+    raw_path: bool,
+    redirect_slashes: bool,
+    route_not_found: Option<PyObject>,
+    method_not_allowed: Option<PyObject>,
+}
+
+///
+/// Node is one segment of the routes, the handlers of the routes that end
+/// here and the segments that can come next.
+///
+#[derive(Default)]
+struct Node {
+    exact: HashMap<String, Node>,
+    param: Option<(String, Box<Node>)>,     // A `{name}` segment and what follows it
+    handlers: Vec<(String, PyObject)>,      // By method, in the order they were added
+}
+
+#[pymethods]
+impl Router {
+    #[new]
+    #[args(raw_path = "false", redirect_slashes = "false", route_not_found = "None", method_not_allowed = "None")]
+    fn new(
+        raw_path: bool,
+        redirect_slashes: bool,
+        route_not_found: Option<PyObject>,
+        method_not_allowed: Option<PyObject>,
+    ) -> Self {
+        Self { root: Node::default(), raw_path, redirect_slashes, route_not_found, method_not_allowed }
+    }
+
+    ///
+    /// PythonMethod: Router.add_route(method, path, handler)
+    ///
+    ///     Sends `method` requests for `path` to `handler`, which is called
+    ///     just like a runner's callback would be. A route already added, a
+    ///     path not starting with `/` or a `{name}` segment that disagrees
+    ///     on its name with another route's at the same point is a
+    ///     ValueError.
+    ///
+    ///     Requires:
+    ///         - method:   str     (e.g. "GET", upper-cased)
+    ///         - path:     str     (e.g. "/users/{id}/posts")
+    ///         - handler:  the callable to handle matching requests
+    ///
+    fn add_route(&mut self, method: &str, path: &str, handler: PyObject) -> PyResult<()> {
+        let rest = match path.strip_prefix('/') {
+            Some(rest) => rest,
+            None => return Err(PyValueError::new_err(format!("route '{}' has to start with '/'", path))),
+        };
+
+        let mut node = &mut self.root;
+        if !rest.is_empty() {
+            for segment in rest.split('/') {
+                node = match param_name(segment) {
+                    Some(name) => {
+                        let (existing, next) = node.param.get_or_insert_with(|| (name.to_string(), Box::default()));
+                        if existing != name {
+                            return Err(PyValueError::new_err(format!(
+                                "route '{}' names a segment '{{{}}}' which another route calls '{{{}}}'", path, name, existing
+                            )))
+                        }
+                        next
+                    },
+                    None => node.exact.entry(segment.to_string()).or_default(),
+                };
+            }
+        }
+
+        let method = method.to_ascii_uppercase();
+        if node.handlers.iter().any(|(existing, _)| *existing == method) {
+            return Err(PyValueError::new_err(format!("{} {} already has a route", method, path)))
+        }
+
+        node.handlers.push((method, handler));
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: Router(request) -> response
+    ///
+    ///     Finds the route for the request and hands it over to the
+    ///     handler, giving back whatever that does.
+    ///
+    #[call]
+    fn __call__(&self, py: Python, request: &PyCell<HTTPRequest>) -> PyResult<PyObject> {
+        let mut params = Vec::new();
+        let (node, method, path) = {
+            let request = request.borrow();
+            if request.raw_path == b"*" {
+                return Ok(Py::new(py, options_response(self.allowed()))?.into_py(py))
+            }
+
+            let path = match self.raw_path {
+                true => http::latin1(&request.raw_path),
+                false => request.path.clone(),
+            };
+
+            (self.root.find(&path, &mut params).filter(|node| !node.handlers.is_empty()), request.method.clone(), path)
+        };
+
+        let node = match node {
+            Some(node) => node,
+            None if self.redirect_slashes && self.matches(&toggle_slash(&path)) => {
+                let location = vec![(String::from("Location"), redirect_location(&request.borrow()))];
+                return Ok(Py::new(py, HTTPResponse::from_parts(308, location, Vec::new()))?.into_py(py))
+            },
+            None => return match self.route_not_found.as_ref() {
+                Some(hook) => hook.call1(py, (request,)),
+                None => Ok(Py::new(py, HTTPResponse::with_status(404))?.into_py(py)),
+            },
+        };
+
+        let handler = node.handler(&method);
+        let handler = match handler {
+            Some(handler) => handler,
+            None => return match self.method_not_allowed.as_ref() {
+                Some(hook) => hook.call1(py, (request, node.methods_allowed())),
+                None => {
+                    let allow = vec![(String::from("Allow"), node.allowed())];
+                    Ok(Py::new(py, HTTPResponse::from_parts(405, allow, Vec::new()))?.into_py(py))
+                },
+            },
+        };
+
+        request.borrow_mut().path_params = params;
+        handler.call1(py, (request,))
+    }
+}
+
+impl Router {
+    /// The `Allow` for `OPTIONS *`, every method a route was added for.
+    fn allowed(&self) -> String {
+        let mut methods = BTreeSet::new();
+        self.root.methods(&mut methods);
+        if methods.contains("GET") {
+            methods.insert("HEAD");
+        }
+        methods.insert("OPTIONS");
+
+        methods.into_iter().collect::<Vec<_>>().join(", ")
+    }
+
+    /// If any route has `path`, for whatever method.
+    fn matches(&self, path: &str) -> bool {
+        self.root.find(path, &mut Vec::new()).is_some_and(|node| !node.handlers.is_empty())
+    }
+}
+
+impl Node {
+    fn methods<'a>(&'a self, methods: &mut BTreeSet<&'a str>) {
+        methods.extend(self.handlers.iter().map(|(method, _)| method.as_str()));
+        for next in self.exact.values().chain(self.param.as_ref().map(|(_, next)| &**next)) {
+            next.methods(methods);
+        }
+    }
+
+    /// The node for `path`, collecting the `{name}` segments it passes through.
+    fn find(&self, path: &str, params: &mut Vec<(String, String)>) -> Option<&Node> {
+        let rest = path.strip_prefix('/')?;
+
+        let mut node = self;
+        if rest.is_empty() {
+            return Some(node)
+        }
+
+        for segment in rest.split('/') {
+            node = match (node.exact.get(segment), node.param.as_ref()) {
+                (Some(next), _) => next,
+                // an empty segment (`/users/`) isn't a value for `{id}`
+                (None, Some((name, next))) if !segment.is_empty() => {
+                    params.push((name.clone(), segment.to_string()));
+                    next
+                },
+                _ => return None,
+            };
+        }
+
+        Some(node)
+    }
+
+    fn handler(&self, method: &str) -> Option<&PyObject> {
+        let find = |method: &str| self.handlers.iter().find(|(existing, _)| existing == method).map(|(_, handler)| handler);
+        match method {
+            "HEAD" => find("HEAD").or_else(|| find("GET")),
+            method => find(method),
+        }
+    }
+
+    /// The methods this node's routes answer, `HEAD` too if there's a `GET`.
+    fn methods_allowed(&self) -> Vec<&str> {
+        let mut methods: Vec<&str> = self.handlers.iter().map(|(method, _)| method.as_str()).collect();
+        if methods.contains(&"GET") && !methods.contains(&"HEAD") {
+            methods.push("HEAD");
+        }
+
+        methods
+    }
+
+    /// The `Allow` header for this node.
+    fn allowed(&self) -> String {
+        self.methods_allowed().join(", ")
+    }
+}
+
+/// The name of a `{name}` segment.
+fn param_name(segment: &str) -> Option<&str> {
+    segment
+        .strip_prefix('{')
+        .and_then(|segment| segment.strip_suffix('}'))
+        .filter(|name| !name.is_empty())
+}
+
+/// `path` with its trailing `/` taken off, or added if it has none.
+fn toggle_slash(path: &str) -> String {
+    match path.strip_suffix('/') {
+        Some(path) => path.to_string(),
+        None => format!("{}/", path),
+    }
+}
+
+///
+/// Internal Method: router::redirect_location(request) -> String
+///
+///     Where `redirect_slashes` sends the request, the path as it was sent
+///     with its trailing `/` toggled and the query string after it. Bytes
+///     that can't go in a header as they are get `%XX` encoded.
+///
+fn redirect_location(request: &HTTPRequest) -> String {
+    let path = request.raw_path.strip_suffix(b"/").unwrap_or(&request.raw_path);
+
+    let mut location = String::with_capacity(path.len() + request.raw_query.len() + 2);
+    for &b in path {
+        match b {
+            b'!'..=b'~' => location.push(b as char),
+            b => location.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    if !request.raw_path.ends_with(b"/") {
+        location.push('/');
+    }
+
+    if !request.raw_query.is_empty() {
+        location.push('?');
+        location.push_str(&request.raw_query);
+    }
+
+    location
+}
+
+///
+/// Internal Method: router::options_star() -> HTTPResponse
+///
+///     The answer to `OPTIONS *` when it isn't passed on to the callback
+///     (see `pass_options_star`), a `200` allowing the methods the Router
+///     knows about when the callback is one (or is middleware in front of
+///     one) and `options_allow` when it isn't.
+///
+pub(crate) fn options_star(py: Python, callback: &PyObject, options: &RunnerOptions) -> HTTPResponse {
+    let middleware = callback.extract::<PyRef<Middleware>>(py);
+    let callback = match middleware.as_ref() {
+        Ok(middleware) => middleware.handler(),
+        Err(_) => callback,
+    };
+
+    let allow = match callback.extract::<PyRef<Router>>(py) {
+        Ok(router) => router.allowed(),
+        Err(_) => options.options_allow.clone(),
+    };
+
+    options_response(allow)
+}
+
+fn options_response(allow: String) -> HTTPResponse {
+    HTTPResponse::from_parts(200, vec![(String::from("Allow"), allow)], Vec::new())
+}
@@ -0,0 +1,305 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::exceptions::{PyRuntimeError, PyStopIteration};
+use pyo3::types::{PyDict, PyTuple};
+use pyo3::wrap_pyfunction;
+
+use crate::{AsyncServer, AsyncServerRunner, ServerState};
+use crate::asgi::Ready;
+use crate::errors;
+use crate::options::RunnerOptions;
+
+
+///
+/// Adds `start_server()` and the classes behind it to the module, the
+/// function has to be wrapped from in here where pyo3 generated it.
+///
+pub(crate) fn init(m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(start_server, m)?)?;
+    m.add_class::<StartServer>()?;
+    m.add_class::<Server>()?;
+    m.add_class::<ServerExit>()?;
+    Ok(())
+}
+
+///
+/// PythonMethod: async_rust.start_server(callback, host="127.0.0.1", port=8080, **options) -> awaitable Server
+///
+///     The `asyncio.start_server` way of running a server, awaiting it
+///     binds and starts accepting in a background task on the running loop
+///     and gives back a Server to manage it with.
+///
+///     Requires:
+///         - callback:     PyObject
+///
+///     Optional:
+///         - host:         str
+///         - port:         int
+///         - **options:    see RunnerOptions (tls, raw, reactor ...)
+///
+#[pyfunction(host = "\"127.0.0.1\"", port = "8080", options = "**")]
+pub(crate) fn start_server(
+    py: Python,
+    callback: PyObject,
+    host: &str,
+    port: u16,
+    options: Option<&PyDict>,
+) -> StartServer {
+    StartServer {
+        args: Some((callback, host.to_string(), port, options.map(|options| options.into_py(py)))),
+        server: None,
+    }
+}
+
+///
+/// StartServer is the awaitable `start_server()` gives back, like a
+/// coroutine nothing happens until it's awaited and it finishes on the
+/// first step.
+///
+/// It can also be used directly with `async with`, the server is started
+/// on enter and closed on exit.
+///
+#[pyclass]
+pub struct StartServer {
+    args: Option<(PyObject, String, u16, Option<Py<PyDict>>)>,  // Taken the first time we're stepped
+    server: Option<Py<Server>>,                                 // The server once started by `async with`
+}
+
+impl StartServer {
+    fn start(&mut self, py: Python) -> PyResult<Server> {
+        let (callback, host, port, options) = self.args
+            .take()
+            .ok_or_else(|| PyRuntimeError::new_err("cannot reuse already awaited start_server()"))?;
+
+        let kwargs = options.as_ref().map(|options| options.as_ref(py));
+        let options = RunnerOptions::from_kwargs(kwargs)?;
+        // `[::1]:8080` is how an IPv6 host has to be written with its port
+        let addr = match host.contains(':') && !host.starts_with('[') {
+            true => format!("[{}]:{}", host, port),
+            false => format!("{}:{}", host, port),
+        };
+
+        let (resolve, backlog) = (options.resolve, options.backlog);
+        let server = py.allow_threads(|| AsyncServer::bind_all(&[addr], resolve, backlog)).map_err(|e| errors::bind_error(py, e))?;
+        let runner = Py::new(py, AsyncServerRunner::with_server(py, server, callback, options, kwargs)?)?;
+
+        let task = py.import("asyncio")?.call1("ensure_future", (runner.clone_ref(py),))?;
+
+        Ok(Server {
+            runner,
+            task: task.into(),
+            entered: false,
+        })
+    }
+}
+
+#[pymethods]
+impl StartServer {
+    fn __aenter__(&mut self, py: Python) -> PyResult<Ready> {
+        let server = Py::new(py, self.start(py)?)?;
+        server.borrow_mut(py).enter()?;
+        self.server = Some(server.clone_ref(py));
+
+        Ok(Ready { value: Some(server.into_py(py)) })
+    }
+
+    fn __aexit__(&self, py: Python, _exc_type: &PyAny, _exc: &PyAny, _traceback: &PyAny) -> PyResult<ServerExit> {
+        match self.server.as_ref() {
+            Some(server) => Ok(server.borrow(py).exit(py)),
+            None => Err(PyRuntimeError::new_err("the server was never entered")),
+        }
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for StartServer {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for StartServer {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, PyObject>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+        let server = slf.start(py)?;
+
+        Ok(IterNextOutput::Return(Py::new(py, server)?.into_py(py)))
+    }
+}
+
+
+///
+/// Server is the handle `start_server()` gives back, modelled on
+/// `asyncio.Server`. The runner underneath is still there as `runner` for
+/// its stats and connections.
+///
+#[pyclass]
+pub struct Server {
+    runner: Py<AsyncServerRunner>,
+    task: PyObject,                 // The background task awaiting the runner
+    entered: bool,                  // Set by `async with`, a server can only be entered once
+}
+
+impl Server {
+    fn enter(&mut self) -> PyResult<()> {
+        if self.entered {
+            return Err(PyRuntimeError::new_err("the server has already been entered"))
+        }
+
+        self.entered = true;
+        Ok(())
+    }
+
+    fn exit(&self, py: Python) -> ServerExit {
+        ServerExit {
+            runner: Some(self.runner.clone_ref(py)),
+            task: self.task.clone_ref(py),
+            waiting: None,
+        }
+    }
+}
+
+#[pymethods]
+impl Server {
+    ///
+    /// PythonMethod: Server.close()
+    ///
+    ///     Stops accepting and cancels the connections still running, the
+    ///     same as `AsyncServerRunner.close()`.
+    ///
+    fn close(&self, py: Python) {
+        close_runner(py, &self.runner);
+    }
+
+    ///
+    /// PythonMethod: Server.wait_closed() -> awaitable
+    ///
+    ///     Waits for the background task to finish once the server has
+    ///     been closed, cancelling the wait doesn't cancel the server.
+    ///
+    fn wait_closed(&self, py: Python) -> PyResult<PyObject> {
+        let waiter = py.import("asyncio")?.call1("shield", (self.task.clone_ref(py),))?;
+        Ok(waiter.into())
+    }
+
+    ///
+    /// PythonMethod: Server.sockets -> list[socket.socket]
+    ///
+    ///     The listening sockets, see `AsyncServerRunner.sockets`.
+    ///
+    #[getter]
+    fn sockets(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        self.runner.borrow(py).sockets(py)
+    }
+
+    ///
+    /// PythonMethod: Server.is_serving() -> bool
+    ///
+    ///     If the server is accepting connections, see
+    ///     `AsyncServerRunner.is_serving()`.
+    ///
+    fn is_serving(&self, py: Python) -> bool {
+        self.runner.borrow(py).is_serving()
+    }
+
+    #[getter]
+    fn runner(&self, py: Python) -> Py<AsyncServerRunner> {
+        self.runner.clone_ref(py)
+    }
+
+    ///
+    /// PythonMethod: Server.__aenter__() -> awaitable Server
+    ///
+    ///     The server is already accepting so there's nothing to wait for,
+    ///     entering the same server twice (nested or not) is a RuntimeError.
+    ///
+    fn __aenter__(mut slf: PyRefMut<Self>, py: Python) -> PyResult<Ready> {
+        slf.enter()?;
+        Ok(Ready { value: Some(slf.into_py(py)) })
+    }
+
+    ///
+    /// PythonMethod: Server.__aexit__(exc_type, exc, traceback) -> awaitable
+    ///
+    ///     Closes the server and waits for it to finish, whether or not the
+    ///     body raised. Exceptions from the body aren't suppressed.
+    ///
+    fn __aexit__(&self, py: Python, _exc_type: &PyAny, _exc: &PyAny, _traceback: &PyAny) -> ServerExit {
+        self.exit(py)
+    }
+}
+
+
+/// Closes the runner behind a Server, letting the background task finish cleanly even if it hasn't started.
+fn close_runner(py: Python, runner: &Py<AsyncServerRunner>) {
+    let mut runner = runner.borrow_mut(py);
+    runner.close(py);
+
+    // the task hasn't got to the runner yet, it still will and that should
+    // just finish rather than fail with "server is closed"
+    if !runner.awaited {
+        runner.server_state = ServerState::Draining;
+    }
+}
+
+
+///
+/// ServerExit is the awaitable behind `__aexit__`, it closes the runner on
+/// its first step and then waits (via `asyncio.wait` so the runner's own
+/// outcome isn't raised here) for the background task to finish.
+///
+#[pyclass]
+pub struct ServerExit {
+    runner: Option<Py<AsyncServerRunner>>,  // Taken when we close it
+    task: PyObject,
+    waiting: Option<PyObject>,              // The iterator of the `asyncio.wait` on the task
+}
+
+#[pyproto]
+impl PyAsyncProtocol for ServerExit {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for ServerExit {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<PyObject, bool>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+
+        if let Some(runner) = slf.runner.take() {
+            close_runner(py, &runner);
+
+            let tasks = PyTuple::new(py, &[slf.task.clone_ref(py)]);
+            let wait = py.import("asyncio")?.call1("wait", (tasks,))?;
+            slf.waiting = Some(wait.call_method0("__await__")?.into());
+        }
+
+        let waiting = match slf.waiting.as_ref() {
+            Some(waiting) => waiting,
+            None => return Ok(IterNextOutput::Return(false)),
+        };
+
+        match waiting.call_method0(py, "__next__") {
+            Ok(yielded) => Ok(IterNextOutput::Yield(yielded)),
+            Err(e) if e.is_instance::<PyStopIteration>(py) => {
+                slf.waiting = None;
+                Ok(IterNextOutput::Return(false))
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use pyo3::prelude::*;
+use pyo3::class::gc::{PyTraverseError, PyVisit};
+use pyo3::class::iter::IterNextOutput;
+use pyo3::exceptions::PyStopIteration;
+use pyo3::types::PyTuple;
+
+
+///
+/// LoopSleeper houses the intenal functions for creating a non-blocking
+/// delay on the event loop to save cpu, anything that polls a socket from
+/// its `__next__` (the tcp runner, the datagram runner etc...) keeps one of
+/// these around and yields from it whenever there is nothing to do.
+///
+/// With a backoff every sleep which runs its course doubles the delay for
+/// the next one up to `max_delay`, `reset()` drops it back to `min_delay`
+/// once there is something to do again (or `settle()` does, for an owner
+/// with nothing better to go on than whether it slept). `wake()` ends the
+/// sleep in progress early, for when the owner has been told something
+/// (to stop, say) that can't wait for the sleep to run out.
+///
+pub(crate) struct LoopSleeper {
+    pub(crate) loop_: PyObject, // The asyncio event loop
+    fut: Option<Py<PyAny>>,     // The temporary future to house the sleep future to save CPU
+    sleeping: Option<PyObject>, // The future `fut` is awaiting, what `wake()` resolves
+    delay: f32,                 // the delay between loop iterations.
+    min_delay: f32,             // Where the delay starts and goes back to on `reset()`
+    max_delay: f32,             // The most the delay backs off to, the same as `min_delay` without a backoff
+    slept: bool,                // Whether we've been slept on since the last `settle()`
+}
+
+impl LoopSleeper {
+    pub(crate) fn new(loop_: PyObject, delay: f32) -> Self {
+        Self::with_backoff(loop_, delay, delay)
+    }
+
+    ///
+    /// A sleeper for one of a connection's polls (a read or write that
+    /// would block, a dial), backing off from `CONNECTION_POLL_DELAY` to
+    /// `CONNECTION_MAX_POLL_DELAY` while the socket stays quiet.
+    ///
+    pub(crate) fn for_connection(loop_: PyObject) -> Self {
+        Self::with_backoff(loop_, crate::CONNECTION_POLL_DELAY, crate::CONNECTION_MAX_POLL_DELAY)
+    }
+
+    pub(crate) fn with_backoff(loop_: PyObject, min_delay: f32, max_delay: f32) -> Self {
+        Self {
+            loop_,
+            fut: None,
+            sleeping: None,
+            delay: min_delay,
+            min_delay,
+            max_delay,
+            slept: false,
+        }
+    }
+
+    /// The python objects we hold, for an owner's `__traverse__`.
+    pub(crate) fn traverse(&self, visit: PyVisit) -> Result<(), PyTraverseError> {
+        visit.call(&self.loop_)?;
+        if let Some(fut) = self.fut.as_ref() {
+            visit.call(fut)?;
+        }
+        if let Some(sleeping) = self.sleeping.as_ref() {
+            visit.call(sleeping)?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Drops the sleep in progress for an owner's `__clear__`, the future
+    /// is how the task sleeping on it comes back round to the owner.
+    ///
+    pub(crate) fn clear(&mut self) {
+        self.fut = None;
+        self.sleeping = None;
+    }
+
+    /// Goes back to the shortest delay, the sleep already running is left alone.
+    pub(crate) fn reset(&mut self) {
+        self.delay = self.min_delay;
+    }
+
+    ///
+    /// For an owner to call after each of its `__next__`s, one that didn't
+    /// sleep got on with something (a read, a write, stepping a handler)
+    /// so the next sleep starts from `min_delay` again. Only a run of
+    /// `__next__`s that all found nothing to do backs the delay off.
+    ///
+    pub(crate) fn settle(&mut self) {
+        if !self.slept {
+            self.reset();
+        }
+        self.slept = false;
+    }
+
+    ///
+    /// Internal Method: LoopSleeper.wake()
+    ///
+    ///     Ends the sleep in progress (if there is one) on the loop's next
+    ///     iteration rather than whenever its `call_later` fires, so the
+    ///     task yielding from us comes straight back round to its owner.
+    ///     It goes through `call_soon_threadsafe` so this can be called
+    ///     from another thread too, the `call_later` still fires later on
+    ///     but finds the future already done.
+    ///
+    pub(crate) fn wake(&mut self, py: Python) {
+        if let Some(sleeping) = self.sleeping.take() {
+            let _ = Py::new(py, SleepWake { fut: sleeping })
+                .and_then(|wake| self.loop_.call_method1(py, "call_soon_threadsafe", (wake,)));
+        }
+    }
+
+    ///
+    /// Internal Method: LoopSleeper._sleep() -> PyResult<()>
+    ///
+    ///     _sleep recreates what asyncio.sleep() does with only the public
+    ///     parts of the loop and future, so it works the same under uvloop.
+    ///     A future from loop.create_future() is woken by loop.call_later()
+    ///     after `delay` (see SleepWake), and we keep the iterator from its
+    ///     `__await__` to yield from.
+    ///
+    ///     Requires:
+    ///         - py: Python
+    ///         - delay: f32
+    ///
+    fn _sleep(&mut self, py: Python, delay: f32) -> PyResult<()> {
+        let fut = self.loop_.call_method0(py, "create_future")?;
+        let wake = Py::new(py, SleepWake { fut: fut.clone_ref(py) })?;
+        self.loop_.call_method1(py, "call_later", (delay, wake))?;
+
+        self.fut = Some(fut.call_method0(py, "__await__")?);
+        self.sleeping = Some(fut);
+
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: LoopSleeper._iter_sleep() -> Option<PyObject>
+    ///
+    ///     _iter_sleep is what actually yields the next iteration the future,
+    ///     you could interprete this has `yield from` in python just with more
+    ///     steps involved.
+    ///
+    ///     Returns `None` once the sleep has finished, the next call after
+    ///     that starts a fresh sleep.
+    ///
+    ///     Requires:
+    ///         - py: Python
+    ///
+    pub(crate) fn _iter_sleep(&mut self, py: Python) -> Option<PyObject> {
+        self.slept = true;
+
+        // if the future isnt set we'll create a new one
+        if self.fut.is_none() {
+            let delay = self.delay;
+            let _ = self._sleep(py, delay);
+        }
+
+        let nxt = self.fut
+            .as_ref()
+            .unwrap()
+            .call_method0(py, "__next__");
+
+        match nxt {
+            Ok(f) => Some(f),
+            Err(_) => {
+                self.fut = None;
+                self.sleeping = None;
+                self.delay = (self.delay * 2.0).min(self.max_delay);
+
+                None
+            },
+        }
+    }
+
+    ///
+    /// Internal Method: LoopSleeper._iter_sleep_for() -> Option<PyObject>
+    ///
+    ///     The same as `_iter_sleep()` but for a sleep of exactly `delay`,
+    ///     for an owner that knows how long it has to wait (a throttled
+    ///     write) rather than polling until something turns up. A sleep
+    ///     that has already run its course is finished off first and the
+    ///     backoff is left as it was.
+    ///
+    ///     Requires:
+    ///         - py: Python
+    ///         - delay: f32
+    ///
+    pub(crate) fn _iter_sleep_for(&mut self, py: Python, delay: f32) -> Option<PyObject> {
+        self.slept = true;
+
+        if let Some(fut) = self.fut.as_ref() {
+            if let Ok(f) = fut.call_method0(py, "__next__") {
+                return Some(f)
+            }
+        }
+
+        self.fut = None;
+        self.sleeping = None;
+        self._sleep(py, delay).ok()?;
+
+        self.fut
+            .as_ref()
+            .unwrap()
+            .call_method0(py, "__next__")
+            .ok()
+    }
+}
+
+
+///
+/// Awaiting is the other half of awaiting from one of our `__next__`s,
+/// where LoopSleeper is a sleep we start ourselves this is any python
+/// awaitable (a coroutine, a future or task, one of our own awaitables)
+/// stepped until it's done the way `await` would, whatever it yields is
+/// passed on for the loop.
+///
+pub(crate) struct Awaiting {
+    iter: PyObject,     // What the awaitable's `__await__` gave back
+}
+
+impl Awaiting {
+    /// Starts awaiting `awaitable` if it is one (it has an `__await__`), `None` if it isn't.
+    pub(crate) fn of(awaitable: &PyAny) -> PyResult<Option<Self>> {
+        if !awaitable.hasattr("__await__")? {
+            return Ok(None)
+        }
+
+        Ok(Some(Self { iter: awaitable.call_method0("__await__")?.into() }))
+    }
+
+    ///
+    /// Internal Method: Awaiting::step() -> PyResult<IterNextOutput<PyObject, PyObject>>
+    ///
+    ///     Steps the awaitable once, `Yield` is what it yielded for the loop
+    ///     and `Return` the value it finished with. With `thrown` the error
+    ///     is thrown in rather than stepping it, like a `throw()` on the
+    ///     coroutine awaiting it. Anything else it raises is raised.
+    ///
+    pub(crate) fn step(&self, py: Python, thrown: Option<PyErr>) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        let stepped = match thrown {
+            Some(e) => self.iter.call_method1(py, "throw", (e.ptype(py), e.pvalue(py))),
+            None => self.iter.call_method0(py, "__next__"),
+        };
+
+        match stepped {
+            Ok(yielded) => Ok(IterNextOutput::Yield(yielded)),
+            Err(e) if e.is_instance::<PyStopIteration>(py) => {
+                Ok(IterNextOutput::Return(e.instance(py).getattr("value")?.into_py(py)))
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Throws an exception in, the way a `throw()` on whatever awaits us passes it on.
+    pub(crate) fn throw(&self, py: Python, type_: &PyAny, value: Option<&PyAny>, traceback: Option<&PyAny>) -> PyResult<PyObject> {
+        self.iter.call_method1(py, "throw", (type_, value, traceback))
+    }
+
+    /// The iterator itself, one of our own awaitables is its own iterator.
+    pub(crate) fn as_object(&self) -> &PyObject {
+        &self.iter
+    }
+}
+
+
+///
+/// SleepWake is the `call_later` callback ending a LoopSleeper's sleep,
+/// it leaves the future alone if it was cancelled in the meantime (the
+/// task awaiting it was) as `set_result` would raise. It ignores any
+/// arguments so it works as a done callback too.
+///
+#[pyclass]
+pub(crate) struct SleepWake {
+    fut: PyObject,
+}
+
+impl SleepWake {
+    pub(crate) fn new(fut: PyObject) -> Self {
+        Self { fut }
+    }
+}
+
+#[pymethods]
+impl SleepWake {
+    #[call]
+    #[args(_args = "*")]
+    fn __call__(&self, py: Python, _args: &PyTuple) -> PyResult<()> {
+        if !self.fut.call_method0(py, "done")?.as_ref(py).is_true()? {
+            self.fut.call_method1(py, "set_result", (py.None(),))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use pyo3::prelude::*;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+
+///
+/// Where request bodies bigger than `spool_threshold` are spooled to, the
+/// `spool_dir` or the system's temp directory.
+///
+#[derive(Clone, Debug)]
+pub(crate) struct SpoolPolicy {
+    pub(crate) threshold: usize,
+    pub(crate) dir: PathBuf,
+}
+
+impl SpoolPolicy {
+    /// If a body of `len` bytes is spooled rather than kept in memory.
+    pub(crate) fn spools(&self, len: usize) -> bool {
+        len > self.threshold
+    }
+}
+
+
+///
+/// Spool is a request body too big to keep in memory, written to an
+/// anonymous temp file as it arrives. On linux that's an `O_TMPFILE`
+/// which never has a name, on other unixes it's removed as soon as it's
+/// made, either way closing it is all it takes to free it. On windows it's
+/// opened to be deleted once the last handle to it goes, how python's
+/// `NamedTemporaryFile` does it, so however the request ends (dropped,
+/// cancelled, the connection failing part way through) nothing is left
+/// behind.
+///
+#[derive(Debug)]
+pub(crate) struct Spool {
+    file: File,
+    len: usize,     // How much has been written
+}
+
+impl Spool {
+    pub(crate) fn create(dir: &Path) -> io::Result<Self> {
+        Ok(Self { file: anonymous_file(dir)?, len: 0 })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.len += data.len();
+        Ok(())
+    }
+
+    ///
+    /// Internal Method: Spool::to_python() -> PyResult<PyObject>
+    ///
+    ///     A python file object (`io.BufferedReader`) over the body from the
+    ///     start, it has its own handle to the file so it can outlive the
+    ///     request but shares its position with any other made from it.
+    ///
+    pub(crate) fn to_python(&self, py: Python) -> PyResult<PyObject> {
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(0))?;
+        python_file(py, file)
+    }
+}
+
+#[cfg(unix)]
+fn python_file(py: Python, file: File) -> PyResult<PyObject> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    let fd = file.into_raw_fd();
+    match py.import("io")?.call_method1("open", (fd, "rb")) {
+        Ok(file) => Ok(file.into()),
+        Err(e) => {
+            // SAFETY: python didn't take the fd, it's still only ours to close
+            drop(unsafe { File::from_raw_fd(fd) });
+            Err(e)
+        },
+    }
+}
+
+#[cfg(windows)]
+fn python_file(py: Python, file: File) -> PyResult<PyObject> {
+    use std::os::windows::io::{FromRawHandle, IntoRawHandle, RawHandle};
+
+    let handle = file.into_raw_handle();
+    let fd = match py.import("msvcrt")?.call_method1("open_osfhandle", (handle as isize, 0)) {
+        Ok(fd) => fd,
+        Err(e) => {
+            // SAFETY: msvcrt didn't take the handle, it's still only ours to close
+            drop(unsafe { File::from_raw_handle(handle as RawHandle) });
+            return Err(e)
+        },
+    };
+
+    // from here on the handle belongs to the fd
+    Ok(py.import("io")?.call_method1("open", (fd, "rb"))?.into())
+}
+
+#[cfg(target_os = "linux")]
+fn anonymous_file(dir: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let tmpfile = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_TMPFILE)
+        .mode(0o600)
+        .open(dir);
+
+    // not every filesystem supports O_TMPFILE
+    match tmpfile {
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EOPNOTSUPP) | Some(libc::EISDIR) | Some(libc::EINVAL)) => unlinked_file(dir),
+        tmpfile => tmpfile,
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn anonymous_file(dir: &Path) -> io::Result<File> {
+    unlinked_file(dir)
+}
+
+#[cfg(unix)]
+fn unlinked_file(dir: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let path = temp_path(dir, "async-rust-body")?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+#[cfg(windows)]
+fn anonymous_file(dir: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_SHARE_ALL: u32 = 0x7;                    // read, write and delete
+    const FILE_ATTRIBUTE_TEMPORARY: u32 = 0x100;
+    const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x0400_0000;
+
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .share_mode(FILE_SHARE_ALL)
+        .attributes(FILE_ATTRIBUTE_TEMPORARY)
+        .custom_flags(FILE_FLAG_DELETE_ON_CLOSE)
+        .open(temp_path(dir, "async-rust-body")?)
+}
+
+/// A path in `dir` no other file has, `<prefix>-<32 random hex digits>`.
+pub(crate) fn temp_path(dir: &Path, prefix: &str) -> io::Result<PathBuf> {
+    let mut random = [0; 16];
+    SystemRandom::new()
+        .fill(&mut random)
+        .map_err(|_| io::Error::other("no randomness for a temp file name"))?;
+    let name: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+
+    Ok(dir.join(format!("{}-{}", prefix, name)))
+}
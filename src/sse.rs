@@ -0,0 +1,288 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::{PyStopAsyncIteration, PyStopIteration, PyTypeError, PyValueError};
+use pyo3::types::{PyDict, PyString};
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::body::BodyStream;
+use crate::http::{self, HTTPResponse};
+use crate::sleep::SleepWake;
+
+
+/// The comment line sent down a stream that's been quiet for `heartbeat` seconds.
+const HEARTBEAT: &[u8] = b": heartbeat\n\n";
+
+
+///
+/// EventSourceResponse can be returned from the callback to send
+/// Server-Sent Events, `events` is an async iterator (usually an async
+/// generator) of events that are written in the `text/event-stream` format
+/// as they're produced. An event is a dict, or just the `data` as a str.
+///
+///     Requires:
+///         - events:       AsyncIterator[dict | str]
+///
+///     Optional:
+///         - status:       int             (defaults to 200)
+///         - headers:      dict
+///         - heartbeat:    float           (seconds of quiet before a `: heartbeat` comment is sent, defaults to 15, None turns it off)
+///
+///     Event keys:
+///         - data:         str             (split over `data:` lines where it has newlines)
+///         - event:        str
+///         - id:           str
+///         - retry:        int             (milliseconds the browser waits before reconnecting)
+///
+///     The response is never compressed and goes out with
+///     `Cache-Control: no-cache, no-transform` and `X-Accel-Buffering: no`
+///     (unless `headers` says otherwise) so proxies pass events straight
+///     on. The stream isn't subject to `handler_timeout` and a client that
+///     disconnects has the iterator cancelled.
+///
+#[pyclass]
+pub struct EventSourceResponse {
+    events: PyObject,
+
+    #[pyo3(get, set)]
+    status: u16,
+
+    headers: Vec<(String, String)>,
+    heartbeat: Option<f64>,
+}
+
+#[pymethods]
+impl EventSourceResponse {
+    #[new]
+    #[args(status = "200", headers = "None", heartbeat = "15.0")]
+    fn new(events: &PyAny, status: u16, headers: Option<&PyDict>, heartbeat: Option<f64>) -> PyResult<Self> {
+        if !BodyStream::is_stream(events)? {
+            return Err(PyTypeError::new_err("events must be an async iterator"))
+        }
+
+        if heartbeat.is_some_and(|heartbeat| !(heartbeat > 0.0 && heartbeat.is_finite())) {
+            return Err(PyValueError::new_err("heartbeat must be a positive number of seconds"))
+        }
+
+        let mut pairs = Vec::new();
+        if let Some(headers) = headers {
+            for (name, value) in headers.iter() {
+                pairs.push((name.extract()?, value.extract()?));
+            }
+        }
+
+        Ok(Self {
+            events: events.into(),
+            status,
+            headers: pairs,
+            heartbeat,
+        })
+    }
+}
+
+impl EventSourceResponse {
+    /// The head written before the first event, the defaults are added to whatever `headers` the handler gave.
+    pub(crate) fn head(&self) -> HTTPResponse {
+        let mut headers = self.headers.clone();
+        let defaults = [
+            ("Content-Type", "text/event-stream; charset=utf-8"),
+            ("Cache-Control", "no-cache, no-transform"),
+            ("X-Accel-Buffering", "no"),
+        ];
+
+        for (name, value) in defaults.iter() {
+            if !headers.iter().any(|(set, _)| set.eq_ignore_ascii_case(name)) {
+                headers.push((name.to_string(), value.to_string()));
+            }
+        }
+
+        HTTPResponse::from_parts(self.status, headers, Vec::new())
+    }
+
+    /// The body, which is waited on from a task of its own so heartbeats can go out in between events.
+    pub(crate) fn stream(&self, py: Python, loop_: &PyObject, chunked: bool) -> PyResult<BodyStream> {
+        let source = EventSource {
+            loop_: loop_.clone_ref(py),
+            heartbeat: self.heartbeat.map(Duration::from_secs_f64),
+            next: None,
+            wait: None,
+            quiet_since: Instant::now(),
+        };
+
+        BodyStream::events(py, self.events.as_ref(py), chunked, source)
+    }
+}
+
+
+///
+/// EventSource is how a BodyStream waits on an EventSourceResponse's
+/// iterator. Unlike an ordinary streamed body the `__anext__()` runs as a
+/// task of its own and we wait on a future woken by whichever comes first,
+/// that task finishing or the heartbeat coming due, since the iterator
+/// itself has no way of telling us it's been quiet for too long.
+///
+pub(crate) struct EventSource {
+    loop_: PyObject,
+    heartbeat: Option<Duration>,
+    next: Option<PyObject>,                 // The task running the iterator's `__anext__()`
+    wait: Option<(PyObject, PyObject)>,     // The `__await__` of the future we're waiting on, and the heartbeat timer to call off
+    quiet_since: Instant,                   // When anything last went out
+}
+
+///
+/// Where an EventSource got to, `Data` is an event (or heartbeat) ready to
+/// be framed.
+///
+pub(crate) enum SourceStep {
+    Yield(PyObject),
+    Data(Vec<u8>),
+    Done,
+}
+
+impl EventSource {
+    ///
+    /// Internal Method: EventSource::step() -> PyResult<SourceStep>
+    ///
+    ///     Moves the iterator along, giving back the next event once it's
+    ///     been produced or a heartbeat if it's due first. An error the
+    ///     iterator raises comes back as it is.
+    ///
+    pub(crate) fn step(&mut self, py: Python, iterator: &PyObject) -> PyResult<SourceStep> {
+        loop {
+            if let Some((waiting, _)) = self.wait.as_ref() {
+                match waiting.call_method0(py, "__next__") {
+                    Ok(yielded) => return Ok(SourceStep::Yield(yielded)),
+                    Err(e) if e.is_instance::<PyStopIteration>(py) => self.stop_waiting(py),
+                    Err(e) => {
+                        self.stop_waiting(py);
+                        return Err(e)
+                    },
+                }
+            }
+
+            let next = match self.next.as_ref() {
+                Some(next) => next.clone_ref(py),
+                None => {
+                    let anext = iterator.call_method0(py, "__anext__")?;
+                    let task: PyObject = py.import("asyncio")?.call1("ensure_future", (anext,))?.into();
+                    self.next = Some(task.clone_ref(py));
+                    task
+                },
+            };
+
+            if next.call_method0(py, "done")?.is_true(py)? {
+                self.next = None;
+                self.quiet_since = Instant::now();
+                return match next.call_method0(py, "result") {
+                    Ok(event) => Ok(SourceStep::Data(format_event(event.as_ref(py))?)),
+                    Err(e) if e.is_instance::<PyStopAsyncIteration>(py) => Ok(SourceStep::Done),
+                    Err(e) => Err(e),
+                }
+            }
+
+            let due = self.heartbeat.map(|heartbeat| heartbeat.saturating_sub(self.quiet_since.elapsed()));
+            if due == Some(Duration::ZERO) {
+                self.quiet_since = Instant::now();
+                return Ok(SourceStep::Data(HEARTBEAT.to_vec()))
+            }
+
+            let fut = self.loop_.call_method0(py, "create_future")?;
+            next.call_method1(py, "add_done_callback", (Py::new(py, SleepWake::new(fut.clone_ref(py)))?,))?;
+            let timer = match due {
+                Some(due) => {
+                    let wake = Py::new(py, SleepWake::new(fut.clone_ref(py)))?;
+                    self.loop_.call_method1(py, "call_later", (due.as_secs_f64(), wake))?
+                },
+                None => py.None(),
+            };
+
+            self.wait = Some((fut.call_method0(py, "__await__")?, timer));
+        }
+    }
+
+    fn stop_waiting(&mut self, py: Python) {
+        if let Some((_, timer)) = self.wait.take() {
+            if !timer.is_none(py) {
+                let _ = timer.call_method0(py, "cancel");
+            }
+        }
+    }
+
+    /// Cancels the iterator's `__anext__()`, the client's gone (or we are).
+    pub(crate) fn cancel(&mut self, py: Python) {
+        self.stop_waiting(py);
+        if let Some(next) = self.next.take() {
+            let _ = next.call_method0(py, "cancel");
+        }
+    }
+}
+
+impl Drop for EventSource {
+    fn drop(&mut self) {
+        // a connection that went away mid write still has the task running
+        if self.next.is_some() || self.wait.is_some() {
+            Python::with_gil(|py| self.cancel(py));
+        }
+    }
+}
+
+///
+/// Internal Method: sse::format_event() -> PyResult<Vec<u8>>
+///
+///     An event in the `text/event-stream` format, a str is just the data.
+///     The `data` has a `data:` line for each of its lines, the other
+///     fields can't have line breaks in them at all.
+///
+fn format_event(event: &PyAny) -> PyResult<Vec<u8>> {
+    let mut out = Vec::new();
+    if let Ok(data) = event.downcast::<PyString>() {
+        write_data(data.to_str()?, &mut out);
+        out.push(b'\n');
+        return Ok(out)
+    }
+
+    let event: &PyDict = event.downcast().map_err(|_| PyTypeError::new_err("an event must be a dict or a str"))?;
+
+    let mut data = None;
+    for (key, value) in event.iter() {
+        let key: &str = key.extract()?;
+        if value.is_none() {
+            continue
+        }
+
+        match key {
+            "event" | "id" => {
+                let value: &str = value.extract()?;
+                if value.contains(['\r', '\n', '\0']) {
+                    return Err(PyValueError::new_err(format!("an event's {} can't contain line breaks", key)))
+                }
+
+                let _ = writeln!(out, "{}: {}", key, value);
+            },
+            "retry" => {
+                let retry: u64 = value.extract().map_err(|_| PyValueError::new_err("an event's retry must be a non-negative int"))?;
+                let _ = writeln!(out, "retry: {}", retry);
+            },
+            "data" => data = Some(http::body_to_bytes(value)?),
+            _ => return Err(PyTypeError::new_err(format!("an event got an unexpected key '{}'", key))),
+        }
+    }
+
+    if let Some(data) = data {
+        write_data(&String::from_utf8_lossy(&data), &mut out);
+    }
+
+    if out.is_empty() {
+        return Err(PyValueError::new_err("an event needs at least one of data, event, id or retry"))
+    }
+
+    out.push(b'\n');
+    Ok(out)
+}
+
+/// A `data:` line for each line of `data`, any of `\r\n`, `\r` or `\n` ends one.
+fn write_data(data: &str, out: &mut Vec<u8>) {
+    for line in data.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+        let _ = writeln!(out, "data: {}", line);
+    }
+}
@@ -0,0 +1,501 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+
+///
+/// The counters a runner keeps about itself, shared with every connection
+/// it spawns so they can be bumped without going back through python.
+/// Each listener has its own too (see `ServerStats::for_listener()`),
+/// anything counted against one is counted against the runner's as well.
+///
+#[derive(Default)]
+pub(crate) struct ServerStats {
+    total: Option<Arc<ServerStats>>,    // The runner's, when these are one listener's
+    accepted: AtomicU64,        // Connections accepted
+    active: AtomicU64,          // Connections currently being handled
+    requests: AtomicU64,        // Requests parsed and handed to a callback
+    bytes_written: AtomicU64,   // Bytes written to clients
+    body_bytes: AtomicU64,      // The part of those that was response bodies rather than heads
+    parse_errors: AtomicU64,    // Requests we couldn't parse
+    denied: AtomicU64,          // Connections refused by `allow_ips` / `deny_ips`
+    rate_limited: AtomicU64,    // Requests refused by `rate_limit`
+    shed_connections: AtomicU64,    // Connections refused for being over `memory_budget`
+    shed_requests: AtomicU64,   // Request bodies refused with a `503` for the same
+    reclaimed: AtomicU64,       // Buffer capacity idle connections gave back, see `AsyncServerRunner::housekeep()`
+}
+
+impl ServerStats {
+    /// The counters for one of a runner's listeners, which add up to `total`.
+    pub(crate) fn for_listener(total: Arc<ServerStats>) -> Arc<Self> {
+        Arc::new(Self { total: Some(total), ..Self::default() })
+    }
+
+    /// Adds `n` to `counter`, of the runner's too when these are a listener's.
+    fn add(&self, counter: impl Fn(&ServerStats) -> &AtomicU64, n: u64) {
+        counter(self).fetch_add(n, Ordering::Relaxed);
+        if let Some(total) = self.total.as_ref() {
+            counter(total).fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    ///
+    /// Counts a newly accepted connection, the guard marks it active until
+    /// it's dropped so a task erroring or being cancelled still gets counted
+    /// as finished.
+    ///
+    pub(crate) fn connection(self: &Arc<Self>) -> ActiveGuard {
+        self.add(|stats| &stats.accepted, 1);
+        self.add(|stats| &stats.active, 1);
+
+        ActiveGuard(self.clone())
+    }
+
+    pub(crate) fn request(&self) {
+        self.add(|stats| &stats.requests, 1);
+    }
+
+    pub(crate) fn parse_error(&self) {
+        self.add(|stats| &stats.parse_errors, 1);
+    }
+
+    pub(crate) fn denied(&self) {
+        self.add(|stats| &stats.denied, 1);
+    }
+
+    pub(crate) fn rate_limited(&self) {
+        self.add(|stats| &stats.rate_limited, 1);
+    }
+
+    pub(crate) fn shed_connection(&self) {
+        self.add(|stats| &stats.shed_connections, 1);
+    }
+
+    pub(crate) fn shed_request(&self) {
+        self.add(|stats| &stats.shed_requests, 1);
+    }
+
+    pub(crate) fn written(&self, n: u64) {
+        self.add(|stats| &stats.bytes_written, n);
+    }
+
+    /// Counts `n` bytes of buffers an idle connection freed.
+    pub(crate) fn reclaimed(&self, n: u64) {
+        self.add(|stats| &stats.reclaimed, n);
+    }
+
+    /// Counts `n` of the bytes passed to `written()` as response body.
+    pub(crate) fn body_written(&self, n: u64) {
+        self.add(|stats| &stats.body_bytes, n);
+    }
+
+    pub(crate) fn snapshot<'p>(&self, py: Python<'p>) -> PyResult<&'p PyDict> {
+        let dict = PyDict::new(py);
+        dict.set_item("connections_accepted", self.accepted.load(Ordering::Relaxed))?;
+        dict.set_item("connections_active", self.active.load(Ordering::Relaxed))?;
+        dict.set_item("requests", self.requests.load(Ordering::Relaxed))?;
+        dict.set_item("bytes_written", self.bytes_written.load(Ordering::Relaxed))?;
+        dict.set_item("body_bytes_written", self.body_bytes.load(Ordering::Relaxed))?;
+        dict.set_item("parse_errors", self.parse_errors.load(Ordering::Relaxed))?;
+        dict.set_item("connections_denied", self.denied.load(Ordering::Relaxed))?;
+        dict.set_item("rate_limited", self.rate_limited.load(Ordering::Relaxed))?;
+        dict.set_item("connections_shed", self.shed_connections.load(Ordering::Relaxed))?;
+        dict.set_item("requests_shed", self.shed_requests.load(Ordering::Relaxed))?;
+        dict.set_item("bytes_reclaimed", self.reclaimed.load(Ordering::Relaxed))?;
+
+        Ok(dict)
+    }
+
+    ///
+    /// Zeroes the counters, `active` is left alone since it's a count of
+    /// what's happening right now rather than a running total.
+    ///
+    pub(crate) fn reset(&self) {
+        self.accepted.store(0, Ordering::Relaxed);
+        self.requests.store(0, Ordering::Relaxed);
+        self.bytes_written.store(0, Ordering::Relaxed);
+        self.body_bytes.store(0, Ordering::Relaxed);
+        self.parse_errors.store(0, Ordering::Relaxed);
+        self.denied.store(0, Ordering::Relaxed);
+        self.rate_limited.store(0, Ordering::Relaxed);
+        self.shed_connections.store(0, Ordering::Relaxed);
+        self.shed_requests.store(0, Ordering::Relaxed);
+        self.reclaimed.store(0, Ordering::Relaxed);
+    }
+}
+
+
+///
+/// Where a `profiling=True` runner's time goes, the total nanoseconds
+/// spent in each section and how many times it was timed, shared with
+/// every connection like ServerStats. See `AsyncServerRunner.profile_snapshot()`.
+///
+#[derive(Default)]
+pub(crate) struct Profile {
+    nanos: [AtomicU64; 5],      // Indexed by Section
+    counts: [AtomicU64; 5],     // How many times each section was timed
+}
+
+/// The sections of work `Profile` times.
+#[derive(Clone, Copy)]
+pub(crate) enum Section {
+    Accept,     // accept() on the listeners, found a client or not
+    Read,       // Reads off the socket, decryption included
+    Parse,      // Parsing request heads, chunked bodies and HTTP/2 frames
+    Handler,    // Calling the callback and stepping what it returned
+    Write,      // Writes to the socket, encryption and sendfile included
+}
+
+const SECTION_NAMES: [&str; 5] = ["accept", "read", "parse", "handler", "write"];
+
+impl Profile {
+    pub(crate) fn record(&self, section: Section, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.nanos[section as usize].fetch_add(nanos, Ordering::Relaxed);
+        self.counts[section as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot<'p>(&self, py: Python<'p>) -> PyResult<&'p PyDict> {
+        let dict = PyDict::new(py);
+        for (i, name) in SECTION_NAMES.iter().enumerate() {
+            let section = PyDict::new(py);
+            section.set_item("ns", self.nanos[i].load(Ordering::Relaxed))?;
+            section.set_item("count", self.counts[i].load(Ordering::Relaxed))?;
+            dict.set_item(name, section)?;
+        }
+
+        Ok(dict)
+    }
+
+    pub(crate) fn reset(&self) {
+        for (nanos, count) in self.nanos.iter().zip(self.counts.iter()) {
+            nanos.store(0, Ordering::Relaxed);
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+///
+/// Runs `op`, adding how long it took to `section` of `profile`. Without
+/// a profile this is the one branch and `op`, so it can go around hot code.
+///
+#[inline]
+pub(crate) fn timed<T>(profile: Option<&Profile>, section: Section, op: impl FnOnce() -> T) -> T {
+    let profile = match profile {
+        Some(profile) => profile,
+        None => return op(),
+    };
+
+    let started = Instant::now();
+    let result = op();
+    profile.record(section, started.elapsed());
+    result
+}
+
+
+///
+/// Held by a connection for as long as it's active.
+///
+pub(crate) struct ActiveGuard(Arc<ServerStats>);
+
+impl ActiveGuard {
+    pub(crate) fn stats(&self) -> &ServerStats {
+        &self.0
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+        if let Some(total) = self.0.total.as_ref() {
+            total.active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+
+///
+/// What a single connection has been up to, updated by the connection as
+/// it goes and read back through its ConnectionInfo.
+///
+pub(crate) struct ConnectionActivity {
+    peer: Mutex<Option<(String, u16)>>, // The client's (host, port) if we could get it
+    proxy: Mutex<Option<Proxied>>,      // What the PROXY protocol header said, if there was one
+    requests: AtomicU64,                // Requests handed to the callback so far
+    current: Mutex<Option<String>>,     // The request line being handled, for reporting a task that fails
+    last_active: Mutex<Instant>,        // When we last read or wrote anything
+    draining: AtomicBool,               // Set by `transfer_and_drain()`, the connection closes once it's between requests
+    reclaim: AtomicBool,                // Set by the runner's housekeeping once we've been idle a while, see `take_reclaim()`
+}
+
+/// A connection that came to us through a proxy using the PROXY protocol.
+struct Proxied {
+    family: &'static str,
+    via: Option<(String, u16)>,     // The proxy's end of the connection
+}
+
+impl Default for ConnectionActivity {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl ConnectionActivity {
+    pub(crate) fn new(peer: Option<(String, u16)>) -> Self {
+        Self {
+            peer: Mutex::new(peer),
+            proxy: Mutex::new(None),
+            requests: AtomicU64::new(0),
+            current: Mutex::new(None),
+            last_active: Mutex::new(Instant::now()),
+            draining: AtomicBool::new(false),
+            reclaim: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    ///
+    /// Records the PROXY protocol header, from then on `peer` is the
+    /// client it names (if it names one) rather than the proxy.
+    ///
+    pub(crate) fn proxied(&self, family: &'static str, client: Option<(String, u16)>) {
+        let mut peer = match self.peer.lock() {
+            Ok(peer) => peer,
+            Err(_) => return,
+        };
+
+        let via = match client {
+            Some(client) => peer.replace(client),
+            None => peer.clone(),
+        };
+
+        if let Ok(mut proxy) = self.proxy.lock() {
+            *proxy = Some(Proxied { family, via });
+        }
+    }
+
+    fn peer(&self) -> Option<(String, u16)> {
+        self.peer.lock().ok().and_then(|peer| peer.clone())
+    }
+
+    /// Records the request line of the request being handled, None once it's done.
+    pub(crate) fn set_request(&self, request: Option<String>) {
+        if let Ok(mut current) = self.current.lock() {
+            *current = request;
+        }
+    }
+
+    /// How long it's been since we last read or wrote anything.
+    pub(crate) fn idle(&self) -> Duration {
+        self.last_active
+            .lock()
+            .map(|last_active| last_active.elapsed())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn touch(&self) {
+        if let Ok(mut last_active) = self.last_active.lock() {
+            *last_active = Instant::now();
+        }
+    }
+
+    /// If the runner wants the connection closed once the request in hand (if any) has been answered.
+    pub(crate) fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// If the runner's housekeeping has asked us to free our idle buffers since we last looked.
+    pub(crate) fn take_reclaim(&self) -> bool {
+        self.reclaim.swap(false, Ordering::Relaxed)
+    }
+}
+
+
+///
+/// When each stage of a request happened, shared between the connection
+/// recording them and the HTTPRequest reporting them as `request.timings`
+/// so stages after the handler's been given the request still show up.
+/// Everything is measured from when the connection was accepted.
+///
+#[derive(Debug)]
+pub(crate) struct RequestTimings {
+    accepted: Instant,                  // When the connection was accepted
+    stages: Mutex<[Option<Instant>; 5]>, // When each `Stage` happened, None until it has
+}
+
+/// The stages of a request `RequestTimings` records, in the order they happen.
+#[derive(Clone, Copy)]
+pub(crate) enum Stage {
+    Headers,        // The head has been read
+    Body,           // All of the body has been read
+    HandlerStart,   // The callback's been called
+    HandlerEnd,     // The callback returned (or raised), not when it was cut off by `handler_timeout`
+    Flushed,        // The response is all written
+}
+
+const STAGE_NAMES: [&str; 5] = ["headers", "body", "handler_start", "handler_end", "flushed"];
+
+impl RequestTimings {
+    pub(crate) fn new(accepted: Instant) -> Self {
+        Self { accepted, stages: Mutex::new([None; 5]) }
+    }
+
+    /// Records `stage` as having happened now, unless it already has.
+    pub(crate) fn mark(&self, stage: Stage) {
+        self.mark_at(stage, Instant::now());
+    }
+
+    pub(crate) fn mark_at(&self, stage: Stage, at: Instant) {
+        if let Ok(mut stages) = self.stages.lock() {
+            stages[stage as usize].get_or_insert(at);
+        }
+    }
+
+    /// The handler's finished, only if it was ever started.
+    pub(crate) fn handler_done(&self) {
+        if self.at(Stage::HandlerStart).is_some() {
+            self.mark(Stage::HandlerEnd);
+        }
+    }
+
+    fn at(&self, stage: Stage) -> Option<Instant> {
+        self.stages.lock().ok()?[stage as usize]
+    }
+
+    ///
+    /// Seconds from the head arriving (or the connection being accepted
+    /// when it never did) to the response being flushed, or to now if it
+    /// hasn't been yet.
+    ///
+    pub(crate) fn total(&self) -> f64 {
+        let start = self.at(Stage::Headers).unwrap_or(self.accepted);
+        let end = self.at(Stage::Flushed).unwrap_or_else(Instant::now);
+        end.saturating_duration_since(start).as_secs_f64()
+    }
+
+    /// Seconds the handler took, None if it never started or never finished.
+    pub(crate) fn handler(&self) -> Option<f64> {
+        let start = self.at(Stage::HandlerStart)?;
+        let end = self.at(Stage::HandlerEnd)?;
+        Some(end.saturating_duration_since(start).as_secs_f64())
+    }
+
+    ///
+    /// The `request.timings` dict, `accept` (always `0.0`) and each stage
+    /// in seconds since then, a stage that hasn't happened is `None`.
+    ///
+    pub(crate) fn to_dict<'p>(&self, py: Python<'p>) -> PyResult<&'p PyDict> {
+        let stages = self.stages.lock().map(|stages| *stages).unwrap_or([None; 5]);
+
+        let dict = PyDict::new(py);
+        dict.set_item("accept", 0.0)?;
+        for (name, at) in STAGE_NAMES.iter().zip(stages.iter()) {
+            let offset = at.map(|at| at.saturating_duration_since(self.accepted).as_secs_f64());
+            dict.set_item(name, offset)?;
+        }
+
+        Ok(dict)
+    }
+}
+
+///
+/// ConnectionInfo describes one of a runner's live connections, see
+/// `AsyncServerRunner.connections()`. The values are read when they're
+/// asked for so holding on to one keeps showing the connection as it is.
+///
+#[pyclass]
+pub struct ConnectionInfo {
+    activity: Arc<ConnectionActivity>,
+    task: PyObject,
+    listener: Arc<str>,     // The name of the listener that accepted it
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(activity: Arc<ConnectionActivity>, task: PyObject, listener: Arc<str>) -> Self {
+        Self { activity, task, listener }
+    }
+
+    /// If this is the connection from `peer`.
+    pub(crate) fn is_peer(&self, peer: &(String, u16)) -> bool {
+        self.activity.peer().as_ref() == Some(peer)
+    }
+
+    /// The request line of the request being handled, if there is one.
+    pub(crate) fn current_request(&self) -> Option<String> {
+        self.activity.current.lock().ok().and_then(|current| current.clone())
+    }
+
+    /// Asks the connection to close once it's between requests, see `ConnectionActivity::draining()`.
+    pub(crate) fn drain(&self) {
+        self.activity.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Asks the connection to free its buffers if it's been idle for `after`, see `ConnectionActivity::take_reclaim()`.
+    pub(crate) fn reclaim(&self, after: Duration) {
+        if self.activity.idle() >= after {
+            self.activity.reclaim.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn cancel(&self, py: Python) -> PyResult<()> {
+        self.task.call_method0(py, "cancel")?;
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl ConnectionInfo {
+    /// The client's `(host, port)`, `None` if it couldn't be read. Behind
+    /// the PROXY protocol this is the client the proxy says it is.
+    #[getter]
+    pub(crate) fn peer(&self) -> Option<(String, u16)> {
+        self.activity.peer()
+    }
+
+    /// The address family from the PROXY protocol header (`"TCP4"`,
+    /// `"TCP6"`, `"UNKNOWN"`...), `None` without `proxy_protocol=True`.
+    #[getter]
+    fn proxy_family(&self) -> Option<&'static str> {
+        self.activity.proxy.lock().ok()?.as_ref().map(|proxy| proxy.family)
+    }
+
+    /// The `(host, port)` of the proxy the connection actually came from
+    /// when it sent a PROXY protocol header.
+    #[getter]
+    fn proxy(&self) -> Option<(String, u16)> {
+        self.activity.proxy.lock().ok()?.as_ref().and_then(|proxy| proxy.via.clone())
+    }
+
+    /// How many requests have been handed to the callback on this connection.
+    #[getter]
+    fn requests(&self) -> u64 {
+        self.activity.requests.load(Ordering::Relaxed)
+    }
+
+    /// Seconds since anything was last read from or written to the client.
+    #[getter]
+    fn idle(&self) -> f64 {
+        self.activity.idle().as_secs_f64()
+    }
+
+    /// The asyncio task driving the connection.
+    #[getter]
+    fn task(&self, py: Python) -> PyObject {
+        self.task.clone_ref(py)
+    }
+
+    /// The name of the listener that accepted the connection, its address
+    /// (`"127.0.0.1:8080"`, `"unix:/run/app.sock"`) unless it was given one.
+    #[getter]
+    fn listener(&self) -> &str {
+        &self.listener
+    }
+}
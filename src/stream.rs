@@ -0,0 +1,931 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::types::IntoPyDict;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::types::{PyByteArray, PyBytes};
+
+use std::net::TcpStream;
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex, MutexGuard};
+use bstr::ByteSlice;
+
+use crate::client::Redial;
+use crate::errors::ConnectionClosed;
+use crate::sleep::LoopSleeper;
+use crate::tls::{ClientTls, TlsSession};
+
+
+/// asyncio's default for how far `readline()` / `readuntil()` will look.
+const DEFAULT_LIMIT: usize = 64 * 1024;
+
+/// asyncio's default for how much a transport buffers before pausing writes.
+pub(crate) const DEFAULT_HIGH_WATER: usize = 64 * 1024;
+
+///
+/// Transport is a raw connection shared by its Reader and Writer, it's
+/// behind a mutex rather than owned by either so the two halves can be
+/// handed to python separately while TLS (which can't be split) keeps a
+/// single session.
+///
+pub(crate) struct Transport {
+    sock: Option<TcpStream>,            // The socket, None once closed
+    tls: Option<TlsSession>,            // The TLS session if the listener terminates TLS
+    buffer: Vec<u8>,                    // Bytes read off the socket but not yet consumed by a read
+    eof: bool,                          // Set once the peer has closed its side
+    out: Vec<u8>,                       // Bytes written by the Writer but not yet taken by the socket
+    closing: bool,                      // Set once the Writer has been closed
+    redial: Option<Box<Redial>>,        // For a pooled connection handed out again, until the server answers
+}
+
+pub(crate) type SharedTransport = Arc<Mutex<Transport>>;
+
+impl Transport {
+    pub(crate) fn new(sock: TcpStream, tls: Option<TlsSession>, buffer: Vec<u8>) -> SharedTransport {
+        Arc::new(Mutex::new(Self {
+            sock: Some(sock),
+            tls,
+            buffer,
+            eof: false,
+            out: Vec::new(),
+            closing: false,
+            redial: None,
+        }))
+    }
+
+    ///
+    /// Reads whatever the socket has into the buffer, `WouldBlock` when
+    /// there's nothing yet. A closed socket reads as EOF.
+    ///
+    /// Anything the Writer left buffered is pushed along first, asyncio's
+    /// transports send in the background and a redial's replay would
+    /// otherwise wait on a `drain()` nobody is going to make.
+    ///
+    fn read_more(&mut self) -> io::Result<()> {
+        if self.reconnecting()? {
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        if !self.out.is_empty() || self.tls.as_ref().is_some_and(TlsSession::is_handshaking) {
+            match self.flush() {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => return Err(e),
+            }
+
+            // the flush found the connection dead and a redial has started
+            if self.redial.as_ref().is_some_and(|redial| redial.is_dialing()) {
+                return Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+
+        match self.read_some() {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(self.lost(e)),
+            Ok(()) if self.eof && self.redial.is_some() => {
+                self.eof = false;
+                Err(self.lost(io::ErrorKind::UnexpectedEof.into()))
+            },
+            result => result,
+        }
+    }
+
+    fn read_some(&mut self) -> io::Result<()> {
+        let sock = match self.sock.as_ref() {
+            Some(sock) => sock,
+            None => {
+                self.eof = true;
+                return Ok(())
+            },
+        };
+
+        let mut chunk = [0; 4096];
+        let n = match self.tls.as_mut() {
+            Some(tls) => tls.read(sock, &mut chunk)?,
+            None => (&*sock).read(&mut chunk)?,
+        };
+
+        match n {
+            0 => self.eof = true,
+            n => {
+                // the server answered so the connection was alive, there's nothing to redial
+                self.redial = None;
+                self.buffer.extend_from_slice(&chunk[..n]);
+            },
+        }
+
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize) -> Vec<u8> {
+        let n = n.min(self.buffer.len());
+        self.buffer.drain(..n).collect()
+    }
+
+    ///
+    /// Writes as much of `out` as the socket takes, `Ok(true)` once all of
+    /// it (and with TLS everything rustls encrypted) is with the kernel.
+    /// The TLS handshake is finished first if nothing has been read yet.
+    ///
+    pub(crate) fn flush(&mut self) -> io::Result<bool> {
+        if self.reconnecting()? {
+            return Ok(false)
+        }
+
+        match self.send() {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => match self.lost(e) {
+                e if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                e => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    fn send(&mut self) -> io::Result<bool> {
+        let sock = match self.sock.as_ref() {
+            Some(sock) => sock,
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        };
+
+        if let Some(tls) = self.tls.as_mut() {
+            if !tls.handshake(sock)? {
+                return Ok(false)
+            }
+        }
+
+        while !self.out.is_empty() {
+            let n = match self.tls.as_mut() {
+                Some(tls) => tls.write(sock, &self.out),
+                None => (&*sock).write(&self.out),
+            };
+
+            match n {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => drop(self.out.drain(..n)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+
+        match self.tls.as_mut() {
+            Some(tls) => tls.flush(sock),
+            None => Ok(true),
+        }
+    }
+
+    /// Buffers `data` to be sent, keeping it for the redial too if there is one.
+    fn queue(&mut self, data: &[u8]) {
+        self.out.extend_from_slice(data);
+        if let Some(redial) = self.redial.as_mut() {
+            if !redial.record(data) {
+                self.redial = None;
+            }
+        }
+    }
+
+    ///
+    /// Internal Method: Transport::lost() -> io::Error
+    ///
+    ///     What a read or write that found the connection gone should give
+    ///     back, the error itself unless a redial can still be made and
+    ///     then WouldBlock while it's underway.
+    ///
+    fn lost(&mut self, e: io::Error) -> io::Error {
+        let lost = matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::NotConnected
+        );
+
+        match self.redial.as_mut() {
+            Some(redial) if lost && !redial.is_dialing() => {
+                redial.start();
+                io::ErrorKind::WouldBlock.into()
+            },
+            _ => e,
+        }
+    }
+
+    ///
+    /// Internal Method: Transport::reconnecting() -> io::Result<bool>
+    ///
+    ///     Moves a redial along, `true` while it's still connecting. Once it
+    ///     has, the new socket takes the dead one's place with everything
+    ///     written since the connection was handed out queued again.
+    ///
+    fn reconnecting(&mut self) -> io::Result<bool> {
+        let redial = match self.redial.as_mut() {
+            Some(redial) if redial.is_dialing() => redial,
+            _ => return Ok(false),
+        };
+
+        let sock = match redial.poll() {
+            Ok(Some(sock)) => sock,
+            Ok(None) => return Ok(true),
+            Err(e) => {
+                self.redial = None;
+                self.close();
+                return Err(e)
+            },
+        };
+
+        let (tls, sent) = self.redial.take().unwrap().finish()?;
+        if let Some(old) = self.sock.replace(sock) {
+            let _ = old.shutdown(std::net::Shutdown::Both);
+        }
+
+        self.tls = tls;
+        self.buffer.clear();
+        self.eof = false;
+        self.out = sent;
+        Ok(false)
+    }
+
+    ///
+    /// Gets a connection a ConnectionPool hands out again ready to redial
+    /// if it turns out to be dead, TLS or not the way it was first made.
+    ///
+    pub(crate) fn arm_redial(&mut self, tls: Option<ClientTls>) {
+        self.redial = self.sock
+            .as_ref()
+            .and_then(|sock| sock.peer_addr().ok())
+            .map(|addr| Box::new(Redial::new(addr, tls)));
+    }
+
+    ///
+    /// If a ConnectionPool can keep the connection for someone else: open,
+    /// with nothing left to send, nothing unread and nothing (not even EOF)
+    /// arriving from the server right now.
+    ///
+    pub(crate) fn is_reusable(&mut self) -> bool {
+        self.redial = None;
+        if self.closing || self.sock.is_none() || self.eof || !self.buffer.is_empty() || !self.out.is_empty() {
+            return false
+        }
+
+        matches!(self.read_some(), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+
+    ///
+    /// Internal Method: Transport::relay() -> io::Result<u64>
+    ///
+    ///     Moves whatever this connection has to read over to be sent on
+    ///     `to` and pushes `to` along, for a CONNECT tunnel (see
+    ///     `tunnel::Tunnel`). Reading stops while `to` has
+    ///     `DEFAULT_HIGH_WATER` waiting to go out so a fast end can't pile
+    ///     up what a slow one hasn't taken. How many bytes were moved, an
+    ///     error is either connection's.
+    ///
+    pub(crate) fn relay(&mut self, to: &mut Transport) -> io::Result<u64> {
+        let mut moved = 0;
+        while !self.eof && to.out.len() < DEFAULT_HIGH_WATER {
+            if self.buffer.is_empty() {
+                match self.read_more() {
+                    Ok(()) => {},
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            moved += self.buffer.len() as u64;
+            to.queue(&self.buffer);
+            self.buffer.clear();
+        }
+
+        match to.flush() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(moved),
+            Err(e) => Err(e),
+            Ok(_) => Ok(moved),
+        }
+    }
+
+    /// If the peer has closed and everything it sent has been taken.
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.eof && self.buffer.is_empty()
+    }
+
+    /// If nothing written is still waiting for the socket to take it.
+    pub(crate) fn is_flushed(&self) -> bool {
+        self.out.is_empty()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.sock.is_none()
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// Sends the TLS close_notify and closes the socket, readers see EOF from then on.
+    pub(crate) fn close(&mut self) {
+        self.redial = None;
+        if let (Some(tls), Some(sock)) = (self.tls.as_mut(), self.sock.as_ref()) {
+            tls.close(sock);
+        }
+
+        if let Some(sock) = self.sock.take() {
+            let _ = sock.shutdown(std::net::Shutdown::Both);
+        }
+        self.out.clear();
+    }
+}
+
+pub(crate) fn lock(transport: &SharedTransport) -> PyResult<MutexGuard<'_, Transport>> {
+    transport
+        .lock()
+        .map_err(|_| PyRuntimeError::new_err("the connection's state was poisoned by a panic"))
+}
+
+
+///
+/// Reader is the read half of a raw connection, modelled on
+/// `asyncio.StreamReader` so protocol code written against that ports
+/// over unchanged.
+///
+///     await reader.read(n=-1)              up to `n` bytes, everything until EOF for -1
+///     await reader.readline()              through the next `\n`, whatever is left at EOF
+///     await reader.readexactly(n)          exactly `n` bytes or IncompleteReadError
+///     await reader.readuntil(separator)    through `separator` or IncompleteReadError
+///     reader.at_eof()
+///
+#[pyclass]
+pub struct Reader {
+    transport: SharedTransport,
+    loop_: PyObject,                    // The asyncio event loop for the awaitables to sleep on
+    limit: usize,                       // How far readline / readuntil look for their separator
+}
+
+impl Reader {
+    pub(crate) fn new(transport: SharedTransport, loop_: PyObject) -> Self {
+        Self {
+            transport,
+            loop_,
+            limit: DEFAULT_LIMIT,
+        }
+    }
+
+    pub(crate) fn transport(&self) -> &SharedTransport {
+        &self.transport
+    }
+
+    fn op(&self, py: Python, kind: ReadKind) -> ReadOp {
+        ReadOp {
+            transport: self.transport.clone(),
+            kind,
+            limit: self.limit,
+            sleeper: LoopSleeper::for_connection(self.loop_.clone_ref(py)),
+        }
+    }
+}
+
+#[pymethods]
+impl Reader {
+    ///
+    /// PythonMethod: Reader.read(n=-1) -> awaitable bytes
+    ///
+    ///     Waits for at least one byte and gives back up to `n`, `b""` at
+    ///     EOF. With `n` < 0 it reads until EOF and returns the lot.
+    ///
+    #[args(n = "-1")]
+    fn read(&self, py: Python, n: isize) -> ReadOp {
+        match n {
+            n if n < 0 => self.op(py, ReadKind::ToEof),
+            n => self.op(py, ReadKind::Some(n as usize)),
+        }
+    }
+
+    ///
+    /// PythonMethod: Reader.readline() -> awaitable bytes
+    ///
+    ///     One line including its `\n`, if EOF comes first whatever was
+    ///     read is returned instead. A line longer than the limit raises
+    ///     ValueError and is discarded.
+    ///
+    fn readline(&self, py: Python) -> ReadOp {
+        self.op(py, ReadKind::Line)
+    }
+
+    ///
+    /// PythonMethod: Reader.readexactly(n) -> awaitable bytes
+    ///
+    ///     Raises `asyncio.IncompleteReadError` carrying the partial bytes
+    ///     if EOF comes before `n` bytes.
+    ///
+    fn readexactly(&self, py: Python, n: isize) -> PyResult<ReadOp> {
+        if n < 0 {
+            return Err(PyValueError::new_err("readexactly size can not be less than zero"))
+        }
+
+        Ok(self.op(py, ReadKind::Exactly(n as usize)))
+    }
+
+    ///
+    /// PythonMethod: Reader.readuntil(separator=b"\n") -> awaitable bytes
+    ///
+    ///     Everything up to and including `separator`, raises
+    ///     `asyncio.IncompleteReadError` at EOF and `asyncio.LimitOverrunError`
+    ///     (leaving the data buffered) if the limit is reached first.
+    ///
+    #[args(separator = "None")]
+    fn readuntil(&self, py: Python, separator: Option<&PyBytes>) -> PyResult<ReadOp> {
+        let separator = separator.map_or_else(|| b"\n".to_vec(), |sep| sep.as_bytes().to_vec());
+        if separator.is_empty() {
+            return Err(PyValueError::new_err("Separator should be at least one-byte string"))
+        }
+
+        Ok(self.op(py, ReadKind::Until(separator)))
+    }
+
+    ///
+    /// PythonMethod: Reader.at_eof() -> bool
+    ///
+    ///     If the client has closed its side and everything it sent has
+    ///     been read.
+    ///
+    fn at_eof(&self) -> PyResult<bool> {
+        let transport = lock(&self.transport)?;
+        Ok(transport.eof && transport.buffer.is_empty())
+    }
+}
+
+
+enum ReadKind {
+    Some(usize),
+    ToEof,
+    Line,
+    Exactly(usize),
+    Until(Vec<u8>),
+}
+
+/// Why a read finished without the data it was asked for.
+enum ReadError {
+    Incomplete(Vec<u8>, Option<usize>),
+    LimitOverrun(&'static str, usize),
+    LineTooLong(&'static str),
+}
+
+///
+/// ReadOp is the awaitable behind each of the Reader's methods, it polls
+/// the transport from `__next__` and sleeps on the loop while the socket
+/// would block, the same way the websocket's operations do.
+///
+#[pyclass]
+pub struct ReadOp {
+    transport: SharedTransport,
+    kind: ReadKind,
+    limit: usize,
+    sleeper: LoopSleeper,
+}
+
+impl ReadOp {
+    ///
+    /// Internal Method: ReadOp::complete() -> Option<Result<Vec<u8>, ReadError>>
+    ///
+    ///     Takes the result out of the buffer if what's there (and whether
+    ///     we're at EOF) is enough to finish the read, `None` to wait for
+    ///     more.
+    ///
+    fn complete(&self, transport: &mut Transport) -> Option<Result<Vec<u8>, ReadError>> {
+        let available = transport.buffer.len();
+
+        let separator: &[u8] = match &self.kind {
+            ReadKind::Some(0) => return Some(Ok(Vec::new())),
+            ReadKind::Some(n) => {
+                return match available > 0 || transport.eof {
+                    true => Some(Ok(transport.take(*n))),
+                    false => None,
+                }
+            },
+            ReadKind::ToEof => {
+                return match transport.eof {
+                    true => Some(Ok(transport.take(available))),
+                    false => None,
+                }
+            },
+            ReadKind::Exactly(n) => {
+                return match (available >= *n, transport.eof) {
+                    (true, _) => Some(Ok(transport.take(*n))),
+                    (false, true) => Some(Err(ReadError::Incomplete(transport.take(available), Some(*n)))),
+                    (false, false) => None,
+                }
+            },
+            ReadKind::Line => b"\n",
+            ReadKind::Until(separator) => separator,
+        };
+
+        let line = matches!(self.kind, ReadKind::Line);
+        match transport.buffer.find(separator) {
+            Some(i) if i + separator.len() > self.limit => {
+                if line {
+                    transport.take(i + separator.len());
+                    return Some(Err(ReadError::LineTooLong("Separator is found, but chunk is longer than limit")))
+                }
+
+                Some(Err(ReadError::LimitOverrun("Separator is found, but chunk is longer than limit", i)))
+            },
+            Some(i) => Some(Ok(transport.take(i + separator.len()))),
+            None if available > self.limit => {
+                if line {
+                    transport.take(available);
+                    return Some(Err(ReadError::LineTooLong("Separator is not found, and chunk exceed the limit")))
+                }
+
+                Some(Err(ReadError::LimitOverrun("Separator is not found, and chunk exceed the limit", available)))
+            },
+            None if transport.eof => {
+                let partial = transport.take(available);
+                match line {
+                    true => Some(Ok(partial)),
+                    false => Some(Err(ReadError::Incomplete(partial, None))),
+                }
+            },
+            None => None,
+        }
+    }
+}
+
+/// Turns a ReadError into the asyncio exception the same failure raises there.
+fn read_error(py: Python, e: ReadError) -> PyResult<PyErr> {
+    let asyncio = py.import("asyncio")?;
+
+    let err = match e {
+        ReadError::Incomplete(partial, expected) => {
+            asyncio.getattr("IncompleteReadError")?.call1((PyBytes::new(py, &partial), expected))?
+        },
+        ReadError::LimitOverrun(message, consumed) => {
+            asyncio.getattr("LimitOverrunError")?.call1((message, consumed))?
+        },
+        ReadError::LineTooLong(message) => return Ok(PyValueError::new_err(message)),
+    };
+
+    Ok(PyErr::from_instance(err))
+}
+
+#[pyproto]
+impl PyAsyncProtocol for ReadOp {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for ReadOp {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+        let this = &mut *slf;
+        let mut guard = lock(&this.transport)?;
+
+        loop {
+            match this.complete(&mut guard) {
+                Some(Ok(data)) => return Ok(IterNextOutput::Return(Some(PyBytes::new(py, &data).into()))),
+                Some(Err(e)) => return Err(read_error(py, e)?),
+                None => {},
+            }
+
+            let transport = &mut *guard;
+            match py.allow_threads(|| transport.read_more()) {
+                Ok(()) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        drop(guard);
+        Ok(IterNextOutput::Yield(this.sleeper._iter_sleep(py)))
+    }
+}
+
+
+///
+/// Writer is the write half of a raw connection, modelled on
+/// `asyncio.StreamWriter`.
+///
+///     writer.write(data)                   buffers `data`, nothing is awaited
+///     await writer.drain()                 waits for the buffer to be taken by the kernel
+///     writer.close()                       flushes what's buffered then closes
+///     await writer.wait_closed()
+///     writer.is_closing()
+///     writer.get_extra_info(name)          "peername", "sockname", "socket" or "fileno"
+///
+/// Once more than the high-water mark (the runner's `write_high_water`) is
+/// buffered `write()` gives a RuntimeWarning, `drain()` is what stops a
+/// fast producer from buffering without limit.
+///
+#[pyclass]
+pub struct Writer {
+    transport: SharedTransport,
+    loop_: PyObject,                    // The asyncio event loop for the awaitables to sleep on
+    high_water: usize,                  // How much can be buffered before write() warns
+    warned: bool,                       // If we've warned since the buffer was last drained
+    peer: Option<(String, u16)>,        // The peer's (host, port)
+    local: Option<(String, u16)>,       // Our end's (host, port)
+}
+
+impl Writer {
+    pub(crate) fn new(transport: SharedTransport, loop_: PyObject, high_water: usize) -> PyResult<Self> {
+        // kept so they can still be asked for once the socket has closed
+        let (peer, local) = {
+            let guard = lock(&transport)?;
+            let sock = guard.sock.as_ref();
+
+            (
+                sock.and_then(|s| host_port(s.peer_addr())),
+                sock.and_then(|s| host_port(s.local_addr())),
+            )
+        };
+
+        Ok(Self {
+            transport,
+            loop_,
+            high_water,
+            warned: false,
+            peer,
+            local,
+        })
+    }
+
+    pub(crate) fn transport(&self) -> &SharedTransport {
+        &self.transport
+    }
+
+    fn op(&self, py: Python, kind: WriteKind) -> WriteOp {
+        WriteOp {
+            transport: self.transport.clone(),
+            kind,
+            sleeper: LoopSleeper::for_connection(self.loop_.clone_ref(py)),
+        }
+    }
+}
+
+/// A socket the way `add_reader` and python's `socket` take it, an fd on unix and a SOCKET on windows.
+#[cfg(unix)]
+pub(crate) type RawSocket = std::os::unix::io::RawFd;
+#[cfg(windows)]
+pub(crate) type RawSocket = std::os::windows::io::RawSocket;
+
+#[cfg(unix)]
+pub(crate) fn raw_fd(sock: &impl std::os::unix::io::AsRawFd) -> RawSocket {
+    sock.as_raw_fd()
+}
+
+#[cfg(windows)]
+pub(crate) fn raw_fd(sock: &impl std::os::windows::io::AsRawSocket) -> RawSocket {
+    sock.as_raw_socket()
+}
+
+///
+/// A python socket over a duplicate of `fd`, it can be inspected or have
+/// options set on it but closing it leaves ours open.
+///
+#[cfg(unix)]
+pub(crate) fn dup_socket(py: Python, fd: RawSocket) -> PyResult<PyObject> {
+    let fd = py.import("os")?.call1("dup", (fd,))?;
+    let kwargs = [("fileno", fd)].into_py_dict(py);
+    Ok(py.import("socket")?.getattr("socket")?.call((), Some(kwargs))?.into())
+}
+
+///
+/// `os.dup()` only works on C runtime fds, a SOCKET is duplicated with
+/// python's own `socket.dup()` (WSADuplicateSocket) from a wrapper which is
+/// detached again so it never closes ours.
+///
+#[cfg(windows)]
+pub(crate) fn dup_socket(py: Python, fd: RawSocket) -> PyResult<PyObject> {
+    let kwargs = [("fileno", fd)].into_py_dict(py);
+    let ours = py.import("socket")?.getattr("socket")?.call((), Some(kwargs))?;
+    let dup = ours.call_method0("dup");
+    ours.call_method0("detach")?;
+    Ok(dup?.into())
+}
+
+fn host_port(addr: io::Result<std::net::SocketAddr>) -> Option<(String, u16)> {
+    addr.ok().map(|addr| (addr.ip().to_string(), addr.port()))
+}
+
+///
+/// Internal Method: stream::extra_info() -> PyResult<Option<PyObject>>
+///
+///     What `get_extra_info(name)` gives for a connection's socket the way
+///     asyncio transports do it, `None` for a name we don't know or once
+///     the socket has been closed.
+///
+///         - "peername" / "sockname"   `(host, port)` of the peer / our end
+///         - "socket"  a python socket over a `dup()` of the fd, closing it leaves ours open
+///         - "fileno"  the fd itself, it's still ours so it mustn't be closed
+///
+pub(crate) fn extra_info(py: Python, sock: Option<&TcpStream>, name: &str) -> PyResult<Option<PyObject>> {
+    let sock = match sock {
+        Some(sock) => sock,
+        None => return Ok(None),
+    };
+
+    let info = match name {
+        "peername" => host_port(sock.peer_addr()).map(|peer| peer.into_py(py)),
+        "sockname" => host_port(sock.local_addr()).map(|local| local.into_py(py)),
+        "socket" => Some(dup_socket(py, raw_fd(sock))?),
+        "fileno" => Some(raw_fd(sock).into_py(py)),
+        _ => None,
+    };
+
+    Ok(info)
+}
+
+#[pymethods]
+impl Writer {
+    ///
+    /// PythonMethod: Writer.write(data)
+    ///
+    ///     Buffers `data` and tries to send it straight away, whatever the
+    ///     socket doesn't take waits for `drain()`.
+    ///
+    ///     Requires:
+    ///         - data:     bytes or bytearray
+    ///
+    fn write(&mut self, py: Python, data: &PyAny) -> PyResult<()> {
+        let data = if let Ok(bytes) = data.downcast::<PyBytes>() {
+            bytes.as_bytes().to_vec()
+        } else if let Ok(array) = data.downcast::<PyByteArray>() {
+            array.to_vec()
+        } else {
+            return Err(PyTypeError::new_err("data must be bytes or bytearray"))
+        };
+
+        let buffered = {
+            let mut guard = lock(&self.transport)?;
+            if guard.closing || guard.sock.is_none() {
+                return Err(ConnectionClosed::new_err("the connection is closed"))
+            }
+
+            let transport = &mut *guard;
+            transport.queue(&data);
+
+            // only worth a syscall if nothing was already waiting on the socket
+            if transport.out.len() == data.len() {
+                match py.allow_threads(|| transport.flush()) {
+                    Ok(_) => {},
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            transport.out.len()
+        };
+
+        if buffered <= self.high_water {
+            self.warned = false;
+        } else if !self.warned {
+            self.warned = true;
+            PyErr::warn(
+                py,
+                py.import("builtins")?.getattr("RuntimeWarning")?,
+                "the write buffer is over its high-water mark, await writer.drain()",
+                1,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: Writer.drain() -> awaitable
+    ///
+    ///     Waits until everything written so far has been taken by the
+    ///     kernel, raising if the connection is lost first.
+    ///
+    fn drain(&mut self, py: Python) -> WriteOp {
+        self.warned = false;
+        self.op(py, WriteKind::Drain)
+    }
+
+    ///
+    /// PythonMethod: Writer.close()
+    ///
+    ///     Stops any more writes, what's already buffered is still sent
+    ///     before the connection is closed in the background.
+    ///
+    fn close(&self, py: Python) -> PyResult<()> {
+        {
+            let mut transport = lock(&self.transport)?;
+            if transport.closing {
+                return Ok(())
+            }
+            transport.closing = true;
+        }
+
+        let op = Py::new(py, self.op(py, WriteKind::Close))?;
+        py.import("asyncio")?.call1("ensure_future", (op,))?;
+        Ok(())
+    }
+
+    ///
+    /// PythonMethod: Writer.wait_closed() -> awaitable
+    ///
+    ///     Waits for the connection to be closed, by `close()` or the
+    ///     runner shutting down.
+    ///
+    fn wait_closed(&self, py: Python) -> WriteOp {
+        self.op(py, WriteKind::WaitClosed)
+    }
+
+    fn is_closing(&self) -> PyResult<bool> {
+        let transport = lock(&self.transport)?;
+        Ok(transport.closing || transport.sock.is_none())
+    }
+
+    ///
+    /// PythonMethod: Writer.get_extra_info(name, default=None)
+    ///
+    ///     See `extra_info()` for the names we know, anything else is
+    ///     `default`. "peername" and "sockname" are still there once the
+    ///     connection has closed, "socket" and "fileno" aren't.
+    ///
+    #[args(default = "None")]
+    fn get_extra_info(&self, py: Python, name: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        let info = match name {
+            "peername" => self.peer.clone().map(|peer| peer.into_py(py)),
+            "sockname" => self.local.clone().map(|local| local.into_py(py)),
+            name => extra_info(py, lock(&self.transport)?.sock.as_ref(), name)?,
+        };
+
+        Ok(info.or(default).unwrap_or_else(|| py.None()))
+    }
+}
+
+
+enum WriteKind {
+    Drain,
+    Close,
+    WaitClosed,
+}
+
+///
+/// WriteOp is the awaitable behind `drain()`, `wait_closed()` and the
+/// background close, it flushes the transport from `__next__` and sleeps
+/// on the loop whenever the socket would block.
+///
+#[pyclass]
+pub struct WriteOp {
+    transport: SharedTransport,
+    kind: WriteKind,
+    sleeper: LoopSleeper,
+}
+
+#[pyproto]
+impl PyAsyncProtocol for WriteOp {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for WriteOp {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+        let this = &mut *slf;
+        let mut guard = lock(&this.transport)?;
+
+        let done = match this.kind {
+            WriteKind::WaitClosed => guard.sock.is_none(),
+            WriteKind::Drain if guard.sock.is_none() => {
+                return Err(ConnectionClosed::new_err("the connection is closed"))
+            },
+            WriteKind::Drain | WriteKind::Close => {
+                let transport = &mut *guard;
+                match py.allow_threads(|| transport.flush()) {
+                    Ok(flushed) => flushed,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+
+                    // a connection that's gone is as closed as it's going to get
+                    Err(_) if matches!(this.kind, WriteKind::Close) => true,
+                    Err(e) => return Err(e.into()),
+                }
+            },
+        };
+
+        if done {
+            if let WriteKind::Close = this.kind {
+                guard.close();
+            }
+
+            return Ok(IterNextOutput::Return(None))
+        }
+
+        drop(guard);
+        Ok(IterNextOutput::Yield(this.sleeper._iter_sleep(py)))
+    }
+}
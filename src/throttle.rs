@@ -0,0 +1,129 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+
+/// The most we'll wait to have saved up before writing, however fast the rate.
+const MAX_QUANTUM: u64 = 16 * 1024;
+
+
+///
+/// A token bucket of bytes, refilled at `rate` a second and holding at
+/// most a second's worth, so however long a connection has been idle it
+/// never bursts past a second of writing. That second is the window the
+/// rate is kept to.
+///
+struct Bucket {
+    rate: f64,
+    tokens: f64,            // Can go below zero when the shared bucket is spent by several connections at once
+    updated: Instant,       // When `tokens` was last topped up
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    ///
+    /// How much of `want` bytes can be written now, or the seconds until
+    /// enough can. Enough is 50ms worth (or all of `want` if it's less)
+    /// so a slow rate isn't written a byte at a time.
+    ///
+    fn allowance(&mut self, want: u64, now: Instant) -> Result<u64, f64> {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+
+        let quantum = ((self.rate / 20.0) as u64).clamp(1, MAX_QUANTUM);
+        let needed = want.min(quantum) as f64;
+        match self.tokens >= needed {
+            true => Ok((self.tokens as u64).min(want)),
+            false => Err((needed - self.tokens) / self.rate),
+        }
+    }
+
+    fn spend(&mut self, n: u64) {
+        self.tokens -= n as f64;
+    }
+}
+
+
+///
+/// WriteBudget is `max_total_write_rate_bytes_per_sec`, the one bucket
+/// every connection of a runner writes its bodies from on top of its own.
+///
+pub(crate) struct WriteBudget(Mutex<Bucket>);
+
+impl WriteBudget {
+    pub(crate) fn new(rate: u64) -> Self {
+        Self(Mutex::new(Bucket::new(rate)))
+    }
+
+    /// The bytes a second it's refilled at.
+    pub(crate) fn rate(&self) -> u64 {
+        self.0.lock().unwrap().rate as u64
+    }
+}
+
+
+///
+/// WriteThrottle keeps a connection's response bodies to
+/// `max_write_rate_bytes_per_sec` and the runner's WriteBudget, whichever
+/// is set. Only body bytes are counted, a head always goes out in full so
+/// however small the rate nothing waits on budget it can never get, and a
+/// write is cut down to what's allowed rather than held back until all of
+/// it would be.
+///
+pub(crate) struct WriteThrottle {
+    own: Option<Bucket>,    // None with only the runner wide limit
+}
+
+impl WriteThrottle {
+    /// A throttle for a new connection, None when neither limit is set.
+    pub(crate) fn new(rate: Option<u64>, budget: Option<&WriteBudget>) -> Option<Self> {
+        if rate.is_none() && budget.is_none() {
+            return None
+        }
+
+        Some(Self { own: rate.map(Bucket::new) })
+    }
+
+    ///
+    /// Internal Method: WriteThrottle::allowance() -> Result<u64, f64>
+    ///
+    ///     How much of `want` body bytes can be written now, at least one,
+    ///     or how many seconds to wait before asking again. Nothing is
+    ///     taken until it's passed to `spend()`.
+    ///
+    pub(crate) fn allowance(&mut self, budget: Option<&WriteBudget>, want: u64) -> Result<u64, f64> {
+        let now = Instant::now();
+        let mut allowed = want;
+        if let Some(own) = self.own.as_mut() {
+            allowed = own.allowance(allowed, now)?;
+        }
+
+        if let Some(budget) = budget {
+            allowed = budget.0.lock().unwrap().allowance(allowed, now)?;
+        }
+
+        Ok(allowed)
+    }
+
+    /// Takes `n` body bytes that have been written out of the buckets.
+    pub(crate) fn spend(&mut self, budget: Option<&WriteBudget>, n: u64) {
+        if n == 0 {
+            return
+        }
+
+        if let Some(own) = self.own.as_mut() {
+            own.spend(n);
+        }
+
+        if let Some(budget) = budget {
+            budget.0.lock().unwrap().spend(n);
+        }
+    }
+}
@@ -0,0 +1,633 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::PyValueError;
+
+use pyo3::types::PyBytes;
+use rustls::{ClientConfig, ClientConnection, Connection, ProtocolVersion, RootCertStore, ServerConfig, ServerConnection};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::io;
+use std::io::prelude::*;
+
+
+/// The protocols we offer via ALPN when nothing else has been configured.
+const DEFAULT_ALPN_PROTOCOLS: &[&str] = &["http/1.1"];
+
+/// Where the usual distros keep their CA bundle, tried in order when a client isn't given a `ca_file`.
+const SYSTEM_CA_FILES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+
+///
+/// TLSConfig is the python facing description of how the listener should
+/// terminate TLS, it loads the certificate chains and private keys once on
+/// creation and builds the rustls `ServerConfig` which is then shared between
+/// every connection the runner accepts.
+///
+/// Certificates are picked per connection from the SNI name the client sends,
+/// `certificates` maps hostnames (either exact or a single level wildcard
+/// like `*.example.com`) to a `(certfile, keyfile)` pair and anything that
+/// doesn't match falls back to the default `certfile` / `keyfile`.
+///
+///     Optional (at least one of the default cert or `certificates`):
+///         - certfile:         String                              (PEM encoded certificate chain)
+///         - keyfile:          String                              (PEM encoded private key)
+///         - alpn_protocols:   Vec<String>                         (defaults to ["http/1.1"])
+///         - certificates:     HashMap<String, (String, String)>   (hostname -> (certfile, keyfile))
+///         - ca_file:          String                              (PEM encoded roots for client certs)
+///         - require_client_cert: bool                             (defaults to false)
+///
+#[pyclass]
+pub struct TLSConfig {
+    pub(crate) config: Arc<ServerConfig>,
+    alpn_protocols: Vec<String>,
+}
+
+#[pymethods]
+impl TLSConfig {
+    #[new]
+    #[args(
+        certfile = "None",
+        keyfile = "None",
+        alpn_protocols = "None",
+        certificates = "None",
+        ca_file = "None",
+        require_client_cert = "false",
+    )]
+    fn new(
+        certfile: Option<String>,
+        keyfile: Option<String>,
+        alpn_protocols: Option<Vec<String>>,
+        certificates: Option<HashMap<String, (String, String)>>,
+        ca_file: Option<String>,
+        require_client_cert: bool,
+    ) -> PyResult<Self> {
+        let alpn_protocols = alpn_protocols.unwrap_or_else(|| {
+            DEFAULT_ALPN_PROTOCOLS
+                .iter()
+                .map(|p| p.to_string())
+                .collect()
+        });
+
+        if alpn_protocols.iter().any(|p| p.is_empty() || p.len() > 255) {
+            return Err(PyValueError::new_err(
+                "ALPN protocol names must be between 1 and 255 bytes long"
+            ))
+        }
+
+        let default = match (certfile, keyfile) {
+            (Some(certfile), Some(keyfile)) => Some(load_certified_key(&certfile, &keyfile)?),
+            (None, None) => None,
+            _ => return Err(PyValueError::new_err(
+                "certfile and keyfile must be given together"
+            )),
+        };
+
+        let mut resolver = SniResolver {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+            default,
+        };
+
+        for (hostname, (certfile, keyfile)) in certificates.unwrap_or_default() {
+            let key = load_certified_key(&certfile, &keyfile)?;
+            let hostname = hostname.to_ascii_lowercase();
+
+            match hostname.strip_prefix("*.") {
+                Some(parent) => resolver.wildcard.insert(parent.to_string(), key),
+                None => resolver.exact.insert(hostname, key),
+            };
+        }
+
+        if resolver.default.is_none() && resolver.exact.is_empty() && resolver.wildcard.is_empty() {
+            return Err(PyValueError::new_err(
+                "a default certfile / keyfile or a certificates dict is required"
+            ))
+        }
+
+        let builder = ServerConfig::builder();
+        let builder = match ca_file {
+            Some(ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(&ca_file)? {
+                    roots.add(cert)
+                        .map_err(|e| PyValueError::new_err(format!("invalid CA certificate: {}", e)))?;
+                }
+
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+                let verifier = if require_client_cert {
+                    verifier
+                } else {
+                    verifier.allow_unauthenticated()
+                };
+
+                let verifier = verifier
+                    .build()
+                    .map_err(|e| PyValueError::new_err(format!("invalid CA roots: {}", e)))?;
+
+                builder.with_client_cert_verifier(verifier)
+            },
+            None if require_client_cert => return Err(PyValueError::new_err(
+                "require_client_cert needs a ca_file to verify against"
+            )),
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_cert_resolver(Arc::new(resolver));
+
+        config.alpn_protocols = alpn_protocols
+            .iter()
+            .map(|p| p.as_bytes().to_vec())
+            .collect();
+
+        Ok(Self {
+            config: Arc::new(config),
+            alpn_protocols,
+        })
+    }
+
+    ///
+    /// The protocols advertised to clients during the handshake, in order
+    /// of preference.
+    ///
+    #[getter]
+    fn alpn_protocols(&self) -> Vec<String> {
+        self.alpn_protocols.clone()
+    }
+}
+
+///
+/// `config` without `h2` among its ALPN protocols, for a runner that won't
+/// speak HTTP/2 so a client can't pick it and then be answered in HTTP/1.
+///
+pub(crate) fn without_h2(config: Arc<ServerConfig>) -> Arc<ServerConfig> {
+    if !config.alpn_protocols.iter().any(|protocol| protocol == b"h2") {
+        return config
+    }
+
+    let mut config = ServerConfig::clone(&config);
+    config.alpn_protocols.retain(|protocol| protocol != b"h2");
+    Arc::new(config)
+}
+
+///
+/// The rustls `ClientConfig` for connections we make ourselves, verifying
+/// the server against the roots in `ca_file` or the system's CA bundle
+/// when there isn't one. No ALPN protocols are offered.
+///
+pub(crate) fn client_config(ca_file: Option<&str>) -> PyResult<Arc<ClientConfig>> {
+    let ca_file = match ca_file {
+        Some(ca_file) => ca_file,
+        None => SYSTEM_CA_FILES
+            .iter()
+            .copied()
+            .find(|path| std::path::Path::new(path).is_file())
+            .ok_or_else(|| PyValueError::new_err("no ca_file was given and there is no system CA bundle to use"))?,
+    };
+
+    let mut roots = RootCertStore::empty();
+    let (_, ignored) = roots.add_parsable_certificates(load_certs(ca_file)?);
+    if roots.is_empty() {
+        return Err(PyValueError::new_err(format!("{} has no usable CA certificates ({} ignored)", ca_file, ignored)))
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+///
+/// ClientTls is what a connection we make needs to start TLS, the config
+/// and the name the server's certificate has to be for (also sent as SNI).
+///
+#[derive(Clone)]
+pub(crate) struct ClientTls {
+    config: Arc<ClientConfig>,
+    name: ServerName<'static>,
+}
+
+impl ClientTls {
+    pub(crate) fn new(config: Arc<ClientConfig>, host: &str) -> PyResult<Self> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|_| PyValueError::new_err(format!("{:?} isn't a hostname or IP address TLS can verify", host)))?;
+
+        Ok(Self { config, name })
+    }
+
+    pub(crate) fn session(&self) -> io::Result<TlsSession> {
+        TlsSession::client(&self.config, self.name.clone())
+    }
+}
+
+fn load_certs(path: &str) -> PyResult<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| pem_error(path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| pem_error(path, e))?;
+
+    if certs.is_empty() {
+        return Err(PyValueError::new_err(format!("no certificates found in {}", path)))
+    }
+
+    Ok(certs)
+}
+
+fn load_private_key(path: &str) -> PyResult<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+///
+/// Loads a certificate chain and its key, checking the key actually belongs
+/// to the certificate so mistakes show up on startup rather than as failed
+/// handshakes later on.
+///
+fn load_certified_key(certfile: &str, keyfile: &str) -> PyResult<Arc<CertifiedKey>> {
+    let certs = load_certs(certfile)?;
+    let key = load_private_key(keyfile)?;
+
+    let provider = rustls::crypto::ring::default_provider();
+    let certified = CertifiedKey::from_der(certs, key, &provider)
+        .map_err(|e| PyValueError::new_err(format!("invalid certificate or key: {}", e)))?;
+
+    Ok(Arc::new(certified))
+}
+
+///
+/// Maps the pem errors onto something python understands, io failures
+/// (missing files, permissions etc...) become OSError and everything else
+/// is treated as a bad value.
+///
+fn pem_error(path: &str, err: rustls::pki_types::pem::Error) -> PyErr {
+    match err {
+        rustls::pki_types::pem::Error::Io(e) => e.into(),
+        e => PyValueError::new_err(format!("failed to parse {}: {:?}", path, e)),
+    }
+}
+
+
+///
+/// Picks the certificate for a connection based on the SNI server name,
+/// exact names are checked first then single level wildcards (so
+/// `*.example.com` matches `api.example.com` but not `a.b.example.com`),
+/// falling back to the default certificate.
+///
+#[derive(Debug)]
+struct SniResolver {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    wildcard: HashMap<String, Arc<CertifiedKey>>,   // keyed by the parent domain without `*.`
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn lookup(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let server_name = server_name.to_ascii_lowercase();
+
+        if let Some(key) = self.exact.get(&server_name) {
+            return Some(key.clone())
+        }
+
+        server_name
+            .split_once('.')
+            .and_then(|(_, parent)| self.wildcard.get(parent))
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.lookup(name))
+            .or_else(|| self.default.clone())
+    }
+}
+
+
+///
+/// TlsSession wraps a rustls connection (the server end of one we accepted
+/// or the client end of one a ConnectionPool made) and drives it over a
+/// non-blocking TcpStream, every method here can return `WouldBlock`
+/// (or `Ok(false)` for the handshake / flush) which just means try again
+/// on the next iteration of the event loop.
+///
+/// The socket is passed in on each call rather than being owned so the
+/// session can sit next to the existing `Stream` wrapper.
+///
+pub(crate) struct TlsSession {
+    conn: Connection,
+}
+
+impl TlsSession {
+    pub(crate) fn new(config: &Arc<ServerConfig>) -> io::Result<Self> {
+        let conn = ServerConnection::new(config.clone())
+            .map_err(io::Error::other)?;
+
+        Ok(Self { conn: conn.into() })
+    }
+
+    /// The client end of a session, for a connection we make to `name`.
+    pub(crate) fn client(config: &Arc<ClientConfig>, name: ServerName<'static>) -> io::Result<Self> {
+        let conn = ClientConnection::new(config.clone(), name)
+            .map_err(io::Error::other)?;
+
+        Ok(Self { conn: conn.into() })
+    }
+
+    ///
+    /// Internal Method: TlsSession::handshake() -> io::Result<bool>
+    ///
+    ///     Progresses the handshake as far as the socket allows, returns
+    ///     `Ok(true)` once the handshake is complete and all handshake data
+    ///     has been flushed, `Ok(false)` if we need to wait for the socket.
+    ///
+    ///     If the client sends something we don't like (no common ALPN
+    ///     protocol, bad certificate etc...) rustls queues an alert which
+    ///     we try to send before handing back the error.
+    ///
+    pub(crate) fn handshake(&mut self, sock: &TcpStream) -> io::Result<bool> {
+        while self.conn.is_handshaking() {
+            if !self.flush(sock)? {
+                return Ok(false)
+            }
+
+            if !self.conn.wants_read() {
+                break
+            }
+
+            match self.conn.read_tls(&mut &*sock) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => self.process_packets(sock)?,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(!self.conn.is_handshaking() && self.flush(sock)?)
+    }
+
+    ///
+    /// Reads decrypted application data into `buf`, `Ok(0)` signals the
+    /// client has gone away.
+    ///
+    pub(crate) fn read(&mut self, sock: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => return Err(e),
+            }
+
+            match self.conn.read_tls(&mut &*sock)? {
+                0 => return Ok(0),
+                _ => {
+                    self.process_packets(sock)?;
+                    self.flush(sock)?;
+                },
+            }
+        }
+    }
+
+    ///
+    /// Encrypts as much of `buf` as rustls will buffer and tries to push
+    /// it out to the socket, any encrypted data left over is sent by `flush`.
+    ///
+    pub(crate) fn write(&mut self, sock: &TcpStream, buf: &[u8]) -> io::Result<usize> {
+        let n = self.conn.writer().write(buf)?;
+        self.flush(sock)?;
+
+        if n == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        Ok(n)
+    }
+
+    ///
+    /// Writes any pending encrypted data to the socket, returns `Ok(true)`
+    /// once there is nothing left to send.
+    ///
+    pub(crate) fn flush(&mut self, sock: &TcpStream) -> io::Result<bool> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut &*sock) {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(true)
+    }
+
+    ///
+    /// Queues a close_notify alert and makes a best effort attempt to send
+    /// it, we don't wait around for the socket if it would block.
+    ///
+    pub(crate) fn close(&mut self, sock: &TcpStream) {
+        self.conn.send_close_notify();
+        let _ = self.flush(sock);
+    }
+
+    pub(crate) fn is_handshaking(&self) -> bool {
+        self.conn.is_handshaking()
+    }
+
+    /// The protocol agreed via ALPN, if the client offered any.
+    pub(crate) fn alpn_protocol(&self) -> Option<String> {
+        self.conn
+            .alpn_protocol()
+            .map(|p| String::from_utf8_lossy(p).into_owned())
+    }
+
+    /// The server name the client asked for via SNI, always `None` for our own client sessions.
+    pub(crate) fn server_name(&self) -> Option<String> {
+        match &self.conn {
+            Connection::Server(conn) => conn.server_name().map(String::from),
+            Connection::Client(_) => None,
+        }
+    }
+
+    /// The DER encoded certificate the peer presented, if any.
+    pub(crate) fn peer_certificate(&self) -> Option<Vec<u8>> {
+        self.conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(|cert| cert.as_ref().to_vec())
+    }
+
+    /// What was negotiated, for `request.tls` once the handshake is done.
+    pub(crate) fn info(&self) -> TLSInfo {
+        let version = self.conn.protocol_version().map(|version| match version {
+            ProtocolVersion::TLSv1_2 => String::from("TLSv1.2"),
+            ProtocolVersion::TLSv1_3 => String::from("TLSv1.3"),
+            other => format!("{:?}", other),
+        });
+
+        let cipher = self.conn
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()));
+
+        TLSInfo {
+            version,
+            cipher,
+            alpn_protocol: self.alpn_protocol(),
+            server_name: self.server_name(),
+            peer_cert: self.peer_certificate(),
+        }
+    }
+
+    fn process_packets(&mut self, sock: &TcpStream) -> io::Result<()> {
+        if let Err(e) = self.conn.process_new_packets() {
+            // send the alert rustls queued for us before giving up.
+            let _ = self.flush(sock);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+
+        Ok(())
+    }
+}
+
+
+///
+/// TLSInfo is `request.tls`, what was negotiated with the client when the
+/// connection was set up. It's made once per connection so every request
+/// on it shares the same one.
+///
+#[pyclass]
+pub struct TLSInfo {
+    version: Option<String>,        // e.g. "TLSv1.3"
+    cipher: Option<String>,         // The rustls name of the suite, e.g. "TLS13_AES_128_GCM_SHA256"
+    alpn_protocol: Option<String>,
+    server_name: Option<String>,
+    peer_cert: Option<Vec<u8>>,     // DER, only with a `ca_file` and a client that sent one
+}
+
+#[pymethods]
+impl TLSInfo {
+    /// The protocol version, `"TLSv1.2"` or `"TLSv1.3"`.
+    #[getter]
+    fn version(&self) -> Option<String> {
+        self.version.clone()
+    }
+
+    /// The cipher suite, e.g. `"TLS13_AES_128_GCM_SHA256"`.
+    #[getter]
+    fn cipher(&self) -> Option<String> {
+        self.cipher.clone()
+    }
+
+    /// The protocol agreed via ALPN, `None` if the client didn't offer any.
+    #[getter]
+    fn alpn_protocol(&self) -> Option<String> {
+        self.alpn_protocol.clone()
+    }
+
+    /// The name the client sent via SNI, `None` if it didn't send one.
+    #[getter]
+    fn server_name(&self) -> Option<String> {
+        self.server_name.clone()
+    }
+
+    ///
+    /// The DER encoded certificate the client authenticated with, for
+    /// parsing with an x509 library. Only present when the TLSConfig has
+    /// a `ca_file` and the client sent one.
+    ///
+    #[getter]
+    fn peer_cert_der(&self, py: Python) -> Option<PyObject> {
+        self.peer_cert
+            .as_ref()
+            .map(|der| PyBytes::new(py, der).into())
+    }
+
+    /// The subject common name of the client certificate, if there is one.
+    #[getter]
+    fn peer_common_name(&self) -> Option<String> {
+        self.peer_cert
+            .as_ref()
+            .and_then(|der| subject_common_name(der))
+    }
+}
+
+
+///
+/// Reads one DER TLV off the front of `data` returning the tag, contents
+/// and whatever is left after it.
+///
+fn der_read(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&len, mut data) = data.split_first()?;
+
+    let len = if len & 0x80 == 0 {
+        len as usize
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > 4 || data.len() < n {
+            return None
+        }
+
+        let len = data[..n].iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+        data = &data[n..];
+        len
+    };
+
+    if data.len() < len {
+        return None
+    }
+
+    Some((tag, &data[..len], &data[len..]))
+}
+
+///
+/// Pulls the subject common name out of a DER encoded certificate, this
+/// is just enough of a walk over the TBSCertificate to find the subject
+/// name rather than a full x509 parser, anything more involved should be
+/// done from python with the raw certificate bytes.
+///
+pub(crate) fn subject_common_name(der: &[u8]) -> Option<String> {
+    const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+
+    let (_, cert, _) = der_read(der)?;
+    let (_, mut tbs, _) = der_read(cert)?;
+
+    // skip the explicitly tagged version if there is one
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_read(tbs)?.2;
+    }
+
+    // serial number, signature algorithm, issuer, validity
+    for _ in 0..4 {
+        tbs = der_read(tbs)?.2;
+    }
+
+    let (_, mut subject, _) = der_read(tbs)?;
+    while !subject.is_empty() {
+        let (_, mut rdn, rest) = der_read(subject)?;
+        subject = rest;
+
+        while !rdn.is_empty() {
+            let (_, attribute, rest) = der_read(rdn)?;
+            rdn = rest;
+
+            let (_, oid, value) = der_read(attribute)?;
+            if oid == COMMON_NAME_OID {
+                let (_, value, _) = der_read(value)?;
+                return Some(String::from_utf8_lossy(value).into_owned())
+            }
+        }
+    }
+
+    None
+}
@@ -0,0 +1,87 @@
+use pyo3::prelude::*;
+
+use std::time::Duration;
+
+use crate::http::HTTPResponse;
+use crate::stream::{self, SharedTransport};
+
+
+/// How long getting through to where an accepted CONNECT asked to go may take before it's a `504`.
+pub(crate) const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+///
+/// Tunnel is an accepted CONNECT once its `200` is out, the client's
+/// connection and the one we made to where it asked to go with whatever
+/// either sends relayed to the other as it arrives. It's over (both are
+/// closed) as soon as either end closes or fails and everything it sent
+/// has gone on to the other, CONNECT has no half-closed tunnels.
+///
+pub(crate) struct Tunnel {
+    client: SharedTransport,
+    target: SharedTransport,
+    sent: u64,          // What the client sent through to the target
+    received: u64,      // What the target sent back
+}
+
+impl Tunnel {
+    pub(crate) fn new(client: SharedTransport, target: SharedTransport) -> Self {
+        Self {
+            client,
+            target,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// What's been relayed each way so far, `(sent, received)` from the client's side, for the access log.
+    pub(crate) fn bytes(&self) -> (u64, u64) {
+        (self.sent, self.received)
+    }
+
+    ///
+    /// Internal Method: Tunnel::pump() -> PyResult<Option<bool>>
+    ///
+    ///     One round of relaying both ways without blocking, if anything
+    ///     moved or `None` once the tunnel is over.
+    ///
+    pub(crate) fn pump(&mut self) -> PyResult<Option<bool>> {
+        let mut client = stream::lock(&self.client)?;
+        let mut target = stream::lock(&self.target)?;
+
+        let relayed = client
+            .relay(&mut target)
+            .and_then(|sent| Ok((sent, target.relay(&mut client)?)));
+
+        // a round that failed never got its bytes through, they aren't counted
+        if let Ok((sent, received)) = relayed {
+            self.sent += sent;
+            self.received += received;
+            let over = (client.is_exhausted() && target.is_flushed()) || (target.is_exhausted() && client.is_flushed());
+            if !over {
+                return Ok(Some(sent + received > 0))
+            }
+        }
+
+        client.close();
+        target.close();
+        Ok(None)
+    }
+}
+
+
+///
+/// What a CONNECT is answered with when we couldn't get through to its
+/// target, a `504` if that took too long and a `502` otherwise.
+///
+pub(crate) fn dial_failed(py: Python, e: &PyErr) -> HTTPResponse {
+    let timed_out = py
+        .import("asyncio")
+        .and_then(|asyncio| asyncio.getattr("TimeoutError"))
+        .is_ok_and(|timeout| e.matches(py, timeout));
+
+    match timed_out {
+        true => HTTPResponse::refused(504, "timed out connecting to the target"),
+        false => HTTPResponse::refused(502, "couldn't connect to the target"),
+    }
+}
@@ -0,0 +1,46 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::PyTypeError;
+
+use crate::http::HTTPResponse;
+
+
+///
+/// Upgrade can be returned from the callback to take the connection over
+/// once the exchange is done, for a `CONNECT` tunnel (see `proxy_mode` and
+/// `connect_handler`) or a protocol of your own that starts with an HTTP
+/// handshake. The head of `response` (a `101` or a `200` usually) is
+/// written and then the connection stops being HTTP,
+/// `protocol_factory(reader, writer)` is run as a task of its own with the
+/// same Reader / Writer a `raw=True` callback gets.
+///
+///     Requires:
+///         - response:     HTTPResponse
+///         - protocol_factory: PyObject    (called as `protocol_factory(reader, writer)`, it can be async)
+///
+///     The response's body is left off and we add no `Content-Length` or
+///     `Connection` to it, whatever follows the head is the protocol's.
+///     Anything the client sent after the request that we'd already read
+///     is what the reader gives back first. The connection is never used
+///     for another request, it closes once the protocol's task is done (or
+///     is cancelled along with the connection).
+///
+#[pyclass]
+pub struct Upgrade {
+    pub(crate) response: Py<HTTPResponse>,
+    pub(crate) factory: PyObject,
+}
+
+#[pymethods]
+impl Upgrade {
+    #[new]
+    fn new(response: Py<HTTPResponse>, protocol_factory: &PyAny) -> PyResult<Self> {
+        if !protocol_factory.is_callable() {
+            return Err(PyTypeError::new_err("protocol_factory must be callable"))
+        }
+
+        Ok(Self {
+            response,
+            factory: protocol_factory.into(),
+        })
+    }
+}
@@ -0,0 +1,110 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::PyValueError;
+use pyo3::types::PyDict;
+
+use std::collections::HashMap;
+
+use crate::headers::HeaderName;
+use crate::http::{self, HTTPRequest, HTTPResponse};
+
+
+///
+/// VirtualHosts dispatches requests to a handler by their Host so one
+/// runner can serve several sites, it's what a dict passed as the runner's
+/// callback becomes but it can be made (and passed) directly too.
+///
+/// The Host is matched without its port and ignoring case, first against
+/// the exact names, then the `*.example.com` patterns (the longest that
+/// matches, and never `example.com` itself) and then `"*"` if there is
+/// one. A request for any other host is a `421 Misdirected Request`, so
+/// is one without a Host at all (HTTP/1.0) when there's no `"*"`.
+///
+///     Requires:
+///         - hosts:    dict    (host name or pattern to its handler)
+///
+///     Example:
+///         AsyncServerRunner("0.0.0.0:8080", {
+///             "example.com": site,
+///             "*.example.com": subdomains,
+///             "*": fallback,
+///         })
+///
+#[pyclass]
+pub struct VirtualHosts {
+    exact: HashMap<String, PyObject>,       // By lowercased name
+    wildcard: Vec<(String, PyObject)>,      // By the `.example.com` a subdomain ends with, longest first
+    default: Option<PyObject>,              // The `"*"` handler
+}
+
+#[pymethods]
+impl VirtualHosts {
+    #[new]
+    pub(crate) fn new(hosts: &PyDict) -> PyResult<Self> {
+        let mut vhosts = Self {
+            exact: HashMap::new(),
+            wildcard: Vec::new(),
+            default: None,
+        };
+
+        for (host, handler) in hosts.iter() {
+            let host: String = host.extract()?;
+            let host = host.trim().to_ascii_lowercase();
+            if !handler.is_callable() {
+                return Err(PyValueError::new_err(format!("the handler for '{}' isn't callable", host)))
+            }
+
+            let handler = handler.into();
+            match host.strip_prefix('*') {
+                Some("") => vhosts.default = Some(handler),
+                Some(suffix) if suffix.len() > 1 && suffix.starts_with('.') && !suffix.contains('*') => {
+                    vhosts.wildcard.push((suffix.to_string(), handler));
+                },
+                None if !host.is_empty() && !host.contains('*') && !host.contains(':') => {
+                    vhosts.exact.insert(host, handler);
+                },
+                _ => return Err(PyValueError::new_err(format!(
+                    "'{}' isn't a host name, a '*.example.com' pattern or '*'", host
+                ))),
+            }
+        }
+
+        vhosts.wildcard.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Ok(vhosts)
+    }
+
+    ///
+    /// PythonMethod: VirtualHosts(request) -> response
+    ///
+    ///     Finds the handler for the request's Host and hands it over,
+    ///     giving back whatever that does.
+    ///
+    #[call]
+    fn __call__(&self, py: Python, request: &PyCell<HTTPRequest>) -> PyResult<PyObject> {
+        let name = request.borrow().headers.get(HeaderName::Host).map(|host| http::host_name(host.trim()));
+
+        match self.handler(name.as_deref()) {
+            Some(handler) => handler.call1(py, (request,)),
+            None => Ok(Py::new(py, HTTPResponse::with_status(421))?.into_py(py)),
+        }
+    }
+}
+
+impl VirtualHosts {
+    /// The handler for a (port-stripped, lowercased) host, `None` is a request without one.
+    fn handler(&self, name: Option<&str>) -> Option<&PyObject> {
+        let name = match name {
+            Some(name) => name,
+            None => return self.default.as_ref(),
+        };
+
+        self.exact
+            .get(name)
+            .or_else(|| {
+                self.wildcard
+                    .iter()
+                    .find(|(suffix, _)| name.len() > suffix.len() && name.ends_with(suffix.as_str()))
+                    .map(|(_, handler)| handler)
+            })
+            .or(self.default.as_ref())
+    }
+}
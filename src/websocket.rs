@@ -0,0 +1,699 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::exceptions::PyTypeError;
+use pyo3::types::{PyBytes, PyString};
+
+use std::net::{Shutdown, TcpStream};
+use std::io;
+use std::io::prelude::*;
+
+use crate::errors::ConnectionClosed;
+use crate::headers::{HeaderName, Headers};
+use crate::http::HTTPResponse;
+use crate::sleep::LoopSleeper;
+use crate::stream;
+use crate::tls::TlsSession;
+
+
+/// The GUID from RFC 6455 which is appended to the client's key.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The only protocol version we (and every browser) speak.
+const WEBSOCKET_VERSION: &str = "13";
+
+
+///
+/// If the request is asking to be upgraded to a websocket, this is only
+/// about intent, `handshake()` decides if the request is actually valid.
+///
+pub(crate) fn is_upgrade(headers: &Headers) -> bool {
+    let has_token = |name: HeaderName, token: &str| {
+        headers.get(name).is_some_and(|value| {
+            value.split(',').any(|v| v.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    has_token(HeaderName::Upgrade, "websocket") && has_token(HeaderName::Connection, "upgrade")
+}
+
+///
+/// Validates the upgrade request and produces the response for it, either
+/// the `101 Switching Protocols` (`Ok`) or the error to send instead (`Err`).
+///
+///     - a missing or malformed `Sec-WebSocket-Key` is a `400`
+///     - any version other than 13 is a `426` advertising the one we support
+///
+pub(crate) fn handshake(method: &str, headers: &Headers) -> Result<HTTPResponse, HTTPResponse> {
+    if method != "GET" {
+        return Err(HTTPResponse::with_status(400))
+    }
+
+    if headers.get(HeaderName::SecWebSocketVersion) != Some(WEBSOCKET_VERSION) {
+        return Err(HTTPResponse::from_parts(
+            426,
+            vec![(String::from("Sec-WebSocket-Version"), String::from(WEBSOCKET_VERSION))],
+            Vec::new(),
+        ))
+    }
+
+    let key = match headers.get(HeaderName::SecWebSocketKey) {
+        Some(key) if base64_decode(key.trim()).is_some_and(|k| k.len() == 16) => key.trim(),
+        _ => return Err(HTTPResponse::with_status(400)),
+    };
+
+    Ok(HTTPResponse::from_parts(
+        101,
+        vec![
+            (String::from("Upgrade"), String::from("websocket")),
+            (String::from("Connection"), String::from("Upgrade")),
+            (String::from("Sec-WebSocket-Accept"), accept_key(key)),
+        ],
+        Vec::new(),
+    ))
+}
+
+///
+/// Computes `Sec-WebSocket-Accept` for a client's key, the base64 of the
+/// SHA1 of the key followed by the magic GUID.
+///
+pub(crate) fn accept_key(key: &str) -> String {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    ctx.update(key.as_bytes());
+    ctx.update(WEBSOCKET_GUID.as_bytes());
+
+    base64_encode(ctx.finish().as_ref())
+}
+
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let data = data.as_bytes();
+    if !data.len().is_multiple_of(4) {
+        return None
+    }
+
+    let chunks = data.len() / 4;
+    let mut out = Vec::with_capacity(chunks * 3);
+    for (i, chunk) in data.chunks(4).enumerate() {
+        // padding is only allowed at the very end
+        let padding = chunk.iter().rev().take_while(|&&b| b == b'=').count();
+        if padding > 2 || (padding > 0 && i + 1 != chunks) {
+            return None
+        }
+
+        let mut n = 0u32;
+        for &b in &chunk[..4 - padding] {
+            let value = BASE64_ALPHABET.iter().position(|&c| c == b)? as u32;
+            n = n << 6 | value;
+        }
+        n <<= 6 * padding as u32;
+
+        let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+        out.extend_from_slice(&bytes[..3 - padding]);
+    }
+
+    Some(out)
+}
+
+
+/// The largest message we'll assemble before failing the connection with 1009.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_ABNORMAL: u16 = 1006;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+
+///
+/// A single frame read off the wire with its payload already unmasked.
+///
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+///
+/// Parses one client frame off the front of `buffer`, `Ok(None)` means we
+/// need more bytes and `Err` is the close code to fail the connection with.
+/// On success the frame is returned with how many bytes it took up.
+///
+fn parse_frame(buffer: &[u8]) -> Result<Option<(Frame, usize)>, u16> {
+    if buffer.len() < 2 {
+        return Ok(None)
+    }
+
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0f;
+    let masked = buffer[1] & 0x80 != 0;
+
+    // no extensions are negotiated so the reserved bits must be clear and
+    // clients must always mask what they send.
+    if buffer[0] & 0x70 != 0 || !masked {
+        return Err(CLOSE_PROTOCOL_ERROR)
+    }
+
+    let (len, mut offset) = match buffer[1] & 0x7f {
+        126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+        127 if buffer.len() >= 10 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(&buffer[2..10]);
+            (u64::from_be_bytes(len), 10)
+        },
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+
+    if opcode >= OPCODE_CLOSE && (!fin || len > 125) {
+        return Err(CLOSE_PROTOCOL_ERROR)
+    }
+
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(CLOSE_TOO_BIG)
+    }
+
+    let len = len as usize;
+    if buffer.len() < offset + 4 + len {
+        return Ok(None)
+    }
+
+    let mut mask = [0; 4];
+    mask.copy_from_slice(&buffer[offset..offset + 4]);
+    offset += 4;
+
+    let payload = buffer[offset..offset + len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((Frame { fin, opcode, payload }, offset + len)))
+}
+
+///
+/// Encodes a single unmasked (server to client) frame with the FIN bit set.
+///
+fn encode_frame(opcode: u8, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(0x80 | opcode);
+
+    match payload.len() {
+        len if len <= 125 => out.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        },
+    }
+
+    out.extend_from_slice(payload);
+}
+
+///
+/// If a peer may close with `code` (RFC 6455 7.4). 1004 is reserved,
+/// 1005, 1006 and 1015 only stand for what happened when there was no
+/// code to send, and the rest of 1000-2999 hasn't been given a meaning
+/// yet, 3000-4999 are for libraries and applications.
+///
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+
+    // control frames are capped at 125 bytes so the reason may get cut short
+    payload.truncate(125);
+    payload
+}
+
+
+///
+/// WebSocketConnection is what the websocket callback gets after a
+/// successful handshake, it takes over the connection from the OnceFuture
+/// which upgraded it so the socket lives as long as the handler needs it.
+///
+///     await ws.receive()          -> str / bytes, or None once closed
+///     await ws.send(data)         str is sent as text, bytes as binary
+///     await ws.close(code=1000, reason="")
+///     ws.get_extra_info(name)     "peername", "sockname", "socket" or "fileno"
+///
+/// Pings are answered automatically and a close from the client is
+/// echoed back, after which `receive()` gives `None` and `close_code`
+/// / `close_reason` say why.
+///
+#[pyclass]
+pub struct WebSocketConnection {
+    loop_: PyObject,                    // The asyncio event loop for the awaitables to sleep on
+    stream: Option<TcpStream>,          // The upgraded socket, None once closed
+    tls: Option<TlsSession>,            // The TLS session if the listener terminates TLS
+    buffer: Vec<u8>,                    // Bytes read off the socket but not yet parsed into frames
+    outgoing: Vec<u8>,                  // Encoded frames waiting to go out
+    fragments: Vec<u8>,                 // The payload of a fragmented message so far
+    fragment_opcode: Option<u8>,        // The opcode of the message being reassembled
+    close_sent: bool,                   // If we've queued our close frame
+    close_code: Option<u16>,            // Set once the connection is closing / closed
+    close_reason: String,
+}
+
+impl WebSocketConnection {
+    pub(crate) fn new(loop_: PyObject, stream: TcpStream, tls: Option<TlsSession>, buffer: Vec<u8>) -> Self {
+        Self {
+            loop_,
+            stream: Some(stream),
+            tls,
+            buffer,
+            outgoing: Vec::new(),
+            fragments: Vec::new(),
+            fragment_opcode: None,
+            close_sent: false,
+            close_code: None,
+            close_reason: String::new(),
+        }
+    }
+
+    fn read_some(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let sock = match self.stream.as_ref() {
+            Some(sock) => sock,
+            None => return Ok(0),
+        };
+
+        match self.tls.as_mut() {
+            Some(tls) => tls.read(sock, buf),
+            None => (&*sock).read(buf),
+        }
+    }
+
+    ///
+    /// Internal Method: WebSocketConnection::flush() -> io::Result<bool>
+    ///
+    ///     Pushes as much of the outgoing frames to the socket as it takes,
+    ///     `Ok(true)` once everything (including TLS records) has gone.
+    ///
+    fn flush(&mut self) -> io::Result<bool> {
+        let sock = match self.stream.as_ref() {
+            Some(sock) => sock,
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        };
+
+        while !self.outgoing.is_empty() {
+            let res = match self.tls.as_mut() {
+                Some(tls) => tls.write(sock, &self.outgoing),
+                None => (&*sock).write(&self.outgoing),
+            };
+
+            match res {
+                Ok(n) => { self.outgoing.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+
+        match self.tls.as_mut() {
+            Some(tls) => tls.flush(sock),
+            None => Ok(true),
+        }
+    }
+
+    fn queue_close(&mut self, code: u16, reason: &str) {
+        if !self.close_sent {
+            encode_frame(OPCODE_CLOSE, &close_payload(code, reason), &mut self.outgoing);
+            self.close_sent = true;
+        }
+    }
+
+    ///
+    /// Fails the connection, the close frame is sent if we can and
+    /// from then on everything sees the connection as closed.
+    ///
+    fn fail(&mut self, code: u16) {
+        self.queue_close(code, "");
+        let _ = self.flush();
+
+        self.close_code.get_or_insert(code);
+        self.shutdown();
+    }
+
+    ///
+    /// Handles one complete frame, giving back a finished message for text
+    /// and binary (once all fragments are in) or `Err` to fail the connection.
+    ///
+    fn handle_frame(&mut self, py: Python, frame: Frame) -> Result<Option<PyObject>, u16> {
+        match frame.opcode {
+            OPCODE_PING => {
+                encode_frame(OPCODE_PONG, &frame.payload, &mut self.outgoing);
+                Ok(None)
+            },
+            OPCODE_PONG => Ok(None),
+            OPCODE_CLOSE => {
+                let (code, reason) = match frame.payload.len() {
+                    0 => (CLOSE_NORMAL, String::new()),
+                    1 => return Err(CLOSE_PROTOCOL_ERROR),
+                    _ => {
+                        let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                        if !valid_close_code(code) {
+                            return Err(CLOSE_PROTOCOL_ERROR)
+                        }
+
+                        let reason = String::from_utf8(frame.payload[2..].to_vec())
+                            .map_err(|_| CLOSE_INVALID_DATA)?;
+                        (code, reason)
+                    },
+                };
+
+                // echo their close back to finish the closing handshake
+                self.queue_close(code, "");
+                self.close_code.get_or_insert(code);
+                self.close_reason = reason;
+                Ok(None)
+            },
+            OPCODE_TEXT | OPCODE_BINARY if self.fragment_opcode.is_none() => {
+                if frame.fin {
+                    return message(py, frame.opcode, frame.payload).map(Some)
+                }
+
+                self.fragment_opcode = Some(frame.opcode);
+                self.fragments = frame.payload;
+                Ok(None)
+            },
+            OPCODE_CONTINUATION if self.fragment_opcode.is_some() => {
+                if self.fragments.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                    return Err(CLOSE_TOO_BIG)
+                }
+
+                self.fragments.extend_from_slice(&frame.payload);
+                if !frame.fin {
+                    return Ok(None)
+                }
+
+                let opcode = self.fragment_opcode.take().unwrap();
+                message(py, opcode, std::mem::take(&mut self.fragments)).map(Some)
+            },
+            _ => Err(CLOSE_PROTOCOL_ERROR),
+        }
+    }
+
+    ///
+    /// Internal Method: WebSocketConnection::poll_receive() -> Option<Option<PyObject>>
+    ///
+    ///     Works through buffered frames and reads more as needed, the outer
+    ///     `None` means the socket has nothing for us yet and `Some(None)` that
+    ///     the connection has closed.
+    ///
+    fn poll_receive(&mut self, py: Python) -> Option<Option<PyObject>> {
+        let mut chunk = [0; 4096];
+
+        loop {
+            if self.close_code.is_some() {
+                // make sure our half of the close handshake goes out
+                return match py.allow_threads(|| self.flush()) {
+                    Ok(false) => None,
+                    _ => {
+                        self.shutdown();
+                        Some(None)
+                    },
+                }
+            }
+
+            match parse_frame(&self.buffer) {
+                Ok(Some((frame, used))) => {
+                    self.buffer.drain(..used);
+                    match self.handle_frame(py, frame) {
+                        Ok(Some(message)) => return Some(Some(message)),
+                        Ok(None) => continue,
+                        Err(code) => {
+                            self.fail(code);
+                            return Some(None)
+                        },
+                    }
+                },
+                Ok(None) => {},
+                Err(code) => {
+                    self.fail(code);
+                    return Some(None)
+                },
+            }
+
+            // pongs and the like should go out even while we wait on the client
+            if py.allow_threads(|| self.flush()).is_err() {
+                self.fail(CLOSE_ABNORMAL);
+                return Some(None)
+            }
+
+            match py.allow_threads(|| self.read_some(&mut chunk)) {
+                Ok(0) => {
+                    self.close_code.get_or_insert(CLOSE_ABNORMAL);
+                    self.shutdown();
+                    return Some(None)
+                },
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(_) => {
+                    self.close_code.get_or_insert(CLOSE_ABNORMAL);
+                    self.shutdown();
+                    return Some(None)
+                },
+            }
+        }
+    }
+
+    ///
+    /// Internal Method: WebSocketConnection::shutdown()
+    ///
+    ///     Drops the socket without waiting on anything, the runner does this
+    ///     itself once the websocket callback returns.
+    ///
+    pub(crate) fn shutdown(&mut self) {
+        if let Some(sock) = self.stream.take() {
+            if let Some(tls) = self.tls.as_mut() {
+                tls.close(&sock);
+            }
+
+            let _ = sock.shutdown(Shutdown::Both);
+        }
+
+        self.buffer.clear();
+        self.outgoing.clear();
+    }
+
+    ///
+    /// Sends a close frame (if the handler never did) and drops the socket.
+    ///
+    pub(crate) fn close_now(&mut self) {
+        if self.stream.is_some() {
+            self.queue_close(CLOSE_NORMAL, "");
+            let _ = self.flush();
+            self.close_code.get_or_insert(CLOSE_NORMAL);
+        }
+
+        self.shutdown();
+    }
+}
+
+fn message(py: Python, opcode: u8, payload: Vec<u8>) -> Result<PyObject, u16> {
+    if opcode == OPCODE_TEXT {
+        let text = String::from_utf8(payload).map_err(|_| CLOSE_INVALID_DATA)?;
+        return Ok(text.into_py(py))
+    }
+
+    Ok(PyBytes::new(py, &payload).into())
+}
+
+#[pymethods]
+impl WebSocketConnection {
+
+    ///
+    /// If the underlying connection has been closed.
+    ///
+    #[getter]
+    fn closed(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    ///
+    /// The close code sent by whichever side closed first, `1006` if the
+    /// connection went away without a close frame. `None` while open.
+    ///
+    #[getter]
+    fn close_code(&self) -> Option<u16> {
+        self.close_code
+    }
+
+    #[getter]
+    fn close_reason(&self) -> String {
+        self.close_reason.clone()
+    }
+
+    ///
+    /// PythonMethod: WebSocketConnection.get_extra_info(name, default=None)
+    ///
+    ///     The same as `Writer.get_extra_info` for the upgraded socket,
+    ///     everything is `default` once the connection has closed.
+    ///
+    #[args(default = "None")]
+    fn get_extra_info(&self, py: Python, name: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        let info = stream::extra_info(py, self.stream.as_ref(), name)?;
+        Ok(info.or(default).unwrap_or_else(|| py.None()))
+    }
+
+    ///
+    /// PythonMethod: WebSocketConnection.receive() -> awaitable str / bytes / None
+    ///
+    ///     Waits for the next text or binary message, `None` once the
+    ///     connection has been closed by either side.
+    ///
+    fn receive(slf: PyRef<Self>, py: Python) -> PyResult<WebSocketOp> {
+        WebSocketOp::new(py, slf, OP_RECEIVE)
+    }
+
+    ///
+    /// PythonMethod: WebSocketConnection.send() -> awaitable None
+    ///
+    ///     Queues `data` as a single frame and waits for it to be written,
+    ///     `str` goes as a text message and `bytes` as binary.
+    ///
+    fn send(mut slf: PyRefMut<Self>, py: Python, data: &PyAny) -> PyResult<WebSocketOp> {
+        if slf.close_sent || slf.stream.is_none() {
+            return Err(ConnectionClosed::new_err("the websocket is closed"))
+        }
+
+        if let Ok(bytes) = data.downcast::<PyBytes>() {
+            encode_frame(OPCODE_BINARY, bytes.as_bytes(), &mut slf.outgoing);
+        } else if let Ok(text) = data.downcast::<PyString>() {
+            encode_frame(OPCODE_TEXT, text.to_str()?.as_bytes(), &mut slf.outgoing);
+        } else {
+            return Err(PyTypeError::new_err("websocket messages must be str or bytes"))
+        }
+
+        WebSocketOp::new(py, slf, OP_SEND)
+    }
+
+    ///
+    /// PythonMethod: WebSocketConnection.close() -> awaitable None
+    ///
+    ///     Starts the closing handshake and waits for the client to answer
+    ///     it, any messages still arriving in the meantime are discarded.
+    ///
+    ///     Optional:
+    ///         - code:     int  (defaults to 1000)
+    ///         - reason:   str
+    ///
+    #[args(code = "1000", reason = "\"\"")]
+    fn close(mut slf: PyRefMut<Self>, py: Python, code: u16, reason: &str) -> PyResult<WebSocketOp> {
+        slf.queue_close(code, reason);
+        WebSocketOp::new(py, slf, OP_CLOSE)
+    }
+}
+
+
+const OP_RECEIVE: u8 = 0;
+const OP_SEND: u8 = 1;
+const OP_CLOSE: u8 = 2;
+
+///
+/// WebSocketOp is the awaitable behind `receive`, `send` and `close`, it
+/// polls the connection from `__next__` and sleeps on the loop while the socket
+/// would block, the same way OnceFuture does.
+///
+#[pyclass]
+pub struct WebSocketOp {
+    ws: Py<WebSocketConnection>,
+    op: u8,
+    sleeper: LoopSleeper,
+}
+
+impl WebSocketOp {
+    fn new<T>(py: Python, ws: T, op: u8) -> PyResult<Self>
+    where
+        T: std::ops::Deref<Target = WebSocketConnection> + Into<Py<WebSocketConnection>>,
+    {
+        let sleeper = LoopSleeper::for_connection(ws.loop_.clone_ref(py));
+        Ok(Self { ws: ws.into(), op, sleeper })
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for WebSocketOp {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for WebSocketOp {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<IterNextOutput<Option<PyObject>, Option<PyObject>>> {
+        // SAFETY: python only calls into a protocol method with the GIL held
+        let py = unsafe { Python::assume_gil_acquired() };
+        let this = &mut *slf;
+        let mut guard = this.ws.borrow_mut(py);
+        let ws = &mut *guard;
+
+        let ready = match this.op {
+            OP_RECEIVE => ws.poll_receive(py),
+
+            OP_SEND => match py.allow_threads(|| ws.flush()) {
+                Ok(true) => Some(None),
+                Ok(false) => None,
+                Err(e) => {
+                    ws.fail(CLOSE_ABNORMAL);
+                    return Err(e.into())
+                },
+            },
+
+            // keep reading (and dropping messages) until the client's close
+            // frame arrives or the connection goes away.
+            _ => loop {
+                match ws.poll_receive(py) {
+                    Some(Some(_)) => continue,
+                    Some(None) => break Some(None),
+                    None => break None,
+                }
+            },
+        };
+
+        drop(guard);
+        match ready {
+            Some(value) => Ok(IterNextOutput::Return(value)),
+            None => Ok(IterNextOutput::Yield(this.sleeper._iter_sleep(py))),
+        }
+    }
+}
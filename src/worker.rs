@@ -0,0 +1,156 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::{PyNotImplementedError, PyRuntimeError};
+use pyo3::types::PyDict;
+
+use std::net::SocketAddr;
+
+
+/// The env var a parent uses to hand the listener over to its workers.
+const HANDOFF_VAR: &str = "ASYNC_RUST_WORKER";
+
+
+///
+/// WorkerHandoff is what crosses the spawn boundary between the parent
+/// and its workers, it's serialized into an env var as `index|address`.
+///
+/// Each worker binds its own SO_REUSEPORT socket to the handed over address
+/// rather than inheriting the parent's fd, separate sockets are what let
+/// the kernel spread new connections across the processes.
+///
+pub(crate) struct WorkerHandoff {
+    pub(crate) index: usize,
+    pub(crate) addr: SocketAddr,
+}
+
+impl WorkerHandoff {
+    fn serialize(&self) -> String {
+        format!("{}|{}", self.index, self.addr)
+    }
+
+    fn deserialize(value: &str) -> Option<Self> {
+        let (index, addr) = value.split_once('|')?;
+
+        Some(Self {
+            index: index.parse().ok()?,
+            addr: addr.parse().ok()?,
+        })
+    }
+
+    ///
+    /// Internal Method: WorkerHandoff::from_env() -> Option<Self>
+    ///
+    ///     Checks if we were spawned as a worker, if so the parent's
+    ///     handoff is returned and we make sure we won't outlive the parent.
+    ///
+    pub(crate) fn from_env() -> Option<Self> {
+        let handoff = Self::deserialize(&std::env::var(HANDOFF_VAR).ok()?)?;
+
+        // if the parent gets killed without a chance to clean up we go with it.
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+        }
+
+        Some(handoff)
+    }
+}
+
+
+///
+/// WorkerPool is held by the parent runner, it spawns the worker processes
+/// by re-running the same script with the handoff set, keeps the `Popen`
+/// handles around and terminates them again when the parent shuts down.
+///
+pub(crate) struct WorkerPool {
+    children: Vec<PyObject>,
+}
+
+impl WorkerPool {
+    pub(crate) fn spawn(py: Python, count: usize, addr: SocketAddr) -> PyResult<Self> {
+        if cfg!(not(unix)) {
+            return Err(PyNotImplementedError::new_err(
+                "multiple workers needs SO_REUSEPORT which is only available on unix"
+            ))
+        }
+
+        let sys = py.import("sys")?;
+        let executable: String = sys.getattr("executable")?.extract()?;
+        let argv: Vec<String> = sys.getattr("argv")?.extract()?;
+
+        if argv.first().is_none_or(|arg| arg.is_empty() || arg == "-c") {
+            return Err(PyRuntimeError::new_err(
+                "multiple workers requires running from a script so the workers can re-run it"
+            ))
+        }
+
+        let mut cmd = vec![executable];
+        cmd.extend(argv);
+
+        let environ = py.import("os")?.getattr("environ")?;
+        let subprocess = py.import("subprocess")?;
+
+        let mut pool = Self { children: Vec::with_capacity(count) };
+        for index in 1..=count {
+            let env = environ.call_method0("copy")?;
+            env.set_item(HANDOFF_VAR, WorkerHandoff { index, addr }.serialize())?;
+
+            let kwargs = PyDict::new(py);
+            kwargs.set_item("env", env)?;
+
+            // if this fails the workers spawned so far are cleaned up by drop.
+            let child = subprocess.call_method("Popen", (cmd.clone(),), Some(kwargs))?;
+            pool.children.push(child.into());
+        }
+
+        Ok(pool)
+    }
+
+    /// The pids of the workers which are still running.
+    pub(crate) fn pids(&self, py: Python) -> PyResult<Vec<u32>> {
+        self.reap(py)?
+            .iter()
+            .map(|child| child.getattr(py, "pid")?.extract(py))
+            .collect()
+    }
+
+    fn reap(&self, py: Python) -> PyResult<Vec<&PyObject>> {
+        let mut alive = Vec::with_capacity(self.children.len());
+        for child in self.children.iter() {
+            if child.call_method0(py, "poll")?.is_none(py) {
+                alive.push(child);
+            }
+        }
+
+        Ok(alive)
+    }
+
+    ///
+    /// Internal Method: WorkerPool::shutdown()
+    ///
+    ///     Sends SIGTERM to every worker and waits for them to exit, anything
+    ///     still around after a few seconds gets killed.
+    ///
+    pub(crate) fn shutdown(&mut self, py: Python) {
+        for child in self.children.iter() {
+            let _ = child.call_method0(py, "terminate");
+        }
+
+        for child in self.children.drain(..) {
+            if child.call_method1(py, "wait", (5,)).is_err() {
+                let _ = child.call_method0(py, "kill");
+                let _ = child.call_method0(py, "wait");
+            }
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        if self.children.is_empty() {
+            return
+        }
+
+        let gil = Python::acquire_gil();
+        self.shutdown(gil.python());
+    }
+}
@@ -0,0 +1,238 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::get_loop;
+use crate::http::{self, HTTPRequest, HTTPResponse};
+
+
+///
+/// WSGIApp adapts a WSGI application (PEP 3333) into a callback the runner
+/// understands, so `AsyncServerRunner(addr, WSGIApp(app))` serves it.
+///
+/// The app is run with `loop.run_in_executor` so a slow sync handler only
+/// ties up a thread rather than the event loop, everything handed to the thread
+/// is a plain python object so nothing borrows the connection's state.
+///
+///     Requires:
+///         - app:      the WSGI application `def app(environ, start_response)`
+///
+///     Optional:
+///         - executor: concurrent.futures.Executor  (defaults to the loop's)
+///
+#[pyclass]
+pub struct WSGIApp {
+    app: PyObject,
+    executor: PyObject,
+}
+
+#[pymethods]
+impl WSGIApp {
+    #[new]
+    #[args(executor = "None")]
+    fn new(py: Python, app: PyObject, executor: Option<PyObject>) -> Self {
+        Self {
+            app,
+            executor: executor.unwrap_or_else(|| py.None()),
+        }
+    }
+
+    ///
+    /// PythonMethod: WSGIApp(request) -> asyncio.Future
+    ///
+    ///     Builds the environ for the request and schedules the app on the
+    ///     executor, the future resolves to the HTTPResponse.
+    ///
+    #[call]
+    fn __call__(&self, py: Python, request: PyRef<HTTPRequest>) -> PyResult<PyObject> {
+        let call = WSGICall {
+            app: self.app.clone_ref(py),
+            environ: build_environ(py, &request)?.into(),
+            start_response: Py::new(py, StartResponse::default())?,
+        };
+
+        let fut = get_loop(py)?.call_method1(
+            "run_in_executor",
+            (self.executor.clone_ref(py), Py::new(py, call)?),
+        )?;
+
+        Ok(fut.into())
+    }
+}
+
+
+///
+/// Builds the WSGI `environ` out of a parsed request.
+///
+fn build_environ<'p>(py: Python<'p>, request: &HTTPRequest) -> PyResult<&'p PyDict> {
+    let environ = PyDict::new(py);
+    environ.set_item("REQUEST_METHOD", &request.method)?;
+    environ.set_item("SCRIPT_NAME", "")?;
+    environ.set_item("PATH_INFO", http::latin1(&request.path_bytes))?;
+    environ.set_item("QUERY_STRING", &request.raw_query)?;
+    environ.set_item("SERVER_PROTOCOL", &request.protocol)?;
+
+    if let Some((host, port)) = request.server.as_ref() {
+        environ.set_item("SERVER_NAME", host)?;
+        environ.set_item("SERVER_PORT", port.to_string())?;
+    }
+
+    if let Some((host, port)) = request.client.as_ref() {
+        environ.set_item("REMOTE_ADDR", host)?;
+        environ.set_item("REMOTE_PORT", port.to_string())?;
+    }
+
+    for (name, value) in request.headers.iter() {
+        let key = name.to_ascii_uppercase().replace('-', "_");
+        let key = match key.as_str() {
+            "CONTENT_TYPE" | "CONTENT_LENGTH" => key,
+            _ => format!("HTTP_{}", key),
+        };
+
+        // repeated headers are joined the way CGI does it
+        let value = match environ.get_item(&key) {
+            Some(existing) => format!("{},{}", existing.extract::<&str>()?, value),
+            None => value.to_string(),
+        };
+
+        environ.set_item(key, value)?;
+    }
+
+    // a WSGI app reads its body synchronously, there's no waiting on the socket for it
+    if request.stream.is_some() {
+        return Err(PyValueError::new_err("a WSGI app can't read a streamed body, stream_request_body needs a handler that uses request.stream()"))
+    }
+
+    // a spooled body is read straight from its temp file
+    let input = match request.spooled.as_ref() {
+        Some(spool) => spool.to_python(py)?,
+        None => py.import("io")?.call_method1("BytesIO", (PyBytes::new(py, request.body.as_slice()),))?.into(),
+    };
+
+    environ.set_item("wsgi.version", (1, 0))?;
+    environ.set_item("wsgi.url_scheme", &request.scheme)?;
+    environ.set_item("wsgi.input", input)?;
+    environ.set_item("wsgi.errors", py.import("sys")?.getattr("stderr")?)?;
+    environ.set_item("wsgi.multithread", true)?;
+    environ.set_item("wsgi.multiprocess", false)?;
+    environ.set_item("wsgi.run_once", false)?;
+
+    Ok(environ)
+}
+
+
+///
+/// StartResponse is the `start_response` callable given to the app, it
+/// holds on to the status and headers and collects anything passed to the
+/// `write` callable it returns.
+///
+#[pyclass]
+#[derive(Default)]
+pub struct StartResponse {
+    status: Option<u16>,
+    headers: Vec<(String, String)>,
+    written: Vec<u8>,
+}
+
+#[pymethods]
+impl StartResponse {
+
+    ///
+    /// PythonMethod: start_response(status, headers, exc_info=None) -> write
+    ///
+    ///     Calling it a second time is only allowed with `exc_info`, since
+    ///     nothing is sent until the app returns we can always replace
+    ///     the earlier status and headers.
+    ///
+    #[call]
+    #[args(exc_info = "None")]
+    fn __call__(
+        mut slf: PyRefMut<Self>,
+        py: Python,
+        status: &str,
+        headers: Vec<(String, String)>,
+        exc_info: Option<PyObject>,
+    ) -> PyResult<PyObject> {
+        let has_exc_info = exc_info.is_some_and(|info| !info.is_none(py));
+        if slf.status.is_some() && !has_exc_info {
+            return Err(PyRuntimeError::new_err("start_response() called twice without exc_info"))
+        }
+
+        let code = status
+            .split(' ')
+            .next()
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| PyValueError::new_err(format!("invalid WSGI status '{}'", status)))?;
+
+        slf.status = Some(code);
+        slf.headers = headers;
+
+        let this: PyObject = slf.into_py(py);
+        this.getattr(py, "write")
+    }
+
+    ///
+    /// PythonMethod: write(data)
+    ///
+    ///     The legacy imperative write, the data goes out ahead of whatever
+    ///     the app's iterable produces.
+    ///
+    fn write(&mut self, data: &PyBytes) {
+        self.written.extend_from_slice(data.as_bytes());
+    }
+}
+
+
+///
+/// WSGICall is what actually runs on the executor thread, it calls the
+/// app, drains the iterable and turns the lot into an HTTPResponse.
+///
+#[pyclass]
+pub struct WSGICall {
+    app: PyObject,
+    environ: PyObject,
+    start_response: Py<StartResponse>,
+}
+
+#[pymethods]
+impl WSGICall {
+    #[call]
+    fn __call__(&self, py: Python) -> PyResult<HTTPResponse> {
+        let result = self.app.call1(py, (self.environ.clone_ref(py), self.start_response.clone_ref(py)))?;
+
+        let drained = self.drain(py, &result);
+
+        // close() has to be called even if iterating the result failed.
+        if result.as_ref(py).hasattr("close")? {
+            result.call_method0(py, "close")?;
+        }
+
+        let body = drained?;
+
+        let mut start = self.start_response.borrow_mut(py);
+        let status = match start.status {
+            Some(status) => status,
+            None => return Err(PyRuntimeError::new_err("WSGI app returned without calling start_response()")),
+        };
+
+        let mut out = std::mem::take(&mut start.written);
+        out.extend_from_slice(&body);
+
+        Ok(HTTPResponse::from_parts(status, std::mem::take(&mut start.headers), out))
+    }
+}
+
+impl WSGICall {
+    fn drain(&self, py: Python, result: &PyObject) -> PyResult<Vec<u8>> {
+        let mut body = Vec::new();
+        for chunk in result.as_ref(py).iter()? {
+            let chunk = chunk?;
+            match chunk.downcast::<PyBytes>() {
+                Ok(chunk) => body.extend_from_slice(chunk.as_bytes()),
+                Err(_) => return Err(PyTypeError::new_err("WSGI app must yield bytes")),
+            }
+        }
+
+        Ok(body)
+    }
+}
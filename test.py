@@ -11,9 +11,12 @@ class AsyncIoCoroutine:
 async def gen():
     print("name")
 
+async def handler(request):
+    return async_rust.HTTPResponse(f"hello {request.client}")
+
 async def t():
     # loop.call_later(1, gen, "a")
-    print(await async_rust.AsyncServerRunner("0.0.0.0:8080", async_rust.OnceFuture))
+    print(await async_rust.AsyncServerRunner("0.0.0.0:8080", handler))
     # await asyncio.sleep(3)
 
     fut = asyncio.get_event_loop().create_future()